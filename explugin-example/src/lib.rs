@@ -17,4 +17,7 @@ impl Plugin for ExEmptyPlugin {
     fn plugin_type(&self) -> u16 {
         PLUGIN_NONE
     }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
 }
@@ -0,0 +1,96 @@
+//! Captures used by the benchmarks: generated in memory, or bundled in `assets/`
+
+// each benchmark uses only some of these functions
+#![allow(dead_code)]
+
+use libpcap_analyzer::{Analyzer, PluginRegistry};
+use libpcap_tools::{Config, PcapDataEngine, PcapEngine};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Size of the UDP payload of generated packets
+const PAYLOAD_LEN: usize = 64;
+
+/// Build a legacy pcap file (Ethernet link type), with `packets` IPv4/UDP packets spread over
+/// `flows` flows (packets of a flow alternate between both directions)
+pub fn generate_pcap(packets: u32, flows: u32) -> Vec<u8> {
+    let mut v = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+    v.extend_from_slice(&[0; 8]);
+    v.extend_from_slice(&65535u32.to_le_bytes());
+    v.extend_from_slice(&1u32.to_le_bytes());
+    for n in 0..packets {
+        let flow = n % flows;
+        let client = [10, 1, (flow >> 8) as u8, flow as u8];
+        let server = [10, 2, 0, 1];
+        let client_port = 1024 + (flow % 60000) as u16;
+        let (src, dst, sport, dport) = if (n / flows) % 2 == 0 {
+            (client, server, client_port, 53)
+        } else {
+            (server, client, 53, client_port)
+        };
+        let frame = udp_frame(src, dst, sport, dport);
+        // pcap record header: timestamp (one packet per millisecond), caplen, origlen
+        v.extend_from_slice(&(1_600_000_000 + n / 1000).to_le_bytes());
+        v.extend_from_slice(&((n % 1000) * 1000).to_le_bytes());
+        v.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        v.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        v.extend_from_slice(&frame);
+    }
+    v
+}
+
+/// Build an Ethernet/IPv4/UDP frame
+fn udp_frame(src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+    let mut v = Vec::with_capacity(14 + 20 + 8 + PAYLOAD_LEN);
+    // Ethernet
+    v.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    v.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    v.extend_from_slice(&0x0800u16.to_be_bytes());
+    // IPv4
+    let ip_len = (20 + 8 + PAYLOAD_LEN) as u16;
+    let mut ip = [0u8; 20];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&ip_len.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 17;
+    ip[12..16].copy_from_slice(&src);
+    ip[16..20].copy_from_slice(&dst);
+    let checksum = ipv4_checksum(&ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    v.extend_from_slice(&ip);
+    // UDP (no checksum)
+    v.extend_from_slice(&sport.to_be_bytes());
+    v.extend_from_slice(&dport.to_be_bytes());
+    v.extend_from_slice(&((8 + PAYLOAD_LEN) as u16).to_be_bytes());
+    v.extend_from_slice(&[0, 0]);
+    v.extend((0..PAYLOAD_LEN).map(|i| i as u8));
+    v
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Read a capture bundled in the `assets` directory of the repository
+pub fn asset(name: &str) -> Vec<u8> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("../assets");
+    path.push(name);
+    std::fs::read(&path).unwrap_or_else(|e| panic!("reading {}: {}", path.display(), e))
+}
+
+/// Run an analyzer using the plugins of `registry` on a capture
+pub fn run_analyzer(registry: PluginRegistry, data: &[u8]) {
+    let config = Config::default();
+    let analyzer = Analyzer::new(Arc::new(registry), &config);
+    let mut engine = PcapDataEngine::new(analyzer, &config);
+    let mut reader = data;
+    engine.run(&mut reader).expect("run analyzer");
+}
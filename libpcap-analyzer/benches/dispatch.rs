@@ -0,0 +1,62 @@
+//! Dispatch of packets to plugins: layer filters, and the full analyzer path
+
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use libpcap_analyzer::plugins::PluginsFactory;
+use libpcap_analyzer::{NetworkLayerType, PluginRegistry, TransportLayerType};
+use libpcap_tools::Config;
+
+fn default_registry() -> PluginRegistry {
+    PluginsFactory::default()
+        .build_plugins(&Config::default())
+        .expect("build plugins")
+}
+
+fn bench_layer_filter(c: &mut Criterion) {
+    let registry = default_registry();
+    // (layer, layer_filter) of a typical packet, and the catch-all filters of each layer
+    let lookups = [
+        (2, 0),
+        (3, NetworkLayerType::Ipv4 as u16),
+        (3, NetworkLayerType::Ipv6 as u16),
+        (3, 0),
+        (4, TransportLayerType::Tcp as u16),
+        (4, TransportLayerType::Udp as u16),
+        (4, 0),
+    ];
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(lookups.len() as u64));
+    group.bench_function("layer_filter", |b| {
+        b.iter(|| {
+            for (layer, filter) in &lookups {
+                black_box(registry.get_plugins_for_layer(*layer, *filter));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_analyzer(c: &mut Criterion) {
+    let data = common::generate_pcap(10_000, 100);
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("analyzer_no_plugins", |b| {
+        b.iter_batched(
+            PluginRegistry::new,
+            |registry| common::run_analyzer(registry, &data),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("analyzer_default_plugins", |b| {
+        b.iter_batched(
+            default_registry,
+            |registry| common::run_analyzer(registry, &data),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_layer_filter, bench_analyzer);
+criterion_main!(benches);
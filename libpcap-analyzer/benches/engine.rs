@@ -0,0 +1,53 @@
+//! Block engine: reading and parsing captures, without analysis
+
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use libpcap_tools::{
+    Config, Error, Packet, ParseContext, PcapAnalyzer, PcapDataEngine, PcapEngine,
+};
+
+/// Analyzer doing nothing but counting packets
+#[derive(Default)]
+struct PacketCounter {
+    packets: u64,
+}
+
+impl PcapAnalyzer for PacketCounter {
+    fn handle_packet(&mut self, packet: &Packet, _ctx: &ParseContext) -> Result<(), Error> {
+        black_box(packet);
+        self.packets += 1;
+        Ok(())
+    }
+}
+
+fn run_engine(data: &[u8]) -> u64 {
+    let config = Config::default();
+    let mut engine = PcapDataEngine::new(PacketCounter::default(), &config);
+    let mut reader = data;
+    engine.run(&mut reader).expect("run engine");
+    engine.data_analyzer().packets
+}
+
+fn bench_engine(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_engine");
+    let captures = vec![
+        ("generated_100k", common::generate_pcap(100_000, 1000)),
+        (
+            "nmap_tcp_22_ipv4.pcap",
+            common::asset("nmap_tcp_22_ipv4.pcap"),
+        ),
+        (
+            "nmap_tcp_22_ipv4_ns.pcapng",
+            common::asset("nmap_tcp_22_ipv4_ns.pcapng"),
+        ),
+    ];
+    for (name, data) in &captures {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(*name, |b| b.iter(|| run_engine(data)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_engine);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+//! Flow tracking: flow table insertions, and lookups and updates on the per-packet path
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use libpcap_tools::{Duration, FiveTuple, Flow, FlowTable};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const NUM_FLOWS: u32 = 10_000;
+
+/// Five-tuples of `NUM_FLOWS` flows, half IPv4/UDP and half IPv6/TCP
+fn five_tuples() -> Vec<FiveTuple> {
+    (0..NUM_FLOWS)
+        .map(|n| {
+            let (proto, src, dst) = if n % 2 == 0 {
+                (
+                    17,
+                    IpAddr::V4(Ipv4Addr::from(0x0a01_0000 + n)),
+                    IpAddr::V4(Ipv4Addr::new(10, 2, 0, 1)),
+                )
+            } else {
+                (
+                    6,
+                    IpAddr::V6(Ipv6Addr::from((0x2001_0db8_u128 << 96) | u128::from(n))),
+                    IpAddr::V6(Ipv6Addr::from((0x2001_0db8_u128 << 96) | 1)),
+                )
+            };
+            FiveTuple {
+                proto,
+                src,
+                dst,
+                src_port: 1024 + (n % 60000) as u16,
+                dst_port: 443,
+            }
+        })
+        .collect()
+}
+
+fn fill_table(five_tuples: &[FiveTuple]) -> FlowTable {
+    let mut table = FlowTable::default().with_rng_seed(0);
+    for five_t in five_tuples {
+        table.insert_flow(five_t.clone(), Flow::new(five_t, 0, 0));
+    }
+    table
+}
+
+fn bench_flows(c: &mut Criterion) {
+    let five_tuples = five_tuples();
+    let reverse = five_tuples
+        .iter()
+        .map(FiveTuple::get_reverse)
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("flow_table");
+    group.throughput(Throughput::Elements(u64::from(NUM_FLOWS)));
+    group.bench_function("insert", |b| {
+        b.iter_batched(
+            || five_tuples.clone(),
+            |five_tuples| fill_table(&five_tuples),
+            BatchSize::SmallInput,
+        )
+    });
+    // one packet per flow, in each direction
+    group.throughput(Throughput::Elements(2 * u64::from(NUM_FLOWS)));
+    group.bench_function("lookup_update", |b| {
+        let mut table = fill_table(&five_tuples);
+        let ts = Duration::new(1, 0);
+        b.iter(|| {
+            for five_t in five_tuples.iter().chain(&reverse) {
+                let flow_id = table.lookup_flow(five_t).expect("known flow");
+                table.update_flow(flow_id, five_t, ts, 100, 0);
+            }
+        })
+    });
+    // lookups of unknown flows (for ex. the first packet of a new flow)
+    group.throughput(Throughput::Elements(u64::from(NUM_FLOWS / 2)));
+    group.bench_function("lookup_miss", |b| {
+        let table = fill_table(&five_tuples[..NUM_FLOWS as usize / 2]);
+        b.iter(|| {
+            for five_t in &five_tuples[NUM_FLOWS as usize / 2..] {
+                black_box(table.lookup_flow(five_t));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_flows);
+criterion_main!(benches);
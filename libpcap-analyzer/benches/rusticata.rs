@@ -0,0 +1,42 @@
+//! Rusticata plugin: protocol probes and parsers
+
+mod common;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use libpcap_analyzer::plugins::PluginsFactory;
+use libpcap_analyzer::PluginRegistry;
+use libpcap_tools::Config;
+
+fn rusticata_registry() -> PluginRegistry {
+    PluginsFactory::default()
+        .build_filter_plugins(|name| name == "RusticataBuilder", &Config::default())
+        .expect("build plugins")
+}
+
+fn bench_rusticata(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rusticata");
+    let captures = vec![
+        // connections to SSH servers, and HTTP requests
+        (
+            "nmap_tcp_22_ipv4.pcap",
+            common::asset("nmap_tcp_22_ipv4.pcap"),
+        ),
+        ("http_host_ipv4.pcap", common::asset("http_host_ipv4.pcap")),
+        // payloads on the DNS port which are not DNS: probes fail until the flow is bypassed
+        ("generated_probes", common::generate_pcap(10_000, 100)),
+    ];
+    for (name, data) in &captures {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter_batched(
+                rusticata_registry,
+                |registry| common::run_analyzer(registry, data),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rusticata);
+criterion_main!(benches);
@@ -1,23 +1,32 @@
-use crate::erspan::ErspanPacket;
-use crate::flow_map::FlowMap;
+use crate::erspan::*;
 use crate::geneve::*;
-use crate::ip_defrag::{DefragEngine, Fragment, IPDefragEngine};
+use crate::gtpu::*;
 use crate::layers::LinkLayerType;
 use crate::mpls::*;
-use crate::packet_info::PacketInfo;
+use crate::packet_info::{Encapsulation, PacketHeaders, PacketInfo, VlanTag};
 use crate::plugin::*;
 use crate::plugin_registry::*;
 use crate::ppp::{PppPacket, PppProtocolTypes};
 use crate::pppoe::PppoeSessionPacket;
+use crate::report::Report;
+use crate::sctp::{parse_sctp_header, sctp_chunks};
 use crate::tcp_reassembly::{finalize_tcp_streams, TcpStreamError, TcpStreamReassembly};
+use crate::teredo::teredo_ipv6_payload;
 use crate::vxlan::*;
+use libpcap_tools::defrag::{DefragConfig, DefragKey, Defragmenter, Fragment};
+use libpcap_tools::ipv6::{
+    is_ipv6_extension, parse_ipv6_extensions, Ipv6Fragment, DEFAULT_IPV6_MAX_EXTENSIONS,
+};
 use libpcap_tools::*;
 
 use pcap_parser::data::{get_packetdata_raw, PacketData};
 use pcap_parser::Linktype;
+use serde::Deserialize;
 use std::cmp::min;
+use std::mem;
 use std::net::IpAddr;
-use std::ops::DerefMut;
+use std::ops::{DerefMut, Range};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use pnet_packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
@@ -26,17 +35,67 @@ use pnet_packet::icmp::IcmpPacket;
 use pnet_packet::icmpv6::Icmpv6Packet;
 use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet};
-use pnet_packet::ipv6::{ExtensionPacket, FragmentPacket, Ipv6Packet};
+use pnet_packet::ipv6::Ipv6Packet;
 use pnet_packet::tcp::TcpPacket;
 use pnet_packet::udp::UdpPacket;
 use pnet_packet::vlan::VlanPacket;
-use pnet_packet::{Packet as PnetPacket, PacketSize};
+use pnet_packet::Packet as PnetPacket;
 
 #[derive(Clone, Debug, Default)]
 pub struct L3Info {
     /// Layer 4 protocol (e.g TCP, UDP, ICMP)
     pub l4_proto: u8,
     pub three_tuple: ThreeTuple,
+    /// IPv4 TTL or IPv6 hop limit
+    pub ttl: u8,
+}
+
+/// Raw headers of the layer being handled, as ranges in the captured data of the packet
+#[derive(Clone, Debug, Default)]
+struct LayerHeaders {
+    l2: Option<Range<usize>>,
+    l3: Option<Range<usize>>,
+    vlan_tags: Vec<VlanTag>,
+}
+
+impl LayerHeaders {
+    fn packet_headers<'h>(&'h self, packet: &'h Packet) -> PacketHeaders<'h> {
+        let data = packet_bytes(packet);
+        // the outer ethernet header is not handled by the analyzer in threaded mode
+        let l2_range = self.l2.clone().or_else(|| match packet.data {
+            PacketData::L2(d) if d.len() >= 14 => Some(0..14),
+            _ => None,
+        });
+        PacketHeaders {
+            data,
+            l2_range,
+            vlan_tags: &self.vlan_tags,
+            l3_range: self.l3.clone(),
+        }
+    }
+}
+
+/// Captured data of a packet
+fn packet_bytes<'p>(packet: &Packet<'p>) -> &'p [u8] {
+    match packet.data {
+        PacketData::L2(d)
+        | PacketData::L3(_, d)
+        | PacketData::L4(_, d)
+        | PacketData::Unsupported(d) => d,
+    }
+}
+
+/// Range of `header` in the captured data of `packet`, if it is part of it (and not, for ex., of
+/// a reassembly buffer)
+fn range_in_packet(packet: &Packet, header: &[u8]) -> Option<Range<usize>> {
+    let data = packet_bytes(packet);
+    let start = (header.as_ptr() as usize).checked_sub(data.as_ptr() as usize)?;
+    let end = start + header.len();
+    if end <= data.len() {
+        Some(start..end)
+    } else {
+        None
+    }
 }
 
 /// Pcap/Pcap-ng analyzer
@@ -55,16 +114,34 @@ pub struct L3Info {
 pub struct Analyzer {
     pub(crate) registry: Arc<PluginRegistry>,
 
-    pub(crate) flows: FlowMap,
+    pub(crate) flows: FlowTable,
 
-    ipv4_defrag: Box<dyn DefragEngine>,
-    ipv6_defrag: Box<dyn DefragEngine>,
+    ipv4_defrag: Defragmenter,
+    ipv6_defrag: Defragmenter,
     pub(crate) tcp_defrag: TcpStreamReassembly,
 
     defrag_count: usize,
+    /// Maximum number of IPv6 extension headers in a packet
+    ipv6_max_extensions: usize,
+    /// Number of tunnels around the layer being handled
+    tunnel_depth: usize,
+    /// ERSPAN session of the layer being handled
+    erspan_session: Option<u16>,
+    /// Innermost encapsulation of the layer being handled
+    encapsulation: Option<Encapsulation>,
+    /// Raw headers of the layer being handled
+    headers: LayerHeaders,
+    /// Number of derived packets (emitted by plugins) around the layer being handled
+    derived_depth: usize,
+    /// Timestamp of the next check of flow expiration
+    next_expiration_check: Duration,
     do_checksums: bool,
     skip_index: usize,
     output_dir: Option<String>,
+    /// File to save the report of all plugins
+    report_file: Option<String>,
+    /// Plugins without checkpoint support were reported
+    checkpoint_warned: AtomicBool,
 }
 
 impl Analyzer {
@@ -75,16 +152,31 @@ impl Analyzer {
             debug!("Will skip to index {}", skip_index);
         }
         let output_dir = config.get("output_dir").map(|s| s.to_owned());
+        let report_file = config.get("report_file").map(|s| s.to_owned());
+        let expiration_policy = ExpirationPolicy::from_config(config);
+        let defrag_config = DefragConfig::from_config(config);
+        let ipv6_max_extensions = config
+            .get_usize("ipv6.max_extensions")
+            .unwrap_or(DEFAULT_IPV6_MAX_EXTENSIONS);
         Analyzer {
             registry,
-            flows: FlowMap::default(),
-            ipv4_defrag: Box::new(IPDefragEngine::new()),
-            ipv6_defrag: Box::new(IPDefragEngine::new()),
+            flows: FlowTable::default().with_expiration_policy(expiration_policy),
+            ipv4_defrag: Defragmenter::new(defrag_config.clone()),
+            ipv6_defrag: Defragmenter::new(defrag_config),
             tcp_defrag: TcpStreamReassembly::default(),
             defrag_count: 0,
+            ipv6_max_extensions,
+            tunnel_depth: 0,
+            erspan_session: None,
+            encapsulation: None,
+            headers: LayerHeaders::default(),
+            derived_depth: 0,
+            next_expiration_check: Duration::default(),
             do_checksums,
             skip_index,
             output_dir,
+            report_file,
+            checkpoint_warned: AtomicBool::new(false),
         }
     }
 
@@ -98,6 +190,30 @@ impl Analyzer {
         handle_l2(packet, ctx, data, self)
     }
 
+    /// Save the results of plugins to the output directory, and write the report (if
+    /// configured)
+    ///
+    /// Must be called after the `post_process` function of plugins.
+    pub(crate) fn save_plugin_results(&self) {
+        if let Some(output_dir) = &self.output_dir {
+            self.registry.run_plugins(
+                |_| true,
+                |p| {
+                    let res = p.save_results(output_dir);
+                    if let Err(e) = res {
+                        warn!("error while saving results for {}: {}", p.name(), e);
+                    }
+                },
+            );
+        }
+        if let Some(report_file) = &self.report_file {
+            let report = Report::collect(&self.registry);
+            if let Err(e) = report.save(report_file) {
+                warn!("error while saving report to {}: {}", report_file, e);
+            }
+        }
+    }
+
     /// Use deterministic values for random numbers (for ex. flow IDs)
     ///
     /// This option is intended for use in testing
@@ -183,8 +299,17 @@ pub(crate) fn handle_l2(
             }
             let payload = eth.payload();
             trace!("    ethertype: 0x{:x}", ethertype.0);
-            run_plugins_v2_link(packet, ctx, LinkLayerType::Ethernet, payload, analyzer)?;
-            handle_l3(packet, ctx, payload, ethertype, analyzer)
+            // this header becomes the innermost one, and VLAN tags are the ones following it
+            let prev_l2 = mem::replace(
+                &mut analyzer.headers.l2,
+                range_in_packet(packet, &data[..14]),
+            );
+            let prev_vlan_tags = mem::take(&mut analyzer.headers.vlan_tags);
+            let res = run_plugins_v2_link(packet, ctx, LinkLayerType::Ethernet, data, analyzer)
+                .and_then(|_| handle_l3(packet, ctx, payload, ethertype, analyzer));
+            analyzer.headers.l2 = prev_l2;
+            analyzer.headers.vlan_tags = prev_vlan_tags;
+            res
         }
         None => {
             // packet too small to be ethernet
@@ -218,7 +343,8 @@ pub(crate) fn handle_l3(
         // 0x8847: MPLS (RFC5332)
         // 0x8848: MPLS with upstream-assigned label (RFC5332)
         EtherTypes::Mpls | EtherTypes::MplsMcast => handle_l3_mpls(packet, ctx, data, analyzer),
-        EtherType(0x88be) => handle_l3_erspan(packet, ctx, data, analyzer),
+        // 0x88be: ERSPAN Type II, 0x22eb: ERSPAN Type III
+        EtherType(0x88be) | EtherType(0x22eb) => handle_l3_erspan(packet, ctx, data, analyzer),
         EtherTypes::PppoeSession => handle_l3_pppoesession(packet, ctx, data, analyzer),
 
         e => {
@@ -289,12 +415,13 @@ fn handle_l3_ipv4(
     // check IP fragmentation before calling handle_l4
     let frag_offset = (ipv4.get_fragment_offset() * 8) as usize;
     let more_fragments = ipv4.get_flags() & Ipv4Flags::MoreFragments != 0;
-    let defrag = analyzer.ipv4_defrag.update(
-        ipv4.get_identification().into(),
-        frag_offset,
-        more_fragments,
-        payload,
-    );
+    let key = DefragKey {
+        three_tuple: t3.clone(),
+        id: ipv4.get_identification().into(),
+    };
+    let defrag = analyzer
+        .ipv4_defrag
+        .update(key, frag_offset, more_fragments, payload, packet.ts);
     let payload = match defrag {
         Fragment::NoFrag(d) => {
             debug_assert!(d.len() < orig_len);
@@ -322,21 +449,12 @@ fn handle_l3_ipv4(
     let l3_info = L3Info {
         three_tuple: t3,
         l4_proto,
+        ttl: ipv4.get_ttl(),
     };
-    handle_l3_common(packet, ctx, payload, &l3_info, analyzer)
-}
-
-fn is_ipv6_opt(opt: IpNextHeaderProtocol) -> bool {
-    matches!(
-        opt,
-        IpNextHeaderProtocols::Hopopt
-            | IpNextHeaderProtocols::Ipv6Opts
-            | IpNextHeaderProtocols::Ipv6Route
-            | IpNextHeaderProtocols::Ipv6Frag
-            | IpNextHeaderProtocols::Esp
-            | IpNextHeaderProtocols::Ah
-            | IpNextHeaderProtocols::MobilityHeader
-    )
+    let header_len = min(ipv4.get_header_length() as usize * 4, data.len());
+    with_l3_header(packet, &data[..header_len], analyzer, |analyzer| {
+        handle_l3_common(packet, ctx, payload, &l3_info, analyzer)
+    })
 }
 
 fn handle_l3_ipv6(
@@ -349,7 +467,6 @@ fn handle_l3_ipv6(
     let ipv6 = Ipv6Packet::new(data).ok_or("Could not build IPv6 packet from data")?;
 
     let mut payload = ipv6.payload();
-    let mut l4_proto = ipv6.get_next_header();
 
     if payload.is_empty() {
         // jumbogram ? (rfc2675)
@@ -367,41 +484,23 @@ fn handle_l3_ipv6(
 
     // XXX remove padding ?
 
-    let mut extensions = Vec::new();
-    let mut frag_ext = None;
-
     // skip all extensions (keep them ?)
-    while is_ipv6_opt(l4_proto) {
-        let ext = ExtensionPacket::new(payload)
-            .ok_or("Could not build IPv6 Extension packet from payload")?;
-        let next_header = ext.get_next_header();
-        trace!("option header: {}", l4_proto);
-        if l4_proto == IpNextHeaderProtocols::Ipv6Frag {
-            if frag_ext.is_some() {
-                warn!("multiple IPv6Frag extensions idx={}", ctx.pcap_index);
-                return Ok(());
-            }
-            frag_ext = FragmentPacket::new(payload);
+    let ext = match parse_ipv6_extensions(
+        ipv6.get_next_header().0,
+        payload,
+        analyzer.ipv6_max_extensions,
+    ) {
+        Ok(ext) => ext,
+        Err(e) => {
+            warn!("{} (idx={})", e, ctx.pcap_index);
+            return Ok(());
         }
-        // XXX fixup wrong extension size calculation in pnet
-        let offset = if l4_proto != IpNextHeaderProtocols::Ah {
-            ext.packet_size()
-        } else {
-            // https://en.wikipedia.org/wiki/IPsec#Authentication_Header
-            // The length of this Authentication Header in 4-octet units, minus 2. For example, an
-            // AH value of 4 equals 3×(32-bit fixed-length AH fields) + 3×(32-bit ICV fields) − 2
-            // and thus an AH value of 4 means 24 octets. Although the size is measured in 4-octet
-            // units, the length of this header needs to be a multiple of 8 octets if carried in an
-            // IPv6 packet. This restriction does not apply to an Authentication Header carried in
-            // an IPv4 packet.
-            let l1 = (payload[1] - 1) as usize;
-            let val = l1 * 4 + l1 * 4 - 2;
-            (val + 7) & (!7)
-        };
-        extensions.push((l4_proto, ext));
-        l4_proto = next_header;
-        payload = &payload[offset..];
-    }
+    };
+    let l4_proto = IpNextHeaderProtocol(ext.next_header);
+    // fixed header and extension headers
+    let header_len = min(40 + payload.len() - ext.payload.len(), data.len());
+    let header = &data[..header_len];
+    let payload = ext.payload;
 
     let t3 = ThreeTuple {
         src: IpAddr::V6(ipv6.get_source()),
@@ -427,15 +526,16 @@ fn handle_l3_ipv6(
     let l3_info = L3Info {
         three_tuple: t3,
         l4_proto: l4_proto.0,
+        ttl: ipv6.get_hop_limit(),
     };
 
-    if let Some(frag_info) = frag_ext {
-        handle_l4_ipv6frag(
-            packet, ctx, &frag_info, payload, &l3_info, l4_proto, analyzer,
-        )
-    } else {
-        handle_l3_common(packet, ctx, payload, &l3_info, analyzer)
-    }
+    with_l3_header(packet, header, analyzer, |analyzer| {
+        if let Some(frag_info) = ext.fragment {
+            handle_l4_ipv6frag(packet, ctx, &frag_info, &l3_info, analyzer)
+        } else {
+            handle_l3_common(packet, ctx, payload, &l3_info, analyzer)
+        }
+    })
 }
 
 fn handle_l3_vlan_801q(
@@ -447,9 +547,18 @@ fn handle_l3_vlan_801q(
     trace!("handle_l3_vlan_801q (idx={})", ctx.pcap_index);
     let vlan = VlanPacket::new(data).ok_or("Could not build 802.1Q Vlan packet from data")?;
     let next_ethertype = vlan.get_ethertype();
-    trace!("    802.1q: VLAN id={}", vlan.get_vlan_identifier());
-
-    handle_l3(packet, ctx, vlan.payload(), next_ethertype, analyzer)
+    let vlan_id = vlan.get_vlan_identifier();
+    trace!("    802.1q: VLAN id={}", vlan_id);
+
+    analyzer.headers.vlan_tags.push(VlanTag {
+        id: vlan_id,
+        priority: vlan.get_priority_code_point().0,
+    });
+    let res = with_encapsulation(analyzer, Encapsulation::Vlan(vlan_id), |analyzer| {
+        handle_l3(packet, ctx, vlan.payload(), next_ethertype, analyzer)
+    });
+    analyzer.headers.vlan_tags.pop();
+    res
 }
 
 fn handle_l3_erspan(
@@ -460,12 +569,32 @@ fn handle_l3_erspan(
 ) -> Result<(), Error> {
     trace!("handle_l3_erspan (idx={})", ctx.pcap_index);
     let erspan = ErspanPacket::new(data).ok_or("Could not build Erspan packet from data")?;
+    let session = erspan.get_span_id();
     trace!(
-        "    erspan: VLAN id={} span ID={}",
+        "    erspan: version={} VLAN id={} span ID={}",
+        erspan.get_version(),
         erspan.get_vlan(),
-        erspan.get_span_id()
+        session
     );
-    handle_l2(packet, ctx, erspan.payload(), analyzer)
+    let payload = erspan.payload();
+    let prev_session = analyzer.erspan_session.replace(session);
+    let res = match erspan.get_frame_type() {
+        ERSPAN_FRAME_ETHERNET => handle_l2(packet, ctx, payload, analyzer),
+        ERSPAN_FRAME_IP => match payload.first().map(|b| b >> 4) {
+            Some(4) => handle_l3(packet, ctx, payload, EtherTypes::Ipv4, analyzer),
+            Some(6) => handle_l3(packet, ctx, payload, EtherTypes::Ipv6, analyzer),
+            _ => Ok(()),
+        },
+        t => {
+            warn!(
+                "ERSPAN: unsupported frame type {} (idx={})",
+                t, ctx.pcap_index
+            );
+            Ok(())
+        }
+    };
+    analyzer.erspan_session = prev_session;
+    res
 }
 
 fn handle_l3_mpls(
@@ -488,12 +617,16 @@ fn handle_l3_mpls(
         return Ok(());
     }
     let first_nibble = payload[0] >> 4;
-    match first_nibble {
-        4 => handle_l3_ipv4(packet, ctx, payload, analyzer),
-        6 => handle_l3_ipv6(packet, ctx, payload, analyzer),
-        _ => handle_l2(packet, ctx, payload, analyzer),
-    }
-    // store top label / decoder association?
+    let label = mpls.get_top_label().get_label();
+    with_encapsulation(
+        analyzer,
+        Encapsulation::Mpls(label),
+        |analyzer| match first_nibble {
+            4 => handle_l3_ipv4(packet, ctx, payload, analyzer),
+            6 => handle_l3_ipv6(packet, ctx, payload, analyzer),
+            _ => handle_l2(packet, ctx, payload, analyzer),
+        },
+    )
 }
 
 fn handle_l3_pppoesession(
@@ -546,12 +679,20 @@ fn handle_l3_common(
     match IpNextHeaderProtocol(l3_info.l4_proto) {
         IpNextHeaderProtocols::Tcp => handle_l4_tcp(packet, ctx, data, l3_info, analyzer),
         IpNextHeaderProtocols::Udp => handle_l4_udp(packet, ctx, data, l3_info, analyzer),
+        IpNextHeaderProtocols::Sctp => handle_l4_sctp(packet, ctx, data, l3_info, analyzer),
         IpNextHeaderProtocols::Icmp => handle_l4_icmp(packet, ctx, data, l3_info, analyzer),
         IpNextHeaderProtocols::Icmpv6 => handle_l4_icmpv6(packet, ctx, data, l3_info, analyzer),
         IpNextHeaderProtocols::Esp => handle_l4_generic(packet, ctx, data, l3_info, analyzer),
-        IpNextHeaderProtocols::Gre => handle_l4_gre(packet, ctx, data, l3_info, analyzer),
-        IpNextHeaderProtocols::Ipv4 => handle_l3(packet, ctx, data, EtherTypes::Ipv4, analyzer),
-        IpNextHeaderProtocols::Ipv6 => handle_l3(packet, ctx, data, EtherTypes::Ipv6, analyzer),
+        IpNextHeaderProtocols::Gre => handle_tunnel(analyzer, |analyzer| {
+            handle_l4_gre(packet, ctx, data, l3_info, analyzer)
+        }),
+        // IP in IP, and 6in4 or 6to4 (RFC 4213, RFC 3056)
+        IpNextHeaderProtocols::Ipv4 => handle_tunnel(analyzer, |analyzer| {
+            handle_l3(packet, ctx, data, EtherTypes::Ipv4, analyzer)
+        }),
+        IpNextHeaderProtocols::Ipv6 => handle_tunnel(analyzer, |analyzer| {
+            handle_l3(packet, ctx, data, EtherTypes::Ipv6, analyzer)
+        }),
         p => {
             warn!("Unsupported L4 proto {} (idx={})", p, ctx.pcap_index);
             handle_l4_generic(packet, ctx, data, l3_info, analyzer)
@@ -572,32 +713,15 @@ fn handle_l4_tcp(
 
     let src_port = tcp.get_source();
     let dst_port = tcp.get_destination();
+    // CWR, ECE, URG, ACK, PSH, RST, SYN and FIN flags
+    let tcp_flags = l4_data[13];
 
     // XXX begin copy/paste of handle_l4_common
     let five_tuple = FiveTuple::from_three_tuple(&l3_info.three_tuple, src_port, dst_port);
     trace!("5-t: {}", five_tuple);
     let now = packet.ts;
 
-    let flow_id = {
-        // flows modification section
-        let flows = &mut analyzer.flows;
-        // lookup flow
-        let flow_id = match flows.lookup_flow(&five_tuple) {
-            Some(id) => id,
-            None => {
-                let flow = Flow::new(&five_tuple, packet.ts.secs, packet.ts.micros);
-                gen_event_new_flow(&flow, &analyzer.registry);
-                flows.insert_flow(five_tuple.clone(), flow)
-            }
-        };
-
-        // update flow
-        flows.entry(flow_id).and_modify(|flow| {
-            flow.flow_id = flow_id;
-            flow.last_seen = now;
-        });
-        flow_id
-    };
+    let flow_id = lookup_or_insert_flow(packet, &five_tuple, tcp_flags, analyzer);
 
     // get a read-only reference to flow
     let flow = analyzer
@@ -610,6 +734,31 @@ fn handle_l4_tcp(
 
     // XXX end copy/paste
 
+    // send the segment to plugins analyzing TCP headers, before reassembly
+    let packet_info = PacketInfo {
+        five_tuple: &five_tuple,
+        to_server,
+        l3_type: l3_info.three_tuple.l3_proto(),
+        l4_data,
+        l4_type: five_tuple.proto,
+        ttl: l3_info.ttl,
+        l4_payload: Some(tcp.payload()),
+        flow: Some(&flow),
+        pcap_index: ctx.pcap_index,
+        interface: ctx.interface(packet.interface),
+        tunnel_depth: analyzer.tunnel_depth,
+        erspan_session: analyzer.erspan_session,
+        encapsulation: analyzer.encapsulation,
+        headers: analyzer.headers.packet_headers(packet),
+        derived: analyzer.derived_depth > 0,
+    };
+    analyzer.registry.run_plugins(
+        |p| p.plugin_type() & PLUGIN_TCP_SEGMENTS != 0,
+        |p| {
+            let _ = p.handle_layer_transport(packet, &packet_info);
+        },
+    );
+
     let res = analyzer
         .tcp_defrag
         .update(&flow, &tcp, to_server, ctx.pcap_index);
@@ -652,6 +801,7 @@ fn handle_l4_tcp(
                 link_type: packet.link_type,
                 data: PacketData::L4(t5.proto, &[]),
                 pcap_index,
+                chunk: None,
             };
             let packet_info = PacketInfo {
                 five_tuple: &t5,
@@ -659,9 +809,16 @@ fn handle_l4_tcp(
                 l3_type: l3_info.three_tuple.l3_proto(),
                 l4_data: &[], // reassembled, so no L4 data
                 l4_type: t5.proto,
+                ttl: l3_info.ttl,
                 l4_payload: Some(l4_payload),
                 flow: Some(&flow),
                 pcap_index,
+                interface: ctx.interface(packet.interface),
+                tunnel_depth: analyzer.tunnel_depth,
+                erspan_session: analyzer.erspan_session,
+                encapsulation: analyzer.encapsulation,
+                headers: PacketHeaders::default(), // reassembled, so no headers
+                derived: analyzer.derived_depth > 0,
             };
             // let start = ::std::time::Instant::now();
             run_plugins_v2_transport(&dummy_packet, ctx, &packet_info, analyzer)?;
@@ -670,9 +827,9 @@ fn handle_l4_tcp(
         }
         Ok(_) => (),
         Err(TcpStreamError::Inverted) => {
-            analyzer.flows.entry(flow_id).and_modify(|f| {
-                f.five_tuple = f.five_tuple.get_reverse();
-            });
+            analyzer
+                .flows
+                .reverse_flow(flow_id, FlowDirection::TcpHandshake);
         }
         Err(e) => {
             warn!("Tcp steam reassembly error: {:?}", e);
@@ -712,13 +869,38 @@ fn handle_l4_udp(
     // if sport/dport == 4789, this could be VXLAN
     // XXX l4 plugins will not be called
     if src_port == 4789 || dst_port == 4789 {
-        return handle_l4_vxlan(packet, ctx, data, l3_info, udp.payload(), analyzer);
+        return handle_tunnel(analyzer, |analyzer| {
+            handle_l4_vxlan(packet, ctx, data, l3_info, udp.payload(), analyzer)
+        });
     }
 
     // if sport/dport == 6081, this could be GENEVE
     // XXX l4 plugins will not be called
     if src_port == 6081 || dst_port == 6081 {
-        return handle_l4_geneve(packet, ctx, data, l3_info, udp.payload(), analyzer);
+        return handle_tunnel(analyzer, |analyzer| {
+            handle_l4_geneve(packet, ctx, data, l3_info, udp.payload(), analyzer)
+        });
+    }
+
+    // if sport/dport == 2152, this could be GTP-U
+    // XXX l4 plugins will not be called for G-PDU messages
+    if src_port == 2152 || dst_port == 2152 {
+        if let Some(inner) = gtpu_user_data(udp.payload()) {
+            return handle_tunnel(analyzer, |analyzer| {
+                handle_l4_gtpu(packet, ctx, inner, analyzer)
+            });
+        }
+    }
+
+    // if sport/dport == 3544, this could be Teredo
+    // XXX l4 plugins will not be called for encapsulated IPv6 packets
+    if src_port == 3544 || dst_port == 3544 {
+        if let Some(ipv6) = teredo_ipv6_payload(udp.payload()) {
+            trace!("handle_l4_teredo (idx={})", ctx.pcap_index);
+            return handle_tunnel(analyzer, |analyzer| {
+                handle_l3(packet, ctx, ipv6, EtherTypes::Ipv6, analyzer)
+            });
+        }
     }
 
     handle_l4_common(
@@ -853,7 +1035,32 @@ fn handle_l4_gre(
     };
     trace!("GRE: type=0x{:x}", next_proto);
 
-    handle_l3(packet, ctx, data, EtherType(next_proto), analyzer)
+    if next_proto == 0 {
+        // keepalive: the outer GRE packet contains an IP packet back to the sender, with an
+        // empty GRE packet
+        trace!("GRE keepalive (idx={})", ctx.pcap_index);
+        return Ok(());
+    }
+
+    match gre_key(l3_data) {
+        Some(key) => with_encapsulation(analyzer, Encapsulation::GreKey(key), |analyzer| {
+            handle_l3(packet, ctx, data, EtherType(next_proto), analyzer)
+        }),
+        None => handle_l3(packet, ctx, data, EtherType(next_proto), analyzer),
+    }
+}
+
+/// Return the key of a GRE packet, if present (RFC 2890)
+fn gre_key(data: &[u8]) -> Option<u32> {
+    let flags = *data.first()?;
+    // key present bit
+    if flags & 0x20 == 0 {
+        return None;
+    }
+    // checksum and offset are present if the checksum or routing bit is set
+    let offset = if flags & 0xc0 != 0 { 8 } else { 4 };
+    let key = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([key[0], key[1], key[2], key[3]]))
 }
 
 fn handle_l4_vxlan(
@@ -868,24 +1075,95 @@ fn handle_l4_vxlan(
     let vxlan = VxlanPacket::new(l4_data).ok_or("Could not build Vxlan packet from data")?;
     let payload = vxlan.payload();
 
-    trace!("    Vxlan: VLAN id={}", vxlan.get_vlan_identifier());
+    let vni = vxlan.get_vlan_identifier();
+    trace!("    Vxlan: VLAN id={}", vni);
+
+    with_encapsulation(analyzer, Encapsulation::Vxlan(vni), |analyzer| {
+        handle_l2(packet, ctx, payload, analyzer)
+    })
+}
+
+/// Return the user data of a GTP-U G-PDU message, or `None` for other messages (for ex.
+/// echo requests), which are handled as UDP payload
+fn gtpu_user_data(l4_data: &[u8]) -> Option<&[u8]> {
+    let gtpu = GtpuPacket::new(l4_data)?;
+    if gtpu.get_version() != 1 || gtpu.get_message_type() != GTPU_MSG_G_PDU {
+        return None;
+    }
+    trace!("    GTP-U: TEID=0x{:x}", gtpu.get_teid());
+    let payload = gtpu.payload();
+    // return a slice with the lifetime of `l4_data`
+    Some(&l4_data[l4_data.len() - payload.len()..])
+}
+
+// GTP-U: GPRS Tunnelling Protocol, user plane (3GPP TS 29.281)
+fn handle_l4_gtpu(
+    packet: &Packet,
+    ctx: &ParseContext,
+    payload: &[u8],
+    analyzer: &mut Analyzer,
+) -> Result<(), Error> {
+    trace!("handle_l4_gtpu (idx={})", ctx.pcap_index);
+    // user data is an IP packet, without link layer
+    match payload.first().map(|b| b >> 4) {
+        Some(4) => handle_l3(packet, ctx, payload, EtherTypes::Ipv4, analyzer),
+        Some(6) => handle_l3(packet, ctx, payload, EtherTypes::Ipv6, analyzer),
+        _ => {
+            warn!("GTP-U: unsupported user data (idx={})", ctx.pcap_index);
+            Ok(())
+        }
+    }
+}
+
+/// Call `f` to handle the inner packet of a tunnel, incrementing the tunnel depth
+fn handle_tunnel<F>(analyzer: &mut Analyzer, f: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut Analyzer) -> Result<(), Error>,
+{
+    analyzer.tunnel_depth += 1;
+    let res = f(analyzer);
+    analyzer.tunnel_depth -= 1;
+    res
+}
 
-    handle_l2(packet, ctx, payload, analyzer)
+/// Call `f` to handle the payload of an IP packet, `header` becoming the innermost IP header
+fn with_l3_header<F>(
+    packet: &Packet,
+    header: &[u8],
+    analyzer: &mut Analyzer,
+    f: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&mut Analyzer) -> Result<(), Error>,
+{
+    let prev = mem::replace(&mut analyzer.headers.l3, range_in_packet(packet, header));
+    let res = f(analyzer);
+    analyzer.headers.l3 = prev;
+    res
+}
+
+/// Call `f` to handle the payload of an encapsulation, which becomes the innermost encapsulation
+fn with_encapsulation<F>(analyzer: &mut Analyzer, encap: Encapsulation, f: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut Analyzer) -> Result<(), Error>,
+{
+    let prev = analyzer.encapsulation.replace(encap);
+    let res = f(analyzer);
+    analyzer.encapsulation = prev;
+    res
 }
 
 fn handle_l4_ipv6frag(
     packet: &Packet,
     ctx: &ParseContext,
-    frag_info: &FragmentPacket,
-    data: &[u8],
+    frag_info: &Ipv6Fragment,
     l3_info: &L3Info,
-    l4_proto: IpNextHeaderProtocol,
     analyzer: &mut Analyzer,
 ) -> Result<(), Error> {
     trace!("handle_l3_ipv6frag (idx={})", ctx.pcap_index);
-    let frag_offset = frag_info.get_fragment_offset() as usize;
-    let frag_id = frag_info.get_id();
-    let last_fragment = frag_info.is_last_fragment();
+    let frag_offset = frag_info.offset;
+    let frag_id = frag_info.id;
+    let last_fragment = !frag_info.more_fragments;
     trace!(
         "IPv6 Fragment frag_offset={} id={} last_fragment={}",
         frag_offset,
@@ -896,9 +1174,16 @@ fn handle_l4_ipv6frag(
     let defrag = {
         // check IP fragmentation before calling handle_l4
         let more_fragments = !last_fragment;
+        let key = DefragKey {
+            three_tuple: ThreeTuple {
+                l4_proto: frag_info.next_header,
+                ..l3_info.three_tuple.clone()
+            },
+            id: frag_id,
+        };
         analyzer
             .ipv6_defrag
-            .update(frag_id, frag_offset, more_fragments, data)
+            .update(key, frag_offset, more_fragments, frag_info.data, packet.ts)
     };
     let data = match defrag {
         Fragment::NoFrag(d) => d,
@@ -919,9 +1204,38 @@ fn handle_l4_ipv6frag(
         }
     };
 
+    // extension headers following the fragment header are part of the fragmented data
+    let (data, l3_info) = if is_ipv6_extension(frag_info.next_header) {
+        let ext = match parse_ipv6_extensions(
+            frag_info.next_header,
+            data,
+            analyzer.ipv6_max_extensions,
+        ) {
+            Ok(ext) => ext,
+            Err(e) => {
+                warn!("IPv6Fragment: {} (idx={})", e, ctx.pcap_index);
+                return Ok(());
+            }
+        };
+        let l3_info = L3Info {
+            l4_proto: ext.next_header,
+            three_tuple: ThreeTuple {
+                l4_proto: ext.next_header,
+                ..l3_info.three_tuple.clone()
+            },
+            ttl: l3_info.ttl,
+        };
+        (ext.payload, l3_info)
+    } else {
+        (data, l3_info.clone())
+    };
+    let l3_info = &l3_info;
+    let l4_proto = IpNextHeaderProtocol(l3_info.l4_proto);
+
     match l4_proto {
         IpNextHeaderProtocols::Tcp => handle_l4_tcp(packet, ctx, data, l3_info, analyzer),
         IpNextHeaderProtocols::Udp => handle_l4_udp(packet, ctx, data, l3_info, analyzer),
+        IpNextHeaderProtocols::Sctp => handle_l4_sctp(packet, ctx, data, l3_info, analyzer),
         IpNextHeaderProtocols::Icmp => handle_l4_icmp(packet, ctx, data, l3_info, analyzer),
         _ => {
             warn!("IPv6Fragment: Unsupported L4 proto {}", l4_proto);
@@ -930,6 +1244,65 @@ fn handle_l4_ipv6frag(
     }
 }
 
+fn handle_l4_sctp(
+    packet: &Packet,
+    ctx: &ParseContext,
+    data: &[u8],
+    l3_info: &L3Info,
+    analyzer: &mut Analyzer,
+) -> Result<(), Error> {
+    trace!("handle_l4_sctp (idx={})", ctx.pcap_index);
+    trace!("    l4_data len: {}", data.len());
+    let (sctp, chunks) = parse_sctp_header(data).ok_or("Could not build SCTP packet from data")?;
+
+    let five_tuple =
+        FiveTuple::from_three_tuple(&l3_info.three_tuple, sctp.src_port, sctp.dst_port);
+    trace!("5-t: {}", five_tuple);
+
+    let flow_id = lookup_or_insert_flow(packet, &five_tuple, 0, analyzer);
+
+    // get a read-only reference to flow
+    let flow = analyzer
+        .flows
+        .get_flow(flow_id)
+        .expect("could not get flow from ID")
+        .clone(); // clone because run_plugins_v2_transport borrows analyzer
+
+    let to_server = flow.five_tuple == five_tuple;
+
+    // call plugins once for each DATA chunk, with the user data as payload
+    // the SCTP packet (L4 data) is only sent with the first chunk, like reassembled TCP data
+    let mut payloads: Vec<&[u8]> = sctp_chunks(chunks)
+        .filter_map(|chunk| chunk.data().map(|(_, user_data)| user_data))
+        .collect();
+    if payloads.is_empty() {
+        payloads.push(&[]);
+    }
+    // clone because run_plugins_v2_transport borrows analyzer
+    let headers = analyzer.headers.clone();
+    for (i, l4_payload) in payloads.into_iter().enumerate() {
+        let pinfo = PacketInfo {
+            five_tuple: &five_tuple,
+            to_server,
+            l3_type: l3_info.three_tuple.l3_proto(),
+            l4_data: if i == 0 { data } else { &[] },
+            l4_type: five_tuple.proto,
+            ttl: l3_info.ttl,
+            l4_payload: Some(l4_payload),
+            flow: Some(&flow),
+            pcap_index: ctx.pcap_index,
+            interface: ctx.interface(packet.interface),
+            tunnel_depth: analyzer.tunnel_depth,
+            erspan_session: analyzer.erspan_session,
+            encapsulation: analyzer.encapsulation,
+            headers: headers.packet_headers(packet),
+            derived: analyzer.derived_depth > 0,
+        };
+        run_plugins_v2_transport(packet, ctx, &pinfo, analyzer)?;
+    }
+    Ok(())
+}
+
 fn handle_l4_generic(
     packet: &Packet,
     ctx: &ParseContext,
@@ -965,28 +1338,8 @@ fn handle_l4_common(
 ) -> Result<(), Error> {
     let five_tuple = FiveTuple::from_three_tuple(&l3_info.three_tuple, src_port, dst_port);
     trace!("5-t: {}", five_tuple);
-    let now = packet.ts;
 
-    let flow_id = {
-        // flows modification section
-        let flows = &mut analyzer.flows;
-        // lookup flow
-        let flow_id = match flows.lookup_flow(&five_tuple) {
-            Some(id) => id,
-            None => {
-                let flow = Flow::new(&five_tuple, packet.ts.secs, packet.ts.micros);
-                gen_event_new_flow(&flow, &analyzer.registry);
-                flows.insert_flow(five_tuple.clone(), flow)
-            }
-        };
-
-        // update flow
-        flows.entry(flow_id).and_modify(|flow| {
-            flow.flow_id = flow_id;
-            flow.last_seen = now;
-        });
-        flow_id
-    };
+    let flow_id = lookup_or_insert_flow(packet, &five_tuple, 0, analyzer);
 
     // get a read-only reference to flow
     let flow = analyzer
@@ -997,15 +1350,24 @@ fn handle_l4_common(
 
     let to_server = flow.five_tuple == five_tuple;
 
+    // clone because run_plugins_v2_transport borrows analyzer
+    let headers = analyzer.headers.clone();
     let pinfo = PacketInfo {
         five_tuple: &five_tuple,
         to_server,
         l3_type: l3_info.three_tuple.l3_proto(),
         l4_data,
         l4_type: five_tuple.proto,
+        ttl: l3_info.ttl,
         l4_payload,
         flow: Some(&flow),
         pcap_index: ctx.pcap_index,
+        interface: ctx.interface(packet.interface),
+        tunnel_depth: analyzer.tunnel_depth,
+        erspan_session: analyzer.erspan_session,
+        encapsulation: analyzer.encapsulation,
+        headers: headers.packet_headers(packet),
+        derived: analyzer.derived_depth > 0,
     };
     // let start = ::std::time::Instant::now();
     run_plugins_v2_transport(packet, ctx, &pinfo, analyzer)?;
@@ -1014,18 +1376,6 @@ fn handle_l4_common(
 
     // XXX do other stuff
 
-    // XXX check session expiration
-    // const FLOW_EXPIRATION: u32 = 100;
-    // for (flow_id, flow) in self.flows.iter() {
-    //     if (now - flow.last_seen).secs > FLOW_EXPIRATION {
-    //         warn!(
-    //             "Flow {} candidate for expiration (delay: {} secs)",
-    //             flow_id,
-    //             (now - flow.last_seen).secs
-    //         );
-    //     }
-    // }
-
     Ok(())
 }
 
@@ -1089,11 +1439,88 @@ where
                     analyzer,
                 )?;
             }
+            PluginResult::Derived(packets) => {
+                for derived in &packets {
+                    handle_derived(packet, ctx, derived, analyzer)?;
+                }
+            }
         }
     }
     Ok(())
 }
 
+/// Maximum nesting of derived packets (packets emitted while handling a derived packet)
+const MAX_DERIVED_DEPTH: usize = 4;
+
+/// Dispatch a packet emitted by a plugin, as if it was captured at the same time as `packet`
+fn handle_derived(
+    packet: &Packet,
+    ctx: &ParseContext,
+    derived: &DerivedPacket,
+    analyzer: &mut Analyzer,
+) -> Result<(), Error> {
+    if analyzer.derived_depth >= MAX_DERIVED_DEPTH {
+        warn!(
+            "Too many nested derived packets, dropping packet (idx={})",
+            ctx.pcap_index
+        );
+        return Ok(());
+    }
+    let (link_type, data) = match derived {
+        DerivedPacket::L2(data) => (Linktype::ETHERNET, PacketData::L2(data)),
+        DerivedPacket::L3(ethertype, data) => (Linktype::RAW, PacketData::L3(*ethertype, data)),
+        DerivedPacket::L4(t5, data) => (packet.link_type, PacketData::L4(t5.proto, data)),
+    };
+    let len = match derived {
+        DerivedPacket::L2(data) | DerivedPacket::L3(_, data) | DerivedPacket::L4(_, data) => {
+            data.len() as u32
+        }
+    };
+    let derived_packet = Packet {
+        interface: packet.interface,
+        ts: packet.ts,
+        link_type,
+        data,
+        caplen: len,
+        origlen: len,
+        pcap_index: packet.pcap_index,
+        chunk: None,
+    };
+    // headers of the current layer are in the data of the original packet
+    let headers = mem::take(&mut analyzer.headers);
+    analyzer.derived_depth += 1;
+    let res = match derived {
+        DerivedPacket::L2(data) => handle_l2(&derived_packet, ctx, data, analyzer),
+        DerivedPacket::L3(ethertype, data) => {
+            handle_l3(&derived_packet, ctx, data, EtherType(*ethertype), analyzer)
+        }
+        DerivedPacket::L4(t5, data) => {
+            let l3_info = L3Info {
+                l4_proto: t5.proto,
+                three_tuple: ThreeTuple {
+                    src: t5.src,
+                    dst: t5.dst,
+                    l4_proto: t5.proto,
+                },
+                ttl: 0,
+            };
+            handle_l4_common(
+                &derived_packet,
+                ctx,
+                &[],
+                &l3_info,
+                t5.src_port,
+                t5.dst_port,
+                Some(data),
+                analyzer,
+            )
+        }
+    };
+    analyzer.derived_depth -= 1;
+    analyzer.headers = headers;
+    res
+}
+
 /// Run plugins attached to the physical layer
 pub(crate) fn run_plugins_v2_physical<'a>(
     packet: &Packet,
@@ -1112,10 +1539,10 @@ pub(crate) fn run_plugins_v2_link<'a>(
     packet: &Packet,
     ctx: &ParseContext,
     linktype: LinkLayerType,
-    l2_payload: &'a [u8],
+    l2_data: &'a [u8],
     analyzer: &mut Analyzer,
 ) -> Result<(), Error> {
-    let cb = move |p: &mut dyn Plugin| p.handle_layer_link(packet, linktype as u16, l2_payload);
+    let cb = move |p: &mut dyn Plugin| p.handle_layer_link(packet, linktype as u16, l2_data);
     let layer = 2;
     let layer_filter = linktype as u16;
     run_plugins_v2(packet, ctx, layer, layer_filter, cb, analyzer)
@@ -1148,6 +1575,93 @@ fn run_plugins_v2_transport(
     run_plugins_v2(packet, ctx, layer, layer_filter, cb, analyzer)
 }
 
+/// Lookup the flow of a packet (or create it), and update it
+///
+/// Flows are expired first (if an expiration policy is configured), so a packet of an expired
+/// flow creates a new flow.
+fn lookup_or_insert_flow(
+    packet: &Packet,
+    five_tuple: &FiveTuple,
+    tcp_flags: u8,
+    analyzer: &mut Analyzer,
+) -> FlowID {
+    let now = packet.ts;
+    expire_flows(now, analyzer);
+    let flow_id = match analyzer.flows.lookup_flow(five_tuple) {
+        Some(id) => id,
+        None => {
+            // make room for the new flow (lookups match both directions, so the flow is new)
+            while analyzer.flows.is_full() {
+                match analyzer.flows.evict_lru() {
+                    Some(flow) => {
+                        debug!("Evicting flow {:x} (flow table full)", flow.flow_id);
+                        gen_event_flow_destroyed(&flow, analyzer);
+                    }
+                    None => break,
+                }
+            }
+            let (client_five_tuple, direction) = guess_flow_direction(five_tuple, tcp_flags);
+            let mut flow = Flow::with_timestamp(&client_five_tuple, now);
+            flow.direction = direction;
+            // flags of the first packet, for plugins handling new flows
+            flow.tcp_flags = tcp_flags;
+            gen_event_new_flow(&flow, &analyzer.registry);
+            analyzer.flows.insert_flow(five_tuple.clone(), flow)
+        }
+    };
+    analyzer
+        .flows
+        .update_flow(flow_id, five_tuple, now, packet.origlen, tcp_flags);
+    flow_id
+}
+
+/// Guess the direction of a new flow from its first packet
+///
+/// Returns the five-tuple of the client to the server, and how it was decided: by the TCP
+/// handshake (SYN sent by the client, SYN+ACK sent by the server), or by the ports if the flow was
+/// captured mid-stream (the server uses a well-known port, below 1024, and the client a port
+/// above). Otherwise, the first packet is assumed to be sent by the client.
+fn guess_flow_direction(five_tuple: &FiveTuple, tcp_flags: u8) -> (FiveTuple, FlowDirection) {
+    const TCP_SYN: u8 = 0x02;
+    const TCP_ACK: u8 = 0x10;
+    if five_tuple.proto == 6 && tcp_flags & TCP_SYN != 0 {
+        if tcp_flags & TCP_ACK != 0 {
+            return (five_tuple.get_reverse(), FlowDirection::TcpHandshake);
+        }
+        return (five_tuple.clone(), FlowDirection::TcpHandshake);
+    }
+    if matches!(five_tuple.proto, 6 | 17 | 132) {
+        if five_tuple.src_port < 1024 && five_tuple.dst_port >= 1024 {
+            return (five_tuple.get_reverse(), FlowDirection::WellKnownPort);
+        }
+        if five_tuple.dst_port < 1024 && five_tuple.src_port >= 1024 {
+            return (five_tuple.clone(), FlowDirection::WellKnownPort);
+        }
+    }
+    (five_tuple.clone(), FlowDirection::FirstPacket)
+}
+
+/// Remove expired flows, at most once per second (of capture time)
+fn expire_flows(now: Duration, analyzer: &mut Analyzer) {
+    if !analyzer.flows.expiration_policy().has_timeouts() || now < analyzer.next_expiration_check {
+        return;
+    }
+    analyzer.next_expiration_check = now + Duration::new(1, 0);
+    for flow in analyzer.flows.expire_flows(now) {
+        debug!("Expiring flow {:x}", flow.flow_id);
+        gen_event_flow_destroyed(&flow, analyzer);
+    }
+}
+
+/// Notify plugins that a flow is destroyed, and remove its TCP reassembly state
+fn gen_event_flow_destroyed(flow: &Flow, analyzer: &mut Analyzer) {
+    analyzer.tcp_defrag.m.remove(&flow.flow_id);
+    analyzer.registry.run_plugins(
+        |p| p.plugin_type() & PLUGIN_FLOW_DEL != 0,
+        |p| p.flow_destroyed(flow),
+    );
+}
+
 pub(crate) fn gen_event_new_flow(flow: &Flow, registry: &PluginRegistry) {
     // let start = ::std::time::Instant::now();
     registry.run_plugins(
@@ -1161,6 +1675,8 @@ pub(crate) fn gen_event_new_flow(flow: &Flow, registry: &PluginRegistry) {
 impl PcapAnalyzer for Analyzer {
     /// Initialize all plugins
     fn init(&mut self) -> Result<(), Error> {
+        // flows of the previous run are kept until now, see `flow_table`
+        self.flows.clear();
         self.registry.run_plugins(|_| true, |p| p.pre_process());
         Ok(())
     }
@@ -1196,6 +1712,18 @@ impl PcapAnalyzer for Analyzer {
         }
     }
 
+    /// Notify plugins of the new interface
+    fn handle_interface_added(
+        &mut self,
+        if_id: u32,
+        info: &InterfaceInfo,
+        _ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        self.registry
+            .run_plugins(|_| true, |p| p.handle_interface_added(if_id, info));
+        Ok(())
+    }
+
     /// Finalize analysis and notify plugins
     fn teardown(&mut self) {
         {
@@ -1215,23 +1743,86 @@ impl PcapAnalyzer for Analyzer {
             );
             // let elapsed = start.elapsed();
             // debug!("Time to run flow_destroyed {}.{}", elapsed.as_secs(), elapsed.as_millis());
-            self.flows.clear();
 
             self.registry.run_plugins(|_| true, |p| p.post_process());
 
-            if let Some(output_dir) = &self.output_dir {
-                self.registry.run_plugins(
-                    |_| true,
-                    |p| {
-                        let res = p.save_results(output_dir);
-                        if let Err(e) = res {
-                            warn!("error while saving results for {}: {}", p.name(), e);
-                        }
-                    },
-                );
-            }
+            self.save_plugin_results();
         };
     }
+
+    fn flow_table(&self) -> Option<&FlowTable> {
+        Some(&self.flows)
+    }
+
+    /// Save the flow table, and the state of plugins supporting checkpoints
+    ///
+    /// Defragmentation and TCP reassembly buffers are not saved: datagrams and TCP segments
+    /// pending at the checkpoint are lost when resuming.
+    fn save_state(&self) -> Result<serde_json::Value, Error> {
+        let mut plugins = serde_json::Map::new();
+        let mut unsupported = Vec::new();
+        self.registry.run_plugins(
+            |_| true,
+            |p| match p.save_state() {
+                Some(state) => {
+                    plugins.insert(p.name().to_owned(), state);
+                }
+                None => unsupported.push(p.name()),
+            },
+        );
+        if !unsupported.is_empty() && !self.checkpoint_warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Plugins without checkpoint support (their results will only cover the packets \
+                 read after resuming): {}",
+                unsupported.join(", ")
+            );
+        }
+        let flows = serde_json::to_value(self.flows.save_state())
+            .or(Err(Error::Checkpoint("could not serialize the flow table")))?;
+        Ok(serde_json::json!({
+            "flows": flows,
+            "next_expiration_check": self.next_expiration_check,
+            "plugins": plugins,
+        }))
+    }
+
+    fn restore_state(&mut self, state: &serde_json::Value) -> Result<(), Error> {
+        let flows = FlowTableState::deserialize(&state["flows"])
+            .or(Err(Error::Checkpoint("invalid flow table")))?;
+        self.flows.restore_state(flows);
+        self.next_expiration_check =
+            Duration::deserialize(&state["next_expiration_check"]).unwrap_or_default();
+        let mut result = Ok(());
+        self.registry.run_plugins(
+            |_| true,
+            |p| {
+                if let Some(plugin_state) = state["plugins"].get(p.name()) {
+                    if let Err(e) = p.restore_state(plugin_state) {
+                        warn!("error while restoring state of {}: {}", p.name(), e);
+                        result = Err(Error::Checkpoint("could not restore plugin state"));
+                    }
+                }
+            },
+        );
+        result
+    }
 }
 
-impl SafePcapAnalyzer for Analyzer {}
+impl SafePcapAnalyzer for Analyzer {
+    /// Refuse plugins which do not declare that they can run on several worker threads
+    fn check_parallel(&self) -> Result<(), Error> {
+        let mut plugins = Vec::new();
+        self.registry
+            .run_plugins(|p| !p.parallel_safe(), |p| plugins.push(p.name()));
+        if plugins.is_empty() {
+            return Ok(());
+        }
+        error!(
+            "Plugins which cannot run on several worker threads: {}",
+            plugins.join(", ")
+        );
+        Err(Error::Unsupported(
+            "Some plugins cannot run on several worker threads",
+        ))
+    }
+}
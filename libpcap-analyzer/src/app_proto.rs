@@ -0,0 +1,418 @@
+//! Lightweight detection of application protocols
+//!
+//! Detection and metadata extraction use the ports and the first payload only, so they are fast
+//! but less accurate than the parsers of the `rusticata` plugin.
+
+use libpcap_tools::FiveTuple;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"CONNECT ",
+    b"PATCH ",
+];
+
+/// Detect the application protocol, using the first payload of a flow
+///
+/// Returns the name of the protocol (`dns`, `tls`, `http`, `ssh`), or `None` if it is not
+/// recognized.
+pub fn detect_app_proto(five_tuple: &FiveTuple, data: &[u8]) -> Option<&'static str> {
+    if five_tuple.src_port == 53 || five_tuple.dst_port == 53 {
+        return Some("dns");
+    }
+    if five_tuple.proto != 6 {
+        return None;
+    }
+    if data.len() >= 2 && data[0] == 0x16 && data[1] == 0x03 {
+        // TLS handshake record
+        Some("tls")
+    } else if data.starts_with(b"SSH-") {
+        Some("ssh")
+    } else if data.starts_with(b"HTTP/") || HTTP_METHODS.iter().any(|m| data.starts_with(m)) {
+        Some("http")
+    } else {
+        None
+    }
+}
+
+/// Extract metadata from the first payload of a flow, sent by the client
+///
+/// `app_proto` is the detected protocol (see `detect_app_proto`). Returns a list of (key,
+/// value) pairs, for ex. `("http.host", "example.com")`:
+///
+/// - `dns.rrname`, `dns.rrtype`: first question of a DNS query
+/// - `http.method`, `http.uri`, `http.host`: HTTP request
+/// - `ssh.banner`: SSH identification string
+/// - `tls.sni`: server name of a TLS client hello
+pub fn app_metadata(
+    app_proto: &str,
+    five_tuple: &FiveTuple,
+    data: &[u8],
+) -> Vec<(&'static str, String)> {
+    let mut metadata = Vec::new();
+    match app_proto {
+        "dns" => {
+            // DNS messages over TCP are prefixed by their length
+            let data = if five_tuple.proto == 6 {
+                data.get(2..)
+            } else {
+                Some(data)
+            };
+            if let Some(msg) = data.and_then(parse_dns) {
+                metadata.push(("dns.rrname", msg.rrname));
+                metadata.push(("dns.rrtype", msg.rrtype.to_string()));
+            }
+        }
+        "http" => {
+            let mut lines = data
+                .split(|&b| b == b'\n')
+                .map(|l| String::from_utf8_lossy(l).trim_end().to_owned());
+            if let Some(request) = lines.next() {
+                let mut fields = request.split(' ');
+                if let (Some(method), Some(uri)) = (fields.next(), fields.next()) {
+                    metadata.push(("http.method", method.to_owned()));
+                    metadata.push(("http.uri", uri.to_owned()));
+                }
+            }
+            let host = lines.take_while(|l| !l.is_empty()).find_map(|l| {
+                let (name, value) = l.split_once(':')?;
+                if name.eq_ignore_ascii_case("host") {
+                    Some(value.trim().to_owned())
+                } else {
+                    None
+                }
+            });
+            if let Some(host) = host {
+                metadata.push(("http.host", host));
+            }
+        }
+        "ssh" => {
+            if let Some(banner) = data.split(|&b| b == b'\n').next() {
+                let banner = String::from_utf8_lossy(banner).trim_end().to_owned();
+                metadata.push(("ssh.banner", banner));
+            }
+        }
+        "tls" => {
+            if let Some(sni) = tls_sni(data) {
+                metadata.push(("tls.sni", sni));
+            }
+        }
+        _ => (),
+    }
+    metadata
+}
+
+/// Get the server name indication (SNI) from a TLS record containing a client hello
+fn tls_sni(data: &[u8]) -> Option<String> {
+    let be_u16 = |i: &[u8], offset: usize| {
+        i.get(offset..offset + 2)
+            .map(|b| usize::from(u16::from_be_bytes([b[0], b[1]])))
+    };
+    // record header (5 bytes), handshake header (4 bytes), client version and random
+    if data.get(5) != Some(&1) {
+        return None;
+    }
+    let mut offset = 5 + 4 + 2 + 32;
+    // session ID, cipher suites and compression methods
+    offset += 1 + *data.get(offset)? as usize;
+    offset += 2 + be_u16(data, offset)?;
+    offset += 1 + *data.get(offset)? as usize;
+    let ext_len = be_u16(data, offset)?;
+    let mut extensions = data.get(offset + 2..offset + 2 + ext_len)?;
+    while extensions.len() >= 4 {
+        let ext_type = be_u16(extensions, 0)?;
+        let len = be_u16(extensions, 2)?;
+        let ext_data = extensions.get(4..4 + len)?;
+        if ext_type == 0 {
+            // server name list: list length, name type (0 for host names), name length, name
+            let name_len = be_u16(ext_data, 3)?;
+            let name = ext_data.get(5..5 + name_len)?;
+            return Some(String::from_utf8_lossy(name).into_owned());
+        }
+        extensions = &extensions[4 + len..];
+    }
+    None
+}
+
+/// Header and first question of a DNS message
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct DnsMessage {
+    pub id: u16,
+    pub flags: u16,
+    pub rrname: String,
+    pub rrtype: u16,
+}
+
+/// Parse the header and first question of a DNS message (without the TCP length prefix)
+pub(crate) fn parse_dns(data: &[u8]) -> Option<DnsMessage> {
+    let be_u16 = |offset: usize| {
+        data.get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    let id = be_u16(0)?;
+    let flags = be_u16(2)?;
+    if be_u16(4)? == 0 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut offset = 12;
+    loop {
+        let len = *data.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // names in questions are not compressed
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = data.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += len;
+    }
+    let rrtype = be_u16(offset)?;
+    let rrname = if labels.is_empty() {
+        "<root>".to_owned()
+    } else {
+        labels.join(".")
+    };
+    Some(DnsMessage {
+        id,
+        flags,
+        rrname,
+        rrtype,
+    })
+}
+
+/// Read a (possibly compressed) name in a DNS message, and return it with the offset after the
+/// name
+fn dns_name(data: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // limit the number of pointers, to avoid loops
+    for _ in 0..32 {
+        loop {
+            let len = *data.get(offset)? as usize;
+            if len & 0xc0 == 0xc0 {
+                let pointer = (len & 0x3f) << 8 | *data.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+                break;
+            }
+            offset += 1;
+            if len == 0 {
+                let name = if labels.is_empty() {
+                    "<root>".to_owned()
+                } else {
+                    labels.join(".")
+                };
+                return Some((name, end.unwrap_or(offset)));
+            }
+            let label = data.get(offset..offset + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += len;
+        }
+    }
+    None
+}
+
+/// Get the addresses of the A and AAAA records in the answer section of a DNS response
+/// (without the TCP length prefix), with the names of the records
+pub(crate) fn dns_answer_addrs(data: &[u8]) -> Vec<(String, IpAddr)> {
+    let mut addrs = Vec::new();
+    let be_u16 = |offset: usize| {
+        data.get(offset..offset + 2)
+            .map(|b| usize::from(u16::from_be_bytes([b[0], b[1]])))
+    };
+    let (questions, answers) = match (be_u16(2), be_u16(4), be_u16(6)) {
+        // QR bit: response
+        (Some(flags), Some(qd), Some(an)) if flags & 0x8000 != 0 => (qd, an),
+        _ => return addrs,
+    };
+    let mut offset = 12;
+    for _ in 0..questions {
+        match dns_name(data, offset) {
+            Some((_, next)) => offset = next + 4,
+            None => return addrs,
+        }
+    }
+    for _ in 0..answers {
+        let (name, next) = match dns_name(data, offset) {
+            Some(name) => name,
+            None => break,
+        };
+        let (rrtype, rdlength) = match (be_u16(next), be_u16(next + 8)) {
+            (Some(rrtype), Some(rdlength)) => (rrtype, rdlength),
+            _ => break,
+        };
+        let rdata = match data.get(next + 10..next + 10 + rdlength) {
+            Some(rdata) => rdata,
+            None => break,
+        };
+        match (rrtype, rdata.len()) {
+            (1, 4) => {
+                let addr = <[u8; 4]>::try_from(rdata).map(Ipv4Addr::from);
+                addrs.extend(addr.ok().map(|a| (name, IpAddr::V4(a))));
+            }
+            (28, 16) => {
+                let addr = <[u8; 16]>::try_from(rdata).map(Ipv6Addr::from);
+                addrs.extend(addr.ok().map(|a| (name, IpAddr::V6(a))));
+            }
+            _ => (),
+        }
+        offset = next + 10 + rdlength;
+    }
+    addrs
+}
+
+/// Name of a DNS record type, or its value if unknown
+pub(crate) fn dns_rrtype_name(rrtype: u16) -> String {
+    let name = match rrtype {
+        1 => "A",
+        2 => "NS",
+        5 => "CNAME",
+        6 => "SOA",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        33 => "SRV",
+        65 => "HTTPS",
+        255 => "ANY",
+        _ => return rrtype.to_string(),
+    };
+    name.to_owned()
+}
+
+/// Name of a DNS response code, or its value if unknown
+pub(crate) fn dns_rcode_name(rcode: u16) -> String {
+    let name = match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => return rcode.to_string(),
+    };
+    name.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{app_metadata, detect_app_proto, dns_answer_addrs};
+    use libpcap_tools::FiveTuple;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn app_proto_test() {
+        let mut five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: 49152,
+            dst_port: 8080,
+        };
+        assert_eq!(
+            detect_app_proto(&five_tuple, b"GET / HTTP/1.1\r\n"),
+            Some("http")
+        );
+        assert_eq!(
+            detect_app_proto(&five_tuple, b"SSH-2.0-OpenSSH"),
+            Some("ssh")
+        );
+        assert_eq!(
+            detect_app_proto(&five_tuple, b"\x16\x03\x01\x02\x00"),
+            Some("tls")
+        );
+        assert_eq!(detect_app_proto(&five_tuple, b"\x00\x01"), None);
+        five_tuple.dst_port = 53;
+        assert_eq!(detect_app_proto(&five_tuple, b"\x00\x01"), Some("dns"));
+        // HTTP is only detected over TCP
+        five_tuple.proto = 17;
+        five_tuple.dst_port = 8080;
+        assert_eq!(detect_app_proto(&five_tuple, b"GET / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn app_metadata_test() {
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: 49152,
+            dst_port: 80,
+        };
+        let request = b"GET /index.html HTTP/1.1\r\nHOST: example.com\r\n\r\n";
+        let metadata = app_metadata("http", &five_tuple, request);
+        assert_eq!(
+            metadata,
+            vec![
+                ("http.method", "GET".to_owned()),
+                ("http.uri", "/index.html".to_owned()),
+                ("http.host", "example.com".to_owned()),
+            ]
+        );
+        // client hello with an empty session ID, one cipher suite, no compression, and a SNI
+        // extension
+        let mut hello = vec![
+            0x16, 0x03, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x03,
+        ];
+        hello.extend_from_slice(&[0; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&[0x00, 0x10, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x0a, 0x00]);
+        hello.extend_from_slice(&[0x00, 0x07]);
+        hello.extend_from_slice(b"a.b.com");
+        let metadata = app_metadata("tls", &five_tuple, &hello);
+        assert_eq!(metadata, vec![("tls.sni", "a.b.com".to_owned())]);
+        assert!(app_metadata("tls", &five_tuple, &hello[..50]).is_empty());
+    }
+
+    #[test]
+    fn dns_answer_addrs_test() {
+        // response for example.com with a CNAME, an A and a AAAA record (compressed names)
+        let mut response = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+        ];
+        response.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        // CNAME www.example.com -> example.com
+        response.extend_from_slice(b"\x03www\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x02");
+        response.extend_from_slice(&[0xc0, 0x0c]);
+        response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0x3c, 0, 4]);
+        response.extend_from_slice(&[93, 184, 216, 34]);
+        response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x1c, 0x00, 0x01, 0, 0, 0, 0x3c, 0, 16]);
+        response.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        response.extend_from_slice(&[0; 11]);
+        response.push(1);
+        let addrs = dns_answer_addrs(&response);
+        assert_eq!(
+            addrs,
+            vec![
+                (
+                    "example.com".to_owned(),
+                    IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))
+                ),
+                (
+                    "example.com".to_owned(),
+                    "2001:db8::1".parse().expect("invalid address")
+                ),
+            ]
+        );
+        // queries and truncated responses have no addresses
+        response[2] = 0x01;
+        assert!(dns_answer_addrs(&response).is_empty());
+        response[2] = 0x81;
+        assert_eq!(dns_answer_addrs(&response[..response.len() - 1]).len(), 1);
+        // pointer loop
+        let mut looped = response[..12].to_vec();
+        looped[5] = 0;
+        looped.extend_from_slice(&[0xc0, 0x0c]);
+        assert!(dns_answer_addrs(&looped).is_empty());
+    }
+}
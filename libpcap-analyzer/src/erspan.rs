@@ -1,4 +1,14 @@
-use pnet_macros_support::types::{u1, u10be, u12be, u3, u4};
+use pnet_macros_support::types::{u1, u10be, u12be, u3, u4, u5};
+
+/// ERSPAN version of Type II packets (GRE protocol type 0x88be)
+pub const ERSPAN_VERSION_II: u4 = 1;
+/// ERSPAN version of Type III packets (GRE protocol type 0x22eb)
+pub const ERSPAN_VERSION_III: u4 = 2;
+
+/// ERSPAN Type III frame type: Ethernet frame
+pub const ERSPAN_FRAME_ETHERNET: u5 = 0;
+/// ERSPAN Type III frame type: IP packet, without link layer
+pub const ERSPAN_FRAME_IP: u5 = 2;
 
 #[derive(PartialEq)]
 /// A structure enabling manipulation of on the wire packets
@@ -83,6 +93,26 @@ impl<'a> ErspanPacket<'a> {
         let b1 = (_self.packet[co + 1] as u10be) as u10be;
         b0 | b1
     }
+    /// Get the frame type field (Type III only).
+    #[inline]
+    pub fn get_frame_type(&self) -> u5 {
+        match self.packet.get(10) {
+            Some(b) if self.get_version() == ERSPAN_VERSION_III => (b >> 2) & 0b1_1111,
+            _ => ERSPAN_FRAME_ETHERNET,
+        }
+    }
+    /// Get the length of the header: 8 bytes for Type II, 12 bytes for Type III, or 20 bytes
+    /// if the Type III platform specific subheader is present.
+    #[inline]
+    pub fn get_header_length(&self) -> usize {
+        if self.get_version() != ERSPAN_VERSION_III {
+            return 8;
+        }
+        match self.packet.get(11) {
+            Some(b) if b & 0b1 != 0 => 20,
+            _ => 12,
+        }
+    }
 }
 impl<'a> ::pnet_macros_support::packet::Packet for ErspanPacket<'a> {
     #[inline]
@@ -93,7 +123,7 @@ impl<'a> ::pnet_macros_support::packet::Packet for ErspanPacket<'a> {
     #[cfg_attr(feature = "clippy", allow(used_underscore_binding))]
     fn payload(&self) -> &[u8] {
         let _self = self;
-        let start = 8;
+        let start = _self.get_header_length();
         let end = _self.packet.len();
         if _self.packet.len() <= start {
             return &[];
@@ -117,4 +147,17 @@ mod tests {
         assert_eq!(packet.get_span_id(), 100);
         assert_eq!(packet.payload(), &[0x12, 0x34]);
     }
+    #[test]
+    fn erspan_type3_test() {
+        // IP frame type, with platform specific subheader
+        const DATA: &[u8] = b"\x20\x17\x00\x64\x00\x00\x00\x00\x00\x00\x08\x01\
+            \x00\x00\x00\x00\x00\x00\x00\x00\x45\x00";
+        let packet = ErspanPacket::new(DATA).expect("ErspanPacket");
+        assert_eq!(packet.get_version(), super::ERSPAN_VERSION_III);
+        assert_eq!(packet.get_vlan(), 23);
+        assert_eq!(packet.get_span_id(), 100);
+        assert_eq!(packet.get_frame_type(), super::ERSPAN_FRAME_IP);
+        assert_eq!(packet.get_header_length(), 20);
+        assert_eq!(packet.payload(), &[0x45, 0x00]);
+    }
 }
@@ -0,0 +1,139 @@
+//! GPRS Tunnelling Protocol, user plane (GTP-U)
+//!
+//! See 3GPP TS 29.281
+
+use pnet_macros_support::types::{u1, u16be, u3, u32be};
+
+/// Message type of GTP-U packets carrying user data (G-PDU)
+pub const GTPU_MSG_G_PDU: u8 = 0xff;
+
+#[derive(PartialEq)]
+/// A structure enabling manipulation of on the wire packets
+pub struct GtpuPacket<'p> {
+    packet: ::pnet_macros_support::packet::PacketData<'p>,
+}
+
+impl<'a> GtpuPacket<'a> {
+    /// Constructs a new GTP-U packet. If the provided buffer is less than the minimum required
+    /// packet size, this will return None.
+    #[inline]
+    pub fn new(packet: &[u8]) -> Option<GtpuPacket> {
+        if packet.len() >= GtpuPacket::minimum_packet_size() {
+            use ::pnet_macros_support::packet::PacketData;
+            Some(GtpuPacket {
+                packet: PacketData::Borrowed(packet),
+            })
+        } else {
+            None
+        }
+    }
+    /// The minimum size (in bytes) a packet of this type can be. It's based on the total size
+    /// of the fixed-size fields.
+    #[inline]
+    pub const fn minimum_packet_size() -> usize {
+        8
+    }
+    /// Get the version (1 for GTP-U)
+    #[inline]
+    pub fn get_version(&self) -> u3 {
+        self.packet[0] >> 5
+    }
+    /// Get the protocol type flag (1 for GTP, 0 for GTP')
+    #[inline]
+    pub fn get_protocol_type(&self) -> u1 {
+        (self.packet[0] >> 4) & 0b1
+    }
+    /// Get the extension header flag
+    #[inline]
+    pub fn get_extension_flag(&self) -> u1 {
+        (self.packet[0] >> 2) & 0b1
+    }
+    /// Get the sequence number flag
+    #[inline]
+    pub fn get_sequence_flag(&self) -> u1 {
+        (self.packet[0] >> 1) & 0b1
+    }
+    /// Get the N-PDU number flag
+    #[inline]
+    pub fn get_npdu_flag(&self) -> u1 {
+        self.packet[0] & 0b1
+    }
+    /// Get the message type
+    #[inline]
+    pub fn get_message_type(&self) -> u8 {
+        self.packet[1]
+    }
+    /// Get the length of the payload, including the optional fields
+    #[inline]
+    pub fn get_length(&self) -> u16be {
+        ((self.packet[2] as u16be) << 8) | (self.packet[3] as u16be)
+    }
+    /// Get the Tunnel Endpoint Identifier (TEID)
+    #[inline]
+    pub fn get_teid(&self) -> u32be {
+        ((self.packet[4] as u32be) << 24)
+            | ((self.packet[5] as u32be) << 16)
+            | ((self.packet[6] as u32be) << 8)
+            | (self.packet[7] as u32be)
+    }
+    /// Get the length of the header, including the optional fields and extension headers
+    fn header_length(&self) -> usize {
+        let flags = self.packet[0] & 0b0111;
+        if flags == 0 {
+            return 8;
+        }
+        // sequence number, N-PDU number and next extension header type are present if any
+        // flag is set
+        let mut offset = 12;
+        if self.get_extension_flag() == 0 || self.packet.len() < offset {
+            return offset;
+        }
+        let mut next_type = self.packet[offset - 1];
+        // each extension header has its length (in 4-octet units) as first byte, and the type
+        // of the next header as last byte
+        while next_type != 0 && offset < self.packet.len() {
+            let ext_len = self.packet[offset] as usize * 4;
+            if ext_len == 0 || offset + ext_len > self.packet.len() {
+                return self.packet.len();
+            }
+            next_type = self.packet[offset + ext_len - 1];
+            offset += ext_len;
+        }
+        offset
+    }
+}
+
+impl<'a> ::pnet_macros_support::packet::Packet for GtpuPacket<'a> {
+    #[inline]
+    fn packet(&self) -> &[u8] {
+        &self.packet[..]
+    }
+    #[inline]
+    fn payload(&self) -> &[u8] {
+        let start = ::std::cmp::min(self.header_length(), self.packet.len());
+        &self.packet[start..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet_macros_support::packet::Packet;
+    #[test]
+    fn gtpu_test() {
+        // G-PDU, no optional fields
+        let data = b"\x30\xff\x00\x02\x00\x00\x00\x2a\x45\x00";
+        let packet = GtpuPacket::new(data).expect("GtpuPacket");
+        assert_eq!(packet.get_version(), 1);
+        assert_eq!(packet.get_protocol_type(), 1);
+        assert_eq!(packet.get_message_type(), GTPU_MSG_G_PDU);
+        assert_eq!(packet.get_length(), 2);
+        assert_eq!(packet.get_teid(), 42);
+        assert_eq!(packet.payload(), b"\x45\x00");
+        // G-PDU, with a PDU session container extension header
+        let data = b"\x34\xff\x00\x0a\x00\x00\x00\x2a\x00\x00\x00\x85\x01\x10\x01\x00\x45\x00";
+        let packet = GtpuPacket::new(data).expect("GtpuPacket");
+        assert_eq!(packet.get_extension_flag(), 1);
+        assert_eq!(packet.payload(), b"\x45\x00");
+    }
+}
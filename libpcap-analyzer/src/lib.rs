@@ -18,25 +18,34 @@ pub use plugin_registry::*;
 
 pub mod plugins;
 pub mod output;
+mod report;
+pub use report::*;
 
 mod analyzer;
 mod threaded_analyzer;
 pub use analyzer::*;
 pub use threaded_analyzer::*;
 
+mod app_proto;
 mod erspan;
 mod geneve;
-mod ip_defrag;
+mod gtpu;
 mod mpls;
 mod ppp;
 mod pppoe;
+mod sctp;
 mod tcp_reassembly;
+mod teredo;
 mod vxlan;
+pub use app_proto::*;
 pub use erspan::*;
 pub use geneve::*;
+pub use gtpu::*;
 pub use mpls::*;
 pub use ppp::*;
 pub use pppoe::*;
+pub use sctp::*;
+pub use teredo::*;
 pub use vxlan::*;
 
 pub mod toeplitz;
@@ -1,4 +1,5 @@
 use libpcap_tools::Config;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::Error;
 use std::path::PathBuf;
@@ -13,4 +14,63 @@ pub fn create_file<P: AsRef<str>>(base: &str, filename: P) -> Result<File, Error
     let mut path = PathBuf::from(base);
     path.push(filename.as_ref());
     File::create(path)
-}
\ No newline at end of file
+}
+
+/// Errors raised while writing the output of a plugin during the analysis
+///
+/// Only the first error is logged, to avoid flooding the logs. Plugins return
+/// `WriteErrors::result` from `save_results`, so failures are also reported at the end of
+/// the analysis.
+#[derive(Debug)]
+pub struct WriteErrors {
+    context: &'static str,
+    count: usize,
+}
+
+impl WriteErrors {
+    /// Create a new tracker, `context` prefixing the logged error (for ex. "eve: could not
+    /// write event")
+    pub fn new(context: &'static str) -> Self {
+        WriteErrors { context, count: 0 }
+    }
+
+    /// Record the result of a write operation
+    pub fn check<E: Display>(&mut self, res: Result<(), E>) {
+        if let Err(e) = res {
+            if self.count == 0 {
+                warn!("{}: {}", self.context, e);
+            }
+            self.count += 1;
+        }
+    }
+
+    /// Number of failed write operations
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// `Err` if any write operation failed
+    pub fn result(&self) -> Result<(), &'static str> {
+        if self.count > 0 {
+            Err("Could not write output")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_errors_result() {
+        let mut errors = WriteErrors::new("test: could not write");
+        errors.check::<String>(Ok(()));
+        assert!(errors.result().is_ok());
+        errors.check(Err("disk full"));
+        errors.check(Err("disk full"));
+        assert_eq!(errors.count(), 2);
+        assert!(errors.result().is_err());
+    }
+}
@@ -1,6 +1,81 @@
-use libpcap_tools::{FiveTuple, Flow};
+use libpcap_tools::{FiveTuple, Flow, InterfaceInfo};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
 
-pub struct PacketInfo<'l3, 'l4, 't, 'f> {
+/// Encapsulation of a packet, used to segment statistics
+///
+/// If a packet has several encapsulations (for ex. a VLAN inside a VXLAN tunnel), the innermost
+/// one is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Encapsulation {
+    /// 802.1Q VLAN ID
+    Vlan(u16),
+    /// MPLS label (top of the stack)
+    Mpls(u32),
+    /// VXLAN network identifier
+    Vxlan(u32),
+    /// GRE key
+    GreKey(u32),
+}
+
+impl fmt::Display for Encapsulation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encapsulation::Vlan(id) => write!(f, "vlan:{}", id),
+            Encapsulation::Mpls(label) => write!(f, "mpls:{}", label),
+            Encapsulation::Vxlan(vni) => write!(f, "vxlan:{}", vni),
+            Encapsulation::GreKey(key) => write!(f, "gre:{}", key),
+        }
+    }
+}
+
+/// Name of the segment of packets with encapsulation `encap` (`none` if not encapsulated)
+pub fn segment_name(encap: Option<Encapsulation>) -> String {
+    encap.map_or_else(|| "none".to_owned(), |e| e.to_string())
+}
+
+/// 802.1Q VLAN tag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VlanTag {
+    /// VLAN ID
+    pub id: u16,
+    /// Priority code point
+    pub priority: u8,
+}
+
+/// Raw headers of a packet, as found in the captured data
+///
+/// Headers are given as ranges in `data`. A header is `None` if the packet has none, or if it is
+/// not part of the captured data (for ex. the inner header of a fragmented tunnel packet, which
+/// is in a reassembly buffer).
+#[derive(Clone, Debug, Default)]
+pub struct PacketHeaders<'h> {
+    /// Captured data of the packet, as read from the input (starting with the link layer header,
+    /// if any)
+    pub data: &'h [u8],
+    /// Range of the innermost ethernet header (addresses and ethertype, VLAN tags excluded).
+    /// If it is not part of the captured data, this is the outermost ethernet header
+    pub l2_range: Option<Range<usize>>,
+    /// VLAN tags following the ethernet header, outermost first
+    pub vlan_tags: &'h [VlanTag],
+    /// Range of the innermost IP header (IPv4 options and IPv6 extension headers included)
+    pub l3_range: Option<Range<usize>>,
+}
+
+impl<'h> PacketHeaders<'h> {
+    /// Innermost ethernet header, see `l2_range`
+    pub fn l2_header(&self) -> Option<&'h [u8]> {
+        self.l2_range.clone().and_then(|r| self.data.get(r))
+    }
+
+    /// Innermost IP header, see `l3_range`
+    pub fn l3_header(&self) -> Option<&'h [u8]> {
+        self.l3_range.clone().and_then(|r| self.data.get(r))
+    }
+}
+
+pub struct PacketInfo<'l3, 'l4, 't, 'f, 'i, 'h> {
     /// The five-tuple for *this packet*
     pub five_tuple: &'t FiveTuple,
     /// true if this packet is in same direction as the first packet
@@ -11,8 +86,23 @@ pub struct PacketInfo<'l3, 'l4, 't, 'f> {
     pub l4_data: &'l3 [u8],
     /// L4 payload type
     pub l4_type: u8,
+    /// IPv4 TTL or IPv6 hop limit of the packet
+    pub ttl: u8,
     /// L4 payload, if protocol is known by core engine
     pub l4_payload: Option<&'l4 [u8]>,
     pub flow: Option<&'f Flow>,
     pub pcap_index: usize,
+    /// Number of tunnels (GRE, IP in IP, VXLAN, GTP-U, Teredo etc.) encapsulating this packet,
+    /// 0 if the packet is not tunneled
+    pub tunnel_depth: usize,
+    /// ERSPAN session ID, if the packet was received from an ERSPAN mirror session
+    pub erspan_session: Option<u16>,
+    /// Innermost encapsulation (VLAN, MPLS, VXLAN or GRE key) of the packet, if any
+    pub encapsulation: Option<Encapsulation>,
+    /// Capture interface of the packet (link type, name etc.), if defined
+    pub interface: Option<&'i InterfaceInfo>,
+    /// Raw L2 and L3 headers of the packet. Empty for reassembled TCP data
+    pub headers: PacketHeaders<'h>,
+    /// true if the packet was emitted by a plugin (see `DerivedPacket`)
+    pub derived: bool,
 }
@@ -1,8 +1,10 @@
 use crate::analyzer::L3Info;
 use crate::packet_info::PacketInfo;
 use crate::plugin_registry::PluginRegistry;
-use libpcap_tools::{Config, FiveTuple, Flow, Packet, ThreeTuple};
+use crate::report::PluginOutput;
+use libpcap_tools::{Config, FiveTuple, Flow, InterfaceInfo, Packet, ThreeTuple};
 use std::any::Any;
+use std::fmt;
 
 /// Result struct manipulated by all plugins
 ///
@@ -16,13 +18,47 @@ pub enum PluginResult<'a> {
     L3(&'a L3Info, &'a [u8]),
     /// Layer 4: 5-tuple and payload
     L4(FiveTuple, &'a [u8]),
+    /// Packets created by the plugin, dispatched to all plugins (see `DerivedPacket`)
+    Derived(Vec<DerivedPacket>),
+}
+
+/// Packet created by a plugin (for ex. a decapsulated tunnel payload, or decrypted TLS records)
+///
+/// Derived packets are dispatched by the analyzer like captured packets, with the timestamp,
+/// interface and index of the packet they are derived from. `PacketInfo::derived` is set for
+/// them. Derived packets can emit other derived packets, up to a fixed nesting depth.
+#[derive(Clone, Debug)]
+pub enum DerivedPacket {
+    /// Ethernet frame
+    L2(Vec<u8>),
+    /// Layer 3 packet, with its ethertype
+    L3(u16, Vec<u8>),
+    /// Layer 4 payload of a flow (handled like reassembled TCP data)
+    L4(FiveTuple, Vec<u8>),
 }
 
 #[derive(Debug)]
 pub enum PluginBuilderError {
     RegistrationFailed(&'static str),
+    /// The configuration of the plugin is invalid
+    InvalidConfig(String),
 }
 
+impl fmt::Display for PluginBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PluginBuilderError::RegistrationFailed(s) => {
+                write!(f, "plugin registration failed: {}", s)
+            }
+            PluginBuilderError::InvalidConfig(s) => {
+                write!(f, "invalid plugin configuration: {}", s)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PluginBuilderError {}
+
 impl From<&'static str> for PluginBuilderError {
     fn from(s: &'static str) -> Self {
         PluginBuilderError::RegistrationFailed(s)
@@ -43,6 +79,14 @@ pub trait PluginBuilder: Sync + Send {
         registry: &mut PluginRegistry,
         config: &Config,
     ) -> Result<(), PluginBuilderError>;
+    /// Check the configuration of the plugins, before any plugin is built.
+    ///
+    /// Plugins settings are usually stored in section `plugin.<name>` (see
+    /// `Config::plugin_config`). Errors should be reported using
+    /// `PluginBuilderError::InvalidConfig`.
+    fn validate_config(&self, _config: &Config) -> Result<(), PluginBuilderError> {
+        Ok(())
+    }
 }
 
 /// Indicates the plugin does not register any callback function
@@ -61,6 +105,12 @@ pub const PLUGIN_L4: u16 = 0b1000;
 pub const PLUGIN_FLOW_NEW: u16 = 0b0001_0000;
 /// Indicates the plugin registers for 'flow destroyed' events
 pub const PLUGIN_FLOW_DEL: u16 = 0b0010_0000;
+/// Indicates the plugin registers for TCP segments, before reassembly
+///
+/// Segments are sent to `handle_layer_transport`, with `l4_data` set to the TCP header and
+/// payload. Plugins registering for `PLUGIN_L4` only receive the reassembled data (with an empty
+/// `l4_data`).
+pub const PLUGIN_TCP_SEGMENTS: u16 = 0b0100_0000;
 
 /// Indicates the plugin register for all layers
 pub const PLUGIN_ALL: u16 = 0b1111_1111;
@@ -82,6 +132,15 @@ pub trait Plugin: Sync + Send {
         PLUGIN_ALL
     }
 
+    /// Can this plugin run in several instances, one on each worker thread of a parallel engine?
+    /// (default: `false`)
+    ///
+    /// Plugins must declare it explicitly. Plugins writing files while packets are handled (for
+    /// ex. event or flow logs) must not: all instances would write the same files.
+    fn parallel_safe(&self) -> bool {
+        false
+    }
+
     /// Plugin initialization function
     /// Called before processing a pcap file
     fn pre_process(&mut self) {}
@@ -98,8 +157,8 @@ pub trait Plugin: Sync + Send {
     }
 
     /// Callback function when layer 2 data is available
-    /// `data` is the raw ethernet data
-    /// `PLUGIN_L1` must be added to `plugin_type()` return
+    /// `data` is the raw ethernet frame, including the ethernet header
+    /// `PLUGIN_L2` must be added to `plugin_type()` return
     /// See crate::layers for possible linklayertype values
     fn handle_layer_link<'s, 'i>(
         &'s mut self,
@@ -135,6 +194,11 @@ pub trait Plugin: Sync + Send {
     ) -> PluginResult<'i> {
         PluginResult::None
     }
+    /// Callback function when a capture interface is defined
+    /// `if_id` is the interface ID used by packets (`Packet::interface`) until the next section
+    /// Called for all plugins
+    fn handle_interface_added(&mut self, _if_id: u32, _info: &InterfaceInfo) {}
+
     /// Callback function when a new flow is created
     /// `PLUGIN_FLOW_NEW` must be added to `plugin_type()` return
     fn flow_created(&mut self, _flow: &Flow) {}
@@ -147,10 +211,30 @@ pub trait Plugin: Sync + Send {
         None
     }
 
+    /// Get structured results, for the report of the analysis (see `Report`)
+    ///
+    /// Called after `post_process`.
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        None
+    }
+
     /// Save results to specified directory
     fn save_results(&mut self, _path: &str) -> Result<(), &'static str> {
         Ok(())
     }
+
+    /// Get the state of the plugin, to store it in a checkpoint
+    ///
+    /// Plugins returning `None` (the default) start from an empty state when an analysis is
+    /// resumed, so their results only cover the packets read after the checkpoint.
+    fn save_state(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Restore a state returned by `save_state`, when resuming from a checkpoint
+    fn restore_state(&mut self, _state: &serde_json::Value) -> Result<(), &'static str> {
+        Ok(())
+    }
 }
 
 /// Derives a plugin builder
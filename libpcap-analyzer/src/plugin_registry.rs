@@ -17,6 +17,46 @@ macro_rules! build_safeplugin {
     };
 }
 
+/// Selection and order of plugins, for ex. from a command-line option
+///
+/// Plugins are matched by name, using case-insensitive patterns: a plugin is matched by a pattern
+/// if its name contains it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PluginSelection {
+    /// Patterns of plugins to keep, in order of execution. If empty, all plugins are kept.
+    pub include: Vec<String>,
+    /// Patterns of plugins to remove
+    pub exclude: Vec<String>,
+}
+
+impl PluginSelection {
+    /// Parse a comma-separated list of patterns, for ex. `tls,flows,-basicstats`
+    ///
+    /// Patterns prefixed by `-` are excluded. Other patterns are kept, in the given order.
+    pub fn parse(s: &str) -> Self {
+        let mut selection = PluginSelection::default();
+        for pattern in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match pattern.strip_prefix('-') {
+                Some(p) => selection.exclude.push(p.to_lowercase()),
+                None => selection.include.push(pattern.to_lowercase()),
+            }
+        }
+        selection
+    }
+
+    /// Return the rank of plugin `name` in the selection, or `None` if not selected
+    pub fn rank(&self, name: &str) -> Option<usize> {
+        let name = name.to_lowercase();
+        if self.exclude.iter().any(|p| name.contains(p.as_str())) {
+            return None;
+        }
+        if self.include.is_empty() {
+            return Some(0);
+        }
+        self.include.iter().position(|p| name.contains(p.as_str()))
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Hash)]
 pub struct PluginInfo {
     pub layer: u8,
@@ -164,6 +204,45 @@ impl PluginRegistry {
         });
     }
 
+    /// Remove plugins which are not selected, and sort remaining plugins in the order of the
+    /// selection
+    ///
+    /// Plugins matching the same pattern keep their relative order. Note that plugin IDs are
+    /// invalidated.
+    pub fn apply_selection(&mut self, selection: &PluginSelection) {
+        let ranks: Vec<_> = self
+            .plugins_all
+            .iter()
+            .map(|p| selection.rank(p.lock().unwrap().name()))
+            .collect();
+        let rank_of = |plugin: &SafePlugin| {
+            self.plugins_all
+                .iter()
+                .position(|p| Arc::ptr_eq(p, plugin))
+                .and_then(|idx| ranks[idx])
+        };
+        let mut plugins_all: Vec<_> = self
+            .plugins_all
+            .iter()
+            .filter_map(|p| rank_of(p).map(|rank| (rank, p.clone())))
+            .collect();
+        plugins_all.sort_by_key(|(rank, _)| *rank);
+        let mut plugins = MultiMap::new();
+        for (info, v) in self.plugins.iter_all() {
+            let mut v: Vec<_> = v
+                .iter()
+                .filter_map(|p| rank_of(p).map(|rank| (rank, p.clone())))
+                .collect();
+            if v.is_empty() {
+                continue;
+            }
+            v.sort_by_key(|(rank, _)| *rank);
+            plugins.insert_many(info.clone(), v.into_iter().map(|(_, p)| p));
+        }
+        self.plugins_all = plugins_all.into_iter().map(|(_, p)| p).collect();
+        self.plugins = plugins;
+    }
+
     /// Register a layer for analysis, for the identified plugin
     ///
     /// `layer_filter` is a filter on the value relative to the layer: for L3,
@@ -216,3 +295,56 @@ impl PluginRegistry {
         self.plugins_all.iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Named(&'static str);
+
+    impl Plugin for Named {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+    }
+
+    fn names(registry: &PluginRegistry) -> Vec<&'static str> {
+        let mut v = Vec::new();
+        registry.run_plugins(|_| true, |p| v.push(p.name()));
+        v
+    }
+
+    #[test]
+    fn plugin_selection() {
+        let selection = PluginSelection::parse("tls, Flows,-basicstats");
+        assert_eq!(selection.include, vec!["tls", "flows"]);
+        assert_eq!(selection.exclude, vec!["basicstats"]);
+
+        let mut registry = PluginRegistry::new();
+        for &name in &["BasicStats", "FlowsInfo", "Rusticata", "TlsStats"] {
+            let id = registry.add_plugin(build_safeplugin!(Named(name)));
+            registry.register_layer(4, 0, id).expect("register plugin");
+        }
+        registry.apply_selection(&selection);
+        assert_eq!(names(&registry), vec!["TlsStats", "FlowsInfo"]);
+        let l4: Vec<_> = registry
+            .get_plugins_for_layer(4, 0)
+            .expect("L4 plugins")
+            .iter()
+            .map(|p| p.lock().unwrap().name())
+            .collect();
+        assert_eq!(l4, vec!["TlsStats", "FlowsInfo"]);
+    }
+
+    #[test]
+    fn plugin_selection_exclude_only() {
+        let selection = PluginSelection::parse("-stats");
+        let mut registry = PluginRegistry::new();
+        for &name in &["BasicStats", "FlowsInfo", "Rusticata", "TlsStats"] {
+            registry.add_plugin(build_safeplugin!(Named(name)));
+        }
+        registry.apply_selection(&selection);
+        assert_eq!(names(&registry), vec!["FlowsInfo", "Rusticata"]);
+        assert!(registry.get_plugins_for_layer(4, 0).is_none());
+    }
+}
@@ -1,15 +1,17 @@
 use crate::plugin::{Plugin, PluginResult};
 use crate::plugin_builder;
 use crate::output;
-use crate::packet_info::PacketInfo;
+use crate::packet_info::{segment_name, Encapsulation, PacketInfo};
 use crate::plugin::{PLUGIN_L3, PLUGIN_L4};
+use crate::report::PluginOutput;
 use indexmap::IndexMap;
-use libpcap_tools::{FiveTuple, FlowID, Packet, ThreeTuple};
-use serde::Serialize;
+use libpcap_tools::{Config, FiveTuple, FlowID, Packet, ThreeTuple};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::any::Any;
+use std::collections::BTreeMap;
 
-#[derive(Default, Serialize)]
+#[derive(Default, Serialize, Deserialize)]
 struct Stats {
     num_bytes : usize,
     num_packets : usize,
@@ -23,13 +25,34 @@ pub struct BasicStats {
 
     l3_conversations: IndexMap<ThreeTuple, Stats>,
     l4_conversations: IndexMap<FiveTuple, Stats>,
+
+    /// Also count packets by encapsulation (`segment_stats` in the global configuration)
+    segment: bool,
+    /// Statistics of each encapsulation (VLAN, MPLS, VXLAN, GRE key), computed at the transport
+    /// layer
+    segments: BTreeMap<Option<Encapsulation>, BasicStats>,
+}
+
+/// State saved in checkpoints
+#[derive(Deserialize)]
+struct BasicStatsState {
+    total_bytes_l3 : usize,
+    total_packets : usize,
+    l3_conversations: Vec<(ThreeTuple, Stats)>,
+    l4_conversations: Vec<(FiveTuple, Stats)>,
+    #[serde(default)]
+    segments: Vec<(Option<Encapsulation>, Value)>,
 }
 
-plugin_builder!(BasicStats, BasicStatsBuilder);
+plugin_builder!(BasicStats, BasicStatsBuilder, |config: &Config| BasicStats {
+    segment: config.get_bool("segment_stats").unwrap_or(false),
+    ..BasicStats::default()
+});
 
 impl Plugin for BasicStats {
     fn name(&self) -> &'static str { "BasicStats" }
     fn plugin_type(&self) -> u16 { PLUGIN_L3|PLUGIN_L4 }
+    fn parallel_safe(&self) -> bool { true }
 
     fn handle_layer_network<'s, 'i>(
         &'s mut self,
@@ -38,11 +61,7 @@ impl Plugin for BasicStats {
         t3: &'s ThreeTuple,
     ) -> PluginResult<'i> {
         // info!("BasicStats::handle_l3 (len {})", data.len());
-        let entry = self.l3_conversations.entry(t3.clone()).or_insert_with(Stats::default);
-        entry.num_bytes += data.len();
-        entry.num_packets += 1;
-        self.total_bytes_l3 += data.len();
-        self.total_packets += 1;
+        self.add_l3(t3, data.len());
         PluginResult::None
     }
 
@@ -51,12 +70,19 @@ impl Plugin for BasicStats {
         _packet: &'s Packet,
         pinfo: &PacketInfo,
     ) -> PluginResult<'i> {
-        let entry = self.l4_conversations.entry(pinfo.five_tuple.clone()).or_insert_with(Stats::default);
-        entry.num_bytes += pinfo.l4_payload.map(|l4| l4.len()).unwrap_or(0);
-        if let Some(flow) = pinfo.flow {
-            entry.flow_id = Some(flow.flow_id);
+        self.add_l4(pinfo);
+        if self.segment {
+            // the encapsulation is not known at the network layer, so network counters of
+            // segments are also updated here
+            let segment = self.segments.entry(pinfo.encapsulation).or_insert_with(BasicStats::default);
+            // reassembled data has no L4 data, and was already counted
+            if !pinfo.l4_data.is_empty() {
+                let t5 = pinfo.five_tuple;
+                let t3 = ThreeTuple { src: t5.src, dst: t5.dst, l4_proto: t5.proto };
+                segment.add_l3(&t3, pinfo.l4_data.len());
+            }
+            segment.add_l4(pinfo);
         }
-        entry.num_packets += 1;
         PluginResult::None
     }
 
@@ -65,6 +91,10 @@ impl Plugin for BasicStats {
         Some(Box::new(v))
     }
 
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
     fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
         let results = self.get_results_json();
         // save data to file
@@ -73,9 +103,56 @@ impl Plugin for BasicStats {
         serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
         Ok(())
     }
+
+    fn save_state(&self) -> Option<Value> {
+        let l3 : Vec<_> = self.l3_conversations.iter().collect();
+        let l4 : Vec<_> = self.l4_conversations.iter().collect();
+        let segments : Vec<_> = self.segments.iter()
+            .map(|(encap, segment)| (encap, segment.save_state()))
+            .collect();
+        Some(json!({
+            "total_bytes_l3": self.total_bytes_l3,
+            "total_packets": self.total_packets,
+            "l3_conversations": l3,
+            "l4_conversations": l4,
+            "segments": segments,
+        }))
+    }
+
+    fn restore_state(&mut self, state: &Value) -> Result<(), &'static str> {
+        let state = BasicStatsState::deserialize(state).or(Err("Invalid BasicStats state"))?;
+        self.total_bytes_l3 = state.total_bytes_l3;
+        self.total_packets = state.total_packets;
+        self.l3_conversations = state.l3_conversations.into_iter().collect();
+        self.l4_conversations = state.l4_conversations.into_iter().collect();
+        self.segments.clear();
+        for (encap, state) in state.segments {
+            let mut segment = BasicStats::default();
+            segment.restore_state(&state)?;
+            self.segments.insert(encap, segment);
+        }
+        Ok(())
+    }
 }
 
 impl BasicStats {
+    fn add_l3(&mut self, t3: &ThreeTuple, len: usize) {
+        let entry = self.l3_conversations.entry(t3.clone()).or_insert_with(Stats::default);
+        entry.num_bytes += len;
+        entry.num_packets += 1;
+        self.total_bytes_l3 += len;
+        self.total_packets += 1;
+    }
+
+    fn add_l4(&mut self, pinfo: &PacketInfo) {
+        let entry = self.l4_conversations.entry(pinfo.five_tuple.clone()).or_insert_with(Stats::default);
+        entry.num_bytes += pinfo.l4_payload.map(|l4| l4.len()).unwrap_or(0);
+        if let Some(flow) = pinfo.flow {
+            entry.flow_id = Some(flow.flow_id);
+        }
+        entry.num_packets += 1;
+    }
+
     fn get_results_json(&mut self) -> Value {
         self.l3_conversations.sort_keys();
         self.l4_conversations.sort_keys();
@@ -108,13 +185,19 @@ impl BasicStats {
                 }
             })
             .collect();
-        let js = json!({
+        let mut js = json!({
             "total_l3": self.total_bytes_l3,
             "total_l3_packets": self.total_packets,
             "l3": l3,
             "total_l4": total_l4,
             "l4": l4,
         });
+        if self.segment {
+            let segments : serde_json::Map<_, _> = self.segments.iter_mut()
+                .map(|(encap, segment)| (segment_name(*encap), segment.get_results_json()))
+                .collect();
+            js["segments"] = Value::Object(segments);
+        }
         js
     }
 }
\ No newline at end of file
@@ -0,0 +1,413 @@
+//! Plugin validating IPv4, TCP, UDP, ICMP and ICMPv6 checksums
+//!
+//! Errors are counted by protocol, and by source address (the sender computes the checksums).
+//! The IPv4 header checksum is verified on the outer header of Ethernet (optionally VLAN tagged)
+//! and raw IP packets. L4 checksums are verified at every layer (tunneled packets included), on
+//! the reassembled datagram for fragmented packets. L4 checksums of truncated packets (captured
+//! length smaller than the original length) are not verified.
+//!
+//! When checksum computation is offloaded to the network card, packets sent by the capturing host
+//! are captured before their checksums are computed: the checksum field is 0, or contains the sum
+//! of the pseudo-header prepared by the operating system. These errors are counted separately,
+//! and hosts with mostly such errors are reported as `likely_offload`: their errors are probably
+//! an artifact of the capture, and not corrupted packets.
+
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_L3, PLUGIN_L4, PLUGIN_TCP_SEGMENTS};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::pcap_parser::data::PacketData;
+use libpcap_tools::{Packet, ThreeTuple};
+use pnet_packet::ip::IpNextHeaderProtocol;
+use pnet_packet::util::{checksum, ipv4_checksum, ipv6_checksum};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::any::Any;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Minimum ratio of offload-like errors for a host to be reported as using checksum offload
+const OFFLOAD_RATIO: f64 = 0.9;
+
+/// Result of a checksum verification
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Valid,
+    /// Invalid checksum. `offload` is true if the value is left by the OS for the network card
+    Invalid {
+        offload: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Counter {
+    checked: u64,
+    errors: u64,
+    offload_errors: u64,
+}
+
+impl Counter {
+    fn add(&mut self, status: Status) {
+        self.checked += 1;
+        if let Status::Invalid { offload } = status {
+            self.errors += 1;
+            if offload {
+                self.offload_errors += 1;
+            }
+        }
+    }
+}
+
+/// Fold a sum of 16 bits words
+fn fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Sum (not complemented) of the pseudo-header of a L4 segment of `len` bytes
+fn pseudo_header_sum(src: &IpAddr, dst: &IpAddr, proto: u8, len: usize) -> u16 {
+    let words = |addr: &IpAddr| -> u64 {
+        let octets = match addr {
+            IpAddr::V4(a) => a.octets().to_vec(),
+            IpAddr::V6(a) => a.octets().to_vec(),
+        };
+        octets
+            .chunks_exact(2)
+            .map(|w| u64::from(u16::from_be_bytes([w[0], w[1]])))
+            .sum()
+    };
+    fold(words(src) + words(dst) + u64::from(proto) + (len as u64 >> 16) + (len as u64 & 0xffff))
+}
+
+/// Verify the IPv4 header checksum. `header` is the IPv4 header, options included
+fn verify_ipv4_header(header: &[u8]) -> Status {
+    let value = u16::from_be_bytes([header[10], header[11]]);
+    if checksum(header, 5) == value {
+        Status::Valid
+    } else {
+        Status::Invalid {
+            offload: value == 0,
+        }
+    }
+}
+
+/// Verify the checksum of a L4 segment (TCP, UDP, ICMP or ICMPv6, header included)
+///
+/// Returns `None` if the segment has no checksum (UDP over IPv4), or is too short.
+fn verify_l4(src: &IpAddr, dst: &IpAddr, proto: u8, data: &[u8]) -> Option<Status> {
+    let (pos, pseudo_header) = match proto {
+        6 => (16, true),
+        17 => (6, true),
+        1 if src.is_ipv4() => (2, false),
+        58 if src.is_ipv6() => (2, true),
+        _ => return None,
+    };
+    let value = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]);
+    if proto == 17 && src.is_ipv4() && value == 0 {
+        return None;
+    }
+    let skipword = pos / 2;
+    let expected = match (src, dst, pseudo_header) {
+        (_, _, false) => checksum(data, skipword),
+        (IpAddr::V4(s), IpAddr::V4(d), true) => {
+            ipv4_checksum(data, skipword, &[], s, d, IpNextHeaderProtocol(proto))
+        }
+        (IpAddr::V6(s), IpAddr::V6(d), true) => {
+            ipv6_checksum(data, skipword, &[], s, d, IpNextHeaderProtocol(proto))
+        }
+        _ => return None,
+    };
+    // 0 is transmitted as all ones for UDP
+    let expected = if proto == 17 && expected == 0 {
+        0xffff
+    } else {
+        expected
+    };
+    if expected == value {
+        return Some(Status::Valid);
+    }
+    let offload =
+        pseudo_header && (value == 0 || value == pseudo_header_sum(src, dst, proto, data.len()));
+    Some(Status::Invalid { offload })
+}
+
+/// Return the outer IPv4 header of a packet (Ethernet, with optional VLAN tags, or raw IP)
+fn outer_ipv4_header<'a>(data: &PacketData<'a>) -> Option<&'a [u8]> {
+    let l3 = match *data {
+        PacketData::L2(data) => {
+            let mut offset = 12;
+            loop {
+                let ethertype = u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]);
+                match ethertype {
+                    0x8100 | 0x88a8 => offset += 4,
+                    0x0800 => break &data[offset + 2..],
+                    _ => return None,
+                }
+            }
+        }
+        PacketData::L3(0x0800, data) => data,
+        _ => return None,
+    };
+    let header_len = usize::from(l3.first()? & 0x0f) * 4;
+    if l3[0] >> 4 != 4 || header_len < 20 {
+        return None;
+    }
+    l3.get(..header_len)
+}
+
+#[derive(Default)]
+pub struct Checksums {
+    ipv4: Counter,
+    tcp: Counter,
+    udp: Counter,
+    icmp: Counter,
+    icmpv6: Counter,
+    /// Errors of all protocols, by source address
+    hosts: FnvHashMap<IpAddr, Counter>,
+    /// Number of L4 segments not verified because the packet is truncated
+    truncated: u64,
+    /// Index of the last packet seen at the network layer (tunneled packets are seen several
+    /// times)
+    last_index: Option<usize>,
+}
+
+/// Counters of the plugin, saved in checkpoints
+#[derive(Serialize, Deserialize)]
+struct ChecksumsState {
+    ipv4: Counter,
+    tcp: Counter,
+    udp: Counter,
+    icmp: Counter,
+    icmpv6: Counter,
+    hosts: Vec<(IpAddr, Counter)>,
+    truncated: u64,
+}
+
+plugin_builder!(Checksums, ChecksumsBuilder);
+
+impl Plugin for Checksums {
+    fn name(&self) -> &'static str {
+        "Checksums"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L3 | PLUGIN_L4 | PLUGIN_TCP_SEGMENTS
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_network<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        _payload: &'i [u8],
+        t3: &'s ThreeTuple,
+    ) -> PluginResult<'i> {
+        if self.last_index == Some(packet.pcap_index) {
+            return PluginResult::None;
+        }
+        self.last_index = Some(packet.pcap_index);
+        if let Some(header) = outer_ipv4_header(&packet.data) {
+            // check that this is the header of the outer layer
+            let src = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+            if t3.src == IpAddr::V4(src) {
+                self.add(t3.src, Protocol::Ipv4, verify_ipv4_header(header));
+            }
+        }
+        PluginResult::None
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        // reassembled TCP data has no header
+        if pinfo.l4_data.is_empty() {
+            return PluginResult::None;
+        }
+        let protocol = match pinfo.l4_type {
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            1 => Protocol::Icmp,
+            58 => Protocol::Icmpv6,
+            _ => return PluginResult::None,
+        };
+        if packet.caplen < packet.origlen {
+            self.truncated += 1;
+            return PluginResult::None;
+        }
+        let t5 = pinfo.five_tuple;
+        if let Some(status) = verify_l4(&t5.src, &t5.dst, t5.proto, pinfo.l4_data) {
+            self.add(t5.src, protocol, status);
+        }
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "checksums.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+
+    fn save_state(&self) -> Option<Value> {
+        let state = ChecksumsState {
+            ipv4: self.ipv4,
+            tcp: self.tcp,
+            udp: self.udp,
+            icmp: self.icmp,
+            icmpv6: self.icmpv6,
+            hosts: self.hosts.iter().map(|(ip, c)| (*ip, *c)).collect(),
+            truncated: self.truncated,
+        };
+        serde_json::to_value(state).ok()
+    }
+
+    fn restore_state(&mut self, state: &Value) -> Result<(), &'static str> {
+        let state = ChecksumsState::deserialize(state).or(Err("Invalid Checksums state"))?;
+        self.ipv4 = state.ipv4;
+        self.tcp = state.tcp;
+        self.udp = state.udp;
+        self.icmp = state.icmp;
+        self.icmpv6 = state.icmpv6;
+        self.hosts = state.hosts.into_iter().collect();
+        self.truncated = state.truncated;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Ipv4,
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+}
+
+impl Checksums {
+    fn add(&mut self, src: IpAddr, protocol: Protocol, status: Status) {
+        let counter = match protocol {
+            Protocol::Ipv4 => &mut self.ipv4,
+            Protocol::Tcp => &mut self.tcp,
+            Protocol::Udp => &mut self.udp,
+            Protocol::Icmp => &mut self.icmp,
+            Protocol::Icmpv6 => &mut self.icmpv6,
+        };
+        counter.add(status);
+        self.hosts.entry(src).or_default().add(status);
+    }
+
+    fn get_results_json(&self) -> Value {
+        let mut hosts: Vec<_> = self.hosts.iter().filter(|(_, c)| c.errors > 0).collect();
+        hosts.sort_by(|(ip_a, a), (ip_b, b)| b.errors.cmp(&a.errors).then(ip_a.cmp(ip_b)));
+        let hosts: Vec<Value> = hosts
+            .iter()
+            .map(|(ip, c)| {
+                json!({
+                    "ip": ip,
+                    "checked": c.checked,
+                    "errors": c.errors,
+                    "offload_errors": c.offload_errors,
+                    "error_rate": c.errors as f64 / c.checked as f64,
+                    "likely_offload": c.offload_errors as f64 >= OFFLOAD_RATIO * c.errors as f64,
+                })
+            })
+            .collect();
+        json!({
+            "checksums": {
+                "ipv4": self.ipv4,
+                "tcp": self.tcp,
+                "udp": self.udp,
+                "icmp": self.icmp,
+                "icmpv6": self.icmpv6,
+                "truncated": self.truncated,
+                "hosts": hosts,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pseudo_header_sum, verify_ipv4_header, verify_l4, Checksums, Protocol, Status};
+    use crate::plugin::Plugin;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn checksums_verify() {
+        // IPv4 header, with a valid checksum
+        let mut header = vec![
+            0x45, 0x00, 0x00, 0x28, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0xa5, 0x74, 0xc0, 0xa8,
+            0x0a, 0x01, 0xc0, 0xa8, 0x0a, 0x0a,
+        ];
+        assert_eq!(verify_ipv4_header(&header), Status::Valid);
+        header[10..12].copy_from_slice(&[0, 0]);
+        assert_eq!(
+            verify_ipv4_header(&header),
+            Status::Invalid { offload: true }
+        );
+
+        let src = IpAddr::V4(Ipv4Addr::new(192, 168, 10, 1));
+        let dst = IpAddr::V4(Ipv4Addr::new(192, 168, 10, 10));
+        // SYN+ACK, with the checksum left by the OS (sum of the pseudo-header)
+        let mut segment = vec![
+            0x00, 0x50, 0xb0, 0x66, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x50, 0x12,
+            0xff, 0xff, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let partial = pseudo_header_sum(&src, &dst, 6, segment.len());
+        assert_eq!(partial, 0x9576);
+        segment[16..18].copy_from_slice(&partial.to_be_bytes());
+        assert_eq!(
+            verify_l4(&src, &dst, 6, &segment),
+            Some(Status::Invalid { offload: true })
+        );
+        // fix the checksum: complement of the sum of the pseudo-header and the segment
+        segment[16..18].copy_from_slice(&[0, 0]);
+        let mut sum = segment
+            .chunks_exact(2)
+            .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+            .sum::<u32>()
+            + u32::from(partial);
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        segment[16..18].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+        assert_eq!(verify_l4(&src, &dst, 6, &segment), Some(Status::Valid));
+        segment[16] ^= 0x01;
+        assert_eq!(
+            verify_l4(&src, &dst, 6, &segment),
+            Some(Status::Invalid { offload: false })
+        );
+
+        // UDP over IPv4 without checksum
+        let datagram = [0x00, 0x35, 0xc0, 0x00, 0x00, 0x08, 0x00, 0x00];
+        assert_eq!(verify_l4(&src, &dst, 17, &datagram), None);
+    }
+
+    #[test]
+    fn checksums_state() {
+        let src = IpAddr::V4(Ipv4Addr::new(192, 168, 10, 1));
+        let mut plugin = Checksums::default();
+        plugin.add(src, Protocol::Tcp, Status::Valid);
+        plugin.add(src, Protocol::Tcp, Status::Invalid { offload: true });
+        plugin.truncated = 1;
+        let state = plugin.save_state().expect("state");
+        let mut restored = Checksums::default();
+        restored.restore_state(&state).expect("restore state");
+        assert_eq!(restored.get_results_json(), plugin.get_results_json());
+    }
+}
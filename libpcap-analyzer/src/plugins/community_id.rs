@@ -3,6 +3,7 @@
 
 use crate::output;
 use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
 use libpcap_tools::{Config, FlowID};
 
 use crate::plugin::{Plugin, PluginBuilderError, PluginResult};
@@ -27,7 +28,7 @@ pub struct CommunityIDBuilder;
 impl crate::plugin::PluginBuilder for CommunityIDBuilder {
     fn name(&self) -> &'static str { "CommunityIDBuilder" }
     fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
-        let seed = config.get_usize("plugin.community_id.seed").unwrap_or(0) as u16;
+        let seed = config.plugin_config("community_id").get_usize("seed").unwrap_or(0) as u16;
         let plugin = CommunityID{
             seed,
             ids:IndexMap::new(),
@@ -37,6 +38,18 @@ impl crate::plugin::PluginBuilder for CommunityIDBuilder {
         registry.register_layer(4, 0, id)?;
         Ok(())
     }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("community_id");
+        match config.get_usize("seed") {
+            Some(seed) if seed > usize::from(u16::MAX) => Err(PluginBuilderError::InvalidConfig(
+                format!("community_id: seed {} is not a 16-bit value", seed),
+            )),
+            None if config.contains("seed") => Err(PluginBuilderError::InvalidConfig(
+                "community_id: seed must be a positive integer".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[inline]
@@ -100,6 +113,9 @@ impl Plugin for CommunityID {
     fn plugin_type(&self) -> u16 {
         PLUGIN_L4
     }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
 
     fn handle_layer_transport<'s, 'i>(
         &'s mut self,
@@ -118,6 +134,10 @@ impl Plugin for CommunityID {
         Some(Box::new(v))
     }
 
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
     fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
         let results = self.get_results_json();
         // save data to file
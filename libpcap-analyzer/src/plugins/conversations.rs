@@ -0,0 +1,314 @@
+//! Plugin aggregating traffic by endpoint and by conversation (top talkers)
+//!
+//! Packets and bytes are counted for each IP address, as source (sent) and destination
+//! (received), and for each pair of addresses (conversation), in both directions. This is the
+//! equivalent of the Endpoints and Conversations statistics of Wireshark.
+//!
+//! The report contains the top sources, destinations and conversations, by bytes (`top` in
+//! section `[plugin.conversations]`, default 10). If `matrix` is true, all conversations are
+//! also saved to `conversations.csv`.
+//!
+//! Packets are counted once, at their outermost IP layer, using their original length.
+
+use crate::output;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult, PLUGIN_L3};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, Packet, ThreeTuple};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+
+const DEFAULT_TOP: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+struct Counter {
+    packets: u64,
+    bytes: u64,
+}
+
+impl Counter {
+    fn add(&mut self, bytes: u32) {
+        self.packets += 1;
+        self.bytes += u64::from(bytes);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Endpoint {
+    ip: IpAddr,
+    sent: Counter,
+    received: Counter,
+    #[serde(serialize_with = "serialize_ts")]
+    first_seen: Duration,
+    #[serde(serialize_with = "serialize_ts")]
+    last_seen: Duration,
+}
+
+/// Conversation between two addresses, `a` being the lowest
+#[derive(Debug, Serialize)]
+struct Conversation {
+    a: IpAddr,
+    b: IpAddr,
+    a_to_b: Counter,
+    b_to_a: Counter,
+    #[serde(serialize_with = "serialize_ts")]
+    first_seen: Duration,
+    #[serde(serialize_with = "serialize_ts")]
+    last_seen: Duration,
+}
+
+impl Conversation {
+    fn bytes(&self) -> u64 {
+        self.a_to_b.bytes + self.b_to_a.bytes
+    }
+}
+
+pub struct Conversations {
+    /// Number of entries in top lists
+    top: usize,
+    /// Save all conversations to a CSV file
+    matrix: bool,
+    endpoints: FnvHashMap<IpAddr, Endpoint>,
+    conversations: FnvHashMap<(IpAddr, IpAddr), Conversation>,
+    /// Index of the last packet seen (tunneled packets are seen several times)
+    last_index: Option<usize>,
+}
+
+pub struct ConversationsBuilder;
+
+impl PluginBuilder for ConversationsBuilder {
+    fn name(&self) -> &'static str { "ConversationsBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let plugin_config = config.plugin_config("conversations");
+        let plugin = Conversations::new(
+            plugin_config.get_usize("top").unwrap_or(DEFAULT_TOP),
+            plugin_config.get_bool("matrix").unwrap_or(false),
+        );
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(3, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("conversations");
+        if config.contains("top") && config.get_usize("top").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "conversations: top must be a positive integer".to_owned(),
+            ));
+        }
+        if config.contains("matrix") && config.get_bool("matrix").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "conversations: matrix must be a boolean".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Conversations {
+    fn name(&self) -> &'static str {
+        "Conversations"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L3
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_network<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        _payload: &'i [u8],
+        t3: &'s ThreeTuple,
+    ) -> PluginResult<'i> {
+        if self.last_index == Some(packet.pcap_index) {
+            return PluginResult::None;
+        }
+        self.last_index = Some(packet.pcap_index);
+        self.add_packet(packet.ts, packet.origlen, t3.src, t3.dst);
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "conversations.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        if self.matrix {
+            let file = output::create_file(path, "conversations.csv")
+                .or(Err("Cannot create output file"))?;
+            let mut writer = BufWriter::new(file);
+            self.write_csv(&mut writer)
+                .and_then(|_| writer.flush())
+                .or(Err("Cannot save results to file"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Conversations {
+    fn new(top: usize, matrix: bool) -> Self {
+        Conversations {
+            top,
+            matrix,
+            endpoints: FnvHashMap::default(),
+            conversations: FnvHashMap::default(),
+            last_index: None,
+        }
+    }
+
+    fn add_packet(&mut self, ts: Duration, bytes: u32, src: IpAddr, dst: IpAddr) {
+        for &(ip, sent) in &[(src, true), (dst, false)] {
+            let endpoint = self.endpoints.entry(ip).or_insert_with(|| Endpoint {
+                ip,
+                sent: Counter::default(),
+                received: Counter::default(),
+                first_seen: ts,
+                last_seen: ts,
+            });
+            if sent {
+                endpoint.sent.add(bytes);
+            } else {
+                endpoint.received.add(bytes);
+            }
+            endpoint.last_seen = endpoint.last_seen.max(ts);
+        }
+        let (a, b) = if src <= dst { (src, dst) } else { (dst, src) };
+        let conversation = self
+            .conversations
+            .entry((a, b))
+            .or_insert_with(|| Conversation {
+                a,
+                b,
+                a_to_b: Counter::default(),
+                b_to_a: Counter::default(),
+                first_seen: ts,
+                last_seen: ts,
+            });
+        if src == a {
+            conversation.a_to_b.add(bytes);
+        } else {
+            conversation.b_to_a.add(bytes);
+        }
+        conversation.last_seen = conversation.last_seen.max(ts);
+    }
+
+    /// Get the `top` items with the highest key, in decreasing order (ties are sorted by address)
+    fn top<'a, T, K, F>(&self, items: impl Iterator<Item = &'a T>, key: F) -> Vec<&'a T>
+    where
+        T: 'a,
+        K: Ord,
+        F: Fn(&T) -> K,
+    {
+        let mut v: Vec<_> = items.collect();
+        v.sort_by(|a, b| key(b).cmp(&key(a)));
+        v.truncate(self.top);
+        v
+    }
+
+    fn write_csv<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(
+            w,
+            "host_a,host_b,packets_a_to_b,bytes_a_to_b,packets_b_to_a,bytes_b_to_a,first_seen,\
+             last_seen"
+        )?;
+        let mut conversations: Vec<_> = self.conversations.values().collect();
+        conversations.sort_by_key(|c| (c.a, c.b));
+        for c in conversations {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{}",
+                c.a,
+                c.b,
+                c.a_to_b.packets,
+                c.a_to_b.bytes,
+                c.b_to_a.packets,
+                c.b_to_a.bytes,
+                format_ts(c.first_seen),
+                format_ts(c.last_seen)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_results_json(&self) -> Value {
+        let top_sources = self.top(self.endpoints.values(), |e| (e.sent.bytes, e.ip));
+        let top_destinations = self.top(self.endpoints.values(), |e| (e.received.bytes, e.ip));
+        let top_conversations = self.top(self.conversations.values(), |c| (c.bytes(), c.a, c.b));
+        json!({
+            "conversations": {
+                "endpoints": self.endpoints.len(),
+                "conversations": self.conversations.len(),
+                "top_sources": top_sources,
+                "top_destinations": top_destinations,
+                "top_conversations": top_conversations,
+            }
+        })
+    }
+}
+
+fn format_ts(ts: Duration) -> String {
+    format!("{}.{:09}", ts.secs, ts.nanos)
+}
+
+fn serialize_ts<S: serde::Serializer>(ts: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format_ts(*ts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Conversations;
+    use libpcap_tools::Duration;
+    use std::net::IpAddr;
+
+    #[test]
+    fn conversations_top() {
+        let mut plugin = Conversations::new(2, true);
+        let ip = |s: &str| s.parse::<IpAddr>().expect("invalid address");
+        let (a, b, c) = (ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3"));
+        plugin.add_packet(Duration::new(1, 0), 1500, b, a);
+        plugin.add_packet(Duration::new(2, 0), 60, a, b);
+        plugin.add_packet(Duration::new(3, 0), 100, a, c);
+        plugin.add_packet(Duration::new(4, 0), 200, c, b);
+
+        let results = plugin.get_results_json();
+        let r = &results["conversations"];
+        assert_eq!(r["endpoints"], 3);
+        assert_eq!(r["conversations"], 3);
+        assert_eq!(r["top_sources"][0]["ip"], "10.0.0.2");
+        assert_eq!(r["top_sources"][0]["sent"]["bytes"], 1500);
+        assert_eq!(r["top_sources"][1]["ip"], "10.0.0.3");
+        assert_eq!(r["top_destinations"][0]["ip"], "10.0.0.1");
+        assert_eq!(r["top_conversations"].as_array().map(Vec::len), Some(2));
+        let top = &r["top_conversations"][0];
+        assert_eq!(top["a"], "10.0.0.1");
+        assert_eq!(top["a_to_b"]["bytes"], 60);
+        assert_eq!(top["b_to_a"]["bytes"], 1500);
+        assert_eq!(top["first_seen"], "1.000000000");
+        assert_eq!(top["last_seen"], "2.000000000");
+
+        let mut csv = Vec::new();
+        plugin.write_csv(&mut csv).expect("could not write CSV");
+        let csv = String::from_utf8(csv).expect("invalid CSV");
+        assert_eq!(csv.lines().count(), 4);
+        assert_eq!(
+            csv.lines().nth(1),
+            Some("10.0.0.1,10.0.0.2,1,60,1,1500,1.000000000,2.000000000")
+        );
+    }
+}
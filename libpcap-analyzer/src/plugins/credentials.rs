@@ -0,0 +1,690 @@
+//! Plugin detecting credentials sent in cleartext
+//!
+//! The reassembled TCP streams of the following protocols are scanned for credentials:
+//!
+//! - FTP (port 21) and POP3 (port 110): `USER` and `PASS` commands
+//! - IMAP (port 143): `LOGIN` command
+//! - SMTP (ports 25 and 587), POP3 and IMAP: `PLAIN` and `LOGIN` SASL authentications
+//! - HTTP: `Basic` authorization (and proxy authorization) headers
+//! - Telnet (port 23): lines typed after `login:` and `password:` prompts
+//!
+//! For each credential, the plugin reports the flow, the protocol, the username and the result of
+//! the authentication, if known. Passwords are redacted, unless `redact_passwords` is set to
+//! `false` in section `[plugin.credentials]`.
+
+use crate::app_proto::detect_app_proto;
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use base64ct::{Base64, Encoding};
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+
+/// Maximum length of a line, longer lines are ignored
+const MAX_LINE: usize = 4096;
+
+const TELNET_IAC: u8 = 255;
+const TELNET_SB: u8 = 250;
+const TELNET_SE: u8 = 240;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protocol {
+    Ftp,
+    Http,
+    Imap,
+    Pop3,
+    Smtp,
+    Telnet,
+}
+
+/// Next line expected from the client
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Expect {
+    Command,
+    /// SASL LOGIN: username, then password (base64)
+    LoginUser,
+    LoginPassword,
+    /// SASL PLAIN: initial response (base64)
+    Plain,
+    /// Telnet: line typed after a prompt
+    TelnetUser,
+    TelnetPassword,
+}
+
+#[derive(Debug, Serialize)]
+struct Credential {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    ts: String,
+    protocol: &'static str,
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    /// Result of the authentication, if known
+    success: Option<bool>,
+}
+
+struct CredentialsFlow {
+    flow_id: FlowID,
+    five_tuple: FiveTuple,
+    protocol: Protocol,
+    redact_passwords: bool,
+    /// Incomplete lines, by direction
+    lines: [Vec<u8>; 2],
+    expect: Expect,
+    /// Username waiting for its password
+    user: Option<String>,
+    /// Tag of the last IMAP authentication command
+    tag: String,
+    /// Index of the credential waiting for the server response
+    pending: Option<usize>,
+    credentials: Vec<Credential>,
+}
+
+pub struct Credentials {
+    redact_passwords: bool,
+    /// State of flows, `None` if the protocol is not supported
+    flows: FnvHashMap<FlowID, Option<CredentialsFlow>>,
+    /// Credentials of destroyed flows
+    results: Vec<Credential>,
+}
+
+pub struct CredentialsBuilder;
+
+impl PluginBuilder for CredentialsBuilder {
+    fn name(&self) -> &'static str { "CredentialsBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let redact_passwords = config
+            .plugin_config("credentials")
+            .get_bool("redact_passwords")
+            .unwrap_or(true);
+        let plugin = Credentials {
+            redact_passwords,
+            flows: FnvHashMap::default(),
+            results: Vec::new(),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("credentials");
+        if config.contains("redact_passwords") && config.get_bool("redact_passwords").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "credentials: redact_passwords must be a boolean".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Credentials {
+    fn name(&self) -> &'static str {
+        "Credentials"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if !data.is_empty() => (flow, data),
+            _ => return PluginResult::None,
+        };
+        let redact_passwords = self.redact_passwords;
+        let state = self.flows.entry(flow.flow_id).or_insert_with(|| {
+            Protocol::detect(&flow.five_tuple, pinfo.to_server, data).map(|protocol| {
+                CredentialsFlow::new(flow.flow_id, &flow.five_tuple, protocol, redact_passwords)
+            })
+        });
+        if let Some(state) = state {
+            state.update(packet.ts, data, pinfo.to_server);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(Some(state)) = self.flows.remove(&flow.flow_id) {
+            self.results.extend(state.credentials);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "credentials.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Credentials {
+    fn get_results_json(&self) -> Value {
+        // flows still active (if called before the end of the analysis)
+        let active = self
+            .flows
+            .values()
+            .flatten()
+            .flat_map(|state| &state.credentials);
+        let credentials: Vec<_> = self.results.iter().chain(active).collect();
+        json!({ "credentials": credentials })
+    }
+}
+
+impl Protocol {
+    /// Detect the protocol of a TCP flow, using the server port (or the first client payload)
+    fn detect(five_tuple: &FiveTuple, to_server: bool, data: &[u8]) -> Option<Self> {
+        if five_tuple.proto != 6 {
+            return None;
+        }
+        match five_tuple.dst_port {
+            21 => Some(Protocol::Ftp),
+            23 => Some(Protocol::Telnet),
+            25 | 587 => Some(Protocol::Smtp),
+            110 => Some(Protocol::Pop3),
+            143 => Some(Protocol::Imap),
+            _ if to_server && detect_app_proto(five_tuple, data) == Some("http") => {
+                Some(Protocol::Http)
+            }
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Ftp => "ftp",
+            Protocol::Http => "http",
+            Protocol::Imap => "imap",
+            Protocol::Pop3 => "pop3",
+            Protocol::Smtp => "smtp",
+            Protocol::Telnet => "telnet",
+        }
+    }
+}
+
+impl CredentialsFlow {
+    fn new(
+        flow_id: FlowID,
+        five_tuple: &FiveTuple,
+        protocol: Protocol,
+        redact_passwords: bool,
+    ) -> Self {
+        CredentialsFlow {
+            flow_id,
+            five_tuple: five_tuple.clone(),
+            protocol,
+            redact_passwords,
+            lines: Default::default(),
+            expect: Expect::Command,
+            user: None,
+            tag: String::new(),
+            pending: None,
+            credentials: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, ts: Duration, data: &[u8], to_server: bool) {
+        if self.protocol == Protocol::Telnet {
+            self.update_telnet(ts, data, to_server);
+            return;
+        }
+        let dir = if to_server { 0 } else { 1 };
+        let mut buf = std::mem::take(&mut self.lines[dir]);
+        buf.extend_from_slice(data);
+        let mut start = 0;
+        while let Some(pos) = buf[start..].iter().position(|&b| b == b'\n') {
+            let line = String::from_utf8_lossy(&buf[start..start + pos]);
+            let line = line.trim_end_matches('\r');
+            if to_server {
+                self.client_line(ts, line);
+            } else {
+                self.server_line(line);
+            }
+            start += pos + 1;
+        }
+        buf.drain(..start);
+        if buf.len() > MAX_LINE {
+            buf.clear();
+        }
+        self.lines[dir] = buf;
+    }
+
+    fn client_line(&mut self, ts: Duration, line: &str) {
+        match self.expect {
+            Expect::LoginUser => {
+                self.expect = Expect::LoginPassword;
+                self.user = decode_base64(line);
+                return;
+            }
+            Expect::LoginPassword => {
+                self.expect = Expect::Command;
+                if let (Some(user), Some(password)) = (self.user.take(), decode_base64(line)) {
+                    self.add(ts, user, password);
+                }
+                return;
+            }
+            Expect::Plain => {
+                self.expect = Expect::Command;
+                self.sasl_plain(ts, line);
+                return;
+            }
+            _ => (),
+        }
+        match self.protocol {
+            Protocol::Ftp | Protocol::Pop3 | Protocol::Smtp => {
+                let (command, args) = split_command(line);
+                if command.eq_ignore_ascii_case("USER") {
+                    self.user = Some(args.to_owned());
+                } else if command.eq_ignore_ascii_case("PASS") {
+                    if let Some(user) = self.user.take() {
+                        self.add(ts, user, args.to_owned());
+                    }
+                } else if command.eq_ignore_ascii_case("AUTH") {
+                    self.sasl(ts, args);
+                }
+            }
+            Protocol::Imap => {
+                let (tag, line) = split_command(line);
+                let (command, args) = split_command(line);
+                if command.eq_ignore_ascii_case("LOGIN") {
+                    let mut args = imap_strings(args).into_iter();
+                    if let (Some(user), Some(password)) = (args.next(), args.next()) {
+                        self.tag = tag.to_owned();
+                        self.add(ts, user, password);
+                    }
+                } else if command.eq_ignore_ascii_case("AUTHENTICATE") {
+                    self.tag = tag.to_owned();
+                    self.sasl(ts, args);
+                }
+            }
+            Protocol::Http => {
+                let (name, value) = match line.split_once(':') {
+                    Some(header) => header,
+                    None => return,
+                };
+                if !name.eq_ignore_ascii_case("authorization")
+                    && !name.eq_ignore_ascii_case("proxy-authorization")
+                {
+                    return;
+                }
+                let (scheme, value) = split_command(value.trim());
+                if !scheme.eq_ignore_ascii_case("basic") {
+                    return;
+                }
+                let decoded = decode_base64(value);
+                if let Some((user, password)) = decoded.as_ref().and_then(|d| d.split_once(':')) {
+                    self.add(ts, user.to_owned(), password.to_owned());
+                }
+            }
+            Protocol::Telnet => (),
+        }
+    }
+
+    /// Handle a SASL authentication command (mechanism and optional initial response)
+    fn sasl(&mut self, ts: Duration, args: &str) {
+        let (mechanism, initial_response) = split_command(args);
+        let initial_response = Some(initial_response).filter(|r| !r.is_empty());
+        if mechanism.eq_ignore_ascii_case("PLAIN") {
+            match initial_response {
+                Some(response) => self.sasl_plain(ts, response),
+                None => self.expect = Expect::Plain,
+            }
+        } else if mechanism.eq_ignore_ascii_case("LOGIN") {
+            match initial_response {
+                Some(response) => {
+                    self.user = decode_base64(response);
+                    self.expect = Expect::LoginPassword;
+                }
+                None => self.expect = Expect::LoginUser,
+            }
+        }
+    }
+
+    /// SASL PLAIN response: authorization identity, username and password, separated by NUL
+    fn sasl_plain(&mut self, ts: Duration, response: &str) {
+        let decoded = match decode_base64(response) {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let mut fields = decoded.split('\0').skip(1);
+        if let (Some(user), Some(password)) = (fields.next(), fields.next()) {
+            self.add(ts, user.to_owned(), password.to_owned());
+        }
+    }
+
+    /// Get the result of the pending authentication from a server response
+    fn server_line(&mut self, line: &str) {
+        let idx = match self.pending {
+            Some(idx) => idx,
+            None => return,
+        };
+        let success = match self.protocol {
+            Protocol::Ftp | Protocol::Smtp => match line.get(..3) {
+                Some("230") | Some("235") => Some(true),
+                Some(code) if code.starts_with('5') => Some(false),
+                _ => None,
+            },
+            Protocol::Pop3 if line.starts_with("+OK") => Some(true),
+            Protocol::Pop3 if line.starts_with("-ERR") => Some(false),
+            Protocol::Imap => {
+                let (tag, status) = split_command(line);
+                if tag == self.tag {
+                    Some(
+                        status
+                            .get(..2)
+                            .map_or(false, |s| s.eq_ignore_ascii_case("OK")),
+                    )
+                } else {
+                    None
+                }
+            }
+            Protocol::Http if line.starts_with("HTTP/") => {
+                let (_, status) = split_command(line);
+                Some(!status.starts_with("401") && !status.starts_with("407"))
+            }
+            _ => None,
+        };
+        if success.is_some() {
+            self.credentials[idx].success = success;
+            self.pending = None;
+        }
+    }
+
+    fn update_telnet(&mut self, ts: Duration, data: &[u8], to_server: bool) {
+        let text = strip_telnet_commands(data);
+        if !to_server {
+            let text = String::from_utf8_lossy(&text).to_ascii_lowercase();
+            let text = text.trim_end();
+            if text.ends_with("login:") || text.ends_with("username:") {
+                if let Some(idx) = self.pending.take() {
+                    // prompt displayed again after a failed login
+                    self.credentials[idx].success = Some(false);
+                }
+                self.expect = Expect::TelnetUser;
+                self.lines[0].clear();
+            } else if text.ends_with("password:") {
+                self.expect = Expect::TelnetPassword;
+                self.lines[0].clear();
+            } else if let Some(idx) = self.pending {
+                if text.contains("incorrect") || text.contains("failed") {
+                    self.credentials[idx].success = Some(false);
+                    self.pending = None;
+                } else if text.contains("last login")
+                    || text.ends_with('$')
+                    || text.ends_with('#')
+                    || text.ends_with('>')
+                {
+                    self.credentials[idx].success = Some(true);
+                    self.pending = None;
+                }
+            }
+            return;
+        }
+        if self.expect != Expect::TelnetUser && self.expect != Expect::TelnetPassword {
+            return;
+        }
+        // clients usually send one character per packet
+        for &b in &text {
+            match b {
+                b'\r' | b'\n' | 0 => {
+                    if self.lines[0].is_empty() {
+                        continue;
+                    }
+                    let line = String::from_utf8_lossy(&self.lines[0]).into_owned();
+                    self.lines[0].clear();
+                    if self.expect == Expect::TelnetUser {
+                        self.user = Some(line);
+                    } else if let Some(user) = self.user.take() {
+                        self.add(ts, user, line);
+                    }
+                    self.expect = Expect::Command;
+                    return;
+                }
+                // backspace and delete
+                0x08 | 0x7f => {
+                    self.lines[0].pop();
+                }
+                _ if self.lines[0].len() < MAX_LINE => self.lines[0].push(b),
+                _ => (),
+            }
+        }
+    }
+
+    fn add(&mut self, ts: Duration, username: String, password: String) {
+        let password = if self.redact_passwords {
+            None
+        } else {
+            Some(password)
+        };
+        self.credentials.push(Credential {
+            flow_id: self.flow_id,
+            five_tuple: self.five_tuple.clone(),
+            ts: format!("{}.{:09}", ts.secs, ts.nanos),
+            protocol: self.protocol.name(),
+            username,
+            password,
+            success: None,
+        });
+        self.pending = Some(self.credentials.len() - 1);
+    }
+}
+
+/// Split a line in a command (or tag) and its arguments
+fn split_command(line: &str) -> (&str, &str) {
+    match line.split_once(' ') {
+        Some((command, args)) => (command, args.trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Parse IMAP atoms and quoted strings
+fn imap_strings(args: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut chars = args.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ' ' {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => s.extend(chars.next()),
+                    _ => s.push(c),
+                }
+            }
+            strings.push(s);
+        } else {
+            let mut s = String::new();
+            while let Some(c) = chars.next_if(|&c| c != ' ') {
+                s.push(c);
+            }
+            strings.push(s);
+        }
+    }
+    strings
+}
+
+fn decode_base64(s: &str) -> Option<String> {
+    let decoded = Base64::decode_vec(s.trim()).ok()?;
+    Some(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Remove telnet commands and option negotiations
+fn strip_telnet_commands(data: &[u8]) -> Vec<u8> {
+    let mut text = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != TELNET_IAC {
+            text.push(data[i]);
+            i += 1;
+            continue;
+        }
+        i = match data.get(i + 1).copied() {
+            // escaped 0xff
+            Some(TELNET_IAC) => {
+                text.push(TELNET_IAC);
+                i + 2
+            }
+            // subnegotiation, until IAC SE
+            Some(TELNET_SB) => match data[i + 2..]
+                .windows(2)
+                .position(|w| w == [TELNET_IAC, TELNET_SE])
+            {
+                Some(pos) => i + 2 + pos + 2,
+                None => data.len(),
+            },
+            // WILL, WONT, DO, DONT and their option
+            Some(251..=254) => i + 3,
+            _ => i + 2,
+        };
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CredentialsFlow, Protocol};
+    use libpcap_tools::{Duration, FiveTuple};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn flow(protocol: Protocol, dst_port: u16, redact_passwords: bool) -> CredentialsFlow {
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: 49152,
+            dst_port,
+        };
+        let data = b"GET / HTTP/1.1\r\n";
+        assert_eq!(Protocol::detect(&five_tuple, true, data), Some(protocol));
+        CredentialsFlow::new(1, &five_tuple, protocol, redact_passwords)
+    }
+
+    fn exchange(state: &mut CredentialsFlow, messages: &[(bool, &[u8])]) {
+        for (i, (to_server, data)) in messages.iter().enumerate() {
+            state.update(Duration::new(i as u32, 0), data, *to_server);
+        }
+    }
+
+    #[test]
+    fn credentials_lines() {
+        let mut ftp = flow(Protocol::Ftp, 21, false);
+        exchange(
+            &mut ftp,
+            &[
+                (false, b"220 FTP server ready\r\n"),
+                (true, b"USER alice\r\n"),
+                (false, b"331 Password required\r\n"),
+                // command split in two segments
+                (true, b"PASS sec"),
+                (true, b"ret\r\n"),
+                (false, b"530 Login incorrect\r\n"),
+            ],
+        );
+        assert_eq!(ftp.credentials.len(), 1);
+        assert_eq!(ftp.credentials[0].username, "alice");
+        assert_eq!(ftp.credentials[0].password.as_deref(), Some("secret"));
+        assert_eq!(ftp.credentials[0].success, Some(false));
+
+        // AUTH LOGIN, "bob" and "pass"
+        let mut smtp = flow(Protocol::Smtp, 587, true);
+        exchange(
+            &mut smtp,
+            &[
+                (true, b"EHLO client\r\nAUTH LOGIN\r\n"),
+                (false, b"334 VXNlcm5hbWU6\r\n"),
+                (true, b"Ym9i\r\n"),
+                (true, b"cGFzcw==\r\n"),
+                (false, b"235 2.7.0 Authentication successful\r\n"),
+            ],
+        );
+        assert_eq!(smtp.credentials[0].username, "bob");
+        assert_eq!(smtp.credentials[0].password, None);
+        assert_eq!(smtp.credentials[0].success, Some(true));
+
+        let mut imap = flow(Protocol::Imap, 143, false);
+        exchange(
+            &mut imap,
+            &[
+                (true, b"a1 LOGIN \"carol\" \"p\\\"w\"\r\n"),
+                (
+                    false,
+                    b"* CAPABILITY IMAP4rev1\r\na1 NO [AUTHENTICATIONFAILED]\r\n",
+                ),
+                // AUTHENTICATE PLAIN, "dave" and "pw"
+                (true, b"a2 AUTHENTICATE PLAIN AGRhdmUAcHc=\r\n"),
+                (false, b"a2 OK done\r\n"),
+            ],
+        );
+        assert_eq!(imap.credentials[0].password.as_deref(), Some("p\"w"));
+        assert_eq!(imap.credentials[0].success, Some(false));
+        assert_eq!(imap.credentials[1].username, "dave");
+        assert_eq!(imap.credentials[1].success, Some(true));
+    }
+
+    #[test]
+    fn credentials_http_telnet() {
+        // "admin:admin"
+        let mut http = flow(Protocol::Http, 80, false);
+        exchange(
+            &mut http,
+            &[
+                (
+                    true,
+                    b"GET / HTTP/1.1\r\nAuthorization: Basic YWRtaW46YWRtaW4=\r\n\r\n",
+                ),
+                (false, b"HTTP/1.1 401 Unauthorized\r\n"),
+            ],
+        );
+        assert_eq!(http.credentials[0].username, "admin");
+        assert_eq!(http.credentials[0].password.as_deref(), Some("admin"));
+        assert_eq!(http.credentials[0].success, Some(false));
+
+        let mut telnet = flow(Protocol::Telnet, 23, false);
+        exchange(
+            &mut telnet,
+            &[
+                (
+                    false,
+                    b"\xff\xfd\x18\xff\xfa\x18\x01\xff\xf0Debian\r\nlogin: ",
+                ),
+                (true, b"r"),
+                (true, b"ooo\x7f"),
+                (true, b"t\r\n"),
+                (false, b"Password: "),
+                (true, b"toor\r\0"),
+                (false, b"Last login: Mon Jan  1\r\nroot@host:~# "),
+            ],
+        );
+        assert_eq!(telnet.credentials[0].username, "root");
+        assert_eq!(telnet.credentials[0].password.as_deref(), Some("toor"));
+        assert_eq!(telnet.credentials[0].success, Some(true));
+    }
+}
@@ -0,0 +1,392 @@
+//! Plugin following DHCP exchanges, and building a table of leases
+//!
+//! DHCP messages (UDP ports 67 and 68) are grouped in exchanges, using the transaction ID and
+//! the client hardware address (for ex. DISCOVER, OFFER, REQUEST, ACK).
+//!
+//! For each client (MAC address), the lease table contains the last address assigned by a
+//! server (in an ACK), the host name, the vendor class, and the fingerprint of the client: the
+//! list of options requested in the parameter request list (option 55), in order.
+
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+const DHCP_MAGIC_COOKIE: &[u8] = &[0x63, 0x82, 0x53, 0x63];
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+const DHCP_RELEASE: u8 = 7;
+
+/// Lease of a client
+#[derive(Serialize)]
+struct Lease {
+    mac: String,
+    /// Address assigned in the last ACK
+    ip: Option<Ipv4Addr>,
+    /// Address requested by the client (option 50)
+    requested_ip: Option<Ipv4Addr>,
+    hostname: Option<String>,
+    vendor_class: Option<String>,
+    /// Parameter request list (option 55), for ex. `1,3,6,15,31,33`
+    fingerprint: Option<String>,
+    server: Option<Ipv4Addr>,
+    /// Lease time, in seconds
+    lease_time: Option<u32>,
+    router: Option<Ipv4Addr>,
+    dns_servers: Vec<Ipv4Addr>,
+    /// Type of the last message
+    state: &'static str,
+    first_seen: String,
+    last_seen: String,
+}
+
+/// Messages with the same transaction ID and client
+#[derive(Serialize)]
+struct Exchange {
+    xid: String,
+    mac: String,
+    messages: Vec<&'static str>,
+    offered_ip: Option<Ipv4Addr>,
+    assigned_ip: Option<Ipv4Addr>,
+    /// `ACK` or `NAK`
+    result: Option<&'static str>,
+    start: String,
+    end: String,
+}
+
+/// DHCP message (header fields and options)
+struct Message<'a> {
+    /// 1 for requests (from clients), 2 for replies
+    op: u8,
+    xid: u32,
+    yiaddr: Ipv4Addr,
+    mac: String,
+    msg_type: u8,
+    options: Vec<(u8, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    fn option(&self, code: u8) -> Option<&'a [u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, v)| *v)
+    }
+
+    fn option_ip(&self, code: u8) -> Option<Ipv4Addr> {
+        self.option(code).and_then(|v| ipv4(v, 0))
+    }
+
+    fn option_string(&self, code: u8) -> Option<String> {
+        self.option(code)
+            .map(|v| String::from_utf8_lossy(v).trim_end_matches('\0').to_owned())
+    }
+}
+
+#[derive(Default)]
+pub struct Dhcp {
+    /// Number of messages, by type
+    messages: BTreeMap<&'static str, u64>,
+    /// Leases, by client MAC address
+    leases: BTreeMap<String, Lease>,
+    exchanges: Vec<Exchange>,
+    /// Index of exchanges, by transaction ID and client MAC address
+    exchange_index: FnvHashMap<(u32, String), usize>,
+}
+
+plugin_builder!(Dhcp, DhcpBuilder);
+
+impl Plugin for Dhcp {
+    fn name(&self) -> &'static str {
+        "Dhcp"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let five_tuple = pinfo.five_tuple;
+        if five_tuple.proto != 17 {
+            return PluginResult::None;
+        }
+        let is_dhcp_port = |port| port == 67 || port == 68;
+        if !is_dhcp_port(five_tuple.src_port) || !is_dhcp_port(five_tuple.dst_port) {
+            return PluginResult::None;
+        }
+        if let Some(msg) = pinfo.l4_payload.and_then(parse_message) {
+            self.handle_message(packet.ts, &msg);
+        }
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "dhcp.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Dhcp {
+    fn handle_message(&mut self, ts: Duration, msg: &Message) {
+        let type_name = message_type_name(msg.msg_type);
+        *self.messages.entry(type_name).or_insert(0) += 1;
+        let ts = format_ts(ts);
+
+        let exchanges = &mut self.exchanges;
+        let idx = *self
+            .exchange_index
+            .entry((msg.xid, msg.mac.clone()))
+            .or_insert_with(|| {
+                exchanges.push(Exchange {
+                    xid: format!("0x{:08x}", msg.xid),
+                    mac: msg.mac.clone(),
+                    messages: Vec::new(),
+                    offered_ip: None,
+                    assigned_ip: None,
+                    result: None,
+                    start: ts.clone(),
+                    end: String::new(),
+                });
+                exchanges.len() - 1
+            });
+        let exchange = &mut self.exchanges[idx];
+        exchange.messages.push(type_name);
+        exchange.end = ts.clone();
+        let yiaddr = Some(msg.yiaddr).filter(|ip| !ip.is_unspecified());
+        match msg.msg_type {
+            DHCP_OFFER => exchange.offered_ip = yiaddr,
+            DHCP_ACK => {
+                exchange.assigned_ip = yiaddr;
+                exchange.result = Some("ACK");
+            }
+            DHCP_NAK => exchange.result = Some("NAK"),
+            _ => (),
+        }
+
+        let lease = self.leases.entry(msg.mac.clone()).or_insert_with(|| Lease {
+            mac: msg.mac.clone(),
+            ip: None,
+            requested_ip: None,
+            hostname: None,
+            vendor_class: None,
+            fingerprint: None,
+            server: None,
+            lease_time: None,
+            router: None,
+            dns_servers: Vec::new(),
+            state: type_name,
+            first_seen: ts.clone(),
+            last_seen: String::new(),
+        });
+        lease.state = type_name;
+        lease.last_seen = ts;
+        if msg.op == 1 {
+            // client options
+            if let Some(ip) = msg.option_ip(50) {
+                lease.requested_ip = Some(ip);
+            }
+            if let Some(hostname) = msg.option_string(12).or_else(|| client_fqdn(msg)) {
+                lease.hostname = Some(hostname);
+            }
+            if let Some(vendor_class) = msg.option_string(60) {
+                lease.vendor_class = Some(vendor_class);
+            }
+            if let Some(params) = msg.option(55) {
+                let params: Vec<_> = params.iter().map(|p| p.to_string()).collect();
+                lease.fingerprint = Some(params.join(","));
+            }
+            if msg.msg_type == DHCP_RELEASE {
+                lease.ip = None;
+            }
+        } else if msg.msg_type == DHCP_ACK {
+            if yiaddr.is_some() {
+                lease.ip = yiaddr;
+            }
+            lease.server = msg.option_ip(54);
+            lease.lease_time = msg
+                .option(51)
+                .filter(|v| v.len() == 4)
+                .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]));
+            lease.router = msg.option_ip(3);
+            if let Some(servers) = msg.option(6) {
+                lease.dns_servers = (0..servers.len())
+                    .step_by(4)
+                    .filter_map(|o| ipv4(servers, o))
+                    .collect();
+            }
+        }
+    }
+
+    fn get_results_json(&self) -> Value {
+        let leases: Vec<_> = self.leases.values().collect();
+        json!({
+            "dhcp": {
+                "messages": self.messages,
+                "leases": leases,
+                "exchanges": self.exchanges,
+            }
+        })
+    }
+}
+
+fn parse_message(data: &[u8]) -> Option<Message> {
+    if data.get(236..240)? != DHCP_MAGIC_COOKIE {
+        return None;
+    }
+    let op = data[0];
+    // only ethernet addresses are supported
+    if data[1] != 1 || data[2] != 6 {
+        return None;
+    }
+    let xid = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let yiaddr = ipv4(data, 16)?;
+    let mac = data[28..34]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":");
+    let mut options = Vec::new();
+    let mut i = &data[240..];
+    while let Some((&code, rem)) = i.split_first() {
+        match code {
+            // pad
+            0 => i = rem,
+            // end
+            255 => break,
+            _ => {
+                let (&len, rem) = rem.split_first()?;
+                let value = rem.get(..usize::from(len))?;
+                options.push((code, value));
+                i = &rem[usize::from(len)..];
+            }
+        }
+    }
+    let msg_type = options
+        .iter()
+        .find(|(code, _)| *code == 53)
+        .and_then(|(_, v)| v.first().copied())?;
+    Some(Message {
+        op,
+        xid,
+        yiaddr,
+        mac,
+        msg_type,
+        options,
+    })
+}
+
+/// Client FQDN (option 81), if encoded in ASCII
+fn client_fqdn(msg: &Message) -> Option<String> {
+    let value = msg.option(81)?;
+    // the E flag indicates the canonical wire format
+    if value.len() <= 3 || value[0] & 0x04 != 0 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&value[3..]).into_owned())
+}
+
+fn ipv4(data: &[u8], offset: usize) -> Option<Ipv4Addr> {
+    let b = data.get(offset..offset + 4)?;
+    Some(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+}
+
+fn message_type_name(msg_type: u8) -> &'static str {
+    match msg_type {
+        DHCP_DISCOVER => "DISCOVER",
+        DHCP_OFFER => "OFFER",
+        DHCP_REQUEST => "REQUEST",
+        4 => "DECLINE",
+        DHCP_ACK => "ACK",
+        DHCP_NAK => "NAK",
+        DHCP_RELEASE => "RELEASE",
+        8 => "INFORM",
+        _ => "UNKNOWN",
+    }
+}
+
+fn format_ts(ts: Duration) -> String {
+    format!("{}.{:09}", ts.secs, ts.nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_message, Dhcp};
+    use libpcap_tools::Duration;
+
+    fn message(op: u8, yiaddr: [u8; 4], options: &[u8]) -> Vec<u8> {
+        let mut data = vec![op, 1, 6, 0, 0x12, 0x34, 0x56, 0x78];
+        data.resize(16, 0);
+        data.extend_from_slice(&yiaddr);
+        data.resize(28, 0);
+        data.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+        data.resize(236, 0);
+        data.extend_from_slice(&[0x63, 0x82, 0x53, 0x63]);
+        data.extend_from_slice(options);
+        data.push(255);
+        data
+    }
+
+    #[test]
+    fn dhcp_lease() {
+        let mut dhcp = Dhcp::default();
+        let discover = message(
+            1,
+            [0; 4],
+            b"\x35\x01\x01\x0c\x04host\x37\x04\x01\x03\x06\x0f",
+        );
+        let offer = message(2, [10, 0, 0, 5], b"\x35\x01\x02");
+        let request = message(1, [0; 4], b"\x35\x01\x03\x32\x04\x0a\x00\x00\x05\x00\x00");
+        let ack = message(
+            2,
+            [10, 0, 0, 5],
+            b"\x35\x01\x05\x36\x04\x0a\x00\x00\x01\x33\x04\x00\x00\x0e\x10\x03\x04\x0a\x00\x00\x01",
+        );
+        for (i, data) in [discover, offer, request, ack].iter().enumerate() {
+            let msg = parse_message(data).expect("DHCP message");
+            dhcp.handle_message(Duration::new(i as u32, 0), &msg);
+        }
+        let results = dhcp.get_results_json();
+        let results = &results["dhcp"];
+        let lease = &results["leases"][0];
+        assert_eq!(lease["mac"], "02:00:00:00:00:01");
+        assert_eq!(lease["ip"], "10.0.0.5");
+        assert_eq!(lease["hostname"], "host");
+        assert_eq!(lease["fingerprint"], "1,3,6,15");
+        assert_eq!(lease["lease_time"], 3600);
+        assert_eq!(lease["router"], "10.0.0.1");
+        let exchange = &results["exchanges"][0];
+        assert_eq!(exchange["xid"], "0x12345678");
+        assert_eq!(exchange["messages"].as_array().map(Vec::len), Some(4));
+        assert_eq!(exchange["result"], "ACK");
+        assert_eq!(results["messages"]["OFFER"], 1);
+    }
+}
@@ -0,0 +1,451 @@
+//! Plugin aggregating DNS queries and responses
+//!
+//! Statistics are computed for each question (name and record type): number of queries and
+//! responses, response codes, and latency. Responses are matched to queries using the flow and
+//! the transaction ID. Queries are also counted by client, to find the top talkers.
+//!
+//! A large number of distinct names, or a high NXDOMAIN rate, may indicate DNS tunneling or a
+//! misconfigured client.
+//!
+//! Only the first message of each TCP segment is parsed.
+//!
+//! If `segment_stats` is set in the global configuration, statistics are also computed for each
+//! encapsulation (VLAN, MPLS label, VXLAN or GRE key).
+
+use crate::app_proto::{dns_rcode_name, dns_rrtype_name, parse_dns, DnsMessage};
+use crate::packet_info::{segment_name, Encapsulation, PacketInfo};
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, FiveTuple, Flow, FlowID, Packet};
+use serde_json::{json, Map, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+
+/// Number of clients in the list of top talkers
+const TOP_TALKERS: usize = 10;
+
+/// DNS response code for non-existent domains
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// Minimum, average and maximum latency, in seconds
+#[derive(Default)]
+pub(super) struct Latency {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Latency {
+    pub(super) fn add(&mut self, latency: f64) {
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        if latency > self.max {
+            self.max = latency;
+        }
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    fn avg(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f64)
+        }
+    }
+
+    pub(super) fn to_json(&self) -> Value {
+        match self.avg() {
+            Some(avg) => json!({ "avg": avg, "min": self.min, "max": self.max }),
+            None => Value::Null,
+        }
+    }
+}
+
+/// Statistics of a question (name and record type)
+#[derive(Default)]
+struct NameStats {
+    queries: u64,
+    responses: u64,
+    rcodes: BTreeMap<u16, u64>,
+    latency: Latency,
+}
+
+impl NameStats {
+    fn nxdomain(&self) -> u64 {
+        self.rcodes.get(&RCODE_NXDOMAIN).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Default)]
+struct ClientStats {
+    queries: u64,
+    nxdomain: u64,
+}
+
+/// Query waiting for its response
+struct PendingQuery {
+    ts: Duration,
+    question: (String, u16),
+}
+
+#[derive(Default)]
+pub struct DnsStats {
+    queries: u64,
+    responses: u64,
+    /// Queries without response
+    unanswered: u64,
+    rcodes: BTreeMap<u16, u64>,
+    latency: Latency,
+    names: FnvHashMap<(String, u16), NameStats>,
+    clients: FnvHashMap<IpAddr, ClientStats>,
+    /// Queries waiting for a response, by flow and transaction ID
+    pending: FnvHashMap<(FlowID, u16), PendingQuery>,
+    /// Also compute statistics by encapsulation
+    segment: bool,
+    segments: BTreeMap<Option<Encapsulation>, DnsStats>,
+}
+
+plugin_builder!(DnsStats, DnsStatsBuilder, |config: &Config| DnsStats {
+    segment: config.get_bool("segment_stats").unwrap_or(false),
+    ..DnsStats::default()
+});
+
+impl Plugin for DnsStats {
+    fn name(&self) -> &'static str {
+        "DnsStats"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let five_tuple = pinfo.five_tuple;
+        if five_tuple.src_port != 53 && five_tuple.dst_port != 53 {
+            return PluginResult::None;
+        }
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) => (flow, data),
+            _ => return PluginResult::None,
+        };
+        // DNS messages over TCP are prefixed by their length
+        let msg = if pinfo.l4_type == 6 {
+            data.get(2..).and_then(parse_dns)
+        } else {
+            parse_dns(data)
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => return PluginResult::None,
+        };
+        if self.segment {
+            self.segments
+                .entry(pinfo.encapsulation)
+                .or_default()
+                .handle_message(flow.flow_id, packet.ts, five_tuple, msg.clone());
+        }
+        self.handle_message(flow.flow_id, packet.ts, five_tuple, msg);
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let before = self.pending.len();
+        self.pending
+            .retain(|(flow_id, _), _| *flow_id != flow.flow_id);
+        self.unanswered += (before - self.pending.len()) as u64;
+        for segment in self.segments.values_mut() {
+            segment.flow_destroyed(flow);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "dns-stats.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        let file =
+            output::create_file(path, "dns-names.csv").or(Err("Cannot create output file"))?;
+        self.write_names_csv(BufWriter::new(file))
+            .or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl DnsStats {
+    fn handle_message(
+        &mut self,
+        flow_id: FlowID,
+        ts: Duration,
+        five_tuple: &FiveTuple,
+        msg: DnsMessage,
+    ) {
+        let question = (msg.rrname, msg.rrtype);
+        if msg.flags & 0x8000 == 0 {
+            self.queries += 1;
+            self.names.entry(question.clone()).or_default().queries += 1;
+            self.clients.entry(five_tuple.src).or_default().queries += 1;
+            let query = PendingQuery { ts, question };
+            // a retransmitted query replaces the previous one
+            if self.pending.insert((flow_id, msg.id), query).is_some() {
+                self.unanswered += 1;
+            }
+        } else {
+            let rcode = msg.flags & 0xf;
+            self.responses += 1;
+            *self.rcodes.entry(rcode).or_insert(0) += 1;
+            if rcode == RCODE_NXDOMAIN {
+                self.clients.entry(five_tuple.dst).or_default().nxdomain += 1;
+            }
+            let stats = self.names.entry(question.clone()).or_default();
+            stats.responses += 1;
+            *stats.rcodes.entry(rcode).or_insert(0) += 1;
+            if let Some(query) = self.pending.remove(&(flow_id, msg.id)) {
+                if query.question == question && ts >= query.ts {
+                    let latency = duration_secs(ts - query.ts);
+                    stats.latency.add(latency);
+                    self.latency.add(latency);
+                }
+            }
+        }
+    }
+
+    /// Questions, sorted by decreasing number of queries
+    fn sorted_names(&self) -> Vec<(&(String, u16), &NameStats)> {
+        let mut names: Vec<_> = self.names.iter().collect();
+        names.sort_by(|a, b| b.1.queries.cmp(&a.1.queries).then_with(|| a.0.cmp(b.0)));
+        names
+    }
+
+    fn get_results_json(&self) -> Value {
+        let nxdomain = self.rcodes.get(&RCODE_NXDOMAIN).copied().unwrap_or(0);
+        let names: Vec<_> = self
+            .sorted_names()
+            .into_iter()
+            .map(|((rrname, rrtype), stats)| {
+                json!({
+                    "rrname": rrname,
+                    "rrtype": dns_rrtype_name(*rrtype),
+                    "queries": stats.queries,
+                    "responses": stats.responses,
+                    "rcodes": rcodes_json(&stats.rcodes),
+                    "latency": stats.latency.to_json(),
+                })
+            })
+            .collect();
+        let mut clients: Vec<_> = self.clients.iter().collect();
+        clients.sort_by(|a, b| b.1.queries.cmp(&a.1.queries).then_with(|| a.0.cmp(b.0)));
+        let top_talkers: Vec<_> = clients
+            .into_iter()
+            .take(TOP_TALKERS)
+            .map(|(ip, stats)| {
+                json!({
+                    "ip": ip,
+                    "queries": stats.queries,
+                    "nxdomain": stats.nxdomain,
+                })
+            })
+            .collect();
+        let mut stats = json!({
+            "queries": self.queries,
+            "responses": self.responses,
+            "unanswered": self.unanswered,
+            "nxdomain_rate": rate(nxdomain, self.responses),
+            "rcodes": rcodes_json(&self.rcodes),
+            "latency": self.latency.to_json(),
+            "names": names,
+            "top_talkers": top_talkers,
+        });
+        if self.segment {
+            let segments: Map<String, Value> = self
+                .segments
+                .iter()
+                .map(|(encap, segment)| {
+                    let mut v = segment.get_results_json();
+                    (segment_name(*encap), v["dns-stats"].take())
+                })
+                .collect();
+            stats["segments"] = Value::Object(segments);
+        }
+        json!({ "dns-stats": stats })
+    }
+
+    fn write_names_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(
+            w,
+            "rrname,rrtype,queries,responses,nxdomain,latency_avg,latency_min,latency_max"
+        )?;
+        for ((rrname, rrtype), stats) in self.sorted_names() {
+            let latency = match stats.latency.avg() {
+                Some(avg) => format!("{},{},{}", avg, stats.latency.min, stats.latency.max),
+                None => ",,".to_owned(),
+            };
+            writeln!(
+                w,
+                "{},{},{},{},{},{}",
+                csv_field(rrname),
+                dns_rrtype_name(*rrtype),
+                stats.queries,
+                stats.responses,
+                stats.nxdomain(),
+                latency
+            )?;
+        }
+        w.flush()
+    }
+}
+
+fn rcodes_json(rcodes: &BTreeMap<u16, u64>) -> Value {
+    let map: Map<String, Value> = rcodes
+        .iter()
+        .map(|(&rcode, &count)| (dns_rcode_name(rcode), json!(count)))
+        .collect();
+    Value::Object(map)
+}
+
+fn rate(count: u64, total: u64) -> Option<f64> {
+    if total == 0 {
+        None
+    } else {
+        Some(count as f64 / total as f64)
+    }
+}
+
+pub(super) fn duration_secs(d: Duration) -> f64 {
+    f64::from(d.secs) + f64::from(d.nanos) / 1e9
+}
+
+/// Quote a CSV field if needed (names may contain any byte)
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_field, DnsStats};
+    use crate::app_proto::DnsMessage;
+    use crate::packet_info::Encapsulation;
+    use libpcap_tools::{Duration, FiveTuple};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn message(id: u16, flags: u16, rrname: &str) -> DnsMessage {
+        DnsMessage {
+            id,
+            flags,
+            rrname: rrname.to_owned(),
+            rrtype: 1,
+        }
+    }
+
+    #[test]
+    fn dns_stats_test() {
+        let query = FiveTuple {
+            proto: 17,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 53)),
+            src_port: 40000,
+            dst_port: 53,
+        };
+        let response = query.get_reverse();
+        let mut stats = DnsStats::default();
+        stats.handle_message(
+            1,
+            Duration::new(1, 0),
+            &query,
+            message(1, 0x0100, "example.com"),
+        );
+        stats.handle_message(
+            1,
+            Duration::new(1, 10),
+            &query,
+            message(2, 0x0100, "nx.example"),
+        );
+        stats.handle_message(
+            1,
+            Duration::new(1, 20),
+            &query,
+            message(3, 0x0100, "lost.example"),
+        );
+        stats.handle_message(
+            1,
+            Duration::new(1, 25_000),
+            &response,
+            message(1, 0x8180, "example.com"),
+        );
+        stats.handle_message(
+            1,
+            Duration::new(1, 30),
+            &response,
+            message(2, 0x8183, "nx.example"),
+        );
+        let mut csv = Vec::new();
+        stats.write_names_csv(&mut csv).expect("CSV output");
+        let results = stats.get_results_json();
+        let results = &results["dns-stats"];
+        assert_eq!(results["queries"], 3);
+        assert_eq!(results["responses"], 2);
+        assert_eq!(results["nxdomain_rate"], 0.5);
+        assert_eq!(results["rcodes"]["NXDOMAIN"], 1);
+        assert_eq!(results["latency"]["max"], 0.025);
+        assert_eq!(results["top_talkers"][0]["ip"], "10.0.0.1");
+        assert_eq!(results["top_talkers"][0]["nxdomain"], 1);
+        assert_eq!(stats.pending.len(), 1);
+        let csv = String::from_utf8(csv).expect("UTF-8");
+        assert!(csv.contains("\nexample.com,A,1,1,0,0.025,0.025,0.025\n"));
+        assert_eq!(csv_field("a,\"b"), "\"a,\"\"b\"");
+    }
+    #[test]
+    fn dns_stats_segments() {
+        let query = FiveTuple {
+            proto: 17,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 53)),
+            src_port: 40000,
+            dst_port: 53,
+        };
+        let mut stats = DnsStats {
+            segment: true,
+            ..DnsStats::default()
+        };
+        for (encap, id) in &[(Some(Encapsulation::Vlan(10)), 1), (None, 2), (None, 3)] {
+            stats.segments.entry(*encap).or_default().handle_message(
+                1,
+                Duration::new(1, 0),
+                &query,
+                message(*id, 0x0100, "a.b"),
+            );
+        }
+        let results = stats.get_results_json();
+        let segments = &results["dns-stats"]["segments"];
+        assert_eq!(segments["vlan:10"]["queries"], 1);
+        assert_eq!(segments["none"]["queries"], 2);
+        assert_eq!(segments["none"]["names"][0]["rrname"], "a.b");
+        assert!(segments["none"]["segments"].is_null());
+    }
+}
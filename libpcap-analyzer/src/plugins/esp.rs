@@ -0,0 +1,488 @@
+//! Plugin recording IPsec ESP security associations, and decapsulating their traffic if possible
+//!
+//! ESP packets (IP protocol 50, or UDP port 4500 with NAT traversal, RFC 3948) are grouped by
+//! security association (SA: SPI, source and destination addresses). Inner packets are
+//! decapsulated, and analyzed by all plugins as derived packets (see `DerivedPacket`), if:
+//!
+//! - the SA uses NULL encryption (RFC 2410). It is detected from the packets: the trailer must use
+//!   the default padding, and the payload must be a valid IP, TCP or UDP packet, for the usual ICV
+//!   lengths (12, 16, 24 or 32 bytes)
+//! - the SA is set in `sas`, in section `[plugin.esp]`: `"<spi> null <ICV length>"`, or
+//!   `"<spi> aes-gcm <key and salt, in hex>"` (RFC 4106, with a 16 bytes ICV)
+//!
+//! Tunnel mode payloads are IP packets. In transport mode, an IP header is added to the payload,
+//! with the addresses of the ESP packet. Extended sequence numbers are not supported for AES-GCM.
+//!
+//! SAs are saved to `esp.json`, with the number of packets, the sequence numbers, the encryption
+//! (if known) and the number of decapsulated packets.
+
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::PLUGIN_L4;
+use crate::plugin::{DerivedPacket, Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Packet};
+use pnet_packet::util::checksum;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+const NAT_T_PORT: u16 = 4500;
+
+/// ICV lengths tested to detect NULL encryption (HMAC-SHA1-96 first, the most common)
+const NULL_ICV_LENS: [usize; 4] = [12, 16, 24, 32];
+/// Number of packets of an SA tested for NULL encryption, before considering it encrypted
+const MAX_NULL_ATTEMPTS: u32 = 8;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// Next header of dummy packets (RFC 4303 section 2.6)
+const NO_NEXT_HEADER: u8 = 59;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Encryption {
+    /// NULL encryption, with the length of the ICV
+    Null(usize),
+    /// AES-GCM, with the key and the salt
+    AesGcm(Vec<u8>, Vec<u8>),
+}
+
+impl Encryption {
+    fn name(&self) -> &'static str {
+        match self {
+            Encryption::Null(_) => "null",
+            Encryption::AesGcm(..) => "aes-gcm",
+        }
+    }
+}
+
+/// Parse an SA of the configuration, and return its SPI and encryption
+fn parse_sa(s: &str) -> Result<(u32, Encryption), String> {
+    let fields: Vec<_> = s.split_whitespace().collect();
+    let (spi, encryption, param) = match fields[..] {
+        [spi, encryption, param] => (spi, encryption, param),
+        _ => return Err(format!("invalid SA {:?}", s)),
+    };
+    let spi = match spi.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => spi.parse(),
+    }
+    .map_err(|_| format!("invalid SPI in SA {:?}", s))?;
+    let encryption = match encryption {
+        "null" => {
+            let icv_len = param
+                .parse()
+                .map_err(|_| format!("invalid ICV length in SA {:?}", s))?;
+            Encryption::Null(icv_len)
+        }
+        "aes-gcm" => match unhex(param) {
+            // 128 or 256 bits key, and 4 bytes salt
+            Some(mut key) if key.len() == 20 || key.len() == 36 => {
+                let salt = key.split_off(key.len() - 4);
+                Encryption::AesGcm(key, salt)
+            }
+            _ => return Err(format!("invalid AES-GCM key in SA {:?}", s)),
+        },
+        _ => return Err(format!("unsupported encryption in SA {:?}", s)),
+    };
+    Ok((spi, encryption))
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn be16(data: &[u8], offset: usize) -> usize {
+    usize::from(u16::from_be_bytes([data[offset], data[offset + 1]]))
+}
+
+fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// Remove the trailer (padding, pad length and next header) of an ESP payload, and return the
+/// next header and the payload
+fn trailer(body: &[u8]) -> Option<(u8, &[u8])> {
+    let (&next_header, rest) = body.split_last()?;
+    let (&pad_len, rest) = rest.split_last()?;
+    let len = rest.len().checked_sub(usize::from(pad_len))?;
+    Some((next_header, &rest[..len]))
+}
+
+/// Check that a payload is a valid packet of the next header protocol
+fn valid_payload(next_header: u8, p: &[u8]) -> bool {
+    match next_header {
+        4 => p.len() >= 20 && p[0] >> 4 == 4 && be16(p, 2) == p.len(),
+        41 => p.len() >= 40 && p[0] >> 4 == 6 && be16(p, 4) + 40 == p.len(),
+        6 => p.len() >= 20 && (20..=p.len()).contains(&(usize::from(p[12] >> 4) * 4)),
+        17 => p.len() >= 8 && be16(p, 4) == p.len(),
+        _ => false,
+    }
+}
+
+/// Detect NULL encryption, and return the length of the ICV
+fn detect_null(esp: &[u8]) -> Option<usize> {
+    NULL_ICV_LENS.iter().copied().find(|&icv_len| {
+        let body = match esp
+            .len()
+            .checked_sub(icv_len)
+            .and_then(|end| esp.get(8..end))
+        {
+            Some(body) => body,
+            None => return false,
+        };
+        match trailer(body) {
+            Some((next_header, payload)) => {
+                // default padding: 1, 2, 3...
+                let padding = &body[payload.len()..body.len() - 2];
+                padding
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &b)| usize::from(b) == i + 1)
+                    && valid_payload(next_header, payload)
+            }
+            None => false,
+        }
+    })
+}
+
+/// Decrypt an ESP packet with AES-GCM (RFC 4106), and return the payload and trailer
+fn open_gcm(key: &[u8], salt: &[u8], esp: &[u8]) -> Option<Vec<u8>> {
+    let iv = esp.get(8..16)?;
+    let nonce = [salt, iv].concat();
+    let nonce = GenericArray::from_slice(&nonce);
+    // SPI and sequence number
+    let payload = Payload {
+        msg: &esp[16..],
+        aad: &esp[..8],
+    };
+    match key.len() {
+        16 => Aes128Gcm::new_from_slice(key).ok()?.decrypt(nonce, payload),
+        _ => Aes256Gcm::new_from_slice(key).ok()?.decrypt(nonce, payload),
+    }
+    .ok()
+}
+
+/// Build the inner packet, and return it with its ethertype
+///
+/// IP payloads (tunnel mode) are returned as is. Other payloads (transport mode) are returned with
+/// an IP header, with the addresses of the ESP packet.
+fn inner_packet(
+    next_header: u8,
+    payload: Vec<u8>,
+    src: IpAddr,
+    dst: IpAddr,
+    ttl: u8,
+) -> Option<(u16, Vec<u8>)> {
+    match (next_header, src, dst) {
+        (4, _, _) => Some((ETHERTYPE_IPV4, payload)),
+        (41, _, _) => Some((ETHERTYPE_IPV6, payload)),
+        (NO_NEXT_HEADER, _, _) => None,
+        (_, IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let len = u16::try_from(20 + payload.len()).ok()?;
+            let mut packet = vec![0x45, 0];
+            packet.extend_from_slice(&len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0, 0, ttl, next_header, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let sum = checksum(&packet, 5);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+            packet.extend(payload);
+            Some((ETHERTYPE_IPV4, packet))
+        }
+        (_, IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let len = u16::try_from(payload.len()).ok()?;
+            let mut packet = vec![0x60, 0, 0, 0];
+            packet.extend_from_slice(&len.to_be_bytes());
+            packet.extend_from_slice(&[next_header, ttl]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            packet.extend(payload);
+            Some((ETHERTYPE_IPV6, packet))
+        }
+        _ => None,
+    }
+}
+
+/// An ESP security association
+#[derive(Serialize)]
+struct SecurityAssociation {
+    /// SPI (hex)
+    spi: String,
+    src: IpAddr,
+    dst: IpAddr,
+    /// ESP is encapsulated in UDP (NAT traversal)
+    nat_t: bool,
+    packets: u64,
+    bytes: u64,
+    first_seq: u32,
+    last_seq: u32,
+    /// Encryption, if configured or detected (`null` or `aes-gcm`)
+    encryption: Option<&'static str>,
+    decapsulated: u64,
+    /// Packets which could not be decrypted or decapsulated, with a known encryption
+    failed: u64,
+    #[serde(skip)]
+    state: Option<Encryption>,
+    #[serde(skip)]
+    null_attempts: u32,
+}
+
+impl SecurityAssociation {
+    fn new(spi: u32, src: IpAddr, dst: IpAddr, seq: u32, state: Option<Encryption>) -> Self {
+        SecurityAssociation {
+            spi: format!("0x{:08x}", spi),
+            src,
+            dst,
+            nat_t: false,
+            packets: 0,
+            bytes: 0,
+            first_seq: seq,
+            last_seq: seq,
+            encryption: state.as_ref().map(Encryption::name),
+            decapsulated: 0,
+            failed: 0,
+            state,
+            null_attempts: 0,
+        }
+    }
+
+    /// Decapsulate an ESP packet, and return the next header and the payload
+    fn decapsulate(&mut self, esp: &[u8]) -> Option<(u8, Vec<u8>)> {
+        if self.state.is_none() {
+            if self.null_attempts >= MAX_NULL_ATTEMPTS {
+                return None;
+            }
+            match detect_null(esp) {
+                Some(icv_len) => {
+                    let state = Encryption::Null(icv_len);
+                    self.encryption = Some(state.name());
+                    self.state = Some(state);
+                }
+                None => {
+                    self.null_attempts += 1;
+                    return None;
+                }
+            }
+        }
+        let res = match self.state.as_ref()? {
+            Encryption::Null(icv_len) => esp
+                .len()
+                .checked_sub(*icv_len)
+                .and_then(|end| esp.get(8..end))
+                .and_then(trailer)
+                .map(|(next_header, payload)| (next_header, payload.to_vec())),
+            Encryption::AesGcm(key, salt) => open_gcm(key, salt, esp).and_then(|body| {
+                let (next_header, payload) = trailer(&body)?;
+                Some((next_header, payload.to_vec()))
+            }),
+        };
+        match res {
+            Some(_) => self.decapsulated += 1,
+            None => self.failed += 1,
+        }
+        res
+    }
+}
+
+pub struct Esp {
+    /// Encryption of the SAs of the configuration, by SPI
+    configured: FnvHashMap<u32, Encryption>,
+    /// SAs, by SPI, source and destination
+    sas: BTreeMap<(u32, IpAddr, IpAddr), SecurityAssociation>,
+}
+
+fn configured_sas(config: &Config) -> Result<FnvHashMap<u32, Encryption>, PluginBuilderError> {
+    let config = config.plugin_config("esp");
+    if !config.contains("sas") {
+        return Ok(FnvHashMap::default());
+    }
+    let sas = config.get_strings("sas").ok_or_else(|| {
+        PluginBuilderError::InvalidConfig("esp: sas must be an array of strings".to_owned())
+    })?;
+    sas.iter()
+        .map(|s| parse_sa(s).map_err(|e| PluginBuilderError::InvalidConfig(format!("esp: {}", e))))
+        .collect()
+}
+
+pub struct EspBuilder;
+
+impl PluginBuilder for EspBuilder {
+    fn name(&self) -> &'static str {
+        "EspBuilder"
+    }
+    fn build(
+        &self,
+        registry: &mut PluginRegistry,
+        config: &Config,
+    ) -> Result<(), PluginBuilderError> {
+        let plugin = Esp {
+            configured: configured_sas(config)?,
+            sas: BTreeMap::new(),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        configured_sas(config).map(|_| ())
+    }
+}
+
+impl Plugin for Esp {
+    fn name(&self) -> &'static str {
+        "Esp"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let t5 = pinfo.five_tuple;
+        let (esp, nat_t) = match (t5.proto, pinfo.l4_payload) {
+            (50, _) => (pinfo.l4_data, false),
+            // a null marker is sent before IKE messages, and keepalives have a single byte
+            (17, Some(data))
+                if (t5.src_port == NAT_T_PORT || t5.dst_port == NAT_T_PORT)
+                    && data.len() > 8
+                    && data[..4] != [0, 0, 0, 0] =>
+            {
+                (data, true)
+            }
+            _ => return PluginResult::None,
+        };
+        if esp.len() < 8 {
+            return PluginResult::None;
+        }
+        let (spi, seq) = (be32(esp, 0), be32(esp, 4));
+        let configured = &self.configured;
+        let sa = self.sas.entry((spi, t5.src, t5.dst)).or_insert_with(|| {
+            let state = configured.get(&spi).cloned();
+            SecurityAssociation::new(spi, t5.src, t5.dst, seq, state)
+        });
+        sa.nat_t |= nat_t;
+        sa.packets += 1;
+        sa.bytes += esp.len() as u64;
+        sa.last_seq = seq;
+        let packet = sa.decapsulate(esp).and_then(|(next_header, payload)| {
+            inner_packet(next_header, payload, t5.src, t5.dst, pinfo.ttl)
+        });
+        match packet {
+            Some((ethertype, data)) => {
+                PluginResult::Derived(vec![DerivedPacket::L3(ethertype, data)])
+            }
+            None => PluginResult::None,
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "esp.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Esp {
+    fn get_results_json(&self) -> Value {
+        let sas: Vec<_> = self.sas.values().collect();
+        json!({ "esp-sas": sas })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_null, inner_packet, parse_sa, unhex, Encryption, SecurityAssociation};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    // IPv4 packet, UDP 1234 -> 5678, payload "test"
+    const INNER: &str = "4500002000000000401166cb0a0000010a00000204d2162e000c000074657374";
+
+    fn sa(state: Option<Encryption>) -> SecurityAssociation {
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+        SecurityAssociation::new(0x1000, addr, addr, 1, state)
+    }
+
+    #[test]
+    fn esp_null() {
+        // tunnel mode, 2 bytes of padding, ICV of HMAC-SHA1-96
+        let esp = unhex(&format!(
+            "0000100000000001{}01020204{}",
+            INNER,
+            "ab".repeat(12)
+        ))
+        .unwrap();
+        assert_eq!(detect_null(&esp), Some(12));
+        let mut sa = sa(None);
+        assert_eq!(sa.decapsulate(&esp), Some((4, unhex(INNER).unwrap())));
+        assert_eq!(sa.encryption, Some("null"));
+        // encrypted data
+        let mut sa = self::sa(None);
+        assert_eq!(sa.decapsulate(&esp[..esp.len() - 1]), None);
+        assert_eq!(sa.encryption, None);
+    }
+
+    #[test]
+    fn esp_aes_gcm() {
+        let (spi, encryption) =
+            parse_sa("0x1000 aes-gcm 0102030405060708090a0b0c0d0e0f10deadbeef").unwrap();
+        assert_eq!(spi, 0x1000);
+        let esp = unhex(
+            "00001000000000010001020304050607094a9aefb44a1c7e306120085ddaae52919fe0c0112b04b2\
+             11be2fd4147d9c12f1da47b0177fc4a7b8ef1fcb68b3d373050b499a",
+        )
+        .unwrap();
+        let mut sa = sa(Some(encryption));
+        assert_eq!(sa.decapsulate(&esp), Some((4, unhex(INNER).unwrap())));
+        assert!(parse_sa("0x1000 aes-gcm 0102").is_err());
+        assert!(parse_sa("4096 null 12").is_ok());
+    }
+
+    #[test]
+    fn esp_transport_mode() {
+        let src = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let dst = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let udp = unhex(&INNER[40..]).unwrap();
+        let (ethertype, packet) = inner_packet(17, udp, src, dst, 64).unwrap();
+        assert_eq!(ethertype, 0x0800);
+        assert_eq!(packet, unhex(INNER).unwrap());
+    }
+}
@@ -0,0 +1,514 @@
+//! Plugin writing events in the EVE format (newline-delimited JSON, as used by Suricata)
+//!
+//! The plugin is enabled by setting `filename` in section `[plugin.eve]`. Relative file names
+//! are created in the output directory. Logged events are selected using `types` (default: all
+//! types):
+//!
+//! - `flow`: start (state `new`) and end (state `closed`) of flows
+//! - `dns`: DNS queries and answers (UDP or TCP, port 53)
+//! - `tls`: TLS handshakes (SNI and version)
+//! - `alert`: protocol anomalies (invalid TLS records, fatal TLS alerts, malformed DNS messages)
+//!
+//! The detected application protocol is given in the `app_proto` field of events.
+
+use crate::app_proto::{detect_app_proto, dns_rcode_name, dns_rrtype_name, parse_dns};
+use crate::output::{self, WriteErrors};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_FLOW_NEW, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, FiveTuple, Flow, FlowID, Packet};
+use serde_json::{json, Map, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use time::OffsetDateTime;
+use tls_parser::{
+    parse_tls_extensions, parse_tls_plaintext, TlsAlertSeverity, TlsExtension, TlsMessage,
+    TlsMessageHandshake, TlsVersion,
+};
+
+/// Names of all event types
+const EVENT_TYPES: &[&str] = &["flow", "dns", "tls", "alert"];
+
+/// Maximum size of buffered TLS data, for each direction
+const TLS_MAX_BUFFER: usize = 65536;
+
+const SID_TLS_INVALID_RECORD: u32 = 1;
+const SID_TLS_FATAL_ALERT: u32 = 2;
+const SID_DNS_MALFORMED: u32 = 3;
+
+pub struct Eve {
+    writer: BufWriter<File>,
+    /// Logged event types
+    types: Vec<&'static str>,
+    flows: FnvHashMap<FlowID, FlowState>,
+    write_errors: WriteErrors,
+}
+
+#[derive(Default)]
+struct FlowState {
+    app_proto: Option<&'static str>,
+    tls: TlsState,
+    /// Do not inspect the payload of this flow anymore
+    bypass: bool,
+}
+
+#[derive(Default)]
+struct TlsState {
+    /// Data not yet parsed, to server and to client
+    buffers: [Vec<u8>; 2],
+    info: TlsInfo,
+    logged: bool,
+}
+
+#[derive(Default)]
+struct TlsInfo {
+    client_hello: bool,
+    server_hello: bool,
+    sni: Option<String>,
+    version: Option<TlsVersion>,
+    /// Fatal alerts seen since the last call to the plugin
+    fatal_alerts: Vec<String>,
+    /// The handshake is finished (or encrypted), stop parsing
+    done: bool,
+}
+
+pub struct EveBuilder;
+
+impl PluginBuilder for EveBuilder {
+    fn name(&self) -> &'static str { "EveBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let eve_config = config.plugin_config("eve");
+        let filename = match eve_config.get("filename") {
+            Some(filename) => filename,
+            None => {
+                debug!("eve: no output file configured");
+                return Ok(());
+            }
+        };
+        let file = output::create_file(output::get_output_dir(config), filename).map_err(|e| {
+            PluginBuilderError::InvalidConfig(format!("eve: cannot create {}: {}", filename, e))
+        })?;
+        let types = match eve_config.get_strings("types") {
+            Some(types) => EVENT_TYPES
+                .iter()
+                .copied()
+                .filter(|t| types.contains(t))
+                .collect(),
+            None => EVENT_TYPES.to_vec(),
+        };
+        let plugin = Eve {
+            writer: BufWriter::new(file),
+            types,
+            flows: FnvHashMap::default(),
+            write_errors: WriteErrors::new("eve: could not write events"),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("eve");
+        if config.contains("filename") && config.get("filename").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "eve: filename must be a string".to_owned(),
+            ));
+        }
+        if !config.contains("types") {
+            return Ok(());
+        }
+        let types = config.get_strings("types").ok_or_else(|| {
+            PluginBuilderError::InvalidConfig("eve: types must be an array of strings".to_owned())
+        })?;
+        match types.iter().find(|t| !EVENT_TYPES.contains(*t)) {
+            Some(t) => Err(PluginBuilderError::InvalidConfig(format!(
+                "eve: unknown event type {}",
+                t
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Plugin for Eve {
+    fn name(&self) -> &'static str {
+        "Eve"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_NEW | PLUGIN_FLOW_DEL
+    }
+
+    fn post_process(&mut self) {
+        let res = self.writer.flush();
+        self.write_errors.check(res);
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let flow = match pinfo.flow {
+            Some(flow) => flow,
+            None => return PluginResult::None,
+        };
+        let data = match pinfo.l4_payload {
+            Some(data) if !data.is_empty() => data,
+            _ => return PluginResult::None,
+        };
+        let mut state = self.flows.remove(&flow.flow_id).unwrap_or_default();
+        if !state.bypass {
+            if state.app_proto.is_none() {
+                state.app_proto = detect_app_proto(pinfo.five_tuple, data);
+                // only DNS and TLS payloads are inspected
+                state.bypass = !matches!(state.app_proto, Some("dns") | Some("tls"));
+            }
+            match state.app_proto {
+                Some("dns") => self.handle_dns(&state, packet, pinfo, data),
+                Some("tls") => self.handle_tls(&mut state, packet, pinfo, flow, data),
+                _ => (),
+            }
+        }
+        self.flows.insert(flow.flow_id, state);
+        PluginResult::None
+    }
+
+    fn flow_created(&mut self, flow: &Flow) {
+        if !self.types.contains(&"flow") {
+            return;
+        }
+        let mut event = self.event(flow.first_seen, "flow", &flow.five_tuple, flow.flow_id);
+        event.insert("flow".into(), flow_json(flow, "new"));
+        self.write_event(event);
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let state = self.flows.remove(&flow.flow_id).unwrap_or_default();
+        // handshakes without server hello are logged at the end of the flow
+        if state.tls.info.client_hello && !state.tls.logged {
+            self.log_tls(flow.last_seen, flow, &state.tls.info);
+        }
+        if !self.types.contains(&"flow") {
+            return;
+        }
+        let mut event = self.event(flow.last_seen, "flow", &flow.five_tuple, flow.flow_id);
+        event.insert(
+            "app_proto".into(),
+            json!(state.app_proto.unwrap_or("failed")),
+        );
+        event.insert("flow".into(), flow_json(flow, "closed"));
+        if flow.five_tuple.proto == 6 {
+            event.insert(
+                "tcp".into(),
+                json!({ "tcp_flags": format!("{:02x}", flow.tcp_flags) }),
+            );
+        }
+        self.write_event(event);
+    }
+
+    fn save_results(&mut self, _path: &str) -> Result<(), &'static str> {
+        self.write_errors.result()
+    }
+}
+
+impl Eve {
+    /// Create an event, with the fields common to all event types
+    fn event(
+        &self,
+        ts: Duration,
+        event_type: &str,
+        five_tuple: &FiveTuple,
+        flow_id: FlowID,
+    ) -> Map<String, Value> {
+        let mut event = Map::new();
+        event.insert("timestamp".into(), json!(eve_timestamp(ts)));
+        event.insert("flow_id".into(), json!(flow_id));
+        event.insert("event_type".into(), json!(event_type));
+        event.insert("src_ip".into(), json!(five_tuple.src.to_string()));
+        event.insert("src_port".into(), json!(five_tuple.src_port));
+        event.insert("dest_ip".into(), json!(five_tuple.dst.to_string()));
+        event.insert("dest_port".into(), json!(five_tuple.dst_port));
+        event.insert("proto".into(), json!(proto_name(five_tuple.proto)));
+        event
+    }
+
+    /// Create an event for the current packet
+    fn packet_event(
+        &self,
+        packet: &Packet,
+        pinfo: &PacketInfo,
+        event_type: &str,
+        app_proto: &str,
+    ) -> Map<String, Value> {
+        let flow_id = pinfo.flow.map(|f| f.flow_id).unwrap_or(0);
+        let mut event = self.event(packet.ts, event_type, pinfo.five_tuple, flow_id);
+        event.insert("pcap_cnt".into(), json!(pinfo.pcap_index));
+        event.insert("app_proto".into(), json!(app_proto));
+        event
+    }
+
+    fn write_event(&mut self, event: Map<String, Value>) {
+        let res = serde_json::to_writer(&mut self.writer, &event)
+            .map_err(std::io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"));
+        self.write_errors.check(res);
+    }
+
+    fn log_alert(
+        &mut self,
+        packet: &Packet,
+        pinfo: &PacketInfo,
+        app_proto: &str,
+        sid: u32,
+        signature: &str,
+    ) {
+        if !self.types.contains(&"alert") {
+            return;
+        }
+        let mut event = self.packet_event(packet, pinfo, "alert", app_proto);
+        let alert = json!({
+            "action": "allowed",
+            "gid": 1,
+            "signature_id": sid,
+            "rev": 1,
+            "signature": signature,
+            "category": "Generic Protocol Command Decode",
+            "severity": 3,
+        });
+        event.insert("alert".into(), alert);
+        self.write_event(event);
+    }
+
+    fn log_tls(&mut self, ts: Duration, flow: &Flow, info: &TlsInfo) {
+        if !self.types.contains(&"tls") {
+            return;
+        }
+        let mut event = self.event(ts, "tls", &flow.five_tuple, flow.flow_id);
+        event.insert("app_proto".into(), json!("tls"));
+        let mut tls = Map::new();
+        if let Some(sni) = &info.sni {
+            tls.insert("sni".into(), json!(sni));
+        }
+        if let Some(version) = info.version {
+            tls.insert("version".into(), json!(tls_version_name(version)));
+        }
+        event.insert("tls".into(), Value::Object(tls));
+        self.write_event(event);
+    }
+
+    fn handle_dns(&mut self, state: &FlowState, packet: &Packet, pinfo: &PacketInfo, data: &[u8]) {
+        let app_proto = state.app_proto.unwrap_or("dns");
+        // DNS messages over TCP are prefixed by their length
+        let msg = if pinfo.l4_type == 6 {
+            data.get(2..).and_then(parse_dns)
+        } else {
+            parse_dns(data)
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => {
+                self.log_alert(
+                    packet,
+                    pinfo,
+                    app_proto,
+                    SID_DNS_MALFORMED,
+                    "DNS malformed message",
+                );
+                return;
+            }
+        };
+        if !self.types.contains(&"dns") {
+            return;
+        }
+        let mut event = self.packet_event(packet, pinfo, "dns", app_proto);
+        let dns = if msg.flags & 0x8000 == 0 {
+            json!({
+                "type": "query",
+                "id": msg.id,
+                "rrname": msg.rrname,
+                "rrtype": dns_rrtype_name(msg.rrtype),
+            })
+        } else {
+            json!({
+                "version": 2,
+                "type": "answer",
+                "id": msg.id,
+                "flags": format!("{:x}", msg.flags),
+                "qr": true,
+                "rd": msg.flags & 0x0100 != 0,
+                "ra": msg.flags & 0x0080 != 0,
+                "rrname": msg.rrname,
+                "rrtype": dns_rrtype_name(msg.rrtype),
+                "rcode": dns_rcode_name(msg.flags & 0xf),
+            })
+        };
+        event.insert("dns".into(), dns);
+        self.write_event(event);
+    }
+
+    fn handle_tls(
+        &mut self,
+        state: &mut FlowState,
+        packet: &Packet,
+        pinfo: &PacketInfo,
+        flow: &Flow,
+        data: &[u8],
+    ) {
+        let tls = &mut state.tls;
+        let buffer = &mut tls.buffers[if pinfo.to_server { 0 } else { 1 }];
+        if buffer.len() + data.len() > TLS_MAX_BUFFER {
+            debug!("eve: TLS buffer too large for flow 0x{:x}", flow.flow_id);
+            state.bypass = true;
+            return;
+        }
+        buffer.extend_from_slice(data);
+        let mut invalid = false;
+        while !buffer.is_empty() && !tls.info.done {
+            let consumed = match parse_tls_plaintext(buffer) {
+                Ok((rem, record)) => {
+                    record.msg.iter().for_each(|msg| tls.info.update(msg));
+                    buffer.len() - rem.len()
+                }
+                Err(e) if e.is_incomplete() => break,
+                Err(_) => {
+                    invalid = true;
+                    break;
+                }
+            };
+            buffer.drain(..consumed);
+        }
+        for alert in std::mem::take(&mut tls.info.fatal_alerts) {
+            let signature = format!("TLS fatal alert: {}", alert);
+            self.log_alert(packet, pinfo, "tls", SID_TLS_FATAL_ALERT, &signature);
+        }
+        if tls.info.server_hello && !tls.logged {
+            self.log_tls(packet.ts, flow, &tls.info);
+            tls.logged = true;
+        }
+        if invalid {
+            self.log_alert(
+                packet,
+                pinfo,
+                "tls",
+                SID_TLS_INVALID_RECORD,
+                "TLS invalid record",
+            );
+            state.bypass = true;
+        } else if tls.info.done {
+            tls.buffers = Default::default();
+            state.bypass = true;
+        }
+    }
+}
+
+impl TlsInfo {
+    fn update(&mut self, msg: &TlsMessage) {
+        match msg {
+            TlsMessage::Handshake(TlsMessageHandshake::ClientHello(ch)) => {
+                self.client_hello = true;
+                self.version = Some(ch.version);
+                let extensions = ch.ext.and_then(|ext| parse_tls_extensions(ext).ok());
+                if let Some((_, extensions)) = extensions {
+                    self.sni = extensions.iter().find_map(|ext| match ext {
+                        TlsExtension::SNI(names) => names
+                            .first()
+                            .map(|(_, name)| String::from_utf8_lossy(name).into_owned()),
+                        _ => None,
+                    });
+                }
+            }
+            TlsMessage::Handshake(TlsMessageHandshake::ServerHello(sh)) => {
+                self.server_hello = true;
+                // TLS 1.3 servers announce the version in an extension
+                let extensions = sh.ext.and_then(|ext| parse_tls_extensions(ext).ok());
+                let selected_version = extensions.and_then(|(_, extensions)| {
+                    extensions.iter().find_map(|ext| match ext {
+                        TlsExtension::SupportedVersions(v) => v.first().copied(),
+                        _ => None,
+                    })
+                });
+                self.version = Some(selected_version.unwrap_or(sh.version));
+            }
+            TlsMessage::Alert(alert) if alert.severity == TlsAlertSeverity::Fatal => {
+                self.fatal_alerts.push(format!("{:?}", alert.code));
+            }
+            TlsMessage::ChangeCipherSpec | TlsMessage::ApplicationData(_) => {
+                self.done = true;
+            }
+            _ => (),
+        }
+    }
+}
+
+fn flow_json(flow: &Flow, state: &str) -> Value {
+    json!({
+        "pkts": flow.packets,
+        "bytes": flow.bytes,
+        "start": eve_timestamp(flow.first_seen),
+        "end": eve_timestamp(flow.last_seen),
+        "age": flow.last_seen.secs.saturating_sub(flow.first_seen.secs),
+        "state": state,
+    })
+}
+
+/// Format a timestamp as in EVE events (for ex. `2022-10-03T09:24:01.123456+0000`)
+fn eve_timestamp(ts: Duration) -> String {
+    match OffsetDateTime::from_unix_timestamp(i64::from(ts.secs)) {
+        Ok(dt) => format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}+0000",
+            dt.year(),
+            u8::from(dt.month()),
+            dt.day(),
+            dt.hour(),
+            dt.minute(),
+            dt.second(),
+            ts.nanos / 1000
+        ),
+        Err(_) => String::new(),
+    }
+}
+
+fn proto_name(proto: u8) -> String {
+    match proto {
+        1 => "ICMP".to_owned(),
+        6 => "TCP".to_owned(),
+        17 => "UDP".to_owned(),
+        58 => "IPv6-ICMP".to_owned(),
+        132 => "SCTP".to_owned(),
+        _ => proto.to_string(),
+    }
+}
+
+fn tls_version_name(version: TlsVersion) -> String {
+    match version.0 {
+        0x0300 => "SSLv3".to_owned(),
+        0x0301 => "TLS 1.0".to_owned(),
+        0x0302 => "TLS 1.1".to_owned(),
+        0x0303 => "TLS 1.2".to_owned(),
+        0x0304 => "TLS 1.3".to_owned(),
+        v => format!("0x{:04x}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn eve_dns_test() {
+        // query, www.example.com, type A
+        let data = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+                     \x03www\x07example\x03com\x00\x00\x01\x00\x01";
+        let msg = parse_dns(data).expect("DNS message");
+        assert_eq!(msg.id, 0x1234);
+        assert_eq!(msg.rrname, "www.example.com");
+        assert_eq!(dns_rrtype_name(msg.rrtype), "A");
+        // truncated question
+        assert_eq!(parse_dns(&data[..20]), None);
+        assert_eq!(
+            eve_timestamp(Duration::new(1_600_000_000, 123_456)),
+            "2020-09-13T12:26:40.123456+0000"
+        );
+    }
+}
@@ -13,6 +13,7 @@ default_plugin_builder!(Empty, EmptyBuilder);
 impl Plugin for Empty {
     fn name(&self) -> &'static str { "Empty" }
     fn plugin_type(&self) -> u16 { PLUGIN_NONE }
+    fn parallel_safe(&self) -> bool { true }
 }
 
 /// Example plugin, reading a configuration value
@@ -24,6 +25,7 @@ pub struct EmptyWithConfig {
 impl Plugin for EmptyWithConfig {
     fn name(&self) -> &'static str { "EmptyWithConfig" }
     fn plugin_type(&self) -> u16 { PLUGIN_NONE }
+    fn parallel_safe(&self) -> bool { true }
 
     fn pre_process(&mut self) {
         info!("Hello, I am plugin EmptyWithConfig, with name {:?}", self.name);
@@ -38,7 +40,7 @@ pub struct EmptyWithConfigBuilder;
 impl crate::plugin::PluginBuilder for EmptyWithConfigBuilder {
     fn name(&self) -> &'static str { "EmptyWithConfigBuilder" }
     fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
-        let name = config.get("plugin.emptywithconfig.name");
+        let name = config.plugin_config("emptywithconfig").get("name");
         let plugin = EmptyWithConfig {
             name: name.map(|s| s.to_string()),
         };
@@ -0,0 +1,1109 @@
+//! Plugin extracting the files transferred in reassembled TCP streams
+//!
+//! The plugin is enabled by setting `dir` in section `[plugin.file_extract]` (relative to the
+//! output directory). Files are extracted from:
+//!
+//! - HTTP: bodies of requests and responses (as transferred, content encodings are not decoded)
+//! - FTP: data connections announced by `PASV`, `EPSV`, `PORT` or `EPRT` in the control
+//!   connection (port 21), named using the following `RETR`, `STOR` or `APPE` command
+//! - SMB2/3 (TCP ports 445 and 139): reads and writes of files opened with `CREATE`
+//! - SMTP: attachments of MIME messages (base64 and quoted-printable encodings are decoded)
+//!
+//! Files are saved as `<index>-<name>`, where the name is a sanitized version of the file name
+//! used by the protocol. A manifest (`manifest.json`, in the same directory) lists the flow,
+//! protocol, file name, size and SHA-256 of every extracted file. Files are truncated to
+//! `max_size` bytes (default: 100 MB).
+
+use super::http::{BodyKind, Event, Head, MessageParser};
+use super::smb::{self, le_u16, le_u32, smb2_buffer, smb_string};
+use crate::app_proto::detect_app_proto;
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use base64ct::{Base64, Encoding};
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Maximum size of SMB messages (reads and writes are usually at most 8 MB)
+const SMB_MAX_MESSAGE: usize = 8 * 1024 * 1024 + 4096;
+
+const SMB_STATUS_PENDING: u32 = 0x0000_0103;
+
+/// Maximum length of a line (FTP and SMTP), longer lines are ignored
+const MAX_LINE: usize = 4096;
+
+/// Maximum nesting of MIME multipart messages
+const MAX_MIME_DEPTH: usize = 8;
+
+/// Entry of the manifest
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    protocol: &'static str,
+    /// File name used by the protocol (URI, FTP command, SMB file, attachment name)
+    filename: Option<String>,
+    /// Name of the extracted file, in the output directory of the plugin
+    path: String,
+    size: u64,
+    /// Data beyond `max_size` was dropped
+    truncated: bool,
+    sha256: Option<String>,
+}
+
+/// File being extracted
+struct CarvedFile {
+    entry: ManifestEntry,
+    path: PathBuf,
+    /// `None` if the file could not be created or written
+    file: Option<File>,
+    max_size: u64,
+}
+
+impl CarvedFile {
+    /// Append data to the file
+    fn write(&mut self, data: &[u8]) {
+        self.write_at(self.entry.size, data);
+    }
+
+    /// Write data at an offset of the file (for ex. SMB reads, which can be out of order)
+    fn write_at(&mut self, offset: u64, data: &[u8]) {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+        let len = (data.len() as u64).min(self.max_size.saturating_sub(offset));
+        if len < data.len() as u64 {
+            self.entry.truncated = true;
+        }
+        if len == 0 {
+            return;
+        }
+        let res = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(&data[..len as usize]));
+        match res {
+            Ok(()) => self.entry.size = self.entry.size.max(offset + len),
+            Err(e) => {
+                warn!(
+                    "file_extract: could not write {}: {}",
+                    self.path.display(),
+                    e
+                );
+                self.file = None;
+            }
+        }
+    }
+
+    fn finish(mut self) -> ManifestEntry {
+        // close the file before hashing it
+        if self.file.take().is_some() {
+            match sha256_file(&self.path) {
+                Ok(hash) => self.entry.sha256 = Some(hash),
+                Err(e) => warn!(
+                    "file_extract: could not read {}: {}",
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+        self.entry
+    }
+}
+
+/// Creation of extracted files, and manifest
+struct Extractor {
+    dir: PathBuf,
+    max_size: u64,
+    /// Number of extracted files, used to build unique file names
+    count: u64,
+    manifest: Vec<ManifestEntry>,
+}
+
+impl Extractor {
+    fn create(
+        &mut self,
+        flow: &Flow,
+        protocol: &'static str,
+        filename: Option<String>,
+    ) -> CarvedFile {
+        self.count += 1;
+        let name = format!("{}-{}", self.count, sanitize(filename.as_deref()));
+        let path = self.dir.join(&name);
+        let file = File::create(&path)
+            .map_err(|e| warn!("file_extract: cannot create {}: {}", path.display(), e))
+            .ok();
+        CarvedFile {
+            entry: ManifestEntry {
+                flow_id: flow.flow_id,
+                five_tuple: flow.five_tuple.clone(),
+                protocol,
+                filename,
+                path: name,
+                size: 0,
+                truncated: false,
+                sha256: None,
+            },
+            path,
+            file,
+            max_size: self.max_size,
+        }
+    }
+
+    fn finish(&mut self, file: CarvedFile) {
+        self.manifest.push(file.finish());
+    }
+}
+
+enum FlowState {
+    Http(HttpExtract),
+    FtpControl(FtpControl),
+    /// FTP data connection, and the endpoint announced in the control connection
+    FtpData((IpAddr, u16), CarvedFile),
+    Smb(SmbExtract),
+    Smtp(SmtpExtract),
+}
+
+pub struct FileExtract {
+    extractor: Extractor,
+    /// State of flows, `None` if files cannot be extracted
+    flows: FnvHashMap<FlowID, Option<FlowState>>,
+    /// FTP data endpoints announced in control connections, and the name of the transferred file
+    ftp_data: FnvHashMap<(IpAddr, u16), Option<String>>,
+}
+
+pub struct FileExtractBuilder;
+
+impl PluginBuilder for FileExtractBuilder {
+    fn name(&self) -> &'static str { "FileExtractBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let plugin_config = config.plugin_config("file_extract");
+        let dir = match plugin_config.get("dir") {
+            Some(dir) => dir,
+            None => {
+                debug!("file_extract: no output directory configured");
+                return Ok(());
+            }
+        };
+        let mut path = PathBuf::from(output::get_output_dir(config));
+        path.push(dir);
+        fs::create_dir_all(&path).map_err(|e| {
+            PluginBuilderError::InvalidConfig(format!("file_extract: cannot create {}: {}", dir, e))
+        })?;
+        let max_size = plugin_config
+            .get_usize("max_size")
+            .map_or(DEFAULT_MAX_SIZE, |size| size as u64);
+        let plugin = FileExtract {
+            extractor: Extractor {
+                dir: path,
+                max_size,
+                count: 0,
+                manifest: Vec::new(),
+            },
+            flows: FnvHashMap::default(),
+            ftp_data: FnvHashMap::default(),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("file_extract");
+        if config.contains("dir") && config.get("dir").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "file_extract: dir must be a string".to_owned(),
+            ));
+        }
+        if config.contains("max_size") && config.get_usize("max_size").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "file_extract: max_size must be a positive integer".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for FileExtract {
+    fn name(&self) -> &'static str {
+        "FileExtract"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if pinfo.five_tuple.proto == 6 && !data.is_empty() => {
+                self.handle_payload(flow, data, pinfo.to_server);
+            }
+            _ => (),
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(Some(state)) = self.flows.remove(&flow.flow_id) {
+            state.finish(&mut self.extractor, &mut self.ftp_data);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, _path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // the manifest is saved with the extracted files
+        let file = File::create(self.extractor.dir.join("manifest.json"))
+            .or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl FileExtract {
+    fn handle_payload(&mut self, flow: &Flow, data: &[u8], to_server: bool) {
+        let extractor = &mut self.extractor;
+        let ftp_data = &mut self.ftp_data;
+        let state = self
+            .flows
+            .entry(flow.flow_id)
+            .or_insert_with(|| FlowState::detect(flow, to_server, data, ftp_data, extractor));
+        if let Some(state) = state {
+            state.update(flow, data, to_server, extractor, ftp_data);
+        }
+    }
+
+    fn get_results_json(&self) -> Value {
+        json!({ "extracted-files": self.extractor.manifest })
+    }
+}
+
+impl FlowState {
+    fn detect(
+        flow: &Flow,
+        to_server: bool,
+        data: &[u8],
+        ftp_data: &FnvHashMap<(IpAddr, u16), Option<String>>,
+        extractor: &mut Extractor,
+    ) -> Option<Self> {
+        let t5 = &flow.five_tuple;
+        let endpoint = (t5.dst, t5.dst_port);
+        if ftp_data.contains_key(&endpoint) {
+            // the name of the file is known when the connection is finished
+            let file = extractor.create(flow, "ftp", None);
+            return Some(FlowState::FtpData(endpoint, file));
+        }
+        match t5.dst_port {
+            21 => Some(FlowState::FtpControl(FtpControl::default())),
+            25 | 587 => Some(FlowState::Smtp(SmtpExtract::default())),
+            139 | 445 => Some(FlowState::Smb(SmbExtract::default())),
+            _ if to_server && detect_app_proto(t5, data) == Some("http") => {
+                Some(FlowState::Http(HttpExtract::default()))
+            }
+            _ => None,
+        }
+    }
+
+    fn update(
+        &mut self,
+        flow: &Flow,
+        data: &[u8],
+        to_server: bool,
+        extractor: &mut Extractor,
+        ftp_data: &mut FnvHashMap<(IpAddr, u16), Option<String>>,
+    ) {
+        match self {
+            FlowState::Http(http) => http.update(flow, data, to_server, extractor),
+            FlowState::FtpControl(ftp) => ftp.update(flow, data, to_server, ftp_data),
+            FlowState::FtpData(_, file) => file.write(data),
+            FlowState::Smb(smb) => smb.update(flow, data, to_server, extractor),
+            FlowState::Smtp(smtp) => smtp.update(flow, data, to_server, extractor),
+        }
+    }
+
+    /// Finish the files being extracted, at the end of the flow
+    fn finish(
+        self,
+        extractor: &mut Extractor,
+        ftp_data: &mut FnvHashMap<(IpAddr, u16), Option<String>>,
+    ) {
+        match self {
+            FlowState::Http(http) => {
+                let [request, response] = http.files;
+                for file in request.into_iter().chain(response) {
+                    extractor.finish(file);
+                }
+            }
+            FlowState::FtpData(endpoint, mut file) => {
+                file.entry.filename = ftp_data.remove(&endpoint).flatten();
+                extractor.finish(file);
+            }
+            FlowState::Smb(smb) => {
+                for file in smb.files.into_values() {
+                    extractor.finish(file);
+                }
+            }
+            FlowState::FtpControl(_) | FlowState::Smtp(_) => (),
+        }
+    }
+}
+
+/// HTTP state of a flow
+#[derive(Default)]
+struct HttpExtract {
+    /// Parsers of requests and responses
+    parsers: [MessageParser; 2],
+    /// Method and URI of the requests waiting for their response
+    requests: VecDeque<(String, String)>,
+    /// File name of the body of the current message, by direction
+    names: [Option<String>; 2],
+    /// Body being extracted, by direction
+    files: [Option<CarvedFile>; 2],
+}
+
+impl HttpExtract {
+    fn update(&mut self, flow: &Flow, data: &[u8], to_server: bool, extractor: &mut Extractor) {
+        let dir = if to_server { 0 } else { 1 };
+        let mut data = data;
+        loop {
+            let (n, event) = self.parsers[dir].parse(data);
+            let consumed = &data[..n];
+            data = &data[n..];
+            match event {
+                Some(Event::Head(head)) => {
+                    let kind = if to_server {
+                        self.request_head(&head)
+                    } else {
+                        self.response_head(&head)
+                    };
+                    if let Some(Event::Error(e)) = self.parsers[dir].start_body(kind) {
+                        debug!("file_extract: http parsing stopped: {}", e);
+                    }
+                }
+                Some(Event::Body(_)) => {
+                    let name = &mut self.names[dir];
+                    self.files[dir]
+                        .get_or_insert_with(|| extractor.create(flow, "http", name.take()))
+                        .write(consumed);
+                }
+                Some(Event::Complete) => {
+                    if let Some(file) = self.files[dir].take() {
+                        extractor.finish(file);
+                    }
+                }
+                Some(Event::Error(e)) => {
+                    debug!("file_extract: http parsing stopped: {}", e);
+                    if let Some(file) = self.files[dir].take() {
+                        extractor.finish(file);
+                    }
+                }
+                None if n > 0 => (),
+                None => break,
+            }
+        }
+    }
+
+    fn request_head(&mut self, head: &Head) -> BodyKind {
+        let method = head.start_line[0].clone();
+        let uri = head.start_line[1].clone();
+        self.names[0] = http_filename(head, &uri);
+        self.requests.push_back((method, uri));
+        // requests without framing headers have no body
+        head.body_kind()
+    }
+
+    fn response_head(&mut self, head: &Head) -> BodyKind {
+        let status = head.start_line[1].parse::<u16>().ok();
+        if let Some(100..=199) = status {
+            // interim response, the final response follows
+            if status == Some(101) {
+                self.stop_parsing();
+            }
+            return BodyKind::None;
+        }
+        let (method, uri) = self.requests.pop_front().unwrap_or_default();
+        self.names[1] = http_filename(head, &uri);
+        if method == "HEAD" || status == Some(204) || status == Some(304) {
+            BodyKind::None
+        } else if method == "CONNECT" && matches!(status, Some(200..=299)) {
+            // tunnel: the following data is not HTTP
+            self.stop_parsing();
+            BodyKind::None
+        } else {
+            match head.body_kind() {
+                BodyKind::None => BodyKind::UntilClose,
+                kind => kind,
+            }
+        }
+    }
+
+    fn stop_parsing(&mut self) {
+        for parser in &mut self.parsers {
+            parser.stop("not HTTP after upgrade or tunnel");
+        }
+    }
+}
+
+/// File name of an HTTP body: `Content-Disposition` file name, or last segment of the URI path
+fn http_filename(head: &Head, uri: &str) -> Option<String> {
+    if let Some(name) = head
+        .header("content-disposition")
+        .and_then(|d| mime_param(d, "filename"))
+    {
+        return Some(name);
+    }
+    let path = uri
+        .split(|c| c == '?' || c == '#')
+        .next()
+        .unwrap_or_default();
+    path.rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_owned())
+}
+
+/// FTP control connection
+#[derive(Default)]
+struct FtpControl {
+    /// Incomplete lines, by direction
+    lines: [Vec<u8>; 2],
+    /// Last data endpoint announced
+    endpoint: Option<(IpAddr, u16)>,
+}
+
+impl FtpControl {
+    fn update(
+        &mut self,
+        flow: &Flow,
+        data: &[u8],
+        to_server: bool,
+        ftp_data: &mut FnvHashMap<(IpAddr, u16), Option<String>>,
+    ) {
+        let dir = if to_server { 0 } else { 1 };
+        for line in read_lines(&mut self.lines[dir], data) {
+            let line = String::from_utf8_lossy(&line);
+            let (command, args) = match line.split_once(' ') {
+                Some((command, args)) => (command, args.trim()),
+                None => (&*line, ""),
+            };
+            let command = command.to_ascii_uppercase();
+            // passive mode: the client connects to the server (the address of the control
+            // connection is used, the announced address can be translated by a NAT)
+            let server = flow.five_tuple.dst;
+            let endpoint = match (to_server, command.as_str()) {
+                (true, "PORT") => ftp_address(args),
+                (true, "EPRT") => ftp_extended_address(args, None),
+                (true, "RETR") | (true, "STOR") | (true, "APPE") => {
+                    if let Some(endpoint) = self.endpoint {
+                        ftp_data.insert(endpoint, Some(args.to_owned()));
+                    }
+                    None
+                }
+                (false, "227") => ftp_address(args).map(|(_, port)| (server, port)),
+                (false, "229") => ftp_extended_address(args, Some(server)),
+                _ => None,
+            };
+            if let Some(endpoint) = endpoint {
+                ftp_data.insert(endpoint, None);
+                self.endpoint = Some(endpoint);
+            }
+        }
+    }
+}
+
+/// Parse an address of `PORT` commands and `PASV` replies (`h1,h2,h3,h4,p1,p2`)
+fn ftp_address(args: &str) -> Option<(IpAddr, u16)> {
+    let start = args.find(|c: char| c.is_ascii_digit())?;
+    let numbers: Vec<u8> = args[start..]
+        .split(|c: char| !c.is_ascii_digit() && c != ',')
+        .next()?
+        .split(',')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    match numbers[..] {
+        [h1, h2, h3, h4, p1, p2] => {
+            Some((IpAddr::from([h1, h2, h3, h4]), u16::from_be_bytes([p1, p2])))
+        }
+        _ => None,
+    }
+}
+
+/// Parse an address of `EPRT` commands (`|proto|address|port|`) and `EPSV` replies
+/// (`(|||port|)`, the address is the one of the server)
+fn ftp_extended_address(args: &str, server: Option<IpAddr>) -> Option<(IpAddr, u16)> {
+    let args = args.get(args.find('(').map_or(0, |pos| pos + 1)..)?;
+    let delimiter = args.chars().next()?;
+    let fields: Vec<_> = args.split(delimiter).collect();
+    let port = fields.get(3)?.parse().ok()?;
+    match server {
+        Some(server) => Some((server, port)),
+        None => Some((fields.get(2)?.parse().ok()?, port)),
+    }
+}
+
+/// SMB state of a flow
+#[derive(Default)]
+struct SmbExtract {
+    directions: [smb::Direction; 2],
+    /// Names of the files being opened, by message ID
+    creates: FnvHashMap<u64, String>,
+    /// Names of opened files, by file ID
+    names: FnvHashMap<[u8; 16], String>,
+    /// Pending reads (file ID and offset), by message ID
+    reads: FnvHashMap<u64, ([u8; 16], u64)>,
+    /// Files being extracted, by file ID
+    files: BTreeMap<[u8; 16], CarvedFile>,
+}
+
+impl SmbExtract {
+    fn update(&mut self, flow: &Flow, data: &[u8], to_server: bool, extractor: &mut Extractor) {
+        let mut messages = Vec::new();
+        self.directions[if to_server { 0 } else { 1 }].push_truncated(
+            data,
+            SMB_MAX_MESSAGE,
+            &mut messages,
+        );
+        for msg in messages {
+            // SMB1 is not supported
+            if !msg.starts_with(b"\xfeSMB") {
+                continue;
+            }
+            // compounded requests
+            let mut offset = 0;
+            while let Some(next) = msg
+                .get(offset..)
+                .and_then(|m| self.handle_smb2(m, flow, extractor))
+            {
+                if next == 0 {
+                    break;
+                }
+                offset += next;
+            }
+        }
+    }
+
+    /// Parse a SMB2 message, and return the offset of the next compounded message (or 0)
+    fn handle_smb2(&mut self, msg: &[u8], flow: &Flow, extractor: &mut Extractor) -> Option<usize> {
+        let status = le_u32(msg, 8)?;
+        let command = le_u16(msg, 12)?;
+        let response = le_u32(msg, 16)? & 0x1 != 0;
+        let next = le_u32(msg, 20)? as usize;
+        let message_id = le_u64(msg, 24)?;
+        // the final response of an asynchronous operation follows
+        if response && status == SMB_STATUS_PENDING {
+            return Some(next);
+        }
+        let msg = if next > 0 { msg.get(..next)? } else { msg };
+        match (command, response) {
+            // CREATE
+            (0x05, false) => {
+                if let Some(name) = smb2_buffer(msg, 64 + 44) {
+                    self.creates.insert(message_id, smb_string(name, true));
+                }
+            }
+            (0x05, true) => {
+                let name = self.creates.remove(&message_id);
+                if let (0, Some(name), Some(file_id)) = (status, name, file_id(msg, 64 + 64)) {
+                    self.names.insert(file_id, name);
+                }
+            }
+            // CLOSE
+            (0x06, false) => {
+                if let Some(file_id) = file_id(msg, 64 + 8) {
+                    self.names.remove(&file_id);
+                    if let Some(file) = self.files.remove(&file_id) {
+                        extractor.finish(file);
+                    }
+                }
+            }
+            // READ
+            (0x08, false) => {
+                if let (Some(offset), Some(file_id)) = (le_u64(msg, 64 + 8), file_id(msg, 64 + 16))
+                {
+                    self.reads.insert(message_id, (file_id, offset));
+                }
+            }
+            (0x08, true) => {
+                let read = self.reads.remove(&message_id);
+                let data_offset = usize::from(*msg.get(64 + 2)?);
+                let len = le_u32(msg, 64 + 4)? as usize;
+                let data = msg.get(data_offset..data_offset + len);
+                if let (0, Some((file_id, offset)), Some(data)) = (status, read, data) {
+                    self.write(flow, file_id, offset, data, extractor);
+                }
+            }
+            // WRITE
+            (0x09, false) => {
+                let data_offset = usize::from(le_u16(msg, 64 + 2)?);
+                let len = le_u32(msg, 64 + 4)? as usize;
+                let data = msg.get(data_offset..data_offset + len);
+                let offset = le_u64(msg, 64 + 8);
+                if let (Some(data), Some(offset), Some(file_id)) =
+                    (data, offset, file_id(msg, 64 + 16))
+                {
+                    self.write(flow, file_id, offset, data, extractor);
+                }
+            }
+            _ => (),
+        }
+        Some(next)
+    }
+
+    fn write(
+        &mut self,
+        flow: &Flow,
+        file_id: [u8; 16],
+        offset: u64,
+        data: &[u8],
+        extractor: &mut Extractor,
+    ) {
+        if data.is_empty() {
+            return;
+        }
+        let names = &self.names;
+        self.files
+            .entry(file_id)
+            .or_insert_with(|| extractor.create(flow, "smb", names.get(&file_id).cloned()))
+            .write_at(offset, data);
+    }
+}
+
+fn le_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let lo = le_u32(data, offset)?;
+    let hi = le_u32(data, offset + 4)?;
+    Some((u64::from(hi) << 32) | u64::from(lo))
+}
+
+fn file_id(msg: &[u8], offset: usize) -> Option<[u8; 16]> {
+    let mut id = [0; 16];
+    id.copy_from_slice(msg.get(offset..offset + 16)?);
+    Some(id)
+}
+
+/// SMTP state of a flow
+#[derive(Default)]
+struct SmtpExtract {
+    /// Incomplete lines, by direction
+    lines: [Vec<u8>; 2],
+    /// The client sent a `DATA` command
+    data_command: bool,
+    /// The client is sending a message
+    in_message: bool,
+    message: Vec<u8>,
+}
+
+impl SmtpExtract {
+    fn update(&mut self, flow: &Flow, data: &[u8], to_server: bool, extractor: &mut Extractor) {
+        let dir = if to_server { 0 } else { 1 };
+        for line in read_lines(&mut self.lines[dir], data) {
+            if !to_server {
+                // the message follows a 354 reply
+                self.in_message = self.data_command && line.starts_with(b"354");
+                self.data_command = false;
+            } else if !self.in_message {
+                self.data_command = line.eq_ignore_ascii_case(b"DATA");
+            } else if line == b"." {
+                self.in_message = false;
+                let message = std::mem::take(&mut self.message);
+                for (name, content) in mime_attachments(&message) {
+                    let mut file = extractor.create(flow, "smtp", Some(name));
+                    file.write(&content);
+                    extractor.finish(file);
+                }
+            } else if (self.message.len() as u64) < extractor.max_size {
+                // remove dot-stuffing
+                let line = line.strip_prefix(b".").unwrap_or(&line);
+                self.message.extend_from_slice(line);
+                self.message.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+}
+
+/// Get the attachments (file name and decoded content) of a MIME message
+fn mime_attachments(message: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut attachments = Vec::new();
+    mime_part(message, 0, &mut attachments);
+    attachments
+}
+
+fn mime_part(part: &[u8], depth: usize, attachments: &mut Vec<(String, Vec<u8>)>) {
+    let (head, body) = if part.starts_with(b"\r\n") {
+        (&part[..0], &part[2..])
+    } else {
+        match part.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => (&part[..pos], &part[pos + 4..]),
+            None => return,
+        }
+    };
+    let headers = mime_headers(head);
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    };
+    let content_type = header("content-type").unwrap_or("text/plain");
+    if content_type.to_ascii_lowercase().starts_with("multipart/") {
+        if let Some(boundary) = mime_param(content_type, "boundary") {
+            if depth < MAX_MIME_DEPTH {
+                for part in split_multipart(body, &boundary) {
+                    mime_part(part, depth + 1, attachments);
+                }
+            }
+        }
+        return;
+    }
+    // only parts with a file name are extracted
+    let name = header("content-disposition")
+        .and_then(|d| mime_param(d, "filename"))
+        .or_else(|| mime_param(content_type, "name"));
+    let name = match name {
+        Some(name) => name,
+        None => return,
+    };
+    let encoding = header("content-transfer-encoding")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let content = match encoding.as_str() {
+        "base64" => decode_base64(body).unwrap_or_else(|| body.to_vec()),
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.to_vec(),
+    };
+    attachments.push((name, content));
+}
+
+/// Parse MIME headers (unfolding continuation lines), with names in lowercase
+fn mime_headers(head: &[u8]) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(head);
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in text.split("\r\n") {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+    }
+    headers
+}
+
+/// Get a parameter of a header value (for ex. `filename` in `attachment; filename="a.txt"`)
+fn mime_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim().trim_matches('"').to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Split the body of a multipart message in parts
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut start = None;
+    let mut offset = 0;
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(delimiter.as_bytes()) {
+            // the end of line before the delimiter is part of the delimiter
+            if let Some(start) = start {
+                let end = offset.saturating_sub(2).max(start);
+                parts.push(&body[start..end]);
+            }
+            if line[delimiter.len()..].starts_with(b"--") {
+                return parts;
+            }
+            start = Some(offset + line.len());
+        }
+        offset += line.len();
+    }
+    // missing final delimiter
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+fn decode_base64(data: &[u8]) -> Option<Vec<u8>> {
+    let data: Vec<u8> = data
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    Base64::decode_vec(std::str::from_utf8(&data).ok()?).ok()
+}
+
+fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'=' {
+            // soft line break
+            if data[i + 1..].starts_with(b"\r\n") {
+                i += 3;
+                continue;
+            }
+            let byte = data
+                .get(i + 1..i + 3)
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            if let Some(byte) = byte {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(data[i]);
+        i += 1;
+    }
+    decoded
+}
+
+/// Add data to a line buffer, and return the complete lines (without the end of line)
+fn read_lines(buf: &mut Vec<u8>, data: &[u8]) -> Vec<Vec<u8>> {
+    buf.extend_from_slice(data);
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while let Some(pos) = buf[start..].iter().position(|&b| b == b'\n') {
+        let line = &buf[start..start + pos];
+        lines.push(line.strip_suffix(b"\r").unwrap_or(line).to_vec());
+        start += pos + 1;
+    }
+    buf.drain(..start);
+    if buf.len() > MAX_LINE {
+        buf.clear();
+    }
+    lines
+}
+
+/// Build a file name from the last component of a path, keeping only safe characters
+fn sanitize(name: Option<&str>) -> String {
+    let name = name
+        .and_then(|n| n.rsplit(|c| c == '/' || c == '\\').next())
+        .unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(100)
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "file".to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Extractor, FileExtract};
+    use crate::plugin::Plugin;
+    use fnv::FnvHashMap;
+    use libpcap_tools::{FiveTuple, Flow};
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const HELLO_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn plugin(name: &str) -> FileExtract {
+        let dir =
+            std::env::temp_dir().join(format!("file-extract-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).expect("cannot create test directory");
+        FileExtract {
+            extractor: Extractor {
+                dir,
+                max_size: 1 << 20,
+                count: 0,
+                manifest: Vec::new(),
+            },
+            flows: FnvHashMap::default(),
+            ftp_data: FnvHashMap::default(),
+        }
+    }
+
+    fn flow(flow_id: u64, src_port: u16, dst_port: u16) -> Flow {
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port,
+            dst_port,
+        };
+        let mut flow = Flow::new(&five_tuple, 0, 0);
+        flow.flow_id = flow_id;
+        flow
+    }
+
+    /// Check the manifest and the content of the extracted files, and remove them
+    fn check_files(plugin: FileExtract, expected: &[(&str, &str)]) {
+        let manifest = &plugin.extractor.manifest;
+        assert_eq!(manifest.len(), expected.len());
+        for (entry, (filename, content)) in manifest.iter().zip(expected) {
+            assert_eq!(entry.filename.as_deref(), Some(*filename));
+            let path = plugin.extractor.dir.join(&entry.path);
+            let data = fs::read(path).expect("cannot read extracted file");
+            assert_eq!(data, content.as_bytes());
+            assert_eq!(entry.size, content.len() as u64);
+            assert_eq!(entry.sha256.as_deref(), Some(HELLO_SHA256));
+        }
+        let _ = fs::remove_dir_all(&plugin.extractor.dir);
+    }
+
+    #[test]
+    fn file_extract_http_ftp() {
+        let mut plugin = plugin("http-ftp");
+        let http = flow(1, 49152, 80);
+        plugin.handle_payload(&http, b"GET /files/hello.txt?x=1 HTTP/1.1\r\n\r\n", true);
+        plugin.handle_payload(
+            &http,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello",
+            false,
+        );
+        plugin.handle_payload(&http, b" world", false);
+
+        let control = flow(2, 49153, 21);
+        plugin.handle_payload(&control, b"220 ready\r\n", false);
+        plugin.handle_payload(&control, b"PASV\r\n", true);
+        plugin.handle_payload(
+            &control,
+            b"227 Entering Passive Mode (192,168,0,2,195,80)\r\n",
+            false,
+        );
+        plugin.handle_payload(&control, b"RETR pub/notes.txt\r\n", true);
+        // the data connection uses the address of the control connection (port 50000)
+        let data = flow(3, 49154, 50000);
+        plugin.handle_payload(&data, b"hello ", false);
+        plugin.handle_payload(&data, b"world", false);
+        for flow in &[http, control, data] {
+            plugin.flow_destroyed(flow);
+        }
+        check_files(
+            plugin,
+            &[
+                ("hello.txt", "hello world"),
+                ("pub/notes.txt", "hello world"),
+            ],
+        );
+    }
+
+    /// Build a SMB2 message, with its NetBIOS header
+    fn smb2(command: u8, response: bool, message_id: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; 64];
+        header[..4].copy_from_slice(b"\xfeSMB");
+        header[12] = command;
+        header[16] = response as u8;
+        header[24] = message_id;
+        let len = (header.len() + body.len()) as u32;
+        let mut msg = len.to_be_bytes().to_vec();
+        msg.extend_from_slice(&header);
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    fn smb2_write(offset: u8, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![0; 48];
+        body[2] = 64 + 48;
+        body[4] = data.len() as u8;
+        body[8] = offset;
+        body[16..32].copy_from_slice(&[7; 16]);
+        body.extend_from_slice(data);
+        smb2(0x09, false, 3 + offset, &body)
+    }
+
+    #[test]
+    fn file_extract_smtp_smb() {
+        let mut plugin = plugin("smtp-smb");
+        let smtp = flow(1, 49152, 25);
+        let message = "Content-Type: multipart/mixed; boundary=\"b1\"\r\n\r\n\
+                       --b1\r\nContent-Type: text/plain\r\n\r\nsee attachment\r\n\
+                       --b1\r\nContent-Type: text/plain; name=\"hello.txt\"\r\n\
+                       Content-Transfer-Encoding: base64\r\n\r\naGVsbG8g\r\nd29ybGQ=\r\n\
+                       --b1--\r\n.\r\n";
+        plugin.handle_payload(&smtp, b"220 ready\r\n", false);
+        plugin.handle_payload(&smtp, b"DATA\r\n", true);
+        plugin.handle_payload(&smtp, b"354 go ahead\r\n", false);
+        plugin.handle_payload(&smtp, message.as_bytes(), true);
+
+        let smb = flow(2, 49153, 445);
+        // CREATE request and response (file ID 0x07...)
+        let name: Vec<u8> = "docs\\hello.txt"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let mut body = vec![0; 56];
+        body[44] = 64 + 56;
+        body[46] = name.len() as u8;
+        body.extend_from_slice(&name);
+        plugin.handle_payload(&smb, &smb2(0x05, false, 1, &body), true);
+        let mut body = vec![0; 88];
+        body[64..80].copy_from_slice(&[7; 16]);
+        plugin.handle_payload(&smb, &smb2(0x05, true, 1, &body), false);
+        // writes, out of order
+        plugin.handle_payload(&smb, &smb2_write(6, b"world"), true);
+        plugin.handle_payload(&smb, &smb2_write(0, b"hello "), true);
+        // CLOSE
+        let mut body = vec![0; 24];
+        body[8..24].copy_from_slice(&[7; 16]);
+        plugin.handle_payload(&smb, &smb2(0x06, false, 4, &body), true);
+        check_files(
+            plugin,
+            &[
+                ("hello.txt", "hello world"),
+                ("docs\\hello.txt", "hello world"),
+            ],
+        );
+    }
+}
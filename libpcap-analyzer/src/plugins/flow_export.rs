@@ -0,0 +1,142 @@
+//! Plugin exporting flows to a CSV file, one row per flow
+//!
+//! The plugin is enabled by setting `filename` in section `[plugin.flow_export]`. Relative
+//! file names are created in the output directory. Rows are written when flows are destroyed
+//! (expired, or at the end of the analysis).
+//!
+//! Timestamps and durations are in seconds. The application protocol is detected using the
+//! first payload of the flow (see `detect_app_proto`), and is empty if unknown. The direction
+//! column tells how the client and server were found (see `FlowDirection`).
+
+use crate::app_proto::detect_app_proto;
+use crate::output::{self, WriteErrors};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, Flow, FlowID, Packet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+const CSV_HEADER: &str = "flow_id,proto,src_ip,src_port,dst_ip,dst_port,first_seen,last_seen,\
+    duration,packets_to_server,packets_to_client,bytes_to_server,bytes_to_client,tcp_flags,\
+    app_proto,direction";
+
+pub struct FlowExport {
+    writer: BufWriter<File>,
+    /// Detected application protocol, for flows with payload
+    app_protos: FnvHashMap<FlowID, Option<&'static str>>,
+    write_errors: WriteErrors,
+}
+
+pub struct FlowExportBuilder;
+
+impl PluginBuilder for FlowExportBuilder {
+    fn name(&self) -> &'static str { "FlowExportBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let filename = match config.plugin_config("flow_export").get("filename") {
+            Some(filename) => filename.to_owned(),
+            None => {
+                debug!("flow_export: no output file configured");
+                return Ok(());
+            }
+        };
+        let file = output::create_file(output::get_output_dir(config), &filename).map_err(|e| {
+            PluginBuilderError::InvalidConfig(format!(
+                "flow_export: cannot create {}: {}",
+                filename, e
+            ))
+        })?;
+        let plugin = FlowExport {
+            writer: BufWriter::new(file),
+            app_protos: FnvHashMap::default(),
+            write_errors: WriteErrors::new("flow_export: could not write flows"),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("flow_export");
+        if config.contains("filename") && config.get("filename").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "flow_export: filename must be a string".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for FlowExport {
+    fn name(&self) -> &'static str {
+        "FlowExport"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+
+    fn pre_process(&mut self) {
+        let res = writeln!(self.writer, "{}", CSV_HEADER);
+        self.write_errors.check(res);
+    }
+
+    fn post_process(&mut self) {
+        let res = self.writer.flush();
+        self.write_errors.check(res);
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        if let (Some(flow), Some(data)) = (pinfo.flow, pinfo.l4_payload) {
+            if !data.is_empty() {
+                self.app_protos
+                    .entry(flow.flow_id)
+                    .or_insert_with(|| detect_app_proto(pinfo.five_tuple, data));
+            }
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let app_proto = self.app_protos.remove(&flow.flow_id).flatten();
+        let res = write_flow(&mut self.writer, flow, app_proto);
+        self.write_errors.check(res);
+    }
+
+    fn save_results(&mut self, _path: &str) -> Result<(), &'static str> {
+        self.write_errors.result()
+    }
+}
+
+fn format_ts(ts: Duration) -> String {
+    format!("{}.{:09}", ts.secs, ts.nanos)
+}
+
+fn write_flow<W: Write>(w: &mut W, flow: &Flow, app_proto: Option<&str>) -> io::Result<()> {
+    let t5 = &flow.five_tuple;
+    writeln!(
+        w,
+        "{},{},{},{},{},{},{},{},{},{},{},{},{},0x{:02x},{},{}",
+        flow.flow_id,
+        t5.proto,
+        t5.src,
+        t5.src_port,
+        t5.dst,
+        t5.dst_port,
+        format_ts(flow.first_seen),
+        format_ts(flow.last_seen),
+        format_ts(flow.last_seen - flow.first_seen),
+        flow.packets_to_server,
+        flow.packets_to_client(),
+        flow.bytes_to_server,
+        flow.bytes_to_client(),
+        flow.tcp_flags,
+        app_proto.unwrap_or(""),
+        flow.direction.as_str()
+    )
+}
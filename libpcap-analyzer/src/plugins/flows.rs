@@ -1,6 +1,7 @@
 //! Plugin to get/save information on flows
 
 use crate::plugin::Plugin;
+use crate::report::PluginOutput;
 use crate::{output, plugin_builder, PLUGIN_FLOW_DEL, PLUGIN_FLOW_NEW};
 use indexmap::IndexMap;
 use libpcap_tools::{Flow, FlowID};
@@ -21,6 +22,9 @@ impl Plugin for FlowsInfo {
     fn plugin_type(&self) -> u16 {
         PLUGIN_FLOW_NEW | PLUGIN_FLOW_DEL
     }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
 
     fn flow_destroyed(&mut self, flow: &Flow) {
         let f = flow.clone();
@@ -32,6 +36,10 @@ impl Plugin for FlowsInfo {
         Some(Box::new(v))
     }
 
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
     fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
         let results = self.get_results_json();
         // save data to file
@@ -46,9 +54,9 @@ impl FlowsInfo {
         let iter = self.flows.iter().map(|(&flow_id, f)| {
             if let Value::Object(mut m) = json!(f.five_tuple) {
                 m.insert("flow_id".into(), json!(flow_id));
-                let first_seen = format!("{}.{}", f.first_seen.secs, f.first_seen.micros);
+                let first_seen = format!("{}.{:09}", f.first_seen.secs, f.first_seen.nanos);
                 m.insert("first_seen".into(), json!(first_seen));
-                let last_seen = format!("{}.{}", f.last_seen.secs, f.last_seen.micros);
+                let last_seen = format!("{}.{:09}", f.last_seen.secs, f.last_seen.nanos);
                 m.insert("last_seen".into(), json!(last_seen));
                 (flow_id.to_string(), Value::Object(m))
             } else {
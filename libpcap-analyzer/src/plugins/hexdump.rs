@@ -18,6 +18,9 @@ impl Plugin for HexDump {
     fn plugin_type(&self) -> u16 {
         PLUGIN_L3|PLUGIN_L4
     }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
 
     fn handle_layer_network<'s, 'i>(
         &'s mut self,
@@ -0,0 +1,290 @@
+//! Plugin building a profile of each IP address
+//!
+//! Profiles are built from the flows, when they are destroyed: for each address, the roles
+//! (client if it initiated flows, server if it answered flows), the ports served, the protocols
+//! spoken (transport, and application protocol detected using the first payload of the flow, see
+//! `detect_app_proto`), the first and last time it was seen, and the number of packets and bytes
+//! sent and received.
+//!
+//! The names resolving to an address are taken from the A and AAAA records of the captured DNS
+//! responses. Only addresses seen in flows are reported.
+
+use crate::app_proto::{detect_app_proto, dns_answer_addrs};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+
+/// Maximum number of DNS names kept for each address
+const MAX_DNS_NAMES: usize = 32;
+
+#[derive(Debug, Serialize)]
+struct HostProfile {
+    ip: IpAddr,
+    roles: BTreeSet<&'static str>,
+    /// Ports served, as `proto/port` (e.g. `tcp/443`)
+    ports_served: BTreeSet<String>,
+    protocols: BTreeSet<&'static str>,
+    #[serde(serialize_with = "serialize_ts")]
+    first_seen: Duration,
+    #[serde(serialize_with = "serialize_ts")]
+    last_seen: Duration,
+    /// Number of flows initiated
+    client_flows: u64,
+    /// Number of flows answered
+    server_flows: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl HostProfile {
+    fn new(ip: IpAddr, ts: Duration) -> Self {
+        HostProfile {
+            ip,
+            roles: BTreeSet::new(),
+            ports_served: BTreeSet::new(),
+            protocols: BTreeSet::new(),
+            first_seen: ts,
+            last_seen: ts,
+            client_flows: 0,
+            server_flows: 0,
+            packets_sent: 0,
+            packets_received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HostProfiles {
+    hosts: FnvHashMap<IpAddr, HostProfile>,
+    /// Detected application protocol, for flows with payload
+    app_protos: FnvHashMap<FlowID, Option<&'static str>>,
+    /// Names resolving to each address, from DNS responses
+    dns_names: FnvHashMap<IpAddr, BTreeSet<String>>,
+}
+
+plugin_builder!(HostProfiles, HostProfilesBuilder);
+
+impl Plugin for HostProfiles {
+    fn name(&self) -> &'static str {
+        "HostProfiles"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let data = match pinfo.l4_payload {
+            Some(data) if !data.is_empty() => data,
+            _ => return PluginResult::None,
+        };
+        if let Some(flow) = pinfo.flow {
+            self.app_protos
+                .entry(flow.flow_id)
+                .or_insert_with(|| detect_app_proto(pinfo.five_tuple, data));
+        }
+        if pinfo.five_tuple.src_port == 53 {
+            match pinfo.five_tuple.proto {
+                17 => self.add_dns_response(data),
+                // skip the length prefix (only the first message of a segment is parsed)
+                6 if data.len() > 2 => self.add_dns_response(&data[2..]),
+                _ => (),
+            }
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let app_proto = self.app_protos.remove(&flow.flow_id).flatten();
+        self.add_flow(flow, app_proto);
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "host_profile.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl HostProfiles {
+    fn add_dns_response(&mut self, data: &[u8]) {
+        for (name, ip) in dns_answer_addrs(data) {
+            let names = self.dns_names.entry(ip).or_default();
+            if names.len() < MAX_DNS_NAMES {
+                names.insert(name);
+            }
+        }
+    }
+
+    fn add_flow(&mut self, flow: &Flow, app_proto: Option<&'static str>) {
+        let t5 = &flow.five_tuple;
+        let transport = proto_name(t5.proto);
+        // the server only has this role if it answered
+        let answered = flow.packets_to_client() > 0;
+        for &(ip, is_client) in &[(t5.src, true), (t5.dst, false)] {
+            let host = self
+                .hosts
+                .entry(ip)
+                .or_insert_with(|| HostProfile::new(ip, flow.first_seen));
+            host.first_seen = host.first_seen.min(flow.first_seen);
+            host.last_seen = host.last_seen.max(flow.last_seen);
+            host.protocols.insert(transport);
+            host.protocols.extend(app_proto);
+            let (sent, received) = if is_client {
+                host.roles.insert("client");
+                host.client_flows += 1;
+                (
+                    (flow.packets_to_server, flow.bytes_to_server),
+                    (flow.packets_to_client(), flow.bytes_to_client()),
+                )
+            } else {
+                if answered {
+                    host.roles.insert("server");
+                    host.server_flows += 1;
+                    if matches!(t5.proto, 6 | 17 | 132) {
+                        host.ports_served
+                            .insert(format!("{}/{}", transport, t5.dst_port));
+                    }
+                }
+                (
+                    (flow.packets_to_client(), flow.bytes_to_client()),
+                    (flow.packets_to_server, flow.bytes_to_server),
+                )
+            };
+            host.packets_sent += sent.0;
+            host.bytes_sent += sent.1;
+            host.packets_received += received.0;
+            host.bytes_received += received.1;
+        }
+    }
+
+    fn get_results_json(&self) -> Value {
+        let mut hosts: Vec<_> = self.hosts.values().collect();
+        hosts.sort_by_key(|h| h.ip);
+        let hosts: Vec<_> = hosts
+            .into_iter()
+            .map(|h| {
+                let mut v = json!(h);
+                v["dns_names"] = match self.dns_names.get(&h.ip) {
+                    Some(names) => json!(names),
+                    None => json!([]),
+                };
+                v
+            })
+            .collect();
+        json!({
+            "host_profile": {
+                "hosts": hosts,
+            }
+        })
+    }
+}
+
+fn proto_name(proto: u8) -> &'static str {
+    match proto {
+        1 => "icmp",
+        6 => "tcp",
+        17 => "udp",
+        58 => "icmpv6",
+        132 => "sctp",
+        _ => "other",
+    }
+}
+
+fn serialize_ts<S: serde::Serializer>(ts: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format!("{}.{:09}", ts.secs, ts.nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HostProfiles;
+    use libpcap_tools::{Duration, FiveTuple, Flow};
+
+    fn flow(src: &str, dst: &str, dst_port: u16, ts: u32, to_client: u64) -> Flow {
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: src.parse().expect("invalid address"),
+            dst: dst.parse().expect("invalid address"),
+            src_port: 49152,
+            dst_port,
+        };
+        let mut flow = Flow::with_timestamp(&five_tuple, Duration::new(ts, 0));
+        flow.update(Duration::new(ts, 0), 100, 0x02, true);
+        for i in 0..to_client {
+            flow.update(Duration::new(ts + 1 + i as u32, 0), 1000, 0x12, false);
+        }
+        flow
+    }
+
+    #[test]
+    fn host_profile_roles() {
+        let mut plugin = HostProfiles::default();
+        // DNS response for www.example.com, A 10.0.0.2
+        let mut response = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        response.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0x3c, 0, 4]);
+        response.extend_from_slice(&[10, 0, 0, 2]);
+        plugin.add_dns_response(&response);
+
+        plugin.add_flow(&flow("10.0.0.1", "10.0.0.2", 443, 10, 2), Some("tls"));
+        plugin.add_flow(&flow("10.0.0.3", "10.0.0.2", 22, 5, 1), Some("ssh"));
+        // unanswered: 10.0.0.4 is not a server
+        plugin.add_flow(&flow("10.0.0.2", "10.0.0.4", 80, 20, 0), None);
+
+        let results = plugin.get_results_json();
+        let hosts = &results["host_profile"]["hosts"];
+        assert_eq!(hosts.as_array().map(Vec::len), Some(4));
+        let host = &hosts[1];
+        assert_eq!(host["ip"], "10.0.0.2");
+        assert_eq!(host["roles"], serde_json::json!(["client", "server"]));
+        assert_eq!(
+            host["ports_served"],
+            serde_json::json!(["tcp/22", "tcp/443"])
+        );
+        assert_eq!(host["protocols"], serde_json::json!(["ssh", "tcp", "tls"]));
+        assert_eq!(host["dns_names"], serde_json::json!(["www.example.com"]));
+        assert_eq!(host["first_seen"], "5.000000000");
+        assert_eq!(host["last_seen"], "20.000000000");
+        assert_eq!(host["server_flows"], 2);
+        assert_eq!(host["client_flows"], 1);
+        assert_eq!(host["bytes_sent"], 3100);
+        assert_eq!(host["bytes_received"], 200);
+        assert_eq!(hosts[0]["roles"], serde_json::json!(["client"]));
+        assert_eq!(hosts[0]["bytes_received"], 2000);
+        assert_eq!(hosts[3]["roles"], serde_json::json!([]));
+        assert_eq!(hosts[3]["packets_received"], 1);
+        assert_eq!(hosts[3]["dns_names"], serde_json::json!([]));
+    }
+}
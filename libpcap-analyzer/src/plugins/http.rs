@@ -0,0 +1,653 @@
+//! Plugin reconstructing HTTP/1.x transactions
+//!
+//! Requests and responses of every HTTP flow are parsed (pipelined requests, chunked and
+//! length-delimited bodies), and matched in order. For each transaction, the plugin records the
+//! request method, host and URI, the response status and content type, the sizes of bodies (after
+//! removing the chunked encoding), and the timestamps of the request and response.
+//!
+//! Flows are detected using their first payload (see `detect_app_proto`). Parsing stops after a
+//! protocol upgrade (for ex. WebSocket) or a `CONNECT` tunnel, and on invalid data.
+//!
+//! Decrypted data of a flow (derived packets, for ex. from the `tls_decrypt` plugin) is parsed
+//! separately from the data of the flow.
+
+use crate::app_proto::detect_app_proto;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::cmp::min;
+
+/// Maximum size of the start line and headers of a message
+const MAX_HEADER_SIZE: usize = 65536;
+
+/// An HTTP request and its response
+#[derive(Debug, Serialize)]
+struct Transaction {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    method: Option<String>,
+    host: Option<String>,
+    uri: Option<String>,
+    version: Option<String>,
+    user_agent: Option<String>,
+    status: Option<u16>,
+    content_type: Option<String>,
+    request_body_len: u64,
+    response_body_len: u64,
+    /// Time of the request (start line)
+    request_ts: Option<String>,
+    /// Time of the response (status line)
+    response_ts: Option<String>,
+    /// Time of the end of the response
+    end_ts: Option<String>,
+}
+
+impl Transaction {
+    fn new(flow_id: FlowID, five_tuple: &FiveTuple) -> Self {
+        Transaction {
+            flow_id,
+            five_tuple: five_tuple.clone(),
+            method: None,
+            host: None,
+            uri: None,
+            version: None,
+            user_agent: None,
+            status: None,
+            content_type: None,
+            request_body_len: 0,
+            response_body_len: 0,
+            request_ts: None,
+            response_ts: None,
+            end_ts: None,
+        }
+    }
+}
+
+/// Start line and headers of a message
+#[derive(Debug, PartialEq)]
+pub(super) struct Head {
+    /// Fields of the start line (method, URI and version, or version, status and reason)
+    pub(super) start_line: Vec<String>,
+    /// Headers, with names in lowercase
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    pub(super) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get the body of the message, if it does not depend on the request
+    pub(super) fn body_kind(&self) -> BodyKind {
+        let chunked = self
+            .header("transfer-encoding")
+            .map_or(false, |v| v.to_ascii_lowercase().contains("chunked"));
+        if chunked {
+            BodyKind::Chunked
+        } else if let Some(len) = self.header("content-length") {
+            len.parse().map_or(BodyKind::Invalid, BodyKind::Length)
+        } else {
+            BodyKind::None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) enum BodyKind {
+    None,
+    Length(u64),
+    Chunked,
+    /// Body delimited by the end of the connection
+    UntilClose,
+    /// Invalid framing (for ex. an invalid `Content-Length`)
+    Invalid,
+}
+
+#[derive(Debug, PartialEq)]
+enum ChunkState {
+    Size,
+    Data(u64),
+    /// End of line after the data of a chunk
+    DataEnd,
+    Trailer,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Head,
+    Body(u64),
+    Chunked(ChunkState),
+    UntilClose,
+    /// Parsing stopped (invalid data, or not HTTP after an upgrade)
+    Stopped,
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) enum Event {
+    Head(Head),
+    /// Number of bytes of body
+    Body(usize),
+    /// End of the message
+    Complete,
+    Error(&'static str),
+}
+
+/// Parser of the messages of one direction of a flow
+#[derive(Debug)]
+pub(super) struct MessageParser {
+    state: State,
+    /// Incomplete headers or line
+    buf: Vec<u8>,
+}
+
+impl Default for MessageParser {
+    fn default() -> Self {
+        MessageParser {
+            state: State::Head,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl MessageParser {
+    /// Parse data, until the next event
+    ///
+    /// Returns the number of bytes consumed, and the event if any (`(0, None)` if more data is
+    /// needed). After a `Head` event, the body of the message must be set using `start_body`.
+    pub(super) fn parse(&mut self, data: &[u8]) -> (usize, Option<Event>) {
+        match self.state {
+            State::Head => {
+                // skip empty lines between messages
+                let mut skipped = 0;
+                if self.buf.is_empty() {
+                    skipped = data
+                        .iter()
+                        .take_while(|&&b| b == b'\r' || b == b'\n')
+                        .count();
+                }
+                let data = &data[skipped..];
+                let old_len = self.buf.len();
+                self.buf.extend_from_slice(data);
+                match find_head_end(&self.buf, old_len.saturating_sub(3)) {
+                    Some(end) => {
+                        let head = parse_head(&self.buf[..end]);
+                        self.buf.clear();
+                        // the body is set by the caller
+                        self.state = State::Body(0);
+                        let event = match head {
+                            Some(head) => Event::Head(head),
+                            None => self.stop("invalid start line or headers"),
+                        };
+                        (skipped + end - old_len, Some(event))
+                    }
+                    None if self.buf.len() > MAX_HEADER_SIZE => {
+                        (skipped + data.len(), Some(self.stop("headers too long")))
+                    }
+                    None => (skipped + data.len(), None),
+                }
+            }
+            State::Body(0) => {
+                self.state = State::Head;
+                (0, Some(Event::Complete))
+            }
+            State::Body(remaining) => {
+                let n = min(remaining, data.len() as u64);
+                self.state = State::Body(remaining - n);
+                body_event(n as usize)
+            }
+            State::Chunked(ChunkState::Data(remaining)) => {
+                let n = min(remaining, data.len() as u64);
+                self.state = if remaining == n {
+                    State::Chunked(ChunkState::DataEnd)
+                } else {
+                    State::Chunked(ChunkState::Data(remaining - n))
+                };
+                body_event(n as usize)
+            }
+            State::Chunked(_) => {
+                let (n, line) = self.read_line(data);
+                let line = match line {
+                    Some(line) => line,
+                    None if self.buf.len() > MAX_HEADER_SIZE => {
+                        return (n, Some(self.stop("chunk line too long")));
+                    }
+                    None => return (n, None),
+                };
+                let event = match self.state {
+                    State::Chunked(ChunkState::Size) => {
+                        let size = line.split(|&b| b == b';').next().unwrap_or_default();
+                        let size = std::str::from_utf8(size)
+                            .ok()
+                            .and_then(|s| u64::from_str_radix(s.trim(), 16).ok());
+                        match size {
+                            Some(0) => {
+                                self.state = State::Chunked(ChunkState::Trailer);
+                                None
+                            }
+                            Some(size) => {
+                                self.state = State::Chunked(ChunkState::Data(size));
+                                None
+                            }
+                            None => Some(self.stop("invalid chunk size")),
+                        }
+                    }
+                    State::Chunked(ChunkState::DataEnd) => {
+                        self.state = State::Chunked(ChunkState::Size);
+                        None
+                    }
+                    _ => {
+                        // trailer, until an empty line
+                        if line.is_empty() {
+                            self.state = State::Head;
+                            Some(Event::Complete)
+                        } else {
+                            None
+                        }
+                    }
+                };
+                (n, event)
+            }
+            State::UntilClose => body_event(data.len()),
+            State::Stopped => (data.len(), None),
+        }
+    }
+
+    /// Set the body of the message, after a `Head` event
+    pub(super) fn start_body(&mut self, kind: BodyKind) -> Option<Event> {
+        if self.state == State::Stopped {
+            return None;
+        }
+        self.state = match kind {
+            BodyKind::None => State::Body(0),
+            BodyKind::Length(len) => State::Body(len),
+            BodyKind::Chunked => State::Chunked(ChunkState::Size),
+            BodyKind::UntilClose => State::UntilClose,
+            BodyKind::Invalid => return Some(self.stop("invalid message length")),
+        };
+        None
+    }
+
+    /// Read a line, which can be split across calls
+    ///
+    /// Returns the number of bytes consumed, and the line without the end of line if complete.
+    fn read_line(&mut self, data: &[u8]) -> (usize, Option<Vec<u8>>) {
+        match data.iter().position(|&b| b == b'\n') {
+            Some(pos) => {
+                self.buf.extend_from_slice(&data[..pos]);
+                let mut line = std::mem::take(&mut self.buf);
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                (pos + 1, Some(line))
+            }
+            None => {
+                self.buf.extend_from_slice(data);
+                (data.len(), None)
+            }
+        }
+    }
+
+    pub(super) fn stop(&mut self, reason: &'static str) -> Event {
+        self.state = State::Stopped;
+        self.buf.clear();
+        Event::Error(reason)
+    }
+}
+
+fn body_event(n: usize) -> (usize, Option<Event>) {
+    if n > 0 {
+        (n, Some(Event::Body(n)))
+    } else {
+        (0, None)
+    }
+}
+
+/// Find the end of the headers (after the empty line), starting the search at `from`
+fn find_head_end(buf: &[u8], from: usize) -> Option<usize> {
+    let mut i = from;
+    while let Some(pos) = buf[i..].iter().position(|&b| b == b'\n') {
+        let end = i + pos + 1;
+        match buf.get(end) {
+            Some(b'\n') => return Some(end + 1),
+            Some(b'\r') if buf.get(end + 1) == Some(&b'\n') => return Some(end + 2),
+            _ => i = end,
+        }
+    }
+    None
+}
+
+fn parse_head(data: &[u8]) -> Option<Head> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines().map(|l| l.trim_end_matches('\r'));
+    let start_line: Vec<String> = lines.next()?.splitn(3, ' ').map(|s| s.to_owned()).collect();
+    if start_line.len() < 2 {
+        return None;
+    }
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    Some(Head {
+        start_line,
+        headers,
+    })
+}
+
+fn format_ts(ts: Duration) -> String {
+    format!("{}.{:09}", ts.secs, ts.nanos)
+}
+
+/// HTTP state of a flow
+#[derive(Default)]
+struct HttpFlow {
+    request: MessageParser,
+    response: MessageParser,
+    /// Transactions of the flow, in the order of requests
+    transactions: Vec<Transaction>,
+    /// Index of the transaction of the response being parsed, or of the next response
+    next_response: usize,
+    /// true if the response being parsed is an interim response (1xx)
+    interim_response: bool,
+}
+
+impl HttpFlow {
+    fn handle_request(&mut self, data: &[u8], ts: Duration, flow_id: FlowID, t5: &FiveTuple) {
+        let mut data = data;
+        loop {
+            let (n, event) = self.request.parse(data);
+            data = &data[n..];
+            match event {
+                Some(Event::Head(head)) => {
+                    let mut tx = Transaction::new(flow_id, t5);
+                    let mut start_line = head.start_line.iter().cloned();
+                    tx.method = start_line.next();
+                    tx.uri = start_line.next();
+                    tx.version = start_line.next();
+                    tx.host = head.header("host").map(|s| s.to_owned());
+                    tx.user_agent = head.header("user-agent").map(|s| s.to_owned());
+                    tx.request_ts = Some(format_ts(ts));
+                    self.transactions.push(tx);
+                    // requests without framing headers have no body
+                    log_error(self.request.start_body(head.body_kind()));
+                }
+                Some(Event::Body(len)) => {
+                    if let Some(tx) = self.transactions.last_mut() {
+                        tx.request_body_len += len as u64;
+                    }
+                }
+                Some(Event::Complete) => (),
+                Some(event) => log_error(Some(event)),
+                None if n > 0 => (),
+                None => break,
+            }
+        }
+    }
+
+    fn handle_response(&mut self, data: &[u8], ts: Duration, flow_id: FlowID, t5: &FiveTuple) {
+        let mut data = data;
+        loop {
+            let (n, event) = self.response.parse(data);
+            data = &data[n..];
+            match event {
+                Some(Event::Head(head)) => {
+                    let status = head.start_line[1].parse::<u16>().ok();
+                    if let Some(100..=199) = status {
+                        // interim response, the final response follows
+                        if status == Some(101) {
+                            self.stop_parsing();
+                        }
+                        self.interim_response = true;
+                        log_error(self.response.start_body(BodyKind::None));
+                        continue;
+                    }
+                    if self.next_response >= self.transactions.len() {
+                        // response without request (for ex. the start of the flow was missed)
+                        self.transactions.push(Transaction::new(flow_id, t5));
+                    }
+                    let tx = &mut self.transactions[self.next_response];
+                    tx.status = status;
+                    tx.content_type = head.header("content-type").map(|s| s.to_owned());
+                    tx.response_ts = Some(format_ts(ts));
+                    let method = tx.method.as_deref().unwrap_or_default();
+                    let kind = if method == "HEAD" || status == Some(204) || status == Some(304) {
+                        BodyKind::None
+                    } else if method == "CONNECT" && matches!(status, Some(200..=299)) {
+                        // tunnel: the following data is not HTTP
+                        tx.end_ts = tx.response_ts.clone();
+                        self.next_response += 1;
+                        self.stop_parsing();
+                        return;
+                    } else {
+                        match head.body_kind() {
+                            BodyKind::None => BodyKind::UntilClose,
+                            kind => kind,
+                        }
+                    };
+                    log_error(self.response.start_body(kind));
+                }
+                Some(Event::Body(len)) => {
+                    if let Some(tx) = self.transactions.get_mut(self.next_response) {
+                        tx.response_body_len += len as u64;
+                    }
+                }
+                Some(Event::Complete) if self.interim_response => {
+                    self.interim_response = false;
+                }
+                Some(Event::Complete) => {
+                    if let Some(tx) = self.transactions.get_mut(self.next_response) {
+                        tx.end_ts = Some(format_ts(ts));
+                    }
+                    self.next_response += 1;
+                }
+                Some(event) => log_error(Some(event)),
+                None if n > 0 => (),
+                None => break,
+            }
+            // update the end of response for bodies delimited by the end of the connection
+            if self.response.state == State::UntilClose {
+                if let Some(tx) = self.transactions.get_mut(self.next_response) {
+                    tx.end_ts = Some(format_ts(ts));
+                }
+            }
+        }
+    }
+
+    fn stop_parsing(&mut self) {
+        self.request.state = State::Stopped;
+        self.response.state = State::Stopped;
+    }
+}
+
+fn log_error(event: Option<Event>) {
+    if let Some(Event::Error(e)) = event {
+        debug!("http: parsing stopped: {}", e);
+    }
+}
+
+#[derive(Default)]
+pub struct Http {
+    /// State of flows (and of their derived data), `None` if the flow is not HTTP
+    flows: FnvHashMap<(FlowID, bool), Option<HttpFlow>>,
+    /// Transactions of destroyed flows
+    transactions: Vec<Transaction>,
+}
+
+plugin_builder!(Http, HttpBuilder);
+
+impl Plugin for Http {
+    fn name(&self) -> &'static str {
+        "Http"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if pinfo.five_tuple.proto == 6 && !data.is_empty() => {
+                (flow, data)
+            }
+            _ => return PluginResult::None,
+        };
+        let key = (flow.flow_id, pinfo.derived);
+        let state = self.flows.entry(key).or_insert_with(|| {
+            if pinfo.to_server && detect_app_proto(pinfo.five_tuple, data) == Some("http") {
+                Some(HttpFlow::default())
+            } else {
+                None
+            }
+        });
+        if let Some(state) = state {
+            if pinfo.to_server {
+                state.handle_request(data, packet.ts, flow.flow_id, &flow.five_tuple);
+            } else {
+                state.handle_response(data, packet.ts, flow.flow_id, &flow.five_tuple);
+            }
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        for derived in [false, true] {
+            if let Some(Some(state)) = self.flows.remove(&(flow.flow_id, derived)) {
+                self.transactions.extend(state.transactions);
+            }
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "http-transactions.json")
+            .or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Http {
+    fn get_results_json(&mut self) -> Value {
+        // flows still active (if called before the end of the analysis)
+        let active = self
+            .flows
+            .values()
+            .flatten()
+            .flat_map(|state| &state.transactions);
+        let transactions: Vec<_> = self.transactions.iter().chain(active).collect();
+        json!({ "http-transactions": transactions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, HttpFlow, MessageParser};
+    use libpcap_tools::{Duration, FiveTuple};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn events(parser: &mut MessageParser, data: &[u8]) -> Vec<Event> {
+        let mut data = data;
+        let mut events = Vec::new();
+        loop {
+            let (n, event) = parser.parse(data);
+            data = &data[n..];
+            match event {
+                Some(event) => events.push(event),
+                None if n > 0 => (),
+                None => break,
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn http_chunked_body() {
+        let mut parser = MessageParser {
+            state: super::State::Chunked(super::ChunkState::Size),
+            buf: Vec::new(),
+        };
+        // chunks split across segments
+        let mut all = events(&mut parser, b"5\r\nhel");
+        all.extend(events(&mut parser, b"lo\r\n3;ext=1\r\nabc\r\n0\r\n\r\nGET"));
+        assert_eq!(
+            all,
+            vec![
+                Event::Body(3),
+                Event::Body(2),
+                Event::Body(3),
+                Event::Complete
+            ]
+        );
+        assert_eq!(parser.buf, b"GET");
+    }
+
+    #[test]
+    fn http_transactions() {
+        let t5 = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: 49152,
+            dst_port: 80,
+        };
+        let mut state = HttpFlow::default();
+        // two pipelined requests, the first one split across segments
+        let requests: &[&[u8]] = &[
+            b"POST /upload HTTP/1.1\r\nHost: exa",
+            b"mple.com\r\nContent-Length: 4\r\n\r\nabcdHEAD / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ];
+        for (i, data) in requests.iter().enumerate() {
+            state.handle_request(data, Duration::new(1, i as u32), 1, &t5);
+        }
+        let responses: &[&[u8]] = &[
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\n",
+            b"Content-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n",
+        ];
+        for (i, data) in responses.iter().enumerate() {
+            state.handle_response(data, Duration::new(2, i as u32), 1, &t5);
+        }
+        assert_eq!(state.transactions.len(), 2);
+        let tx = &state.transactions[0];
+        assert_eq!(tx.method.as_deref(), Some("POST"));
+        assert_eq!(tx.host.as_deref(), Some("example.com"));
+        assert_eq!(tx.uri.as_deref(), Some("/upload"));
+        assert_eq!(tx.status, Some(201));
+        assert_eq!(tx.content_type.as_deref(), Some("text/plain"));
+        assert_eq!((tx.request_body_len, tx.response_body_len), (4, 2));
+        assert_eq!(tx.request_ts.as_deref(), Some("1.000000000"));
+        assert_eq!(tx.end_ts.as_deref(), Some("2.000001000"));
+        // responses to HEAD requests have no body
+        let tx = &state.transactions[1];
+        assert_eq!(tx.method.as_deref(), Some("HEAD"));
+        assert_eq!(tx.status, Some(200));
+        assert_eq!(tx.response_body_len, 0);
+        assert_eq!(state.next_response, 2);
+    }
+}
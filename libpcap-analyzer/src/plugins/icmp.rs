@@ -0,0 +1,489 @@
+//! Plugin summarizing ICMP and ICMPv6 traffic
+//!
+//! The plugin reports:
+//!
+//! - the number of messages of each type
+//! - echo requests and replies, matched using the identifier and sequence number, with the
+//!   round-trip time for each pair of hosts
+//! - the codes of destination unreachable messages, and redirects
+//! - traceroutes: time exceeded messages sent by at least two routers for the same probes (source
+//!   and destination of the quoted packet)
+//! - an inventory of IPv6 routers (from router advertisements: flags, prefixes, MTU, DNS servers)
+//!   and neighbors (link-layer addresses from neighbor discovery)
+
+use super::dns_stats::{duration_secs, Latency};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, Packet};
+use pnet_base::MacAddr;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Maximum number of redirects recorded
+const MAX_REDIRECTS: usize = 1000;
+
+#[derive(Default)]
+struct EchoStats {
+    requests: u64,
+    replies: u64,
+    rtt: Latency,
+}
+
+#[derive(PartialEq, Serialize)]
+struct Redirect {
+    /// Router sending the redirect
+    router: IpAddr,
+    /// Host receiving the redirect
+    host: IpAddr,
+    /// Destination of the redirected packet
+    destination: Option<IpAddr>,
+    /// Better first hop for the destination
+    gateway: IpAddr,
+}
+
+struct Traceroute {
+    /// Transport protocol of the probes
+    proto: u8,
+    /// Routers, in the order of their first time exceeded message
+    hops: Vec<IpAddr>,
+}
+
+/// IPv6 router, from router advertisements
+#[derive(Default, Serialize)]
+struct Router {
+    mac: Option<String>,
+    advertisements: u64,
+    hop_limit: u8,
+    /// Router lifetime in seconds (0 if the router is not a default router)
+    lifetime: u16,
+    managed: bool,
+    other_config: bool,
+    mtu: Option<u32>,
+    prefixes: BTreeSet<String>,
+    dns_servers: BTreeSet<IpAddr>,
+}
+
+#[derive(Default)]
+pub struct Icmp {
+    /// Number of messages, by type
+    icmp_types: BTreeMap<String, u64>,
+    icmpv6_types: BTreeMap<String, u64>,
+    /// Number of destination unreachable messages, by code
+    unreachable: BTreeMap<String, u64>,
+    redirects: Vec<Redirect>,
+    /// Echo statistics, by (source, destination) of the requests
+    echo: FnvHashMap<(IpAddr, IpAddr), EchoStats>,
+    /// Echo requests waiting for a reply, by (source, destination, identifier, sequence)
+    pending_echo: FnvHashMap<(IpAddr, IpAddr, u16, u16), Duration>,
+    /// Time exceeded messages, by (source, destination) of the probes
+    traceroutes: FnvHashMap<(IpAddr, IpAddr), Traceroute>,
+    routers: BTreeMap<IpAddr, Router>,
+    /// Link-layer addresses of IPv6 neighbors
+    neighbors: BTreeMap<IpAddr, BTreeSet<String>>,
+    /// Neighbor solicitations for duplicate address detection
+    dad_probes: u64,
+}
+
+plugin_builder!(Icmp, IcmpBuilder);
+
+impl Plugin for Icmp {
+    fn name(&self) -> &'static str {
+        "Icmp"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let v6 = match pinfo.l4_type {
+            1 => false,
+            58 => true,
+            _ => return PluginResult::None,
+        };
+        let five_tuple = pinfo.five_tuple;
+        self.handle_icmp(packet.ts, five_tuple.src, five_tuple.dst, v6, pinfo.l4_data);
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "icmp.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Icmp {
+    fn handle_icmp(&mut self, ts: Duration, src: IpAddr, dst: IpAddr, v6: bool, data: &[u8]) {
+        let (icmp_type, code) = match data {
+            [icmp_type, code, ..] if data.len() >= 8 => (*icmp_type, *code),
+            _ => return,
+        };
+        let types = if v6 {
+            &mut self.icmpv6_types
+        } else {
+            &mut self.icmp_types
+        };
+        *types.entry(type_name(icmp_type, v6)).or_insert(0) += 1;
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        let seq = u16::from_be_bytes([data[6], data[7]]);
+        match (icmp_type, v6) {
+            (8, false) | (128, true) => {
+                self.echo.entry((src, dst)).or_default().requests += 1;
+                self.pending_echo.insert((src, dst, id, seq), ts);
+            }
+            (0, false) | (129, true) => {
+                let stats = self.echo.entry((dst, src)).or_default();
+                stats.replies += 1;
+                if let Some(request_ts) = self.pending_echo.remove(&(dst, src, id, seq)) {
+                    if ts >= request_ts {
+                        stats.rtt.add(duration_secs(ts - request_ts));
+                    }
+                }
+            }
+            (3, false) | (1, true) => {
+                *self
+                    .unreachable
+                    .entry(unreachable_name(code, v6))
+                    .or_insert(0) += 1;
+            }
+            (5, false) => {
+                let gateway = IpAddr::V4(Ipv4Addr::from([data[4], data[5], data[6], data[7]]));
+                let destination = quoted_packet(&data[8..], v6).map(|(_, dst, _)| dst);
+                self.add_redirect(src, dst, destination, gateway);
+            }
+            (137, true) => {
+                if let (Some(gateway), Some(destination)) = (ipv6(data, 8), ipv6(data, 24)) {
+                    self.add_redirect(src, dst, Some(destination), gateway);
+                }
+            }
+            // time exceeded in transit
+            (11, false) | (3, true) if code == 0 => {
+                if let Some((probe_src, probe_dst, proto)) = quoted_packet(&data[8..], v6) {
+                    let traceroute = self
+                        .traceroutes
+                        .entry((probe_src, probe_dst))
+                        .or_insert_with(|| Traceroute {
+                            proto,
+                            hops: Vec::new(),
+                        });
+                    if !traceroute.hops.contains(&src) {
+                        traceroute.hops.push(src);
+                    }
+                }
+            }
+            (134, true) => self.handle_router_advertisement(src, data),
+            // neighbor solicitation
+            (135, true) => {
+                if src == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+                    self.dad_probes += 1;
+                } else if let Some(mac) = ndp_option(data.get(24..), 1) {
+                    self.neighbors.entry(src).or_default().insert(mac);
+                }
+            }
+            // neighbor advertisement
+            (136, true) => {
+                if let (Some(target), Some(mac)) = (ipv6(data, 8), ndp_option(data.get(24..), 2)) {
+                    self.neighbors.entry(target).or_default().insert(mac);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn add_redirect(
+        &mut self,
+        router: IpAddr,
+        host: IpAddr,
+        destination: Option<IpAddr>,
+        gateway: IpAddr,
+    ) {
+        let redirect = Redirect {
+            router,
+            host,
+            destination,
+            gateway,
+        };
+        if self.redirects.len() < MAX_REDIRECTS && !self.redirects.contains(&redirect) {
+            self.redirects.push(redirect);
+        }
+    }
+
+    fn handle_router_advertisement(&mut self, src: IpAddr, data: &[u8]) {
+        if data.len() < 16 {
+            return;
+        }
+        let router = self.routers.entry(src).or_default();
+        router.advertisements += 1;
+        router.hop_limit = data[4];
+        router.managed = data[5] & 0x80 != 0;
+        router.other_config = data[5] & 0x40 != 0;
+        router.lifetime = u16::from_be_bytes([data[6], data[7]]);
+        for option in NdpOptions(&data[16..]) {
+            match option[0] {
+                // source link-layer address
+                1 => router.mac = mac_address(option),
+                // prefix information
+                3 if option.len() >= 32 => {
+                    if let Some(prefix) = ipv6(option, 16) {
+                        router.prefixes.insert(format!("{}/{}", prefix, option[2]));
+                    }
+                }
+                // MTU
+                5 => {
+                    router.mtu = Some(u32::from_be_bytes([
+                        option[4], option[5], option[6], option[7],
+                    ]))
+                }
+                // recursive DNS servers
+                25 => router.dns_servers.extend(
+                    (8..option.len())
+                        .step_by(16)
+                        .filter_map(|o| ipv6(option, o)),
+                ),
+                _ => (),
+            }
+        }
+    }
+
+    fn get_results_json(&self) -> Value {
+        let mut echo: Vec<_> = self.echo.iter().collect();
+        echo.sort_by(|a, b| a.0.cmp(b.0));
+        let echo: Vec<_> = echo
+            .into_iter()
+            .map(|((src, dst), stats)| {
+                json!({
+                    "src": src,
+                    "dst": dst,
+                    "requests": stats.requests,
+                    "replies": stats.replies,
+                    "rtt": stats.rtt.to_json(),
+                })
+            })
+            .collect();
+        let mut traceroutes: Vec<_> = self
+            .traceroutes
+            .iter()
+            .filter(|(_, t)| t.hops.len() >= 2)
+            .collect();
+        traceroutes.sort_by(|a, b| a.0.cmp(b.0));
+        let traceroutes: Vec<_> = traceroutes
+            .into_iter()
+            .map(|((src, dst), t)| {
+                json!({
+                    "src": src,
+                    "dst": dst,
+                    "proto": t.proto,
+                    "hops": t.hops,
+                })
+            })
+            .collect();
+        let neighbors: Vec<_> = self
+            .neighbors
+            .iter()
+            .map(|(ip, macs)| json!({ "ip": ip, "macs": macs }))
+            .collect();
+        let routers: Vec<_> = self
+            .routers
+            .iter()
+            .map(|(ip, router)| {
+                let mut v = json!(router);
+                v["ip"] = json!(ip);
+                v
+            })
+            .collect();
+        json!({
+            "icmp": {
+                "icmp_types": self.icmp_types,
+                "icmpv6_types": self.icmpv6_types,
+                "echo": echo,
+                "unreachable": self.unreachable,
+                "redirects": self.redirects,
+                "traceroutes": traceroutes,
+                "ndp": {
+                    "routers": routers,
+                    "neighbors": neighbors,
+                    "dad_probes": self.dad_probes,
+                },
+            }
+        })
+    }
+}
+
+/// Iterator over the options of a neighbor discovery message
+struct NdpOptions<'a>(&'a [u8]);
+
+impl<'a> Iterator for NdpOptions<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the length is in units of 8 bytes, including the type and length fields
+        let len = usize::from(*self.0.get(1)?) * 8;
+        let option = self.0.get(..len).filter(|_| len > 0)?;
+        self.0 = &self.0[len..];
+        Some(option)
+    }
+}
+
+/// Get the link-layer address from the first option of type `option_type`
+fn ndp_option(options: Option<&[u8]>, option_type: u8) -> Option<String> {
+    NdpOptions(options?)
+        .find(|o| o[0] == option_type)
+        .and_then(mac_address)
+}
+
+/// Link-layer address (Ethernet) of a source or target link-layer address option
+fn mac_address(option: &[u8]) -> Option<String> {
+    match option.get(2..8)? {
+        [a, b, c, d, e, f] => Some(MacAddr::new(*a, *b, *c, *d, *e, *f).to_string()),
+        _ => None,
+    }
+}
+
+fn ipv6(data: &[u8], offset: usize) -> Option<IpAddr> {
+    let bytes = <[u8; 16]>::try_from(data.get(offset..offset + 16)?).ok()?;
+    Some(IpAddr::V6(Ipv6Addr::from(bytes)))
+}
+
+/// Get the source, destination and protocol of the packet quoted in an ICMP error
+fn quoted_packet(data: &[u8], v6: bool) -> Option<(IpAddr, IpAddr, u8)> {
+    if v6 {
+        Some((ipv6(data, 8)?, ipv6(data, 24)?, *data.get(6)?))
+    } else {
+        let hdr = data.get(..20)?;
+        let src = Ipv4Addr::new(hdr[12], hdr[13], hdr[14], hdr[15]);
+        let dst = Ipv4Addr::new(hdr[16], hdr[17], hdr[18], hdr[19]);
+        Some((IpAddr::V4(src), IpAddr::V4(dst), hdr[9]))
+    }
+}
+
+fn type_name(icmp_type: u8, v6: bool) -> String {
+    let name = match (icmp_type, v6) {
+        (0, false) | (129, true) => "echo-reply",
+        (3, false) | (1, true) => "destination-unreachable",
+        (4, false) => "source-quench",
+        (5, false) | (137, true) => "redirect",
+        (8, false) | (128, true) => "echo-request",
+        (9, false) | (134, true) => "router-advertisement",
+        (10, false) | (133, true) => "router-solicitation",
+        (11, false) | (3, true) => "time-exceeded",
+        (12, false) | (4, true) => "parameter-problem",
+        (13, false) => "timestamp",
+        (14, false) => "timestamp-reply",
+        (2, true) => "packet-too-big",
+        (130, true) => "multicast-listener-query",
+        (131, true) | (143, true) => "multicast-listener-report",
+        (132, true) => "multicast-listener-done",
+        (135, true) => "neighbor-solicitation",
+        (136, true) => "neighbor-advertisement",
+        _ => return icmp_type.to_string(),
+    };
+    name.to_owned()
+}
+
+fn unreachable_name(code: u8, v6: bool) -> String {
+    let name = match (code, v6) {
+        (0, false) => "net-unreachable",
+        (1, false) | (3, true) => "host-unreachable",
+        (2, false) => "protocol-unreachable",
+        (3, false) | (4, true) => "port-unreachable",
+        (4, false) => "fragmentation-needed",
+        (5, false) => "source-route-failed",
+        (9, false) | (10, false) | (13, false) | (1, true) => "administratively-prohibited",
+        (0, true) => "no-route",
+        (2, true) => "beyond-scope",
+        (5, true) => "source-policy-failed",
+        (6, true) => "reject-route",
+        _ => return code.to_string(),
+    };
+    name.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Icmp;
+    use libpcap_tools::Duration;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn icmp_test() {
+        let host = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let target = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut icmp = Icmp::default();
+        // echo request and reply (id 1, seq 2), then a request without reply
+        let request = [8, 0, 0, 0, 0, 1, 0, 2];
+        let reply = [0, 0, 0, 0, 0, 1, 0, 2];
+        icmp.handle_icmp(Duration::new(1, 0), host, target, false, &request);
+        icmp.handle_icmp(Duration::new(1, 20_000), target, host, false, &reply);
+        icmp.handle_icmp(
+            Duration::new(2, 0),
+            host,
+            target,
+            false,
+            &[8, 0, 0, 0, 0, 1, 0, 3],
+        );
+        // time exceeded from two routers, quoting a UDP probe
+        let mut quoted = vec![0x45; 20];
+        quoted[9] = 17;
+        quoted[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        quoted[16..20].copy_from_slice(&[192, 0, 2, 1]);
+        for router in 1..=2 {
+            let mut msg = vec![11, 0, 0, 0, 0, 0, 0, 0];
+            msg.extend_from_slice(&quoted);
+            let router = IpAddr::V4(Ipv4Addr::new(10, 0, router, 254));
+            icmp.handle_icmp(Duration::new(3, 0), router, host, false, &msg);
+        }
+        // router advertisement, with a source link-layer address and a prefix
+        let router = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        let all_nodes = IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1));
+        let mut ra = vec![134, 0, 0, 0, 64, 0x40, 0x07, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+        ra.extend_from_slice(&[1, 1, 0x02, 0, 0, 0, 0, 0x01]);
+        ra.extend_from_slice(&[3, 4, 64, 0xc0]);
+        ra.resize(ra.len() + 12, 0);
+        ra.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        ra.resize(ra.len() + 12, 0);
+        icmp.handle_icmp(Duration::new(4, 0), router, all_nodes, true, &ra);
+
+        let results = icmp.get_results_json();
+        let results = &results["icmp"];
+        assert_eq!(results["icmp_types"]["echo-request"], 2);
+        let echo = &results["echo"][0];
+        assert_eq!(echo["requests"], 2);
+        assert_eq!(echo["replies"], 1);
+        assert_eq!(echo["rtt"]["max"], 0.02);
+        let traceroute = &results["traceroutes"][0];
+        assert_eq!(traceroute["dst"], "192.0.2.1");
+        assert_eq!(traceroute["proto"], 17);
+        assert_eq!(traceroute["hops"][1], "10.0.2.254");
+        let router = &results["ndp"]["routers"][0];
+        assert_eq!(router["ip"], "fe80::1");
+        assert_eq!(router["mac"], "02:00:00:00:00:01");
+        assert_eq!(router["lifetime"], 1800);
+        assert_eq!(router["other_config"], true);
+        assert_eq!(router["prefixes"][0], "2001:db8::/64");
+    }
+}
@@ -0,0 +1,566 @@
+//! Plugin logging the operations of industrial control system (ICS) protocols
+//!
+//! Supported protocols, detected using the destination port of the first packet of flows:
+//!
+//! - Modbus TCP (port 502): function codes, unit IDs, register/coil addresses and quantities,
+//!   exception codes
+//! - DNP3 (TCP or UDP port 20000): link addresses, application function codes, first object
+//!   (group and variation), internal indications of responses
+//! - S7comm (TCP port 102, over TPKT and COTP): function codes, and the variables read or
+//!   written (area, DB number, address and length)
+//!
+//! Each flow has a log of operations (requests and responses). DNP3 application messages split
+//! across several link frames are not reassembled: only the first fragment is parsed.
+
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::any::Any;
+
+/// Maximum number of operations logged for a flow
+const MAX_OPERATIONS: usize = 10000;
+
+/// Maximum number of S7 variables logged for an operation
+const MAX_S7_ITEMS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protocol {
+    Modbus,
+    Dnp3,
+    S7comm,
+}
+
+#[derive(Serialize)]
+struct Operation {
+    ts: String,
+    to_server: bool,
+    function: String,
+    /// Fields specific to the protocol
+    #[serde(flatten)]
+    details: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+struct IcsFlowInfo {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    protocol: &'static str,
+    operations: Vec<Operation>,
+    /// Operations not logged (after `MAX_OPERATIONS`)
+    dropped: u64,
+}
+
+/// Framing state of one direction of a flow
+#[derive(Default)]
+struct Direction {
+    /// Incomplete frame
+    buf: Vec<u8>,
+    /// Invalid data was found, stop parsing
+    done: bool,
+}
+
+struct IcsFlow {
+    protocol: Protocol,
+    info: IcsFlowInfo,
+    directions: [Direction; 2],
+}
+
+#[derive(Default)]
+pub struct Ics {
+    /// State of flows, `None` if the flow is not an ICS protocol
+    flows: FnvHashMap<FlowID, Option<IcsFlow>>,
+    /// Information of destroyed flows
+    results: Vec<IcsFlowInfo>,
+}
+
+plugin_builder!(Ics, IcsBuilder);
+
+impl Plugin for Ics {
+    fn name(&self) -> &'static str {
+        "Ics"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if !data.is_empty() => (flow, data),
+            _ => return PluginResult::None,
+        };
+        let state = self.flows.entry(flow.flow_id).or_insert_with(|| {
+            let five_tuple = pinfo.five_tuple;
+            let protocol = match (five_tuple.proto, five_tuple.dst_port) {
+                _ if !pinfo.to_server => None,
+                (6, 502) => Some(Protocol::Modbus),
+                (6, 20000) | (17, 20000) => Some(Protocol::Dnp3),
+                (6, 102) => Some(Protocol::S7comm),
+                _ => None,
+            };
+            protocol.map(|protocol| IcsFlow::new(protocol, flow.flow_id, &flow.five_tuple))
+        });
+        if let Some(state) = state {
+            state.update(data, pinfo.to_server, packet.ts);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(Some(state)) = self.flows.remove(&flow.flow_id) {
+            self.results.push(state.info);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "ics.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Ics {
+    fn get_results_json(&mut self) -> Value {
+        // flows still active (if called before the end of the analysis)
+        let active = self.flows.values().flatten().map(|state| &state.info);
+        let flows: Vec<_> = self.results.iter().chain(active).collect();
+        json!({ "ics-flows": flows })
+    }
+}
+
+impl Protocol {
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Modbus => "modbus",
+            Protocol::Dnp3 => "dnp3",
+            Protocol::S7comm => "s7comm",
+        }
+    }
+
+    /// Get the length of the frame at the start of `data`, or `None` if the header is incomplete
+    fn frame_len(self, data: &[u8]) -> Result<Option<usize>, &'static str> {
+        match self {
+            Protocol::Modbus => {
+                // MBAP header: transaction ID, protocol ID (0), length
+                if data.len() < 6 {
+                    return Ok(None);
+                }
+                if data[2..4] != [0, 0] {
+                    return Err("invalid Modbus protocol ID");
+                }
+                let len = usize::from(u16::from_be_bytes([data[4], data[5]]));
+                if !(2..=254).contains(&len) {
+                    return Err("invalid Modbus length");
+                }
+                Ok(Some(6 + len))
+            }
+            Protocol::Dnp3 => {
+                if data.len() < 3 {
+                    return Ok(None);
+                }
+                if data[..2] != [0x05, 0x64] || data[2] < 5 {
+                    return Err("invalid DNP3 link header");
+                }
+                // header (with CRC), then blocks of 16 bytes of user data, each one followed by
+                // a CRC
+                let user_len = usize::from(data[2]) - 5;
+                Ok(Some(10 + user_len + 2 * ((user_len + 15) / 16)))
+            }
+            Protocol::S7comm => {
+                // TPKT header: version (3), reserved, length
+                if data.len() < 4 {
+                    return Ok(None);
+                }
+                let len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+                if data[0] != 3 || len < 7 {
+                    return Err("invalid TPKT header");
+                }
+                Ok(Some(len))
+            }
+        }
+    }
+
+    /// Parse a frame, and return the function and details of the operation
+    fn parse(self, frame: &[u8], to_server: bool) -> Option<(String, Map<String, Value>)> {
+        match self {
+            Protocol::Modbus => parse_modbus(frame, to_server),
+            Protocol::Dnp3 => parse_dnp3(frame),
+            Protocol::S7comm => parse_s7comm(frame),
+        }
+    }
+}
+
+impl IcsFlow {
+    fn new(protocol: Protocol, flow_id: FlowID, five_tuple: &FiveTuple) -> Self {
+        IcsFlow {
+            protocol,
+            info: IcsFlowInfo {
+                flow_id,
+                five_tuple: five_tuple.clone(),
+                protocol: protocol.name(),
+                operations: Vec::new(),
+                dropped: 0,
+            },
+            directions: Default::default(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8], to_server: bool, ts: Duration) {
+        let dir = &mut self.directions[if to_server { 0 } else { 1 }];
+        if dir.done {
+            return;
+        }
+        dir.buf.extend_from_slice(data);
+        let mut consumed = 0;
+        loop {
+            let len = match self.protocol.frame_len(&dir.buf[consumed..]) {
+                Ok(Some(len)) if consumed + len <= dir.buf.len() => len,
+                Ok(_) => break,
+                Err(e) => {
+                    debug!("ics: {} in flow 0x{:x}", e, self.info.flow_id);
+                    dir.done = true;
+                    break;
+                }
+            };
+            let frame = &dir.buf[consumed..consumed + len];
+            consumed += len;
+            let (function, details) = match self.protocol.parse(frame, to_server) {
+                Some(op) => op,
+                None => continue,
+            };
+            if self.info.operations.len() >= MAX_OPERATIONS {
+                self.info.dropped += 1;
+                continue;
+            }
+            self.info.operations.push(Operation {
+                ts: format!("{}.{:09}", ts.secs, ts.nanos),
+                to_server,
+                function,
+                details,
+            });
+        }
+        dir.buf.drain(..consumed);
+    }
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn parse_modbus(frame: &[u8], to_server: bool) -> Option<(String, Map<String, Value>)> {
+    let mut details = Map::new();
+    details.insert("transaction_id".into(), json!(be_u16(frame, 0)?));
+    details.insert("unit_id".into(), json!(frame.get(6)?));
+    let pdu = frame.get(7..)?;
+    let function_code = *pdu.first()?;
+    let function = modbus_function_name(function_code & 0x7f);
+    if function_code & 0x80 != 0 {
+        let code = *pdu.get(1)?;
+        details.insert("exception".into(), json!(modbus_exception_name(code)));
+        return Some((function, details));
+    }
+    if to_server {
+        match function_code {
+            // reads, and writes of multiple coils or registers
+            1..=4 | 15 | 16 | 23 => {
+                details.insert("address".into(), json!(be_u16(pdu, 1)?));
+                details.insert("quantity".into(), json!(be_u16(pdu, 3)?));
+                if function_code == 23 {
+                    details.insert("write_address".into(), json!(be_u16(pdu, 5)?));
+                    details.insert("write_quantity".into(), json!(be_u16(pdu, 7)?));
+                }
+            }
+            // writes of a single coil or register
+            5 | 6 => {
+                details.insert("address".into(), json!(be_u16(pdu, 1)?));
+                details.insert("value".into(), json!(be_u16(pdu, 3)?));
+            }
+            _ => (),
+        }
+    }
+    Some((function, details))
+}
+
+fn parse_dnp3(frame: &[u8]) -> Option<(String, Map<String, Value>)> {
+    let mut details = Map::new();
+    // addresses are little-endian
+    let dst = u16::from_le_bytes([*frame.get(4)?, *frame.get(5)?]);
+    let src = u16::from_le_bytes([*frame.get(6)?, *frame.get(7)?]);
+    details.insert("src".into(), json!(src));
+    details.insert("dst".into(), json!(dst));
+    // remove the CRC of each block
+    let user_data: Vec<u8> = frame
+        .get(10..)?
+        .chunks(18)
+        .flat_map(|block| &block[..block.len().saturating_sub(2)])
+        .copied()
+        .collect();
+    if user_data.is_empty() {
+        // link layer frame
+        details.insert("link_control".into(), json!(frame[3]));
+        return Some(("LINK".to_owned(), details));
+    }
+    // transport header: only the first fragment contains the application header
+    if user_data[0] & 0x40 == 0 {
+        return None;
+    }
+    let function_code = *user_data.get(2)?;
+    let objects = if function_code == 129 || function_code == 130 {
+        let iin = be_u16(&user_data, 3)?;
+        details.insert("iin".into(), json!(format!("0x{:04x}", iin)));
+        user_data.get(5..)
+    } else {
+        user_data.get(3..)
+    };
+    if let Some([group, variation, ..]) = objects {
+        details.insert("object".into(), json!(format!("g{}v{}", group, variation)));
+    }
+    Some((dnp3_function_name(function_code), details))
+}
+
+fn parse_s7comm(frame: &[u8]) -> Option<(String, Map<String, Value>)> {
+    let mut details = Map::new();
+    // COTP header: length, PDU type
+    let cotp_len = usize::from(*frame.get(4)?);
+    match *frame.get(5)? {
+        0xe0 => return Some(("COTP_CONNECTION_REQUEST".to_owned(), details)),
+        0xd0 => return Some(("COTP_CONNECTION_CONFIRM".to_owned(), details)),
+        0xf0 => (),
+        _ => return None,
+    }
+    let s7 = frame.get(5 + cotp_len..)?;
+    if s7.first() != Some(&0x32) {
+        return None;
+    }
+    let rosctr = *s7.get(1)?;
+    let param_len = usize::from(be_u16(s7, 6)?);
+    let header_len = match rosctr {
+        // acknowledgements contain an error class and code
+        2 | 3 => {
+            let error = be_u16(s7, 10)?;
+            if error != 0 {
+                details.insert("error".into(), json!(format!("0x{:04x}", error)));
+            }
+            12
+        }
+        _ => 10,
+    };
+    let rosctr_name = match rosctr {
+        1 => "job",
+        2 => "ack",
+        3 => "ack_data",
+        7 => "userdata",
+        _ => "unknown",
+    };
+    details.insert("rosctr".into(), json!(rosctr_name));
+    details.insert("pdu_ref".into(), json!(be_u16(s7, 4)?));
+    if rosctr == 7 {
+        return Some(("USERDATA".to_owned(), details));
+    }
+    let params = s7.get(header_len..header_len + param_len)?;
+    let function_code = *params.first()?;
+    // variables read or written
+    if rosctr == 1 && (function_code == 0x04 || function_code == 0x05) {
+        let items: Vec<_> = params
+            .get(2..)
+            .unwrap_or_default()
+            .chunks_exact(12)
+            .take(MAX_S7_ITEMS)
+            .filter_map(s7_item)
+            .collect();
+        details.insert("items".into(), Value::Array(items));
+    }
+    Some((s7_function_name(function_code), details))
+}
+
+/// Parse a variable specification (S7ANY addressing)
+fn s7_item(item: &[u8]) -> Option<Value> {
+    if item[0] != 0x12 || item[2] != 0x10 {
+        return None;
+    }
+    let length = be_u16(item, 4)?;
+    let db = be_u16(item, 6)?;
+    let address = (u32::from(item[9]) << 16) | (u32::from(item[10]) << 8) | u32::from(item[11]);
+    let (byte, bit) = (address >> 3, address & 0x7);
+    let address = match item[8] {
+        0x84 => format!("DB{}.{}.{}", db, byte, bit),
+        area => {
+            let area = match area {
+                0x81 => "I",
+                0x82 => "Q",
+                0x83 => "M",
+                0x1c => "C",
+                0x1d => "T",
+                _ => "?",
+            };
+            format!("{}{}.{}", area, byte, bit)
+        }
+    };
+    Some(json!({ "address": address, "length": length }))
+}
+
+fn modbus_function_name(code: u8) -> String {
+    let name = match code {
+        1 => "READ_COILS",
+        2 => "READ_DISCRETE_INPUTS",
+        3 => "READ_HOLDING_REGISTERS",
+        4 => "READ_INPUT_REGISTERS",
+        5 => "WRITE_SINGLE_COIL",
+        6 => "WRITE_SINGLE_REGISTER",
+        7 => "READ_EXCEPTION_STATUS",
+        8 => "DIAGNOSTICS",
+        15 => "WRITE_MULTIPLE_COILS",
+        16 => "WRITE_MULTIPLE_REGISTERS",
+        17 => "REPORT_SERVER_ID",
+        22 => "MASK_WRITE_REGISTER",
+        23 => "READ_WRITE_MULTIPLE_REGISTERS",
+        43 => "ENCAPSULATED_INTERFACE_TRANSPORT",
+        _ => return code.to_string(),
+    };
+    name.to_owned()
+}
+
+fn modbus_exception_name(code: u8) -> String {
+    let name = match code {
+        1 => "ILLEGAL_FUNCTION",
+        2 => "ILLEGAL_DATA_ADDRESS",
+        3 => "ILLEGAL_DATA_VALUE",
+        4 => "SERVER_DEVICE_FAILURE",
+        5 => "ACKNOWLEDGE",
+        6 => "SERVER_DEVICE_BUSY",
+        10 => "GATEWAY_PATH_UNAVAILABLE",
+        11 => "GATEWAY_TARGET_FAILED_TO_RESPOND",
+        _ => return code.to_string(),
+    };
+    name.to_owned()
+}
+
+fn dnp3_function_name(code: u8) -> String {
+    let name = match code {
+        0 => "CONFIRM",
+        1 => "READ",
+        2 => "WRITE",
+        3 => "SELECT",
+        4 => "OPERATE",
+        5 => "DIRECT_OPERATE",
+        6 => "DIRECT_OPERATE_NR",
+        7 => "IMMED_FREEZE",
+        13 => "COLD_RESTART",
+        14 => "WARM_RESTART",
+        18 => "STOP_APPL",
+        20 => "ENABLE_UNSOLICITED",
+        21 => "DISABLE_UNSOLICITED",
+        23 => "DELAY_MEASURE",
+        129 => "RESPONSE",
+        130 => "UNSOLICITED_RESPONSE",
+        _ => return code.to_string(),
+    };
+    name.to_owned()
+}
+
+fn s7_function_name(code: u8) -> String {
+    let name = match code {
+        0x00 => "CPU_SERVICES",
+        0x04 => "READ_VAR",
+        0x05 => "WRITE_VAR",
+        0x1a => "REQUEST_DOWNLOAD",
+        0x1b => "DOWNLOAD_BLOCK",
+        0x1c => "DOWNLOAD_ENDED",
+        0x1d => "START_UPLOAD",
+        0x1e => "UPLOAD",
+        0x1f => "END_UPLOAD",
+        0x28 => "PI_SERVICE",
+        0x29 => "PLC_STOP",
+        0xf0 => "SETUP_COMMUNICATION",
+        _ => return format!("0x{:02x}", code),
+    };
+    name.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IcsFlow, Protocol};
+    use libpcap_tools::{Duration, FiveTuple};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn five_tuple(proto: u8, dst_port: u16) -> FiveTuple {
+        FiveTuple {
+            proto,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: 49152,
+            dst_port,
+        }
+    }
+
+    #[test]
+    fn ics_modbus() {
+        let mut flow = IcsFlow::new(Protocol::Modbus, 1, &five_tuple(6, 502));
+        // two requests (read holding registers, write single register), split across segments
+        let requests = b"\x00\x01\x00\x00\x00\x06\x01\x03\x00\x10\x00\x02\
+                         \x00\x02\x00\x00\x00\x06\x01\x06\x00\x20\x12\x34";
+        flow.update(&requests[..15], true, Duration::new(1, 0));
+        flow.update(&requests[15..], true, Duration::new(1, 0));
+        // exception response
+        flow.update(
+            b"\x00\x02\x00\x00\x00\x03\x01\x86\x02",
+            false,
+            Duration::new(2, 0),
+        );
+        let ops = &flow.info.operations;
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0].function, "READ_HOLDING_REGISTERS");
+        assert_eq!(ops[0].details["address"], 16);
+        assert_eq!(ops[0].details["quantity"], 2);
+        assert_eq!(ops[1].details["value"], 0x1234);
+        assert_eq!(ops[2].function, "WRITE_SINGLE_REGISTER");
+        assert_eq!(ops[2].details["exception"], "ILLEGAL_DATA_ADDRESS");
+    }
+
+    #[test]
+    fn ics_dnp3_s7comm() {
+        let mut flow = IcsFlow::new(Protocol::Dnp3, 1, &five_tuple(6, 20000));
+        // READ request of class 0 data (g60v1)
+        let frame = b"\x05\x64\x0b\xc4\x01\x00\x02\x00\xff\xff\xc0\xc0\x01\x3c\x01\x06\xff\xff";
+        flow.update(frame, true, Duration::new(1, 0));
+        let op = &flow.info.operations[0];
+        assert_eq!(op.function, "READ");
+        assert_eq!(op.details["src"], 2);
+        assert_eq!(op.details["object"], "g60v1");
+
+        let mut flow = IcsFlow::new(Protocol::S7comm, 2, &five_tuple(6, 102));
+        // read 4 bytes at DB1.DBB10
+        let frame = b"\x03\x00\x00\x1f\x02\xf0\x80\x32\x01\x00\x00\x00\x01\x00\x0e\x00\x00\
+                      \x04\x01\x12\x0a\x10\x02\x00\x04\x00\x01\x84\x00\x00\x50";
+        flow.update(frame, true, Duration::new(1, 0));
+        let op = &flow.info.operations[0];
+        assert_eq!(op.function, "READ_VAR");
+        assert_eq!(op.details["rosctr"], "job");
+        assert_eq!(op.details["items"][0]["address"], "DB1.10.0");
+        assert_eq!(op.details["items"][0]["length"], 4);
+    }
+}
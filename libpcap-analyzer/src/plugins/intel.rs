@@ -0,0 +1,604 @@
+//! Plugin matching traffic against threat intelligence indicators
+//!
+//! Indicators are loaded from the files listed in `files`, in section `[plugin.intel]`. The
+//! format of a file depends on its extension:
+//!
+//! - `.json`: STIX 2.x bundle. The comparisons of the patterns of `indicator` objects are used
+//!   (`ipv4-addr:value`, `ipv6-addr:value`, `domain-name:value` and `url:value`)
+//! - `.csv`: `type,value[,description]` rows, where `type` is `ip`, `cidr`, `domain`, `ja3` or
+//!   `url` (other rows, for ex. a header, are ignored)
+//! - other files: one indicator per line, the type being detected from the value
+//!
+//! Empty lines and lines starting with `#` are ignored. Alerts are raised when:
+//!
+//! - the source or destination address of a flow matches an IP address or CIDR indicator
+//! - a DNS query, HTTP host or TLS SNI matches a domain indicator, or one of its subdomains
+//! - the URL of an HTTP request matches a URL indicator (the scheme is ignored)
+//! - the JA3 hash of a TLS client hello matches a JA3 indicator
+//!
+//! Each alert contains the flow, the matched field and value, the indicator and the list (file
+//! name) it comes from. An indicator is reported at most once per flow.
+
+use super::tls_metadata::ClientHello;
+use crate::app_proto::{app_metadata, detect_app_proto};
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_FLOW_NEW, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Maximum number of alerts, later alerts are counted but not stored
+const MAX_ALERTS: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum IndicatorType {
+    Ip,
+    Cidr,
+    Domain,
+    Ja3,
+    Url,
+}
+
+impl IndicatorType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ip" => Some(IndicatorType::Ip),
+            "cidr" => Some(IndicatorType::Cidr),
+            "domain" => Some(IndicatorType::Domain),
+            "ja3" => Some(IndicatorType::Ja3),
+            "url" => Some(IndicatorType::Url),
+            _ => None,
+        }
+    }
+
+    /// Detect the type of an indicator from its value
+    fn detect(value: &str) -> Option<Self> {
+        if value.contains("://") {
+            Some(IndicatorType::Url)
+        } else if value.parse::<IpAddr>().is_ok() {
+            Some(IndicatorType::Ip)
+        } else if parse_cidr(value).is_some() {
+            Some(IndicatorType::Cidr)
+        } else if value.len() == 32 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+            Some(IndicatorType::Ja3)
+        } else if value.contains('.')
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+        {
+            Some(IndicatorType::Domain)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Indicator {
+    indicator_type: IndicatorType,
+    value: String,
+    /// List (file name) of the indicator
+    list: String,
+    description: Option<String>,
+}
+
+/// Indicators, indexed by type. If an indicator is in several lists, the first one is used.
+#[derive(Default)]
+struct Indicators {
+    indicators: Vec<Indicator>,
+    ips: FnvHashMap<IpAddr, usize>,
+    /// Networks (address and prefix length)
+    networks: Vec<(IpAddr, u8, usize)>,
+    domains: FnvHashMap<String, usize>,
+    ja3: FnvHashMap<String, usize>,
+    urls: FnvHashMap<String, usize>,
+}
+
+impl Indicators {
+    /// Load a list of indicators, and return the number of indicators in the file
+    fn load(&mut self, path: &str) -> Result<usize, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let list = Path::new(path)
+            .file_name()
+            .map_or_else(|| path.to_owned(), |n| n.to_string_lossy().into_owned());
+        let extension = Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("json") => self
+                .load_stix(&content, &list)
+                .map_err(|e| format!("invalid STIX bundle {}: {}", path, e)),
+            Some("csv") => Ok(self.load_csv(&content, &list)),
+            _ => Ok(self.load_text(&content, &list)),
+        }
+    }
+
+    fn load_text(&mut self, content: &str, list: &str) -> usize {
+        let mut count = 0;
+        for line in lines(content) {
+            match IndicatorType::detect(line) {
+                Some(indicator_type) => {
+                    count += self.add(indicator_type, line, list, None) as usize;
+                }
+                None => debug!("intel: {}: unknown indicator type for {:?}", list, line),
+            }
+        }
+        count
+    }
+
+    fn load_csv(&mut self, content: &str, list: &str) -> usize {
+        let mut count = 0;
+        for line in lines(content) {
+            let mut fields = line.splitn(3, ',').map(|f| f.trim().trim_matches('"'));
+            let indicator_type = fields.next().and_then(IndicatorType::from_name);
+            if let (Some(indicator_type), Some(value)) = (indicator_type, fields.next()) {
+                let description = fields.next().filter(|d| !d.is_empty());
+                count += self.add(indicator_type, value, list, description) as usize;
+            }
+        }
+        count
+    }
+
+    fn load_stix(&mut self, content: &str, list: &str) -> Result<usize, String> {
+        let bundle: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        let objects = match bundle["objects"].as_array() {
+            Some(objects) => objects.iter().collect(),
+            // single object
+            None => vec![&bundle],
+        };
+        let mut count = 0;
+        for object in objects {
+            if object["type"] != "indicator" {
+                continue;
+            }
+            let pattern_type = object["pattern_type"].as_str().unwrap_or("stix");
+            let pattern = match object["pattern"].as_str() {
+                Some(pattern) if pattern_type == "stix" => pattern,
+                _ => continue,
+            };
+            let description = object["name"]
+                .as_str()
+                .or_else(|| object["description"].as_str());
+            for (object_path, value) in stix_comparisons(pattern) {
+                let indicator_type = match object_path {
+                    "ipv4-addr:value" | "ipv6-addr:value" if value.contains('/') => {
+                        IndicatorType::Cidr
+                    }
+                    "ipv4-addr:value" | "ipv6-addr:value" => IndicatorType::Ip,
+                    "domain-name:value" => IndicatorType::Domain,
+                    "url:value" => IndicatorType::Url,
+                    _ => continue,
+                };
+                count += self.add(indicator_type, value, list, description) as usize;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Add an indicator, and return false if it is invalid
+    fn add(
+        &mut self,
+        indicator_type: IndicatorType,
+        value: &str,
+        list: &str,
+        description: Option<&str>,
+    ) -> bool {
+        let idx = self.indicators.len();
+        let added = match indicator_type {
+            IndicatorType::Ip => value
+                .parse()
+                .map(|ip| self.ips.entry(ip).or_insert(idx))
+                .is_ok(),
+            IndicatorType::Cidr => parse_cidr(value)
+                .map(|(network, prefix)| self.networks.push((network, prefix, idx)))
+                .is_some(),
+            IndicatorType::Domain => {
+                self.domains.entry(normalize_domain(value)).or_insert(idx);
+                true
+            }
+            IndicatorType::Ja3 => {
+                self.ja3.entry(value.to_ascii_lowercase()).or_insert(idx);
+                true
+            }
+            IndicatorType::Url => {
+                self.urls.entry(normalize_url(value)).or_insert(idx);
+                true
+            }
+        };
+        if !added {
+            debug!("intel: {}: invalid indicator {:?}", list, value);
+            return false;
+        }
+        self.indicators.push(Indicator {
+            indicator_type,
+            value: value.to_owned(),
+            list: list.to_owned(),
+            description: description.map(|d| d.to_owned()),
+        });
+        true
+    }
+
+    fn match_ip(&self, ip: IpAddr) -> Option<usize> {
+        self.ips.get(&ip).copied().or_else(|| {
+            self.networks
+                .iter()
+                .find(|(network, prefix, _)| in_network(ip, *network, *prefix))
+                .map(|(_, _, idx)| *idx)
+        })
+    }
+
+    /// Match a domain, or one of its parent domains
+    fn match_domain(&self, name: &str) -> Option<usize> {
+        let name = normalize_domain(name);
+        let mut domain = name.as_str();
+        loop {
+            if let Some(&idx) = self.domains.get(domain) {
+                return Some(idx);
+            }
+            domain = domain.split_once('.')?.1;
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Alert {
+    ts: String,
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    /// Field containing the value (for ex. `dst_ip` or `dns.rrname`)
+    field: &'static str,
+    value: String,
+    indicator: String,
+    #[serde(rename = "type")]
+    indicator_type: IndicatorType,
+    list: String,
+    description: Option<String>,
+}
+
+pub struct Intel {
+    indicators: Indicators,
+    /// Indicators already reported, by flow
+    reported: FnvHashMap<FlowID, Vec<usize>>,
+    alerts: Vec<Alert>,
+    /// Alerts not stored, after `MAX_ALERTS`
+    dropped_alerts: u64,
+}
+
+pub struct IntelBuilder;
+
+impl PluginBuilder for IntelBuilder {
+    fn name(&self) -> &'static str { "IntelBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let plugin_config = config.plugin_config("intel");
+        let files = match plugin_config.get_strings("files") {
+            Some(files) if !files.is_empty() => files,
+            _ => {
+                debug!("intel: no indicator files configured");
+                return Ok(());
+            }
+        };
+        let mut indicators = Indicators::default();
+        for file in files {
+            let count = indicators
+                .load(file)
+                .map_err(|e| PluginBuilderError::InvalidConfig(format!("intel: {}", e)))?;
+            info!("intel: loaded {} indicators from {}", count, file);
+        }
+        let plugin = Intel {
+            indicators,
+            reported: FnvHashMap::default(),
+            alerts: Vec::new(),
+            dropped_alerts: 0,
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("intel");
+        if config.contains("files") && config.get_strings("files").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "intel: files must be an array of strings".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Intel {
+    fn name(&self) -> &'static str {
+        "Intel"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_NEW | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        // names and fingerprints are sent by clients
+        if !pinfo.to_server {
+            return PluginResult::None;
+        }
+        if let (Some(flow), Some(data)) = (pinfo.flow, pinfo.l4_payload) {
+            self.match_payload(packet.ts, flow, data);
+        }
+        PluginResult::None
+    }
+
+    fn flow_created(&mut self, flow: &Flow) {
+        let t5 = &flow.five_tuple;
+        for &(field, ip) in &[("src_ip", t5.src), ("dst_ip", t5.dst)] {
+            if let Some(idx) = self.indicators.match_ip(ip) {
+                self.alert(flow.first_seen, flow, field, ip.to_string(), idx);
+            }
+        }
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        self.reported.remove(&flow.flow_id);
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "intel.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Intel {
+    /// Match the names and fingerprints of the first message of DNS, HTTP and TLS payloads
+    fn match_payload(&mut self, ts: Duration, flow: &Flow, data: &[u8]) {
+        let t5 = &flow.five_tuple;
+        let app_proto = match detect_app_proto(t5, data) {
+            Some(app_proto) => app_proto,
+            None => return,
+        };
+        let metadata = app_metadata(app_proto, t5, data);
+        let get = |key: &str| metadata.iter().find(|(k, _)| *k == key).map(|(_, v)| v);
+        let mut matches = Vec::new();
+        for &key in &["dns.rrname", "http.host", "tls.sni"] {
+            if let Some(name) = get(key) {
+                // remove the port of HTTP hosts
+                let name = match name.rsplit_once(':') {
+                    Some((host, port)) if port.parse::<u16>().is_ok() => host,
+                    _ => name.as_str(),
+                };
+                if let Some(idx) = self.indicators.match_domain(name) {
+                    matches.push((key, name.to_owned(), idx));
+                }
+            }
+        }
+        if let (Some(host), Some(uri)) = (get("http.host"), get("http.uri")) {
+            // absolute URI (proxy requests)
+            let url = if uri.contains("://") {
+                uri.to_owned()
+            } else {
+                format!("http://{}{}", host, uri)
+            };
+            if let Some(&idx) = self.indicators.urls.get(&normalize_url(&url)) {
+                matches.push(("http.url", url, idx));
+            }
+        }
+        if app_proto == "tls" && !self.indicators.ja3.is_empty() {
+            if let Some(ja3) = ClientHello::from_record(data).map(|hello| hello.ja3_hash()) {
+                if let Some(&idx) = self.indicators.ja3.get(&ja3) {
+                    matches.push(("tls.ja3", ja3, idx));
+                }
+            }
+        }
+        for (field, value, idx) in matches {
+            self.alert(ts, flow, field, value, idx);
+        }
+    }
+
+    fn alert(&mut self, ts: Duration, flow: &Flow, field: &'static str, value: String, idx: usize) {
+        let reported = self.reported.entry(flow.flow_id).or_default();
+        if reported.contains(&idx) {
+            return;
+        }
+        reported.push(idx);
+        let indicator = &self.indicators.indicators[idx];
+        info!(
+            "intel: flow 0x{:x}: {} {} matches {} ({})",
+            flow.flow_id, field, value, indicator.value, indicator.list
+        );
+        if self.alerts.len() >= MAX_ALERTS {
+            self.dropped_alerts += 1;
+            return;
+        }
+        self.alerts.push(Alert {
+            ts: format!("{}.{:09}", ts.secs, ts.nanos),
+            flow_id: flow.flow_id,
+            five_tuple: flow.five_tuple.clone(),
+            field,
+            value,
+            indicator: indicator.value.clone(),
+            indicator_type: indicator.indicator_type,
+            list: indicator.list.clone(),
+            description: indicator.description.clone(),
+        });
+    }
+
+    fn get_results_json(&self) -> Value {
+        json!({
+            "intel": {
+                "indicators": self.indicators.indicators.len(),
+                "alerts": self.alerts,
+                "dropped_alerts": self.dropped_alerts,
+            }
+        })
+    }
+}
+
+/// Lines of a list, without comments and empty lines
+fn lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+}
+
+/// Get the comparisons of a STIX pattern (object path and value), for ex.
+/// `[domain-name:value = 'example.com']`
+fn stix_comparisons(pattern: &str) -> Vec<(&str, &str)> {
+    let mut comparisons = Vec::new();
+    let mut rest = pattern;
+    while let Some(eq) = rest.find('=') {
+        let object_path = rest[..eq]
+            .rsplit(|c: char| c == '[' || c == '(' || c.is_whitespace())
+            .find(|s| !s.is_empty())
+            .unwrap_or_default();
+        let value = rest[eq + 1..].trim_start().strip_prefix('\'');
+        match value.and_then(|v| v.find('\'').map(|end| (v, end))) {
+            // negated comparisons (`!=`) are not indicators
+            Some((value, end)) if rest[..eq].ends_with('!') => rest = &value[end + 1..],
+            Some((value, end)) => {
+                comparisons.push((object_path, &value[..end]));
+                rest = &value[end + 1..];
+            }
+            None => rest = &rest[eq + 1..],
+        }
+    }
+    comparisons
+}
+
+fn parse_cidr(s: &str) -> Option<(IpAddr, u8)> {
+    let (ip, prefix) = s.split_once('/')?;
+    let ip: IpAddr = ip.parse().ok()?;
+    let prefix: u8 = prefix.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    if prefix <= max {
+        Some((ip, prefix))
+    } else {
+        None
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn normalize_domain(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Normalize a URL for comparisons: without scheme, and with a lowercase host
+fn normalize_url(url: &str) -> String {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let (host, path) = match url.find('/') {
+        Some(pos) => url.split_at(pos),
+        None => (url, "/"),
+    };
+    format!("{}{}", host.to_ascii_lowercase(), path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stix_comparisons, Indicators, Intel};
+    use crate::plugin::Plugin;
+    use fnv::FnvHashMap;
+    use libpcap_tools::{Duration, FiveTuple, Flow};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn intel_load() {
+        let mut indicators = Indicators::default();
+        let text = "# comment\n192.0.2.1\n10.0.0.0/8\nEvil.example.\n\
+                    e7d705a3286e19ea42f587b344ee6865\nhttp://bad.example/payload\n";
+        assert_eq!(indicators.load_text(text, "list.txt"), 5);
+        let csv = "type,value,description\ndomain,c2.test,C2 server\nip,not-an-ip\n";
+        assert_eq!(indicators.load_csv(csv, "list.csv"), 1);
+        let stix = r#"{"type": "bundle", "objects": [{"type": "indicator", "name": "Bad",
+            "pattern": "[ipv4-addr:value = '198.51.100.0/24'] OR [domain-name:value = 'x.test']"}]}"#;
+        assert_eq!(indicators.load_stix(stix, "bundle.json"), Ok(2));
+        assert_eq!(
+            stix_comparisons("[url:value = 'http://a/b' AND ipv4-addr:value != '1.2.3.4']"),
+            vec![("url:value", "http://a/b")]
+        );
+
+        let ip = |s: &str| s.parse::<IpAddr>().expect("invalid address");
+        assert_eq!(indicators.match_ip(ip("192.0.2.1")), Some(0));
+        assert_eq!(indicators.match_ip(ip("10.1.2.3")), Some(1));
+        assert_eq!(indicators.match_ip(ip("198.51.100.7")), Some(6));
+        assert_eq!(indicators.match_ip(ip("192.0.2.2")), None);
+        assert_eq!(indicators.match_domain("www.EVIL.example"), Some(2));
+        assert_eq!(indicators.match_domain("notevil.example"), None);
+        assert_eq!(
+            indicators.indicators[5].description.as_deref(),
+            Some("C2 server")
+        );
+    }
+
+    #[test]
+    fn intel_alerts() {
+        let mut indicators = Indicators::default();
+        indicators.load_text("10.0.0.2\nevil.example\nhttp://c2.test/gate.php\n", "iocs");
+        let mut intel = Intel {
+            indicators,
+            reported: FnvHashMap::default(),
+            alerts: Vec::new(),
+            dropped_alerts: 0,
+        };
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: 49152,
+            dst_port: 80,
+        };
+        let flow = Flow::new(&five_tuple, 1, 0);
+        intel.flow_created(&flow);
+        let request = b"GET /gate.php HTTP/1.1\r\nHost: C2.test\r\n\r\n";
+        intel.match_payload(Duration::new(2, 0), &flow, request);
+        // the same indicator is reported once per flow
+        intel.match_payload(Duration::new(3, 0), &flow, request);
+        let results = intel.get_results_json();
+        let alerts = &results["intel"]["alerts"];
+        assert_eq!(alerts.as_array().map(Vec::len), Some(2));
+        assert_eq!(alerts[0]["field"], "dst_ip");
+        assert_eq!(alerts[0]["type"], "ip");
+        assert_eq!(alerts[1]["field"], "http.url");
+        assert_eq!(alerts[1]["value"], "http://C2.test/gate.php");
+        assert_eq!(alerts[1]["list"], "iocs");
+    }
+}
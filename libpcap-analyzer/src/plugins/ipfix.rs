@@ -0,0 +1,401 @@
+//! Plugin exporting flows as IPFIX records (RFC 7011)
+//!
+//! Records are written to a file (`filename` in section `[plugin.ipfix]`, relative to the
+//! output directory), and/or sent to a collector over UDP (`collector`, for ex.
+//! `"127.0.0.1:4739"`). The plugin is disabled if none is set.
+//!
+//! Flows are exported as biflows (RFC 5103) when they are destroyed, using standard information
+//! elements for the five-tuple, counters, TCP flags and timestamps. The detected application
+//! protocol is exported in an enterprise-specific information element (ID 1, enterprise number
+//! set by `enterprise_id`, 32473 by default).
+
+use crate::app_proto::detect_app_proto;
+use crate::output::{self, WriteErrors};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, Flow, FlowID, Packet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, UdpSocket};
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const TEMPLATE_ID_IPV4: u16 = 256;
+const TEMPLATE_ID_IPV6: u16 = 257;
+
+/// Enterprise number of reverse information elements (RFC 5103)
+const REVERSE_PEN: u32 = 29305;
+/// Default enterprise number for the application protocol (reserved for documentation, RFC 5612)
+const DEFAULT_PEN: u32 = 32473;
+/// Information element of the application protocol, in the enterprise space
+const IE_APP_PROTO: u16 = 1;
+const VARIABLE_LENGTH: u16 = 65535;
+
+/// Maximum size of messages, to avoid fragmentation when sent over UDP
+const MAX_MESSAGE_SIZE: usize = 1400;
+
+/// Fields of templates: information element ID, length, enterprise number
+type FieldSpecifier = (u16, u16, Option<u32>);
+
+/// Fields following the addresses, for both templates
+const FLOW_FIELDS: &[FieldSpecifier] = &[
+    (7, 2, None),              // sourceTransportPort
+    (11, 2, None),             // destinationTransportPort
+    (4, 1, None),              // protocolIdentifier
+    (6, 2, None),              // tcpControlBits
+    (2, 8, None),              // packetDeltaCount
+    (1, 8, None),              // octetDeltaCount
+    (2, 8, Some(REVERSE_PEN)), // reverse packetDeltaCount
+    (1, 8, Some(REVERSE_PEN)), // reverse octetDeltaCount
+    (152, 8, None),            // flowStartMilliseconds
+    (153, 8, None),            // flowEndMilliseconds
+];
+
+pub struct Ipfix {
+    file: Option<BufWriter<File>>,
+    socket: Option<UdpSocket>,
+    enterprise_id: u32,
+    observation_domain: u32,
+    /// Encoded records not yet exported, for IPv4 and IPv6 flows
+    records_v4: Vec<u8>,
+    records_v6: Vec<u8>,
+    /// Number of records not yet exported
+    pending: u32,
+    /// Number of exported records (sequence number of the next message)
+    sequence: u32,
+    /// Export time of messages (time of the last flow, since the capture is offline)
+    export_time: u32,
+    app_protos: FnvHashMap<FlowID, Option<&'static str>>,
+    write_errors: WriteErrors,
+}
+
+pub struct IpfixBuilder;
+
+impl PluginBuilder for IpfixBuilder {
+    fn name(&self) -> &'static str { "IpfixBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let ipfix_config = config.plugin_config("ipfix");
+        let invalid = |msg: String| PluginBuilderError::InvalidConfig(format!("ipfix: {}", msg));
+        let file = match ipfix_config.get("filename") {
+            Some(filename) => {
+                let file = output::create_file(output::get_output_dir(config), filename)
+                    .map_err(|e| invalid(format!("cannot create {}: {}", filename, e)))?;
+                Some(BufWriter::new(file))
+            }
+            None => None,
+        };
+        let socket = match ipfix_config.get("collector") {
+            Some(collector) => {
+                let socket = connect(collector)
+                    .map_err(|e| invalid(format!("cannot connect to {}: {}", collector, e)))?;
+                Some(socket)
+            }
+            None => None,
+        };
+        if file.is_none() && socket.is_none() {
+            debug!("ipfix: no output file or collector configured");
+            return Ok(());
+        }
+        let plugin = Ipfix {
+            file,
+            socket,
+            enterprise_id: ipfix_config
+                .get_usize("enterprise_id")
+                .map_or(DEFAULT_PEN, |v| v as u32),
+            observation_domain: ipfix_config
+                .get_usize("observation_domain")
+                .map_or(0, |v| v as u32),
+            records_v4: Vec::new(),
+            records_v6: Vec::new(),
+            pending: 0,
+            sequence: 0,
+            export_time: 0,
+            app_protos: FnvHashMap::default(),
+            write_errors: WriteErrors::new("ipfix: could not export records"),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("ipfix");
+        for key in &["filename", "collector"] {
+            if config.contains(key) && config.get(key).is_none() {
+                return Err(PluginBuilderError::InvalidConfig(format!(
+                    "ipfix: {} must be a string",
+                    key
+                )));
+            }
+        }
+        for key in &["enterprise_id", "observation_domain"] {
+            if !config.contains(key) {
+                continue;
+            }
+            match config.get_usize(key) {
+                Some(v) if v <= u32::MAX as usize => (),
+                _ => {
+                    return Err(PluginBuilderError::InvalidConfig(format!(
+                        "ipfix: {} must be a 32-bit unsigned integer",
+                        key
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Ipfix {
+    fn name(&self) -> &'static str {
+        "Ipfix"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+
+    fn post_process(&mut self) {
+        self.export();
+        if let Some(file) = &mut self.file {
+            let res = file.flush();
+            self.write_errors.check(res);
+        }
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        if let (Some(flow), Some(data)) = (pinfo.flow, pinfo.l4_payload) {
+            if !data.is_empty() {
+                self.app_protos
+                    .entry(flow.flow_id)
+                    .or_insert_with(|| detect_app_proto(pinfo.five_tuple, data));
+            }
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let app_proto = self.app_protos.remove(&flow.flow_id).flatten();
+        let mut record = Vec::new();
+        encode_record(&mut record, flow, app_proto);
+        // export pending records first if the message would be too large
+        let size = self.records_v4.len() + self.records_v6.len() + record.len();
+        if size > MAX_MESSAGE_SIZE - self.overhead() {
+            self.export();
+        }
+        let records = match flow.five_tuple.src {
+            IpAddr::V4(_) => &mut self.records_v4,
+            IpAddr::V6(_) => &mut self.records_v6,
+        };
+        records.extend_from_slice(&record);
+        self.pending += 1;
+        self.export_time = self.export_time.max(flow.last_seen.secs);
+    }
+
+    fn save_results(&mut self, _path: &str) -> Result<(), &'static str> {
+        self.write_errors.result()
+    }
+}
+
+impl Ipfix {
+    /// Size of a message without records: header, template set and data set headers
+    fn overhead(&self) -> usize {
+        16 + template_set(self.enterprise_id).len() + 2 * 4
+    }
+
+    /// Export pending records, in a single message
+    fn export(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        let msg = self.message();
+        self.records_v4.clear();
+        self.records_v6.clear();
+        self.sequence = self.sequence.wrapping_add(self.pending);
+        self.pending = 0;
+        if let Some(file) = &mut self.file {
+            let res = file.write_all(&msg);
+            self.write_errors.check(res);
+        }
+        if let Some(socket) = &self.socket {
+            let res = socket.send(&msg).map(|_| ());
+            self.write_errors.check(res);
+        }
+    }
+
+    /// Build a message with the templates and the pending records
+    fn message(&self) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(MAX_MESSAGE_SIZE);
+        msg.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+        // length, set at the end
+        msg.extend_from_slice(&[0, 0]);
+        msg.extend_from_slice(&self.export_time.to_be_bytes());
+        msg.extend_from_slice(&self.sequence.to_be_bytes());
+        msg.extend_from_slice(&self.observation_domain.to_be_bytes());
+        msg.extend_from_slice(&template_set(self.enterprise_id));
+        for (template_id, records) in &[
+            (TEMPLATE_ID_IPV4, &self.records_v4),
+            (TEMPLATE_ID_IPV6, &self.records_v6),
+        ] {
+            if !records.is_empty() {
+                push_set(&mut msg, *template_id, records);
+            }
+        }
+        let len = msg.len() as u16;
+        msg[2..4].copy_from_slice(&len.to_be_bytes());
+        msg
+    }
+}
+
+/// Create an UDP socket connected to the collector
+fn connect(collector: &str) -> io::Result<UdpSocket> {
+    let socket = if collector.starts_with('[') {
+        UdpSocket::bind("[::]:0")?
+    } else {
+        UdpSocket::bind("0.0.0.0:0")?
+    };
+    socket.connect(collector)?;
+    Ok(socket)
+}
+
+/// Append a set (header and content) to a message
+fn push_set(msg: &mut Vec<u8>, set_id: u16, content: &[u8]) {
+    msg.extend_from_slice(&set_id.to_be_bytes());
+    msg.extend_from_slice(&((content.len() + 4) as u16).to_be_bytes());
+    msg.extend_from_slice(content);
+}
+
+/// Build the template set, defining the IPv4 and IPv6 templates
+fn template_set(enterprise_id: u32) -> Vec<u8> {
+    let templates: [(u16, [FieldSpecifier; 2]); 2] = [
+        // sourceIPv4Address, destinationIPv4Address
+        (TEMPLATE_ID_IPV4, [(8, 4, None), (12, 4, None)]),
+        // sourceIPv6Address, destinationIPv6Address
+        (TEMPLATE_ID_IPV6, [(27, 16, None), (28, 16, None)]),
+    ];
+    let mut content = Vec::new();
+    for (template_id, addr_fields) in &templates {
+        let app_proto_field = (IE_APP_PROTO, VARIABLE_LENGTH, Some(enterprise_id));
+        let fields: Vec<FieldSpecifier> = addr_fields
+            .iter()
+            .chain(FLOW_FIELDS)
+            .copied()
+            .chain(std::iter::once(app_proto_field))
+            .collect();
+        content.extend_from_slice(&template_id.to_be_bytes());
+        content.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (id, length, enterprise) in fields {
+            match enterprise {
+                Some(enterprise) => {
+                    content.extend_from_slice(&(id | 0x8000).to_be_bytes());
+                    content.extend_from_slice(&length.to_be_bytes());
+                    content.extend_from_slice(&enterprise.to_be_bytes());
+                }
+                None => {
+                    content.extend_from_slice(&id.to_be_bytes());
+                    content.extend_from_slice(&length.to_be_bytes());
+                }
+            }
+        }
+    }
+    let mut set = Vec::new();
+    push_set(&mut set, TEMPLATE_SET_ID, &content);
+    set
+}
+
+fn to_millis(ts: Duration) -> u64 {
+    u64::from(ts.secs) * 1000 + u64::from(ts.nanos / 1_000_000)
+}
+
+/// Encode a flow, using the template matching its address family
+fn encode_record(buf: &mut Vec<u8>, flow: &Flow, app_proto: Option<&str>) {
+    let t5 = &flow.five_tuple;
+    match (t5.src, t5.dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            buf.extend_from_slice(&src.octets());
+            buf.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            buf.extend_from_slice(&to_ipv6(src).octets());
+            buf.extend_from_slice(&to_ipv6(dst).octets());
+        }
+    }
+    buf.extend_from_slice(&t5.src_port.to_be_bytes());
+    buf.extend_from_slice(&t5.dst_port.to_be_bytes());
+    buf.push(t5.proto);
+    buf.extend_from_slice(&u16::from(flow.tcp_flags).to_be_bytes());
+    buf.extend_from_slice(&flow.packets_to_server.to_be_bytes());
+    buf.extend_from_slice(&flow.bytes_to_server.to_be_bytes());
+    buf.extend_from_slice(&flow.packets_to_client().to_be_bytes());
+    buf.extend_from_slice(&flow.bytes_to_client().to_be_bytes());
+    buf.extend_from_slice(&to_millis(flow.first_seen).to_be_bytes());
+    buf.extend_from_slice(&to_millis(flow.last_seen).to_be_bytes());
+    // variable-length field (short form, the name is less than 255 bytes)
+    let app_proto = app_proto.unwrap_or("").as_bytes();
+    buf.push(app_proto.len() as u8);
+    buf.extend_from_slice(app_proto);
+}
+
+fn to_ipv6(addr: IpAddr) -> std::net::Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libpcap_tools::FiveTuple;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn ipfix_message() {
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: 1234,
+            dst_port: 443,
+        };
+        let mut flow = Flow::new(&five_tuple, 1, 0);
+        flow.update(Duration::new(2, 500_000), 60, 0x02, true);
+        flow.update(Duration::new(3, 0), 1500, 0x12, false);
+        let mut plugin = Ipfix {
+            file: None,
+            socket: None,
+            enterprise_id: DEFAULT_PEN,
+            observation_domain: 1,
+            records_v4: Vec::new(),
+            records_v6: Vec::new(),
+            pending: 0,
+            sequence: 0,
+            export_time: 0,
+            app_protos: FnvHashMap::default(),
+            write_errors: WriteErrors::new("ipfix: could not export records"),
+        };
+        plugin.app_protos.insert(flow.flow_id, Some("tls"));
+        plugin.flow_destroyed(&flow);
+        // addresses (8), ports (4), protocol (1), flags (2), counters (32), timestamps (16) and
+        // application protocol (1 + 3)
+        assert_eq!(plugin.records_v4.len(), 67);
+        assert_eq!(&plugin.records_v4[63..], b"\x03tls");
+        let msg = plugin.message();
+        assert_eq!(&msg[0..2], &IPFIX_VERSION.to_be_bytes());
+        assert_eq!(usize::from(u16::from_be_bytes([msg[2], msg[3]])), msg.len());
+        assert_eq!(&msg[4..8], &3u32.to_be_bytes());
+        assert_eq!(msg.len(), plugin.overhead() - 4 + 67);
+        // data set follows the template set
+        let data_set = &msg[msg.len() - 71..];
+        assert_eq!(&data_set[0..4], &[0x01, 0x00, 0x00, 71]);
+        plugin.export();
+        assert_eq!(plugin.sequence, 1);
+        assert!(plugin.records_v4.is_empty());
+    }
+}
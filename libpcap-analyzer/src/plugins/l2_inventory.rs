@@ -0,0 +1,312 @@
+//! Plugin building an inventory of link-layer (ethernet) addresses and ARP traffic
+//!
+//! For each source MAC address, the plugin records the number of frames and bytes, the VLANs and
+//! ethertypes, and the IPv4 addresses announced in ARP messages. ARP requests are matched with
+//! their replies, and gratuitous ARP messages are counted.
+//!
+//! An IPv4 address claimed by several MAC addresses in ARP messages is reported as possible ARP
+//! spoofing. Note that this can also be caused by legitimate changes (for ex. a replaced network
+//! card, or a failover between two hosts).
+
+use crate::plugin::{Plugin, PluginResult, PLUGIN_L2};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use libpcap_tools::{Duration, Packet};
+use pnet_base::MacAddr;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Serialize)]
+struct MacInfo {
+    frames: u64,
+    bytes: u64,
+    first_seen: String,
+    last_seen: String,
+    /// The address is locally administered (not assigned by the vendor)
+    local: bool,
+    vlans: BTreeSet<u16>,
+    ethertypes: BTreeSet<String>,
+    /// IPv4 addresses announced in ARP messages
+    ips: BTreeSet<Ipv4Addr>,
+}
+
+/// MAC address announced for an IPv4 address
+#[derive(Serialize)]
+struct Binding {
+    mac: String,
+    first_seen: String,
+    last_seen: String,
+    packets: u64,
+}
+
+/// ARP requests from a host for a target address
+#[derive(Default, Serialize)]
+struct ArpPair {
+    requests: u64,
+    replies: u64,
+    /// MAC addresses in the replies
+    macs: BTreeSet<String>,
+}
+
+#[derive(Default)]
+pub struct L2Inventory {
+    macs: BTreeMap<MacAddr, MacInfo>,
+    arp_requests: u64,
+    arp_replies: u64,
+    /// ARP probes (sender address 0.0.0.0, used for address conflict detection)
+    arp_probes: u64,
+    gratuitous_arp: u64,
+    /// ARP messages where the sender MAC is not the ethernet source address
+    arp_mac_mismatch: u64,
+    /// ARP requests and replies, by (requester, target)
+    arp_pairs: BTreeMap<(Ipv4Addr, Ipv4Addr), ArpPair>,
+    /// MAC addresses announced for each IPv4 address
+    bindings: BTreeMap<Ipv4Addr, BTreeMap<MacAddr, Binding>>,
+}
+
+plugin_builder!(L2Inventory, L2InventoryBuilder);
+
+impl Plugin for L2Inventory {
+    fn name(&self) -> &'static str {
+        "L2Inventory"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L2
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_link<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        _linklayertype: u16,
+        data: &'i [u8],
+    ) -> PluginResult<'i> {
+        self.handle_frame(packet.ts, data);
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "l2-inventory.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl L2Inventory {
+    fn handle_frame(&mut self, ts: Duration, data: &[u8]) {
+        if data.len() < 14 {
+            return;
+        }
+        let src = mac_address(&data[6..12]);
+        let mut ethertype = u16::from_be_bytes([data[12], data[13]]);
+        let mut offset = 14;
+        let mut vlans = Vec::new();
+        // 802.1Q and 802.1ad tags
+        while ethertype == 0x8100 || ethertype == 0x88a8 {
+            let tag = match data.get(offset..offset + 4) {
+                Some(tag) => tag,
+                None => return,
+            };
+            vlans.push(u16::from_be_bytes([tag[0], tag[1]]) & 0x0fff);
+            ethertype = u16::from_be_bytes([tag[2], tag[3]]);
+            offset += 4;
+        }
+        let ts_str = format_ts(ts);
+        let info = self.macs.entry(src).or_insert_with(|| MacInfo {
+            frames: 0,
+            bytes: 0,
+            first_seen: ts_str.clone(),
+            last_seen: String::new(),
+            local: data[6] & 0x02 != 0,
+            vlans: BTreeSet::new(),
+            ethertypes: BTreeSet::new(),
+            ips: BTreeSet::new(),
+        });
+        info.frames += 1;
+        info.bytes += data.len() as u64;
+        info.last_seen = ts_str;
+        info.vlans.extend(vlans);
+        // values up to 1500 are lengths (802.3 frames)
+        if ethertype > 1500 {
+            info.ethertypes.insert(format!("0x{:04x}", ethertype));
+        }
+        if ethertype == ETHERTYPE_ARP {
+            self.handle_arp(ts, src, &data[offset..]);
+        }
+    }
+
+    fn handle_arp(&mut self, ts: Duration, eth_src: MacAddr, data: &[u8]) {
+        // only ethernet and IPv4 addresses are supported
+        let arp = match data.get(..28) {
+            Some(arp) if arp[..6] == [0x00, 0x01, 0x08, 0x00, 6, 4] => arp,
+            _ => return,
+        };
+        let operation = u16::from_be_bytes([arp[6], arp[7]]);
+        let sender_mac = mac_address(&arp[8..14]);
+        let sender_ip = Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]);
+        let target_ip = Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]);
+        if sender_mac != eth_src {
+            self.arp_mac_mismatch += 1;
+        }
+        if sender_ip.is_unspecified() {
+            self.arp_probes += 1;
+            return;
+        }
+        match operation {
+            1 => {
+                self.arp_requests += 1;
+                if sender_ip != target_ip {
+                    let pair = self.arp_pairs.entry((sender_ip, target_ip)).or_default();
+                    pair.requests += 1;
+                }
+            }
+            2 => {
+                self.arp_replies += 1;
+                if let Some(pair) = self.arp_pairs.get_mut(&(target_ip, sender_ip)) {
+                    pair.replies += 1;
+                    pair.macs.insert(sender_mac.to_string());
+                }
+            }
+            _ => return,
+        }
+        if sender_ip == target_ip {
+            self.gratuitous_arp += 1;
+        }
+        if let Some(info) = self.macs.get_mut(&sender_mac) {
+            info.ips.insert(sender_ip);
+        }
+        let ts_str = format_ts(ts);
+        let binding = self
+            .bindings
+            .entry(sender_ip)
+            .or_default()
+            .entry(sender_mac)
+            .or_insert_with(|| Binding {
+                mac: sender_mac.to_string(),
+                first_seen: ts_str.clone(),
+                last_seen: String::new(),
+                packets: 0,
+            });
+        binding.packets += 1;
+        binding.last_seen = ts_str;
+    }
+
+    fn get_results_json(&self) -> Value {
+        let macs: Vec<_> = self
+            .macs
+            .iter()
+            .map(|(mac, info)| {
+                let mut v = json!(info);
+                v["mac"] = json!(mac.to_string());
+                v
+            })
+            .collect();
+        let pairs: Vec<_> = self
+            .arp_pairs
+            .iter()
+            .map(|((requester, target), pair)| {
+                let mut v = json!(pair);
+                v["requester"] = json!(requester);
+                v["target"] = json!(target);
+                v
+            })
+            .collect();
+        let spoofing: Vec<_> = self
+            .bindings
+            .iter()
+            .filter(|(_, macs)| macs.len() > 1)
+            .map(|(ip, macs)| json!({ "ip": ip, "macs": macs.values().collect::<Vec<_>>() }))
+            .collect();
+        json!({
+            "l2-inventory": {
+                "macs": macs,
+                "arp": {
+                    "requests": self.arp_requests,
+                    "replies": self.arp_replies,
+                    "probes": self.arp_probes,
+                    "gratuitous": self.gratuitous_arp,
+                    "mac_mismatch": self.arp_mac_mismatch,
+                    "pairs": pairs,
+                },
+                "arp_spoofing": spoofing,
+            }
+        })
+    }
+}
+
+fn mac_address(b: &[u8]) -> MacAddr {
+    MacAddr::new(b[0], b[1], b[2], b[3], b[4], b[5])
+}
+
+fn format_ts(ts: Duration) -> String {
+    format!("{}.{:09}", ts.secs, ts.nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::L2Inventory;
+    use libpcap_tools::Duration;
+
+    /// Build an ARP frame (in VLAN 10)
+    fn arp_frame(operation: u8, mac: u8, sender_ip: [u8; 4], target_ip: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, mac]);
+        frame.extend_from_slice(&[0x81, 0x00, 0x00, 0x0a, 0x08, 0x06]);
+        frame.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0, operation]);
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, mac]);
+        frame.extend_from_slice(&sender_ip);
+        frame.extend_from_slice(&[0; 6]);
+        frame.extend_from_slice(&target_ip);
+        frame
+    }
+
+    #[test]
+    fn l2_inventory_arp() {
+        let mut inventory = L2Inventory::default();
+        let host = [192, 168, 0, 1];
+        let gateway = [192, 168, 0, 254];
+        let frames = [
+            arp_frame(1, 1, host, gateway),
+            arp_frame(2, 2, gateway, host),
+            // another host claims the address of the gateway
+            arp_frame(2, 3, gateway, gateway),
+        ];
+        for (i, frame) in frames.iter().enumerate() {
+            inventory.handle_frame(Duration::new(i as u32, 0), frame);
+        }
+        let results = inventory.get_results_json();
+        let results = &results["l2-inventory"];
+        assert_eq!(results["macs"].as_array().map(Vec::len), Some(3));
+        assert_eq!(results["macs"][0]["mac"], "02:00:00:00:00:01");
+        assert_eq!(results["macs"][0]["vlans"][0], 10);
+        assert_eq!(results["macs"][0]["local"], true);
+        assert_eq!(results["arp"]["requests"], 1);
+        assert_eq!(results["arp"]["gratuitous"], 1);
+        let pair = &results["arp"]["pairs"][0];
+        assert_eq!(pair["target"], "192.168.0.254");
+        assert_eq!(pair["replies"], 1);
+        assert_eq!(pair["macs"][0], "02:00:00:00:00:02");
+        let spoofing = &results["arp_spoofing"];
+        assert_eq!(spoofing[0]["ip"], "192.168.0.254");
+        assert_eq!(spoofing[0]["macs"][1]["mac"], "02:00:00:00:00:03");
+    }
+}
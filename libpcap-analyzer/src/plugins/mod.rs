@@ -2,23 +2,66 @@
 
 use std::collections::HashMap;
 
-use crate::{Plugin, PluginBuilder, PluginBuilderError, PluginRegistry};
+use crate::{Plugin, PluginBuilder, PluginBuilderError, PluginRegistry, PluginSelection};
 use libpcap_tools::Config;
 
 mod basic_stats;
+mod checksums;
 #[cfg(feature = "plugin_community_id")]
 mod community_id;
+mod conversations;
+#[cfg(feature = "plugin_credentials")]
+mod credentials;
+mod dhcp;
+mod dns_stats;
+#[cfg(feature = "plugin_esp")]
+mod esp;
+#[cfg(feature = "plugin_eve")]
+mod eve;
 #[cfg(feature = "plugin_examples")]
 mod examples;
+#[cfg(feature = "plugin_file_extract")]
+mod file_extract;
+mod flow_export;
 mod flows;
 #[cfg(feature = "plugins_debug")]
 mod hexdump;
+mod host_profile;
+mod http;
+mod icmp;
+mod ics;
+#[cfg(feature = "plugin_intel")]
+mod intel;
+mod ipfix;
+mod l2_inventory;
+#[cfg(feature = "plugin_os_fingerprint")]
+mod os_fingerprint;
 #[cfg(feature = "plugin_ospf")]
 mod ospf;
+#[cfg(feature = "arrow")]
+mod parquet_export;
+mod portscan;
+mod prometheus;
+#[cfg(feature = "plugin_quic")]
+mod quic;
 #[cfg(feature = "plugin_rusticata")]
 mod rusticata;
+#[cfg(feature = "plugin_script")]
+mod script;
+mod sctp;
+mod smb;
+#[cfg(feature = "plugin_sqlite")]
+pub(crate) mod sqlite;
+mod tcp_health;
+mod throughput;
+#[cfg(feature = "plugin_tls_decrypt")]
+mod tls_decrypt;
+#[cfg(feature = "plugin_tls_metadata")]
+mod tls_metadata;
 #[cfg(feature = "plugin_tls_stats")]
 mod tls_stats;
+mod voip;
+mod wireguard;
 
 /// Storage of plugin instances
 pub struct Plugins {
@@ -45,9 +88,14 @@ impl PluginsFactory {
     }
 
     /// Instantiate all plugins
+    ///
+    /// The configuration is validated by all builders before creating plugins.
     pub fn build_plugins(&self, config: &Config) -> Result<PluginRegistry, PluginBuilderError> {
         let mut registry = PluginRegistry::new();
 
+        for b in &self.list {
+            b.validate_config(config)?;
+        };
         for b in &self.list {
             b.build(&mut registry, config)?;
         };
@@ -62,6 +110,11 @@ impl PluginsFactory {
     {
         let mut registry = PluginRegistry::new();
 
+        for b in &self.list {
+            if predicate(b.name()) {
+                b.validate_config(config)?;
+            }
+        };
         for b in &self.list {
             if predicate(b.name()) {
                 b.build(&mut registry, config)?;
@@ -71,6 +124,16 @@ impl PluginsFactory {
         Ok(registry)
     }
 
+    /// Instantiate selected plugins, in the order of the selection
+    ///
+    /// Builders are filtered using their name, then created plugins are filtered and sorted
+    /// using the plugin names (see `PluginRegistry::apply_selection`).
+    pub fn build_selected_plugins(&self, selection: &PluginSelection, config: &Config) -> Result<PluginRegistry, PluginBuilderError> {
+        let mut registry = self.build_filter_plugins(|n| selection.rank(n).is_some(), config)?;
+        registry.apply_selection(selection);
+        Ok(registry)
+    }
+
     /// Iterate builder names
     pub fn iter_builders<Op>(&self, op: Op)
     where
@@ -87,7 +150,26 @@ impl Default for PluginsFactory {
     fn default() -> Self {
         let mut v: Vec<Box<dyn PluginBuilder>> = vec![
             Box::new(basic_stats::BasicStatsBuilder),
+            Box::new(checksums::ChecksumsBuilder),
+            Box::new(conversations::ConversationsBuilder),
+            Box::new(dhcp::DhcpBuilder),
+            Box::new(dns_stats::DnsStatsBuilder),
             Box::new(flows::FlowsInfoBuilder),
+            Box::new(flow_export::FlowExportBuilder),
+            Box::new(host_profile::HostProfilesBuilder),
+            Box::new(http::HttpBuilder),
+            Box::new(icmp::IcmpBuilder),
+            Box::new(ics::IcsBuilder),
+            Box::new(ipfix::IpfixBuilder),
+            Box::new(l2_inventory::L2InventoryBuilder),
+            Box::new(portscan::PortscanBuilder),
+            Box::new(prometheus::PrometheusBuilder),
+            Box::new(sctp::SctpStatsBuilder),
+            Box::new(smb::SmbBuilder),
+            Box::new(tcp_health::TcpHealthBuilder),
+            Box::new(throughput::ThroughputBuilder),
+            Box::new(voip::VoipBuilder),
+            Box::new(wireguard::WireGuardBuilder),
             ];
 
         #[cfg(feature = "plugin_community_id")]
@@ -96,6 +178,12 @@ impl Default for PluginsFactory {
         v.push(Box::new(hexdump::HexDumpBuilder));
         #[cfg(feature = "plugin_tls_stats")]
         v.push(Box::new(tls_stats::TlsStatsBuilder));
+        #[cfg(feature = "plugin_tls_decrypt")]
+        v.push(Box::new(tls_decrypt::TlsDecryptBuilder));
+        #[cfg(feature = "plugin_tls_metadata")]
+        v.push(Box::new(tls_metadata::TlsMetadataBuilder));
+        #[cfg(feature = "plugin_quic")]
+        v.push(Box::new(quic::QuicBuilder));
         #[cfg(feature = "plugin_rusticata")]
         v.push(Box::new(rusticata::RusticataBuilder));
         #[cfg(feature = "plugin_examples")]
@@ -105,6 +193,24 @@ impl Default for PluginsFactory {
         }
         #[cfg(feature = "plugin_ospf")]
         v.push(Box::new(ospf::OspfLogBuilder));
+        #[cfg(feature = "plugin_script")]
+        v.push(Box::new(script::ScriptBuilder));
+        #[cfg(feature = "plugin_eve")]
+        v.push(Box::new(eve::EveBuilder));
+        #[cfg(feature = "plugin_esp")]
+        v.push(Box::new(esp::EspBuilder));
+        #[cfg(feature = "arrow")]
+        v.push(Box::new(parquet_export::ParquetExportBuilder));
+        #[cfg(feature = "plugin_sqlite")]
+        v.push(Box::new(sqlite::SqliteBuilder));
+        #[cfg(feature = "plugin_credentials")]
+        v.push(Box::new(credentials::CredentialsBuilder));
+        #[cfg(feature = "plugin_file_extract")]
+        v.push(Box::new(file_extract::FileExtractBuilder));
+        #[cfg(feature = "plugin_intel")]
+        v.push(Box::new(intel::IntelBuilder));
+        #[cfg(feature = "plugin_os_fingerprint")]
+        v.push(Box::new(os_fingerprint::OsFingerprintBuilder));
 
         PluginsFactory { list: v }
     }
@@ -0,0 +1,540 @@
+//! Plugin guessing the operating system of hosts (passive fingerprinting, p0f-style)
+//!
+//! Signatures are computed from TCP SYN (clients) and SYN+ACK (servers) packets: initial TTL,
+//! MSS, window size and scale, and layout of the TCP options. The JA3 hashes of TLS client hellos
+//! are also recorded. Signatures are matched against a bundled database (`os_signatures.txt`),
+//! and optionally the file set by `signatures` in section `[plugin.os_fingerprint]` (same format,
+//! its entries are tested first).
+//!
+//! If no TCP signature matches exactly, a fuzzy match is tried, on the initial TTL and options
+//! layout only. The probable OS of a host is the label matched by most packets, exact matches
+//! being preferred.
+
+use super::tls_metadata::ClientHello;
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_L4, PLUGIN_TCP_SEGMENTS};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+
+/// Bundled signature database
+const BUNDLED_SIGNATURES: &str = include_str!("os_signatures.txt");
+
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    /// SYN, sent by a client
+    Syn,
+    /// SYN+ACK, sent by a server
+    SynAck,
+}
+
+#[derive(Debug, PartialEq)]
+enum WindowSize {
+    Any,
+    Value(u16),
+    /// Multiple of the MSS
+    Mss(u32),
+}
+
+#[derive(Debug, PartialEq)]
+struct TcpSignature {
+    label: String,
+    direction: Direction,
+    ittl: u8,
+    mss: Option<u16>,
+    window: WindowSize,
+    scale: Option<u8>,
+    options: String,
+}
+
+impl TcpSignature {
+    /// Parse a signature `ittl:mss:window,scale:options` (`*` matches any value)
+    fn parse(label: &str, direction: Direction, s: &str) -> Option<Self> {
+        let mut fields = s.splitn(4, ':');
+        let ittl = fields.next()?.parse().ok()?;
+        let mss = match fields.next()? {
+            "*" => None,
+            mss => Some(mss.parse().ok()?),
+        };
+        let (window, scale) = fields.next()?.split_once(',')?;
+        let window = match window {
+            "*" => WindowSize::Any,
+            w => match w.strip_prefix("mss*") {
+                Some(n) => WindowSize::Mss(n.parse().ok()?),
+                None => WindowSize::Value(w.parse().ok()?),
+            },
+        };
+        let scale = match scale {
+            "*" => None,
+            scale => Some(scale.parse().ok()?),
+        };
+        Some(TcpSignature {
+            label: label.to_owned(),
+            direction,
+            ittl,
+            mss,
+            window,
+            scale,
+            options: fields.next()?.to_owned(),
+        })
+    }
+
+    fn matches(&self, obs: &TcpObservation) -> bool {
+        let window = match self.window {
+            WindowSize::Any => true,
+            WindowSize::Value(w) => w == obs.window,
+            WindowSize::Mss(n) => obs
+                .mss
+                .map_or(false, |mss| u32::from(mss) * n == u32::from(obs.window)),
+        };
+        self.matches_fuzzy(obs)
+            && window
+            && self.mss.map_or(true, |mss| obs.mss == Some(mss))
+            && self.scale.map_or(true, |scale| scale == obs.scale)
+    }
+
+    fn matches_fuzzy(&self, obs: &TcpObservation) -> bool {
+        self.direction == obs.direction && self.ittl == obs.ittl && self.options == obs.options
+    }
+}
+
+/// Signature database
+#[derive(Default)]
+struct Signatures {
+    tcp: Vec<TcpSignature>,
+    /// Labels, by JA3 hash
+    tls: FnvHashMap<String, String>,
+}
+
+impl Signatures {
+    /// Load signatures (see `os_signatures.txt` for the format)
+    fn load(&mut self, content: &str) -> Result<(), String> {
+        let mut section = "";
+        for (num, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name;
+                continue;
+            }
+            let err = || format!("invalid signature at line {}: {:?}", num + 1, line);
+            let (label, signature) = line.rsplit_once('=').ok_or_else(err)?;
+            let (label, signature) = (label.trim(), signature.trim());
+            let direction = match section {
+                "syn" => Direction::Syn,
+                "synack" => Direction::SynAck,
+                "tls" => {
+                    self.tls
+                        .entry(signature.to_ascii_lowercase())
+                        .or_insert_with(|| label.to_owned());
+                    continue;
+                }
+                _ => return Err(format!("unknown section {:?}", section)),
+            };
+            let signature = TcpSignature::parse(label, direction, signature).ok_or_else(err)?;
+            self.tcp.push(signature);
+        }
+        Ok(())
+    }
+
+    /// Get the label of a TCP signature, and true if the match is fuzzy
+    fn match_tcp(&self, obs: &TcpObservation) -> Option<(&str, bool)> {
+        self.tcp
+            .iter()
+            .find(|sig| sig.matches(obs))
+            .map(|sig| (sig.label.as_str(), false))
+            .or_else(|| {
+                self.tcp
+                    .iter()
+                    .find(|sig| sig.matches_fuzzy(obs))
+                    .map(|sig| (sig.label.as_str(), true))
+            })
+    }
+}
+
+/// Characteristics of a SYN or SYN+ACK packet
+#[derive(Debug, PartialEq)]
+struct TcpObservation {
+    direction: Direction,
+    ttl: u8,
+    /// Initial TTL (guessed)
+    ittl: u8,
+    mss: Option<u16>,
+    window: u16,
+    /// Window scale, 0 if absent
+    scale: u8,
+    options: String,
+}
+
+impl TcpObservation {
+    /// Parse the header of a TCP SYN or SYN+ACK packet
+    fn parse(ttl: u8, l4_data: &[u8]) -> Option<Self> {
+        if l4_data.len() < 20 {
+            return None;
+        }
+        let header_len = usize::from(l4_data[12] >> 4) * 4;
+        let flags = l4_data[13];
+        let direction = match flags & (TCP_SYN | TCP_ACK) {
+            TCP_SYN => Direction::Syn,
+            f if f == TCP_SYN | TCP_ACK => Direction::SynAck,
+            _ => return None,
+        };
+        let window = u16::from_be_bytes([l4_data[14], l4_data[15]]);
+        let mut opts = l4_data.get(20..header_len)?;
+        let mut layout = Vec::new();
+        let (mut mss, mut scale) = (None, 0);
+        while let Some(&kind) = opts.first() {
+            match kind {
+                0 => {
+                    layout.push(format!("eol+{}", opts.len() - 1));
+                    break;
+                }
+                1 => {
+                    layout.push("nop".to_owned());
+                    opts = &opts[1..];
+                    continue;
+                }
+                _ => (),
+            }
+            let len = usize::from(*opts.get(1)?);
+            let value = opts.get(2..len)?;
+            match (kind, value) {
+                (2, &[a, b]) => {
+                    mss = Some(u16::from_be_bytes([a, b]));
+                    layout.push("mss".to_owned());
+                }
+                (3, &[shift]) => {
+                    scale = shift;
+                    layout.push("ws".to_owned());
+                }
+                (4, _) => layout.push("sok".to_owned()),
+                (5, _) => layout.push("sack".to_owned()),
+                (8, _) => layout.push("ts".to_owned()),
+                _ => layout.push(format!("?{}", kind)),
+            }
+            opts = &opts[len..];
+        }
+        Some(TcpObservation {
+            direction,
+            ttl,
+            ittl: initial_ttl(ttl),
+            mss,
+            window,
+            scale,
+            options: layout.join(","),
+        })
+    }
+}
+
+impl fmt::Display for TcpObservation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mss {
+            Some(mss) => write!(f, "{}:{}:", self.ittl, mss)?,
+            None => write!(f, "{}:*:", self.ittl)?,
+        }
+        write!(f, "{},{}:{}", self.window, self.scale, self.options)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TcpRecord {
+    direction: Direction,
+    signature: String,
+    label: Option<String>,
+    fuzzy: bool,
+    packets: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct TlsRecord {
+    ja3: String,
+    label: Option<String>,
+    flows: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Host {
+    /// Probable operating system
+    os: Option<String>,
+    /// Distance (number of hops), from the TTL of TCP packets
+    distance: Option<u8>,
+    tcp: Vec<TcpRecord>,
+    tls: Vec<TlsRecord>,
+}
+
+impl Host {
+    /// Get the label matched by most packets, exact matches being preferred
+    fn probable_os(&self) -> Option<String> {
+        let mut votes: Vec<(&str, u64, u64)> = Vec::new();
+        let labels = self
+            .tcp
+            .iter()
+            .map(|r| (&r.label, r.fuzzy, r.packets))
+            .chain(self.tls.iter().map(|r| (&r.label, false, r.flows)));
+        for (label, fuzzy, count) in labels {
+            let label = match label {
+                Some(label) => label.as_str(),
+                None => continue,
+            };
+            let idx = match votes.iter().position(|(l, _, _)| *l == label) {
+                Some(idx) => idx,
+                None => {
+                    votes.push((label, 0, 0));
+                    votes.len() - 1
+                }
+            };
+            if fuzzy {
+                votes[idx].2 += count;
+            } else {
+                votes[idx].1 += count;
+            }
+        }
+        votes
+            .iter()
+            .max_by_key(|(_, exact, fuzzy)| (*exact, *fuzzy))
+            .map(|(label, _, _)| (*label).to_owned())
+    }
+}
+
+pub struct OsFingerprint {
+    signatures: Signatures,
+    hosts: FnvHashMap<IpAddr, Host>,
+}
+
+pub struct OsFingerprintBuilder;
+
+impl PluginBuilder for OsFingerprintBuilder {
+    fn name(&self) -> &'static str { "OsFingerprintBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let mut signatures = Signatures::default();
+        let plugin_config = config.plugin_config("os_fingerprint");
+        if let Some(path) = plugin_config.get("signatures") {
+            let content = fs::read_to_string(path).map_err(|e| {
+                PluginBuilderError::InvalidConfig(format!("os_fingerprint: {}: {}", path, e))
+            })?;
+            signatures.load(&content).map_err(|e| {
+                PluginBuilderError::InvalidConfig(format!("os_fingerprint: {}: {}", path, e))
+            })?;
+        }
+        signatures
+            .load(BUNDLED_SIGNATURES)
+            .expect("invalid bundled signatures");
+        let plugin = OsFingerprint {
+            signatures,
+            hosts: FnvHashMap::default(),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+}
+
+impl Plugin for OsFingerprint {
+    fn name(&self) -> &'static str {
+        "OsFingerprint"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_TCP_SEGMENTS
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        if pinfo.l4_type != 6 {
+            return PluginResult::None;
+        }
+        let src = pinfo.five_tuple.src;
+        if let Some(obs) = TcpObservation::parse(pinfo.ttl, pinfo.l4_data) {
+            self.add_tcp(src, &obs);
+        } else if let Some(data) = pinfo.l4_payload {
+            // reassembled data (not a segment), handshake record, client hello
+            if pinfo.l4_data.is_empty()
+                && pinfo.to_server
+                && data.len() > 5
+                && data[0] == 0x16
+                && data[5] == 1
+            {
+                if let Some(hello) = ClientHello::from_record(data) {
+                    self.add_tls(src, hello.ja3_hash());
+                }
+            }
+        }
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "os_fingerprint.json")
+            .or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl OsFingerprint {
+    fn add_tcp(&mut self, ip: IpAddr, obs: &TcpObservation) {
+        let host = self.hosts.entry(ip).or_default();
+        let distance = obs.ittl.saturating_sub(obs.ttl);
+        host.distance = Some(host.distance.map_or(distance, |d| d.min(distance)));
+        let signature = obs.to_string();
+        if let Some(record) = host
+            .tcp
+            .iter_mut()
+            .find(|r| r.direction == obs.direction && r.signature == signature)
+        {
+            record.packets += 1;
+            return;
+        }
+        let (label, fuzzy) = match self.signatures.match_tcp(obs) {
+            Some((label, fuzzy)) => (Some(label.to_owned()), fuzzy),
+            None => (None, false),
+        };
+        debug!(
+            "os_fingerprint: {} {:?} {} -> {:?}",
+            ip, obs.direction, signature, label
+        );
+        host.tcp.push(TcpRecord {
+            direction: obs.direction,
+            signature,
+            label,
+            fuzzy,
+            packets: 1,
+        });
+    }
+
+    fn add_tls(&mut self, ip: IpAddr, ja3: String) {
+        let host = self.hosts.entry(ip).or_default();
+        if let Some(record) = host.tls.iter_mut().find(|r| r.ja3 == ja3) {
+            record.flows += 1;
+            return;
+        }
+        let label = self.signatures.tls.get(&ja3).cloned();
+        host.tls.push(TlsRecord {
+            ja3,
+            label,
+            flows: 1,
+        });
+    }
+
+    fn get_results_json(&mut self) -> Value {
+        for host in self.hosts.values_mut() {
+            host.os = host.probable_os();
+        }
+        let mut hosts: Vec<_> = self.hosts.iter().collect();
+        hosts.sort_by_key(|(ip, _)| *ip);
+        let hosts: Vec<_> = hosts
+            .into_iter()
+            .map(|(ip, host)| {
+                let mut v = json!(host);
+                v["ip"] = json!(ip);
+                v
+            })
+            .collect();
+        json!({ "os-fingerprint": { "hosts": hosts } })
+    }
+}
+
+/// Guess the initial TTL of a packet
+fn initial_ttl(ttl: u8) -> u8 {
+    match ttl {
+        0..=32 => 32,
+        33..=64 => 64,
+        65..=128 => 128,
+        _ => 255,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, OsFingerprint, Signatures, TcpObservation, BUNDLED_SIGNATURES};
+    use fnv::FnvHashMap;
+
+    /// Build a TCP header with options
+    fn tcp_header(flags: u8, window: u16, options: &[u8]) -> Vec<u8> {
+        let mut v = vec![0xc0, 0x00, 0x00, 0x50, 0, 0, 0, 1, 0, 0, 0, 0];
+        v.push((((20 + options.len()) / 4) as u8) << 4);
+        v.push(flags);
+        v.extend_from_slice(&window.to_be_bytes());
+        v.extend_from_slice(&[0, 0, 0, 0]);
+        v.extend_from_slice(options);
+        v
+    }
+
+    #[test]
+    fn os_fingerprint_tcp() {
+        let mut signatures = Signatures::default();
+        signatures
+            .load(BUNDLED_SIGNATURES)
+            .expect("invalid signatures");
+        assert!(signatures.load("[syn]\nbad = 64:*").is_err());
+
+        // Linux: mss, sok, ts, nop, ws
+        let options = [
+            2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7,
+        ];
+        let syn = TcpObservation::parse(61, &tcp_header(0x02, 64240, &options))
+            .expect("could not parse SYN");
+        assert_eq!(syn.direction, Direction::Syn);
+        assert_eq!(syn.to_string(), "64:1460:64240,7:mss,sok,ts,nop,ws");
+        assert_eq!(signatures.match_tcp(&syn), Some(("Linux 4.19+", false)));
+        // Windows: mss, nop, ws, nop, nop, sok
+        let options = [2, 4, 0x05, 0xb4, 1, 3, 3, 8, 1, 1, 4, 2];
+        let syn = TcpObservation::parse(128, &tcp_header(0x02, 12345, &options))
+            .expect("could not parse SYN");
+        assert_eq!(signatures.match_tcp(&syn), Some(("Windows 10/11", true)));
+        // not a SYN
+        assert_eq!(TcpObservation::parse(64, &tcp_header(0x10, 512, &[])), None);
+        let synack =
+            TcpObservation::parse(50, &tcp_header(0x12, 65535, &[2, 4, 5, 0xb4, 0, 0, 0, 0]))
+                .expect("could not parse SYN+ACK");
+        assert_eq!(synack.to_string(), "64:1460:65535,0:mss,eol+3");
+        assert_eq!(signatures.match_tcp(&synack), None);
+
+        let mut plugin = OsFingerprint {
+            signatures,
+            hosts: FnvHashMap::default(),
+        };
+        let ip = "192.0.2.1".parse().expect("invalid address");
+        plugin.add_tcp(ip, &syn);
+        plugin.add_tcp(ip, &syn);
+        plugin.add_tls(ip, "0123456789abcdef0123456789abcdef".to_owned());
+        let results = plugin.get_results_json();
+        let host = &results["os-fingerprint"]["hosts"][0];
+        assert_eq!(host["ip"], "192.0.2.1");
+        assert_eq!(host["os"], "Windows 10/11");
+        assert_eq!(host["distance"], 0);
+        assert_eq!(host["tcp"][0]["packets"], 2);
+        assert_eq!(host["tls"][0]["label"], serde_json::Value::Null);
+    }
+}
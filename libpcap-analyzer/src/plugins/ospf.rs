@@ -274,6 +274,9 @@ impl Plugin for OspfLog {
     fn plugin_type(&self) -> u16 {
         PLUGIN_L3
     }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
     fn handle_layer_network<'s, 'i>(
         &'s mut self,
         packet: &'s Packet,
@@ -0,0 +1,357 @@
+//! Plugin writing packet and flow records as Apache Parquet files
+//!
+//! Files are set in section `[plugin.parquet]`: `packets_file` for packet records (one row per
+//! layer 4 packet) and `flows_file` for flow records (one row per flow, written when the flow is
+//! destroyed). Relative file names are created in the output directory. The plugin is disabled
+//! if no file is set.
+//!
+//! Rows are written in batches of `batch_size` rows (default: 65536). Timestamps are in
+//! nanoseconds since the Unix epoch.
+
+use crate::app_proto::detect_app_proto;
+use crate::output::{self, WriteErrors};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use arrow::array::{
+    ArrayRef, BooleanArray, StringArray, TimestampNanosecondArray, UInt16Array, UInt32Array,
+    UInt64Array, UInt8Array,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, Flow, FlowID, Packet};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_BATCH_SIZE: usize = 65536;
+
+/// Columns of packet records
+#[derive(Default)]
+struct PacketColumns {
+    ts: Vec<i64>,
+    pcap_index: Vec<u64>,
+    flow_id: Vec<u64>,
+    proto: Vec<u8>,
+    src_ip: Vec<String>,
+    src_port: Vec<u16>,
+    dst_ip: Vec<String>,
+    dst_port: Vec<u16>,
+    to_server: Vec<bool>,
+    origlen: Vec<u32>,
+    payload_len: Vec<u32>,
+    tunnel_depth: Vec<u8>,
+}
+
+/// Columns of flow records
+#[derive(Default)]
+struct FlowColumns {
+    flow_id: Vec<u64>,
+    proto: Vec<u8>,
+    src_ip: Vec<String>,
+    src_port: Vec<u16>,
+    dst_ip: Vec<String>,
+    dst_port: Vec<u16>,
+    first_seen: Vec<i64>,
+    last_seen: Vec<i64>,
+    packets_to_server: Vec<u64>,
+    packets_to_client: Vec<u64>,
+    bytes_to_server: Vec<u64>,
+    bytes_to_client: Vec<u64>,
+    tcp_flags: Vec<u8>,
+    app_proto: Vec<Option<&'static str>>,
+}
+
+/// A Parquet file, and the rows not yet written
+struct Output<C> {
+    // plugins must be `Sync`, which is not guaranteed for the writer
+    writer: Mutex<ArrowWriter<File>>,
+    schema: SchemaRef,
+    columns: C,
+    rows: usize,
+}
+
+pub struct ParquetExport {
+    packets: Option<Output<PacketColumns>>,
+    flows: Option<Output<FlowColumns>>,
+    batch_size: usize,
+    app_protos: FnvHashMap<FlowID, Option<&'static str>>,
+    write_errors: WriteErrors,
+}
+
+pub struct ParquetExportBuilder;
+
+impl PluginBuilder for ParquetExportBuilder {
+    fn name(&self) -> &'static str { "ParquetExportBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let parquet_config = config.plugin_config("parquet");
+        let packets = match parquet_config.get("packets_file") {
+            Some(filename) => Some(Output::create(config, filename, packet_schema())?),
+            None => None,
+        };
+        let flows = match parquet_config.get("flows_file") {
+            Some(filename) => Some(Output::create(config, filename, flow_schema())?),
+            None => None,
+        };
+        if packets.is_none() && flows.is_none() {
+            debug!("parquet: no output file configured");
+            return Ok(());
+        }
+        let plugin = ParquetExport {
+            packets,
+            flows,
+            batch_size: parquet_config
+                .get_usize("batch_size")
+                .unwrap_or(DEFAULT_BATCH_SIZE),
+            app_protos: FnvHashMap::default(),
+            write_errors: WriteErrors::new("parquet: could not write records"),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("parquet");
+        for key in &["packets_file", "flows_file"] {
+            if config.contains(key) && config.get(key).is_none() {
+                return Err(PluginBuilderError::InvalidConfig(format!(
+                    "parquet: {} must be a string",
+                    key
+                )));
+            }
+        }
+        if config.contains("batch_size")
+            && !matches!(config.get_usize("batch_size"), Some(n) if n > 0)
+        {
+            return Err(PluginBuilderError::InvalidConfig(
+                "parquet: batch_size must be a positive integer".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for ParquetExport {
+    fn name(&self) -> &'static str {
+        "ParquetExport"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+
+    fn post_process(&mut self) {
+        let mut res = Ok(());
+        if let Some(packets) = self.packets.take() {
+            res = res.and(packets.close());
+        }
+        if let Some(flows) = self.flows.take() {
+            res = res.and(flows.close());
+        }
+        self.write_errors.check(res);
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        if self.flows.is_some() {
+            if let (Some(flow), Some(data)) = (pinfo.flow, pinfo.l4_payload) {
+                if !data.is_empty() {
+                    self.app_protos
+                        .entry(flow.flow_id)
+                        .or_insert_with(|| detect_app_proto(pinfo.five_tuple, data));
+                }
+            }
+        }
+        if let Some(packets) = &mut self.packets {
+            let t5 = pinfo.five_tuple;
+            let c = &mut packets.columns;
+            c.ts.push(to_nanos(packet.ts));
+            c.pcap_index.push(pinfo.pcap_index as u64);
+            c.flow_id.push(pinfo.flow.map_or(0, |f| f.flow_id));
+            c.proto.push(t5.proto);
+            c.src_ip.push(t5.src.to_string());
+            c.src_port.push(t5.src_port);
+            c.dst_ip.push(t5.dst.to_string());
+            c.dst_port.push(t5.dst_port);
+            c.to_server.push(pinfo.to_server);
+            c.origlen.push(packet.origlen);
+            c.payload_len
+                .push(pinfo.l4_payload.map_or(0, |d| d.len() as u32));
+            c.tunnel_depth.push(pinfo.tunnel_depth as u8);
+            packets.rows += 1;
+            if packets.rows >= self.batch_size {
+                let res = packets.flush();
+                self.write_errors.check(res);
+            }
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let app_proto = self.app_protos.remove(&flow.flow_id).flatten();
+        if let Some(flows) = &mut self.flows {
+            let t5 = &flow.five_tuple;
+            let c = &mut flows.columns;
+            c.flow_id.push(flow.flow_id);
+            c.proto.push(t5.proto);
+            c.src_ip.push(t5.src.to_string());
+            c.src_port.push(t5.src_port);
+            c.dst_ip.push(t5.dst.to_string());
+            c.dst_port.push(t5.dst_port);
+            c.first_seen.push(to_nanos(flow.first_seen));
+            c.last_seen.push(to_nanos(flow.last_seen));
+            c.packets_to_server.push(flow.packets_to_server);
+            c.packets_to_client.push(flow.packets_to_client());
+            c.bytes_to_server.push(flow.bytes_to_server);
+            c.bytes_to_client.push(flow.bytes_to_client());
+            c.tcp_flags.push(flow.tcp_flags);
+            c.app_proto.push(app_proto);
+            flows.rows += 1;
+            if flows.rows >= self.batch_size {
+                let res = flows.flush();
+                self.write_errors.check(res);
+            }
+        }
+    }
+
+    fn save_results(&mut self, _path: &str) -> Result<(), &'static str> {
+        self.write_errors.result()
+    }
+}
+
+/// Conversion of buffered rows to a record batch
+trait Columns: Default {
+    fn into_arrays(self) -> Vec<ArrayRef>;
+}
+
+impl<C: Columns> Output<C> {
+    fn create(config: &Config, filename: &str, schema: Schema) -> Result<Self, PluginBuilderError> {
+        let invalid =
+            |e: String| PluginBuilderError::InvalidConfig(format!("parquet: {}: {}", filename, e));
+        let file = output::create_file(output::get_output_dir(config), filename)
+            .map_err(|e| invalid(e.to_string()))?;
+        let schema = Arc::new(schema);
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(file, schema.clone(), Some(props))
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(Output {
+            writer: Mutex::new(writer),
+            schema,
+            columns: C::default(),
+            rows: 0,
+        })
+    }
+
+    /// Write buffered rows
+    fn flush(&mut self) -> Result<(), String> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let columns = std::mem::take(&mut self.columns);
+        self.rows = 0;
+        let batch = RecordBatch::try_new(self.schema.clone(), columns.into_arrays())
+            .map_err(|e| e.to_string())?;
+        let mut writer = self.writer.lock().unwrap();
+        writer.write(&batch).map_err(|e| e.to_string())
+    }
+
+    /// Write buffered rows and the file footer
+    fn close(mut self) -> Result<(), String> {
+        let res = self.flush();
+        let writer = self.writer.into_inner().unwrap();
+        res.and(writer.close().map(|_| ()).map_err(|e| e.to_string()))
+    }
+}
+
+impl Columns for PacketColumns {
+    fn into_arrays(self) -> Vec<ArrayRef> {
+        vec![
+            Arc::new(TimestampNanosecondArray::from(self.ts)),
+            Arc::new(UInt64Array::from(self.pcap_index)),
+            Arc::new(UInt64Array::from(self.flow_id)),
+            Arc::new(UInt8Array::from(self.proto)),
+            Arc::new(StringArray::from(self.src_ip)),
+            Arc::new(UInt16Array::from(self.src_port)),
+            Arc::new(StringArray::from(self.dst_ip)),
+            Arc::new(UInt16Array::from(self.dst_port)),
+            Arc::new(BooleanArray::from(self.to_server)),
+            Arc::new(UInt32Array::from(self.origlen)),
+            Arc::new(UInt32Array::from(self.payload_len)),
+            Arc::new(UInt8Array::from(self.tunnel_depth)),
+        ]
+    }
+}
+
+impl Columns for FlowColumns {
+    fn into_arrays(self) -> Vec<ArrayRef> {
+        vec![
+            Arc::new(UInt64Array::from(self.flow_id)),
+            Arc::new(UInt8Array::from(self.proto)),
+            Arc::new(StringArray::from(self.src_ip)),
+            Arc::new(UInt16Array::from(self.src_port)),
+            Arc::new(StringArray::from(self.dst_ip)),
+            Arc::new(UInt16Array::from(self.dst_port)),
+            Arc::new(TimestampNanosecondArray::from(self.first_seen)),
+            Arc::new(TimestampNanosecondArray::from(self.last_seen)),
+            Arc::new(UInt64Array::from(self.packets_to_server)),
+            Arc::new(UInt64Array::from(self.packets_to_client)),
+            Arc::new(UInt64Array::from(self.bytes_to_server)),
+            Arc::new(UInt64Array::from(self.bytes_to_client)),
+            Arc::new(UInt8Array::from(self.tcp_flags)),
+            Arc::new(StringArray::from(self.app_proto)),
+        ]
+    }
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Nanosecond, None)
+}
+
+fn packet_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("ts", timestamp_type(), false),
+        Field::new("pcap_index", DataType::UInt64, false),
+        Field::new("flow_id", DataType::UInt64, false),
+        Field::new("proto", DataType::UInt8, false),
+        Field::new("src_ip", DataType::Utf8, false),
+        Field::new("src_port", DataType::UInt16, false),
+        Field::new("dst_ip", DataType::Utf8, false),
+        Field::new("dst_port", DataType::UInt16, false),
+        Field::new("to_server", DataType::Boolean, false),
+        Field::new("origlen", DataType::UInt32, false),
+        Field::new("payload_len", DataType::UInt32, false),
+        Field::new("tunnel_depth", DataType::UInt8, false),
+    ])
+}
+
+fn flow_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("flow_id", DataType::UInt64, false),
+        Field::new("proto", DataType::UInt8, false),
+        Field::new("src_ip", DataType::Utf8, false),
+        Field::new("src_port", DataType::UInt16, false),
+        Field::new("dst_ip", DataType::Utf8, false),
+        Field::new("dst_port", DataType::UInt16, false),
+        Field::new("first_seen", timestamp_type(), false),
+        Field::new("last_seen", timestamp_type(), false),
+        Field::new("packets_to_server", DataType::UInt64, false),
+        Field::new("packets_to_client", DataType::UInt64, false),
+        Field::new("bytes_to_server", DataType::UInt64, false),
+        Field::new("bytes_to_client", DataType::UInt64, false),
+        Field::new("tcp_flags", DataType::UInt8, false),
+        Field::new("app_proto", DataType::Utf8, true),
+    ])
+}
+
+fn to_nanos(ts: Duration) -> i64 {
+    i64::from(ts.secs) * 1_000_000_000 + i64::from(ts.nanos)
+}
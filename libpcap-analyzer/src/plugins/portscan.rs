@@ -0,0 +1,404 @@
+//! Plugin detecting port scans and host sweeps
+//!
+//! Each flow created by a TCP packet without ACK (SYN, FIN, NULL or Xmas scans) or by an UDP
+//! packet is a connection attempt of its source. An attempt is completed when the destination
+//! answers (SYN+ACK for TCP, any packet for UDP).
+//!
+//! For each source, attempts are kept during a time window (`window`, in seconds, default 60).
+//! An alert is raised when, in this window, a source tried:
+//!
+//! - `min_ports` (default 20) different ports on the same host (vertical scan)
+//! - the same port on `min_hosts` (default 20) different hosts (horizontal scan, or sweep)
+//!
+//! and at least half of these attempts were not completed. Alerts are updated while the scan
+//! continues, with the number of targets and failed attempts.
+
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_FLOW_NEW, PLUGIN_L4, PLUGIN_TCP_SEGMENTS};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use fnv::{FnvHashMap, FnvHashSet};
+use libpcap_tools::{Config, Duration, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+
+const DEFAULT_WINDOW: u32 = 60;
+const DEFAULT_MIN_PORTS: usize = 20;
+const DEFAULT_MIN_HOSTS: usize = 20;
+/// Maximum number of attempts kept for a source, older attempts are dropped
+const MAX_ATTEMPTS: usize = 10_000;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+#[derive(Debug)]
+struct Attempt {
+    ts: Duration,
+    flow_id: FlowID,
+    proto: u8,
+    dst: IpAddr,
+    dst_port: u16,
+    completed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ScanType {
+    /// Many ports of one host
+    Vertical,
+    /// One port of many hosts
+    Horizontal,
+}
+
+#[derive(Debug, Serialize)]
+struct Alert {
+    #[serde(rename = "type")]
+    scan_type: ScanType,
+    src_ip: IpAddr,
+    /// Scanned host (vertical scans)
+    dst_ip: Option<IpAddr>,
+    /// Scanned port (horizontal scans)
+    dst_port: Option<u16>,
+    proto: u8,
+    first_seen: String,
+    last_seen: String,
+    /// Number of ports (vertical scans) or hosts (horizontal scans) tried in the window
+    targets: usize,
+    /// Number of targets without completed attempts
+    failed: usize,
+}
+
+pub struct Portscan {
+    window: u32,
+    min_ports: usize,
+    min_hosts: usize,
+    /// Attempts in the window, by source
+    sources: FnvHashMap<IpAddr, VecDeque<Attempt>>,
+    /// Sources of attempts not completed yet
+    pending: FnvHashMap<FlowID, IpAddr>,
+    alerts: Vec<Alert>,
+    /// Index of alerts, by source, type, protocol, and host or port
+    alert_index: FnvHashMap<(IpAddr, ScanType, u8, Option<IpAddr>, Option<u16>), usize>,
+}
+
+pub struct PortscanBuilder;
+
+impl PluginBuilder for PortscanBuilder {
+    fn name(&self) -> &'static str { "PortscanBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let plugin_config = config.plugin_config("portscan");
+        let window = plugin_config
+            .get_usize("window")
+            .map_or(DEFAULT_WINDOW, |w| w as u32);
+        let plugin = Portscan::new(
+            window,
+            plugin_config
+                .get_usize("min_ports")
+                .unwrap_or(DEFAULT_MIN_PORTS),
+            plugin_config
+                .get_usize("min_hosts")
+                .unwrap_or(DEFAULT_MIN_HOSTS),
+        );
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("portscan");
+        for key in &["window", "min_ports", "min_hosts"] {
+            if config.contains(key) && !matches!(config.get_usize(key), Some(n) if n > 0) {
+                return Err(PluginBuilderError::InvalidConfig(format!(
+                    "portscan: {} must be a positive integer",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Portscan {
+    fn name(&self) -> &'static str {
+        "Portscan"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_TCP_SEGMENTS | PLUGIN_FLOW_NEW | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        if pinfo.to_server {
+            return PluginResult::None;
+        }
+        let answered = match pinfo.l4_type {
+            6 => pinfo.l4_data.get(13).map_or(false, |&flags| {
+                flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK
+            }),
+            17 => true,
+            _ => false,
+        };
+        if answered {
+            if let Some(flow) = pinfo.flow {
+                self.completed(flow.flow_id);
+            }
+        }
+        PluginResult::None
+    }
+
+    fn flow_created(&mut self, flow: &Flow) {
+        let t5 = &flow.five_tuple;
+        let attempt = match t5.proto {
+            6 => flow.tcp_flags & TCP_ACK == 0,
+            17 => true,
+            _ => false,
+        };
+        if attempt {
+            self.add_attempt(
+                t5.src,
+                Attempt {
+                    ts: flow.first_seen,
+                    flow_id: flow.flow_id,
+                    proto: t5.proto,
+                    dst: t5.dst,
+                    dst_port: t5.dst_port,
+                    completed: false,
+                },
+            );
+        }
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        self.pending.remove(&flow.flow_id);
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "portscan.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Portscan {
+    fn new(window: u32, min_ports: usize, min_hosts: usize) -> Self {
+        Portscan {
+            window,
+            min_ports,
+            min_hosts,
+            sources: FnvHashMap::default(),
+            pending: FnvHashMap::default(),
+            alerts: Vec::new(),
+            alert_index: FnvHashMap::default(),
+        }
+    }
+
+    fn completed(&mut self, flow_id: FlowID) {
+        if let Some(src) = self.pending.remove(&flow_id) {
+            if let Some(attempts) = self.sources.get_mut(&src) {
+                if let Some(attempt) = attempts.iter_mut().rev().find(|a| a.flow_id == flow_id) {
+                    attempt.completed = true;
+                }
+            }
+        }
+    }
+
+    fn add_attempt(&mut self, src: IpAddr, attempt: Attempt) {
+        let attempts = self.sources.entry(src).or_default();
+        // remove attempts older than the window
+        let start = attempt.ts.secs.saturating_sub(self.window);
+        while let Some(old) = attempts.front() {
+            if old.ts.secs >= start && attempts.len() < MAX_ATTEMPTS {
+                break;
+            }
+            self.pending.remove(&old.flow_id);
+            attempts.pop_front();
+        }
+        self.pending.insert(attempt.flow_id, src);
+        let (ts, proto, dst, dst_port) = (attempt.ts, attempt.proto, attempt.dst, attempt.dst_port);
+        attempts.push_back(attempt);
+
+        // ports of the destination host, and hosts of the destination port
+        let (ports, failed_ports) = targets(
+            attempts.iter().filter(|a| a.proto == proto && a.dst == dst),
+            |a| (a.dst, a.dst_port),
+        );
+        let (hosts, failed_hosts) = targets(
+            attempts
+                .iter()
+                .filter(|a| a.proto == proto && a.dst_port == dst_port),
+            |a| (a.dst, 0),
+        );
+        let first_seen = |vertical: bool| {
+            attempts
+                .iter()
+                .filter(|a| a.proto == proto)
+                .find(|a| {
+                    if vertical {
+                        a.dst == dst
+                    } else {
+                        a.dst_port == dst_port
+                    }
+                })
+                .map_or(ts, |a| a.ts)
+        };
+        let ts_string = |ts: Duration| format!("{}.{:09}", ts.secs, ts.nanos);
+        let mut alerts = Vec::new();
+        if ports >= self.min_ports && failed_ports * 2 >= ports {
+            alerts.push(Alert {
+                scan_type: ScanType::Vertical,
+                src_ip: src,
+                dst_ip: Some(dst),
+                dst_port: None,
+                proto,
+                first_seen: ts_string(first_seen(true)),
+                last_seen: ts_string(ts),
+                targets: ports,
+                failed: failed_ports,
+            });
+        }
+        if hosts >= self.min_hosts && failed_hosts * 2 >= hosts {
+            alerts.push(Alert {
+                scan_type: ScanType::Horizontal,
+                src_ip: src,
+                dst_ip: None,
+                dst_port: Some(dst_port),
+                proto,
+                first_seen: ts_string(first_seen(false)),
+                last_seen: ts_string(ts),
+                targets: hosts,
+                failed: failed_hosts,
+            });
+        }
+        for alert in alerts {
+            self.alert(alert);
+        }
+    }
+
+    /// Raise an alert, or update it if the scan was already reported
+    fn alert(&mut self, alert: Alert) {
+        let key = (
+            alert.src_ip,
+            alert.scan_type,
+            alert.proto,
+            alert.dst_ip,
+            alert.dst_port,
+        );
+        if let Some(&idx) = self.alert_index.get(&key) {
+            let previous = &mut self.alerts[idx];
+            previous.last_seen = alert.last_seen;
+            previous.targets = previous.targets.max(alert.targets);
+            previous.failed = previous.failed.max(alert.failed);
+            return;
+        }
+        warn!(
+            "portscan: {:?} scan from {} ({} targets, {} failed)",
+            alert.scan_type, alert.src_ip, alert.targets, alert.failed
+        );
+        self.alert_index.insert(key, self.alerts.len());
+        self.alerts.push(alert);
+    }
+
+    fn get_results_json(&self) -> Value {
+        json!({
+            "portscan": {
+                "alerts": self.alerts,
+            }
+        })
+    }
+}
+
+/// Count the distinct targets of attempts, and the targets without completed attempts
+fn targets<'a, I, F>(attempts: I, target: F) -> (usize, usize)
+where
+    I: Iterator<Item = &'a Attempt>,
+    F: Fn(&Attempt) -> (IpAddr, u16),
+{
+    let mut all = FnvHashSet::default();
+    let mut completed = FnvHashSet::default();
+    for attempt in attempts {
+        all.insert(target(attempt));
+        if attempt.completed {
+            completed.insert(target(attempt));
+        }
+    }
+    (all.len(), all.len() - completed.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Portscan;
+    use crate::plugin::Plugin;
+    use libpcap_tools::{FiveTuple, Flow};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn syn_flow(flow_id: u64, dst: u8, dst_port: u16, ts: u32) -> Flow {
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 1, dst)),
+            src_port: 40000,
+            dst_port,
+        };
+        let mut flow = Flow::new(&five_tuple, ts, 0);
+        flow.flow_id = flow_id;
+        flow.tcp_flags = 0x02;
+        flow
+    }
+
+    #[test]
+    fn portscan_detection() {
+        let mut plugin = Portscan::new(60, 5, 5);
+        // vertical scan of 10.0.1.1, one port is open
+        for port in 1..=6 {
+            plugin.flow_created(&syn_flow(u64::from(port), 1, port, 100));
+            if port == 3 {
+                plugin.completed(u64::from(port));
+            }
+        }
+        // sweep of port 22, too slow to be detected
+        for host in 2..=6 {
+            plugin.flow_created(&syn_flow(
+                100 + u64::from(host),
+                host,
+                22,
+                200 * u32::from(host),
+            ));
+        }
+        // connections to a web server
+        for i in 0..10 {
+            plugin.flow_created(&syn_flow(1000 + i, 7, 443, 2000));
+            plugin.completed(1000 + i);
+        }
+        let results = plugin.get_results_json();
+        let alerts = results["portscan"]["alerts"].as_array().expect("no alerts");
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0]["type"], "vertical");
+        assert_eq!(alerts[0]["dst_ip"], "10.0.1.1");
+        assert_eq!(alerts[0]["targets"], 6);
+        assert_eq!(alerts[0]["failed"], 5);
+        assert_eq!(alerts[0]["first_seen"], "100.000000000");
+    }
+}
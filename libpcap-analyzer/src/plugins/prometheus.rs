@@ -0,0 +1,364 @@
+//! Plugin exposing counters of the analysis as Prometheus metrics
+//!
+//! The plugin is enabled by setting `listen` (for ex. `127.0.0.1:9184`) in section
+//! `[plugin.prometheus]`. Metrics are served over HTTP on `/metrics`, using the Prometheus text
+//! format, by a thread started with the plugin. This is mostly useful for long-running analyses
+//! (live capture, or large inputs), since the listener stops when the program exits.
+//!
+//! Exposed metrics:
+//!
+//! - `pcap_analyzer_packets_total`: number of layer 3 packets
+//! - `pcap_analyzer_packets_per_second`: packet rate since the previous scrape
+//! - `pcap_analyzer_bytes_total{proto}`: layer 3 payload bytes, by layer 4 protocol
+//! - `pcap_analyzer_app_bytes_total{app_proto}`: layer 4 payload bytes, by application protocol
+//!   (see `detect_app_proto`)
+//! - `pcap_analyzer_parse_errors_total{proto}`: truncated or invalid TCP and UDP headers
+//! - `pcap_analyzer_flows_active`: number of flows not yet destroyed
+//! - `pcap_analyzer_flows_total`: number of created flows
+
+use crate::app_proto::detect_app_proto;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_FLOW_NEW, PLUGIN_L3, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Flow, FlowID, Packet, ThreeTuple};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Application protocols with a byte counter (other protocols are counted as `unknown`)
+const APP_PROTOS: &[&str] = &["dns", "http", "ssh", "tls", "unknown"];
+
+/// Counters shared by the plugin and the HTTP listener
+struct Counters {
+    packets: AtomicU64,
+    /// Bytes by layer 4 protocol number
+    bytes: Vec<AtomicU64>,
+    /// Bytes by application protocol, in the order of `APP_PROTOS`
+    app_bytes: Vec<AtomicU64>,
+    /// Parse errors by layer 4 protocol number
+    parse_errors: Vec<AtomicU64>,
+    flows_active: AtomicI64,
+    flows_total: AtomicU64,
+}
+
+impl Counters {
+    fn new() -> Self {
+        let counters = |n: usize| -> Vec<AtomicU64> { (0..n).map(|_| AtomicU64::new(0)).collect() };
+        Counters {
+            packets: AtomicU64::new(0),
+            bytes: counters(256),
+            app_bytes: counters(APP_PROTOS.len()),
+            parse_errors: counters(256),
+            flows_active: AtomicI64::new(0),
+            flows_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Write all metrics, using the Prometheus text format
+    fn render(&self, packets_per_sec: f64) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let value = |v: &AtomicU64| v.load(Ordering::Relaxed).to_string();
+        // counters by protocol number, only for protocols seen
+        let by_proto = |v: &[AtomicU64]| -> Vec<(String, String)> {
+            v.iter()
+                .enumerate()
+                .filter(|(_, c)| c.load(Ordering::Relaxed) > 0)
+                .map(|(p, c)| (format!("{{proto=\"{}\"}}", proto_name(p as u8)), value(c)))
+                .collect()
+        };
+        metric(
+            "pcap_analyzer_packets_total",
+            "counter",
+            "Number of layer 3 packets",
+            &[(String::new(), value(&self.packets))],
+        );
+        metric(
+            "pcap_analyzer_packets_per_second",
+            "gauge",
+            "Packet rate since the previous scrape",
+            &[(String::new(), format!("{:.3}", packets_per_sec))],
+        );
+        metric(
+            "pcap_analyzer_bytes_total",
+            "counter",
+            "Layer 3 payload bytes, by layer 4 protocol",
+            &by_proto(&self.bytes),
+        );
+        let app_bytes: Vec<_> = APP_PROTOS
+            .iter()
+            .zip(&self.app_bytes)
+            .map(|(p, c)| (format!("{{app_proto=\"{}\"}}", p), value(c)))
+            .collect();
+        metric(
+            "pcap_analyzer_app_bytes_total",
+            "counter",
+            "Layer 4 payload bytes, by application protocol",
+            &app_bytes,
+        );
+        metric(
+            "pcap_analyzer_parse_errors_total",
+            "counter",
+            "Truncated or invalid transport headers",
+            &by_proto(&self.parse_errors),
+        );
+        let flows_active = self.flows_active.load(Ordering::Relaxed).max(0);
+        metric(
+            "pcap_analyzer_flows_active",
+            "gauge",
+            "Number of flows not yet destroyed",
+            &[(String::new(), flows_active.to_string())],
+        );
+        metric(
+            "pcap_analyzer_flows_total",
+            "counter",
+            "Number of created flows",
+            &[(String::new(), value(&self.flows_total))],
+        );
+        out
+    }
+}
+
+pub struct Prometheus {
+    counters: Arc<Counters>,
+    /// Detected application protocol, for flows with payload
+    app_protos: FnvHashMap<FlowID, Option<&'static str>>,
+}
+
+pub struct PrometheusBuilder;
+
+impl PluginBuilder for PrometheusBuilder {
+    fn name(&self) -> &'static str { "PrometheusBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let listen = match config.plugin_config("prometheus").get("listen") {
+            Some(listen) => listen.to_owned(),
+            None => {
+                debug!("prometheus: no listen address configured");
+                return Ok(());
+            }
+        };
+        let listener = TcpListener::bind(&listen).map_err(|e| {
+            PluginBuilderError::InvalidConfig(format!(
+                "prometheus: cannot listen on {}: {}",
+                listen, e
+            ))
+        })?;
+        let counters = Arc::new(Counters::new());
+        let server_counters = counters.clone();
+        thread::Builder::new()
+            .name("prometheus".to_owned())
+            .spawn(move || serve(listener, &server_counters))
+            .map_err(|e| {
+                PluginBuilderError::InvalidConfig(format!(
+                    "prometheus: cannot start listener: {}",
+                    e
+                ))
+            })?;
+        info!("prometheus: serving metrics on http://{}/metrics", listen);
+        let plugin = Prometheus {
+            counters,
+            app_protos: FnvHashMap::default(),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(3, 0, id)?;
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("prometheus");
+        if config.contains("listen") && config.get("listen").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "prometheus: listen must be a string".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Prometheus {
+    fn name(&self) -> &'static str {
+        "Prometheus"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L3 | PLUGIN_L4 | PLUGIN_FLOW_NEW | PLUGIN_FLOW_DEL
+    }
+
+    fn handle_layer_network<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        payload: &'i [u8],
+        t3: &'s ThreeTuple,
+    ) -> PluginResult<'i> {
+        let proto = usize::from(t3.l4_proto);
+        let counters = &self.counters;
+        counters.packets.fetch_add(1, Ordering::Relaxed);
+        counters.bytes[proto].fetch_add(payload.len() as u64, Ordering::Relaxed);
+        if !valid_transport_header(t3.l4_proto, payload) {
+            counters.parse_errors[proto].fetch_add(1, Ordering::Relaxed);
+        }
+        PluginResult::None
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if !data.is_empty() => (flow, data),
+            _ => return PluginResult::None,
+        };
+        let app_proto = *self
+            .app_protos
+            .entry(flow.flow_id)
+            .or_insert_with(|| detect_app_proto(pinfo.five_tuple, data));
+        let idx = app_proto
+            .and_then(|p| APP_PROTOS.iter().position(|&a| a == p))
+            .unwrap_or(APP_PROTOS.len() - 1);
+        self.counters.app_bytes[idx].fetch_add(data.len() as u64, Ordering::Relaxed);
+        PluginResult::None
+    }
+
+    fn flow_created(&mut self, _flow: &Flow) {
+        self.counters.flows_active.fetch_add(1, Ordering::Relaxed);
+        self.counters.flows_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        self.app_protos.remove(&flow.flow_id);
+        self.counters.flows_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Check the length of TCP and UDP headers (other protocols are not checked)
+fn valid_transport_header(l4_proto: u8, data: &[u8]) -> bool {
+    match l4_proto {
+        6 => {
+            data.len() >= 20
+                && usize::from(data[12] >> 4) * 4 >= 20
+                && usize::from(data[12] >> 4) * 4 <= data.len()
+        }
+        17 => data.len() >= 8,
+        _ => true,
+    }
+}
+
+fn proto_name(proto: u8) -> String {
+    match proto {
+        1 => "icmp".to_owned(),
+        6 => "tcp".to_owned(),
+        17 => "udp".to_owned(),
+        47 => "gre".to_owned(),
+        50 => "esp".to_owned(),
+        58 => "icmpv6".to_owned(),
+        132 => "sctp".to_owned(),
+        p => p.to_string(),
+    }
+}
+
+/// Answer HTTP requests, until the program exits
+fn serve(listener: TcpListener, counters: &Counters) {
+    let mut last_scrape = (Instant::now(), 0);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("prometheus: could not accept connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = handle_request(stream, counters, &mut last_scrape) {
+            debug!("prometheus: error while answering request: {}", e);
+        }
+    }
+}
+
+fn handle_request(
+    mut stream: TcpStream,
+    counters: &Counters,
+    last_scrape: &mut (Instant, u64),
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // read the request header (the body, if any, is ignored)
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut fields = request_line.split(|&b| b == b' ');
+    let (status, body) = match (fields.next(), fields.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            let now = Instant::now();
+            let packets = counters.packets.load(Ordering::Relaxed);
+            let elapsed = now.duration_since(last_scrape.0).as_secs_f64();
+            let rate = if elapsed > 0.0 {
+                (packets - last_scrape.1) as f64 / elapsed
+            } else {
+                0.0
+            };
+            *last_scrape = (now, packets);
+            ("200 OK", counters.render(rate))
+        }
+        (Some(b"GET"), _) => ("404 Not Found", "Not Found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{valid_transport_header, Counters};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn prometheus_render() {
+        let counters = Counters::new();
+        counters.packets.store(3, Ordering::Relaxed);
+        counters.bytes[6].store(120, Ordering::Relaxed);
+        counters.bytes[200].store(10, Ordering::Relaxed);
+        counters.flows_active.store(2, Ordering::Relaxed);
+        let out = counters.render(1.5);
+        assert!(out.contains("# TYPE pcap_analyzer_packets_total counter\n"));
+        assert!(out.contains("\npcap_analyzer_packets_total 3\n"));
+        assert!(out.contains("\npcap_analyzer_packets_per_second 1.500\n"));
+        assert!(out.contains("\npcap_analyzer_bytes_total{proto=\"tcp\"} 120\n"));
+        assert!(out.contains("\npcap_analyzer_bytes_total{proto=\"200\"} 10\n"));
+        assert!(!out.contains("proto=\"udp\""));
+        assert!(out.contains("\npcap_analyzer_app_bytes_total{app_proto=\"unknown\"} 0\n"));
+        assert!(out.contains("\npcap_analyzer_flows_active 2\n"));
+        // TCP header with a data offset of 5 (20 bytes)
+        let mut tcp = [0u8; 20];
+        tcp[12] = 0x50;
+        assert!(valid_transport_header(6, &tcp));
+        assert!(!valid_transport_header(6, &tcp[..10]));
+        tcp[12] = 0x60;
+        assert!(!valid_transport_header(6, &tcp));
+        assert!(!valid_transport_header(17, &[0; 4]));
+    }
+}
@@ -0,0 +1,538 @@
+//! Plugin analyzing QUIC connections (and HTTP/3, using the ALPN)
+//!
+//! Initial packets sent by clients are decrypted using the initial secrets (RFC 9001 section 5.2),
+//! which only depend on the destination connection ID chosen by the client. The ClientHello is
+//! reassembled from the CRYPTO frames, to get the SNI, ALPN (`h3` for HTTP/3) and JA4
+//! fingerprint. QUIC versions 1 and 2 are supported.
+//!
+//! Connections are identified by their connection IDs, so a connection migrating to another
+//! five-tuple (for ex. after a NAT rebinding) is recognized if the first packet of the new flow
+//! uses a connection ID already seen. Connection IDs negotiated in encrypted packets
+//! (`NEW_CONNECTION_ID` frames) cannot be tracked.
+
+use super::tls_metadata::ClientHello;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt};
+use aes::Aes128;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Nonce};
+use fnv::FnvHashMap;
+use hkdf::Hkdf;
+use libpcap_tools::{FiveTuple, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use tls_parser::{parse_tls_message_handshake, TlsMessage, TlsMessageHandshake};
+
+const QUIC_V1: u32 = 0x0000_0001;
+const QUIC_V2: u32 = 0x6b33_43cf;
+
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+const INITIAL_SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb,
+    0xf9, 0xbd, 0x2e, 0xd9,
+];
+
+/// Maximum size of the reassembled CRYPTO stream of a connection
+const MAX_CRYPTO_SIZE: usize = 65536;
+
+/// Packet types of long headers (with the values of QUIC version 1)
+const PACKET_INITIAL: u8 = 0;
+const PACKET_RETRY: u8 = 3;
+
+/// Keys protecting Initial packets
+struct InitialKeys {
+    key: Vec<u8>,
+    iv: Vec<u8>,
+    hp: Vec<u8>,
+}
+
+/// A QUIC connection, possibly spanning several flows
+#[derive(Serialize)]
+struct Connection {
+    version: String,
+    /// Destination connection ID of the first Initial packet of the client
+    original_dcid: String,
+    client_cid: String,
+    server_cid: Option<String>,
+    sni: Option<String>,
+    alpn: Vec<String>,
+    ja4: Option<String>,
+    /// Flows of the connection, the first one is the flow where the connection started
+    flows: Vec<ConnectionFlow>,
+    packets: u64,
+    bytes: u64,
+    #[serde(skip)]
+    state: ConnectionState,
+}
+
+#[derive(Serialize)]
+struct ConnectionFlow {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    version: u32,
+    /// Keys of the Initial packets of the client (`None` if the version is not supported)
+    keys: Option<InitialKeys>,
+    /// CRYPTO frames of the client, by offset
+    crypto: BTreeMap<u64, Vec<u8>>,
+    crypto_len: usize,
+    /// The ClientHello was parsed, or cannot be parsed
+    done: bool,
+}
+
+#[derive(Default)]
+pub struct Quic {
+    connections: Vec<Connection>,
+    /// Connection of each connection ID
+    cids: FnvHashMap<Vec<u8>, usize>,
+    /// Lengths of the known connection IDs (short headers do not contain the length)
+    cid_lens: Vec<usize>,
+    /// Connection of flows, `None` if the flow is not QUIC
+    flows: FnvHashMap<FlowID, Option<usize>>,
+}
+
+plugin_builder!(Quic, QuicBuilder);
+
+impl Plugin for Quic {
+    fn name(&self) -> &'static str {
+        "Quic"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if pinfo.five_tuple.proto == 17 && !data.is_empty() => {
+                (flow, data)
+            }
+            _ => return PluginResult::None,
+        };
+        let conn = match self.flows.get(&flow.flow_id) {
+            Some(conn) => *conn,
+            None => {
+                let conn = self.identify(data, pinfo.to_server);
+                if let Some(idx) = conn {
+                    self.connections[idx].flows.push(ConnectionFlow {
+                        flow_id: flow.flow_id,
+                        five_tuple: flow.five_tuple.clone(),
+                    });
+                }
+                self.flows.insert(flow.flow_id, conn);
+                conn
+            }
+        };
+        if let Some(idx) = conn {
+            self.handle_datagram(idx, data, pinfo.to_server);
+        }
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "quic.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Quic {
+    fn get_results_json(&mut self) -> Value {
+        json!({ "quic-connections": self.connections })
+    }
+
+    /// Find the connection of the first packet of a flow, or create it for a new connection
+    fn identify(&mut self, data: &[u8], to_server: bool) -> Option<usize> {
+        if data[0] & 0x80 == 0 {
+            // short header: the connection ID length is not known
+            if data[0] & 0x40 == 0 {
+                return None;
+            }
+            return self
+                .cid_lens
+                .iter()
+                .find_map(|&len| self.cids.get(data.get(1..1 + len)?).copied());
+        }
+        let hdr = parse_long_header(data)?;
+        if let Some(&idx) = self.cids.get(hdr.dcid) {
+            return Some(idx);
+        }
+        if !to_server || hdr.packet_type != PACKET_INITIAL || hdr.dcid.len() < 8 {
+            return None;
+        }
+        let keys = initial_keys(hdr.version, hdr.dcid, false);
+        if keys.is_none() {
+            debug!("quic: unsupported version 0x{:08x}", hdr.version);
+        }
+        let idx = self.connections.len();
+        self.connections.push(Connection {
+            version: format!("0x{:08x}", hdr.version),
+            original_dcid: hex(hdr.dcid),
+            client_cid: hex(hdr.scid),
+            server_cid: None,
+            sni: None,
+            alpn: Vec::new(),
+            ja4: None,
+            flows: Vec::new(),
+            packets: 0,
+            bytes: 0,
+            state: ConnectionState {
+                version: hdr.version,
+                done: keys.is_none(),
+                keys,
+                ..ConnectionState::default()
+            },
+        });
+        self.add_cid(hdr.dcid, idx);
+        self.add_cid(hdr.scid, idx);
+        Some(idx)
+    }
+
+    fn add_cid(&mut self, cid: &[u8], idx: usize) {
+        if cid.is_empty() {
+            return;
+        }
+        self.cids.insert(cid.to_vec(), idx);
+        if !self.cid_lens.contains(&cid.len()) {
+            self.cid_lens.push(cid.len());
+        }
+    }
+
+    fn handle_datagram(&mut self, idx: usize, data: &[u8], to_server: bool) {
+        let conn = &mut self.connections[idx];
+        conn.packets += 1;
+        conn.bytes += data.len() as u64;
+        let mut new_cids = Vec::new();
+        // a datagram can contain several coalesced packets with long headers
+        let mut data = data;
+        while let Some(hdr) = parse_long_header(data) {
+            let packet = &data[..hdr.len];
+            if to_server {
+                if hdr.packet_type == PACKET_INITIAL && !conn.state.done {
+                    conn.handle_client_initial(packet, &hdr);
+                }
+            } else if hdr.packet_type == PACKET_RETRY {
+                // the client restarts with the connection ID chosen by the server
+                conn.state.keys = initial_keys(hdr.version, hdr.scid, false);
+                new_cids.push(hdr.scid);
+            } else if conn.server_cid.is_none() {
+                conn.server_cid = Some(hex(hdr.scid));
+                new_cids.push(hdr.scid);
+            }
+            data = &data[hdr.len..];
+        }
+        for cid in new_cids {
+            self.add_cid(cid, idx);
+        }
+    }
+}
+
+impl Connection {
+    fn handle_client_initial(&mut self, packet: &[u8], hdr: &LongHeader) {
+        let payload = match &self.state.keys {
+            Some(keys) => decrypt_initial(packet, hdr.pn_offset, keys),
+            None => None,
+        };
+        let payload = match payload {
+            Some(payload) => payload,
+            None => {
+                debug!("quic: could not decrypt Initial packet");
+                return;
+            }
+        };
+        let state = &mut self.state;
+        for (offset, data) in crypto_frames(&payload) {
+            if state.crypto_len + data.len() > MAX_CRYPTO_SIZE {
+                state.done = true;
+                return;
+            }
+            state.crypto_len += data.len();
+            state.crypto.insert(offset, data.to_vec());
+        }
+        // reassemble the contiguous part of the stream
+        let mut stream: Vec<u8> = Vec::new();
+        for (&offset, data) in &state.crypto {
+            let offset = offset as usize;
+            if offset > stream.len() {
+                break;
+            }
+            if offset + data.len() > stream.len() {
+                stream.extend_from_slice(&data[stream.len() - offset..]);
+            }
+        }
+        if stream.len() < 4 {
+            return;
+        }
+        let len =
+            (usize::from(stream[1]) << 16) | (usize::from(stream[2]) << 8) | usize::from(stream[3]);
+        if stream.len() < 4 + len {
+            return;
+        }
+        state.done = true;
+        state.crypto = BTreeMap::new();
+        if let Ok((_, TlsMessage::Handshake(TlsMessageHandshake::ClientHello(ch)))) =
+            parse_tls_message_handshake(&stream[..4 + len])
+        {
+            let ciphers = ch.ciphers.iter().map(|c| c.0).collect();
+            let hello = ClientHello::new(ch.version.0, ciphers, ch.ext.unwrap_or_default());
+            self.ja4 = Some(hello.ja4('q'));
+            self.sni = hello.sni;
+            self.alpn = hello.alpn;
+        }
+    }
+}
+
+/// Long header of a QUIC packet
+struct LongHeader<'a> {
+    version: u32,
+    /// Packet type, using the values of QUIC version 1
+    packet_type: u8,
+    dcid: &'a [u8],
+    scid: &'a [u8],
+    /// Offset of the packet number
+    pn_offset: usize,
+    /// Length of the packet, including the header
+    len: usize,
+}
+
+fn parse_long_header(data: &[u8]) -> Option<LongHeader> {
+    // header form and fixed bits
+    if data.first()? & 0xc0 != 0xc0 {
+        return None;
+    }
+    let version = u32::from_be_bytes([*data.get(1)?, *data.get(2)?, *data.get(3)?, *data.get(4)?]);
+    let packet_type = match version {
+        QUIC_V1 => (data[0] >> 4) & 0x03,
+        // version 2 uses different values (RFC 9369 section 3.2)
+        QUIC_V2 => ((data[0] >> 4) + 3) & 0x03,
+        _ => return None,
+    };
+    let dcid_len = usize::from(*data.get(5)?);
+    let dcid = data.get(6..6 + dcid_len)?;
+    let scid_len = usize::from(*data.get(6 + dcid_len)?);
+    let mut offset = 7 + dcid_len;
+    let scid = data.get(offset..offset + scid_len)?;
+    offset += scid_len;
+    if packet_type == PACKET_RETRY {
+        return Some(LongHeader {
+            version,
+            packet_type,
+            dcid,
+            scid,
+            pn_offset: 0,
+            len: data.len(),
+        });
+    }
+    if packet_type == PACKET_INITIAL {
+        let (token_len, n) = varint(&data[offset..])?;
+        offset += n + usize::try_from(token_len).ok()?;
+    }
+    let (length, n) = varint(data.get(offset..)?)?;
+    let pn_offset = offset + n;
+    let len = pn_offset + usize::try_from(length).ok()?;
+    if len > data.len() {
+        return None;
+    }
+    Some(LongHeader {
+        version,
+        packet_type,
+        dcid,
+        scid,
+        pn_offset,
+        len,
+    })
+}
+
+/// Read a variable-length integer, and return the value and its size
+fn varint(data: &[u8]) -> Option<(u64, usize)> {
+    let len = 1 << (data.first()? >> 6);
+    let bytes = data.get(..len)?;
+    let value = bytes
+        .iter()
+        .skip(1)
+        .fold(u64::from(bytes[0] & 0x3f), |v, &b| (v << 8) | u64::from(b));
+    Some((value, len))
+}
+
+/// HKDF-Expand-Label function of TLS 1.3, with an empty context
+fn hkdf_expand_label(secret: &[u8], label: &str, len: usize) -> Option<Vec<u8>> {
+    let hk = Hkdf::<Sha256>::from_prk(secret).ok()?;
+    let label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(4 + label.len());
+    info.extend_from_slice(&(len as u16).to_be_bytes());
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+    let mut okm = vec![0; len];
+    hk.expand(&info, &mut okm).ok()?;
+    Some(okm)
+}
+
+/// Derive the keys of Initial packets, from the destination connection ID chosen by the client
+fn initial_keys(version: u32, dcid: &[u8], server: bool) -> Option<InitialKeys> {
+    let (salt, prefix) = match version {
+        QUIC_V1 => (&INITIAL_SALT_V1, "quic"),
+        QUIC_V2 => (&INITIAL_SALT_V2, "quicv2"),
+        _ => return None,
+    };
+    let (initial_secret, _) = Hkdf::<Sha256>::extract(Some(&salt[..]), dcid);
+    let label = if server { "server in" } else { "client in" };
+    let secret = hkdf_expand_label(&initial_secret, label, 32)?;
+    Some(InitialKeys {
+        key: hkdf_expand_label(&secret, &format!("{} key", prefix), 16)?,
+        iv: hkdf_expand_label(&secret, &format!("{} iv", prefix), 12)?,
+        hp: hkdf_expand_label(&secret, &format!("{} hp", prefix), 16)?,
+    })
+}
+
+/// Remove the header protection and decrypt an Initial packet, and return the payload
+///
+/// Packet numbers are not reconstructed from previous packets, which is not needed for the first
+/// packets of a connection.
+fn decrypt_initial(packet: &[u8], pn_offset: usize, keys: &InitialKeys) -> Option<Vec<u8>> {
+    let sample = packet.get(pn_offset + 4..pn_offset + 20)?;
+    let mut mask = GenericArray::clone_from_slice(sample);
+    Aes128::new(GenericArray::from_slice(&keys.hp)).encrypt_block(&mut mask);
+    let mut header = packet[..pn_offset + 4].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    let pn_len = usize::from(header[0] & 0x03) + 1;
+    header.truncate(pn_offset + pn_len);
+    let mut nonce = keys.iv.clone();
+    for i in 0..pn_len {
+        header[pn_offset + i] ^= mask[1 + i];
+        // the packet number is XORed with the end of the IV
+        nonce[12 - pn_len + i] ^= header[pn_offset + i];
+    }
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(&keys.key));
+    let payload = Payload {
+        msg: &packet[pn_offset + pn_len..],
+        aad: &header,
+    };
+    cipher.decrypt(Nonce::from_slice(&nonce), payload).ok()
+}
+
+/// Get the CRYPTO frames of a decrypted Initial packet, as (offset, data) pairs
+fn crypto_frames(payload: &[u8]) -> Vec<(u64, &[u8])> {
+    fn parse<'a>(mut i: &'a [u8], frames: &mut Vec<(u64, &'a [u8])>) -> Option<()> {
+        let next = |i: &mut &'a [u8]| -> Option<u64> {
+            let s: &'a [u8] = *i;
+            let (v, n) = varint(s)?;
+            *i = &s[n..];
+            Some(v)
+        };
+        while !i.is_empty() {
+            match next(&mut i)? {
+                // PADDING, PING
+                0x00 | 0x01 => (),
+                // ACK: largest acknowledged, delay, ranges, and ECN counts
+                t @ 0x02..=0x03 => {
+                    next(&mut i)?;
+                    next(&mut i)?;
+                    let ranges = next(&mut i)?;
+                    next(&mut i)?;
+                    for _ in 0..ranges {
+                        next(&mut i)?;
+                        next(&mut i)?;
+                    }
+                    if t == 0x03 {
+                        for _ in 0..3 {
+                            next(&mut i)?;
+                        }
+                    }
+                }
+                // CRYPTO
+                0x06 => {
+                    let offset = next(&mut i)?;
+                    let len = usize::try_from(next(&mut i)?).ok()?;
+                    frames.push((offset, i.get(..len)?));
+                    i = &i[len..];
+                }
+                // CONNECTION_CLOSE: error code, frame type, reason
+                0x1c => {
+                    next(&mut i)?;
+                    next(&mut i)?;
+                    let len = usize::try_from(next(&mut i)?).ok()?;
+                    i = i.get(len..)?;
+                }
+                // other frames are not allowed in Initial packets
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+    let mut frames = Vec::new();
+    parse(payload, &mut frames);
+    frames
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crypto_frames, hex, initial_keys, varint, QUIC_V1};
+
+    #[test]
+    fn quic_initial_keys() {
+        // RFC 9001 appendix A.1
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let keys = initial_keys(QUIC_V1, &dcid, false).expect("initial keys");
+        assert_eq!(hex(&keys.key), "1f369613dd76d5467730efcbe3b1a22d");
+        assert_eq!(hex(&keys.iv), "fa044b2f42a3fd3b46fb255c");
+        assert_eq!(hex(&keys.hp), "9f50449e04a0e810283a1e9933adedd2");
+        let keys = initial_keys(QUIC_V1, &dcid, true).expect("initial keys");
+        assert_eq!(hex(&keys.key), "cf3a5331653c364c88f0f379b6067e37");
+        assert!(initial_keys(0xff00_001d, &dcid, false).is_none());
+    }
+
+    #[test]
+    fn quic_frames() {
+        // RFC 9000 appendix A.1
+        assert_eq!(varint(&[0x25]), Some((37, 1)));
+        assert_eq!(varint(&[0x7b, 0xbd]), Some((15293, 2)));
+        assert_eq!(varint(&[0x9d, 0x7f, 0x3e, 0x7d]), Some((494_878_333, 4)));
+        assert_eq!(varint(&[0x7b]), None);
+        // PING, CRYPTO (offset 0, 3 bytes), ACK, CRYPTO (offset 3, 2 bytes), PADDING
+        let payload = [
+            0x01, 0x06, 0x00, 0x03, 0xaa, 0xbb, 0xcc, 0x02, 0x05, 0x00, 0x00, 0x00, 0x06, 0x03,
+            0x02, 0xdd, 0xee, 0x00, 0x00,
+        ];
+        let frames = crypto_frames(&payload);
+        assert_eq!(
+            frames,
+            vec![(0, &[0xaa, 0xbb, 0xcc][..]), (3, &[0xdd, 0xee][..])]
+        );
+    }
+}
@@ -1,11 +1,13 @@
-use crate::default_plugin_builder;
 use crate::packet_info::PacketInfo;
-use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
 use crate::output;
 use fnv::{FnvHashMap, FnvHashSet};
-use libpcap_tools::{Flow, FlowID, Packet};
+use libpcap_tools::{Config, Duration, FiveTuple, Flow, FlowDirection, FlowID, Packet};
 use rusticata::prologue::*;
-use serde_json::{Map, Value};
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::any::Any;
 use std::collections::HashMap;
 
@@ -15,6 +17,19 @@ use to_json_ext::ToJsonExt;
 const PROBE_TCP: u32 = 0x0600_0000;
 const PROBE_UDP: u32 = 0x1100_0000;
 
+/// Default maximum number of parsers (flows being parsed)
+const DEFAULT_MAX_FLOWS: usize = 100_000;
+/// Default maximum number of bytes given to a parser
+const DEFAULT_MAX_BYTES: usize = 1 << 20;
+/// Default delay (in seconds) after which a parser without data is evicted
+const DEFAULT_IDLE_TIMEOUT: u32 = 600;
+/// Interval (in seconds) between two checks of idle parsers
+const IDLE_CHECK_INTERVAL: u32 = 10;
+/// Number of parse errors after which the parser of a flow is dropped
+const MAX_PARSE_ERRORS: u32 = 8;
+/// Default number of payloads probed before giving up on a flow
+const DEFAULT_MAX_PROBES: usize = 1;
+
 // This enum defines the order TCP probes will be applied
 #[repr(u16)]
 enum TcpProbeOrder {
@@ -49,19 +64,330 @@ enum UdpProbeOrder {
 // (filter, (name, probe))
 type ProbeDef = (u32, (&'static str, ProbeL4));
 
+/// Names of all parsers (see `pre_process`)
+const PARSER_NAMES: &[&str] = &[
+    "dns_tcp", "http", "kerberos_tcp", "ldap_tcp", "openvpn_tcp", "ssh", "tls",
+    "dhcp", "dns_udp", "dtls", "ikev2", "ikev2_natt", "kerberos_udp", "ldap_udp", "ntp",
+    "openvpn_udp", "radius", "snmpv1", "snmpv2c", "snmpv3",
+];
+
+/// Name of the protocol of a parser (for ex. "dns" for "dns_tcp")
+fn protocol_name(parser_name: &str) -> &str {
+    parser_name
+        .strip_suffix("_tcp")
+        .or_else(|| parser_name.strip_suffix("_udp"))
+        .unwrap_or(parser_name)
+}
+
+/// Return true if the parser `name` is selected by the list of protocols
+///
+/// A protocol selects the parser with the same name, and the TCP and UDP parsers of this
+/// protocol (for ex. "dns" selects "dns_tcp" and "dns_udp").
+fn parser_selected<S: AsRef<str>>(protocols: &[S], name: &str) -> bool {
+    let base_name = protocol_name(name);
+    protocols
+        .iter()
+        .any(|p| p.as_ref() == name || p.as_ref() == base_name)
+}
+
+/// Read the port hints (section `plugin.rusticata.port_hints`, mapping ports to protocols)
+fn port_hints(config: &Config) -> Result<Vec<(u16, String)>, String> {
+    let section = match config.section("port_hints") {
+        Some(section) => section,
+        None if config.contains("port_hints") => {
+            return Err("rusticata: port_hints must be a table".to_owned())
+        }
+        None => return Ok(Vec::new()),
+    };
+    let mut hints = Vec::new();
+    for key in section.keys() {
+        let port = key
+            .parse::<u16>()
+            .map_err(|_| format!("rusticata: invalid port {} in port_hints", key))?;
+        let protocol = section
+            .get(key)
+            .ok_or_else(|| format!("rusticata: protocol of port {} must be a string", key))?;
+        if !PARSER_NAMES
+            .iter()
+            .any(|name| parser_selected(&[protocol], name))
+        {
+            return Err(format!(
+                "rusticata: unknown protocol {} in port_hints",
+                protocol
+            ));
+        }
+        hints.push((port, protocol.to_owned()));
+    }
+    Ok(hints)
+}
+
+/// Protocol detected by the probes
+struct Detection {
+    parser_name: &'static str,
+    /// Confidence, in percent: 100 divided by the number of probes which are certain
+    confidence: u8,
+    /// Other parsers whose probe is certain or unsure
+    candidates: Vec<&'static str>,
+    /// The probe found the payload was sent by the server (`ProbeResult::Reverse`)
+    reverse: bool,
+}
+
+/// Run the probes on a payload, except the probes of the `excluded` parsers
+///
+/// All probes are run. If some are certain, the first one (in probe order) is detected,
+/// otherwise the probes which are unsure are returned.
+fn run_probes(
+    probes: &[ProbeDef],
+    i: &[u8],
+    l4_info: &L4Info,
+    excluded: &[&str],
+) -> Result<Detection, Vec<ProbeDef>> {
+    let mut certain = Vec::new();
+    let mut unsure_probes: Vec<ProbeDef> = Vec::new();
+    let filter = (l4_info.l4_proto as u32) << 24;
+    for (prio, (name, probe)) in probes
+        .iter()
+        .filter(|(id, (name, _))| id & filter != 0 && !excluded.contains(name))
+    {
+        // debug!("trying probe {}", name);
+        match probe(i, l4_info) {
+            ProbeResult::Certain => {
+                trace!("probe {} MATCHED", name);
+                certain.push((*name, false));
+            }
+            ProbeResult::Reverse => {
+                trace!("probe {} MATCHED (reverse)", name);
+                certain.push((*name, true));
+            }
+            ProbeResult::Unsure => {
+                unsure_probes.push((*prio, (name, *probe)));
+            }
+            ProbeResult::NotForUs => (),
+            ProbeResult::Fatal => {
+                warn!("Probe {} returned fatal error", name);
+                // XXX disable probe if too many errors?
+            }
+        }
+    }
+    if certain.is_empty() {
+        return Err(unsure_probes);
+    }
+    let (parser_name, reverse) = certain.remove(0);
+    let confidence = (100 / (certain.len() + 1)) as u8;
+    let candidates = certain
+        .into_iter()
+        .map(|(name, _)| name)
+        .chain(unsure_probes.iter().map(|(_, (name, _))| *name))
+        .collect();
+    Ok(Detection {
+        parser_name,
+        confidence,
+        candidates,
+        reverse,
+    })
+}
+
+fn l4_info(pinfo: &PacketInfo) -> L4Info {
+    L4Info {
+        src_port: pinfo.five_tuple.src_port,
+        dst_port: pinfo.five_tuple.dst_port,
+        l4_proto: pinfo.l4_type,
+    }
+}
+
+/// Limits of the memory used by parsers (0 means no limit)
+struct Limits {
+    /// Maximum number of parsers, the least recently used parser is evicted when reached
+    max_flows: usize,
+    /// Maximum number of bytes given to a parser, the parser is evicted after
+    max_bytes: usize,
+    /// Evict parsers which did not receive data for this number of seconds
+    idle_timeout: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_flows: DEFAULT_MAX_FLOWS,
+            max_bytes: DEFAULT_MAX_BYTES,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl Limits {
+    fn from_config(config: &Config) -> Self {
+        let default = Limits::default();
+        Limits {
+            max_flows: config.get_usize("max_flows").unwrap_or(default.max_flows),
+            max_bytes: config.get_usize("max_bytes").unwrap_or(default.max_bytes),
+            idle_timeout: config
+                .get_usize("idle_timeout")
+                .map(|t| t.min(u32::MAX as usize) as u32)
+                .unwrap_or(default.idle_timeout),
+        }
+    }
+}
+
+/// Number of parsers evicted, by reason
+#[derive(Default, Serialize)]
+struct Evictions {
+    max_flows: u64,
+    max_bytes: u64,
+    idle: u64,
+}
+
+enum EvictionReason {
+    MaxFlows,
+    MaxBytes,
+    Idle,
+}
+
+/// Parser of a flow
+struct FlowParser {
+    /// Five-tuple of the flow (client to server)
+    five_tuple: FiveTuple,
+    parser_name: &'static str,
+    parser: Box<dyn RParser>,
+    /// Number of bytes given to the parser
+    bytes: usize,
+    /// Timestamp of the last data given to the parser
+    last_seen: Duration,
+    /// Number of parse errors
+    errors: u32,
+    /// Confidence of the detection, in percent
+    confidence: u8,
+    /// Other parsers whose probe matched
+    candidates: Vec<&'static str>,
+    /// Parsers previously used for the flow (the protocol changed, for ex. with STARTTLS)
+    upgraded_from: Vec<&'static str>,
+    /// The probe found the client and server of the flow are swapped, so the direction of
+    /// payloads is reversed
+    reversed: bool,
+    /// How the direction of the flow was decided
+    direction: &'static str,
+}
+
+impl FlowParser {
+    /// Record of the flow: ID, five-tuple, protocol, and keys and values of the parser
+    fn to_json(&self, flow_id: FlowID) -> Value {
+        json!({
+            "flow_id": flow_id,
+            "five_tuple": self.five_tuple,
+            "protocol": protocol_name(self.parser_name),
+            "parser": self.parser_name,
+            "parse_errors": self.errors,
+            "confidence": self.confidence,
+            "candidates": self.candidates,
+            "upgraded_from": self.upgraded_from,
+            "direction": self.direction,
+            "metadata": self.parser.to_json_value(),
+        })
+    }
+}
+
 #[derive(Default)]
 pub struct Rusticata {
+    /// Parsers to use (from `plugin.rusticata.protocols`), or `None` for all parsers
+    protocols: Option<Vec<String>>,
+    /// Parsers not to use (from `plugin.rusticata.disabled_protocols`)
+    disabled_protocols: Vec<String>,
+    /// Protocols to try first, by port (from `plugin.rusticata.port_hints`)
+    port_hints: Vec<(u16, String)>,
+    /// Number of payloads probed before giving up on a flow (from `plugin.rusticata.max_probes`)
+    max_probes: usize,
+    /// Look for another protocol when a parser fails (from `plugin.rusticata.reprobe`)
+    reprobe: bool,
+
     builder_map: HashMap<&'static str, Box<dyn RBuilder>>,
     probes_l4: Vec<ProbeDef>,
+    /// Probes for flows using a port with a hint (hinted probes first)
+    port_probes: FnvHashMap<u16, Vec<ProbeDef>>,
 
     flow_probes: FnvHashMap<FlowID, Vec<ProbeDef>>,
-    flow_parsers: FnvHashMap<FlowID, Box<dyn RParser>>,
+    /// Number of payloads probed without result, for flows without a parser
+    flow_probe_attempts: FnvHashMap<FlowID, usize>,
+    flow_parsers: FnvHashMap<FlowID, FlowParser>,
     flow_bypass: FnvHashSet<FlowID>,
 
-    flow_parsers_archive: Vec<(FlowID, Box<dyn RParser>)>,
+    /// Records of the parsers which are done (parsers are dropped to free memory)
+    flow_parsers_archive: Vec<(FlowID, Value)>,
+
+    limits: Limits,
+    evictions: Evictions,
+    /// Time (in seconds) of the next check of idle parsers
+    next_idle_check: u32,
 }
 
-default_plugin_builder!(Rusticata, RusticataBuilder);
+pub struct RusticataBuilder;
+
+impl PluginBuilder for RusticataBuilder {
+    fn name(&self) -> &'static str { "RusticataBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("rusticata");
+        let protocols = config
+            .get_strings("protocols")
+            .map(|v| v.iter().map(|s| s.to_string()).collect());
+        let disabled_protocols = config
+            .get_strings("disabled_protocols")
+            .map(|v| v.iter().map(|s| s.to_string()).collect())
+            .unwrap_or_default();
+        let port_hints = port_hints(&config).map_err(PluginBuilderError::InvalidConfig)?;
+        let plugin = Rusticata {
+            protocols,
+            disabled_protocols,
+            port_hints,
+            max_probes: config
+                .get_usize("max_probes")
+                .unwrap_or(DEFAULT_MAX_PROBES)
+                .max(1),
+            reprobe: config.get_bool("reprobe").unwrap_or(false),
+            limits: Limits::from_config(&config),
+            ..Rusticata::default()
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("rusticata");
+        for key in &["protocols", "disabled_protocols"] {
+            if !config.contains(key) {
+                continue;
+            }
+            let protocols = config.get_strings(key).ok_or_else(|| {
+                PluginBuilderError::InvalidConfig(format!(
+                    "rusticata: {} must be an array of strings",
+                    key
+                ))
+            })?;
+            for p in protocols {
+                if !PARSER_NAMES.iter().any(|name| parser_selected(&[p], name)) {
+                    return Err(PluginBuilderError::InvalidConfig(format!(
+                        "rusticata: unknown protocol {}",
+                        p
+                    )));
+                }
+            }
+        }
+        port_hints(&config).map_err(PluginBuilderError::InvalidConfig)?;
+        for key in &["max_flows", "max_bytes", "idle_timeout", "max_probes"] {
+            if config.contains(key) && config.get_usize(key).is_none() {
+                return Err(PluginBuilderError::InvalidConfig(format!(
+                    "rusticata: {} must be a positive integer",
+                    key
+                )));
+            }
+        }
+        if config.contains("reprobe") && config.get_bool("reprobe").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "rusticata: reprobe must be a boolean".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
 
 macro_rules! add_parser {
     (tcp $name:expr, $pat:expr, $builder:expr, $bmap:ident, $probes:ident) => {
@@ -87,6 +413,9 @@ impl Plugin for Rusticata {
     fn plugin_type(&self) -> u16 {
         PLUGIN_L4 | PLUGIN_FLOW_DEL
     }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
 
     fn pre_process(&mut self) {
         let mut builder_map: HashMap<&'static str, Box<dyn RBuilder>> = HashMap::new();
@@ -117,15 +446,44 @@ impl Plugin for Rusticata {
 
         probes_l4.sort_unstable_by(|a, b| a.0.cmp(&b.0));
 
+        if let Some(protocols) = &self.protocols {
+            builder_map.retain(|name, _| parser_selected(protocols, name));
+            probes_l4.retain(|(_, (name, _))| parser_selected(protocols, name));
+        }
+        let disabled = &self.disabled_protocols;
+        builder_map.retain(|name, _| !parser_selected(disabled, name));
+        probes_l4.retain(|(_, (name, _))| !parser_selected(disabled, name));
+
+        // for ports with hints, try the probes of the hinted protocols first
+        let mut port_probes: FnvHashMap<u16, Vec<ProbeDef>> = FnvHashMap::default();
+        for (port, protocol) in &self.port_hints {
+            let (hinted, others): (Vec<_>, Vec<_>) = probes_l4
+                .iter()
+                .partition(|(_, (name, _))| parser_selected(&[protocol], name));
+            if hinted.is_empty() {
+                warn!(
+                    "rusticata: parser for hint {} on port {} is disabled",
+                    protocol, port
+                );
+                continue;
+            }
+            port_probes.insert(*port, hinted.into_iter().chain(others).copied().collect());
+        }
+
         self.builder_map = builder_map;
         self.probes_l4 = probes_l4;
+        self.port_probes = port_probes;
     }
 
     fn handle_layer_transport<'s, 'i>(
         &'s mut self,
-        _packet: &'s Packet,
+        packet: &'s Packet,
         pinfo: &PacketInfo,
     ) -> PluginResult<'i> {
+        if self.limits.idle_timeout > 0 && packet.ts.secs >= self.next_idle_check {
+            self.evict_idle_parsers(packet.ts);
+            self.next_idle_check = packet.ts.secs.saturating_add(IDLE_CHECK_INTERVAL);
+        }
         let flow_id = match pinfo.flow {
             Some(f) => f.flow_id,
             None => {
@@ -137,32 +495,38 @@ impl Plugin for Rusticata {
         if self.flow_bypass.contains(&flow_id) {
             return PluginResult::None;
         }
+        // TCP parsers receive the reassembled stream (in order, without retransmissions), not
+        // the segments
+        if pinfo.l4_type == 6 && !pinfo.l4_data.is_empty() {
+            return PluginResult::None;
+        }
         if let Some(d) = pinfo.l4_payload {
             if d.is_empty() {
                 return PluginResult::None;
             }
-            let parser: &mut dyn RParser = {
+            let flow_parser: &mut FlowParser = {
                 // check if we already have a parser
-                if let Some(parser) = self.flow_parsers.get_mut(&flow_id) {
-                    parser.as_mut()
-                } else if let Some(parser) = self.try_probe(d, flow_id, pinfo) {
-                    parser.as_mut()
+                if let Some(flow_parser) = self.flow_parsers.get_mut(&flow_id) {
+                    flow_parser
+                } else if let Some(flow_parser) = self.try_probe(d, flow_id, pinfo, packet.ts) {
+                    flow_parser
                 } else {
                     return PluginResult::None;
                 }
             };
-            let direction = if pinfo.to_server {
+            // the direction is swapped if the probe found the flow is reversed
+            let direction = if pinfo.to_server != flow_parser.reversed {
                 Direction::ToServer
             } else {
                 Direction::ToClient
             };
-            let res = parser.parse_l4(d, direction);
-            if res != ParseResult::Ok {
+            let res = flow_parser.parser.parse_l4(d, direction);
+            if res != ParseResult::Ok && res != ParseResult::Error {
                 // remove current parser for this flow
                 self.archive_parser(flow_id);
             }
             match res {
-                ParseResult::Ok => (),
+                ParseResult::Ok => self.account_data(flow_id, d.len(), packet.ts),
                 ParseResult::Stop => {
                     // add to bypass? This means no other L7 parser will receive data
                     self.flow_bypass.insert(flow_id);
@@ -171,13 +535,18 @@ impl Plugin for Rusticata {
                     // recurse to call probing function
                     // TODO risk of infinite loop?
                     info!("Protocol change for flow 0x{:x}", flow_id);
-                    return self.handle_layer_transport(_packet, pinfo);
+                    return self.handle_layer_transport(packet, pinfo);
                 }
                 ParseResult::Error => {
                     warn!(
                         "rusticata: parser failed (idx={}) (5t: {})",
                         pinfo.pcap_index, pinfo.five_tuple
                     );
+                    if self.reprobe && self.switch_parser(d, flow_id, pinfo, packet.ts) {
+                        // give the data to the new parser
+                        return self.handle_layer_transport(packet, pinfo);
+                    }
+                    self.parse_error(flow_id);
                 }
                 ParseResult::Fatal => {
                     warn!(
@@ -194,6 +563,7 @@ impl Plugin for Rusticata {
     fn flow_destroyed(&mut self, flow: &Flow) {
         let flow_id = flow.flow_id;
         self.flow_probes.remove(&flow_id);
+        self.flow_probe_attempts.remove(&flow_id);
         self.flow_bypass.remove(&flow_id);
         self.archive_parser(flow_id)
     }
@@ -201,9 +571,11 @@ impl Plugin for Rusticata {
     fn post_process(&mut self) {
         // move all parsers to archive
         self.flow_probes.clear();
+        self.flow_probe_attempts.clear();
         self.flow_bypass.clear();
-        for (flow_id, parser) in self.flow_parsers.drain() {
-            self.flow_parsers_archive.push((flow_id, parser));
+        for (flow_id, flow_parser) in self.flow_parsers.drain() {
+            let record = flow_parser.to_json(flow_id);
+            self.flow_parsers_archive.push((flow_id, record));
         }
     }
 
@@ -211,6 +583,10 @@ impl Plugin for Rusticata {
         let v = self.get_results_json();
         Some(Box::new(v))
     }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
     
     fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
         let results = self.get_results_json();
@@ -223,45 +599,40 @@ impl Plugin for Rusticata {
 }
 
 impl Rusticata {
-    fn probe(&mut self, i: &[u8], flow_id: FlowID, l4_info: &L4Info) -> Option<String> {
+    fn probe(&mut self, i: &[u8], flow_id: FlowID, l4_info: &L4Info) -> Option<Detection> {
         // check if we have a list of unsure probes
-        // otherwise, iterate on full list
+        // otherwise, iterate on the list of the port (if there is a hint), or the full list
+        let port_probes = &self.port_probes;
         let probes = match self.flow_probes.get(&flow_id) {
             Some(list) => list,
-            None => &self.probes_l4,
+            None => [l4_info.dst_port, l4_info.src_port]
+                .iter()
+                .find_map(|port| port_probes.get(port))
+                .unwrap_or(&self.probes_l4),
         };
-        let mut unsure_probes: Vec<ProbeDef> = Vec::new();
-        let filter = (l4_info.l4_proto as u32) << 24;
-        for (prio, (name, probe)) in probes.iter().filter(|(id, _)| id & filter != 0) {
-            // debug!("trying probe {}", name);
-            match probe(i, l4_info) {
-                ProbeResult::Certain | ProbeResult::Reverse => {
-                    trace!("probe {} MATCHED", name);
-                    let proto = (*name).to_string();
-                    self.flow_probes.remove(&flow_id);
-                    return Some(proto);
-                }
-                ProbeResult::Unsure => {
-                    unsure_probes.push((*prio, (name, *probe)));
-                }
-                ProbeResult::NotForUs => (),
-                ProbeResult::Fatal => {
-                    warn!(
-                        "Probe {} returned fatal error for flow ID 0x{:x}",
-                        name, flow_id
-                    );
-                    // XXX disable probe if too many errors?
+        match run_probes(probes, i, l4_info, &[]) {
+            Ok(detection) => {
+                self.flow_probes.remove(&flow_id);
+                self.flow_probe_attempts.remove(&flow_id);
+                Some(detection)
+            }
+            Err(unsure_probes) if unsure_probes.is_empty() => {
+                // try all probes again on the next payloads, up to `max_probes` payloads
+                self.flow_probes.remove(&flow_id);
+                let attempts = self.flow_probe_attempts.entry(flow_id).or_insert(0);
+                *attempts += 1;
+                if *attempts >= self.max_probes {
+                    trace!("Adding flow to bypass");
+                    self.flow_probe_attempts.remove(&flow_id);
+                    self.flow_bypass.insert(flow_id);
                 }
+                None
+            }
+            Err(unsure_probes) => {
+                self.flow_probes.insert(flow_id, unsure_probes);
+                None
             }
         }
-        if unsure_probes.is_empty() {
-            trace!("Adding flow to bypass");
-            self.flow_probes.remove(&flow_id);
-            self.flow_bypass.insert(flow_id);
-        } else {
-            self.flow_probes.insert(flow_id, unsure_probes);
-        }
-        None
     }
 
     fn try_probe(
@@ -269,24 +640,16 @@ impl Rusticata {
         data: &[u8],
         flow_id: FlowID,
         pinfo: &PacketInfo,
-    ) -> Option<&mut Box<dyn RParser>> {
-        let l4_info = L4Info {
-            src_port: pinfo.five_tuple.src_port,
-            dst_port: pinfo.five_tuple.dst_port,
-            l4_proto: pinfo.l4_type,
-        };
-        let maybe_s = self.probe(data, flow_id, &l4_info);
-        if let Some(parser_name) = maybe_s {
-            debug!("Protocol recognized as {}", parser_name);
+        ts: Duration,
+    ) -> Option<&mut FlowParser> {
+        let maybe_detection = self.probe(data, flow_id, &l4_info(pinfo));
+        if let Some(detection) = maybe_detection {
+            debug!(
+                "Protocol recognized as {} (confidence {}%)",
+                detection.parser_name, detection.confidence
+            );
             // warn!("Protocol recognized as {} (5t: {})", parser_name, pinfo.five_tuple);
-            if let Some(builder) = self.builder_map.get((&parser_name) as &str) {
-                self.flow_parsers.insert(flow_id, builder.build());
-                self.flow_parsers.get_mut(&flow_id)
-            } else {
-                warn!("Could not build parser for proto {}", parser_name);
-                self.flow_bypass.insert(flow_id);
-                None
-            }
+            self.new_parser(flow_id, detection, Vec::new(), pinfo, ts)
         } else {
             // proto not recognized
             trace!("Parser not recognized");
@@ -294,25 +657,177 @@ impl Rusticata {
         }
     }
 
+    /// Build the parser detected for a flow
+    fn new_parser(
+        &mut self,
+        flow_id: FlowID,
+        detection: Detection,
+        upgraded_from: Vec<&'static str>,
+        pinfo: &PacketInfo,
+        ts: Duration,
+    ) -> Option<&mut FlowParser> {
+        let parser_name = detection.parser_name;
+        if let Some(builder) = self.builder_map.get(parser_name) {
+            let (five_tuple, direction) = match pinfo.flow {
+                Some(flow) => (flow.five_tuple.clone(), flow.direction.as_str()),
+                None => (
+                    pinfo.five_tuple.clone(),
+                    FlowDirection::FirstPacket.as_str(),
+                ),
+            };
+            // the payload is sent by the server, but the analyzer found it was sent by the client
+            let reversed = detection.reverse && pinfo.to_server;
+            let (five_tuple, direction) = if reversed {
+                (five_tuple.get_reverse(), "probe")
+            } else {
+                (five_tuple, direction)
+            };
+            let flow_parser = FlowParser {
+                five_tuple,
+                parser_name,
+                parser: builder.build(),
+                bytes: 0,
+                last_seen: ts,
+                errors: 0,
+                confidence: detection.confidence,
+                candidates: detection.candidates,
+                upgraded_from,
+                reversed,
+                direction,
+            };
+            if self.limits.max_flows > 0 && self.flow_parsers.len() >= self.limits.max_flows {
+                self.evict_oldest_parser();
+            }
+            self.flow_parsers.insert(flow_id, flow_parser);
+            self.flow_parsers.get_mut(&flow_id)
+        } else {
+            warn!("Could not build parser for proto {}", parser_name);
+            self.flow_bypass.insert(flow_id);
+            None
+        }
+    }
+
+    /// Look for another protocol after a parse error (for ex. TLS after a STARTTLS command),
+    /// and replace the parser of the flow if a probe is certain
+    ///
+    /// The parsers already used for the flow are not tried again.
+    fn switch_parser(
+        &mut self,
+        data: &[u8],
+        flow_id: FlowID,
+        pinfo: &PacketInfo,
+        ts: Duration,
+    ) -> bool {
+        let (current, mut used) = match self.flow_parsers.get(&flow_id) {
+            Some(flow_parser) => (flow_parser.parser_name, flow_parser.upgraded_from.clone()),
+            None => return false,
+        };
+        used.push(current);
+        let detection = match run_probes(&self.probes_l4, data, &l4_info(pinfo), &used) {
+            Ok(detection) => detection,
+            Err(_) => return false,
+        };
+        info!(
+            "Protocol change for flow 0x{:x}: {} -> {}",
+            flow_id, current, detection.parser_name
+        );
+        self.archive_parser(flow_id);
+        self.new_parser(flow_id, detection, used, pinfo, ts)
+            .is_some()
+    }
+
     fn archive_parser(&mut self, flow_id: FlowID) {
-        if let Some(parser) = self.flow_parsers.remove(&flow_id) {
-            self.flow_parsers_archive.push((flow_id, parser))
+        if let Some(flow_parser) = self.flow_parsers.remove(&flow_id) {
+            let record = flow_parser.to_json(flow_id);
+            self.flow_parsers_archive.push((flow_id, record))
         }
     }
 
-    fn get_results_json(&mut self) -> Value {
-        let mut archived_parsers: Map<_, _> = self
-            .flow_parsers_archive
+    /// Archive the parser of a flow before the end of the flow (the remaining data of the flow
+    /// is not parsed)
+    fn evict_parser(&mut self, flow_id: FlowID, reason: EvictionReason) {
+        trace!("Evicting parser of flow 0x{:x}", flow_id);
+        self.archive_parser(flow_id);
+        self.flow_probes.remove(&flow_id);
+        self.flow_bypass.insert(flow_id);
+        match reason {
+            EvictionReason::MaxFlows => self.evictions.max_flows += 1,
+            EvictionReason::MaxBytes => self.evictions.max_bytes += 1,
+            EvictionReason::Idle => self.evictions.idle += 1,
+        }
+    }
+
+    /// Evict the least recently used parser
+    fn evict_oldest_parser(&mut self) {
+        let oldest = self
+            .flow_parsers
             .iter()
-            .map(|(flow_id, parser)| (flow_id.to_string(), parser.to_json_value()))
-            .collect();
-        let mut active_parsers: Map<_, _> = self
+            .min_by_key(|(_, flow_parser)| flow_parser.last_seen)
+            .map(|(flow_id, _)| *flow_id);
+        if let Some(flow_id) = oldest {
+            self.evict_parser(flow_id, EvictionReason::MaxFlows);
+        }
+    }
+
+    /// Evict parsers which did not receive data since `idle_timeout`
+    fn evict_idle_parsers(&mut self, now: Duration) {
+        let timeout = self.limits.idle_timeout;
+        let idle: Vec<_> = self
             .flow_parsers
             .iter()
-            .map(|(flow_id, parser)| (flow_id.to_string(), parser.to_json_value()))
+            .filter(|(_, flow_parser)| (now - flow_parser.last_seen).secs >= timeout)
+            .map(|(flow_id, _)| *flow_id)
             .collect();
-        // merge results and return
-        archived_parsers.append(&mut active_parsers);
-        Value::Object(archived_parsers)
+        for flow_id in idle {
+            self.evict_parser(flow_id, EvictionReason::Idle);
+        }
+    }
+
+    /// Count data given to the parser of a flow, and evict it if it received too much data
+    fn account_data(&mut self, flow_id: FlowID, len: usize, ts: Duration) {
+        let max_bytes = self.limits.max_bytes;
+        if let Some(flow_parser) = self.flow_parsers.get_mut(&flow_id) {
+            flow_parser.bytes += len;
+            flow_parser.last_seen = flow_parser.last_seen.max(ts);
+            if max_bytes > 0 && flow_parser.bytes >= max_bytes {
+                self.evict_parser(flow_id, EvictionReason::MaxBytes);
+            }
+        }
+    }
+
+    /// Count a parse error of the parser of a flow
+    ///
+    /// Data is given in order, so an error does not come from missing or retransmitted segments,
+    /// and the parser is kept (the following messages can still be parsed), unless it failed
+    /// `MAX_PARSE_ERRORS` times.
+    fn parse_error(&mut self, flow_id: FlowID) {
+        let failed = match self.flow_parsers.get_mut(&flow_id) {
+            Some(flow_parser) => {
+                flow_parser.errors += 1;
+                flow_parser.errors >= MAX_PARSE_ERRORS
+            }
+            None => false,
+        };
+        if failed {
+            self.archive_parser(flow_id);
+            self.flow_bypass.insert(flow_id);
+        }
+    }
+
+    /// Records of all flows with a parser (sorted by flow ID), and eviction counters
+    fn get_results_json(&mut self) -> Value {
+        let mut records = self.flow_parsers_archive.clone();
+        records.extend(
+            self.flow_parsers
+                .iter()
+                .map(|(flow_id, flow_parser)| (*flow_id, flow_parser.to_json(*flow_id))),
+        );
+        // stable sort, so records of the same flow stay in order
+        records.sort_by_key(|(flow_id, _)| *flow_id);
+        let records: Vec<_> = records.into_iter().map(|(_, record)| record).collect();
+        json!({
+            "flows": records,
+            "evictions": self.evictions,
+        })
     }
 }
@@ -0,0 +1,237 @@
+//! Plugin running user scripts, written in the Rhai language
+//!
+//! The script is set in the configuration (`path` in section `[plugin.script]`). It can define
+//! the following functions, which are called for the corresponding events:
+//!
+//! - `on_packet(pkt)`: layer 4 data. `pkt` is a map with keys `src`, `dst`, `src_port`,
+//!   `dst_port`, `proto`, `to_server`, `flow_id`, `ts` (seconds, as a float), `pcap_index` and
+//!   `payload` (a blob, empty if the L4 payload is not known)
+//! - `on_flow_created(flow)` and `on_flow_destroyed(flow)`: `flow` is a map with keys `src`,
+//!   `dst`, `src_port`, `dst_port`, `proto`, `flow_id`, `first_seen` and `last_seen`
+//! - `on_end()`: end of the analysis
+//!
+//! Since Rhai functions cannot access global variables, results are stored as named metrics,
+//! using `metric_add(name, value)`, `metric_set(name, value)` and `metric_get(name)`. Metrics
+//! are saved to `script-metrics.json`.
+
+use crate::output::{self, WriteErrors};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_FLOW_NEW, PLUGIN_L4, PLUGIN_NONE};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use libpcap_tools::{Config, Duration, FiveTuple, Flow, FlowID, Packet};
+use rhai::{Dynamic, Engine, Map, Scope, AST, FLOAT, INT};
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+type Metrics = Arc<Mutex<BTreeMap<String, FLOAT>>>;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// Registered layers and events (depends on the functions defined by the script)
+    plugin_type: u16,
+    has_on_end: bool,
+    metrics: Metrics,
+    /// Failed calls to script functions
+    errors: WriteErrors,
+}
+
+pub struct ScriptBuilder;
+
+impl PluginBuilder for ScriptBuilder {
+    fn name(&self) -> &'static str { "ScriptBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let path = match config.plugin_config("script").get_path("path") {
+            Some(path) => path,
+            None => {
+                debug!("script: no script configured");
+                return Ok(());
+            }
+        };
+        let plugin = Script::new(&path)?;
+        let protos = plugin.plugin_type;
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        if protos & PLUGIN_L4 != 0 {
+            registry.register_layer(4, 0, id)?;
+        }
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("script");
+        match config.get_path("path") {
+            Some(path) => Script::new(&path).map(|_| ()),
+            None if config.contains("path") => Err(PluginBuilderError::InvalidConfig(
+                "script: path must be a string".to_owned(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Script {
+    fn new(path: &Path) -> Result<Self, PluginBuilderError> {
+        let metrics = Metrics::default();
+        let mut engine = Engine::new();
+        register_metrics(&mut engine, &metrics);
+        let ast = engine.compile_file(path.into()).map_err(|e| {
+            PluginBuilderError::InvalidConfig(format!(
+                "script: cannot load {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let has_fn = |name: &str| ast.iter_functions().any(|f| f.name == name);
+        let mut plugin_type = PLUGIN_NONE;
+        if has_fn("on_packet") {
+            plugin_type |= PLUGIN_L4;
+        }
+        if has_fn("on_flow_created") {
+            plugin_type |= PLUGIN_FLOW_NEW;
+        }
+        if has_fn("on_flow_destroyed") {
+            plugin_type |= PLUGIN_FLOW_DEL;
+        }
+        let has_on_end = has_fn("on_end");
+        Ok(Script {
+            engine,
+            ast,
+            scope: Scope::new(),
+            plugin_type,
+            has_on_end,
+            metrics,
+            errors: WriteErrors::new("script"),
+        })
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Dynamic>) {
+        let res = self
+            .engine
+            .call_fn_raw(&mut self.scope, &self.ast, false, true, name, None, args)
+            .map(|_| ())
+            .map_err(|e| format!("function {} failed: {}", name, e));
+        self.errors.check(res);
+    }
+
+    fn get_results_json(&self) -> Value {
+        let metrics = self.metrics.lock().unwrap();
+        json!(*metrics)
+    }
+}
+
+fn register_metrics(engine: &mut Engine, metrics: &Metrics) {
+    let m = metrics.clone();
+    engine.register_fn("metric_add", move |name: &str, value: INT| {
+        *m.lock().unwrap().entry(name.to_owned()).or_insert(0.0) += value as FLOAT;
+    });
+    let m = metrics.clone();
+    engine.register_fn("metric_add", move |name: &str, value: FLOAT| {
+        *m.lock().unwrap().entry(name.to_owned()).or_insert(0.0) += value;
+    });
+    let m = metrics.clone();
+    engine.register_fn("metric_set", move |name: &str, value: INT| {
+        m.lock().unwrap().insert(name.to_owned(), value as FLOAT);
+    });
+    let m = metrics.clone();
+    engine.register_fn("metric_set", move |name: &str, value: FLOAT| {
+        m.lock().unwrap().insert(name.to_owned(), value);
+    });
+    let m = metrics.clone();
+    engine.register_fn("metric_get", move |name: &str| {
+        m.lock().unwrap().get(name).copied().unwrap_or(0.0)
+    });
+}
+
+fn ts_to_float(ts: Duration) -> FLOAT {
+    FLOAT::from(ts.secs) + FLOAT::from(ts.nanos) / 1_000_000_000.0
+}
+
+fn five_tuple_map(five_tuple: &FiveTuple, flow_id: Option<FlowID>) -> Map {
+    let mut map = Map::new();
+    map.insert("src".into(), five_tuple.src.to_string().into());
+    map.insert("dst".into(), five_tuple.dst.to_string().into());
+    map.insert("src_port".into(), (five_tuple.src_port as INT).into());
+    map.insert("dst_port".into(), (five_tuple.dst_port as INT).into());
+    map.insert("proto".into(), (five_tuple.proto as INT).into());
+    if let Some(flow_id) = flow_id {
+        map.insert("flow_id".into(), (flow_id as INT).into());
+    }
+    map
+}
+
+fn flow_map(flow: &Flow) -> Map {
+    let mut map = five_tuple_map(&flow.five_tuple, Some(flow.flow_id));
+    map.insert("first_seen".into(), ts_to_float(flow.first_seen).into());
+    map.insert("last_seen".into(), ts_to_float(flow.last_seen).into());
+    map
+}
+
+impl Plugin for Script {
+    fn name(&self) -> &'static str {
+        "Script"
+    }
+    fn plugin_type(&self) -> u16 {
+        self.plugin_type
+    }
+
+    fn pre_process(&mut self) {
+        // run the top-level statements of the script
+        if let Err(e) = self.engine.run_ast_with_scope(&mut self.scope, &self.ast) {
+            warn!("script: initialization failed: {}", e);
+        }
+    }
+
+    fn post_process(&mut self) {
+        if self.has_on_end {
+            self.call("on_end", Vec::new());
+        }
+        if self.errors.count() > 0 {
+            warn!("script: {} function calls failed", self.errors.count());
+        }
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let mut map = five_tuple_map(pinfo.five_tuple, pinfo.flow.map(|f| f.flow_id));
+        map.insert("to_server".into(), pinfo.to_server.into());
+        map.insert("ts".into(), ts_to_float(packet.ts).into());
+        map.insert("pcap_index".into(), (pinfo.pcap_index as INT).into());
+        let payload = pinfo.l4_payload.unwrap_or_default().to_vec();
+        map.insert("payload".into(), Dynamic::from_blob(payload));
+        self.call("on_packet", vec![map.into()]);
+        PluginResult::None
+    }
+
+    fn flow_created(&mut self, flow: &Flow) {
+        self.call("on_flow_created", vec![flow_map(flow).into()]);
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        self.call("on_flow_destroyed", vec![flow_map(flow).into()]);
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        let file = output::create_file(path, "script-metrics.json")
+            .or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        self.errors.result().or(Err("Some script functions failed"))
+    }
+}
@@ -0,0 +1,207 @@
+//! Plugin counting SCTP chunks, by type and by association
+//!
+//! Chunks are counted by type (DATA, INIT, SACK, etc.), and DATA chunks by payload protocol
+//! identifier (PPID, for ex. 3 for M3UA or 46 for Diameter). For each association (flow), the
+//! verification tags seen in each direction, the chunk counts and the number of bytes of user
+//! data are reported when the flow is destroyed.
+
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::sctp::{parse_sctp_header, sctp_chunk_name, sctp_chunks, SCTP_CHUNK_ABORT};
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Maximum number of verification tags kept for each direction of an association
+const MAX_TAGS: usize = 4;
+
+#[derive(Default, Serialize)]
+struct Association {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    packets: u64,
+    /// Verification tags of packets to the server (the tags chosen by the server)
+    tags_to_server: BTreeSet<u32>,
+    /// Verification tags of packets to the client
+    tags_to_client: BTreeSet<u32>,
+    chunks: BTreeMap<&'static str, u64>,
+    data_bytes: u64,
+    aborted: bool,
+}
+
+#[derive(Default)]
+pub struct SctpStats {
+    packets: u64,
+    chunks: BTreeMap<&'static str, u64>,
+    /// Number of DATA chunks, by payload protocol identifier
+    ppids: BTreeMap<u32, u64>,
+    data_bytes: u64,
+    associations: FnvHashMap<FlowID, Association>,
+    /// Associations of destroyed flows
+    results: Vec<Association>,
+}
+
+plugin_builder!(SctpStats, SctpStatsBuilder);
+
+impl Plugin for SctpStats {
+    fn name(&self) -> &'static str {
+        "SctpStats"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        // the SCTP packet is only sent with the first DATA chunk
+        if pinfo.l4_type != 132 || pinfo.l4_data.is_empty() {
+            return PluginResult::None;
+        }
+        if let Some(flow) = pinfo.flow {
+            self.add_packet(flow, pinfo.to_server, pinfo.l4_data);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(association) = self.associations.remove(&flow.flow_id) {
+            self.results.push(association);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "sctp.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl SctpStats {
+    fn add_packet(&mut self, flow: &Flow, to_server: bool, l4_data: &[u8]) {
+        let (header, chunks) = match parse_sctp_header(l4_data) {
+            Some(h) => h,
+            None => return,
+        };
+        self.packets += 1;
+        let association = self
+            .associations
+            .entry(flow.flow_id)
+            .or_insert_with(|| Association {
+                flow_id: flow.flow_id,
+                five_tuple: flow.five_tuple.clone(),
+                ..Association::default()
+            });
+        association.packets += 1;
+        let tags = if to_server {
+            &mut association.tags_to_server
+        } else {
+            &mut association.tags_to_client
+        };
+        // INIT chunks have no tag
+        if header.verification_tag != 0 && tags.len() < MAX_TAGS {
+            tags.insert(header.verification_tag);
+        }
+        for chunk in sctp_chunks(chunks) {
+            let name = sctp_chunk_name(chunk.chunk_type);
+            *self.chunks.entry(name).or_default() += 1;
+            *association.chunks.entry(name).or_default() += 1;
+            if chunk.chunk_type == SCTP_CHUNK_ABORT {
+                association.aborted = true;
+            }
+            if let Some((ppid, user_data)) = chunk.data() {
+                *self.ppids.entry(ppid).or_default() += 1;
+                self.data_bytes += user_data.len() as u64;
+                association.data_bytes += user_data.len() as u64;
+            }
+        }
+    }
+
+    fn get_results_json(&self) -> Value {
+        let mut associations: Vec<_> = self
+            .results
+            .iter()
+            .chain(self.associations.values())
+            .collect();
+        associations.sort_by_key(|a| a.flow_id);
+        json!({
+            "sctp": {
+                "packets": self.packets,
+                "chunks": self.chunks,
+                "ppids": self.ppids,
+                "data_bytes": self.data_bytes,
+                "associations": associations,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SctpStats;
+    use libpcap_tools::{FiveTuple, Flow};
+
+    #[test]
+    fn sctp_stats_chunks() {
+        let five_tuple = FiveTuple {
+            proto: 132,
+            src: "10.0.0.1".parse().expect("invalid address"),
+            dst: "10.0.0.2".parse().expect("invalid address"),
+            src_port: 2905,
+            dst_port: 2905,
+        };
+        let flow = Flow::new(&five_tuple, 1, 0);
+        let mut plugin = SctpStats::default();
+        // INIT (no tag)
+        let init = b"\x0b\x59\x0b\x59\x00\x00\x00\x00\x00\x00\x00\x00\
+            \x01\x00\x00\x14\x00\x00\x00\x2a\x00\x01\x00\x00\x00\x0a\x00\x0a\x00\x00\x00\x01";
+        plugin.add_packet(&flow, true, init);
+        // DATA (M3UA, 3 bytes) and SACK
+        let data = b"\x0b\x59\x0b\x59\x00\x00\x00\x2a\x00\x00\x00\x00\
+            \x00\x03\x00\x13\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x03abc\x00\
+            \x03\x00\x00\x10\x00\x00\x00\x01\x00\x00\x10\x00\x00\x00\x00\x00";
+        plugin.add_packet(&flow, true, data);
+        // ABORT
+        plugin.add_packet(
+            &flow,
+            false,
+            b"\x0b\x59\x0b\x59\x00\x00\x00\x07\x00\x00\x00\x00\x06\x00\x00\x04",
+        );
+
+        let results = plugin.get_results_json();
+        let r = &results["sctp"];
+        assert_eq!(r["packets"], 3);
+        assert_eq!(r["chunks"]["DATA"], 1);
+        assert_eq!(r["chunks"]["INIT"], 1);
+        assert_eq!(r["ppids"]["3"], 1);
+        assert_eq!(r["data_bytes"], 3);
+        let association = &r["associations"][0];
+        assert_eq!(association["packets"], 3);
+        assert_eq!(association["tags_to_server"], serde_json::json!([42]));
+        assert_eq!(association["tags_to_client"], serde_json::json!([7]));
+        assert_eq!(association["chunks"]["SACK"], 1);
+        assert_eq!(association["aborted"], true);
+    }
+}
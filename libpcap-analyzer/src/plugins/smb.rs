@@ -0,0 +1,619 @@
+//! Plugin parsing SMB1 and SMB2/3 sessions
+//!
+//! For each SMB flow (TCP port 445, or 139 for NetBIOS sessions), the plugin records the
+//! negotiated dialect, the number of requests of each command, the shares (tree connects) and
+//! files opened by the client, and the NTLMSSP authentication exchanges found in session setups
+//! (server challenge, domain, user and workstation of the client, NTLM version and result).
+//!
+//! Encrypted SMB3 messages cannot be parsed, and are only reported. For SMB1, only the first
+//! command of `AndX` chains is parsed.
+
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::cmp::min;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Maximum number of bytes of a message which are parsed (the rest is skipped)
+const MAX_MESSAGE: usize = 65536;
+
+/// Maximum number of shares or files recorded for a flow
+const MAX_NAMES: usize = 10000;
+
+const NTLMSSP_SIGNATURE: &[u8] = b"NTLMSSP\0";
+
+const STATUS_MORE_PROCESSING_REQUIRED: u32 = 0xc000_0016;
+
+#[derive(Serialize)]
+struct SmbFlowInfo {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    /// Version of the last message (`SMB1` or `SMB2`)
+    version: Option<&'static str>,
+    dialect: Option<String>,
+    /// Encrypted messages (SMB3) were seen
+    encrypted: bool,
+    /// Number of requests, by command
+    commands: BTreeMap<String, u64>,
+    trees: BTreeSet<String>,
+    files: BTreeSet<String>,
+    ntlmssp: Vec<NtlmExchange>,
+}
+
+/// NTLMSSP authentication exchange
+#[derive(Default, Serialize)]
+struct NtlmExchange {
+    /// Server challenge (hex)
+    challenge: Option<String>,
+    target_name: Option<String>,
+    /// Version of the server OS (`major.minor.build`)
+    server_version: Option<String>,
+    domain: Option<String>,
+    user: Option<String>,
+    workstation: Option<String>,
+    ntlm_version: Option<&'static str>,
+    /// `success`, or the NT status of the failed session setup
+    result: Option<String>,
+}
+
+/// NetBIOS framing state of one direction of a flow
+#[derive(Default)]
+pub(super) struct Direction {
+    /// NetBIOS header and (possibly truncated) message being read
+    buf: Vec<u8>,
+    /// Number of bytes to skip, after a truncated message
+    skip: usize,
+}
+
+impl Direction {
+    /// Add data, and return the complete messages (truncated to `MAX_MESSAGE` bytes)
+    fn push(&mut self, data: &[u8], messages: &mut Vec<Vec<u8>>) {
+        self.push_truncated(data, MAX_MESSAGE, messages);
+    }
+
+    /// Add data, and return the complete messages (truncated to `max_len` bytes)
+    pub(super) fn push_truncated(
+        &mut self,
+        data: &[u8],
+        max_len: usize,
+        messages: &mut Vec<Vec<u8>>,
+    ) {
+        let mut data = data;
+        while !data.is_empty() {
+            if self.skip > 0 {
+                let n = min(self.skip, data.len());
+                self.skip -= n;
+                data = &data[n..];
+                continue;
+            }
+            let need = match self.message_len() {
+                Some(len) => 4 + min(len, max_len),
+                None => 4,
+            };
+            let n = min(need - self.buf.len(), data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if let Some(len) = self.message_len() {
+                let keep = min(len, max_len);
+                if self.buf.len() == 4 + keep {
+                    // only session messages contain SMB data
+                    if self.buf[0] == 0 {
+                        messages.push(self.buf[4..].to_vec());
+                    }
+                    self.skip = len - keep;
+                    self.buf.clear();
+                }
+            }
+        }
+    }
+
+    fn message_len(&self) -> Option<usize> {
+        let hdr = self.buf.get(..4)?;
+        Some((usize::from(hdr[1]) << 16) | (usize::from(hdr[2]) << 8) | usize::from(hdr[3]))
+    }
+}
+
+struct SmbFlow {
+    info: SmbFlowInfo,
+    directions: [Direction; 2],
+    /// Dialects requested in the SMB1 negotiate request
+    smb1_dialects: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Smb {
+    /// State of flows, `None` if the flow is not SMB
+    flows: FnvHashMap<FlowID, Option<SmbFlow>>,
+    /// Information of destroyed flows
+    results: Vec<SmbFlowInfo>,
+}
+
+plugin_builder!(Smb, SmbBuilder);
+
+impl Plugin for Smb {
+    fn name(&self) -> &'static str {
+        "Smb"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if pinfo.five_tuple.proto == 6 && !data.is_empty() => {
+                (flow, data)
+            }
+            _ => return PluginResult::None,
+        };
+        let state = self.flows.entry(flow.flow_id).or_insert_with(|| {
+            let port = pinfo.five_tuple.dst_port;
+            if pinfo.to_server && (port == 445 || port == 139) {
+                Some(SmbFlow::new(flow.flow_id, &flow.five_tuple))
+            } else {
+                None
+            }
+        });
+        if let Some(state) = state {
+            state.update(data, pinfo.to_server);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(Some(state)) = self.flows.remove(&flow.flow_id) {
+            self.results.push(state.info);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "smb.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Smb {
+    fn get_results_json(&mut self) -> Value {
+        // flows still active (if called before the end of the analysis)
+        let active = self.flows.values().flatten().map(|state| &state.info);
+        let flows: Vec<_> = self.results.iter().chain(active).collect();
+        json!({ "smb-flows": flows })
+    }
+}
+
+impl SmbFlow {
+    fn new(flow_id: FlowID, five_tuple: &FiveTuple) -> Self {
+        SmbFlow {
+            info: SmbFlowInfo {
+                flow_id,
+                five_tuple: five_tuple.clone(),
+                version: None,
+                dialect: None,
+                encrypted: false,
+                commands: BTreeMap::new(),
+                trees: BTreeSet::new(),
+                files: BTreeSet::new(),
+                ntlmssp: Vec::new(),
+            },
+            directions: Default::default(),
+            smb1_dialects: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8], to_server: bool) {
+        let mut messages = Vec::new();
+        self.directions[if to_server { 0 } else { 1 }].push(data, &mut messages);
+        for msg in messages {
+            match msg.get(..4) {
+                Some(b"\xfeSMB") => {
+                    self.info.version = Some("SMB2");
+                    // compounded requests
+                    let mut offset = 0;
+                    while let Some(next) = msg.get(offset..).and_then(|m| self.handle_smb2(m)) {
+                        if next == 0 {
+                            break;
+                        }
+                        offset += next;
+                    }
+                }
+                Some(b"\xffSMB") => {
+                    self.info.version = Some("SMB1");
+                    self.handle_smb1(&msg);
+                }
+                Some(b"\xfdSMB") => self.info.encrypted = true,
+                _ => debug!("smb: invalid message in flow 0x{:x}", self.info.flow_id),
+            }
+        }
+    }
+
+    /// Parse a SMB2 message, and return the offset of the next compounded message (or 0)
+    fn handle_smb2(&mut self, msg: &[u8]) -> Option<usize> {
+        let status = le_u32(msg, 8)?;
+        let command = le_u16(msg, 12)?;
+        let response = le_u32(msg, 16)? & 0x1 != 0;
+        let next = le_u32(msg, 20)? as usize;
+        if !response {
+            self.count_command(smb2_command_name(command));
+        }
+        match (command, response) {
+            // NEGOTIATE
+            (0x00, true) => {
+                if let Some(dialect) = le_u16(msg, 64 + 4) {
+                    self.info.dialect = Some(smb2_dialect_name(dialect));
+                }
+            }
+            // SESSION_SETUP
+            (0x01, false) => {
+                if let Some(blob) = smb2_buffer(msg, 64 + 12) {
+                    self.handle_security_blob(blob);
+                }
+            }
+            (0x01, true) => {
+                if let Some(blob) = smb2_buffer(msg, 64 + 4) {
+                    self.handle_security_blob(blob);
+                }
+                self.session_setup_result(status);
+            }
+            // TREE_CONNECT
+            (0x03, false) => {
+                if let Some(path) = smb2_buffer(msg, 64 + 4) {
+                    insert_name(&mut self.info.trees, smb_string(path, true));
+                }
+            }
+            // CREATE
+            (0x05, false) => {
+                if let Some(name) = smb2_buffer(msg, 64 + 44) {
+                    insert_name(&mut self.info.files, smb_string(name, true));
+                }
+            }
+            _ => (),
+        }
+        Some(next)
+    }
+
+    fn handle_smb1(&mut self, msg: &[u8]) -> Option<()> {
+        let command = *msg.get(4)?;
+        let status = le_u32(msg, 5)?;
+        let response = msg.get(9)? & 0x80 != 0;
+        let unicode = le_u16(msg, 10)? & 0x8000 != 0;
+        let word_count = usize::from(*msg.get(32)?);
+        let words = msg.get(33..33 + 2 * word_count)?;
+        let byte_count = usize::from(le_u16(msg, 33 + 2 * word_count)?);
+        let bytes_offset = 35 + 2 * word_count;
+        let bytes = msg.get(bytes_offset..min(bytes_offset + byte_count, msg.len()))?;
+        if !response {
+            self.count_command(smb1_command_name(command));
+        }
+        match (command, response) {
+            // NEGOTIATE: null-terminated dialect strings, each one prefixed by 0x02
+            (0x72, false) => {
+                self.smb1_dialects = bytes
+                    .split(|&b| b == 0)
+                    .filter_map(|s| s.strip_prefix(b"\x02"))
+                    .map(|s| String::from_utf8_lossy(s).into_owned())
+                    .collect();
+            }
+            (0x72, true) => {
+                let index = usize::from(le_u16(words, 0)?);
+                self.info.dialect = self.smb1_dialects.get(index).cloned();
+            }
+            // SESSION_SETUP_ANDX
+            (0x73, _) => {
+                self.handle_security_blob(bytes);
+                if response {
+                    self.session_setup_result(status);
+                }
+            }
+            // TREE_CONNECT_ANDX: password, then path
+            (0x75, false) => {
+                let offset = bytes_offset + usize::from(le_u16(words, 6)?);
+                let path = msg.get(smb1_string_offset(offset, unicode)..)?;
+                insert_name(&mut self.info.trees, smb_string(path, unicode));
+            }
+            // NT_CREATE_ANDX
+            (0xa2, false) => {
+                let offset = smb1_string_offset(bytes_offset, unicode);
+                let len = usize::from(le_u16(words, 5)?);
+                let name = msg.get(offset..min(offset + len, msg.len()))?;
+                insert_name(&mut self.info.files, smb_string(name, unicode));
+            }
+            _ => (),
+        }
+        Some(())
+    }
+
+    fn count_command(&mut self, name: String) {
+        *self.info.commands.entry(name).or_insert(0) += 1;
+    }
+
+    /// Parse the NTLMSSP message of a security blob (usually wrapped in SPNEGO)
+    fn handle_security_blob(&mut self, blob: &[u8]) -> Option<()> {
+        let pos = blob
+            .windows(NTLMSSP_SIGNATURE.len())
+            .position(|w| w == NTLMSSP_SIGNATURE)?;
+        let msg = &blob[pos..];
+        match le_u32(msg, 8)? {
+            // CHALLENGE
+            2 => {
+                let flags = le_u32(msg, 20)?;
+                let unicode = flags & 0x1 != 0;
+                let server_version = if flags & 0x0200_0000 != 0 {
+                    msg.get(48..52)
+                        .map(|v| format!("{}.{}.{}", v[0], v[1], u16::from_le_bytes([v[2], v[3]])))
+                } else {
+                    None
+                };
+                self.info.ntlmssp.push(NtlmExchange {
+                    challenge: msg.get(24..32).map(hex),
+                    target_name: ntlm_field(msg, 12).map(|s| smb_string(s, unicode)),
+                    server_version,
+                    ..NtlmExchange::default()
+                });
+            }
+            // AUTHENTICATE
+            3 => {
+                let unicode = le_u32(msg, 60)? & 0x1 != 0;
+                let nt_response_len = le_u16(msg, 20)?;
+                let exchange = match self.info.ntlmssp.last_mut() {
+                    Some(exchange) if exchange.user.is_none() => exchange,
+                    _ => {
+                        self.info.ntlmssp.push(NtlmExchange::default());
+                        self.info.ntlmssp.last_mut()?
+                    }
+                };
+                exchange.domain = ntlm_field(msg, 28).map(|s| smb_string(s, unicode));
+                exchange.user = ntlm_field(msg, 36).map(|s| smb_string(s, unicode));
+                exchange.workstation = ntlm_field(msg, 44).map(|s| smb_string(s, unicode));
+                exchange.ntlm_version = match nt_response_len {
+                    0 => None,
+                    24 => Some("NTLMv1"),
+                    _ => Some("NTLMv2"),
+                };
+            }
+            _ => (),
+        }
+        Some(())
+    }
+
+    fn session_setup_result(&mut self, status: u32) {
+        if status == STATUS_MORE_PROCESSING_REQUIRED {
+            return;
+        }
+        if let Some(exchange) = self.info.ntlmssp.last_mut() {
+            if exchange.user.is_some() && exchange.result.is_none() {
+                exchange.result = Some(if status == 0 {
+                    "success".to_owned()
+                } else {
+                    format!("0x{:08x}", status)
+                });
+            }
+        }
+    }
+}
+
+pub(super) fn le_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub(super) fn le_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Get a buffer of a SMB2 message, using the offset (from the start of the header) and length
+/// fields stored at `field`
+pub(super) fn smb2_buffer(msg: &[u8], field: usize) -> Option<&[u8]> {
+    let offset = usize::from(le_u16(msg, field)?);
+    let len = usize::from(le_u16(msg, field + 2)?);
+    msg.get(offset..offset + len)
+}
+
+/// Get a field of a NTLMSSP message (length, maximum length and offset)
+fn ntlm_field(msg: &[u8], field: usize) -> Option<&[u8]> {
+    let len = usize::from(le_u16(msg, field)?);
+    let offset = le_u32(msg, field + 4)? as usize;
+    msg.get(offset..offset + len)
+}
+
+/// Unicode strings of SMB1 messages are aligned on 2 bytes, from the start of the header
+fn smb1_string_offset(offset: usize, unicode: bool) -> usize {
+    if unicode && offset % 2 != 0 {
+        offset + 1
+    } else {
+        offset
+    }
+}
+
+/// Decode a string (UTF-16LE if `unicode`), stopping at the first null character
+pub(super) fn smb_string(data: &[u8], unicode: bool) -> String {
+    if unicode {
+        let units: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        String::from_utf8_lossy(&data[..len]).into_owned()
+    }
+}
+
+fn insert_name(names: &mut BTreeSet<String>, name: String) {
+    if !name.is_empty() && names.len() < MAX_NAMES {
+        names.insert(name);
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn smb2_command_name(command: u16) -> String {
+    let name = match command {
+        0x00 => "NEGOTIATE",
+        0x01 => "SESSION_SETUP",
+        0x02 => "LOGOFF",
+        0x03 => "TREE_CONNECT",
+        0x04 => "TREE_DISCONNECT",
+        0x05 => "CREATE",
+        0x06 => "CLOSE",
+        0x07 => "FLUSH",
+        0x08 => "READ",
+        0x09 => "WRITE",
+        0x0a => "LOCK",
+        0x0b => "IOCTL",
+        0x0c => "CANCEL",
+        0x0d => "ECHO",
+        0x0e => "QUERY_DIRECTORY",
+        0x0f => "CHANGE_NOTIFY",
+        0x10 => "QUERY_INFO",
+        0x11 => "SET_INFO",
+        0x12 => "OPLOCK_BREAK",
+        _ => return format!("0x{:04x}", command),
+    };
+    name.to_owned()
+}
+
+fn smb2_dialect_name(dialect: u16) -> String {
+    let name = match dialect {
+        0x0202 => "2.0.2",
+        0x0210 => "2.1",
+        0x02ff => "2.???",
+        0x0300 => "3.0",
+        0x0302 => "3.0.2",
+        0x0311 => "3.1.1",
+        _ => return format!("0x{:04x}", dialect),
+    };
+    name.to_owned()
+}
+
+fn smb1_command_name(command: u8) -> String {
+    let name = match command {
+        0x04 => "CLOSE",
+        0x25 => "TRANSACTION",
+        0x2b => "ECHO",
+        0x2e => "READ_ANDX",
+        0x2f => "WRITE_ANDX",
+        0x32 => "TRANSACTION2",
+        0x71 => "TREE_DISCONNECT",
+        0x72 => "NEGOTIATE",
+        0x73 => "SESSION_SETUP_ANDX",
+        0x74 => "LOGOFF_ANDX",
+        0x75 => "TREE_CONNECT_ANDX",
+        0xa0 => "NT_TRANSACT",
+        0xa2 => "NT_CREATE_ANDX",
+        _ => return format!("0x{:02x}", command),
+    };
+    name.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SmbFlow;
+    use libpcap_tools::FiveTuple;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+    }
+
+    /// Build a SMB2 message, prefixed by the NetBIOS header
+    fn smb2(command: u16, response: bool, body: &[u8]) -> Vec<u8> {
+        let mut msg = b"\xfeSMB\x40\x00".to_vec();
+        msg.resize(12, 0);
+        msg.extend_from_slice(&command.to_le_bytes());
+        msg.resize(16, 0);
+        msg.push(response as u8);
+        msg.resize(64, 0);
+        msg.extend_from_slice(body);
+        let len = msg.len() as u32;
+        let mut data = len.to_be_bytes().to_vec();
+        data.extend(msg);
+        data
+    }
+
+    /// Build a SMB2 request body, with a buffer at `field`
+    fn smb2_body(len: usize, field: usize, buffer: &[u8]) -> Vec<u8> {
+        let mut body = vec![0; len];
+        body[field..field + 2].copy_from_slice(&(64 + len as u16).to_le_bytes());
+        body[field + 2..field + 4].copy_from_slice(&(buffer.len() as u16).to_le_bytes());
+        body.extend_from_slice(buffer);
+        body
+    }
+
+    #[test]
+    fn smb2_session() {
+        let t5 = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: 49152,
+            dst_port: 445,
+        };
+        let mut state = SmbFlow::new(1, &t5);
+        // NTLMSSP AUTHENTICATE message, with a NTLMv2 response
+        let mut ntlm = b"NTLMSSP\0\x03\0\0\0".to_vec();
+        ntlm.resize(64, 0);
+        let fields = [
+            (28, utf16("CORP")),
+            (36, utf16("alice")),
+            (44, utf16("WS1")),
+            (20, vec![0; 48]),
+        ];
+        for (field, value) in fields.iter() {
+            let offset = ntlm.len() as u32;
+            ntlm[*field..*field + 2].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            ntlm[*field + 4..*field + 8].copy_from_slice(&offset.to_le_bytes());
+            ntlm.extend_from_slice(value);
+        }
+        ntlm[60] = 0x01;
+        let mut requests = smb2(1, false, &smb2_body(24, 12, &ntlm));
+        requests.extend(smb2(3, false, &smb2_body(8, 4, &utf16("\\\\srv\\share"))));
+        requests.extend(smb2(5, false, &smb2_body(56, 44, &utf16("dir\\file.txt"))));
+        // messages split across segments
+        for segment in requests.chunks(50) {
+            state.update(segment, true);
+        }
+        state.update(&smb2(1, true, &[9, 0, 0, 0, 0, 0, 0, 0]), false);
+        let info = &state.info;
+        assert_eq!(info.version, Some("SMB2"));
+        assert_eq!(info.commands.get("SESSION_SETUP"), Some(&1));
+        assert_eq!(info.commands.get("CREATE"), Some(&1));
+        assert!(info.trees.contains("\\\\srv\\share"));
+        assert!(info.files.contains("dir\\file.txt"));
+        assert_eq!(info.ntlmssp.len(), 1);
+        let exchange = &info.ntlmssp[0];
+        assert_eq!(exchange.domain.as_deref(), Some("CORP"));
+        assert_eq!(exchange.user.as_deref(), Some("alice"));
+        assert_eq!(exchange.workstation.as_deref(), Some("WS1"));
+        assert_eq!(exchange.ntlm_version, Some("NTLMv2"));
+        assert_eq!(exchange.result.as_deref(), Some("success"));
+    }
+}
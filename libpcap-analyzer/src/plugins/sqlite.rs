@@ -0,0 +1,306 @@
+//! Plugin storing flows and protocol metadata in a SQLite database
+//!
+//! The database is set by `filename` in section `[plugin.sqlite]` (relative to the output
+//! directory). The file is overwritten. If the report file (`report_file`) has the `.db` or
+//! `.sqlite` extension, the results of plugins are also stored in a SQLite database (which can be
+//! the same file).
+//!
+//! Schema:
+//!
+//! ```sql
+//! -- one row per flow, written when the flow is destroyed
+//! CREATE TABLE flows (
+//!     flow_id INTEGER PRIMARY KEY,    -- flow ID, as a signed 64-bit integer
+//!     proto INTEGER NOT NULL,
+//!     src_ip TEXT NOT NULL,
+//!     src_port INTEGER NOT NULL,
+//!     dst_ip TEXT NOT NULL,
+//!     dst_port INTEGER NOT NULL,
+//!     first_seen REAL NOT NULL,       -- seconds since the Unix epoch
+//!     last_seen REAL NOT NULL,
+//!     packets_to_server INTEGER NOT NULL,
+//!     packets_to_client INTEGER NOT NULL,
+//!     bytes_to_server INTEGER NOT NULL,
+//!     bytes_to_client INTEGER NOT NULL,
+//!     tcp_flags INTEGER NOT NULL,
+//!     app_proto TEXT                  -- detected application protocol, if known
+//! );
+//! CREATE INDEX flows_five_tuple ON flows (src_ip, dst_ip, src_port, dst_port, proto);
+//! CREATE INDEX flows_time ON flows (first_seen, last_seen);
+//!
+//! -- metadata of the first payload sent by the client (see `app_metadata`)
+//! CREATE TABLE protocol_metadata (
+//!     flow_id INTEGER NOT NULL,       -- references flows (flow_id)
+//!     key TEXT NOT NULL,              -- for ex. "http.host" or "tls.sni"
+//!     value TEXT NOT NULL
+//! );
+//! CREATE INDEX protocol_metadata_flow ON protocol_metadata (flow_id);
+//! CREATE INDEX protocol_metadata_key ON protocol_metadata (key, value);
+//!
+//! -- results of plugins (report), one row per value
+//! CREATE TABLE plugin_metrics (
+//!     plugin TEXT NOT NULL,
+//!     key TEXT NOT NULL,              -- path of the value in the results, for ex. "flows.0.bytes"
+//!     value                           -- integer, real or text
+//! );
+//! CREATE INDEX plugin_metrics_key ON plugin_metrics (plugin, key);
+//! ```
+
+use crate::app_proto::{app_metadata, detect_app_proto};
+use crate::output::{self, WriteErrors};
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::Report;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, Duration, Flow, FlowID, Packet};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const FLOWS_SCHEMA: &str = "
+CREATE TABLE flows (
+    flow_id INTEGER PRIMARY KEY,
+    proto INTEGER NOT NULL,
+    src_ip TEXT NOT NULL,
+    src_port INTEGER NOT NULL,
+    dst_ip TEXT NOT NULL,
+    dst_port INTEGER NOT NULL,
+    first_seen REAL NOT NULL,
+    last_seen REAL NOT NULL,
+    packets_to_server INTEGER NOT NULL,
+    packets_to_client INTEGER NOT NULL,
+    bytes_to_server INTEGER NOT NULL,
+    bytes_to_client INTEGER NOT NULL,
+    tcp_flags INTEGER NOT NULL,
+    app_proto TEXT
+);
+CREATE INDEX flows_five_tuple ON flows (src_ip, dst_ip, src_port, dst_port, proto);
+CREATE INDEX flows_time ON flows (first_seen, last_seen);
+CREATE TABLE protocol_metadata (
+    flow_id INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+CREATE INDEX protocol_metadata_flow ON protocol_metadata (flow_id);
+CREATE INDEX protocol_metadata_key ON protocol_metadata (key, value);
+";
+
+const METRICS_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS plugin_metrics (
+    plugin TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value
+);
+CREATE INDEX IF NOT EXISTS plugin_metrics_key ON plugin_metrics (plugin, key);
+DELETE FROM plugin_metrics;
+";
+
+pub struct Sqlite {
+    // plugins must be `Sync`, which is not the case for connections
+    conn: Mutex<Connection>,
+    /// Detected application protocol, for flows with payload
+    app_protos: FnvHashMap<FlowID, Option<&'static str>>,
+    write_errors: WriteErrors,
+}
+
+pub struct SqliteBuilder;
+
+impl PluginBuilder for SqliteBuilder {
+    fn name(&self) -> &'static str { "SqliteBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let filename = match config.plugin_config("sqlite").get("filename") {
+            Some(filename) => filename.to_owned(),
+            None => {
+                debug!("sqlite: no database configured");
+                return Ok(());
+            }
+        };
+        let mut path = PathBuf::from(output::get_output_dir(config));
+        path.push(&filename);
+        let conn = create_database(&path).map_err(|e| {
+            PluginBuilderError::InvalidConfig(format!("sqlite: cannot create {}: {}", filename, e))
+        })?;
+        let plugin = Sqlite {
+            conn: Mutex::new(conn),
+            app_protos: FnvHashMap::default(),
+            write_errors: WriteErrors::new("sqlite: could not write to database"),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("sqlite");
+        if config.contains("filename") && config.get("filename").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "sqlite: filename must be a string".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Sqlite {
+    fn name(&self) -> &'static str {
+        "Sqlite"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+
+    fn post_process(&mut self) {
+        let res = self.conn.lock().unwrap().execute_batch("COMMIT");
+        self.write_errors.check(res);
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if !data.is_empty() => (flow, data),
+            _ => return PluginResult::None,
+        };
+        if self.app_protos.contains_key(&flow.flow_id) || !pinfo.to_server {
+            return PluginResult::None;
+        }
+        let app_proto = detect_app_proto(pinfo.five_tuple, data);
+        self.app_protos.insert(flow.flow_id, app_proto);
+        if let Some(app_proto) = app_proto {
+            let metadata = app_metadata(app_proto, pinfo.five_tuple, data);
+            let res = self.insert_metadata(flow.flow_id, &metadata);
+            self.write_errors.check(res);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let app_proto = self.app_protos.remove(&flow.flow_id).flatten();
+        let res = self.insert_flow(flow, app_proto);
+        self.write_errors.check(res);
+    }
+
+    fn save_results(&mut self, _path: &str) -> Result<(), &'static str> {
+        self.write_errors.result()
+    }
+}
+
+impl Sqlite {
+    fn insert_flow(&self, flow: &Flow, app_proto: Option<&str>) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "INSERT OR REPLACE INTO flows VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        let t5 = &flow.five_tuple;
+        stmt.execute(params![
+            flow.flow_id as i64,
+            t5.proto,
+            t5.src.to_string(),
+            t5.src_port,
+            t5.dst.to_string(),
+            t5.dst_port,
+            to_secs(flow.first_seen),
+            to_secs(flow.last_seen),
+            flow.packets_to_server as i64,
+            flow.packets_to_client() as i64,
+            flow.bytes_to_server as i64,
+            flow.bytes_to_client() as i64,
+            flow.tcp_flags,
+            app_proto,
+        ])?;
+        Ok(())
+    }
+
+    fn insert_metadata(
+        &self,
+        flow_id: FlowID,
+        metadata: &[(&'static str, String)],
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("INSERT INTO protocol_metadata VALUES (?, ?, ?)")?;
+        for (key, value) in metadata {
+            stmt.execute(params![flow_id as i64, key, value])?;
+        }
+        Ok(())
+    }
+}
+
+/// Create (or overwrite) the database, and start a transaction
+///
+/// All rows are inserted in a single transaction, committed at the end of the analysis.
+fn create_database(path: &Path) -> Result<Connection, Box<dyn std::error::Error>> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let conn = Connection::open(path)?;
+    conn.execute_batch(FLOWS_SCHEMA)?;
+    conn.execute_batch("BEGIN")?;
+    Ok(conn)
+}
+
+fn to_secs(ts: Duration) -> f64 {
+    f64::from(ts.secs) + f64::from(ts.nanos) / 1_000_000_000.0
+}
+
+/// Save the results of all plugins in the `plugin_metrics` table of a database
+///
+/// Previous results are replaced, other tables are kept.
+pub(crate) fn save_report(report: &Report, path: &Path) -> Result<(), io::Error> {
+    let to_io = |e: rusqlite::Error| io::Error::new(io::ErrorKind::Other, e.to_string());
+    let mut conn = Connection::open(path).map_err(to_io)?;
+    let tx = conn.transaction().map_err(to_io)?;
+    tx.execute_batch(METRICS_SCHEMA).map_err(to_io)?;
+    {
+        let mut stmt = tx
+            .prepare("INSERT INTO plugin_metrics VALUES (?, ?, ?)")
+            .map_err(to_io)?;
+        for (plugin, results) in &report.plugins {
+            let mut values = Vec::new();
+            flatten(String::new(), results, &mut values);
+            for (key, value) in values {
+                stmt.execute(params![plugin, key, value]).map_err(to_io)?;
+            }
+        }
+    }
+    tx.commit().map_err(to_io)
+}
+
+/// Flatten a JSON value to a list of (path, value) pairs, where values are SQL values
+fn flatten(path: String, value: &Value, out: &mut Vec<(String, rusqlite::types::Value)>) {
+    use rusqlite::types::Value as SqlValue;
+    let join = |key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    let value = match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(i64::from(*b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        Value::Array(v) => {
+            for (i, item) in v.iter().enumerate() {
+                flatten(join(&i.to_string()), item, out);
+            }
+            return;
+        }
+        Value::Object(m) => {
+            for (key, item) in m {
+                flatten(join(key), item, out);
+            }
+            return;
+        }
+    };
+    out.push((path, value));
+}
@@ -0,0 +1,326 @@
+//! Plugin measuring the quality of TCP connections
+//!
+//! For each TCP flow, the following events are counted: retransmissions, out-of-order segments,
+//! duplicate ACKs and zero window advertisements. The round-trip times of the handshake are also
+//! measured: SYN to SYN+ACK (network RTT to the server), and SYN to the ACK of the client
+//! (complete handshake).
+//!
+//! A segment with data before the highest sequence number seen in its direction is out of order
+//! if it was received shortly (less than the RTT, or 3 ms if unknown) after the highest segment,
+//! and a retransmission otherwise.
+//!
+//! The report contains totals, and the flows with most events in each category (worst
+//! offenders).
+
+use super::dns_stats::duration_secs;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_TCP_SEGMENTS};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+
+/// Number of flows in each list of worst offenders
+const WORST_FLOWS: usize = 10;
+
+/// Delay under which a segment is out of order rather than retransmitted, if the RTT is not known
+const DEFAULT_REORDER_DELAY: f64 = 0.003;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// State of one direction of a flow
+#[derive(Default)]
+struct DirectionState {
+    /// Next expected sequence number (after the highest segment)
+    next_seq: Option<u32>,
+    /// Timestamp of the highest segment
+    last_ts: Duration,
+    /// Last acknowledgement number and window
+    last_ack: Option<(u32, u16)>,
+}
+
+#[derive(Default, Serialize)]
+struct FlowHealth {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    packets: u64,
+    retransmissions: u64,
+    out_of_order: u64,
+    dup_acks: u64,
+    zero_windows: u64,
+    /// SYN to SYN+ACK, in seconds
+    syn_synack_rtt: Option<f64>,
+    /// SYN to ACK of the handshake, in seconds
+    handshake_rtt: Option<f64>,
+    #[serde(skip)]
+    syn_ts: Option<Duration>,
+    #[serde(skip)]
+    directions: [DirectionState; 2],
+}
+
+impl FlowHealth {
+    fn new(flow: &Flow) -> Self {
+        FlowHealth {
+            flow_id: flow.flow_id,
+            five_tuple: flow.five_tuple.clone(),
+            ..FlowHealth::default()
+        }
+    }
+
+    /// Update the state with a TCP segment (`l4_data` is the TCP header and payload)
+    fn add_segment(&mut self, ts: Duration, to_server: bool, l4_data: &[u8]) {
+        if l4_data.len() < 20 {
+            return;
+        }
+        let be_u32 = |i: usize| {
+            u32::from_be_bytes([l4_data[i], l4_data[i + 1], l4_data[i + 2], l4_data[i + 3]])
+        };
+        let seq = be_u32(4);
+        let ack = be_u32(8);
+        let header_len = usize::from(l4_data[12] >> 4) * 4;
+        let flags = l4_data[13];
+        let window = u16::from_be_bytes([l4_data[14], l4_data[15]]);
+        let payload_len = l4_data.len().saturating_sub(header_len) as u32;
+        self.packets += 1;
+
+        // handshake
+        let syn = flags & TCP_SYN != 0;
+        let ack_flag = flags & TCP_ACK != 0;
+        if syn && !ack_flag && to_server {
+            // use the last SYN, if retransmitted
+            self.syn_ts = Some(ts);
+        } else if let Some(syn_ts) = self.syn_ts.filter(|&syn_ts| ts >= syn_ts) {
+            if syn && ack_flag && !to_server && self.syn_synack_rtt.is_none() {
+                self.syn_synack_rtt = Some(duration_secs(ts - syn_ts));
+            } else if !syn
+                && ack_flag
+                && to_server
+                && self.syn_synack_rtt.is_some()
+                && self.handshake_rtt.is_none()
+            {
+                self.handshake_rtt = Some(duration_secs(ts - syn_ts));
+            }
+        }
+
+        if window == 0 && flags & TCP_RST == 0 {
+            self.zero_windows += 1;
+        }
+
+        let reorder_delay = self.syn_synack_rtt.unwrap_or(DEFAULT_REORDER_DELAY);
+        let dir = &mut self.directions[if to_server { 0 } else { 1 }];
+        // SYN and FIN use one sequence number
+        let seg_len = payload_len + u32::from(syn) + u32::from(flags & TCP_FIN != 0);
+        if seg_len > 0 && flags & TCP_RST == 0 {
+            let end = seq.wrapping_add(seg_len);
+            match dir.next_seq {
+                Some(next_seq) if seq_before(seq, next_seq) => {
+                    let delay = if ts >= dir.last_ts {
+                        duration_secs(ts - dir.last_ts)
+                    } else {
+                        0.0
+                    };
+                    if delay < reorder_delay && !syn {
+                        self.out_of_order += 1;
+                    } else {
+                        self.retransmissions += 1;
+                    }
+                    if seq_before(next_seq, end) {
+                        dir.next_seq = Some(end);
+                    }
+                }
+                _ => {
+                    dir.next_seq = Some(end);
+                    dir.last_ts = ts;
+                }
+            }
+        }
+
+        if ack_flag && flags & TCP_RST == 0 {
+            // pure ACK, with the same acknowledgement number and window as the previous one
+            if seg_len == 0 && dir.last_ack == Some((ack, window)) {
+                self.dup_acks += 1;
+            }
+            dir.last_ack = Some((ack, window));
+        }
+    }
+
+    /// Number of events, for the lists of worst offenders
+    fn events(&self) -> u64 {
+        self.retransmissions + self.out_of_order + self.dup_acks + self.zero_windows
+    }
+}
+
+/// Return true if sequence number `a` is before `b` (modulo 2^32)
+fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Default)]
+pub struct TcpHealth {
+    flows: FnvHashMap<FlowID, FlowHealth>,
+    /// Destroyed flows, with at least one event or a handshake RTT
+    results: Vec<FlowHealth>,
+    /// Number of destroyed flows without events
+    clean_flows: u64,
+}
+
+plugin_builder!(TcpHealth, TcpHealthBuilder);
+
+impl Plugin for TcpHealth {
+    fn name(&self) -> &'static str {
+        "TcpHealth"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_TCP_SEGMENTS | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        if pinfo.l4_type != 6 {
+            return PluginResult::None;
+        }
+        if let Some(flow) = pinfo.flow {
+            let health = self
+                .flows
+                .entry(flow.flow_id)
+                .or_insert_with(|| FlowHealth::new(flow));
+            health.add_segment(packet.ts, pinfo.to_server, pinfo.l4_data);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(health) = self.flows.remove(&flow.flow_id) {
+            if health.events() > 0 || health.handshake_rtt.is_some() {
+                self.results.push(health);
+            } else {
+                self.clean_flows += 1;
+            }
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "tcp_health.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl TcpHealth {
+    fn get_results_json(&self) -> Value {
+        let flows: Vec<&FlowHealth> = self.results.iter().chain(self.flows.values()).collect();
+        let total = |f: fn(&FlowHealth) -> u64| flows.iter().map(|&h| f(h)).sum::<u64>();
+        // flows with the highest values, in decreasing order
+        let worst = |key: fn(&FlowHealth) -> f64| {
+            let mut v: Vec<_> = flows.iter().copied().filter(|&h| key(h) > 0.0).collect();
+            v.sort_by(|a, b| {
+                key(b)
+                    .partial_cmp(&key(a))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            v.truncate(WORST_FLOWS);
+            v
+        };
+        json!({
+            "tcp-health": {
+                "flows": flows.len() as u64 + self.clean_flows,
+                "retransmissions": total(|h| h.retransmissions),
+                "out_of_order": total(|h| h.out_of_order),
+                "dup_acks": total(|h| h.dup_acks),
+                "zero_windows": total(|h| h.zero_windows),
+                "worst": {
+                    "retransmissions": worst(|h| h.retransmissions as f64),
+                    "out_of_order": worst(|h| h.out_of_order as f64),
+                    "dup_acks": worst(|h| h.dup_acks as f64),
+                    "zero_windows": worst(|h| h.zero_windows as f64),
+                    "handshake_rtt": worst(|h| h.handshake_rtt.unwrap_or(0.0)),
+                },
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{seq_before, FlowHealth};
+    use libpcap_tools::{Duration, FiveTuple, Flow};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    /// Build a TCP segment
+    fn segment(seq: u32, ack: u32, flags: u8, window: u16, payload_len: usize) -> Vec<u8> {
+        let mut v = vec![0xc0, 0x00, 0x00, 0x50];
+        v.extend_from_slice(&seq.to_be_bytes());
+        v.extend_from_slice(&ack.to_be_bytes());
+        v.extend_from_slice(&[0x50, flags]);
+        v.extend_from_slice(&window.to_be_bytes());
+        v.extend_from_slice(&[0, 0, 0, 0]);
+        v.resize(20 + payload_len, 0);
+        v
+    }
+
+    #[test]
+    fn tcp_health_events() {
+        assert!(seq_before(0xffff_fff0, 0x10));
+        assert!(!seq_before(0x10, 0xffff_fff0));
+
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: 49152,
+            dst_port: 80,
+        };
+        let mut h = FlowHealth::new(&Flow::new(&five_tuple, 0, 0));
+        let ms = |ms: u32| Duration::new(10, ms * 1000);
+        // handshake: 20 ms to the server, 1 ms to the client
+        h.add_segment(ms(0), true, &segment(1000, 0, 0x02, 64240, 0));
+        h.add_segment(ms(20), false, &segment(5000, 1001, 0x12, 65160, 0));
+        h.add_segment(ms(21), true, &segment(1001, 5001, 0x10, 502, 0));
+        // data, then a retransmission after 200 ms
+        h.add_segment(ms(22), true, &segment(1001, 5001, 0x18, 502, 100));
+        h.add_segment(ms(222), true, &segment(1001, 5001, 0x18, 502, 100));
+        // segments received in the wrong order
+        h.add_segment(ms(230), true, &segment(1201, 5001, 0x18, 502, 100));
+        h.add_segment(ms(231), true, &segment(1101, 5001, 0x18, 502, 100));
+        // duplicate ACKs, then zero window
+        h.add_segment(ms(240), false, &segment(5001, 1301, 0x10, 509, 0));
+        h.add_segment(ms(241), false, &segment(5001, 1301, 0x10, 509, 0));
+        h.add_segment(ms(242), false, &segment(5001, 1301, 0x10, 509, 0));
+        h.add_segment(ms(250), false, &segment(5001, 1301, 0x10, 0, 0));
+
+        assert_eq!(h.packets, 11);
+        assert_eq!(h.retransmissions, 1);
+        assert_eq!(h.out_of_order, 1);
+        assert_eq!(h.dup_acks, 2);
+        assert_eq!(h.zero_windows, 1);
+        let rtt = h.syn_synack_rtt.expect("no SYN/SYN+ACK RTT");
+        assert!((rtt - 0.020).abs() < 1e-9);
+        let rtt = h.handshake_rtt.expect("no handshake RTT");
+        assert!((rtt - 0.021).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,400 @@
+//! Plugin computing the throughput (packets and bytes) over time
+//!
+//! Packets are counted in fixed intervals (`interval` in section `[plugin.throughput]`: a number
+//! of seconds, or a duration like `"10s"`, `"1min"` or `"1h"`, default 1 second). Intervals are
+//! aligned on multiples of their duration, and intervals without packets are omitted.
+//!
+//! Each interval has global counters, and counters by IP protocol, by VLAN (outer 802.1Q tag of
+//! ethernet frames), and by subnet (set with `subnets`, a list of networks: a packet is counted
+//! in a subnet if its source or destination address belongs to it).
+//!
+//! Packets are counted once, at their outermost IP layer (tunneled packets are counted in the
+//! protocol of the tunnel), using their original length. Non-IP packets are not counted.
+//!
+//! Results are saved to `throughput.json`, and to `throughput.csv` with one row per interval and
+//! series (`all`, `proto:<name>`, `vlan:<id>` or `subnet:<network>`), to be graphed directly.
+
+use crate::output;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_L2, PLUGIN_L3};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use libpcap_tools::{Config, Packet, ThreeTuple};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+
+const DEFAULT_INTERVAL: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+struct Counter {
+    packets: u64,
+    bytes: u64,
+}
+
+impl Counter {
+    fn add(&mut self, bytes: u32) {
+        self.packets += 1;
+        self.bytes += u64::from(bytes);
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Bucket {
+    #[serde(flatten)]
+    all: Counter,
+    protocols: BTreeMap<&'static str, Counter>,
+    vlans: BTreeMap<u16, Counter>,
+    /// Counters, by index of the subnet
+    #[serde(skip)]
+    subnets: BTreeMap<usize, Counter>,
+}
+
+pub struct Throughput {
+    /// Duration of intervals, in seconds
+    interval: u32,
+    /// Subnets (network and prefix length), and their names
+    subnets: Vec<(IpAddr, u8, String)>,
+    /// Buckets, by start of interval
+    buckets: BTreeMap<u32, Bucket>,
+    /// Index of the last packet seen at layer 2 (tunneled packets are seen several times)
+    last_l2: Option<usize>,
+    /// Index of the last packet seen at layer 3
+    last_l3: Option<usize>,
+}
+
+pub struct ThroughputBuilder;
+
+impl PluginBuilder for ThroughputBuilder {
+    fn name(&self) -> &'static str { "ThroughputBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let plugin_config = config.plugin_config("throughput");
+        let interval = match plugin_config.get_usize("interval") {
+            Some(secs) => secs as u32,
+            None => plugin_config
+                .get("interval")
+                .and_then(parse_interval)
+                .unwrap_or(DEFAULT_INTERVAL),
+        };
+        let subnets = plugin_config
+            .get_strings("subnets")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|s| parse_cidr(s).map(|(network, prefix)| (network, prefix, s.to_owned())))
+            .collect();
+        let plugin = Throughput::new(interval, subnets);
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(2, 0, id)?;
+        registry.register_layer(3, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("throughput");
+        if config.contains("interval") {
+            let interval = match config.get_usize("interval") {
+                Some(secs) => u32::try_from(secs).ok(),
+                None => config.get("interval").and_then(parse_interval),
+            };
+            if !matches!(interval, Some(secs) if secs > 0) {
+                return Err(PluginBuilderError::InvalidConfig(
+                    "throughput: interval must be a positive number of seconds, or a duration \
+                     (for ex. \"10s\", \"1min\")"
+                        .to_owned(),
+                ));
+            }
+        }
+        if config.contains("subnets") {
+            match config.get_strings("subnets") {
+                Some(subnets) if subnets.iter().all(|s| parse_cidr(s).is_some()) => (),
+                _ => {
+                    return Err(PluginBuilderError::InvalidConfig(
+                        "throughput: subnets must be a list of networks (for ex. \"10.0.0.0/8\")"
+                            .to_owned(),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for Throughput {
+    fn name(&self) -> &'static str {
+        "Throughput"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L2 | PLUGIN_L3
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_link<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        _linklayertype: u16,
+        data: &'i [u8],
+    ) -> PluginResult<'i> {
+        if self.last_l2 == Some(packet.pcap_index) {
+            return PluginResult::None;
+        }
+        self.last_l2 = Some(packet.pcap_index);
+        // outer 802.1Q or 802.1ad tag
+        if let (Some(&[0x81, 0x00]) | Some(&[0x88, 0xa8]), Some(tag)) =
+            (data.get(12..14), data.get(14..16))
+        {
+            let vlan = u16::from_be_bytes([tag[0], tag[1]]) & 0x0fff;
+            self.bucket(packet.ts.secs)
+                .vlans
+                .entry(vlan)
+                .or_default()
+                .add(packet.origlen);
+        }
+        PluginResult::None
+    }
+
+    fn handle_layer_network<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        _payload: &'i [u8],
+        t3: &'s ThreeTuple,
+    ) -> PluginResult<'i> {
+        if self.last_l3 == Some(packet.pcap_index) {
+            return PluginResult::None;
+        }
+        self.last_l3 = Some(packet.pcap_index);
+        self.add_packet(packet.ts.secs, packet.origlen, t3);
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "throughput.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        let file =
+            output::create_file(path, "throughput.csv").or(Err("Cannot create output file"))?;
+        let mut writer = BufWriter::new(file);
+        self.write_csv(&mut writer)
+            .and_then(|_| writer.flush())
+            .or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Throughput {
+    fn new(interval: u32, subnets: Vec<(IpAddr, u8, String)>) -> Self {
+        Throughput {
+            interval: interval.max(1),
+            subnets,
+            buckets: BTreeMap::new(),
+            last_l2: None,
+            last_l3: None,
+        }
+    }
+
+    fn bucket(&mut self, secs: u32) -> &mut Bucket {
+        let start = secs - secs % self.interval;
+        self.buckets.entry(start).or_default()
+    }
+
+    fn add_packet(&mut self, secs: u32, bytes: u32, t3: &ThreeTuple) {
+        let subnets: Vec<usize> = self
+            .subnets
+            .iter()
+            .enumerate()
+            .filter(|(_, (network, prefix, _))| {
+                in_network(t3.src, *network, *prefix) || in_network(t3.dst, *network, *prefix)
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+        let bucket = self.bucket(secs);
+        bucket.all.add(bytes);
+        bucket
+            .protocols
+            .entry(proto_name(t3.l4_proto))
+            .or_default()
+            .add(bytes);
+        for idx in subnets {
+            bucket.subnets.entry(idx).or_default().add(bytes);
+        }
+    }
+
+    /// Get the series of a bucket: name and counter
+    fn series<'a>(&'a self, bucket: &'a Bucket) -> impl Iterator<Item = (String, Counter)> + 'a {
+        let protocols = bucket
+            .protocols
+            .iter()
+            .map(|(proto, c)| (format!("proto:{}", proto), *c));
+        let vlans = bucket
+            .vlans
+            .iter()
+            .map(|(vlan, c)| (format!("vlan:{}", vlan), *c));
+        let subnets = bucket
+            .subnets
+            .iter()
+            .map(move |(idx, c)| (format!("subnet:{}", self.subnets[*idx].2), *c));
+        std::iter::once(("all".to_owned(), bucket.all))
+            .chain(protocols)
+            .chain(vlans)
+            .chain(subnets)
+    }
+
+    fn write_csv<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "ts,interval,series,packets,bytes")?;
+        for (start, bucket) in &self.buckets {
+            for (series, c) in self.series(bucket) {
+                writeln!(
+                    w,
+                    "{},{},{},{},{}",
+                    start, self.interval, series, c.packets, c.bytes
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_results_json(&self) -> Value {
+        let buckets: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|(start, bucket)| {
+                let mut v = json!(bucket);
+                v["ts"] = json!(start);
+                let subnets: BTreeMap<_, _> = bucket
+                    .subnets
+                    .iter()
+                    .map(|(idx, c)| (self.subnets[*idx].2.as_str(), c))
+                    .collect();
+                v["subnets"] = json!(subnets);
+                v
+            })
+            .collect();
+        json!({
+            "throughput": {
+                "interval": self.interval,
+                "buckets": buckets,
+            }
+        })
+    }
+}
+
+/// Parse a duration: number of seconds, optionally followed by a unit (`s`, `min` or `m`, `h`)
+fn parse_interval(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(pos);
+    let value: u32 = value.parse().ok()?;
+    let factor = match unit.trim() {
+        "" | "s" => 1,
+        "m" | "min" => 60,
+        "h" => 3600,
+        _ => return None,
+    };
+    value.checked_mul(factor)
+}
+
+fn proto_name(proto: u8) -> &'static str {
+    match proto {
+        1 => "icmp",
+        6 => "tcp",
+        17 => "udp",
+        47 => "gre",
+        50 => "esp",
+        51 => "ah",
+        58 => "icmpv6",
+        89 => "ospf",
+        132 => "sctp",
+        _ => "other",
+    }
+}
+
+fn parse_cidr(s: &str) -> Option<(IpAddr, u8)> {
+    let (ip, prefix) = s.split_once('/')?;
+    let ip: IpAddr = ip.parse().ok()?;
+    let prefix: u8 = prefix.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    if prefix <= max {
+        Some((ip, prefix))
+    } else {
+        None
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cidr, parse_interval, Throughput};
+    use libpcap_tools::ThreeTuple;
+
+    #[test]
+    fn throughput_buckets() {
+        assert_eq!(parse_interval("10"), Some(10));
+        assert_eq!(parse_interval("10s"), Some(10));
+        assert_eq!(parse_interval("1min"), Some(60));
+        assert_eq!(parse_interval("2 h"), Some(7200));
+        assert_eq!(parse_interval("1d"), None);
+
+        let subnet = "10.0.0.0/8";
+        let (network, prefix) = parse_cidr(subnet).expect("invalid subnet");
+        let mut plugin = Throughput::new(10, vec![(network, prefix, subnet.to_owned())]);
+        let t3 = |src: &str, dst: &str, l4_proto| ThreeTuple {
+            src: src.parse().expect("invalid address"),
+            dst: dst.parse().expect("invalid address"),
+            l4_proto,
+        };
+        plugin.add_packet(1001, 100, &t3("10.0.0.1", "192.0.2.1", 6));
+        plugin.add_packet(1009, 60, &t3("192.0.2.1", "10.0.0.1", 6));
+        plugin.add_packet(1010, 80, &t3("192.0.2.1", "192.0.2.2", 17));
+        plugin.bucket(1012).vlans.entry(10).or_default().add(84);
+
+        let results = plugin.get_results_json();
+        let buckets = &results["throughput"]["buckets"];
+        assert_eq!(buckets[0]["ts"], 1000);
+        assert_eq!(buckets[0]["packets"], 2);
+        assert_eq!(buckets[0]["bytes"], 160);
+        assert_eq!(buckets[0]["protocols"]["tcp"]["bytes"], 160);
+        assert_eq!(buckets[0]["subnets"][subnet]["packets"], 2);
+        assert_eq!(buckets[1]["ts"], 1010);
+        assert_eq!(buckets[1]["protocols"]["udp"]["packets"], 1);
+        assert_eq!(buckets[1]["vlans"]["10"]["bytes"], 84);
+
+        let mut csv = Vec::new();
+        plugin.write_csv(&mut csv).expect("could not write CSV");
+        let csv = String::from_utf8(csv).expect("invalid CSV");
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "ts,interval,series,packets,bytes");
+        assert_eq!(lines[1], "1000,10,all,2,160");
+        assert_eq!(lines[2], "1000,10,proto:tcp,2,160");
+        assert_eq!(lines[3], "1000,10,subnet:10.0.0.0/8,2,160");
+        assert_eq!(lines[6], "1010,10,vlan:10,1,84");
+    }
+}
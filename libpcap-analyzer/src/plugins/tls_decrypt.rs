@@ -0,0 +1,946 @@
+//! Plugin decrypting TLS sessions, using the secrets of a key log file
+//!
+//! The key log file (`SSLKEYLOGFILE` format, written by browsers, curl or OpenSSL) is set by
+//! `keylog_file` in section `[plugin.tls_decrypt]`, or by the `SSLKEYLOGFILE` environment
+//! variable. The plugin is disabled if no file is set. Secrets of a session are found using the
+//! random of its ClientHello.
+//!
+//! Records of the reassembled TCP streams are decrypted for TLS 1.2 (`CLIENT_RANDOM` master
+//! secrets) and TLS 1.3 (handshake and traffic secrets, key updates are followed), with the
+//! AES-GCM and ChaCha20-Poly1305 cipher suites. Decrypted application data is emitted as derived
+//! packets of the flow (see `DerivedPacket`), so other plugins (for ex. `http`) analyze it like
+//! cleartext data, with `PacketInfo::derived` set.
+//!
+//! Sessions are saved to `tls-decrypt.json`, with the number of decrypted records and of records
+//! which could not be decrypted (unknown secrets, or invalid data) in each direction.
+
+use crate::app_proto::detect_app_proto;
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{DerivedPacket, Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::ChaCha20Poly1305;
+use fnv::FnvHashMap;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use libpcap_tools::{Config, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Sha256, Sha384};
+use std::any::Any;
+use std::{env, fs, mem};
+
+/// Maximum size of the fragment of a record (ciphertext of TLS 1.2, RFC 5246 section 6.2.3)
+const MAX_RECORD_SIZE: usize = 16384 + 2048;
+/// Maximum size of a handshake message reassembled from several records
+const MAX_HANDSHAKE_SIZE: usize = 256 * 1024;
+
+const RECORD_CHANGE_CIPHER_SPEC: u8 = 20;
+const RECORD_HANDSHAKE: u8 = 22;
+const RECORD_APPLICATION_DATA: u8 = 23;
+const RECORD_HEARTBEAT: u8 = 24;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const HANDSHAKE_FINISHED: u8 = 20;
+const HANDSHAKE_KEY_UPDATE: u8 = 24;
+
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+const TLS12: u16 = 0x0303;
+const TLS13: u16 = 0x0304;
+
+/// Random of a ServerHello which is a HelloRetryRequest (RFC 8446 section 4.1.3)
+const HELLO_RETRY_REQUEST: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// Secrets of a session, by label of the key log file
+type Secrets = FnvHashMap<String, Vec<u8>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Hash {
+    Sha256,
+    Sha384,
+}
+
+impl Hash {
+    fn output_len(self) -> usize {
+        match self {
+            Hash::Sha256 => 32,
+            Hash::Sha384 => 48,
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Hash::Sha256 => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+                    .expect("HMAC accepts keys of any size");
+                data.iter().for_each(|d| mac.update(d));
+                mac.finalize().into_bytes().to_vec()
+            }
+            Hash::Sha384 => {
+                let mut mac = <Hmac<Sha384> as Mac>::new_from_slice(key)
+                    .expect("HMAC accepts keys of any size");
+                data.iter().for_each(|d| mac.update(d));
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// HKDF-Expand-Label of TLS 1.3, with an empty context (RFC 8446 section 7.1)
+    fn expand_label(self, secret: &[u8], label: &str, len: usize) -> Option<Vec<u8>> {
+        let label = format!("tls13 {}", label);
+        let mut info = Vec::with_capacity(4 + label.len());
+        info.extend_from_slice(&(len as u16).to_be_bytes());
+        info.push(label.len() as u8);
+        info.extend_from_slice(label.as_bytes());
+        info.push(0);
+        let mut okm = vec![0; len];
+        match self {
+            Hash::Sha256 => Hkdf::<Sha256>::from_prk(secret)
+                .ok()?
+                .expand(&info, &mut okm),
+            Hash::Sha384 => Hkdf::<Sha384>::from_prk(secret)
+                .ok()?
+                .expand(&info, &mut okm),
+        }
+        .ok()?;
+        Some(okm)
+    }
+
+    /// PRF of TLS 1.2 (RFC 5246 section 5)
+    fn prf(self, secret: &[u8], label: &[u8], seed: &[u8], len: usize) -> Vec<u8> {
+        let seed = [label, seed].concat();
+        let mut a = self.hmac(secret, &[&seed]);
+        let mut out = Vec::with_capacity(len + self.output_len());
+        while out.len() < len {
+            out.extend(self.hmac(secret, &[&a, &seed]));
+            a = self.hmac(secret, &[&a]);
+        }
+        out.truncate(len);
+        out
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn key_len(self) -> usize {
+        match self {
+            Cipher::Aes128Gcm => 16,
+            Cipher::Aes256Gcm | Cipher::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// Length of the implicit part of the nonce in TLS 1.2 (RFC 5288 and RFC 7905)
+    fn fixed_iv_len(self) -> usize {
+        match self {
+            Cipher::Aes128Gcm | Cipher::Aes256Gcm => 4,
+            Cipher::ChaCha20Poly1305 => 12,
+        }
+    }
+
+    fn open(self, key: &[u8], nonce: &[u8], aad: &[u8], msg: &[u8]) -> Option<Vec<u8>> {
+        let nonce = GenericArray::from_slice(nonce);
+        let payload = Payload { msg, aad };
+        match self {
+            Cipher::Aes128Gcm => Aes128Gcm::new_from_slice(key).ok()?.decrypt(nonce, payload),
+            Cipher::Aes256Gcm => Aes256Gcm::new_from_slice(key).ok()?.decrypt(nonce, payload),
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new_from_slice(key)
+                .ok()?
+                .decrypt(nonce, payload),
+        }
+        .ok()
+    }
+}
+
+/// Cipher and hash of a cipher suite, if it is supported
+fn cipher_suite(id: u16) -> Option<(Cipher, Hash)> {
+    match id {
+        // TLS 1.3
+        0x1301 => Some((Cipher::Aes128Gcm, Hash::Sha256)),
+        0x1302 => Some((Cipher::Aes256Gcm, Hash::Sha384)),
+        0x1303 => Some((Cipher::ChaCha20Poly1305, Hash::Sha256)),
+        // TLS 1.2: (EC)DHE and RSA key exchanges
+        0x009c | 0x009e | 0xc02b | 0xc02f => Some((Cipher::Aes128Gcm, Hash::Sha256)),
+        0x009d | 0x009f | 0xc02c | 0xc030 => Some((Cipher::Aes256Gcm, Hash::Sha384)),
+        0xcca8 | 0xcca9 | 0xccaa => Some((Cipher::ChaCha20Poly1305, Hash::Sha256)),
+        _ => None,
+    }
+}
+
+/// Keys protecting the records of one direction
+struct RecordKeys {
+    cipher: Cipher,
+    hash: Hash,
+    key: Vec<u8>,
+    iv: Vec<u8>,
+    /// TLS 1.3 traffic secret of the keys (empty for TLS 1.2), to follow key updates
+    secret: Vec<u8>,
+    /// Sequence number of the next record
+    seq: u64,
+}
+
+impl RecordKeys {
+    /// Derive the keys from a TLS 1.3 traffic secret
+    fn tls13(cipher: Cipher, hash: Hash, secret: &[u8]) -> Option<Self> {
+        Some(RecordKeys {
+            cipher,
+            hash,
+            key: hash.expand_label(secret, "key", cipher.key_len())?,
+            iv: hash.expand_label(secret, "iv", 12)?,
+            secret: secret.to_vec(),
+            seq: 0,
+        })
+    }
+
+    /// Derive the keys of the client and of the server from a TLS 1.2 master secret
+    fn tls12(
+        cipher: Cipher,
+        hash: Hash,
+        master: &[u8],
+        client_random: &[u8],
+        server_random: &[u8],
+    ) -> (Self, Self) {
+        let (key_len, iv_len) = (cipher.key_len(), cipher.fixed_iv_len());
+        // AEAD cipher suites do not use MAC keys
+        let seed = [server_random, client_random].concat();
+        let block = hash.prf(master, b"key expansion", &seed, 2 * (key_len + iv_len));
+        let (keys, ivs) = block.split_at(2 * key_len);
+        let record_keys = |key: &[u8], iv: &[u8]| RecordKeys {
+            cipher,
+            hash,
+            key: key.to_vec(),
+            iv: iv.to_vec(),
+            secret: Vec::new(),
+            seq: 0,
+        };
+        (
+            record_keys(&keys[..key_len], &ivs[..iv_len]),
+            record_keys(&keys[key_len..], &ivs[iv_len..]),
+        )
+    }
+
+    /// Derive the next keys, after a TLS 1.3 KeyUpdate message
+    fn update(&self) -> Option<Self> {
+        let secret = self
+            .hash
+            .expand_label(&self.secret, "traffic upd", self.hash.output_len())?;
+        RecordKeys::tls13(self.cipher, self.hash, &secret)
+    }
+
+    /// XOR the sequence number with the IV (RFC 8446 section 5.3)
+    fn nonce(&self) -> Vec<u8> {
+        let mut nonce = self.iv.clone();
+        let seq = self.seq.to_be_bytes();
+        nonce
+            .iter_mut()
+            .rev()
+            .zip(seq.iter().rev())
+            .for_each(|(n, s)| *n ^= s);
+        nonce
+    }
+
+    /// Decrypt a TLS 1.3 record, and return the content type and the plaintext
+    fn open_tls13(&mut self, header: &[u8], fragment: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let nonce = self.nonce();
+        self.seq += 1;
+        let mut plaintext = self.cipher.open(&self.key, &nonce, header, fragment)?;
+        // remove the padding: the content type is the last non-zero byte
+        let len = plaintext.iter().rposition(|&b| b != 0)?;
+        let content_type = plaintext[len];
+        plaintext.truncate(len);
+        Some((content_type, plaintext))
+    }
+
+    /// Decrypt a TLS 1.2 record
+    fn open_tls12(&mut self, header: &[u8], fragment: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let (nonce, msg) = match self.cipher {
+            Cipher::ChaCha20Poly1305 => (self.nonce(), fragment),
+            Cipher::Aes128Gcm | Cipher::Aes256Gcm => {
+                // the explicit part of the nonce is sent before the ciphertext
+                let explicit = fragment.get(..8)?;
+                ([&self.iv, explicit].concat(), &fragment[8..])
+            }
+        };
+        let len = msg.len().checked_sub(16)? as u16;
+        let mut aad = self.seq.to_be_bytes().to_vec();
+        aad.extend_from_slice(&header[..3]);
+        aad.extend_from_slice(&len.to_be_bytes());
+        self.seq += 1;
+        let plaintext = self.cipher.open(&self.key, &nonce, &aad, msg)?;
+        Some((header[0], plaintext))
+    }
+}
+
+/// State and statistics of one direction of a session
+#[derive(Default, Serialize)]
+struct Direction {
+    decrypted_records: u64,
+    /// Size of the decrypted application data
+    decrypted_bytes: u64,
+    /// Encrypted records which could not be decrypted
+    failed_records: u64,
+    /// Data of an incomplete record
+    #[serde(skip)]
+    buffer: Vec<u8>,
+    /// Data of an incomplete handshake message, fragmented over several records
+    #[serde(skip)]
+    handshake: Vec<u8>,
+    /// Records are encrypted (after the ChangeCipherSpec in TLS 1.2, or the ServerHello in TLS 1.3)
+    #[serde(skip)]
+    encrypted: bool,
+    /// Keys of the records, `None` if the secrets are unknown
+    #[serde(skip)]
+    keys: Option<RecordKeys>,
+}
+
+/// TLS session of a flow
+#[derive(Serialize)]
+struct Session {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    client_random: Option<String>,
+    version: Option<String>,
+    cipher_suite: Option<String>,
+    /// Secrets of the session were found in the key log file
+    keys_found: bool,
+    /// Reason why the decryption stopped, if any
+    error: Option<&'static str>,
+    client: Direction,
+    server: Direction,
+    #[serde(skip)]
+    secrets: Option<Secrets>,
+    #[serde(skip)]
+    suite: Option<(Cipher, Hash)>,
+    #[serde(skip)]
+    tls13: bool,
+    #[serde(skip)]
+    randoms: (Vec<u8>, Vec<u8>),
+}
+
+impl Session {
+    fn new(flow_id: FlowID, five_tuple: &FiveTuple) -> Self {
+        Session {
+            flow_id,
+            five_tuple: five_tuple.clone(),
+            client_random: None,
+            version: None,
+            cipher_suite: None,
+            keys_found: false,
+            error: None,
+            client: Direction::default(),
+            server: Direction::default(),
+            secrets: None,
+            suite: None,
+            tls13: false,
+            randoms: (Vec::new(), Vec::new()),
+        }
+    }
+
+    fn direction(&mut self, to_server: bool) -> &mut Direction {
+        if to_server {
+            &mut self.client
+        } else {
+            &mut self.server
+        }
+    }
+
+    fn secret(&self, label: &str) -> Option<&[u8]> {
+        self.secrets.as_ref()?.get(label).map(|s| s.as_slice())
+    }
+
+    /// Handle data of the stream, and return the decrypted application data
+    fn handle_data(
+        &mut self,
+        data: &[u8],
+        to_server: bool,
+        keylog: &FnvHashMap<Vec<u8>, Secrets>,
+    ) -> Vec<u8> {
+        let mut plaintext = Vec::new();
+        if self.error.is_some() {
+            return plaintext;
+        }
+        let mut buffer = mem::take(&mut self.direction(to_server).buffer);
+        buffer.extend_from_slice(data);
+        let mut offset = 0;
+        while let Some(header) = buffer.get(offset..offset + 5) {
+            let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+            if !(RECORD_CHANGE_CIPHER_SPEC..=RECORD_HEARTBEAT).contains(&header[0])
+                || len > MAX_RECORD_SIZE
+            {
+                self.error = Some("invalid record");
+                return plaintext;
+            }
+            let fragment = match buffer.get(offset + 5..offset + 5 + len) {
+                Some(fragment) => fragment,
+                None => break,
+            };
+            self.handle_record(header, fragment, to_server, keylog, &mut plaintext);
+            if self.error.is_some() {
+                return plaintext;
+            }
+            offset += 5 + len;
+        }
+        buffer.drain(..offset);
+        self.direction(to_server).buffer = buffer;
+        plaintext
+    }
+
+    fn handle_record(
+        &mut self,
+        header: &[u8],
+        fragment: &[u8],
+        to_server: bool,
+        keylog: &FnvHashMap<Vec<u8>, Secrets>,
+        plaintext: &mut Vec<u8>,
+    ) {
+        let content_type = header[0];
+        if !self.direction(to_server).encrypted {
+            match content_type {
+                RECORD_HANDSHAKE => self.handle_handshake(fragment, to_server, keylog),
+                RECORD_CHANGE_CIPHER_SPEC if !self.tls13 => self.change_cipher_spec(to_server),
+                _ => (),
+            }
+            return;
+        }
+        // TLS 1.3 peers send a cleartext ChangeCipherSpec, for compatibility with middleboxes
+        if self.tls13 && content_type != RECORD_APPLICATION_DATA {
+            return;
+        }
+        let tls13 = self.tls13;
+        let dir = self.direction(to_server);
+        let record = match dir.keys.as_mut() {
+            Some(keys) if tls13 => keys.open_tls13(header, fragment),
+            Some(keys) => keys.open_tls12(header, fragment),
+            None => None,
+        };
+        let (content_type, data) = match record {
+            Some(record) => record,
+            None => {
+                dir.failed_records += 1;
+                return;
+            }
+        };
+        dir.decrypted_records += 1;
+        match content_type {
+            RECORD_APPLICATION_DATA => {
+                dir.decrypted_bytes += data.len() as u64;
+                plaintext.extend_from_slice(&data);
+            }
+            RECORD_HANDSHAKE if tls13 => self.handle_encrypted_handshake(&data, to_server),
+            _ => (),
+        }
+    }
+
+    /// Handle cleartext handshake messages (ClientHello and ServerHello)
+    fn handle_handshake(
+        &mut self,
+        data: &[u8],
+        to_server: bool,
+        keylog: &FnvHashMap<Vec<u8>, Secrets>,
+    ) {
+        let messages = self.reassemble_handshake(data, to_server);
+        for (msg_type, body) in handshake_messages(&messages) {
+            match msg_type {
+                HANDSHAKE_CLIENT_HELLO if to_server => {
+                    // legacy_version, random
+                    if let Some(random) = body.get(2..34) {
+                        self.client_random = Some(hex(random));
+                        self.secrets = keylog.get(random).cloned();
+                        self.keys_found = self.secrets.is_some();
+                        self.randoms.0 = random.to_vec();
+                    }
+                }
+                HANDSHAKE_SERVER_HELLO if !to_server => self.server_hello(body),
+                _ => (),
+            }
+        }
+    }
+
+    fn server_hello(&mut self, body: &[u8]) {
+        let (random, suite, version) = match parse_server_hello(body) {
+            Some(server_hello) => server_hello,
+            None => {
+                self.error = Some("invalid ServerHello");
+                return;
+            }
+        };
+        if random == HELLO_RETRY_REQUEST {
+            // the client sends another ClientHello
+            return;
+        }
+        self.randoms.1 = random.to_vec();
+        self.version = Some(match version {
+            TLS12 => "TLS 1.2".to_owned(),
+            TLS13 => "TLS 1.3".to_owned(),
+            v => format!("0x{:04x}", v),
+        });
+        self.cipher_suite = Some(format!("0x{:04x}", suite));
+        self.suite = cipher_suite(suite);
+        if self.suite.is_none() {
+            self.error = Some("unsupported cipher suite");
+            return;
+        }
+        match version {
+            TLS12 => (),
+            TLS13 => {
+                // records following the ServerHello are encrypted with the handshake secrets
+                self.tls13 = true;
+                self.client.encrypted = true;
+                self.server.encrypted = true;
+                self.client.keys = self.tls13_keys("CLIENT_HANDSHAKE_TRAFFIC_SECRET");
+                self.server.keys = self.tls13_keys("SERVER_HANDSHAKE_TRAFFIC_SECRET");
+            }
+            _ => self.error = Some("unsupported version"),
+        }
+    }
+
+    /// Handle encrypted handshake messages (TLS 1.3)
+    fn handle_encrypted_handshake(&mut self, data: &[u8], to_server: bool) {
+        let messages = self.reassemble_handshake(data, to_server);
+        for (msg_type, _) in handshake_messages(&messages) {
+            match msg_type {
+                HANDSHAKE_FINISHED => {
+                    let label = if to_server {
+                        "CLIENT_TRAFFIC_SECRET_0"
+                    } else {
+                        "SERVER_TRAFFIC_SECRET_0"
+                    };
+                    let keys = self.tls13_keys(label);
+                    self.direction(to_server).keys = keys;
+                }
+                HANDSHAKE_KEY_UPDATE => {
+                    let dir = self.direction(to_server);
+                    dir.keys = dir.keys.as_ref().and_then(RecordKeys::update);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Add the handshake data of a record to the incomplete message of the direction, and
+    /// return the complete messages
+    fn reassemble_handshake(&mut self, data: &[u8], to_server: bool) -> Vec<u8> {
+        let dir = self.direction(to_server);
+        let mut messages = mem::take(&mut dir.handshake);
+        messages.extend_from_slice(data);
+        let complete_len = handshake_messages(&messages)
+            .map(|(_, body)| 4 + body.len())
+            .sum();
+        dir.handshake = messages.split_off(complete_len);
+        if let Some(header) = dir.handshake.get(..4) {
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            if len > MAX_HANDSHAKE_SIZE {
+                dir.handshake.clear();
+                self.error = Some("handshake message too large");
+            }
+        }
+        messages
+    }
+
+    /// Start the encryption of a direction (TLS 1.2)
+    fn change_cipher_spec(&mut self, to_server: bool) {
+        let keys = match (self.suite, self.secret("CLIENT_RANDOM")) {
+            (Some((cipher, hash)), Some(master)) => {
+                let (client, server) =
+                    RecordKeys::tls12(cipher, hash, master, &self.randoms.0, &self.randoms.1);
+                Some(if to_server { client } else { server })
+            }
+            _ => None,
+        };
+        let dir = self.direction(to_server);
+        dir.encrypted = true;
+        dir.keys = keys;
+    }
+
+    fn tls13_keys(&self, label: &str) -> Option<RecordKeys> {
+        let (cipher, hash) = self.suite?;
+        RecordKeys::tls13(cipher, hash, self.secret(label)?)
+    }
+}
+
+/// Iterate on the complete handshake messages of `data`, and return their type and body
+///
+/// Iteration stops at the first incomplete message (see `Session::reassemble_handshake`).
+fn handshake_messages(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut data = data;
+    std::iter::from_fn(move || {
+        let header = data.get(..4)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let msg = (header[0], data.get(4..4 + len)?);
+        data = &data[4 + len..];
+        Some(msg)
+    })
+}
+
+fn be16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *data.get(offset)?,
+        *data.get(offset + 1)?,
+    ]))
+}
+
+/// Parse a ServerHello, and return the random, the cipher suite and the negotiated version
+fn parse_server_hello(body: &[u8]) -> Option<(&[u8], u16, u16)> {
+    let mut version = be16(body, 0)?;
+    let random = body.get(2..34)?;
+    let offset = 35 + usize::from(*body.get(34)?);
+    let suite = be16(body, offset)?;
+    // cipher suite and compression method
+    let offset = offset + 3;
+    if let Some(len) = be16(body, offset) {
+        let mut extensions = body.get(offset + 2..offset + 2 + usize::from(len))?;
+        while extensions.len() >= 4 {
+            let len = usize::from(be16(extensions, 2)?);
+            let data = extensions.get(4..4 + len)?;
+            if be16(extensions, 0)? == EXT_SUPPORTED_VERSIONS {
+                version = be16(data, 0)?;
+            }
+            extensions = &extensions[4 + len..];
+        }
+    }
+    Some((random, suite, version))
+}
+
+/// Parse a key log file, and return the secrets by client random
+fn parse_keylog(content: &str) -> FnvHashMap<Vec<u8>, Secrets> {
+    let mut keylog: FnvHashMap<Vec<u8>, Secrets> = FnvHashMap::default();
+    let lines = content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'));
+    for line in lines {
+        let mut fields = line.split_whitespace();
+        let label = fields.next();
+        let random = fields.next().and_then(unhex);
+        let secret = fields.next().and_then(unhex);
+        match (label, random, secret) {
+            (Some(label), Some(random), Some(secret)) => {
+                keylog
+                    .entry(random)
+                    .or_default()
+                    .insert(label.to_owned(), secret);
+            }
+            _ => debug!("tls_decrypt: invalid key log line {:?}", line),
+        }
+    }
+    keylog
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub struct TlsDecrypt {
+    /// Secrets of the key log file, by client random
+    keylog: FnvHashMap<Vec<u8>, Secrets>,
+    /// Sessions of active flows, `None` if the flow is not TLS
+    flows: FnvHashMap<FlowID, Option<Session>>,
+    /// Sessions of destroyed flows
+    sessions: Vec<Session>,
+}
+
+pub struct TlsDecryptBuilder;
+
+impl PluginBuilder for TlsDecryptBuilder {
+    fn name(&self) -> &'static str {
+        "TlsDecryptBuilder"
+    }
+    fn build(
+        &self,
+        registry: &mut PluginRegistry,
+        config: &Config,
+    ) -> Result<(), PluginBuilderError> {
+        let path = match config.plugin_config("tls_decrypt").get("keylog_file") {
+            Some(path) => path.to_owned(),
+            None => match env::var("SSLKEYLOGFILE") {
+                Ok(path) if !path.is_empty() => path,
+                _ => {
+                    debug!("tls_decrypt: no key log file configured");
+                    return Ok(());
+                }
+            },
+        };
+        let content = fs::read_to_string(&path).map_err(|e| {
+            PluginBuilderError::InvalidConfig(format!("tls_decrypt: cannot read {}: {}", path, e))
+        })?;
+        let keylog = parse_keylog(&content);
+        info!(
+            "tls_decrypt: loaded secrets of {} sessions from {}",
+            keylog.len(),
+            path
+        );
+        let plugin = TlsDecrypt {
+            keylog,
+            flows: FnvHashMap::default(),
+            sessions: Vec::new(),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+}
+
+impl Plugin for TlsDecrypt {
+    fn name(&self) -> &'static str {
+        "TlsDecrypt"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            // derived packets are the decrypted data
+            (Some(flow), Some(data))
+                if pinfo.five_tuple.proto == 6 && !data.is_empty() && !pinfo.derived =>
+            {
+                (flow, data)
+            }
+            _ => return PluginResult::None,
+        };
+        let keylog = &self.keylog;
+        let session = self.flows.entry(flow.flow_id).or_insert_with(|| {
+            if pinfo.to_server && detect_app_proto(pinfo.five_tuple, data) == Some("tls") {
+                Some(Session::new(flow.flow_id, &flow.five_tuple))
+            } else {
+                None
+            }
+        });
+        let plaintext = match session {
+            Some(session) => session.handle_data(data, pinfo.to_server, keylog),
+            None => return PluginResult::None,
+        };
+        if plaintext.is_empty() {
+            return PluginResult::None;
+        }
+        PluginResult::Derived(vec![DerivedPacket::L4(pinfo.five_tuple.clone(), plaintext)])
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(Some(session)) = self.flows.remove(&flow.flow_id) {
+            self.sessions.push(session);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "tls-decrypt.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl TlsDecrypt {
+    fn get_results_json(&mut self) -> Value {
+        // flows still active (if called before the end of the analysis)
+        let active = self.flows.values().flatten();
+        let sessions: Vec<_> = self.sessions.iter().chain(active).collect();
+        json!({ "tls-decrypt": sessions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_keylog, unhex, Hash, Session};
+    use libpcap_tools::FiveTuple;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn session() -> Session {
+        let five_tuple = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: 40000,
+            dst_port: 443,
+        };
+        Session::new(0, &five_tuple)
+    }
+
+    fn data(s: &str) -> Vec<u8> {
+        unhex(s).expect("invalid hex")
+    }
+
+    #[test]
+    fn tls_decrypt_prf() {
+        // TLS 1.2 PRF test vector (SHA-256)
+        let secret = data("9bbe436ba940f017b17652849a71db35");
+        let seed = data("a0ba9f936cda311827a6f796ffd5198c");
+        let out = Hash::Sha256.prf(&secret, b"test label", &seed, 100);
+        let expected = data(
+            "e3f229ba727be17b8d122620557cd453c2aab21d07c3d495329b52d4e61edb5a6b301791e90d35c9c9\
+             a46b4e14baf9af0fa022f7077def17abfd3797c0564bab4fbc91666e9def9b97fce34f796789baa480\
+             82d122ee42c5a72e5a5110fff70187347b66",
+        );
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn tls_decrypt_tls12() {
+        let client_random = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let keylog = parse_keylog(&format!(
+            "# comment\nCLIENT_RANDOM {} {}\n",
+            client_random,
+            "55".repeat(48)
+        ));
+        let mut session = session();
+        let client_hello = data(
+            "160301002f0100002b0303000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e\
+             1f000002c02f01000000",
+        );
+        let server_hello = data(
+            "160303002a020000260303202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e\
+             3f00c02f00",
+        );
+        // ChangeCipherSpec, Finished, application data
+        let client = data(
+            "140303000101160303003c0000000000000000238b037377041e869bf4fa07623f9953d60e147e2712\
+             19e97e3d4f4b1bdf654271e7022e0cad5d40bd99b37dcdbcff5b021bf8d5170303002a000000000000\
+             000151291dcc2de8b4c5e3aad6dbdabcb37bea34fd380acab3036e23e4c9a32c17401004",
+        );
+        assert!(session.handle_data(&client_hello, true, &keylog).is_empty());
+        assert!(session
+            .handle_data(&server_hello, false, &keylog)
+            .is_empty());
+        assert!(session.keys_found);
+        // records split over several segments
+        let (start, end) = client.split_at(20);
+        assert!(session.handle_data(start, true, &keylog).is_empty());
+        let plaintext = session.handle_data(end, true, &keylog);
+        assert_eq!(plaintext, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(session.version.as_deref(), Some("TLS 1.2"));
+        assert_eq!(session.client.decrypted_records, 2);
+        assert_eq!(session.client.failed_records, 0);
+    }
+
+    #[test]
+    fn tls_decrypt_tls13() {
+        let client_random = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let secrets = [
+            ("CLIENT_HANDSHAKE_TRAFFIC_SECRET", "11"),
+            ("SERVER_HANDSHAKE_TRAFFIC_SECRET", "22"),
+            ("CLIENT_TRAFFIC_SECRET_0", "33"),
+            ("SERVER_TRAFFIC_SECRET_0", "44"),
+        ];
+        let keylog: String = secrets
+            .iter()
+            .map(|(label, s)| format!("{} {} {}\n", label, client_random, s.repeat(32)))
+            .collect();
+        let keylog = parse_keylog(&keylog);
+        let mut session = session();
+        let client_hello = data(
+            "160301002f0100002b0303000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e\
+             1f000002130101000000",
+        );
+        // ServerHello, ChangeCipherSpec, Finished, application data
+        let server = data(
+            "16030300320200002e0303202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d\
+             3e3f001301000006002b00020304140303000101170303003574a1173234ab199c01c4d542280213b2\
+             42676b7cb30c4082c35657303c11fcaaa5bbf508e6db4ff9232c1381699b38a30c076bb0d617030300\
+             2c1ae5f258ed086524d92c6ca7b0e49cbfd4d060d91e4a3131e25f273e1d5cd37a7908acd5fae01573\
+             573a1bd8",
+        );
+        // ChangeCipherSpec, Finished, application data
+        let client = data(
+            "14030300010117030300351b11b6661da7b9a9cf1435b0da503f4cf9cac08b054453915bdd09a21020\
+             126f053267829190aea5cf536bfabfd1e9a99facbc342a1703030023b1a56efa789f3b86964654ce8e\
+             2e3aca70074ad6f862d320ebb21cb736bcadac0d2a0c",
+        );
+        assert!(session.handle_data(&client_hello, true, &keylog).is_empty());
+        let plaintext = session.handle_data(&server, false, &keylog);
+        assert_eq!(plaintext, b"HTTP/1.1 204 No Content\r\n\r\n");
+        let plaintext = session.handle_data(&client, true, &keylog);
+        assert_eq!(plaintext, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(session.version.as_deref(), Some("TLS 1.3"));
+        assert_eq!(session.cipher_suite.as_deref(), Some("0x1301"));
+        assert_eq!(session.server.decrypted_records, 2);
+        assert_eq!(session.client.failed_records, 0);
+        // unknown secrets
+        let mut session = super::Session::new(1, &session.five_tuple);
+        let empty = parse_keylog("");
+        session.handle_data(&client_hello, true, &empty);
+        assert!(session.handle_data(&server, false, &empty).is_empty());
+        assert!(!session.keys_found);
+        assert_eq!(session.server.failed_records, 2);
+    }
+
+    #[test]
+    fn tls_decrypt_fragmented_handshake() {
+        let client_random = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        let keylog = parse_keylog(&format!(
+            "CLIENT_RANDOM {} {}\n",
+            client_random,
+            "55".repeat(48)
+        ));
+        let record = |content_type: u8, fragment: &[u8]| {
+            let mut v = vec![content_type, 3, 3];
+            v.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            v.extend_from_slice(fragment);
+            v
+        };
+        let mut session = session();
+        let client_hello = data(
+            "0100002b0303000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f000002\
+             c02f01000000",
+        );
+        // ClientHello fragmented over two records, and a heartbeat record
+        let (start, end) = client_hello.split_at(20);
+        assert!(session
+            .handle_data(&record(22, start), true, &keylog)
+            .is_empty());
+        assert!(session.client_random.is_none());
+        let mut records = record(22, end);
+        records.extend_from_slice(&record(
+            24,
+            &data("0100100000000000000000000000000000000000"),
+        ));
+        assert!(session.handle_data(&records, true, &keylog).is_empty());
+        assert_eq!(session.error, None);
+        assert_eq!(session.client_random.as_deref(), Some(client_random));
+        assert!(session.keys_found);
+    }
+}
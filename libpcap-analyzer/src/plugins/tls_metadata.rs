@@ -0,0 +1,622 @@
+//! Plugin extracting metadata and fingerprints of TLS handshakes
+//!
+//! For each TLS flow, the plugin records the SNI, the ALPN protocols offered by the client and
+//! selected by the server, the negotiated version and cipher suite, and the fingerprints of the
+//! client and server hellos:
+//!
+//! - [JA3 and JA3S](https://github.com/salesforce/ja3), as strings and MD5 hashes
+//! - [JA4](https://github.com/FoxIO-LLC/ja4) (client fingerprint)
+//!
+//! Certificates sent by the server are fingerprinted (SHA-256). If `certs_dir` is set in section
+//! `[plugin.tls_metadata]` (relative to the output directory), certificates are also saved in
+//! this directory, in DER format, as `<flow id>-<index>.der`. Certificates are encrypted with
+//! TLS 1.3, so they are only available for previous versions.
+//!
+//! Handshake messages are reassembled if they span several records.
+
+use crate::app_proto::detect_app_proto;
+use crate::output;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginBuilder, PluginBuilderError, PluginResult};
+use crate::plugin::{PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::plugin_registry::PluginRegistry;
+use crate::report::PluginOutput;
+use fnv::FnvHashMap;
+use libpcap_tools::{Config, FiveTuple, Flow, FlowID, Packet};
+use md5::Md5;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::fs;
+use std::path::PathBuf;
+use tls_parser::{
+    parse_tls_message_handshake, parse_tls_raw_record, TlsCipherSuite, TlsMessage,
+    TlsMessageHandshake, TlsRecordType,
+};
+
+/// Maximum size of buffered data (incomplete records and handshake messages), per direction
+const TLS_MAX_BUFFER: usize = 131_072;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// Metadata of a TLS flow
+#[derive(Debug, Serialize)]
+struct FlowMetadata {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    sni: Option<String>,
+    /// Protocols offered by the client
+    alpn: Vec<String>,
+    /// Protocol selected by the server
+    server_alpn: Option<String>,
+    version: Option<String>,
+    cipher: Option<String>,
+    ja3: Option<String>,
+    ja3_hash: Option<String>,
+    ja3s: Option<String>,
+    ja3s_hash: Option<String>,
+    ja4: Option<String>,
+    certificates: Vec<Certificate>,
+}
+
+#[derive(Debug, Serialize)]
+struct Certificate {
+    /// SHA-256 hash of the certificate (DER)
+    sha256: String,
+    len: usize,
+    /// File name, if certificates are saved
+    file: Option<String>,
+}
+
+/// Parsing state of one direction of a flow
+#[derive(Default)]
+struct Direction {
+    /// Incomplete record
+    records: Vec<u8>,
+    /// Incomplete handshake message
+    handshake: Vec<u8>,
+    /// The handshake is encrypted (after ChangeCipherSpec) or parsing failed, stop parsing
+    done: bool,
+}
+
+struct TlsFlow {
+    metadata: FlowMetadata,
+    directions: [Direction; 2],
+}
+
+pub struct TlsMetadata {
+    /// Directory where certificates are saved
+    certs_dir: Option<PathBuf>,
+    /// State of flows, `None` if the flow is not TLS
+    flows: FnvHashMap<FlowID, Option<TlsFlow>>,
+    /// Metadata of destroyed flows
+    results: Vec<FlowMetadata>,
+}
+
+pub struct TlsMetadataBuilder;
+
+impl PluginBuilder for TlsMetadataBuilder {
+    fn name(&self) -> &'static str { "TlsMetadataBuilder" }
+    fn build(&self, registry:&mut PluginRegistry, config:&Config) -> Result<(), PluginBuilderError> {
+        let certs_dir = match config.plugin_config("tls_metadata").get("certs_dir") {
+            Some(dir) => {
+                let mut path = PathBuf::from(output::get_output_dir(config));
+                path.push(dir);
+                fs::create_dir_all(&path).map_err(|e| {
+                    PluginBuilderError::InvalidConfig(format!(
+                        "tls_metadata: cannot create {}: {}",
+                        dir, e
+                    ))
+                })?;
+                Some(path)
+            }
+            None => None,
+        };
+        let plugin = TlsMetadata {
+            certs_dir,
+            flows: FnvHashMap::default(),
+            results: Vec::new(),
+        };
+        let safe_p = build_safeplugin!(plugin);
+        let id = registry.add_plugin(safe_p);
+        registry.register_layer(4, 0, id)?;
+        Ok(())
+    }
+    fn validate_config(&self, config: &Config) -> Result<(), PluginBuilderError> {
+        let config = config.plugin_config("tls_metadata");
+        if config.contains("certs_dir") && config.get("certs_dir").is_none() {
+            return Err(PluginBuilderError::InvalidConfig(
+                "tls_metadata: certs_dir must be a string".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Plugin for TlsMetadata {
+    fn name(&self) -> &'static str {
+        "TlsMetadata"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        _packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if !data.is_empty() => (flow, data),
+            _ => return PluginResult::None,
+        };
+        let state = self.flows.entry(flow.flow_id).or_insert_with(|| {
+            if pinfo.to_server && detect_app_proto(pinfo.five_tuple, data) == Some("tls") {
+                Some(TlsFlow::new(flow))
+            } else {
+                None
+            }
+        });
+        if let Some(state) = state {
+            let certs_dir = self.certs_dir.as_ref();
+            state.update(data, pinfo.to_server, certs_dir);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(Some(state)) = self.flows.remove(&flow.flow_id) {
+            self.results.push(state.metadata);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "tls-metadata.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl TlsMetadata {
+    fn get_results_json(&mut self) -> Value {
+        // flows still active (if called before the end of the analysis)
+        let active = self.flows.values().flatten().map(|state| &state.metadata);
+        let flows: Vec<_> = self.results.iter().chain(active).collect();
+        json!({ "tls-metadata": flows })
+    }
+}
+
+impl TlsFlow {
+    fn new(flow: &Flow) -> Self {
+        TlsFlow {
+            metadata: FlowMetadata {
+                flow_id: flow.flow_id,
+                five_tuple: flow.five_tuple.clone(),
+                sni: None,
+                alpn: Vec::new(),
+                server_alpn: None,
+                version: None,
+                cipher: None,
+                ja3: None,
+                ja3_hash: None,
+                ja3s: None,
+                ja3s_hash: None,
+                ja4: None,
+                certificates: Vec::new(),
+            },
+            directions: Default::default(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8], to_server: bool, certs_dir: Option<&PathBuf>) {
+        let dir = &mut self.directions[if to_server { 0 } else { 1 }];
+        if dir.done {
+            return;
+        }
+        if dir.records.len() + data.len() > TLS_MAX_BUFFER {
+            debug!(
+                "tls_metadata: buffer too large for flow 0x{:x}",
+                self.metadata.flow_id
+            );
+            dir.done = true;
+            return;
+        }
+        dir.records.extend_from_slice(data);
+        let mut messages = Vec::new();
+        let mut consumed = 0;
+        while !dir.done {
+            let (rem, record) = match parse_tls_raw_record(&dir.records[consumed..]) {
+                Ok(r) => r,
+                Err(e) if e.is_incomplete() => break,
+                Err(_) => {
+                    debug!(
+                        "tls_metadata: invalid record in flow 0x{:x}",
+                        self.metadata.flow_id
+                    );
+                    dir.done = true;
+                    break;
+                }
+            };
+            consumed = dir.records.len() - rem.len();
+            let record_type = record.hdr.record_type;
+            if record_type == TlsRecordType::Handshake {
+                dir.handshake.extend_from_slice(record.data);
+                // extract complete handshake messages
+                while dir.handshake.len() >= 4 {
+                    let len = (usize::from(dir.handshake[1]) << 16)
+                        | (usize::from(dir.handshake[2]) << 8)
+                        | usize::from(dir.handshake[3]);
+                    if dir.handshake.len() < 4 + len {
+                        break;
+                    }
+                    messages.push(dir.handshake.drain(..4 + len).collect::<Vec<_>>());
+                }
+                if dir.handshake.len() > TLS_MAX_BUFFER {
+                    dir.done = true;
+                }
+            } else if record_type == TlsRecordType::ChangeCipherSpec
+                || record_type == TlsRecordType::ApplicationData
+            {
+                dir.done = true;
+            }
+        }
+        dir.records.drain(..consumed);
+        if dir.done {
+            *dir = Direction {
+                done: true,
+                ..Direction::default()
+            };
+        }
+        for msg in messages {
+            if let Ok((_, TlsMessage::Handshake(hs))) = parse_tls_message_handshake(&msg) {
+                self.handle_handshake(&hs, certs_dir);
+            }
+        }
+    }
+
+    fn handle_handshake(&mut self, hs: &TlsMessageHandshake, certs_dir: Option<&PathBuf>) {
+        let md = &mut self.metadata;
+        match hs {
+            TlsMessageHandshake::ClientHello(ch) => {
+                let ciphers: Vec<u16> = ch.ciphers.iter().map(|c| c.0).collect();
+                let hello = ClientHello::new(ch.version.0, ciphers, ch.ext.unwrap_or_default());
+                md.sni = hello.sni.clone();
+                md.alpn = hello.alpn.clone();
+                md.ja3 = Some(hello.ja3());
+                md.ja3_hash = Some(hello.ja3_hash());
+                md.ja4 = Some(hello.ja4('t'));
+            }
+            TlsMessageHandshake::ServerHello(sh) => {
+                let extensions = parse_extensions(sh.ext.unwrap_or_default());
+                // TLS 1.3 servers announce the version in an extension
+                let version = extensions
+                    .iter()
+                    .find(|(t, _)| *t == EXT_SUPPORTED_VERSIONS)
+                    .and_then(|(_, data)| be_u16(data, 0))
+                    .unwrap_or(sh.version.0);
+                md.version = Some(tls_version_name(version));
+                md.cipher = Some(match TlsCipherSuite::from_id(sh.cipher.0) {
+                    Some(cipher) => cipher.name.to_owned(),
+                    None => format!("0x{:04x}", sh.cipher.0),
+                });
+                md.server_alpn = extensions
+                    .iter()
+                    .find(|(t, _)| *t == EXT_ALPN)
+                    .and_then(|(_, data)| parse_alpn(data).into_iter().next());
+                let ext_types: Vec<u16> = extensions.iter().map(|(t, _)| *t).collect();
+                let ja3s = format!(
+                    "{},{},{}",
+                    sh.version.0,
+                    sh.cipher.0,
+                    join(&ext_types, "-", |t| t.to_string())
+                );
+                md.ja3s_hash = Some(hex(&Md5::digest(ja3s.as_bytes())));
+                md.ja3s = Some(ja3s);
+            }
+            TlsMessageHandshake::Certificate(certs) => {
+                for cert in &certs.cert_chain {
+                    let index = md.certificates.len();
+                    let file = certs_dir.and_then(|dir| {
+                        let filename = format!("{:016x}-{}.der", md.flow_id, index);
+                        match fs::write(dir.join(&filename), cert.data) {
+                            Ok(()) => Some(filename),
+                            Err(e) => {
+                                warn!("tls_metadata: cannot save certificate: {}", e);
+                                None
+                            }
+                        }
+                    });
+                    md.certificates.push(Certificate {
+                        sha256: hex(&Sha256::digest(cert.data)),
+                        len: cert.data.len(),
+                        file,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Fields of a client hello used for fingerprints
+#[derive(Debug, Default)]
+pub(super) struct ClientHello {
+    version: u16,
+    /// Cipher suites, without GREASE values
+    ciphers: Vec<u16>,
+    /// Extension types, in order, without GREASE values
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    /// Supported versions, without GREASE values
+    versions: Vec<u16>,
+    pub(super) sni: Option<String>,
+    pub(super) alpn: Vec<String>,
+}
+
+impl ClientHello {
+    pub(super) fn new(version: u16, ciphers: Vec<u16>, ext: &[u8]) -> Self {
+        let mut hello = ClientHello {
+            version,
+            ciphers: ciphers.into_iter().filter(|&c| !is_grease(c)).collect(),
+            ..ClientHello::default()
+        };
+        for (ext_type, data) in parse_extensions(ext) {
+            if is_grease(ext_type) {
+                continue;
+            }
+            hello.extensions.push(ext_type);
+            match ext_type {
+                EXT_SERVER_NAME => {
+                    // list length, name type (0 for host names), name length, name
+                    let name = be_u16(data, 3).and_then(|len| data.get(5..5 + usize::from(len)));
+                    hello.sni = name.map(|n| String::from_utf8_lossy(n).into_owned());
+                }
+                EXT_SUPPORTED_GROUPS => hello.groups = u16_list(data.get(2..)),
+                EXT_EC_POINT_FORMATS => {
+                    hello.point_formats = data.get(1..).unwrap_or_default().to_vec();
+                }
+                EXT_SIGNATURE_ALGORITHMS => hello.signature_algorithms = u16_list(data.get(2..)),
+                EXT_ALPN => hello.alpn = parse_alpn(data),
+                EXT_SUPPORTED_VERSIONS => hello.versions = u16_list(data.get(1..)),
+                _ => (),
+            }
+        }
+        hello.groups.retain(|&g| !is_grease(g));
+        hello.versions.retain(|&v| !is_grease(v));
+        hello
+    }
+
+    /// Parse a client hello, from a record containing the complete message
+    #[cfg(any(feature = "plugin_intel", feature = "plugin_os_fingerprint"))]
+    pub(super) fn from_record(data: &[u8]) -> Option<Self> {
+        let (_, record) = parse_tls_raw_record(data).ok()?;
+        if record.hdr.record_type != TlsRecordType::Handshake {
+            return None;
+        }
+        match parse_tls_message_handshake(record.data) {
+            Ok((_, TlsMessage::Handshake(TlsMessageHandshake::ClientHello(ch)))) => {
+                let ciphers = ch.ciphers.iter().map(|c| c.0).collect();
+                Some(ClientHello::new(
+                    ch.version.0,
+                    ciphers,
+                    ch.ext.unwrap_or_default(),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn ja3(&self) -> String {
+        let dec = |v: &u16| v.to_string();
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(&self.ciphers, "-", dec),
+            join(&self.extensions, "-", dec),
+            join(&self.groups, "-", dec),
+            join(&self.point_formats, "-", |v| v.to_string())
+        )
+    }
+
+    /// Compute the JA3 hash (MD5 of the JA3 string)
+    pub(super) fn ja3_hash(&self) -> String {
+        hex(&Md5::digest(self.ja3().as_bytes()))
+    }
+
+    /// Compute the JA4 fingerprint, `transport` is `t` for TCP and `q` for QUIC
+    pub(super) fn ja4(&self, transport: char) -> String {
+        let version = self.versions.iter().max().copied().unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            0x0002 => "s2",
+            0xfeff => "d1",
+            0xfefd => "d2",
+            0xfefc => "d3",
+            _ => "00",
+        };
+        let sni = if self.extensions.contains(&EXT_SERVER_NAME) {
+            'd'
+        } else {
+            'i'
+        };
+        let alpn = match self.alpn.first().map(|a| a.as_bytes()) {
+            Some(name) if !name.is_empty() => {
+                let (first, last) = (name[0], name[name.len() - 1]);
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    let (first, last) = (format!("{:02x}", first), format!("{:02x}", last));
+                    format!("{}{}", &first[..1], &last[1..])
+                }
+            }
+            _ => "00".to_owned(),
+        };
+        let hex4 = |v: &u16| format!("{:04x}", v);
+        let mut ciphers = self.ciphers.clone();
+        ciphers.sort_unstable();
+        let mut extensions: Vec<u16> = self
+            .extensions
+            .iter()
+            .copied()
+            .filter(|&e| e != EXT_SERVER_NAME && e != EXT_ALPN)
+            .collect();
+        extensions.sort_unstable();
+        let mut ext_string = join(&extensions, ",", hex4);
+        if !self.signature_algorithms.is_empty() {
+            ext_string.push('_');
+            ext_string.push_str(&join(&self.signature_algorithms, ",", hex4));
+        }
+        format!(
+            "{}{}{}{:02}{:02}{}_{}_{}",
+            transport,
+            version,
+            sni,
+            self.ciphers.len().min(99),
+            self.extensions.len().min(99),
+            alpn,
+            ja4_hash(&join(&ciphers, ",", hex4), ciphers.is_empty()),
+            ja4_hash(&ext_string, extensions.is_empty())
+        )
+    }
+}
+
+/// First 12 hex characters of the SHA-256 hash of `s`, or zeros if the list is empty
+fn ja4_hash(s: &str, empty: bool) -> String {
+    if empty {
+        "000000000000".to_owned()
+    } else {
+        hex(&Sha256::digest(s.as_bytes()))[..12].to_owned()
+    }
+}
+
+/// GREASE values (RFC 8701) are ignored in fingerprints
+fn is_grease(v: u16) -> bool {
+    v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn u16_list(data: Option<&[u8]>) -> Vec<u16> {
+    data.unwrap_or_default()
+        .chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .collect()
+}
+
+/// Split extensions into (type, data) pairs
+fn parse_extensions(mut ext: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut extensions = Vec::new();
+    while let (Some(ext_type), Some(len)) = (be_u16(ext, 0), be_u16(ext, 2)) {
+        let end = 4 + usize::from(len);
+        match ext.get(4..end) {
+            Some(data) => extensions.push((ext_type, data)),
+            None => break,
+        }
+        ext = &ext[end..];
+    }
+    extensions
+}
+
+/// Parse the protocol name list of an ALPN extension
+fn parse_alpn(data: &[u8]) -> Vec<String> {
+    let mut protocols = Vec::new();
+    let mut list = data.get(2..).unwrap_or_default();
+    while let Some((&len, rest)) = list.split_first() {
+        match rest.get(..usize::from(len)) {
+            Some(name) => protocols.push(String::from_utf8_lossy(name).into_owned()),
+            None => break,
+        }
+        list = &rest[usize::from(len)..];
+    }
+    protocols
+}
+
+fn join<T, F: Fn(&T) -> String>(values: &[T], sep: &str, f: F) -> String {
+    values.iter().map(f).collect::<Vec<_>>().join(sep)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn tls_version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".to_owned(),
+        0x0301 => "TLS 1.0".to_owned(),
+        0x0302 => "TLS 1.1".to_owned(),
+        0x0303 => "TLS 1.2".to_owned(),
+        0x0304 => "TLS 1.3".to_owned(),
+        v => format!("0x{:04x}", v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex, is_grease, ClientHello};
+    use md5::{Digest, Md5};
+
+    #[test]
+    fn tls_fingerprints() {
+        assert!(is_grease(0x0a0a) && is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a) && !is_grease(0x1301));
+        let mut ext = Vec::new();
+        // GREASE extension (ignored)
+        ext.extend_from_slice(&[0x3a, 0x3a, 0x00, 0x00]);
+        // SNI: example.com
+        ext.extend_from_slice(&[0x00, 0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b]);
+        ext.extend_from_slice(b"example.com");
+        // supported groups: GREASE, x25519, secp256r1
+        ext.extend_from_slice(&[
+            0x00, 0x0a, 0x00, 0x08, 0x00, 0x06, 0x2a, 0x2a, 0x00, 0x1d, 0x00, 0x17,
+        ]);
+        // EC point formats: uncompressed
+        ext.extend_from_slice(&[0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]);
+        // signature algorithms: ecdsa_secp256r1_sha256, rsa_pss_rsae_sha256
+        ext.extend_from_slice(&[0x00, 0x0d, 0x00, 0x06, 0x00, 0x04, 0x04, 0x03, 0x08, 0x04]);
+        // ALPN: h2, http/1.1
+        ext.extend_from_slice(&[0x00, 0x10, 0x00, 0x0e, 0x00, 0x0c, 0x02]);
+        ext.extend_from_slice(b"h2\x08http/1.1");
+        // supported versions: TLS 1.3, TLS 1.2
+        ext.extend_from_slice(&[0x00, 0x2b, 0x00, 0x05, 0x04, 0x03, 0x04, 0x03, 0x03]);
+        let hello = ClientHello::new(0x0303, vec![0x5a5a, 0x1301, 0xc02b], &ext);
+        assert_eq!(hello.sni.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, vec!["h2".to_owned(), "http/1.1".to_owned()]);
+        let ja3 = "771,4865-49195,0-10-11-13-16-43,29-23,0";
+        assert_eq!(hello.ja3(), ja3);
+        assert_eq!(hex(&Md5::digest(ja3.as_bytes())).len(), 32);
+        let ja4 = hello.ja4('t');
+        assert!(ja4.starts_with("t13d0206h2_"), "{}", ja4);
+        assert_eq!(ja4.len(), 36);
+    }
+}
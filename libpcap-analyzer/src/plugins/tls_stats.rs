@@ -1,30 +1,39 @@
-use crate::packet_info::PacketInfo;
+use crate::packet_info::{segment_name, Encapsulation, PacketInfo};
 use crate::plugin::{Plugin, PluginResult, PLUGIN_L4};
+use crate::report::PluginOutput;
 use crate::{output, plugin_builder};
-use libpcap_tools::{FiveTuple, Packet};
+use libpcap_tools::{Config, FiveTuple, Packet};
 use rusticata::tls::*;
 use rusticata::tls_parser::TlsVersion;
 use rusticata::*;
-use serde_json::{self, json, Value};
+use serde_json::{self, json, Map, Value};
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 struct Stats<'a> {
     parser: TlsParser<'a>,
     bypass: bool,
+    /// Encapsulation of the first packet of the conversation
+    encapsulation: Option<Encapsulation>,
 }
 
 /// Display statistics on SSL/TLS connections
 ///
 /// Note: Empty fields (version, cipher, etc.) means that the connection is either
 /// incomplete (alert during handshake) or that the handshake was not seen.
+///
+/// If `segment_stats` is set in the global configuration, statistics are also computed for each
+/// encapsulation (VLAN, MPLS label, VXLAN or GRE key), in `tls-stats-segments`.
 #[derive(Default)]
 pub struct TlsStats<'a> {
     tls_conversations: HashMap<FiveTuple, Stats<'a>>,
+    segment: bool,
 }
 
-// plugin_builder!(TlsStats, TlsStatsBuilder);
-plugin_builder!(TlsStats, TlsStatsBuilder);
+plugin_builder!(TlsStats, TlsStatsBuilder, |config: &Config| TlsStats {
+    segment: config.get_bool("segment_stats").unwrap_or(false),
+    ..TlsStats::default()
+});
 
 impl<'a> Plugin for TlsStats<'a> {
     fn name(&self) -> &'static str {
@@ -33,6 +42,9 @@ impl<'a> Plugin for TlsStats<'a> {
     fn plugin_type(&self) -> u16 {
         PLUGIN_L4
     }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
     fn handle_layer_transport<'s, 'i>(
         &'s mut self,
         _packet: &'s Packet,
@@ -60,7 +72,7 @@ impl<'a> Plugin for TlsStats<'a> {
                 return PluginResult::None;
             }
             // could be TLS. instantiate parser and add flow to tracked conversations
-            let mut stats = Stats::new();
+            let mut stats = Stats::new(pinfo.encapsulation);
             stats.update(data, pinfo);
             self.tls_conversations
                 .insert(flow.five_tuple.clone(), stats);
@@ -73,6 +85,10 @@ impl<'a> Plugin for TlsStats<'a> {
         Some(Box::new(v))
     }
 
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
     fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
         let results = self.get_results_json();
         // save data to file
@@ -100,6 +116,12 @@ impl<'a> TlsStats<'a> {
                     "cipher": stats.parser.cipher.map(|c| c.name).unwrap_or(""),
                 });
                 if let Some(o) = js.as_object_mut() {
+                    if self.segment {
+                        o.insert(
+                            "encapsulation".to_owned(),
+                            json!(segment_name(stats.encapsulation)),
+                        );
+                    }
                     if let Some(ja3) = &stats.parser.ja3 {
                         o.insert("ja3".to_owned(), json!(ja3));
                     }
@@ -111,60 +133,83 @@ impl<'a> TlsStats<'a> {
             })
             .collect();
         map.insert("tls-stats-conversations".into(), json!(conversations));
-        //
-        // SSL/TLS ports
-        let mut m = HashMap::new();
-        for t5 in self.tls_conversations.keys() {
-            let count_ref = m.entry(t5.dst_port).or_insert(0);
-            *count_ref += 1;
-        }
-        map.insert("tls-stats-tls-ports".into(), json!(m));
-        //
-        // SSL record version
-        let mut m = HashMap::new();
-        for stats in self.tls_conversations.values() {
-            let count_ref = m.entry(stats.parser.ssl_record_version.0).or_insert(0);
-            *count_ref += 1;
-        }
-        let m2: HashMap<_, _> = m
-            .iter()
-            .map(|(k, v)| (TlsVersion(*k).to_string(), v))
-            .collect();
-        map.insert("tls-stats-ssl-record-version".into(), json!(m2));
-        //
-        // Client-Hello version
-        let mut m = HashMap::new();
-        for stats in self.tls_conversations.values() {
-            let count_ref = m.entry(stats.parser.client_version.0).or_insert(0);
-            *count_ref += 1;
-        }
-        let m2: HashMap<_, _> = m
-            .iter()
-            .map(|(k, v)| (TlsVersion(*k).to_string(), v))
-            .collect();
-        map.insert("tls-stats-client-hello-version".into(), json!(m2));
-        //
-        // Ciphers
-        let mut m = HashMap::new();
-        for stats in self.tls_conversations.values() {
-            let cipher = match stats.parser.cipher {
-                Some(ciphersuite) => ciphersuite.name,
-                None => "<None>",
-            };
-            let count_ref = m.entry(cipher).or_insert(0);
-            *count_ref += 1;
+        let all: Vec<_> = self.tls_conversations.iter().collect();
+        map.extend(aggregate_stats(&all));
+        if self.segment {
+            let mut segments = BTreeMap::new();
+            for (t5, stats) in &self.tls_conversations {
+                segments
+                    .entry(stats.encapsulation)
+                    .or_insert_with(Vec::new)
+                    .push((t5, stats));
+            }
+            let segments: Map<_, _> = segments
+                .into_iter()
+                .map(|(encap, convs)| (segment_name(encap), Value::Object(aggregate_stats(&convs))))
+                .collect();
+            map.insert("tls-stats-segments".into(), Value::Object(segments));
         }
-        map.insert("tls-stats-ciphers".into(), json!(m));
         Value::Object(map)
     }
 }
 
+/// Aggregate statistics of conversations: ports, versions and ciphers
+fn aggregate_stats(conversations: &[(&FiveTuple, &Stats)]) -> Map<String, Value> {
+    let mut map = Map::new();
+    //
+    // SSL/TLS ports
+    let mut m = HashMap::new();
+    for (t5, _) in conversations {
+        let count_ref = m.entry(t5.dst_port).or_insert(0);
+        *count_ref += 1;
+    }
+    map.insert("tls-stats-tls-ports".into(), json!(m));
+    //
+    // SSL record version
+    let mut m = HashMap::new();
+    for (_, stats) in conversations {
+        let count_ref = m.entry(stats.parser.ssl_record_version.0).or_insert(0);
+        *count_ref += 1;
+    }
+    let m2: HashMap<_, _> = m
+        .iter()
+        .map(|(k, v)| (TlsVersion(*k).to_string(), v))
+        .collect();
+    map.insert("tls-stats-ssl-record-version".into(), json!(m2));
+    //
+    // Client-Hello version
+    let mut m = HashMap::new();
+    for (_, stats) in conversations {
+        let count_ref = m.entry(stats.parser.client_version.0).or_insert(0);
+        *count_ref += 1;
+    }
+    let m2: HashMap<_, _> = m
+        .iter()
+        .map(|(k, v)| (TlsVersion(*k).to_string(), v))
+        .collect();
+    map.insert("tls-stats-client-hello-version".into(), json!(m2));
+    //
+    // Ciphers
+    let mut m = HashMap::new();
+    for (_, stats) in conversations {
+        let cipher = match stats.parser.cipher {
+            Some(ciphersuite) => ciphersuite.name,
+            None => "<None>",
+        };
+        let count_ref = m.entry(cipher).or_insert(0);
+        *count_ref += 1;
+    }
+    map.insert("tls-stats-ciphers".into(), json!(m));
+    map
+}
+
 impl<'a> Stats<'a> {
-    fn new() -> Stats<'a> {
+    fn new(encapsulation: Option<Encapsulation>) -> Stats<'a> {
         let parser = TlsParser::new(b"tls-stats");
         Stats {
             parser,
             bypass: false,
+            encapsulation,
         }
     }
 
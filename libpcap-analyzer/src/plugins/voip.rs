@@ -0,0 +1,479 @@
+//! Plugin analyzing VoIP calls (SIP signaling and RTP media)
+//!
+//! SIP messages (port 5060) are grouped in calls using the `Call-ID` header. The SDP bodies of
+//! the INVITE and its responses give the codecs and the media addresses (IP and port) of both
+//! sides, which are used to find the RTP streams of the call.
+//!
+//! For each call, the plugin reports the start (INVITE), answer and end (BYE or CANCEL), the
+//! final status of the INVITE, and for each RTP stream (SSRC): packets, lost packets (using the
+//! sequence numbers) and interarrival jitter (RFC 3550 section 6.4.1).
+//!
+//! SIP over TCP is supported only if each segment contains one message.
+
+use super::dns_stats::duration_secs;
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, Packet};
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+const SIP_PORT: u16 = 5060;
+
+/// RTP stream, identified by its SSRC
+struct RtpStream {
+    ssrc: u32,
+    src: String,
+    dst: String,
+    payload_type: u8,
+    packets: u64,
+    /// First sequence number
+    base_seq: u32,
+    /// Highest sequence number, extended with the number of wraparounds
+    max_seq: u32,
+    /// Arrival time and RTP timestamp of the last packet
+    last: Option<(Duration, u32)>,
+    /// Interarrival jitter, in timestamp units
+    jitter: f64,
+}
+
+struct Call {
+    call_id: String,
+    from: Option<String>,
+    to: Option<String>,
+    start: Duration,
+    answer: Option<Duration>,
+    end: Option<Duration>,
+    /// Final status of the INVITE
+    status: Option<u16>,
+    /// Codecs (name and clock rate), by payload type
+    codecs: BTreeMap<u8, (String, u32)>,
+    media: Vec<(IpAddr, u16)>,
+    streams: Vec<RtpStream>,
+}
+
+#[derive(Default)]
+pub struct Voip {
+    calls: Vec<Call>,
+    /// Index of calls, by Call-ID
+    call_ids: FnvHashMap<String, usize>,
+    /// Index of calls, by media address
+    media: FnvHashMap<(IpAddr, u16), usize>,
+}
+
+plugin_builder!(Voip, VoipBuilder);
+
+impl Plugin for Voip {
+    fn name(&self) -> &'static str {
+        "Voip"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let data = match pinfo.l4_payload {
+            Some(data) if !data.is_empty() => data,
+            _ => return PluginResult::None,
+        };
+        let t5 = pinfo.five_tuple;
+        if t5.src_port == SIP_PORT || t5.dst_port == SIP_PORT {
+            if t5.proto == 6 || t5.proto == 17 {
+                self.handle_sip(packet.ts, data);
+            }
+        } else if t5.proto == 17 {
+            let call = self
+                .media
+                .get(&(t5.dst, t5.dst_port))
+                .or_else(|| self.media.get(&(t5.src, t5.src_port)));
+            if let Some(&idx) = call {
+                let src = format_addr(t5.src, t5.src_port);
+                let dst = format_addr(t5.dst, t5.dst_port);
+                self.calls[idx].handle_rtp(packet.ts, data, src, dst);
+            }
+        }
+        PluginResult::None
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file = output::create_file(path, "voip.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl Voip {
+    fn handle_sip(&mut self, ts: Duration, data: &[u8]) {
+        let msg = match SipMessage::parse(data) {
+            Some(msg) => msg,
+            None => return,
+        };
+        let call_id = match msg.header("call-id", "i") {
+            Some(call_id) => call_id,
+            None => return,
+        };
+        let idx = match self.call_ids.get(call_id) {
+            Some(&idx) => idx,
+            // calls start with an INVITE
+            None if msg.method == Some("INVITE") => {
+                self.calls.push(Call {
+                    call_id: call_id.to_owned(),
+                    from: msg.header("from", "f").map(str::to_owned),
+                    to: msg.header("to", "t").map(str::to_owned),
+                    start: ts,
+                    answer: None,
+                    end: None,
+                    status: None,
+                    codecs: BTreeMap::new(),
+                    media: Vec::new(),
+                    streams: Vec::new(),
+                });
+                self.call_ids
+                    .insert(call_id.to_owned(), self.calls.len() - 1);
+                self.calls.len() - 1
+            }
+            None => return,
+        };
+        let call = &mut self.calls[idx];
+        match (msg.method, msg.status) {
+            (Some("BYE"), _) | (Some("CANCEL"), _) => {
+                call.end.get_or_insert(ts);
+            }
+            (None, Some(status)) if msg.cseq_method() == Some("INVITE") => {
+                if status >= 200 && call.status.is_none() {
+                    call.status = Some(status);
+                    if status < 300 {
+                        call.answer = Some(ts);
+                    }
+                }
+            }
+            _ => (),
+        }
+        if let Some(sdp) = msg.body.filter(|_| msg.is_sdp()) {
+            for addr in call.parse_sdp(sdp) {
+                self.media.insert(addr, idx);
+            }
+        }
+    }
+
+    fn get_results_json(&self) -> Value {
+        let calls: Vec<_> = self.calls.iter().map(Call::to_json).collect();
+        json!({ "voip-calls": calls })
+    }
+}
+
+impl Call {
+    /// Parse a SDP body, and return the new media addresses
+    fn parse_sdp(&mut self, sdp: &str) -> Vec<(IpAddr, u16)> {
+        let mut connection = None;
+        let mut new_media = Vec::new();
+        for line in sdp.lines() {
+            let line = line.trim_end();
+            if let Some(c) = line.strip_prefix("c=") {
+                // c=IN IP4 192.0.2.1
+                connection = c.split_whitespace().nth(2).and_then(|a| a.parse().ok());
+            } else if let Some(m) = line.strip_prefix("m=") {
+                // m=audio 49170 RTP/AVP 0 8 101
+                let fields: Vec<_> = m.split_whitespace().collect();
+                if let (Some(port), Some(ip)) =
+                    (fields.get(1).and_then(|p| p.parse().ok()), connection)
+                {
+                    if !self.media.contains(&(ip, port)) {
+                        self.media.push((ip, port));
+                        new_media.push((ip, port));
+                    }
+                }
+                for pt in fields.iter().skip(3).filter_map(|pt| pt.parse().ok()) {
+                    if let Some(codec) = static_payload_type(pt) {
+                        self.codecs.entry(pt).or_insert(codec);
+                    }
+                }
+            } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+                // a=rtpmap:0 PCMU/8000
+                let mut fields = rtpmap.splitn(2, ' ');
+                let pt = fields.next().and_then(|pt| pt.parse().ok());
+                let mut encoding = fields.next().unwrap_or_default().split('/');
+                let name = encoding.next().unwrap_or_default().to_owned();
+                let rate = encoding.next().and_then(|r| r.parse().ok()).unwrap_or(8000);
+                if let Some(pt) = pt {
+                    self.codecs.insert(pt, (name, rate));
+                }
+            }
+        }
+        new_media
+    }
+
+    fn handle_rtp(&mut self, ts: Duration, data: &[u8], src: String, dst: String) {
+        // RTP version 2, fixed header of 12 bytes
+        if data.len() < 12 || data[0] >> 6 != 2 {
+            return;
+        }
+        let payload_type = data[1] & 0x7f;
+        // RTCP packets may use the same ports (RFC 5761)
+        if (72..=76).contains(&payload_type) {
+            return;
+        }
+        let seq = u16::from_be_bytes([data[2], data[3]]);
+        let rtp_ts = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        let clock_rate = self.codecs.get(&payload_type).map_or(8000, |c| c.1);
+        let stream = match self.streams.iter().position(|s| s.ssrc == ssrc) {
+            Some(i) => &mut self.streams[i],
+            None => {
+                self.streams.push(RtpStream {
+                    ssrc,
+                    src,
+                    dst,
+                    payload_type,
+                    packets: 0,
+                    base_seq: u32::from(seq),
+                    max_seq: u32::from(seq),
+                    last: None,
+                    jitter: 0.0,
+                });
+                self.streams.last_mut().expect("stream was just added")
+            }
+        };
+        stream.packets += 1;
+        let delta = seq.wrapping_sub(stream.max_seq as u16);
+        // ignore duplicated or reordered packets
+        if delta < 0x8000 {
+            stream.max_seq += u32::from(delta);
+        }
+        if let Some((last_arrival, last_rtp_ts)) = stream.last {
+            if ts >= last_arrival {
+                let arrival = duration_secs(ts - last_arrival) * f64::from(clock_rate);
+                let d = (arrival - f64::from(rtp_ts.wrapping_sub(last_rtp_ts) as i32)).abs();
+                stream.jitter += (d - stream.jitter) / 16.0;
+            }
+        }
+        stream.last = Some((ts, rtp_ts));
+    }
+
+    fn to_json(&self) -> Value {
+        let streams: Vec<_> = self
+            .streams
+            .iter()
+            .map(|s| {
+                let expected = u64::from(s.max_seq - s.base_seq) + 1;
+                let lost = expected.saturating_sub(s.packets);
+                let (codec, clock_rate) = match self.codecs.get(&s.payload_type) {
+                    Some((name, rate)) => (Some(name), *rate),
+                    None => (None, 8000),
+                };
+                json!({
+                    "ssrc": format!("0x{:08x}", s.ssrc),
+                    "src": s.src,
+                    "dst": s.dst,
+                    "payload_type": s.payload_type,
+                    "codec": codec,
+                    "packets": s.packets,
+                    "lost": lost,
+                    "loss_rate": lost as f64 / expected as f64,
+                    "jitter_ms": s.jitter * 1000.0 / f64::from(clock_rate),
+                })
+            })
+            .collect();
+        let codecs: Vec<_> = self.codecs.values().map(|c| &c.0).collect();
+        let media: Vec<_> = self
+            .media
+            .iter()
+            .map(|(ip, port)| format_addr(*ip, *port))
+            .collect();
+        // duration of the conversation, until the end of the call or the last RTP packet
+        let last_rtp = self
+            .streams
+            .iter()
+            .filter_map(|s| s.last.map(|l| l.0))
+            .max();
+        let duration = match (self.answer, self.end.or(last_rtp)) {
+            (Some(answer), Some(end)) if end >= answer => Some(duration_secs(end - answer)),
+            _ => None,
+        };
+        json!({
+            "call_id": self.call_id,
+            "from": self.from,
+            "to": self.to,
+            "start": format_ts(self.start),
+            "answer": self.answer.map(format_ts),
+            "end": self.end.map(format_ts),
+            "duration": duration,
+            "status": self.status,
+            "codecs": codecs,
+            "media": media,
+            "rtp_streams": streams,
+        })
+    }
+}
+
+/// SIP request or response
+struct SipMessage<'a> {
+    /// Method of requests
+    method: Option<&'a str>,
+    /// Status code of responses
+    status: Option<u16>,
+    headers: Vec<(&'a str, &'a str)>,
+    body: Option<&'a str>,
+}
+
+impl<'a> SipMessage<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let text = std::str::from_utf8(data).ok()?;
+        let (head, body) = match text.find("\r\n\r\n") {
+            Some(pos) => (&text[..pos], Some(&text[pos + 4..])),
+            None => (text, None),
+        };
+        let mut lines = head.split("\r\n");
+        let start_line = lines.next()?;
+        let (method, status) = if let Some(status) = start_line.strip_prefix("SIP/2.0 ") {
+            (None, Some(status.get(..3)?.parse().ok()?))
+        } else if start_line.ends_with(" SIP/2.0") {
+            (start_line.split(' ').next(), None)
+        } else {
+            return None;
+        };
+        let headers = lines
+            .filter_map(|l| {
+                let mut kv = l.splitn(2, ':');
+                Some((kv.next()?.trim(), kv.next()?.trim()))
+            })
+            .collect();
+        Some(SipMessage {
+            method,
+            status,
+            headers,
+            body: body.filter(|b| !b.is_empty()),
+        })
+    }
+
+    /// Get a header, using its name or its compact form
+    fn header(&self, name: &str, compact: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name) || k.eq_ignore_ascii_case(compact))
+            .map(|(_, v)| *v)
+    }
+
+    fn cseq_method(&self) -> Option<&'a str> {
+        self.header("cseq", "cseq")?.split_whitespace().nth(1)
+    }
+
+    fn is_sdp(&self) -> bool {
+        self.header("content-type", "c")
+            .map_or(false, |t| t.eq_ignore_ascii_case("application/sdp"))
+    }
+}
+
+/// Codec of static RTP payload types (RFC 3551)
+fn static_payload_type(pt: u8) -> Option<(String, u32)> {
+    let name = match pt {
+        0 => "PCMU",
+        3 => "GSM",
+        4 => "G723",
+        8 => "PCMA",
+        9 => "G722",
+        18 => "G729",
+        _ => return None,
+    };
+    Some((name.to_owned(), 8000))
+}
+
+fn format_addr(ip: IpAddr, port: u16) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("{}:{}", ip, port),
+        IpAddr::V6(ip) => format!("[{}]:{}", ip, port),
+    }
+}
+
+fn format_ts(ts: Duration) -> String {
+    format!("{}.{:09}", ts.secs, ts.nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Voip;
+    use libpcap_tools::Duration;
+
+    fn sip(start_line: &str, cseq: &str, sdp: Option<&str>) -> Vec<u8> {
+        let mut msg = format!(
+            "{}\r\nCall-ID: abc@host\r\nFrom: <sip:alice@example.com>\r\nTo: <sip:bob@example.com>\r\nCSeq: {}\r\n",
+            start_line, cseq
+        );
+        if let Some(sdp) = sdp {
+            msg.push_str("Content-Type: application/sdp\r\n\r\n");
+            msg.push_str(sdp);
+        } else {
+            msg.push_str("\r\n");
+        }
+        msg.into_bytes()
+    }
+
+    fn rtp(seq: u16, ts: u32) -> Vec<u8> {
+        let mut data = vec![0x80, 0x00];
+        data.extend_from_slice(&seq.to_be_bytes());
+        data.extend_from_slice(&ts.to_be_bytes());
+        data.extend_from_slice(&[0x11, 0x22, 0x33, 0x44]);
+        data.resize(12 + 160, 0);
+        data
+    }
+
+    #[test]
+    fn voip_call() {
+        let mut voip = Voip::default();
+        let offer = "v=0\r\nc=IN IP4 192.0.2.1\r\nm=audio 4000 RTP/AVP 0 101\r\n\
+                     a=rtpmap:101 telephone-event/8000\r\n";
+        let answer = "v=0\r\nc=IN IP4 192.0.2.2\r\nm=audio 5000 RTP/AVP 0\r\n";
+        let invite = sip(
+            "INVITE sip:bob@example.com SIP/2.0",
+            "1 INVITE",
+            Some(offer),
+        );
+        voip.handle_sip(Duration::new(1, 0), &invite);
+        let ok = sip("SIP/2.0 200 OK", "1 INVITE", Some(answer));
+        voip.handle_sip(Duration::new(3, 0), &ok);
+        assert_eq!(voip.media.len(), 2);
+        // RTP packets every 20 ms, packet 3 is lost, packet 4 is late by 5 ms
+        let call = &mut voip.calls[0];
+        for (seq, arrival) in &[(1, 0), (2, 20_000), (4, 65_000), (5, 80_000)] {
+            let data = rtp(*seq, u32::from(*seq) * 160);
+            let (src, dst) = ("192.0.2.2:5000".to_owned(), "192.0.2.1:4000".to_owned());
+            call.handle_rtp(Duration::new(4, *arrival), &data, src, dst);
+        }
+        let bye = sip("BYE sip:alice@example.com SIP/2.0", "2 BYE", None);
+        voip.handle_sip(Duration::new(13, 0), &bye);
+
+        let results = voip.get_results_json();
+        let call = &results["voip-calls"][0];
+        assert_eq!(call["call_id"], "abc@host");
+        assert_eq!(call["status"], 200);
+        assert_eq!(call["duration"], 10.0);
+        assert_eq!(call["codecs"][0], "PCMU");
+        let stream = &call["rtp_streams"][0];
+        assert_eq!(stream["codec"], "PCMU");
+        assert_eq!(stream["packets"], 4);
+        assert_eq!(stream["lost"], 1);
+        assert!(stream["jitter_ms"].as_f64().unwrap_or_default() > 0.0);
+    }
+}
@@ -0,0 +1,308 @@
+//! Plugin recognizing WireGuard tunnels, and recording their handshakes
+//!
+//! A UDP flow is WireGuard if its first message is a handshake message (initiation, response or
+//! cookie reply, recognized by their type and fixed length), or a transport message on the
+//! default port (51820). For each tunnel, the plugin records the number of handshakes and their
+//! timestamps, the session indexes chosen by the peers, whether cookies were used (MAC2 set, when
+//! a peer is under load), and the number and size of transport messages (and keepalives).
+//!
+//! Transport data is encrypted with keys derived from the static keys of the peers, so the inner
+//! traffic cannot be decapsulated.
+
+use crate::packet_info::PacketInfo;
+use crate::plugin::{Plugin, PluginResult, PLUGIN_FLOW_DEL, PLUGIN_L4};
+use crate::report::PluginOutput;
+use crate::{output, plugin_builder};
+use fnv::FnvHashMap;
+use libpcap_tools::{Duration, FiveTuple, Flow, FlowID, Packet};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::Any;
+
+const DEFAULT_PORT: u16 = 51820;
+
+const MESSAGE_INITIATION: u8 = 1;
+const MESSAGE_RESPONSE: u8 = 2;
+const MESSAGE_COOKIE_REPLY: u8 = 3;
+const MESSAGE_TRANSPORT: u8 = 4;
+
+/// Size of a transport message without data (header and authentication tag)
+const KEEPALIVE_LEN: usize = 32;
+
+/// A WireGuard message (see the WireGuard whitepaper, section 5.4)
+#[derive(Debug, PartialEq)]
+enum Message {
+    Initiation {
+        sender: u32,
+        mac2: bool,
+    },
+    Response {
+        sender: u32,
+        receiver: u32,
+        mac2: bool,
+    },
+    CookieReply {
+        receiver: u32,
+    },
+    Transport {
+        receiver: u32,
+        counter: u64,
+    },
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(b)
+}
+
+/// Parse the header of a message, checking the length of the message
+fn parse_message(data: &[u8]) -> Option<Message> {
+    // type, followed by 3 reserved bytes
+    if data.len() < 4 || data[1..4] != [0, 0, 0] {
+        return None;
+    }
+    let mac2_set = |offset: usize| data[offset..offset + 16].iter().any(|&b| b != 0);
+    match (data[0], data.len()) {
+        (MESSAGE_INITIATION, 148) => Some(Message::Initiation {
+            sender: le32(data, 4),
+            mac2: mac2_set(132),
+        }),
+        (MESSAGE_RESPONSE, 92) => Some(Message::Response {
+            sender: le32(data, 4),
+            receiver: le32(data, 8),
+            mac2: mac2_set(76),
+        }),
+        (MESSAGE_COOKIE_REPLY, 64) => Some(Message::CookieReply {
+            receiver: le32(data, 4),
+        }),
+        // encrypted data is padded to a multiple of 16 bytes
+        (MESSAGE_TRANSPORT, len) if len >= KEEPALIVE_LEN && len % 16 == 0 => {
+            let mut counter = [0; 8];
+            counter.copy_from_slice(&data[8..16]);
+            Some(Message::Transport {
+                receiver: le32(data, 4),
+                counter: u64::from_le_bytes(counter),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn format_ts(ts: Duration) -> String {
+    format!("{}.{:09}", ts.secs, ts.nanos)
+}
+
+/// A WireGuard tunnel (a UDP flow)
+#[derive(Serialize)]
+struct Tunnel {
+    flow_id: FlowID,
+    #[serde(flatten)]
+    five_tuple: FiveTuple,
+    initiations: u64,
+    responses: u64,
+    cookie_replies: u64,
+    /// A cookie was used by a peer (MAC2 set in a handshake message)
+    cookies_used: bool,
+    /// Index of the last session, chosen by the initiator and by the responder (hex)
+    initiator_index: Option<String>,
+    responder_index: Option<String>,
+    /// Time of the first and last handshake responses
+    first_handshake: Option<String>,
+    last_handshake: Option<String>,
+    transport_packets: u64,
+    /// Size of the transport messages
+    transport_bytes: u64,
+    keepalives: u64,
+    /// Highest counter of transport messages
+    max_counter: u64,
+    /// Payloads which are not WireGuard messages
+    invalid: u64,
+}
+
+impl Tunnel {
+    fn new(flow_id: FlowID, five_tuple: &FiveTuple) -> Self {
+        Tunnel {
+            flow_id,
+            five_tuple: five_tuple.clone(),
+            initiations: 0,
+            responses: 0,
+            cookie_replies: 0,
+            cookies_used: false,
+            initiator_index: None,
+            responder_index: None,
+            first_handshake: None,
+            last_handshake: None,
+            transport_packets: 0,
+            transport_bytes: 0,
+            keepalives: 0,
+            max_counter: 0,
+            invalid: 0,
+        }
+    }
+
+    fn handle_message(&mut self, data: &[u8], ts: Duration) {
+        match parse_message(data) {
+            Some(Message::Initiation { sender, mac2 }) => {
+                self.initiations += 1;
+                self.cookies_used |= mac2;
+                self.initiator_index = Some(format!("{:08x}", sender));
+            }
+            Some(Message::Response {
+                sender,
+                receiver,
+                mac2,
+            }) => {
+                self.responses += 1;
+                self.cookies_used |= mac2;
+                self.initiator_index = Some(format!("{:08x}", receiver));
+                self.responder_index = Some(format!("{:08x}", sender));
+                let ts = format_ts(ts);
+                self.first_handshake.get_or_insert_with(|| ts.clone());
+                self.last_handshake = Some(ts);
+            }
+            Some(Message::CookieReply { .. }) => self.cookie_replies += 1,
+            Some(Message::Transport { counter, .. }) => {
+                self.transport_packets += 1;
+                self.transport_bytes += data.len() as u64;
+                if data.len() == KEEPALIVE_LEN {
+                    self.keepalives += 1;
+                }
+                self.max_counter = self.max_counter.max(counter);
+            }
+            None => self.invalid += 1,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WireGuard {
+    /// Tunnels of active flows, `None` if the flow is not WireGuard
+    flows: FnvHashMap<FlowID, Option<Tunnel>>,
+    /// Tunnels of destroyed flows
+    tunnels: Vec<Tunnel>,
+}
+
+plugin_builder!(WireGuard, WireGuardBuilder);
+
+impl Plugin for WireGuard {
+    fn name(&self) -> &'static str {
+        "WireGuard"
+    }
+    fn plugin_type(&self) -> u16 {
+        PLUGIN_L4 | PLUGIN_FLOW_DEL
+    }
+    fn parallel_safe(&self) -> bool {
+        true
+    }
+
+    fn handle_layer_transport<'s, 'i>(
+        &'s mut self,
+        packet: &'s Packet,
+        pinfo: &PacketInfo,
+    ) -> PluginResult<'i> {
+        let (flow, data) = match (pinfo.flow, pinfo.l4_payload) {
+            (Some(flow), Some(data)) if pinfo.five_tuple.proto == 17 && !data.is_empty() => {
+                (flow, data)
+            }
+            _ => return PluginResult::None,
+        };
+        let t5 = pinfo.five_tuple;
+        let tunnel = self
+            .flows
+            .entry(flow.flow_id)
+            .or_insert_with(|| match parse_message(data) {
+                Some(Message::Transport { .. })
+                    if t5.src_port != DEFAULT_PORT && t5.dst_port != DEFAULT_PORT =>
+                {
+                    None
+                }
+                Some(_) => Some(Tunnel::new(flow.flow_id, &flow.five_tuple)),
+                None => None,
+            });
+        if let Some(tunnel) = tunnel {
+            tunnel.handle_message(data, packet.ts);
+        }
+        PluginResult::None
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        if let Some(Some(tunnel)) = self.flows.remove(&flow.flow_id) {
+            self.tunnels.push(tunnel);
+        }
+    }
+
+    fn get_results(&mut self) -> Option<Box<dyn Any>> {
+        let v = self.get_results_json();
+        Some(Box::new(v))
+    }
+
+    fn get_output(&mut self) -> Option<Box<dyn PluginOutput>> {
+        Some(Box::new(self.get_results_json()))
+    }
+
+    fn save_results(&mut self, path: &str) -> Result<(), &'static str> {
+        let results = self.get_results_json();
+        // save data to file
+        let file =
+            output::create_file(path, "wireguard.json").or(Err("Cannot create output file"))?;
+        serde_json::to_writer(file, &results).or(Err("Cannot save results to file"))?;
+        Ok(())
+    }
+}
+
+impl WireGuard {
+    fn get_results_json(&mut self) -> Value {
+        // flows still active (if called before the end of the analysis)
+        let active = self.flows.values().flatten();
+        let tunnels: Vec<_> = self.tunnels.iter().chain(active).collect();
+        json!({ "wireguard-tunnels": tunnels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_message, Message};
+
+    #[test]
+    fn wireguard_messages() {
+        let mut initiation = vec![0; 148];
+        initiation[0] = 1;
+        initiation[4..8].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        assert_eq!(
+            parse_message(&initiation),
+            Some(Message::Initiation {
+                sender: 0x1234_5678,
+                mac2: false
+            })
+        );
+        let mut response = vec![0; 92];
+        response[0] = 2;
+        response[4] = 0xaa;
+        response[8] = 0xbb;
+        response[80] = 1;
+        assert_eq!(
+            parse_message(&response),
+            Some(Message::Response {
+                sender: 0xaa,
+                receiver: 0xbb,
+                mac2: true
+            })
+        );
+        let mut keepalive = vec![0; 32];
+        keepalive[0] = 4;
+        keepalive[8] = 3;
+        assert_eq!(
+            parse_message(&keepalive),
+            Some(Message::Transport {
+                receiver: 0,
+                counter: 3
+            })
+        );
+        // invalid lengths, reserved bytes set
+        assert_eq!(parse_message(&initiation[..147]), None);
+        assert_eq!(parse_message(&keepalive[..31]), None);
+        keepalive[2] = 1;
+        assert_eq!(parse_message(&keepalive), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Report of the analysis, with the results of all plugins
+//!
+//! Plugins return structured results using `Plugin::get_output`. At the end of the analysis,
+//! the results are collected into a single [`Report`], which is saved to the file set by the
+//! `report_file` configuration variable (in JSON or TOML format, or in a SQLite database with the
+//! `plugin_sqlite` feature).
+
+use crate::plugin_registry::PluginRegistry;
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Structured results of a plugin
+///
+/// This trait is implemented for all serializable types, so plugins can return their results
+/// directly (for ex. a `serde_json::Value`, or a structure deriving `Serialize`).
+pub trait PluginOutput {
+    /// Convert the results to a JSON value
+    fn to_json(&self) -> Result<Value, serde_json::Error>;
+}
+
+impl<T: Serialize> PluginOutput for T {
+    fn to_json(&self) -> Result<Value, serde_json::Error> {
+        serde_json::to_value(self)
+    }
+}
+
+/// File format of a report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Toml,
+    /// SQLite database (see the `sqlite` plugin for the schema)
+    #[cfg(feature = "plugin_sqlite")]
+    Sqlite,
+}
+
+impl ReportFormat {
+    /// Get the format from the extension of a file name (JSON, unless the extension is `.toml`,
+    /// or `.db` and `.sqlite` for SQLite)
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => ReportFormat::Toml,
+            #[cfg(feature = "plugin_sqlite")]
+            Some(ext) if ext.eq_ignore_ascii_case("db") || ext.eq_ignore_ascii_case("sqlite") => {
+                ReportFormat::Sqlite
+            }
+            _ => ReportFormat::Json,
+        }
+    }
+}
+
+/// Results of all plugins
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    /// Results, indexed by plugin name
+    pub plugins: IndexMap<String, Value>,
+}
+
+impl Report {
+    /// Collect the results of all plugins of the registry
+    ///
+    /// Plugins without results are not present in the report. If several plugins have the same
+    /// name, the index of the plugin is appended to the name.
+    pub fn collect(registry: &PluginRegistry) -> Self {
+        let mut report = Report::default();
+        registry.run_plugins(
+            |_| true,
+            |p| {
+                let output = match p.get_output() {
+                    Some(output) => output,
+                    None => return,
+                };
+                let value = match output.to_json() {
+                    Ok(value) => value,
+                    Err(e) => {
+                        warn!("Could not serialize results of plugin {}: {}", p.name(), e);
+                        return;
+                    }
+                };
+                let mut name = p.name().to_owned();
+                if report.plugins.contains_key(&name) {
+                    name = format!("{}#{}", name, report.plugins.len());
+                }
+                report.plugins.insert(name, value);
+            },
+        );
+        report
+    }
+
+    /// Serialize the report
+    pub fn to_string_as(&self, format: ReportFormat) -> Result<String, io::Error> {
+        match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self).map_err(other_error),
+            ReportFormat::Toml => {
+                // TOML has no null value: remove them, then convert to a TOML value (which
+                // also sorts values before tables, as required by the format)
+                let mut value = serde_json::to_value(self).map_err(other_error)?;
+                remove_nulls(&mut value);
+                let value = toml::Value::try_from(value).map_err(other_error)?;
+                toml::to_string(&value).map_err(other_error)
+            }
+            #[cfg(feature = "plugin_sqlite")]
+            ReportFormat::Sqlite => Err(other_error("SQLite reports cannot be serialized")),
+        }
+    }
+
+    /// Save the report to a file. The format depends on the file extension (see
+    /// `ReportFormat::from_path`).
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let format = ReportFormat::from_path(&path);
+        #[cfg(feature = "plugin_sqlite")]
+        {
+            if format == ReportFormat::Sqlite {
+                return crate::plugins::sqlite::save_report(self, path.as_ref());
+            }
+        }
+        let s = self.to_string_as(format)?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(s.as_bytes())?;
+        writer.flush()
+    }
+}
+
+fn other_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Array(v) => {
+            v.retain(|v| !v.is_null());
+            v.iter_mut().for_each(remove_nulls);
+        }
+        Value::Object(m) => {
+            *m = std::mem::take(m)
+                .into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .collect();
+            m.values_mut().for_each(remove_nulls);
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn report_formats() {
+        let mut report = Report::default();
+        report.plugins.insert(
+            "Stats".to_owned(),
+            json!({"flows": {"1": {"packets": 3}}, "version": null, "total": 12}),
+        );
+        let s = report
+            .to_string_as(ReportFormat::Json)
+            .expect("JSON report");
+        let v: Value = serde_json::from_str(&s).expect("parse JSON report");
+        assert_eq!(v["plugins"]["Stats"]["total"], 12);
+        let s = report
+            .to_string_as(ReportFormat::Toml)
+            .expect("TOML report");
+        let v: toml::Value = s.parse().expect("parse TOML report");
+        assert_eq!(v["plugins"]["Stats"]["total"].as_integer(), Some(12));
+        assert_eq!(
+            v["plugins"]["Stats"]["flows"]["1"]["packets"].as_integer(),
+            Some(3)
+        );
+        assert!(v["plugins"]["Stats"].get("version").is_none());
+        assert_eq!(
+            ReportFormat::from_path("out/report.TOML"),
+            ReportFormat::Toml
+        );
+        assert_eq!(ReportFormat::from_path("report"), ReportFormat::Json);
+    }
+}
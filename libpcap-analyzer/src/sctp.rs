@@ -0,0 +1,149 @@
+//! Stream Control Transmission Protocol (SCTP)
+//!
+//! See RFC 4960
+
+/// Chunk type of DATA chunks
+pub const SCTP_CHUNK_DATA: u8 = 0;
+/// Chunk type of ABORT chunks
+pub const SCTP_CHUNK_ABORT: u8 = 6;
+
+/// Common header of an SCTP packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SctpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// Verification tag (chosen by the receiver of the packet, 0 for INIT chunks)
+    pub verification_tag: u32,
+}
+
+/// Parse the common header of an SCTP packet
+///
+/// Returns the header, and the data following it (the chunks).
+pub fn parse_sctp_header(data: &[u8]) -> Option<(SctpHeader, &[u8])> {
+    if data.len() < 12 {
+        return None;
+    }
+    let header = SctpHeader {
+        src_port: u16::from_be_bytes([data[0], data[1]]),
+        dst_port: u16::from_be_bytes([data[2], data[3]]),
+        verification_tag: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+    };
+    // bytes 8 to 11: checksum
+    Some((header, &data[12..]))
+}
+
+/// Chunk of an SCTP packet
+#[derive(Debug, PartialEq, Eq)]
+pub struct SctpChunk<'a> {
+    pub chunk_type: u8,
+    pub flags: u8,
+    /// Value of the chunk, without type, flags, length and padding
+    pub value: &'a [u8],
+}
+
+impl<'a> SctpChunk<'a> {
+    /// Return the payload protocol identifier and the user data, if this is a DATA chunk
+    pub fn data(&self) -> Option<(u32, &'a [u8])> {
+        // TSN, stream identifier, stream sequence number, payload protocol identifier
+        if self.chunk_type != SCTP_CHUNK_DATA || self.value.len() < 12 {
+            return None;
+        }
+        let v = self.value;
+        let ppid = u32::from_be_bytes([v[8], v[9], v[10], v[11]]);
+        Some((ppid, &v[12..]))
+    }
+}
+
+/// Iterator over the chunks of an SCTP packet, see [`sctp_chunks`]
+pub struct SctpChunks<'a> {
+    data: &'a [u8],
+}
+
+/// Return an iterator over the chunks following the common header
+///
+/// The iteration stops at the first truncated or invalid chunk.
+pub fn sctp_chunks(data: &[u8]) -> SctpChunks {
+    SctpChunks { data }
+}
+
+impl<'a> Iterator for SctpChunks<'a> {
+    type Item = SctpChunk<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.data;
+        if data.len() < 4 {
+            return None;
+        }
+        let len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+        if len < 4 || len > data.len() {
+            self.data = &[];
+            return None;
+        }
+        // chunks are padded to a multiple of 4 bytes (the padding of the last chunk can be
+        // missing)
+        let padded_len = (len + 3) & !3;
+        self.data = data.get(padded_len..).unwrap_or(&[]);
+        Some(SctpChunk {
+            chunk_type: data[0],
+            flags: data[1],
+            value: &data[4..len],
+        })
+    }
+}
+
+/// Return the name of an SCTP chunk type
+pub fn sctp_chunk_name(chunk_type: u8) -> &'static str {
+    match chunk_type {
+        0 => "DATA",
+        1 => "INIT",
+        2 => "INIT_ACK",
+        3 => "SACK",
+        4 => "HEARTBEAT",
+        5 => "HEARTBEAT_ACK",
+        6 => "ABORT",
+        7 => "SHUTDOWN",
+        8 => "SHUTDOWN_ACK",
+        9 => "ERROR",
+        10 => "COOKIE_ECHO",
+        11 => "COOKIE_ACK",
+        12 => "ECNE",
+        13 => "CWR",
+        14 => "SHUTDOWN_COMPLETE",
+        15 => "AUTH",
+        64 => "I_DATA",
+        128 => "ASCONF_ACK",
+        130 => "RE_CONFIG",
+        132 => "PAD",
+        192 => "FORWARD_TSN",
+        193 => "ASCONF",
+        194 => "I_FORWARD_TSN",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn sctp_test() {
+        // ports 2905 -> 2905 (M3UA), tag 0x01020304, DATA chunk (PPID 3, 3 bytes of data,
+        // padded), then SACK without padding
+        let data = b"\x0b\x59\x0b\x59\x01\x02\x03\x04\x00\x00\x00\x00\
+            \x00\x03\x00\x13\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x03abc\x00\
+            \x03\x00\x00\x10\x00\x00\x00\x01\x00\x00\x10\x00\x00\x00\x00\x00";
+        let (header, chunks) = parse_sctp_header(data).expect("SCTP header");
+        assert_eq!(header.src_port, 2905);
+        assert_eq!(header.dst_port, 2905);
+        assert_eq!(header.verification_tag, 0x0102_0304);
+        let chunks: Vec<_> = sctp_chunks(chunks).collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].chunk_type, SCTP_CHUNK_DATA);
+        assert_eq!(chunks[0].flags, 3);
+        assert_eq!(chunks[0].data(), Some((3, &b"abc"[..])));
+        assert_eq!(sctp_chunk_name(chunks[1].chunk_type), "SACK");
+        assert_eq!(chunks[1].data(), None);
+        // truncated chunk
+        assert_eq!(sctp_chunks(b"\x00\x03\x00\x20\x00").count(), 0);
+        assert!(parse_sctp_header(b"\x0b\x59\x0b\x59").is_none());
+    }
+}
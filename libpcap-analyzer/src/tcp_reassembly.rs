@@ -221,6 +221,10 @@ impl TcpStream {
                     src.last_rel_ack = Wrapping(1);
                     src.next_rel_seq = Wrapping(1);
                     src.status = TcpStatus::Listen;
+                    if !to_server {
+                        // the analyzer already set the server as destination of the flow
+                        return Ok(None);
+                    }
                     // swap sides and tell analyzer to do the same for flow
                     std::mem::swap(&mut self.client, &mut self.server);
                     return Err(TcpStreamError::Inverted);
@@ -0,0 +1,54 @@
+//! Teredo: Tunneling IPv6 over UDP through NATs
+//!
+//! See RFC 4380
+
+/// Return the encapsulated IPv6 packet, after the optional authentication and origin
+/// indication headers
+///
+/// Returns `None` if the data is not a Teredo packet.
+pub fn teredo_ipv6_payload(data: &[u8]) -> Option<&[u8]> {
+    let mut data = data;
+    loop {
+        match data {
+            // authentication: ID length, authentication value length, client ID, authentication
+            // value, nonce (8 bytes), confirmation (1 byte)
+            [0x00, 0x01, id_len, au_len, ..] => {
+                let len = 4 + *id_len as usize + *au_len as usize + 9;
+                data = data.get(len..)?;
+            }
+            // origin indication: obfuscated port (2 bytes) and address (4 bytes)
+            [0x00, 0x00, ..] => {
+                data = data.get(8..)?;
+            }
+            _ => break,
+        }
+    }
+    if data.len() >= 40 && data[0] >> 4 == 6 {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::teredo_ipv6_payload;
+    #[test]
+    fn teredo_test() {
+        let mut ipv6 = vec![0; 40];
+        ipv6[0] = 0x60;
+        assert_eq!(teredo_ipv6_payload(&ipv6), Some(&ipv6[..]));
+        // origin indication
+        let mut data = vec![0, 0, 0xc3, 0x50, 1, 2, 3, 4];
+        data.extend_from_slice(&ipv6);
+        assert_eq!(teredo_ipv6_payload(&data), Some(&ipv6[..]));
+        // authentication (no client ID or authentication value), then origin indication
+        let mut data = vec![0, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 0];
+        data.extend_from_slice(&[0, 0, 0xc3, 0x50, 1, 2, 3, 4]);
+        data.extend_from_slice(&ipv6);
+        assert_eq!(teredo_ipv6_payload(&data), Some(&ipv6[..]));
+        // not IPv6
+        assert_eq!(teredo_ipv6_payload(&[0x45; 40]), None);
+        assert_eq!(teredo_ipv6_payload(&[0, 1, 0xff, 0]), None);
+    }
+}
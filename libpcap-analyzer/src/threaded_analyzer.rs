@@ -145,7 +145,7 @@ impl<'a> ThreadedAnalyzer<'a> {
                     &packet,
                     ctx,
                     LinkLayerType::Ethernet,
-                    payload,
+                    data,
                     &mut self.analyzer,
                 )?;
                 extern_dispatch_l3(&self.local_jobs, packet, ctx, payload, ethertype)
@@ -173,6 +173,17 @@ impl<'a> PcapAnalyzer for ThreadedAnalyzer<'a> {
         Ok(())
     }
 
+    fn handle_interface_added(
+        &mut self,
+        if_id: u32,
+        info: &InterfaceInfo,
+        _ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        self.registry
+            .run_plugins(|_| true, |p| p.handle_interface_added(if_id, info));
+        Ok(())
+    }
+
     fn teardown(&mut self) {
         debug!("main: exit");
         self.wait_for_empty_jobs();
@@ -188,6 +199,7 @@ impl<'a> PcapAnalyzer for ThreadedAnalyzer<'a> {
         debug!("main: all workers ended");
 
         self.registry.run_plugins(|_| true, |p| p.post_process());
+        self.analyzer.save_plugin_results();
     }
 
     fn before_refill(&mut self) {
@@ -1,7 +1,9 @@
 use crate::context::*;
 use crate::error::Error;
+use crate::flow_table::FlowTable;
 use crate::packet::Packet;
 pub use pcap_parser::PcapBlockOwned;
+use std::net::IpAddr;
 
 /// Common trait for pcap/pcap-ng analyzers
 pub trait PcapAnalyzer {
@@ -19,14 +21,108 @@ pub trait PcapAnalyzer {
         Ok(())
     }
 
+    /// Optional callback, called when a new section starts (pcapng Section Header Block, or
+    /// legacy pcap file header)
+    ///
+    /// Interfaces are defined per section: interface IDs of the previous section are no longer
+    /// valid. The index of the section is `ctx.section_index`.
+    fn section_start(&mut self, _ctx: &ParseContext) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Optional callback, called when a section ends (before the next section starts, or at
+    /// the end of the file)
+    fn section_end(&mut self, _ctx: &ParseContext) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Optional callback, called when an interface is defined in the current section (pcapng
+    /// Interface Description Block, or legacy pcap file header)
+    ///
+    /// `if_id` is the interface ID used by the packets of the section (`Packet::interface`).
+    /// Interfaces of the section are also stored in `ctx.interfaces`.
+    fn handle_interface_added(
+        &mut self,
+        _if_id: u32,
+        _info: &InterfaceInfo,
+        _ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// Callback function for every pcap Packet containing data
     fn handle_packet(&mut self, packet: &Packet, ctx: &ParseContext) -> Result<(), Error>;
 
+    /// Optional callback for pcapng Name Resolution Blocks
+    ///
+    /// `names` contains the (address, host name) records of the block. All names seen so far are
+    /// also stored in `ctx.hostnames`.
+    fn handle_name_resolution(
+        &mut self,
+        _names: &[(IpAddr, String)],
+        _ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Optional callback for pcapng Interface Statistics Blocks
+    ///
+    /// The last statistics of every interface are also stored in `ctx.interface_stats`.
+    fn handle_interface_statistics(
+        &mut self,
+        _stats: &InterfaceStatistics,
+        _ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
     /// Teardown function, called after reading pcap data (optional)
     fn teardown(&mut self) {}
 
+    /// Optional callback, called before a new chunk of input data is read
+    ///
+    /// Packet data borrowed from previous blocks may be released after this call. Data kept as
+    /// `PacketBytes` (see `Packet::bytes`) stays valid.
     fn before_refill(&mut self) {}
+
+    /// Return the flow table of the analyzer, if flows are tracked (optional)
+    ///
+    /// Flows and their counters can be queried at any time, including after `teardown`.
+    fn flow_table(&self) -> Option<&FlowTable> {
+        None
+    }
+
+    /// Return the state of the analyzer, to save it in a checkpoint (optional)
+    ///
+    /// The default implementation does not support checkpoints.
+    fn save_state(&self) -> Result<serde_json::Value, Error> {
+        Err(Error::Unsupported(
+            "Checkpoints are not supported by this analyzer",
+        ))
+    }
+
+    /// Restore the state of the analyzer from a checkpoint (optional)
+    ///
+    /// This function is called after `init`, before reading the blocks following the
+    /// checkpoint.
+    fn restore_state(&mut self, _state: &serde_json::Value) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "Checkpoints are not supported by this analyzer",
+        ))
+    }
 }
 
 /// Common trait for pcap/pcap-ng analyzers (thread-safe version)
-pub trait SafePcapAnalyzer: PcapAnalyzer + Send + Sync {}
+///
+/// This trait declares that an analyzer can be run on worker threads, for ex. by
+/// [`ParallelPcapDataEngine`](crate::ParallelPcapDataEngine).
+pub trait SafePcapAnalyzer: PcapAnalyzer + Send + Sync {
+    /// Check that the analyzer can run in several instances, one on each worker thread
+    /// (optional)
+    ///
+    /// This function is called before starting the workers, if there are more than one. An
+    /// error aborts the run.
+    fn check_parallel(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+use crate::context::*;
+use crate::data_engine::{add_hostnames, block_packet, legacy_interface};
+use crate::error::{BlockType, Error};
+use crate::packet::Packet;
+use async_trait::async_trait;
+use pcap_parser::pcapng::{parse_block_be, parse_block_le};
+use pcap_parser::{
+    nom, parse_pcap_frame, parse_pcap_frame_be, parse_pcap_frame_modified, parse_pcap_header,
+    Block, PcapBlockOwned, PcapError,
+};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Maximum size of a block. Larger blocks are rejected, to bound the memory used for an input
+/// which is not trusted (for ex. a file uploaded by a client)
+const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Common trait for asynchronous pcap/pcap-ng analyzers
+///
+/// Callbacks can `await`, for ex. to send results to a database or to another task. They are
+/// called sequentially, in the order of the packets.
+#[async_trait]
+pub trait AsyncPcapAnalyzer: Send {
+    /// Initialization function, called before reading pcap data (optional)
+    async fn init(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Callback function for every pcap Packet containing data
+    async fn handle_packet(&mut self, packet: &Packet<'_>, ctx: &ParseContext)
+        -> Result<(), Error>;
+
+    /// Teardown function, called after reading pcap data (optional)
+    async fn teardown(&mut self) {}
+}
+
+/// Asynchronous pcap/pcap-ng data analyzer engine
+///
+/// `AsyncPcapEngine` reads a pcap or pcap-ng input from a `tokio::io::AsyncRead`, and gives its
+/// packets to an `AsyncPcapAnalyzer`, like `PcapDataEngine` does for a `PcapAnalyzer`. It can be
+/// used in async services (for ex. to analyze a file uploaded over HTTP) without blocking a
+/// thread of the runtime while waiting for data.
+///
+/// Interfaces, name resolution and interface statistics blocks are not given to the analyzer,
+/// but are stored in the `ParseContext` given with each packet.
+///
+/// Dropping the future returned by `run` cancels the analysis without calling `teardown`. Use
+/// [`AsyncPcapEngine::set_stop_handle`] to stop the run and keep partial results.
+///
+/// This engine requires the `async` feature.
+pub struct AsyncPcapEngine<A: AsyncPcapAnalyzer> {
+    data_analyzer: A,
+    ctx: ParseContext,
+    /// True if a section was started
+    in_section: bool,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl<A: AsyncPcapAnalyzer> AsyncPcapEngine<A> {
+    pub fn new(data_analyzer: A) -> Self {
+        AsyncPcapEngine {
+            data_analyzer,
+            ctx: ParseContext::default(),
+            in_section: false,
+            stop: None,
+        }
+    }
+
+    pub fn data_analyzer(&self) -> &A {
+        &self.data_analyzer
+    }
+
+    pub fn data_analyzer_mut(&mut self) -> &mut A {
+        &mut self.data_analyzer
+    }
+
+    /// Stop reading input when `stop` is set to `true`
+    ///
+    /// The current block is processed, and `teardown` is still called, so the analyzer can
+    /// report partial results.
+    pub fn set_stop_handle(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop
+            .as_ref()
+            .map_or(false, |stop| stop.load(Ordering::Relaxed))
+    }
+
+    /// Main function: given a reader, read all pcap data and call analyzer for each Packet
+    pub async fn run<R: AsyncRead + Unpin>(&mut self, reader: R) -> Result<(), Error> {
+        self.ctx = ParseContext::default();
+        self.in_section = false;
+        self.data_analyzer.init().await?;
+        let mut reader = AsyncBlockReader::new(reader);
+        while reader.read_block().await? {
+            let block = reader.parse_block()?;
+            let res = self.handle_block(&block).await;
+            res.map_err(|e| Error::Block {
+                block_index: reader.block_index,
+                offset: reader.offset,
+                block_type: BlockType::of(&block),
+                pcap_index: self.ctx.pcap_index,
+                source: Box::new(e),
+            })?;
+            reader.consume();
+            if self.stopped() {
+                warn!("Run stopped before the end of the input, results are partial");
+                break;
+            }
+        }
+        self.data_analyzer.teardown().await;
+        Ok(())
+    }
+
+    /// Start a new section: interfaces of the previous section are no longer valid
+    fn start_section(&mut self) {
+        if self.in_section {
+            self.ctx.section_index += 1;
+        }
+        self.ctx.interfaces = Vec::new();
+        self.ctx.interface_stats.clear();
+        self.in_section = true;
+    }
+
+    async fn handle_block(&mut self, block: &PcapBlockOwned<'_>) -> Result<(), Error> {
+        match block {
+            PcapBlockOwned::NG(Block::SectionHeader(ref shb)) => {
+                self.ctx.big_endian = shb.big_endian();
+                self.start_section();
+            }
+            PcapBlockOwned::NG(Block::InterfaceDescription(ref idb)) => {
+                self.ctx.interfaces.push(pcapng_build_interface(idb));
+            }
+            PcapBlockOwned::LegacyHeader(ref hdr) => {
+                self.start_section();
+                trace!("Legacy pcap,  link type: {}", hdr.network);
+                self.ctx.interfaces.push(legacy_interface(hdr));
+            }
+            PcapBlockOwned::NG(Block::InterfaceStatistics(ref isb)) => {
+                let stats = pcapng_build_interface_statistics(
+                    isb,
+                    self.ctx.interface(isb.if_id),
+                    self.ctx.big_endian,
+                );
+                self.ctx.interface_stats.insert(stats.if_id, stats);
+            }
+            PcapBlockOwned::NG(Block::NameResolution(ref nrb)) => {
+                let names = pcapng_parse_name_resolution(nrb);
+                add_hostnames(&mut self.ctx, &names);
+            }
+            _ => match block_packet(block, &mut self.ctx, None)? {
+                Some(packet) => self.data_analyzer.handle_packet(&packet, &self.ctx).await?,
+                None => warn!("unsupported block"),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Format of the input, detected from the first bytes
+#[derive(Clone, Copy)]
+enum Format {
+    Unknown,
+    Legacy {
+        big_endian: bool,
+        /// Modified pcap format (magic `0xa1b2cd34`), with extended record headers
+        modified: bool,
+    },
+    /// Pcap-NG (the byte order is given by the Section Header Block)
+    NG {
+        big_endian: bool,
+    },
+}
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> usize {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    let value = if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    };
+    usize::try_from(value).unwrap_or(usize::MAX)
+}
+
+/// Reader of pcap/pcapng blocks from an asynchronous input
+///
+/// The length of each block is read from its header, and the complete block is read before
+/// parsing it, so blocks are never parsed from partial data.
+struct AsyncBlockReader<R> {
+    reader: R,
+    /// Data of the current block
+    buffer: Vec<u8>,
+    format: Format,
+    /// Index of the current block
+    block_index: usize,
+    /// Offset of the current block in the input
+    offset: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncBlockReader<R> {
+    fn new(reader: R) -> Self {
+        AsyncBlockReader {
+            reader,
+            buffer: Vec::new(),
+            format: Format::Unknown,
+            block_index: 0,
+            offset: 0,
+        }
+    }
+
+    /// Read data until the buffer contains `len` bytes. Returns `false` if the end of the input
+    /// is reached before
+    async fn fill(&mut self, len: usize) -> Result<bool, Error> {
+        if len > MAX_BLOCK_SIZE {
+            return Err(Error::Generic("Block too large"));
+        }
+        let mut position = self.buffer.len();
+        if len <= position {
+            return Ok(true);
+        }
+        self.buffer.resize(len, 0);
+        while position < len {
+            let n = self.reader.read(&mut self.buffer[position..]).await?;
+            if n == 0 {
+                self.buffer.truncate(position);
+                return Ok(false);
+            }
+            position += n;
+        }
+        Ok(true)
+    }
+
+    /// Read the next block in the buffer. Returns `false` at the end of the input
+    async fn read_block(&mut self) -> Result<bool, Error> {
+        self.buffer.clear();
+        let complete = match self.format {
+            Format::Unknown => {
+                if !self.fill(4).await? {
+                    return self.end();
+                }
+                match self.buffer[..4] {
+                    [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => {
+                        self.format = Format::Legacy {
+                            big_endian: false,
+                            modified: false,
+                        };
+                        self.fill(24).await?
+                    }
+                    [0x34, 0xcd, 0xb2, 0xa1] => {
+                        self.format = Format::Legacy {
+                            big_endian: false,
+                            modified: true,
+                        };
+                        self.fill(24).await?
+                    }
+                    [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => {
+                        self.format = Format::Legacy {
+                            big_endian: true,
+                            modified: false,
+                        };
+                        self.fill(24).await?
+                    }
+                    [0x0a, 0x0d, 0x0d, 0x0a] => self.read_ng_block().await?,
+                    _ => return Err(Error::Generic("Unsupported input format")),
+                }
+            }
+            Format::Legacy {
+                big_endian,
+                modified,
+            } => {
+                // record header: timestamp (8 bytes), captured and original lengths, followed by
+                // interface index, protocol and packet type (8 bytes) in the modified format
+                let header_len = if modified { 24 } else { 16 };
+                if self.fill(header_len).await? {
+                    let caplen = read_u32(&self.buffer, 8, big_endian);
+                    self.fill(caplen.saturating_add(header_len)).await?
+                } else {
+                    false
+                }
+            }
+            Format::NG { .. } => self.read_ng_block().await?,
+        };
+        if complete {
+            Ok(true)
+        } else {
+            self.end()
+        }
+    }
+
+    async fn read_ng_block(&mut self) -> Result<bool, Error> {
+        // block type and length
+        if !self.fill(8).await? {
+            return Ok(false);
+        }
+        if self.buffer[..4] == [0x0a, 0x0d, 0x0d, 0x0a] {
+            // Section Header Block: read the byte-order magic
+            if !self.fill(12).await? {
+                return Ok(false);
+            }
+            let big_endian = self.buffer[8..12] == [0x1a, 0x2b, 0x3c, 0x4d];
+            self.format = Format::NG { big_endian };
+        }
+        let big_endian = match self.format {
+            Format::NG { big_endian } => big_endian,
+            _ => false,
+        };
+        let len = read_u32(&self.buffer, 4, big_endian);
+        if len < 12 || len % 4 != 0 {
+            return Err(Error::Generic("Invalid block length"));
+        }
+        self.fill(len).await
+    }
+
+    /// End of the input: check that the last block is complete
+    fn end(&self) -> Result<bool, Error> {
+        if !self.buffer.is_empty() {
+            warn!(
+                "Could not read complete data block (block_index={})",
+                self.block_index
+            );
+            warn!("Hint: the input file may be truncated.");
+        }
+        Ok(false)
+    }
+
+    /// Parse the block read in the buffer
+    fn parse_block(&self) -> Result<PcapBlockOwned, Error> {
+        let data = &self.buffer[..];
+        let res = match self.format {
+            Format::Legacy { .. } if self.block_index == 0 => {
+                parse_pcap_header(data).map(|(rem, hdr)| (rem, PcapBlockOwned::LegacyHeader(hdr)))
+            }
+            Format::Legacy {
+                big_endian,
+                modified,
+            } => {
+                let res = if big_endian {
+                    parse_pcap_frame_be(data)
+                } else if modified {
+                    parse_pcap_frame_modified(data)
+                } else {
+                    parse_pcap_frame(data)
+                };
+                res.map(|(rem, b)| (rem, PcapBlockOwned::Legacy(b)))
+            }
+            Format::NG { big_endian } => {
+                let res = if big_endian {
+                    parse_block_be(data)
+                } else {
+                    parse_block_le(data)
+                };
+                res.map(|(rem, block)| (rem, PcapBlockOwned::NG(block)))
+            }
+            Format::Unknown => unreachable!("format is detected before reading a block"),
+        };
+        match res {
+            Ok((_, block)) => Ok(block),
+            Err(e) => {
+                let source = match e {
+                    nom::Err::Error(e) | nom::Err::Failure(e) => e.to_owned_vec(),
+                    nom::Err::Incomplete(_) => PcapError::Incomplete,
+                };
+                error!(
+                    "error while reading: {:?} (block_index={})",
+                    source, self.block_index
+                );
+                Err(Error::Parse {
+                    block_index: self.block_index,
+                    offset: self.offset,
+                    source,
+                })
+            }
+        }
+    }
+
+    /// Move to the next block
+    fn consume(&mut self) {
+        self.block_index += 1;
+        self.offset += self.buffer.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct PacketCounter {
+        lengths: Vec<u32>,
+        teardown: bool,
+    }
+
+    #[async_trait]
+    impl AsyncPcapAnalyzer for PacketCounter {
+        async fn handle_packet(
+            &mut self,
+            packet: &Packet<'_>,
+            _ctx: &ParseContext,
+        ) -> Result<(), Error> {
+            self.lengths.push(packet.caplen);
+            Ok(())
+        }
+
+        async fn teardown(&mut self) {
+            self.teardown = true;
+        }
+    }
+
+    fn legacy_pcap(lengths: &[u32], modified: bool) -> Vec<u8> {
+        // little-endian header, link type raw IPv4 (228)
+        let mut v = if modified {
+            vec![0x34, 0xcd, 0xb2, 0xa1, 2, 0, 4, 0]
+        } else {
+            vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0]
+        };
+        v.extend_from_slice(&[0; 8]);
+        v.extend_from_slice(&65535u32.to_le_bytes());
+        v.extend_from_slice(&228u32.to_le_bytes());
+        for (i, &len) in lengths.iter().enumerate() {
+            v.extend_from_slice(&(i as u32).to_le_bytes());
+            v.extend_from_slice(&0u32.to_le_bytes());
+            v.extend_from_slice(&len.to_le_bytes());
+            v.extend_from_slice(&len.to_le_bytes());
+            if modified {
+                // interface index, protocol, packet type and padding
+                v.extend_from_slice(&[0; 8]);
+            }
+            v.extend(std::iter::repeat(0x45).take(len as usize));
+        }
+        v
+    }
+
+    #[tokio::test]
+    async fn async_legacy_pcap() {
+        let input = legacy_pcap(&[20, 40, 60], false);
+        let mut engine = AsyncPcapEngine::new(PacketCounter::default());
+        engine.run(&input[..]).await.expect("run");
+        assert_eq!(engine.data_analyzer().lengths, vec![20, 40, 60]);
+        assert!(engine.data_analyzer().teardown);
+        assert_eq!(engine.ctx.interfaces.len(), 1);
+
+        // truncated input: the incomplete packet is ignored
+        let mut engine = AsyncPcapEngine::new(PacketCounter::default());
+        engine.run(&input[..input.len() - 10]).await.expect("run");
+        assert_eq!(engine.data_analyzer().lengths, vec![20, 40]);
+
+        let mut engine = AsyncPcapEngine::new(PacketCounter::default());
+        assert!(engine.run(&[0u8; 32][..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn async_modified_pcap() {
+        let input = legacy_pcap(&[20, 40, 60], true);
+        let mut engine = AsyncPcapEngine::new(PacketCounter::default());
+        engine.run(&input[..]).await.expect("run");
+        assert_eq!(engine.data_analyzer().lengths, vec![20, 40, 60]);
+    }
+}
@@ -1,8 +1,26 @@
+use crate::checkpoint::{Checkpoint, CheckpointConfig};
+use crate::chunk::{DataChunk, PacketBytes};
 use crate::config::Config;
 use crate::context::*;
-use crate::error::Error;
-use pcap_parser::{PcapBlockOwned, PcapError};
-use std::io::Read;
+use crate::duration::Duration;
+use crate::error::{Error, ErrorCounters, ErrorPolicy};
+use crate::progress::ProgressReporter;
+use memmap2::Mmap;
+use pcap_parser::nom;
+use pcap_parser::pcapng::{parse_block_be, parse_block_le, EnhancedPacketBlock, EPB_MAGIC};
+use pcap_parser::{
+    parse_pcap_frame, parse_pcap_frame_be, parse_pcap_frame_modified, parse_pcap_header, Block,
+    LegacyPcapBlock, PcapBlockOwned, PcapError,
+};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Number of blocks read between two checks of the checkpoint interval
+const CHECKPOINT_CHECK_INTERVAL: usize = 1024;
 
 pub trait BlockAnalyzer {
     /// Initialization function, called before reading pcap data (optional)
@@ -19,13 +37,53 @@ pub trait BlockAnalyzer {
     /// Teardown function, called after reading pcap data (optional)
     fn teardown(&mut self) {}
 
+    /// Optional callback, called before a new chunk of input data is read
+    ///
+    /// Packet data borrowed from previous blocks may be released after this call. Data kept as
+    /// `PacketBytes` (see `Packet::bytes`) stays valid.
     fn before_refill(&mut self) {}
+
+    /// Select the input of the next blocks, when reading multiple inputs at the same time
+    /// (optional)
+    fn set_input(&mut self, _index: usize) {}
+
+    /// Return the timestamp of a data block, used to merge inputs in chronological order
+    /// (optional)
+    ///
+    /// Blocks without timestamp are given to the analyzer as soon as they are read.
+    fn block_timestamp(&self, _block: &PcapBlockOwned) -> Option<Duration> {
+        None
+    }
+
+    /// Return the state of the analyzer, to save it in a checkpoint (optional)
+    fn save_state(&self) -> Result<serde_json::Value, Error> {
+        Err(Error::Unsupported(
+            "Checkpoints are not supported by this analyzer",
+        ))
+    }
+
+    /// Restore the state of the analyzer from a checkpoint (optional)
+    ///
+    /// This function is called after `init`. Blocks required to parse the next blocks (file or
+    /// section header, interfaces) are then given again to the analyzer.
+    fn restore_state(&mut self, _state: &serde_json::Value) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "Checkpoints are not supported by this analyzer",
+        ))
+    }
 }
 
 pub struct BlockEngine<A: BlockAnalyzer> {
     analyzer: A,
 
     capacity: usize,
+    /// Map regular files in memory, instead of reading them through a buffer
+    use_mmap: bool,
+    progress: Option<ProgressReporter>,
+    stop: Option<Arc<AtomicBool>>,
+    checkpoint: Option<CheckpointConfig>,
+    error_policy: ErrorPolicy,
+    errors: ErrorCounters,
 }
 
 impl<A: BlockAnalyzer> BlockEngine<A> {
@@ -33,7 +91,17 @@ impl<A: BlockAnalyzer> BlockEngine<A> {
         let capacity = config
             .get_usize("buffer_initial_capacity")
             .unwrap_or(128 * 1024);
-        BlockEngine { analyzer, capacity }
+        let use_mmap = config.get_bool("mmap").unwrap_or(true);
+        BlockEngine {
+            analyzer,
+            capacity,
+            use_mmap,
+            progress: None,
+            stop: None,
+            checkpoint: None,
+            error_policy: ErrorPolicy::from_config(config),
+            errors: ErrorCounters::default(),
+        }
     }
 
     pub fn analyzer(&self) -> &A {
@@ -44,58 +112,874 @@ impl<A: BlockAnalyzer> BlockEngine<A> {
         &mut self.analyzer
     }
 
+    /// Report the progress of the next runs to `reporter`
+    ///
+    /// When reading a regular file with `run_mmap`, the total size is set from the file size.
+    /// Otherwise, it must be set in the reporter if known.
+    pub fn set_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.progress = Some(reporter);
+    }
+
+    fn progress_start(&mut self) {
+        if let Some(progress) = &mut self.progress {
+            progress.start();
+        }
+    }
+
+    /// Stop reading input when `stop` is set to `true`
+    ///
+    /// The current block is processed, and `teardown` is still called, so the analyzer can
+    /// report partial results.
+    pub fn set_stop_handle(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    /// Write checkpoints of the analysis state periodically, and when the run is stopped
+    ///
+    /// Checkpoints are only written when reading a file mapped in memory (see `run_mmap`). The
+    /// analyzer must support `BlockAnalyzer::save_state`.
+    pub fn set_checkpoint_config(&mut self, config: CheckpointConfig) {
+        self.checkpoint = Some(config);
+    }
+
+    /// Set the action taken on errors (by default, read from the configuration)
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// Return the number of errors skipped during the last run
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.errors
+    }
+
+    /// Apply the error policy to the result of the analysis of a block
+    ///
+    /// If the error is skipped, the default value is returned.
+    fn check_result<T: Default>(
+        &mut self,
+        res: Result<T, Error>,
+        ctx: &ParseBlockContext,
+    ) -> Result<T, Error> {
+        match res {
+            Err(e) if self.error_policy != ErrorPolicy::Abort => {
+                warn!("Skipping block (block_index={}): {}", ctx.block_index, e);
+                self.errors.skipped_packets += 1;
+                Ok(T::default())
+            }
+            res => res,
+        }
+    }
+
+    fn skip_invalid_blocks(&self) -> bool {
+        self.error_policy == ErrorPolicy::SkipBlock
+    }
+
+    /// Start a run: reset counters, and call `init`
+    fn start(&mut self) -> Result<(), Error> {
+        self.errors = ErrorCounters::default();
+        self.analyzer.init()?;
+        self.progress_start();
+        Ok(())
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop
+            .as_ref()
+            .map_or(false, |stop| stop.load(Ordering::Relaxed))
+    }
+
+    /// End the run: call the last progress report, and `teardown`
+    fn finish(&mut self) {
+        if self.stopped() {
+            warn!("Run stopped before the end of the input, results are partial");
+        }
+        if self.errors.total() > 0 {
+            warn!(
+                "Errors skipped: {} packets, {} blocks",
+                self.errors.skipped_packets, self.errors.skipped_blocks
+            );
+        }
+        if let Some(progress) = &mut self.progress {
+            progress.finish();
+        }
+        self.analyzer.teardown();
+    }
+
     /// Main function: given a reader, read all pcap data and call analyzer for each Packet
     pub fn run(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
-        let mut reader = pcap_parser::create_reader(self.capacity, reader)?;
+        self.start()?;
+        let mut ctx = ParseBlockContext::default();
+        self.read_blocks(reader, &mut ctx)?;
+        self.finish();
+        Ok(())
+    }
 
-        self.analyzer.init()?;
+    /// Read all pcap data of a file, mapping it in memory
+    ///
+    /// Blocks are parsed directly from the mapped file, so there is no copy to an intermediate
+    /// buffer and `BlockAnalyzer::before_refill` is never called: data stays valid until
+    /// `teardown` returns.
+    ///
+    /// If the file is not a regular file (for ex. a pipe), cannot be mapped, or if the `mmap`
+    /// configuration key is false, the file is read as a stream (see `run`).
+    ///
+    /// The file must not be modified while it is read.
+    pub fn run_mmap(&mut self, file: &File) -> Result<(), Error> {
+        self.read_mmap(file, None)
+    }
+
+    /// Resume reading a file from a checkpoint, mapping it in memory
+    ///
+    /// The analyzer state is restored from the checkpoint (after `init`), and the blocks
+    /// required to parse the next blocks (file or section header, interfaces) are given again to
+    /// the analyzer. The file must be the one used to create the checkpoint.
+    pub fn resume_mmap(&mut self, file: &File, checkpoint: Checkpoint) -> Result<(), Error> {
+        self.read_mmap(file, Some(checkpoint))
+    }
+
+    fn read_mmap(&mut self, file: &File, resume: Option<Checkpoint>) -> Result<(), Error> {
+        let metadata = file.metadata()?;
+        if let Some(progress) = &mut self.progress {
+            if metadata.is_file() {
+                progress.set_total_bytes(Some(metadata.len()));
+            }
+        }
+        let chunk = if self.use_mmap && metadata.is_file() {
+            // Safety: the mapped file is only read, and is not expected to change during the run
+            match unsafe { Mmap::map(file) } {
+                Ok(mmap) => Some(DataChunk::new(mmap)),
+                Err(e) => {
+                    warn!("Could not map input file ({}), reading it as a stream", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let skip_invalid = self.skip_invalid_blocks();
+        let mut reader = match chunk
+            .as_ref()
+            .and_then(|chunk| SliceReader::new(chunk.as_slice(), skip_invalid))
+        {
+            Some(reader) => reader,
+            None if resume.is_some() => {
+                return Err(Error::Unsupported(
+                    "Resuming from a checkpoint requires an input file mapped in memory",
+                ));
+            }
+            None => {
+                debug!("Input file not mapped, reading it as a stream");
+                if self.checkpoint.is_some() {
+                    warn!(
+                        "Checkpoints require an input file mapped in memory, none will be written"
+                    );
+                }
+                let mut file = file;
+                return self.run(&mut file);
+            }
+        };
+        self.start()?;
+        let mut ctx = ParseBlockContext {
+            chunk: chunk.clone(),
+            ..ParseBlockContext::default()
+        };
+        if let Some(checkpoint) = resume {
+            self.restore_checkpoint(&mut reader, &checkpoint, &mut ctx)?;
+        }
+        let mut last_checkpoint = Instant::now();
+        while let Some((offset, block)) = reader.next_block(&mut self.progress)? {
+            ctx.offset = offset;
+            let res = self.analyzer.handle_block(&block, &ctx);
+            self.check_result(res, &ctx)?;
+            ctx.block_index += 1;
+            if self.stopped() {
+                // save the state, so the run can be resumed
+                self.write_checkpoint(&reader, &ctx);
+                break;
+            }
+            if ctx.block_index % CHECKPOINT_CHECK_INTERVAL == 0 {
+                let due = self
+                    .checkpoint
+                    .as_ref()
+                    .map_or(false, |config| last_checkpoint.elapsed() >= config.interval);
+                if due {
+                    self.write_checkpoint(&reader, &ctx);
+                    last_checkpoint = Instant::now();
+                }
+            }
+        }
+        self.errors.skipped_blocks += reader.skipped_blocks;
+        self.finish();
+        Ok(())
+    }
+
+    fn restore_checkpoint(
+        &mut self,
+        reader: &mut SliceReader,
+        checkpoint: &Checkpoint,
+        ctx: &mut ParseBlockContext,
+    ) -> Result<(), Error> {
+        if checkpoint.file_size != reader.data.len() as u64 {
+            return Err(Error::Checkpoint("the size of the input file is different"));
+        }
+        self.analyzer.restore_state(&checkpoint.state)?;
+        ctx.block_index = checkpoint.block_index;
+        for &(offset, _) in &checkpoint.headers {
+            reader.seek(offset)?;
+            if let Some((offset, block)) = reader.next_block(&mut None)? {
+                ctx.offset = offset;
+                self.analyzer.handle_block(&block, ctx)?;
+            }
+        }
+        reader.seek(checkpoint.offset)?;
+        if let Some(progress) = &mut self.progress {
+            progress.update(checkpoint.offset as usize, false);
+        }
+        info!(
+            "Resuming from checkpoint (offset={}, block_index={})",
+            checkpoint.offset, checkpoint.block_index
+        );
+        Ok(())
+    }
+
+    /// Save the analyzer state and the position of `reader` to the checkpoint file
+    ///
+    /// If the analyzer does not support checkpoints, they are disabled.
+    fn write_checkpoint(&mut self, reader: &SliceReader, ctx: &ParseBlockContext) {
+        let config = match &self.checkpoint {
+            Some(config) => config,
+            None => return,
+        };
+        let state = match self.analyzer.save_state() {
+            Ok(state) => state,
+            Err(e) => {
+                warn!(
+                    "Could not save analyzer state ({}), checkpoints disabled",
+                    e
+                );
+                self.checkpoint = None;
+                return;
+            }
+        };
+        let checkpoint = Checkpoint {
+            file_size: reader.data.len() as u64,
+            offset: reader.position as u64,
+            block_index: ctx.block_index,
+            headers: reader.headers.clone(),
+            state,
+        };
+        match checkpoint.save(&config.path) {
+            Ok(()) => debug!(
+                "Checkpoint written (offset={}, block_index={})",
+                checkpoint.offset, checkpoint.block_index
+            ),
+            Err(e) => warn!("Could not write checkpoint: {}", e),
+        }
+    }
+
+    /// Read all inputs one after the other
+    ///
+    /// `init` and `teardown` are called only once, so the analyzer state (for ex. flows) is kept
+    /// across inputs.
+    pub fn run_sequential(&mut self, readers: &mut [Box<dyn Read>]) -> Result<(), Error> {
+        self.start()?;
+        let mut ctx = ParseBlockContext::default();
+        for reader in readers.iter_mut() {
+            if self.stopped() {
+                break;
+            }
+            self.read_blocks(reader, &mut ctx)?;
+        }
+        self.finish();
+        Ok(())
+    }
+
+    /// Read all inputs at the same time, giving data blocks to the analyzer in chronological
+    /// order
+    ///
+    /// Timestamps are given by `BlockAnalyzer::block_timestamp`, and `BlockAnalyzer::set_input`
+    /// is called before each block. Other blocks are given to the analyzer as soon as they are
+    /// read. Data blocks with the same timestamp are given in the order of the inputs.
+    ///
+    /// Data blocks are kept while waiting for other inputs (their data is shared with the chunk
+    /// of input data): the options of Enhanced Packet Blocks are not kept.
+    pub fn run_merged(&mut self, readers: &mut [Box<dyn Read>]) -> Result<(), Error> {
+        self.start()?;
         let mut ctx = ParseBlockContext::default();
-        let mut last_incomplete_index = 0;
+        let capacity = self.capacity;
+        let skip_invalid = self.skip_invalid_blocks();
+        let mut inputs = readers
+            .iter_mut()
+            .map(|reader| BlockReader::new(capacity, reader, skip_invalid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut pending = Vec::with_capacity(inputs.len());
+        for (index, input) in inputs.iter_mut().enumerate() {
+            pending.push(self.next_data_block(input, index, &mut ctx)?);
+        }
+        while !self.stopped() {
+            // select the input with the oldest pending block
+            let next = pending
+                .iter()
+                .enumerate()
+                .filter_map(|(index, p)| p.as_ref().map(|(ts, _, _)| (*ts, index)))
+                .min();
+            let index = match next {
+                Some((_, index)) => index,
+                None => break,
+            };
+            let (_, offset, block) = pending[index].take().expect("pending block");
+            self.analyzer.set_input(index);
+            ctx.offset = offset;
+            ctx.chunk = Some(block.chunk().clone());
+            let res = block.with_block(|block| self.analyzer.handle_block(block, &ctx));
+            self.check_result(res, &ctx)?;
+            ctx.block_index += 1;
+            pending[index] = self.next_data_block(&mut inputs[index], index, &mut ctx)?;
+        }
+        self.errors.skipped_blocks += inputs.iter().map(|r| r.skipped_blocks).sum::<u64>();
+        self.finish();
+        Ok(())
+    }
+
+    fn read_blocks<R: Read>(
+        &mut self,
+        reader: R,
+        ctx: &mut ParseBlockContext,
+    ) -> Result<(), Error> {
+        let mut reader = BlockReader::new(self.capacity, reader, self.skip_invalid_blocks())?;
+        while let Some(res) = reader.next_block(
+            &mut self.analyzer,
+            &mut self.progress,
+            |analyzer, block, offset, chunk| {
+                ctx.offset = offset;
+                ctx.chunk = Some(chunk.clone());
+                analyzer.handle_block(block, ctx)
+            },
+        )? {
+            self.check_result(res, ctx)?;
+            ctx.block_index += 1;
+            if self.stopped() {
+                break;
+            }
+        }
+        self.errors.skipped_blocks += reader.skipped_blocks;
+        Ok(())
+    }
 
+    /// Read blocks of an input until a data block with a timestamp, and return a copy of it
+    ///
+    /// Other blocks are given to the analyzer.
+    fn next_data_block(
+        &mut self,
+        reader: &mut BlockReader,
+        index: usize,
+        ctx: &mut ParseBlockContext,
+    ) -> Result<Option<(Duration, u64, PendingBlock)>, Error> {
+        self.analyzer.set_input(index);
         loop {
-            match reader.next() {
-                Ok((offset, block)) => {
-                    self.analyzer.handle_block(&block, &ctx)?;
+            let res = reader.next_block(
+                &mut self.analyzer,
+                &mut self.progress,
+                |analyzer, block, offset, chunk| {
+                    if let Some(ts) = analyzer.block_timestamp(block) {
+                        if let Some(pending) = PendingBlock::from_block(block, chunk) {
+                            return Ok(Some((ts, offset, pending)));
+                        }
+                    }
+                    ctx.offset = offset;
+                    ctx.chunk = Some(chunk.clone());
+                    analyzer.handle_block(block, ctx).map(|_| None)
+                },
+            )?;
+            match res {
+                None => return Ok(None),
+                Some(res) => {
+                    if let Some(pending) = self.check_result(res, ctx)? {
+                        return Ok(Some(pending));
+                    }
                     ctx.block_index += 1;
-                    reader.consume_noshift(offset);
-                    continue;
                 }
-                Err(PcapError::Eof) => break,
-                Err(PcapError::Incomplete) => {
-                    if last_incomplete_index == ctx.block_index && reader.reader_exhausted() {
+            }
+        }
+    }
+}
+
+fn is_packet_block(block: &PcapBlockOwned) -> bool {
+    matches!(
+        block,
+        PcapBlockOwned::Legacy(_)
+            | PcapBlockOwned::NG(Block::EnhancedPacket(_))
+            | PcapBlockOwned::NG(Block::SimplePacket(_))
+    )
+}
+
+/// Return the length of the pcap-ng block at the start of `data`, read from the block header
+///
+/// Returns `None` if the length is not valid, or if the block is not complete in `data`.
+fn ng_block_len(data: &[u8], big_endian: bool) -> Option<usize> {
+    let bytes = <[u8; 4]>::try_from(data.get(4..8)?).ok()?;
+    let len = if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    } as usize;
+    if len >= 12 && len % 4 == 0 && len <= data.len() {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+/// Reader of pcap/pcapng blocks from a stream, reading data by chunks
+///
+/// Blocks are parsed from the current chunk. When the next block is not complete, a new chunk is
+/// allocated, starting with the incomplete data. Chunks are never modified once read, so the data
+/// of previous blocks stays valid as long as it is referenced (see `PacketBytes`).
+struct BlockReader<'r> {
+    reader: Box<dyn Read + 'r>,
+    /// Size of new chunks (larger if a block does not fit)
+    capacity: usize,
+    chunk: DataChunk,
+    /// Offset of the next block in the chunk
+    position: usize,
+    /// True if the end of the input was reached
+    eof: bool,
+    format: SliceFormat,
+    /// Number of blocks read
+    block_index: usize,
+    /// Offset of the next block in the input
+    offset: u64,
+    /// Skip pcap-ng blocks which cannot be parsed, instead of returning an error
+    skip_invalid: bool,
+    /// Number of blocks skipped
+    skipped_blocks: u64,
+}
+
+impl<'r> BlockReader<'r> {
+    fn new<R: Read + 'r>(capacity: usize, reader: R, skip_invalid: bool) -> Result<Self, Error> {
+        let mut reader = BlockReader {
+            reader: Box::new(reader),
+            capacity: capacity.max(64),
+            chunk: DataChunk::new(Vec::new()),
+            position: 0,
+            eof: false,
+            format: SliceFormat::LegacyHeader,
+            block_index: 0,
+            offset: 0,
+            skip_invalid,
+            skipped_blocks: 0,
+        };
+        reader.refill()?;
+        reader.format = SliceFormat::detect(reader.chunk.as_slice())
+            .ok_or(Error::Pcap(PcapError::HeaderNotRecognized))?;
+        Ok(reader)
+    }
+
+    /// Read a new chunk, starting with the data not parsed yet from the current chunk
+    fn refill(&mut self) -> Result<(), Error> {
+        let remaining = &self.chunk.as_slice()[self.position..];
+        // grow chunks if blocks are larger than the capacity
+        let size = self.capacity.max(2 * remaining.len());
+        let mut data = Vec::with_capacity(size);
+        data.extend_from_slice(remaining);
+        data.resize(size, 0);
+        let mut len = remaining.len();
+        while len < size {
+            match self.reader.read(&mut data[len..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        data.truncate(len);
+        self.chunk = DataChunk::new(data);
+        self.position = 0;
+        Ok(())
+    }
+
+    /// Read the next block and call `f` on it, with the offset of the block and its chunk.
+    /// Returns `None` at the end of the input
+    fn next_block<A, F, T>(
+        &mut self,
+        analyzer: &mut A,
+        progress: &mut Option<ProgressReporter>,
+        f: F,
+    ) -> Result<Option<T>, Error>
+    where
+        A: BlockAnalyzer,
+        F: FnOnce(&mut A, &PcapBlockOwned, u64, &DataChunk) -> T,
+    {
+        loop {
+            let data = &self.chunk.as_slice()[self.position..];
+            if data.is_empty() && self.eof {
+                return Ok(None);
+            }
+            match parse_any_block(data, &mut self.format) {
+                Ok((rem, block)) => {
+                    let len = data.len() - rem.len();
+                    let res = f(analyzer, &block, self.offset, &self.chunk);
+                    if let Some(progress) = progress {
+                        progress.update(len, is_packet_block(&block));
+                    }
+                    self.block_index += 1;
+                    self.offset += len as u64;
+                    self.position += len;
+                    return Ok(Some(res));
+                }
+                Err(nom::Err::Incomplete(_)) => {
+                    if self.eof {
                         warn!(
                             "Could not read complete data block (block_index={})",
-                            ctx.block_index
+                            self.block_index
                         );
-                        warn!(
-                            "  Buffer: consumed={} position={}",
-                            reader.consumed(),
-                            reader.position()
-                        );
-                        warn!("Hint: the reader buffer size may be too small, or the input file may be truncated.");
-                        break;
+                        warn!("Hint: the input file may be truncated.");
+                        return Ok(None);
                     }
-                    last_incomplete_index = ctx.block_index;
-                    // refill the buffer
+                    // read a new chunk
                     debug!("need refill");
-                    self.analyzer.before_refill();
-                    reader.refill().map_err(|e| e.to_owned_vec())?;
-                    continue;
+                    analyzer.before_refill();
+                    self.refill()?;
                 }
-                Err(e) => {
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
                     let e = e.to_owned_vec();
+                    let len = match self.format {
+                        SliceFormat::NG { big_endian } if self.skip_invalid => {
+                            ng_block_len(data, big_endian)
+                        }
+                        _ => None,
+                    };
+                    if let Some(len) = len {
+                        warn!(
+                            "Skipping invalid block (block_index={}): {:?}",
+                            self.block_index, e
+                        );
+                        if let Some(progress) = progress {
+                            progress.update(len, false);
+                        }
+                        self.block_index += 1;
+                        self.offset += len as u64;
+                        self.position += len;
+                        self.skipped_blocks += 1;
+                        continue;
+                    }
                     error!("error while reading: {:?}", e);
                     error!(
-                        "  Buffer: consumed={} position={}",
-                        reader.consumed(),
-                        reader.position()
+                        "  Chunk: position={} length={}",
+                        self.position,
+                        self.chunk.as_slice().len()
                     );
-                    return Err(Error::Pcap(e));
+                    return Err(Error::Parse {
+                        block_index: self.block_index,
+                        offset: self.offset,
+                        source: e,
+                    });
                 }
             }
         }
+    }
+}
 
-        self.analyzer.teardown();
+/// Format of pcap data, detected from the first bytes of the input
+#[derive(Clone, Copy)]
+enum SliceFormat {
+    /// Legacy pcap, header not read yet
+    LegacyHeader,
+    Legacy {
+        big_endian: bool,
+        /// Modified pcap format (magic `0xa1b2cd34`), with extended record headers
+        modified: bool,
+    },
+    /// Pcap-NG (the byte order is given by the Section Header Block)
+    NG {
+        big_endian: bool,
+    },
+}
+
+impl SliceFormat {
+    /// Detect the format of `data`. Returns `None` if it is not supported (for ex. compressed
+    /// data, or the big-endian modified pcap format)
+    fn detect(data: &[u8]) -> Option<Self> {
+        match data.get(..4)? {
+            [0xd4, 0xc3, 0xb2, 0xa1]
+            | [0x4d, 0x3c, 0xb2, 0xa1]
+            | [0x34, 0xcd, 0xb2, 0xa1]
+            | [0xa1, 0xb2, 0xc3, 0xd4]
+            | [0xa1, 0xb2, 0x3c, 0x4d] => Some(SliceFormat::LegacyHeader),
+            [0x0a, 0x0d, 0x0d, 0x0a] => Some(SliceFormat::NG { big_endian: false }),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the block at the start of `data`, and update `format` if it is a file or section header
+fn parse_any_block<'a>(
+    data: &'a [u8],
+    format: &mut SliceFormat,
+) -> nom::IResult<&'a [u8], PcapBlockOwned<'a>, PcapError<&'a [u8]>> {
+    match *format {
+        SliceFormat::LegacyHeader => parse_pcap_header(data).map(|(rem, hdr)| {
+            let big_endian = hdr.magic_number & 0xffff == 0xb2a1;
+            let modified = hdr.magic_number == 0xa1b2_cd34;
+            *format = SliceFormat::Legacy {
+                big_endian,
+                modified,
+            };
+            (rem, PcapBlockOwned::LegacyHeader(hdr))
+        }),
+        SliceFormat::Legacy {
+            big_endian,
+            modified,
+        } => {
+            let res = if big_endian {
+                parse_pcap_frame_be(data)
+            } else if modified {
+                parse_pcap_frame_modified(data)
+            } else {
+                parse_pcap_frame(data)
+            };
+            res.map(|(rem, b)| (rem, PcapBlockOwned::Legacy(b)))
+        }
+        SliceFormat::NG { big_endian } => {
+            let res = if big_endian {
+                parse_block_be(data)
+            } else {
+                parse_block_le(data)
+            };
+            res.map(|(rem, block)| {
+                if let Block::SectionHeader(ref shb) = block {
+                    *format = SliceFormat::NG {
+                        big_endian: shb.big_endian(),
+                    };
+                }
+                (rem, PcapBlockOwned::NG(block))
+            })
+        }
+    }
+}
+
+/// Reader of pcap/pcapng blocks from data mapped in memory
+struct SliceReader<'a> {
+    data: &'a [u8],
+    /// Offset of the next block
+    position: usize,
+    format: SliceFormat,
+    /// Number of blocks read
+    block_index: usize,
+    /// Position (offset, length) of the file or section header, and interface blocks
+    headers: Vec<(u64, u64)>,
+    /// Skip pcap-ng blocks which cannot be parsed, instead of returning an error
+    skip_invalid: bool,
+    /// Number of blocks skipped
+    skipped_blocks: u64,
+}
+
+impl<'a> SliceReader<'a> {
+    /// Detect the format of `data`. Returns `None` if it is not supported
+    fn new(data: &'a [u8], skip_invalid: bool) -> Option<Self> {
+        let format = SliceFormat::detect(data)?;
+        Some(SliceReader {
+            data,
+            position: 0,
+            format,
+            block_index: 0,
+            headers: Vec::new(),
+            skip_invalid,
+            skipped_blocks: 0,
+        })
+    }
+
+    /// Set the offset of the next block
+    fn seek(&mut self, offset: u64) -> Result<(), Error> {
+        if offset > self.data.len() as u64 {
+            return Err(Error::Checkpoint("offset is after the end of the input"));
+        }
+        self.position = offset as usize;
         Ok(())
     }
+
+    /// Parse the next block, and return it with its offset. Returns `None` at the end of the data
+    fn next_block(
+        &mut self,
+        progress: &mut Option<ProgressReporter>,
+    ) -> Result<Option<(u64, PcapBlockOwned<'a>)>, Error> {
+        loop {
+            let offset = self.position as u64;
+            match self.parse_block(progress) {
+                Ok(block) => return Ok(block.map(|block| (offset, block))),
+                Err(SliceError::Invalid(len)) => {
+                    warn!("Skipping invalid block (block_index={})", self.block_index);
+                    if let Some(progress) = progress {
+                        progress.update(len, false);
+                    }
+                    self.position += len;
+                    self.block_index += 1;
+                    self.skipped_blocks += 1;
+                }
+                Err(SliceError::Error(e)) => return Err(e),
+            }
+        }
+    }
+
+    fn parse_block(
+        &mut self,
+        progress: &mut Option<ProgressReporter>,
+    ) -> Result<Option<PcapBlockOwned<'a>>, SliceError> {
+        let input: &'a [u8] = self.data;
+        let data = &input[self.position..];
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let res = parse_any_block(data, &mut self.format);
+        match res {
+            Ok((rem, block)) => {
+                let len = data.len() - rem.len();
+                let header = (self.position as u64, len as u64);
+                match block {
+                    PcapBlockOwned::LegacyHeader(_)
+                    | PcapBlockOwned::NG(Block::SectionHeader(_)) => self.headers = vec![header],
+                    PcapBlockOwned::NG(Block::InterfaceDescription(_)) => self.headers.push(header),
+                    _ => (),
+                }
+                if let Some(progress) = progress {
+                    progress.update(len, is_packet_block(&block));
+                }
+                self.position += len;
+                self.block_index += 1;
+                Ok(Some(block))
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                warn!(
+                    "Could not read complete data block (block_index={})",
+                    self.block_index
+                );
+                warn!("Hint: the input file may be truncated.");
+                Ok(None)
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                if let SliceFormat::NG { big_endian } = self.format {
+                    if self.skip_invalid {
+                        if let Some(len) = ng_block_len(data, big_endian) {
+                            debug!("error while reading: {:?}", e);
+                            return Err(SliceError::Invalid(len));
+                        }
+                    }
+                }
+                error!(
+                    "error while reading: {:?} (block_index={})",
+                    e, self.block_index
+                );
+                Err(SliceError::Error(Error::Parse {
+                    block_index: self.block_index,
+                    offset: self.position as u64,
+                    source: e.to_owned_vec(),
+                }))
+            }
+        }
+    }
+}
+
+/// Error while parsing a block from data mapped in memory
+enum SliceError {
+    /// Invalid block, which can be skipped (length of the block)
+    Invalid(usize),
+    Error(Error),
+}
+
+/// Data block, kept while reading other inputs
+enum PendingBlock {
+    Legacy {
+        ts_sec: u32,
+        ts_usec: u32,
+        caplen: u32,
+        origlen: u32,
+        data: PacketBytes,
+    },
+    Enhanced {
+        if_id: u32,
+        ts_high: u32,
+        ts_low: u32,
+        caplen: u32,
+        origlen: u32,
+        data: PacketBytes,
+    },
+}
+
+impl PendingBlock {
+    fn from_block(block: &PcapBlockOwned, chunk: &DataChunk) -> Option<Self> {
+        let bytes = |data: &[u8]| {
+            chunk
+                .slice_ref(data)
+                .unwrap_or_else(|| PacketBytes::copy_from_slice(data))
+        };
+        match block {
+            PcapBlockOwned::Legacy(b) => Some(PendingBlock::Legacy {
+                ts_sec: b.ts_sec,
+                ts_usec: b.ts_usec,
+                caplen: b.caplen,
+                origlen: b.origlen,
+                data: bytes(b.data),
+            }),
+            PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => Some(PendingBlock::Enhanced {
+                if_id: epb.if_id,
+                ts_high: epb.ts_high,
+                ts_low: epb.ts_low,
+                caplen: epb.caplen,
+                origlen: epb.origlen,
+                data: bytes(epb.data),
+            }),
+            _ => None,
+        }
+    }
+
+    fn chunk(&self) -> &DataChunk {
+        match self {
+            PendingBlock::Legacy { data, .. } | PendingBlock::Enhanced { data, .. } => data.chunk(),
+        }
+    }
+
+    /// Rebuild the block, and call `f` on it
+    fn with_block<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&PcapBlockOwned) -> T,
+    {
+        let block = match self {
+            PendingBlock::Legacy {
+                ts_sec,
+                ts_usec,
+                caplen,
+                origlen,
+                data,
+            } => PcapBlockOwned::Legacy(LegacyPcapBlock {
+                ts_sec: *ts_sec,
+                ts_usec: *ts_usec,
+                caplen: *caplen,
+                origlen: *origlen,
+                data,
+            }),
+            PendingBlock::Enhanced {
+                if_id,
+                ts_high,
+                ts_low,
+                caplen,
+                origlen,
+                data,
+            } => PcapBlockOwned::NG(Block::EnhancedPacket(EnhancedPacketBlock {
+                block_type: EPB_MAGIC,
+                block_len1: 32 + data.len() as u32,
+                if_id: *if_id,
+                ts_high: *ts_high,
+                ts_low: *ts_low,
+                caplen: *caplen,
+                origlen: *origlen,
+                data,
+                options: Vec::new(),
+                block_len2: 32 + data.len() as u32,
+            })),
+        };
+        f(&block)
+    }
 }
@@ -0,0 +1,74 @@
+//! Checkpoints of the analysis state
+//!
+//! A [`Checkpoint`] stores the position in an input file, and the state of the analyzer at this
+//! position. An interrupted analysis can be resumed from the last checkpoint, instead of
+//! reading the file again from the start.
+//!
+//! Checkpoints are written periodically by engines (see [`CheckpointConfig`]), and when a run
+//! is stopped. The state of analyzers is saved using `PcapAnalyzer::save_state`.
+
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Analysis state, saved at a position of an input file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Size of the input file, to check that the same file is used to resume the analysis
+    pub file_size: u64,
+    /// Offset of the next block in the input file
+    pub offset: u64,
+    /// Number of blocks read
+    pub block_index: usize,
+    /// Position (offset, length) of the blocks required to parse the next blocks: pcap file
+    /// header, or Section Header Block and Interface Description Blocks of the current section
+    pub headers: Vec<(u64, u64)>,
+    /// State of the analyzer
+    pub state: serde_json::Value,
+}
+
+impl Checkpoint {
+    /// Read a checkpoint file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let checkpoint = serde_json::from_reader(BufReader::new(file)).map_err(io::Error::from)?;
+        Ok(checkpoint)
+    }
+
+    /// Write the checkpoint to a file
+    ///
+    /// Data is written to a temporary file, renamed when complete, so the previous checkpoint
+    /// is kept if writing fails.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self).map_err(io::Error::from)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Periodic checkpoints of a run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    /// Checkpoint file, overwritten by each checkpoint
+    pub path: PathBuf,
+    /// Minimum time between two checkpoints
+    pub interval: Duration,
+}
+
+impl CheckpointConfig {
+    pub fn new<P: Into<PathBuf>>(path: P, interval: Duration) -> Self {
+        CheckpointConfig {
+            path: path.into(),
+            interval,
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// Chunk of input data, shared by the packets parsed from it
+///
+/// Engines read their input by chunks (or map it in memory as a single chunk). A chunk is never
+/// modified once blocks are parsed from it: when more data is needed, a new chunk is allocated,
+/// and the previous one is released when the last reference to it is dropped.
+///
+/// Packets keep a reference to their chunk (see `Packet::chunk`), so analyzers can keep slices
+/// of packet data as [`PacketBytes`] after the packet was handled, without copying them.
+#[derive(Clone)]
+pub struct DataChunk(Arc<dyn AsRef<[u8]> + Send + Sync>);
+
+impl DataChunk {
+    pub fn new<T: AsRef<[u8]> + Send + Sync + 'static>(data: T) -> Self {
+        DataChunk(Arc::new(data))
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+
+    /// Return a reference to `subset`, which must be a slice of the data of the chunk
+    ///
+    /// Returns `None` if `subset` is not in the chunk (for ex. data which was copied or
+    /// reassembled).
+    pub fn slice_ref(&self, subset: &[u8]) -> Option<PacketBytes> {
+        let data = self.as_slice();
+        let start = (subset.as_ptr() as usize).checked_sub(data.as_ptr() as usize)?;
+        let end = start + subset.len();
+        if end > data.len() {
+            return None;
+        }
+        Some(PacketBytes {
+            chunk: self.clone(),
+            range: start..end,
+        })
+    }
+
+    /// Return `true` if both values reference the same chunk
+    pub fn ptr_eq(&self, other: &DataChunk) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for DataChunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DataChunk")
+            .field("len", &self.as_slice().len())
+            .finish()
+    }
+}
+
+/// Slice of packet data, keeping its chunk of input data alive
+///
+/// `PacketBytes` is cheap to clone, and can be kept (or sent to other threads) after the packet
+/// was handled. Data which is not in a chunk can be stored by copying it (see
+/// `PacketBytes::copy_from_slice`).
+#[derive(Clone)]
+pub struct PacketBytes {
+    chunk: DataChunk,
+    range: Range<usize>,
+}
+
+impl PacketBytes {
+    /// Copy `data` to a new chunk
+    pub fn copy_from_slice(data: &[u8]) -> Self {
+        PacketBytes::from(data.to_vec())
+    }
+
+    /// Return a sub-slice, sharing the same chunk
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "range out of bounds"
+        );
+        PacketBytes {
+            chunk: self.chunk.clone(),
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+
+    /// Return the chunk containing the data
+    pub fn chunk(&self) -> &DataChunk {
+        &self.chunk
+    }
+}
+
+impl From<Vec<u8>> for PacketBytes {
+    fn from(data: Vec<u8>) -> Self {
+        let range = 0..data.len();
+        PacketBytes {
+            chunk: DataChunk::new(data),
+            range,
+        }
+    }
+}
+
+impl Deref for PacketBytes {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &self.chunk.as_slice()[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for PacketBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for PacketBytes {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for PacketBytes {}
+
+impl fmt::Debug for PacketBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PacketBytes")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_slices() {
+        let chunk = DataChunk::new(vec![1u8, 2, 3, 4, 5, 6]);
+        let data = chunk.as_slice();
+        let bytes = chunk.slice_ref(&data[2..5]).expect("slice in chunk");
+        assert_eq!(&*bytes, &[3, 4, 5]);
+        assert!(bytes.chunk().ptr_eq(&chunk));
+        assert_eq!(&*bytes.slice(1..3), &[4, 5]);
+        // data outside the chunk
+        let other = vec![3u8, 4, 5];
+        assert!(chunk.slice_ref(&other).is_none());
+        assert_eq!(PacketBytes::copy_from_slice(&other), bytes);
+        // the chunk is kept alive by the slice
+        drop(chunk);
+        assert_eq!(&*bytes, &[3, 4, 5]);
+    }
+}
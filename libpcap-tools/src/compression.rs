@@ -0,0 +1,79 @@
+//! Detection and decompression of compressed inputs
+
+use std::io::{self, BufRead, BufReader, Read};
+
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+
+/// Compression format of a stream
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Detect compression format using the magic bytes at the start of `data`
+    pub fn of_magic(data: &[u8]) -> Compression {
+        if data.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if data.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if data.starts_with(LZ4_MAGIC) {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Wrap `input` in a decoder, if data is compressed (gzip, xz, zstd or lz4)
+///
+/// The format is detected from the content, not from the file name, so this also works on
+/// standard input. Concatenated gzip members (for ex. appended with `cat`) are all read.
+pub fn decompress_reader(input: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(input);
+    let compression = Compression::of_magic(reader.fill_buf()?);
+    debug!("Input compression: {:?}", compression);
+    let reader: Box<dyn Read> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::Lz4 => Box::new(lz4::Decoder::new(reader)?),
+    };
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompress_reader;
+    use flate2::write::GzEncoder;
+    use std::io::{Read, Write};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).expect("compress");
+        encoder.finish().expect("compress")
+    }
+
+    #[test]
+    fn concatenated_gzip_members() {
+        let mut input = gzip(b"first member, ");
+        input.extend(gzip(b"second member"));
+        let mut reader = decompress_reader(Box::new(std::io::Cursor::new(input))).expect("reader");
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).expect("decompress");
+        assert_eq!(output, b"first member, second member");
+    }
+}
@@ -1,6 +1,8 @@
 use std::io;
+use std::path::PathBuf;
 use std::str::FromStr;
 
+#[derive(Clone, Debug)]
 pub struct Config {
     value: toml::Value,
 }
@@ -38,6 +40,54 @@ impl Config {
         let item = self.get_value(k)?;
         item.as_bool()
     }
+    /// Get an entry of type string by path, as a file path
+    pub fn get_path<T: AsRef<str>>(&self, k: T) -> Option<PathBuf> {
+        self.get(k).map(PathBuf::from)
+    }
+    /// Get an entry of type array of strings by path
+    ///
+    /// Returns `None` if the entry is not an array, or if an element is not a string.
+    pub fn get_strings<T: AsRef<str>>(&self, k: T) -> Option<Vec<&str>> {
+        let item = self.get_value(k)?;
+        item.as_array()?.iter().map(|v| v.as_str()).collect()
+    }
+    /// Return true if an entry is present at path (of any type)
+    pub fn contains<T: AsRef<str>>(&self, k: T) -> bool {
+        self.get_value(k).is_some()
+    }
+    /// Return the keys of the root section
+    pub fn keys(&self) -> Vec<&str> {
+        match self.value.as_table() {
+            Some(t) => t.keys().map(|k| k.as_str()).collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Get a copy of the section at path, as a new configuration object
+    ///
+    /// Returns `None` if the section does not exist, or is not a table.
+    pub fn section<T: AsRef<str>>(&self, k: T) -> Option<Config> {
+        let item = self.get_value(k)?;
+        if item.is_table() {
+            Some(Config {
+                value: item.clone(),
+            })
+        } else {
+            None
+        }
+    }
+    /// Get the configuration of a plugin (section `plugin.<name>`)
+    ///
+    /// An empty configuration is returned if the section does not exist, so plugins can use
+    /// their default values.
+    pub fn plugin_config<T: AsRef<str>>(&self, name: T) -> Config {
+        self.get_value("plugin")
+            .and_then(|plugins| plugins.get(name.as_ref()))
+            .filter(|item| item.is_table())
+            .map(|item| Config {
+                value: item.clone(),
+            })
+            .unwrap_or_default()
+    }
     /// Add a new section at location path.
     /// To insert at root, use an empty path.
     pub fn add_section<T: AsRef<str>, V: ToString>(
@@ -125,4 +175,32 @@ mod tests {
         // println!("get -> {:?}", res);
         assert_eq!(res, Some("value2"));
     }
+    #[test]
+    fn config_plugin_sections() {
+        let mut config = Config::default();
+        let s = r#"
+            [plugin.rusticata]
+            protocols = ["tls", "ssh"]
+            log = true
+            [plugin.dump]
+            path = "/tmp/dump"
+            count = 3
+        "#;
+        config.load_config(s.as_bytes()).expect("load config");
+        let rusticata = config.plugin_config("rusticata");
+        assert_eq!(rusticata.get_strings("protocols"), Some(vec!["tls", "ssh"]));
+        assert_eq!(rusticata.get_bool("log"), Some(true));
+        assert_eq!(rusticata.get("log"), None);
+        let dump = config.plugin_config("dump");
+        assert_eq!(dump.get_path("path"), Some("/tmp/dump".into()));
+        assert_eq!(dump.get_usize("count"), Some(3));
+        let mut keys = dump.keys();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["count", "path"]);
+        // missing plugin section
+        let other = config.plugin_config("other");
+        assert!(other.keys().is_empty());
+        assert!(config.section("plugin.dump.path").is_none());
+        assert!(config.contains("plugin.dump.count"));
+    }
 }
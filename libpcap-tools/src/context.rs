@@ -1,16 +1,24 @@
+use crate::chunk::DataChunk;
 use crate::duration::Duration;
 use pcap_parser::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// Block parsing context
 #[derive(Clone, Default)]
 pub struct ParseBlockContext {
     /// Index of current block in the pcap file
     pub block_index: usize,
+    /// Offset of current block in the pcap file
+    pub offset: u64,
+    /// Chunk of input data containing the current block, if shared by the engine
+    pub chunk: Option<DataChunk>,
 }
 
 /// pcap parsing context
-#[derive(Clone, Default)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct ParseContext {
     /// Timestamp of first packet seen
     pub first_packet_ts: Duration,
@@ -18,10 +26,62 @@ pub struct ParseContext {
     pub rel_ts: Duration,
     /// Index of current packet in pcap file
     pub pcap_index: usize,
+    /// Index of current section in pcap file (a legacy pcap file has a single section)
+    pub section_index: usize,
+    /// Host names, from pcapng Name Resolution Blocks
+    pub hostnames: HashMap<IpAddr, Vec<String>>,
+    /// Last statistics of each interface (indexed by interface ID), from pcapng Interface
+    /// Statistics Blocks
+    pub interface_stats: HashMap<u32, InterfaceStatistics>,
+    /// Interfaces of the current section (indexed by interface ID)
+    ///
+    /// Interfaces are not saved in checkpoints, since the interface blocks are read again when
+    /// resuming.
+    #[serde(skip)]
+    pub interfaces: Vec<InterfaceInfo>,
+    /// Byte order of the current pcapng section, from its Section Header Block
+    #[serde(skip)]
+    pub big_endian: bool,
+}
+
+impl ParseContext {
+    /// Return the interface with ID `if_id` in the current section, if defined
+    pub fn interface(&self, if_id: u32) -> Option<&InterfaceInfo> {
+        self.interfaces.get(if_id as usize)
+    }
+}
+
+/// Statistics of a capture interface, from a pcapng Interface Statistics Block
+///
+/// Counters are `None` if not present in the block.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceStatistics {
+    /// Interface ID
+    pub if_id: u32,
+    /// Time of the statistics
+    pub ts: Duration,
+    /// Number of packets received by the interface (`isb_ifrecv`)
+    pub if_recv: Option<u64>,
+    /// Number of packets dropped by the interface, for ex. due to lack of resources
+    /// (`isb_ifdrop`)
+    pub if_drop: Option<u64>,
+    /// Number of packets accepted by the capture filter (`isb_filteraccept`)
+    pub filter_accept: Option<u64>,
+    /// Number of packets dropped by the operating system (`isb_osdrop`)
+    pub os_drop: Option<u64>,
+    /// Number of packets delivered to the user (`isb_usrdeliv`)
+    pub usr_deliv: Option<u64>,
+}
+
+impl InterfaceStatistics {
+    /// Return the total number of dropped packets (interface and operating system)
+    pub fn drops(&self) -> u64 {
+        self.if_drop.unwrap_or(0) + self.os_drop.unwrap_or(0)
+    }
 }
 
 /// Information related to a network interface used for capture
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct InterfaceInfo {
     /// The `Linktype` used for data format
     pub link_type: Linktype,
@@ -33,6 +93,10 @@ pub struct InterfaceInfo {
     pub if_tsoffset: u64,
     /// Maximum number of octets captured from each packet.
     pub snaplen: u32,
+    /// Name of the interface (pcapng `if_name` option)
+    pub if_name: Option<String>,
+    /// Description of the interface (pcapng `if_description` option)
+    pub if_description: Option<String>,
 }
 
 impl Default for InterfaceInfo {
@@ -43,6 +107,8 @@ impl Default for InterfaceInfo {
             ts_unit: 0,
             if_tsoffset: 0,
             snaplen: 0,
+            if_name: None,
+            if_description: None,
         }
     }
 }
@@ -52,6 +118,8 @@ pub fn pcapng_build_interface<'a>(idb: &'a InterfaceDescriptionBlock<'a>) -> Int
     let mut if_tsresol: u8 = 6;
     let mut ts_unit: u64 = 1_000_000;
     let mut if_tsoffset: u64 = 0;
+    let mut if_name = None;
+    let mut if_description = None;
     for opt in idb.options.iter() {
         match opt.code {
             OptionCode::IfTsresol => {
@@ -64,10 +132,14 @@ pub fn pcapng_build_interface<'a>(idb: &'a InterfaceDescriptionBlock<'a>) -> Int
             }
             OptionCode::IfTsoffset => {
                 if opt.value.len() >= 8 {
-                    let int_bytes = <[u8; 8]>::try_from(opt.value).expect("Convert bytes to u64");
+                    let int_bytes =
+                        <[u8; 8]>::try_from(&opt.value[..8]).expect("Convert bytes to u64");
                     if_tsoffset = u64::from_le_bytes(int_bytes) /* LittleEndian::read_u64(opt.value) */;
                 }
             }
+            // if_name and if_description (the codes are also used by SHB options)
+            OptionCode(2) => if_name = Some(option_string(opt.value)),
+            OptionCode(3) => if_description = Some(option_string(opt.value)),
             _ => (),
         }
     }
@@ -77,7 +149,91 @@ pub fn pcapng_build_interface<'a>(idb: &'a InterfaceDescriptionBlock<'a>) -> Int
         ts_unit,
         if_tsoffset,
         snaplen: idb.snaplen,
+        if_name,
+        if_description,
+    }
+}
+
+/// Decode a string option (UTF-8, possibly zero-terminated)
+fn option_string(value: &[u8]) -> String {
+    String::from_utf8_lossy(value)
+        .trim_end_matches('\0')
+        .to_owned()
+}
+
+/// Build interface statistics from an ISB. `if_info` is the interface of the block, if known,
+/// and `big_endian` the byte order of its section
+pub fn pcapng_build_interface_statistics(
+    isb: &InterfaceStatisticsBlock,
+    if_info: Option<&InterfaceInfo>,
+    big_endian: bool,
+) -> InterfaceStatistics {
+    let ts = match if_info {
+        Some(if_info) if if_info.ts_unit > 0 => {
+            let (secs, frac) = build_ts(
+                isb.ts_high,
+                isb.ts_low,
+                if_info.if_tsoffset,
+                if_info.ts_unit,
+            );
+            let nanos = u64::from(frac) * 1_000_000_000 / if_info.ts_unit;
+            Duration::from_nanos(secs, nanos as u32)
+        }
+        _ => Duration::default(),
+    };
+    let mut stats = InterfaceStatistics {
+        if_id: isb.if_id,
+        ts,
+        ..InterfaceStatistics::default()
+    };
+    for opt in isb.options.iter() {
+        if opt.value.len() < 8 {
+            continue;
+        }
+        let int_bytes = <[u8; 8]>::try_from(&opt.value[..8]).expect("Convert bytes to u64");
+        let value = Some(if big_endian {
+            u64::from_be_bytes(int_bytes)
+        } else {
+            u64::from_le_bytes(int_bytes)
+        });
+        match opt.code.0 {
+            4 => stats.if_recv = value,
+            5 => stats.if_drop = value,
+            6 => stats.filter_accept = value,
+            7 => stats.os_drop = value,
+            8 => stats.usr_deliv = value,
+            _ => (),
+        }
+    }
+    stats
+}
+
+/// Return the (address, host name) records of a NRB
+///
+/// A record can contain several names for the same address. Invalid records are ignored.
+pub fn pcapng_parse_name_resolution(nrb: &NameResolutionBlock) -> Vec<(IpAddr, String)> {
+    let mut names = Vec::new();
+    for record in nrb.nr.iter() {
+        let (addr, value): (IpAddr, &[u8]) = match record.record_type {
+            NameRecordType::Ipv4 if record.record_value.len() > 4 => {
+                let octets = <[u8; 4]>::try_from(&record.record_value[..4]).expect("IPv4 address");
+                (Ipv4Addr::from(octets).into(), &record.record_value[4..])
+            }
+            NameRecordType::Ipv6 if record.record_value.len() > 16 => {
+                let octets =
+                    <[u8; 16]>::try_from(&record.record_value[..16]).expect("IPv6 address");
+                (Ipv6Addr::from(octets).into(), &record.record_value[16..])
+            }
+            _ => continue,
+        };
+        // names are zero-terminated strings
+        for name in value.split(|&b| b == 0).filter(|s| !s.is_empty()) {
+            if let Ok(name) = std::str::from_utf8(name) {
+                names.push((addr, name.to_owned()));
+            }
+        }
     }
+    names
 }
 
 // pub fn pcapng_build_packet<'a>(
@@ -1,19 +1,163 @@
 use crate::analyzer::PcapAnalyzer;
 use crate::block_engine::{BlockAnalyzer, BlockEngine};
+use crate::checkpoint::{Checkpoint, CheckpointConfig};
+use crate::chunk::DataChunk;
 use crate::config::Config;
 use crate::context::*;
-use crate::duration::{Duration, MICROS_PER_SEC};
+use crate::duration::{Duration, NANOS_PER_SEC};
+use crate::engine::InputMode;
 use crate::engine::PcapEngine;
-use crate::error::Error;
+use crate::error::{BlockType, Error, ErrorCounters};
 use crate::packet::Packet;
-use pcap_parser::{Block, PcapBlockOwned};
+use crate::progress::ProgressReporter;
+use pcap_parser::pcapng::EnhancedPacketBlock;
+use pcap_parser::{Block, LegacyPcapBlock, PcapBlockOwned, PcapHeader};
+use serde::Deserialize;
+use std::fs::File;
 use std::io::Read;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 struct PcapDataAnalyzer<A: PcapAnalyzer> {
     data_analyzer: A,
 
     ctx: ParseContext,
-    interfaces: Vec<InterfaceInfo>,
+    /// True if a section was started (and `section_end` was not called yet)
+    in_section: bool,
+    /// Index of the current input, when reading multiple inputs at the same time
+    current_input: usize,
+    /// Interfaces of the other inputs
+    saved_interfaces: Vec<Vec<InterfaceInfo>>,
+}
+
+fn epb_timestamp(if_info: &InterfaceInfo, epb: &EnhancedPacketBlock) -> Duration {
+    let unit = if_info.ts_unit;
+    let (ts_sec, ts_frac) =
+        pcap_parser::build_ts(epb.ts_high, epb.ts_low, if_info.if_tsoffset, unit);
+    // convert the fractional part to nanoseconds (`unit` can be a power of 2)
+    let ts_nanos = if unit != u64::from(NANOS_PER_SEC) {
+        (u64::from(ts_frac) * u64::from(NANOS_PER_SEC) / unit.max(1)) as u32
+    } else {
+        ts_frac
+    };
+    Duration::from_nanos(ts_sec, ts_nanos)
+}
+
+fn legacy_timestamp(if_info: &InterfaceInfo, b: &LegacyPcapBlock) -> Duration {
+    if if_info.if_tsresol == 6 {
+        Duration::new(b.ts_sec, b.ts_usec)
+    } else {
+        Duration::from_nanos(b.ts_sec, b.ts_usec)
+    }
+}
+
+/// Build the interface of a legacy pcap file, from the file header
+pub(crate) fn legacy_interface(hdr: &PcapHeader) -> InterfaceInfo {
+    let precision = if hdr.is_nanosecond_precision() { 9 } else { 6 };
+    let ts_unit = if hdr.is_nanosecond_precision() {
+        1_000_000_000
+    } else {
+        1_000_000
+    };
+    InterfaceInfo {
+        link_type: hdr.network,
+        if_tsoffset: 0,
+        if_tsresol: precision,
+        ts_unit,
+        snaplen: hdr.snaplen,
+        ..InterfaceInfo::default()
+    }
+}
+
+/// Build the packet of a data block (Enhanced Packet, Simple Packet or legacy pcap block), and
+/// update the packet index and timestamps of `ctx`. `chunk` is the chunk of input data
+/// containing the block, if any
+///
+/// Returns `None` if the block does not contain packet data.
+pub(crate) fn block_packet<'a>(
+    block: &'a PcapBlockOwned,
+    ctx: &mut ParseContext,
+    chunk: Option<&DataChunk>,
+) -> Result<Option<Packet<'a>>, Error> {
+    let packet = match block {
+        PcapBlockOwned::NG(Block::EnhancedPacket(ref epb)) => {
+            ctx.pcap_index += 1;
+            let if_info = ctx
+                .interfaces
+                .get(epb.if_id as usize)
+                .ok_or(Error::UnknownInterface(epb.if_id))?;
+            let ts = epb_timestamp(if_info, epb);
+            let data =
+                pcap_parser::data::get_packetdata(epb.data, if_info.link_type, epb.caplen as usize)
+                    .ok_or(Error::PacketData(if_info.link_type))?;
+            Packet {
+                interface: epb.if_id,
+                ts,
+                link_type: if_info.link_type,
+                data,
+                origlen: epb.origlen,
+                caplen: epb.caplen,
+                pcap_index: ctx.pcap_index,
+                chunk: chunk.cloned(),
+            }
+        }
+        PcapBlockOwned::NG(Block::SimplePacket(ref spb)) => {
+            ctx.pcap_index += 1;
+            let if_info = ctx.interfaces.first().ok_or(Error::UnknownInterface(0))?;
+            let blen = spb.block_len1.saturating_sub(16) as usize;
+            let data = pcap_parser::data::get_packetdata(spb.data, if_info.link_type, blen)
+                .ok_or(Error::PacketData(if_info.link_type))?;
+            Packet {
+                interface: 0,
+                ts: Duration::default(),
+                data,
+                link_type: if_info.link_type,
+                origlen: spb.origlen,
+                caplen: if_info.snaplen,
+                pcap_index: ctx.pcap_index,
+                chunk: chunk.cloned(),
+            }
+        }
+        PcapBlockOwned::Legacy(ref b) => {
+            ctx.pcap_index += 1;
+            let if_info = ctx.interfaces.first().ok_or(Error::UnknownInterface(0))?;
+            let blen = b.caplen as usize;
+            let data = pcap_parser::data::get_packetdata(b.data, if_info.link_type, blen)
+                .ok_or(Error::PacketData(if_info.link_type))?;
+            let ts = legacy_timestamp(if_info, b);
+            Packet {
+                interface: 0,
+                ts,
+                link_type: if_info.link_type,
+                data,
+                origlen: b.origlen,
+                caplen: b.caplen,
+                pcap_index: ctx.pcap_index,
+                chunk: chunk.cloned(),
+            }
+        }
+        _ => return Ok(None),
+    };
+    trace!("**************************************************************");
+    // build ts
+    if ctx.first_packet_ts.is_null() {
+        ctx.first_packet_ts = packet.ts;
+    }
+    trace!("    time  : {} / {:09}", packet.ts.secs, packet.ts.nanos);
+    ctx.rel_ts = packet.ts - ctx.first_packet_ts; // an underflow is weird but not critical
+    trace!("    reltime  : {}.{:09}", ctx.rel_ts.secs, ctx.rel_ts.nanos);
+    Ok(Some(packet))
+}
+
+/// Add the names of a Name Resolution Block to the host names of `ctx`
+pub(crate) fn add_hostnames(ctx: &mut ParseContext, names: &[(IpAddr, String)]) {
+    for (addr, name) in names {
+        let entry = ctx.hostnames.entry(*addr).or_insert_with(Vec::new);
+        if !entry.contains(name) {
+            entry.push(name.clone());
+        }
+    }
 }
 
 /// pcap/pcap-ng data analyzer engine
@@ -67,163 +211,232 @@ impl<A: PcapAnalyzer> PcapDataEngine<A> {
     pub fn data_analyzer_mut(&mut self) -> &mut A {
         &mut self.engine.analyzer_mut().data_analyzer
     }
+
+    /// Return the number of errors skipped during the last run (see `ErrorPolicy`)
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.engine.error_counters()
+    }
 }
 
 impl<A: PcapAnalyzer> PcapDataAnalyzer<A> {
     pub fn new(data_analyzer: A) -> Self {
         let ctx = ParseContext::default();
-        let interfaces = Vec::new();
         PcapDataAnalyzer {
             data_analyzer,
             ctx,
-            interfaces,
+            in_section: false,
+            current_input: 0,
+            saved_interfaces: Vec::new(),
         }
     }
-}
 
-impl<A: PcapAnalyzer> PcapEngine for PcapDataEngine<A> {
-    fn run(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
-        self.engine.run(reader)
+    /// End the current section (if any), and start a new one
+    fn start_section(&mut self) -> Result<(), Error> {
+        if self.in_section {
+            self.data_analyzer.section_end(&self.ctx)?;
+            self.ctx.section_index += 1;
+        }
+        // reset section-related variables
+        self.ctx.interfaces = Vec::new();
+        self.ctx.interface_stats.clear();
+        self.in_section = true;
+        self.data_analyzer.section_start(&self.ctx)
     }
-}
 
-impl<A: PcapAnalyzer> BlockAnalyzer for PcapDataAnalyzer<A> {
-    fn init(&mut self) -> Result<(), Error> {
-        self.data_analyzer.init()
+    /// Add an interface to the current section, and notify the data analyzer
+    fn add_interface(&mut self, if_info: InterfaceInfo) -> Result<(), Error> {
+        let if_id = self.ctx.interfaces.len();
+        self.ctx.interfaces.push(if_info);
+        self.data_analyzer.handle_interface_added(
+            if_id as u32,
+            &self.ctx.interfaces[if_id],
+            &self.ctx,
+        )
     }
 
-    fn handle_block(
+    /// Parse a block, and call the data analyzer
+    fn handle_data_block(
         &mut self,
         block: &PcapBlockOwned,
         block_ctx: &ParseBlockContext,
     ) -> Result<(), Error> {
         self.data_analyzer.handle_block(block, block_ctx)?;
         let packet = match block {
-            PcapBlockOwned::NG(Block::SectionHeader(_)) => {
-                // reset section-related variables
-                self.interfaces = Vec::new();
-                return Ok(());
+            PcapBlockOwned::NG(Block::SectionHeader(ref shb)) => {
+                self.ctx.big_endian = shb.big_endian();
+                return self.start_section();
             }
             PcapBlockOwned::NG(Block::InterfaceDescription(ref idb)) => {
                 let if_info = pcapng_build_interface(idb);
-                self.interfaces.push(if_info);
-                return Ok(());
-            }
-            PcapBlockOwned::NG(Block::EnhancedPacket(ref epb)) => {
-                self.ctx.pcap_index += 1;
-                assert!((epb.if_id as usize) < self.interfaces.len());
-                let if_info = &self.interfaces[epb.if_id as usize];
-                let unit = if_info.ts_unit;
-                let (ts_sec, ts_frac) =
-                    pcap_parser::build_ts(epb.ts_high, epb.ts_low, if_info.if_tsoffset, unit);
-                let unit = unit as u32; // XXX lossy cast
-                let ts_usec = if unit != MICROS_PER_SEC {
-                    ts_frac / ((unit / MICROS_PER_SEC) as u32)
-                } else {
-                    ts_frac
-                };
-                let ts = Duration::new(ts_sec, ts_usec);
-                let data = pcap_parser::data::get_packetdata(
-                    epb.data,
-                    if_info.link_type,
-                    epb.caplen as usize,
-                )
-                .ok_or(Error::Generic("Parsing PacketData failed (EnhancedPacket)"))?;
-                Packet {
-                    interface: epb.if_id,
-                    ts,
-                    link_type: if_info.link_type,
-                    data,
-                    origlen: epb.origlen,
-                    caplen: epb.caplen,
-                    pcap_index: self.ctx.pcap_index,
-                }
-            }
-            PcapBlockOwned::NG(Block::SimplePacket(ref spb)) => {
-                self.ctx.pcap_index += 1;
-                assert!(!self.interfaces.is_empty());
-                let if_info = &self.interfaces[0];
-                let blen = (spb.block_len1 - 16) as usize;
-                let data = pcap_parser::data::get_packetdata(spb.data, if_info.link_type, blen)
-                    .ok_or(Error::Generic("Parsing PacketData failed (SimplePacket)"))?;
-                Packet {
-                    interface: 0,
-                    ts: Duration::default(),
-                    data,
-                    link_type: if_info.link_type,
-                    origlen: spb.origlen,
-                    caplen: if_info.snaplen,
-                    pcap_index: self.ctx.pcap_index,
-                }
+                return self.add_interface(if_info);
             }
             PcapBlockOwned::LegacyHeader(ref hdr) => {
-                let precision = if hdr.is_nanosecond_precision() { 9 } else { 6 };
-                let ts_unit = if hdr.is_nanosecond_precision() {
-                    1_000_000_000
-                } else {
-                    1_000_000
-                };
-                let if_info = InterfaceInfo {
-                    link_type: hdr.network,
-                    if_tsoffset: 0,
-                    if_tsresol: precision,
-                    ts_unit,
-                    snaplen: hdr.snaplen,
-                };
-                self.interfaces.push(if_info);
+                self.start_section()?;
                 trace!("Legacy pcap,  link type: {}", hdr.network);
-                return Ok(());
+                return self.add_interface(legacy_interface(hdr));
             }
-            PcapBlockOwned::Legacy(ref b) => {
-                self.ctx.pcap_index += 1;
-                assert!(!self.interfaces.is_empty());
-                let if_info = &self.interfaces[0];
-                let blen = b.caplen as usize;
-                let data = pcap_parser::data::get_packetdata(b.data, if_info.link_type, blen)
-                    .ok_or(Error::Generic("Parsing PacketData failed (Legacy Packet)"))?;
-                let ts = if if_info.if_tsresol == 6 {
-                    Duration::new(b.ts_sec, b.ts_usec)
-                } else {
-                    Duration::new(b.ts_sec, b.ts_usec / 1000)
-                };
-                Packet {
-                    interface: 0,
-                    ts,
-                    link_type: if_info.link_type,
-                    data,
-                    origlen: b.origlen,
-                    caplen: b.caplen,
-                    pcap_index: self.ctx.pcap_index,
-                }
+            PcapBlockOwned::NG(Block::InterfaceStatistics(ref isb)) => {
+                let if_info = self.ctx.interface(isb.if_id);
+                let stats = pcapng_build_interface_statistics(isb, if_info, self.ctx.big_endian);
+                debug!(
+                    "Interface {} statistics: drops={} (if_drop={:?}, os_drop={:?})",
+                    stats.if_id,
+                    stats.drops(),
+                    stats.if_drop,
+                    stats.os_drop
+                );
+                self.ctx.interface_stats.insert(stats.if_id, stats.clone());
+                return self
+                    .data_analyzer
+                    .handle_interface_statistics(&stats, &self.ctx);
             }
-            PcapBlockOwned::NG(Block::InterfaceStatistics(_))
-            | PcapBlockOwned::NG(Block::NameResolution(_)) => {
-                // XXX just ignore block
-                return Ok(());
-            }
-            _ => {
-                warn!("unsupported block");
-                return Ok(());
+            PcapBlockOwned::NG(Block::NameResolution(ref nrb)) => {
+                let names = pcapng_parse_name_resolution(nrb);
+                trace!("Name resolution block: {} records", names.len());
+                add_hostnames(&mut self.ctx, &names);
+                return self.data_analyzer.handle_name_resolution(&names, &self.ctx);
             }
+            _ => match block_packet(block, &mut self.ctx, block_ctx.chunk.as_ref())? {
+                Some(packet) => packet,
+                None => {
+                    warn!("unsupported block");
+                    return Ok(());
+                }
+            },
         };
-        trace!("**************************************************************");
-        // build ts
-        if self.ctx.first_packet_ts.is_null() {
-            self.ctx.first_packet_ts = packet.ts;
-        }
-        trace!("    time  : {} / {:06}", packet.ts.secs, packet.ts.micros);
-        self.ctx.rel_ts = packet.ts - self.ctx.first_packet_ts; // an underflow is weird but not critical
-        trace!(
-            "    reltime  : {}.{:06}",
-            self.ctx.rel_ts.secs,
-            self.ctx.rel_ts.micros
-        );
         // call data analyzer
         self.data_analyzer.handle_packet(&packet, &self.ctx)?;
         Ok(())
     }
+}
+
+impl<A: PcapAnalyzer> PcapEngine for PcapDataEngine<A> {
+    fn run(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
+        self.engine.run(reader)
+    }
+
+    /// Read all pcap data of a file, mapping it in memory if possible
+    ///
+    /// Pipes and other special files are read as a stream.
+    fn run_file(&mut self, file: &File) -> Result<(), Error> {
+        self.engine.run_mmap(file)
+    }
+
+    fn set_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.engine.set_progress_reporter(reporter)
+    }
+
+    fn set_stop_handle(&mut self, stop: Arc<AtomicBool>) {
+        self.engine.set_stop_handle(stop)
+    }
+
+    fn set_checkpoint_config(&mut self, config: CheckpointConfig) {
+        self.engine.set_checkpoint_config(config)
+    }
+
+    /// Resume reading a file from a checkpoint
+    ///
+    /// The `section_start` callback is called again for the section of the checkpoint.
+    fn resume_file(&mut self, file: &File, checkpoint: Checkpoint) -> Result<(), Error> {
+        self.engine.resume_mmap(file, checkpoint)
+    }
+
+    /// Read multiple inputs as a single capture
+    ///
+    /// The analyzer is initialized once, so its state (for ex. flows) is kept across inputs.
+    /// Each input starts a new section. When merging inputs, sections of the inputs are
+    /// interleaved: packets of an input can be received after the `section_start` callback of
+    /// another input.
+    fn run_inputs(&mut self, readers: &mut [Box<dyn Read>], mode: InputMode) -> Result<(), Error> {
+        match mode {
+            InputMode::Sequential => self.engine.run_sequential(readers),
+            InputMode::Merge => self.engine.run_merged(readers),
+        }
+    }
+}
+
+impl<A: PcapAnalyzer> BlockAnalyzer for PcapDataAnalyzer<A> {
+    fn init(&mut self) -> Result<(), Error> {
+        self.in_section = false;
+        self.ctx.section_index = 0;
+        self.current_input = 0;
+        self.saved_interfaces.clear();
+        self.data_analyzer.init()
+    }
+
+    fn save_state(&self) -> Result<serde_json::Value, Error> {
+        let ctx = serde_json::to_value(&self.ctx)
+            .map_err(|_| Error::Checkpoint("could not serialize the parse context"))?;
+        let mut state = serde_json::Map::new();
+        state.insert("ctx".to_owned(), ctx);
+        state.insert("analyzer".to_owned(), self.data_analyzer.save_state()?);
+        Ok(serde_json::Value::Object(state))
+    }
+
+    fn restore_state(&mut self, state: &serde_json::Value) -> Result<(), Error> {
+        let ctx = state
+            .get("ctx")
+            .ok_or(Error::Checkpoint("missing parse context"))?;
+        self.ctx = ParseContext::deserialize(ctx)
+            .map_err(|_| Error::Checkpoint("invalid parse context"))?;
+        self.data_analyzer.restore_state(&state["analyzer"])
+    }
+
+    fn set_input(&mut self, index: usize) {
+        if index == self.current_input {
+            return;
+        }
+        let len = self
+            .saved_interfaces
+            .len()
+            .max(index + 1)
+            .max(self.current_input + 1);
+        self.saved_interfaces.resize_with(len, Vec::new);
+        std::mem::swap(
+            &mut self.ctx.interfaces,
+            &mut self.saved_interfaces[self.current_input],
+        );
+        std::mem::swap(&mut self.ctx.interfaces, &mut self.saved_interfaces[index]);
+        self.current_input = index;
+    }
+
+    fn block_timestamp(&self, block: &PcapBlockOwned) -> Option<Duration> {
+        match block {
+            PcapBlockOwned::NG(Block::EnhancedPacket(ref epb)) => self
+                .ctx
+                .interface(epb.if_id)
+                .map(|if_info| epb_timestamp(if_info, epb)),
+            PcapBlockOwned::Legacy(ref b) => self
+                .ctx
+                .interfaces
+                .first()
+                .map(|if_info| legacy_timestamp(if_info, b)),
+            _ => None,
+        }
+    }
+
+    fn handle_block(
+        &mut self,
+        block: &PcapBlockOwned,
+        block_ctx: &ParseBlockContext,
+    ) -> Result<(), Error> {
+        self.handle_data_block(block, block_ctx)
+            .map_err(|e| Error::Block {
+                block_index: block_ctx.block_index,
+                offset: block_ctx.offset,
+                block_type: BlockType::of(block),
+                pcap_index: self.ctx.pcap_index,
+                source: Box::new(e),
+            })
+    }
 
     fn teardown(&mut self) {
+        if self.in_section {
+            self.in_section = false;
+            if let Err(e) = self.data_analyzer.section_end(&self.ctx) {
+                warn!("section_end returned error: {}", e);
+            }
+        }
         self.data_analyzer.teardown()
     }
 
@@ -231,3 +444,406 @@ impl<A: PcapAnalyzer> BlockAnalyzer for PcapDataAnalyzer<A> {
         self.data_analyzer.before_refill()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::PacketBytes;
+    use pcap_parser::data::PacketData;
+    use pcap_parser::Linktype;
+    use std::io::Cursor;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[derive(Default)]
+    struct BlockCounter {
+        names: Vec<(IpAddr, String)>,
+        drops: u64,
+        /// (section index, start or end) events
+        sections: Vec<(usize, bool)>,
+        /// Timestamps (seconds) of packets
+        packets: Vec<u32>,
+        /// (interface ID, name) events
+        interfaces: Vec<(u32, Option<String>)>,
+    }
+
+    impl PcapAnalyzer for BlockCounter {
+        fn section_start(&mut self, ctx: &ParseContext) -> Result<(), Error> {
+            self.sections.push((ctx.section_index, true));
+            Ok(())
+        }
+
+        fn section_end(&mut self, ctx: &ParseContext) -> Result<(), Error> {
+            self.sections.push((ctx.section_index, false));
+            Ok(())
+        }
+
+        fn handle_packet(&mut self, packet: &Packet, _ctx: &ParseContext) -> Result<(), Error> {
+            self.packets.push(packet.ts.secs);
+            Ok(())
+        }
+
+        fn save_state(&self) -> Result<serde_json::Value, Error> {
+            Ok(serde_json::json!(self.packets))
+        }
+
+        fn restore_state(&mut self, state: &serde_json::Value) -> Result<(), Error> {
+            self.packets = Vec::deserialize(state).map_err(|_| Error::Generic("Invalid state"))?;
+            Ok(())
+        }
+
+        fn handle_name_resolution(
+            &mut self,
+            names: &[(IpAddr, String)],
+            _ctx: &ParseContext,
+        ) -> Result<(), Error> {
+            self.names.extend_from_slice(names);
+            Ok(())
+        }
+
+        fn handle_interface_added(
+            &mut self,
+            if_id: u32,
+            info: &InterfaceInfo,
+            ctx: &ParseContext,
+        ) -> Result<(), Error> {
+            assert_eq!(ctx.interfaces.len(), if_id as usize + 1);
+            self.interfaces.push((if_id, info.if_name.clone()));
+            Ok(())
+        }
+
+        fn handle_interface_statistics(
+            &mut self,
+            stats: &InterfaceStatistics,
+            ctx: &ParseContext,
+        ) -> Result<(), Error> {
+            assert_eq!(ctx.interface_stats.get(&0), Some(stats));
+            self.drops = stats.drops();
+            Ok(())
+        }
+    }
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = (body.len() + 12) as u32;
+        let mut v = Vec::new();
+        v.extend_from_slice(&block_type.to_le_bytes());
+        v.extend_from_slice(&len.to_le_bytes());
+        v.extend_from_slice(body);
+        v.extend_from_slice(&len.to_le_bytes());
+        v
+    }
+
+    fn shb() -> Vec<u8> {
+        let mut shb = vec![0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0];
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        block(0x0a0d_0d0a, &shb)
+    }
+
+    #[test]
+    fn name_resolution_and_interface_statistics() {
+        let mut data = shb();
+        // IDB: ethernet, snaplen 65535
+        data.extend(block(1, &[1, 0, 0, 0, 0xff, 0xff, 0, 0]));
+        // NRB: 10.0.0.1 -> "host", end of records
+        let nrb = [
+            1, 0, 9, 0, 10, 0, 0, 1, b'h', b'o', b's', b't', 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        data.extend(block(4, &nrb));
+        // ISB: isb_ifdrop = 2, isb_osdrop = 3, end of options
+        let mut isb = vec![0; 12];
+        isb.extend_from_slice(&[5, 0, 8, 0]);
+        isb.extend_from_slice(&2u64.to_le_bytes());
+        isb.extend_from_slice(&[7, 0, 8, 0]);
+        isb.extend_from_slice(&3u64.to_le_bytes());
+        isb.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend(block(5, &isb));
+
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run(&mut Cursor::new(data)).expect("run engine");
+        let analyzer = engine.data_analyzer();
+        assert_eq!(
+            analyzer.names,
+            vec![(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), "host".to_owned())]
+        );
+        assert_eq!(analyzer.drops, 5);
+    }
+
+    #[test]
+    fn interface_statistics_big_endian() {
+        let block_be = |block_type: u32, body: &[u8]| {
+            let len = (body.len() + 12) as u32;
+            let mut v = block_type.to_be_bytes().to_vec();
+            v.extend_from_slice(&len.to_be_bytes());
+            v.extend_from_slice(body);
+            v.extend_from_slice(&len.to_be_bytes());
+            v
+        };
+        let mut shb = vec![0x1a, 0x2b, 0x3c, 0x4d, 0, 1, 0, 0];
+        shb.extend_from_slice(&(-1i64).to_be_bytes());
+        let mut data = block_be(0x0a0d_0d0a, &shb);
+        // IDB: ethernet, snaplen 65535
+        data.extend(block_be(1, &[0, 1, 0, 0, 0, 0, 0xff, 0xff]));
+        // ISB: isb_ifdrop = 2, isb_osdrop = 3, end of options
+        let mut isb = vec![0; 12];
+        isb.extend_from_slice(&[0, 5, 0, 8]);
+        isb.extend_from_slice(&2u64.to_be_bytes());
+        isb.extend_from_slice(&[0, 7, 0, 8]);
+        isb.extend_from_slice(&3u64.to_be_bytes());
+        isb.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend(block_be(5, &isb));
+
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run(&mut Cursor::new(data)).expect("run engine");
+        assert_eq!(engine.data_analyzer().drops, 5);
+    }
+
+    /// EPB with an empty packet, `ts` is in seconds
+    fn epb(if_id: u32, ts: u32) -> Vec<u8> {
+        let mut epb = if_id.to_le_bytes().to_vec();
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&(ts * 1_000_000).to_le_bytes());
+        epb.extend_from_slice(&[0; 8]);
+        block(6, &epb)
+    }
+
+    #[test]
+    fn error_policy() {
+        let mut data = shb();
+        data.extend(block(1, &[1, 0, 0, 0, 0xff, 0xff, 0, 0]));
+        data.extend(epb(0, 1));
+        // invalid interface id
+        data.extend(epb(3, 2));
+        data.extend(epb(0, 3));
+
+        let mut config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        match engine.run(&mut Cursor::new(&data)) {
+            Err(Error::Block {
+                block_index,
+                offset,
+                block_type,
+                pcap_index,
+                source,
+            }) => {
+                assert_eq!(block_index, 3);
+                // SHB (28 bytes), IDB (20 bytes), EPB (32 bytes)
+                assert_eq!(offset, 80);
+                assert_eq!(block_type, BlockType::NG(6));
+                assert_eq!(pcap_index, 2);
+                assert!(matches!(*source, Error::UnknownInterface(3)));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(engine.data_analyzer().packets, vec![1]);
+
+        config.set("error_policy", "skip-packet");
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run(&mut Cursor::new(&data)).expect("run engine");
+        assert_eq!(engine.data_analyzer().packets, vec![1, 3]);
+        assert_eq!(engine.error_counters().skipped_packets, 1);
+    }
+
+    #[test]
+    fn interface_added() {
+        let mut data = shb();
+        // IDB: ethernet, snaplen 65535, if_name "eth0", if_tsresol 10^-9, end of options
+        let mut idb = vec![1, 0, 0, 0, 0xff, 0xff, 0, 0];
+        idb.extend_from_slice(&[2, 0, 4, 0, b'e', b't', b'h', b'0']);
+        idb.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0]);
+        idb.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend(block(1, &idb));
+        // IDB: raw IPv4, no options
+        data.extend(block(1, &[228, 0, 0, 0, 0xff, 0xff, 0, 0]));
+
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run(&mut Cursor::new(data)).expect("run engine");
+        assert_eq!(
+            engine.data_analyzer().interfaces,
+            vec![(0, Some("eth0".to_owned())), (1, None)]
+        );
+        let ctx = &engine.ctx;
+        assert_eq!(
+            ctx.interface(0).map(|i| i.link_type),
+            Some(Linktype::ETHERNET)
+        );
+        assert_eq!(ctx.interface(0).map(|i| i.if_tsresol), Some(9));
+        assert_eq!(ctx.interface(1).map(|i| i.link_type), Some(Linktype::IPV4));
+        assert!(ctx.interface(2).is_none());
+    }
+
+    #[test]
+    fn section_callbacks() {
+        let mut data = shb();
+        data.extend(shb());
+        data.extend(shb());
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run(&mut Cursor::new(data)).expect("run engine");
+        assert_eq!(
+            engine.data_analyzer().sections,
+            vec![
+                (0, true),
+                (0, false),
+                (1, true),
+                (1, false),
+                (2, true),
+                (2, false)
+            ]
+        );
+    }
+
+    /// Build a legacy pcap file (Ethernet link type), with one empty packet per timestamp
+    fn legacy_pcap(timestamps: &[u32]) -> Vec<u8> {
+        let mut v = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        v.extend_from_slice(&[0; 8]);
+        v.extend_from_slice(&65535u32.to_le_bytes());
+        v.extend_from_slice(&1u32.to_le_bytes());
+        for ts in timestamps {
+            v.extend_from_slice(&ts.to_le_bytes());
+            v.extend_from_slice(&[0; 12]);
+        }
+        v
+    }
+
+    // Modified pcap format: record headers have 8 more bytes (interface index, protocol, packet
+    // type)
+    #[test]
+    fn modified_pcap() {
+        let mut input = vec![0x34, 0xcd, 0xb2, 0xa1, 2, 0, 4, 0];
+        input.extend_from_slice(&[0; 8]);
+        input.extend_from_slice(&65535u32.to_le_bytes());
+        input.extend_from_slice(&1u32.to_le_bytes());
+        for ts in &[1u32, 2, 3] {
+            input.extend_from_slice(&ts.to_le_bytes());
+            input.extend_from_slice(&[0; 4]);
+            input.extend_from_slice(&14u32.to_le_bytes());
+            input.extend_from_slice(&14u32.to_le_bytes());
+            input.extend_from_slice(&[0; 8]);
+            input.extend_from_slice(&[0; 14]);
+        }
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run(&mut Cursor::new(input)).expect("run engine");
+        assert_eq!(engine.data_analyzer().packets, vec![1, 2, 3]);
+    }
+
+    fn run_inputs(mode: InputMode) -> Vec<u32> {
+        let mut readers: Vec<Box<dyn Read>> = vec![
+            Box::new(Cursor::new(legacy_pcap(&[1, 3, 3]))),
+            Box::new(Cursor::new(legacy_pcap(&[2, 3, 4]))),
+        ];
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run_inputs(&mut readers, mode).expect("run engine");
+        assert_eq!(engine.data_analyzer().sections.len(), 4);
+        engine.data_analyzer().packets.clone()
+    }
+
+    #[test]
+    fn multiple_inputs() {
+        assert_eq!(run_inputs(InputMode::Sequential), vec![1, 3, 3, 2, 3, 4]);
+        assert_eq!(run_inputs(InputMode::Merge), vec![1, 2, 3, 3, 3, 4]);
+    }
+
+    #[test]
+    fn stop_handle() {
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        // stop after the first block: the section is still ended
+        let stop = Arc::new(AtomicBool::new(true));
+        let mut input = Cursor::new(legacy_pcap(&[1, 2, 3]));
+        engine
+            .run_with_cancel(&mut input, stop)
+            .expect("run engine");
+        assert!(engine.data_analyzer().packets.is_empty());
+        assert_eq!(engine.data_analyzer().sections, vec![(0, true), (0, false)]);
+    }
+
+    #[derive(Default)]
+    struct KeepData {
+        kept: Vec<PacketBytes>,
+    }
+
+    impl PcapAnalyzer for KeepData {
+        fn handle_packet(&mut self, packet: &Packet, _ctx: &ParseContext) -> Result<(), Error> {
+            if let PacketData::L2(data) = packet.data {
+                self.kept.push(packet.bytes(data).expect("data in chunk"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packet_bytes_across_refills() {
+        let mut input = legacy_pcap(&[]);
+        for i in 0..10u8 {
+            input.extend_from_slice(&[0; 8]);
+            input.extend_from_slice(&40u32.to_le_bytes());
+            input.extend_from_slice(&40u32.to_le_bytes());
+            input.extend_from_slice(&[i; 40]);
+        }
+        // small chunks, so the input is read in several chunks
+        let mut config = Config::default();
+        config.set("buffer_initial_capacity", 64);
+        let mut engine = PcapDataEngine::new(KeepData::default(), &config);
+        engine.run(&mut Cursor::new(input)).expect("run engine");
+        let kept = &engine.data_analyzer().kept;
+        assert_eq!(kept.len(), 10);
+        for (i, data) in kept.iter().enumerate() {
+            assert_eq!(&data[..], &[i as u8; 40][..]);
+        }
+        assert!(!kept[0].chunk().ptr_eq(kept[9].chunk()));
+    }
+
+    #[test]
+    fn mmap_input() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("libpcap_tools_mmap_{}.pcap", std::process::id()));
+        // truncated last packet
+        let mut data = legacy_pcap(&[1, 2, 3]);
+        data.truncate(data.len() - 4);
+        std::fs::write(&path, &data).expect("write input file");
+        let file = File::open(&path).expect("open input file");
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine.run_file(&file).expect("run engine");
+        std::fs::remove_file(&path).expect("remove input file");
+        assert_eq!(engine.data_analyzer().packets, vec![1, 2]);
+        assert_eq!(engine.data_analyzer().sections, vec![(0, true), (0, false)]);
+    }
+
+    #[test]
+    fn checkpoint_resume() {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "libpcap_tools_checkpoint_{}.pcap",
+            std::process::id()
+        ));
+        let checkpoint_path = path.with_extension("json");
+        let timestamps = (0..2000).collect::<Vec<u32>>();
+        std::fs::write(&path, legacy_pcap(&timestamps)).expect("write input file");
+        let file = File::open(&path).expect("open input file");
+        let config = Config::default();
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        let checkpoint_config =
+            CheckpointConfig::new(&checkpoint_path, std::time::Duration::default());
+        engine.set_checkpoint_config(checkpoint_config);
+        engine.run_file(&file).expect("run engine");
+        let checkpoint = Checkpoint::load(&checkpoint_path).expect("load checkpoint");
+        std::fs::remove_file(&checkpoint_path).expect("remove checkpoint file");
+        // header, and 1023 packets of 16 bytes
+        assert_eq!(checkpoint.block_index, 1024);
+        assert_eq!(checkpoint.offset, 24 + 1023 * 16);
+        assert_eq!(checkpoint.headers, vec![(0, 24)]);
+
+        let mut engine = PcapDataEngine::new(BlockCounter::default(), &config);
+        engine
+            .resume_file(&file, checkpoint)
+            .expect("resume engine");
+        std::fs::remove_file(&path).expect("remove input file");
+        assert_eq!(engine.data_analyzer().packets, timestamps);
+        assert_eq!(engine.data_analyzer().sections, vec![(0, true), (0, false)]);
+    }
+}
@@ -0,0 +1,337 @@
+//! IPv4 and IPv6 defragmentation
+//!
+//! [`Defragmenter`] reassembles fragmented datagrams (IPv4 fragments, or IPv6 fragment
+//! extension headers), so the L4 payload can be dispatched in one piece.
+//!
+//! Datagrams are identified by their source and destination addresses, L4 protocol and
+//! identification. Incomplete datagrams are dropped after a timeout, or when the memory limits
+//! are reached (oldest datagrams first).
+
+use crate::config::Config;
+use crate::duration::Duration;
+use crate::three_tuple::ThreeTuple;
+use std::collections::{HashMap, VecDeque};
+
+/// Maximum size of a reassembled datagram payload
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Result of the defragmentation of a fragment
+#[derive(Debug, PartialEq, Eq)]
+pub enum Fragment<'a> {
+    /// Data is not fragmented - return original slice
+    NoFrag(&'a [u8]),
+    /// Data was defragmented - return buffer
+    Complete(Vec<u8>),
+    /// Fragment is part of a (yet) unfinished buffer
+    Incomplete,
+    /// Defragmentation error (the datagram is dropped)
+    Error,
+}
+
+/// Limits of the defragmentation engine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefragConfig {
+    /// Incomplete datagrams are dropped after this duration (default: 30 seconds)
+    pub timeout: Duration,
+    /// Maximum number of incomplete datagrams (default: 4096)
+    pub max_datagrams: usize,
+    /// Maximum number of bytes stored for incomplete datagrams (default: 4 MB)
+    pub max_memory: usize,
+}
+
+impl Default for DefragConfig {
+    fn default() -> Self {
+        DefragConfig {
+            timeout: Duration::new(30, 0),
+            max_datagrams: 4096,
+            max_memory: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl DefragConfig {
+    /// Read limits from the `defrag` section of `config` (keys `timeout`, in seconds,
+    /// `max_datagrams` and `max_memory`, in bytes)
+    pub fn from_config(config: &Config) -> Self {
+        let default = DefragConfig::default();
+        DefragConfig {
+            timeout: config
+                .get_usize("defrag.timeout")
+                .map_or(default.timeout, |secs| Duration::new(secs as u32, 0)),
+            max_datagrams: config
+                .get_usize("defrag.max_datagrams")
+                .unwrap_or(default.max_datagrams),
+            max_memory: config
+                .get_usize("defrag.max_memory")
+                .unwrap_or(default.max_memory),
+        }
+    }
+}
+
+/// Identifier of a fragmented datagram
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DefragKey {
+    /// Source and destination addresses, and L4 protocol
+    pub three_tuple: ThreeTuple,
+    /// IP identification (16 bits for IPv4, 32 bits for IPv6)
+    pub id: u32,
+}
+
+struct Datagram {
+    /// Timestamp of the first fragment
+    first_seen: Duration,
+    /// Fragments (offset, data), in reception order
+    fragments: Vec<(usize, Vec<u8>)>,
+    /// Length of the payload, known when the last fragment is received
+    total_len: Option<usize>,
+    /// Number of bytes stored
+    size: usize,
+}
+
+impl Datagram {
+    /// Return the payload if all fragments were received
+    ///
+    /// If fragments overlap, the data of the fragment with the lowest offset is used.
+    fn reassemble(&mut self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut end = 0;
+        for (offset, data) in &self.fragments {
+            if *offset > end {
+                return None;
+            }
+            end = end.max(offset + data.len());
+        }
+        if end < total_len {
+            return None;
+        }
+        let mut buffer = Vec::with_capacity(total_len);
+        for (offset, data) in &self.fragments {
+            let start = buffer.len().saturating_sub(*offset);
+            if start < data.len() {
+                buffer.extend_from_slice(&data[start..]);
+            }
+        }
+        buffer.truncate(total_len);
+        Some(buffer)
+    }
+}
+
+/// IPv4 and IPv6 defragmentation engine
+#[derive(Default)]
+pub struct Defragmenter {
+    config: DefragConfig,
+    datagrams: HashMap<DefragKey, Datagram>,
+    /// Keys of incomplete datagrams, oldest first
+    order: VecDeque<DefragKey>,
+    /// Number of bytes stored
+    memory: usize,
+}
+
+impl Defragmenter {
+    pub fn new(config: DefragConfig) -> Self {
+        Defragmenter {
+            config,
+            ..Defragmenter::default()
+        }
+    }
+
+    /// Return the number of incomplete datagrams
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    /// Returns true if there is no incomplete datagram
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Return the number of bytes stored for incomplete datagrams
+    pub fn memory_usage(&self) -> usize {
+        self.memory
+    }
+
+    /// Update the engine with a fragment, received at `now`
+    ///
+    /// `offset` is the offset of the fragment in the datagram payload, in bytes.
+    pub fn update<'a>(
+        &mut self,
+        key: DefragKey,
+        offset: usize,
+        more_fragments: bool,
+        frag: &'a [u8],
+        now: Duration,
+    ) -> Fragment<'a> {
+        // check if data is not fragmented
+        if !more_fragments && offset == 0 {
+            return Fragment::NoFrag(frag);
+        }
+        self.expire(now);
+        let end = offset + frag.len();
+        if end > MAX_DATAGRAM_SIZE || (more_fragments && frag.is_empty()) {
+            warn!(
+                "defrag: invalid fragment offset={} len={}",
+                offset,
+                frag.len()
+            );
+            self.remove(&key);
+            return Fragment::Error;
+        }
+        if !self.datagrams.contains_key(&key) {
+            self.order.push_back(key.clone());
+        }
+        let datagram = self
+            .datagrams
+            .entry(key.clone())
+            .or_insert_with(|| Datagram {
+                first_seen: now,
+                fragments: Vec::new(),
+                total_len: None,
+                size: 0,
+            });
+        if !more_fragments {
+            if datagram.total_len.map_or(false, |len| len != end) {
+                warn!("defrag: inconsistent datagram length for id {}", key.id);
+                self.remove(&key);
+                return Fragment::Error;
+            }
+            datagram.total_len = Some(end);
+        }
+        datagram.fragments.push((offset, frag.to_vec()));
+        datagram.size += frag.len();
+        self.memory += frag.len();
+        if let Some(buffer) = datagram.reassemble() {
+            trace!("defrag: done for id {}", key.id);
+            self.remove(&key);
+            return Fragment::Complete(buffer);
+        }
+        self.enforce_limits();
+        if self.datagrams.contains_key(&key) {
+            Fragment::Incomplete
+        } else {
+            warn!(
+                "defrag: memory limit reached, dropping datagram id {}",
+                key.id
+            );
+            Fragment::Error
+        }
+    }
+
+    /// Drop incomplete datagrams older than the timeout
+    pub fn expire(&mut self, now: Duration) {
+        while let Some(key) = self.order.front() {
+            match self.datagrams.get(key) {
+                Some(d) if d.first_seen + self.config.timeout < now => {
+                    debug!("defrag: timeout for id {}", key.id);
+                    let key = key.clone();
+                    self.remove(&key);
+                }
+                Some(_) => break,
+                None => {
+                    self.order.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Drop the oldest datagrams while limits are exceeded
+    fn enforce_limits(&mut self) {
+        while self.datagrams.len() > self.config.max_datagrams
+            || self.memory > self.config.max_memory
+        {
+            match self.order.pop_front() {
+                Some(key) => self.remove(&key),
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &DefragKey) {
+        if let Some(datagram) = self.datagrams.remove(key) {
+            self.memory -= datagram.size;
+        }
+        // keys are removed lazily from `order`, except the oldest one
+        if self.order.front() == Some(key) {
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: u32) -> DefragKey {
+        DefragKey {
+            three_tuple: ThreeTuple::default(),
+            id,
+        }
+    }
+
+    #[test]
+    fn defrag_out_of_order() {
+        let mut defrag = Defragmenter::default();
+        let now = Duration::new(1, 0);
+        assert_eq!(
+            defrag.update(key(1), 16, false, &[3; 8], now),
+            Fragment::Incomplete
+        );
+        assert_eq!(
+            defrag.update(key(1), 0, true, &[1; 8], now),
+            Fragment::Incomplete
+        );
+        // another datagram with the same ID, from other addresses
+        let other = DefragKey {
+            three_tuple: ThreeTuple {
+                l4_proto: 17,
+                ..ThreeTuple::default()
+            },
+            id: 1,
+        };
+        assert_eq!(
+            defrag.update(other, 0, true, &[9; 8], now),
+            Fragment::Incomplete
+        );
+        let mut expected = vec![1; 8];
+        expected.extend_from_slice(&[2; 8]);
+        expected.extend_from_slice(&[3; 8]);
+        assert_eq!(
+            defrag.update(key(1), 8, true, &[2; 8], now),
+            Fragment::Complete(expected)
+        );
+        assert_eq!(defrag.len(), 1);
+        assert_eq!(defrag.memory_usage(), 8);
+    }
+
+    #[test]
+    fn defrag_limits() {
+        let config = DefragConfig {
+            max_memory: 16,
+            ..DefragConfig::default()
+        };
+        let mut defrag = Defragmenter::new(config);
+        let now = Duration::new(1, 0);
+        assert_eq!(
+            defrag.update(key(1), 0, true, &[1; 8], now),
+            Fragment::Incomplete
+        );
+        assert_eq!(
+            defrag.update(key(2), 0, true, &[2; 8], now),
+            Fragment::Incomplete
+        );
+        // memory limit reached: datagram 1 is dropped
+        assert_eq!(
+            defrag.update(key(3), 0, true, &[3; 8], now),
+            Fragment::Incomplete
+        );
+        assert_eq!(defrag.len(), 2);
+        assert_eq!(
+            defrag.update(key(1), 8, false, &[1; 8], now),
+            Fragment::Incomplete
+        );
+        // timeout
+        defrag.expire(Duration::new(40, 0));
+        assert!(defrag.is_empty());
+        assert_eq!(defrag.memory_usage(), 0);
+    }
+}
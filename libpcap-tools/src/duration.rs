@@ -1,26 +1,42 @@
+use serde::{Deserialize, Serialize};
 use std::ops::{Add,Sub};
 
 /// Reimplementation of std::time::Duration, but panic-free
 /// and partial, only to match our needs:
-///   - use micros instead of nanos, avoid casts
+///   - use u32 fields, avoid casts
 ///   - expose fields
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug, Serialize, Deserialize)]
 pub struct Duration {
     pub secs: u32,
-    pub micros: u32,
+    pub nanos: u32,
 }
 
 pub const MICROS_PER_SEC: u32 = 1_000_000;
+pub const NANOS_PER_SEC: u32 = 1_000_000_000;
+pub const NANOS_PER_MICRO: u32 = 1_000;
 
 impl Duration {
     /// Build Duration from secs and micros
     pub fn new(secs:u32, micros:u32) -> Duration {
-        Duration{ secs, micros }
+        let secs = secs.wrapping_add(micros / MICROS_PER_SEC);
+        let nanos = (micros % MICROS_PER_SEC) * NANOS_PER_MICRO;
+        Duration{ secs, nanos }
+    }
+    /// Build Duration from secs and nanos
+    pub fn from_nanos(secs:u32, nanos:u32) -> Duration {
+        let secs = secs.wrapping_add(nanos / NANOS_PER_SEC);
+        let nanos = nanos % NANOS_PER_SEC;
+        Duration{ secs, nanos }
+    }
+    /// Return the fractional part of the duration, in microseconds (truncated)
+    #[inline]
+    pub fn micros(self) -> u32 {
+        self.nanos / NANOS_PER_MICRO
     }
     /// Test if Duration object is null
     #[inline]
     pub fn is_null(self) -> bool {
-        self.secs == 0 && self.micros == 0
+        self.secs == 0 && self.nanos == 0
     }
 }
 
@@ -30,14 +46,15 @@ impl Add for Duration {
     #[allow(clippy::suspicious_arithmetic_impl)]
     fn add(self, other: Duration) -> Self::Output {
         let secs = self.secs.wrapping_add(other.secs);
-        let micros = self.micros.wrapping_add(other.micros);
-        let (secs,micros) = if micros > MICROS_PER_SEC {
-            (secs + (micros / MICROS_PER_SEC), micros % MICROS_PER_SEC)
+        // both values are lower than NANOS_PER_SEC, so this cannot overflow
+        let nanos = self.nanos.wrapping_add(other.nanos);
+        let (secs,nanos) = if nanos >= NANOS_PER_SEC {
+            (secs.wrapping_add(1), nanos - NANOS_PER_SEC)
         } else {
-            (secs,micros)
+            (secs,nanos)
         };
 
-        Duration{ secs, micros }
+        Duration{ secs, nanos }
     }
 }
 
@@ -47,16 +64,14 @@ impl Sub for Duration {
     #[allow(clippy::suspicious_arithmetic_impl)]
     fn sub(self, other: Duration) -> Self::Output {
         let secs = self.secs.wrapping_sub(other.secs);
-        let (secs,micros) = if self.micros >= other.micros {
-            (secs,self.micros - other.micros)
+        let (secs,nanos) = if self.nanos >= other.nanos {
+            (secs,self.nanos - other.nanos)
         } else {
-            let diff = other.micros.wrapping_sub(self.micros);
-            let secs_less = diff / MICROS_PER_SEC;
-            let micros = MICROS_PER_SEC - diff;
-            (secs.wrapping_sub(1 + secs_less),micros)
+            let diff = other.nanos.wrapping_sub(self.nanos) % NANOS_PER_SEC;
+            (secs.wrapping_sub(1),NANOS_PER_SEC - diff)
         };
 
-        Duration{ secs, micros }
+        Duration{ secs, nanos }
     }
 }
 
@@ -69,6 +84,18 @@ mod tests {
         let d2 = Duration::new(1234, 6789);
         let d = d2 - d1;
         assert_eq!(d.secs,0);
-        assert_eq!(d.micros,1111);
+        assert_eq!(d.micros(),1111);
+    }
+
+    #[test]
+    fn duration_nanos() {
+        let d1 = Duration::from_nanos(1, 999_999_999);
+        let d2 = Duration::from_nanos(0, 2);
+        let d = d1 + d2;
+        assert_eq!(d, Duration::from_nanos(2, 1));
+        assert_eq!(d - d1, d2);
+        assert_eq!(d1 - Duration::from_nanos(0, 999_999_999), Duration::new(1, 0));
+        assert_eq!(Duration::new(1, 2).nanos, 2000);
+        assert_eq!(Duration::from_nanos(0, 1_500_000_000), Duration::new(1, 500_000));
     }
 }
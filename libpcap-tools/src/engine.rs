@@ -1,8 +1,84 @@
+use crate::checkpoint::{Checkpoint, CheckpointConfig};
 use crate::error::Error;
+use crate::progress::ProgressReporter;
+use std::fs::File;
 use std::io::Read;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// How multiple inputs are read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputMode {
+    /// Inputs are read one after the other (for ex. rotated capture files)
+    Sequential,
+    /// Packets of all inputs are merged in chronological order
+    Merge,
+}
 
 /// Interface for all Pcap engines
 pub trait PcapEngine {
     /// Main function: given a reader, read all pcap data and call analyzer for each Packet
     fn run(&mut self, f: &mut dyn Read) -> Result<(), Error>;
+
+    /// Read all pcap data of a file
+    ///
+    /// Engines can map the file in memory, to avoid copying data. The default implementation
+    /// reads the file as a stream.
+    fn run_file(&mut self, file: &File) -> Result<(), Error> {
+        let mut file = file;
+        self.run(&mut file)
+    }
+
+    /// Report the progress of the next runs to `reporter`
+    ///
+    /// The default implementation ignores the reporter.
+    fn set_progress_reporter(&mut self, _reporter: ProgressReporter) {}
+
+    /// Stop reading input when `stop` is set to `true` (for ex. from a signal handler)
+    ///
+    /// `teardown` is still called, so analyzers can report partial results.
+    ///
+    /// The default implementation ignores the flag.
+    fn set_stop_handle(&mut self, _stop: Arc<AtomicBool>) {}
+
+    /// Read all pcap data, until the end of the input or until `stop` is set to `true`
+    ///
+    /// See [`PcapEngine::set_stop_handle`].
+    fn run_with_cancel(
+        &mut self,
+        reader: &mut dyn Read,
+        stop: Arc<AtomicBool>,
+    ) -> Result<(), Error> {
+        self.set_stop_handle(stop);
+        self.run(reader)
+    }
+
+    /// Write checkpoints of the analysis state periodically, when reading a file with
+    /// `run_file` or `resume_file`
+    ///
+    /// The default implementation does not support checkpoints, and ignores the configuration.
+    fn set_checkpoint_config(&mut self, _config: CheckpointConfig) {
+        warn!("Checkpoints are not supported by this engine");
+    }
+
+    /// Resume reading a file from a checkpoint
+    ///
+    /// The file must be the one used to create the checkpoint.
+    fn resume_file(&mut self, _file: &File, _checkpoint: Checkpoint) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "Resuming from a checkpoint is not supported by this engine",
+        ))
+    }
+
+    /// Read multiple inputs as a single capture, keeping the analyzer state across inputs
+    ///
+    /// The default implementation only supports a single input.
+    fn run_inputs(&mut self, readers: &mut [Box<dyn Read>], _mode: InputMode) -> Result<(), Error> {
+        match readers {
+            [reader] => self.run(reader.as_mut()),
+            _ => Err(Error::Unsupported(
+                "Multiple inputs are not supported by this engine",
+            )),
+        }
+    }
 }
@@ -1,9 +1,15 @@
+use crate::config::Config;
 use pcap_parser::nom::{error::ErrorKind, Err};
-use pcap_parser::PcapError;
+use pcap_parser::{Linktype, PcapBlockOwned, PcapError};
 use std::convert::From;
+use std::fmt;
 use std::io;
 use thiserror::Error;
 
+/// Errors returned by engines and analyzers
+///
+/// Errors raised while handling a block of the input are wrapped in [`Error::Block`], which
+/// gives the position of the block. Use [`Error::root_cause`] to get the original error.
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Internal parser error {0:?}")]
@@ -14,6 +20,83 @@ pub enum Error {
     Pcap(#[from] PcapError<&'static [u8]>),
     #[error("Generic error {0}")]
     Generic(&'static str),
+    #[cfg(feature = "live")]
+    #[error("Live capture error: {0}")]
+    Live(#[from] pcap::Error),
+    /// Data which could not be parsed as a block
+    #[error("Invalid block (block_index={block_index}, offset={offset}): {source:?}")]
+    Parse {
+        /// Index of the block in the input
+        block_index: usize,
+        /// Offset of the block in the input
+        offset: u64,
+        source: PcapError<&'static [u8]>,
+    },
+    /// Error while handling a block
+    #[error("{source} (block_index={block_index}, offset={offset}, block_type={block_type}, pcap_index={pcap_index})")]
+    Block {
+        /// Index of the block in the input
+        block_index: usize,
+        /// Offset of the block in the input
+        offset: u64,
+        block_type: BlockType,
+        /// Index of the last packet read (the packet of the block, for packet blocks)
+        pcap_index: usize,
+        source: Box<Error>,
+    },
+    /// A packet references an interface which is not defined in the section
+    #[error("Unknown interface {0}")]
+    UnknownInterface(u32),
+    /// Packet data could not be decoded for its link type
+    #[error("Could not decode packet data (link type {0})")]
+    PacketData(Linktype),
+    /// Checkpoint which cannot be used with the input or the analyzer
+    #[error("Invalid checkpoint: {0}")]
+    Checkpoint(&'static str),
+    /// Operation not supported by the engine or the analyzer
+    #[error("{0}")]
+    Unsupported(&'static str),
+}
+
+impl Error {
+    /// Return the original error, without the block context
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Error::Block { source, .. } => source.root_cause(),
+            e => e,
+        }
+    }
+}
+
+/// Type of a block of the input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockType {
+    /// Legacy pcap file header
+    LegacyHeader,
+    /// Legacy pcap packet record
+    Legacy,
+    /// Pcap-NG block (block type)
+    NG(u32),
+}
+
+impl BlockType {
+    pub fn of(block: &PcapBlockOwned) -> Self {
+        match block {
+            PcapBlockOwned::LegacyHeader(_) => BlockType::LegacyHeader,
+            PcapBlockOwned::Legacy(_) => BlockType::Legacy,
+            PcapBlockOwned::NG(b) => BlockType::NG(b.magic()),
+        }
+    }
+}
+
+impl fmt::Display for BlockType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockType::LegacyHeader => f.write_str("pcap header"),
+            BlockType::Legacy => f.write_str("pcap record"),
+            BlockType::NG(block_type) => write!(f, "0x{:08x}", block_type),
+        }
+    }
 }
 
 impl From<&'static str> for Error {
@@ -36,3 +119,58 @@ impl<'a> From<Err<PcapError<&'a [u8]>>> for Error {
         }
     }
 }
+
+/// Action taken by engines when an error occurs while reading or analyzing the input
+///
+/// The policy is read from the `error_policy` configuration key: `"abort"` (default),
+/// `"skip-packet"` or `"skip-block"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop the run, and return the error
+    Abort,
+    /// Log and count errors returned by the analyzer for a block (for ex. a packet referencing
+    /// an unknown interface), and continue with the next block
+    SkipPacket,
+    /// Same as `SkipPacket`, and also skip pcap-ng blocks which cannot be parsed, using the
+    /// length of the block header
+    SkipBlock,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        ErrorPolicy::Abort
+    }
+}
+
+impl ErrorPolicy {
+    /// Read the error policy from the `error_policy` key of `config`
+    ///
+    /// Unknown values are ignored (with a warning), and the default policy is used.
+    pub fn from_config(config: &Config) -> Self {
+        match config.get("error_policy") {
+            None | Some("abort") => ErrorPolicy::Abort,
+            Some("skip-packet") => ErrorPolicy::SkipPacket,
+            Some("skip-block") => ErrorPolicy::SkipBlock,
+            Some(s) => {
+                warn!("Invalid value '{}' for error_policy, using 'abort'", s);
+                ErrorPolicy::Abort
+            }
+        }
+    }
+}
+
+/// Number of errors skipped during a run, following the [`ErrorPolicy`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCounters {
+    /// Blocks for which the analyzer returned an error
+    pub skipped_packets: u64,
+    /// Blocks which could not be parsed
+    pub skipped_blocks: u64,
+}
+
+impl ErrorCounters {
+    /// Return the total number of errors
+    pub fn total(&self) -> u64 {
+        self.skipped_packets + self.skipped_blocks
+    }
+}
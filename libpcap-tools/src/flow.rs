@@ -1,4 +1,5 @@
 use crate::Duration;
+use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
 
 use crate::five_tuple::FiveTuple;
@@ -7,8 +8,36 @@ use crate::five_tuple::FiveTuple;
 #[allow(clippy::upper_case_acronyms)]
 pub type FlowID = u64;
 
+/// How the direction of a flow (client to server) was decided
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowDirection {
+    /// Direction of the first packet
+    FirstPacket,
+    /// TCP handshake: SYN sent by the client, or SYN+ACK sent by the server
+    TcpHandshake,
+    /// Well-known port: the server uses a port below 1024, and the client a port above
+    WellKnownPort,
+}
+
+impl FlowDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlowDirection::FirstPacket => "first_packet",
+            FlowDirection::TcpHandshake => "tcp_handshake",
+            FlowDirection::WellKnownPort => "well_known_port",
+        }
+    }
+}
+
+impl Default for FlowDirection {
+    fn default() -> Self {
+        FlowDirection::FirstPacket
+    }
+}
+
 /// Network flow information
-#[derive(Clone, PartialEq, Eq, Default, Debug)]
+#[derive(Clone, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
 pub struct Flow {
     /// The `Flow` identifier
     pub flow_id: FlowID,
@@ -18,18 +47,78 @@ pub struct Flow {
     pub first_seen: Duration,
     /// timestamp of last seen packet
     pub last_seen: Duration,
+    /// Number of packets, in both directions
+    pub packets: u64,
+    /// Number of bytes (original length of packets), in both directions
+    pub bytes: u64,
+    /// Number of packets in the direction of the first packet (to server)
+    #[serde(default)]
+    pub packets_to_server: u64,
+    /// Number of bytes in the direction of the first packet (to server)
+    #[serde(default)]
+    pub bytes_to_server: u64,
+    /// Union of the TCP flags seen, in both directions (0 if not TCP)
+    pub tcp_flags: u8,
+    /// How the direction of the flow was decided
+    #[serde(default)]
+    pub direction: FlowDirection,
 }
 
 impl Flow {
     pub fn new(five_tuple: &FiveTuple, ts_sec: u32, ts_usec: u32) -> Self {
-        let d = Duration::new(ts_sec, ts_usec);
+        Flow::with_timestamp(five_tuple, Duration::new(ts_sec, ts_usec))
+    }
+
+    /// Create a flow first seen at `ts` (with nanosecond precision)
+    pub fn with_timestamp(five_tuple: &FiveTuple, ts: Duration) -> Self {
         Flow {
             flow_id: 0,
             five_tuple: five_tuple.clone(),
-            first_seen: d,
-            last_seen: d,
+            first_seen: ts,
+            last_seen: ts,
+            packets: 0,
+            bytes: 0,
+            packets_to_server: 0,
+            bytes_to_server: 0,
+            tcp_flags: 0,
+            direction: FlowDirection::FirstPacket,
         }
     }
+
+    /// Update counters with a packet of the flow, seen at `ts`
+    ///
+    /// `to_server` is true if the packet is in the direction of the first packet of the flow.
+    /// `tcp_flags` should be 0 if the flow is not TCP.
+    pub fn update(&mut self, ts: Duration, bytes: u32, tcp_flags: u8, to_server: bool) {
+        self.last_seen = ts;
+        self.packets += 1;
+        self.bytes += u64::from(bytes);
+        if to_server {
+            self.packets_to_server += 1;
+            self.bytes_to_server += u64::from(bytes);
+        }
+        self.tcp_flags |= tcp_flags;
+    }
+
+    /// Number of packets in the reverse direction (to client)
+    pub fn packets_to_client(&self) -> u64 {
+        self.packets - self.packets_to_server
+    }
+
+    /// Number of bytes in the reverse direction (to client)
+    pub fn bytes_to_client(&self) -> u64 {
+        self.bytes - self.bytes_to_server
+    }
+
+    /// Reverse the direction of the flow (for ex. if the first packet was sent by the server)
+    ///
+    /// The five-tuple is reversed, and the counters of both directions are swapped.
+    pub fn reverse(&mut self, direction: FlowDirection) {
+        self.five_tuple = self.five_tuple.get_reverse();
+        self.packets_to_server = self.packets_to_client();
+        self.bytes_to_server = self.bytes_to_client();
+        self.direction = direction;
+    }
 }
 
 #[allow(clippy::derive_hash_xor_eq)]
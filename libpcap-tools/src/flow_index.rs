@@ -0,0 +1,182 @@
+use crate::flow::FlowID;
+use crate::flow_key::{FlowHasher, FlowKey};
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// Hash value of empty slots (the hashes of entries have the highest bit set)
+const EMPTY: u64 = 0;
+const OCCUPIED: u64 = 1 << 63;
+
+const MIN_CAPACITY: usize = 64;
+
+/// Open-addressing hash table from flow keys to flow IDs, used on the per-packet path
+///
+/// Collisions are resolved by linear probing. The hashes of the entries are stored in a separate
+/// array, so a lookup usually reads a single cache line of hashes before comparing a key. Entries
+/// are removed by shifting the following entries back, so there are no tombstones and lookups
+/// stay short after flows are removed.
+pub(crate) struct FlowIndex {
+    hasher: FlowHasher,
+    /// Hash of each slot (`EMPTY` if the slot is free)
+    hashes: Vec<u64>,
+    entries: Vec<(FlowKey, FlowID)>,
+    len: usize,
+}
+
+impl Default for FlowIndex {
+    fn default() -> Self {
+        FlowIndex {
+            hasher: FlowHasher::new(),
+            hashes: vec![EMPTY; MIN_CAPACITY],
+            entries: vec![(FlowKey::default(), 0); MIN_CAPACITY],
+            len: 0,
+        }
+    }
+}
+
+impl FlowIndex {
+    #[inline]
+    fn hash(&self, key: &FlowKey) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        hasher.finish() | OCCUPIED
+    }
+
+    #[inline]
+    fn mask(&self) -> usize {
+        self.hashes.len() - 1
+    }
+
+    /// Return the slot of `key`, or the free slot where it should be inserted
+    #[inline]
+    fn find_slot(&self, key: &FlowKey, hash: u64) -> Result<usize, usize> {
+        let mask = self.mask();
+        let mut i = hash as usize & mask;
+        loop {
+            match self.hashes[i] {
+                EMPTY => return Err(i),
+                h if h == hash && self.entries[i].0 == *key => return Ok(i),
+                _ => i = (i + 1) & mask,
+            }
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: &FlowKey) -> Option<FlowID> {
+        let hash = self.hash(key);
+        self.find_slot(key, hash).ok().map(|i| self.entries[i].1)
+    }
+
+    /// Insert or replace the flow ID of `key`
+    pub fn insert(&mut self, key: FlowKey, flow_id: FlowID) {
+        // keep the load factor below 3/4
+        if (self.len + 1) * 4 > self.hashes.len() * 3 {
+            self.grow();
+        }
+        let hash = self.hash(&key);
+        match self.find_slot(&key, hash) {
+            Ok(i) => self.entries[i].1 = flow_id,
+            Err(i) => {
+                self.hashes[i] = hash;
+                self.entries[i] = (key, flow_id);
+                self.len += 1;
+            }
+        }
+    }
+
+    fn grow(&mut self) {
+        let capacity = self.hashes.len() * 2;
+        let hashes = std::mem::replace(&mut self.hashes, vec![EMPTY; capacity]);
+        let entries = std::mem::replace(&mut self.entries, vec![(FlowKey::default(), 0); capacity]);
+        for (hash, entry) in hashes.into_iter().zip(entries) {
+            if hash != EMPTY {
+                let i = self.find_slot(&entry.0, hash).unwrap_err();
+                self.hashes[i] = hash;
+                self.entries[i] = entry;
+            }
+        }
+    }
+
+    /// Remove `key`, and return its flow ID
+    pub fn remove(&mut self, key: &FlowKey) -> Option<FlowID> {
+        let hash = self.hash(key);
+        let mut hole = self.find_slot(key, hash).ok()?;
+        let flow_id = self.entries[hole].1;
+        // shift back the following entries which can be moved to the hole
+        let mask = self.mask();
+        let mut i = hole;
+        loop {
+            i = (i + 1) & mask;
+            let hash = self.hashes[i];
+            if hash == EMPTY {
+                break;
+            }
+            let home = hash as usize & mask;
+            // the entry must stay if its home slot is (cyclically) in (hole, i]
+            let stays = if hole <= i {
+                hole < home && home <= i
+            } else {
+                hole < home || home <= i
+            };
+            if !stays {
+                self.hashes[hole] = hash;
+                self.entries[hole] = self.entries[i];
+                hole = i;
+            }
+        }
+        self.hashes[hole] = EMPTY;
+        self.len -= 1;
+        Some(flow_id)
+    }
+
+    pub fn clear(&mut self) {
+        *self = FlowIndex {
+            hasher: self.hasher.clone(),
+            ..FlowIndex::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::five_tuple::FiveTuple;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn key(n: u32) -> FlowKey {
+        FlowKey::new(&FiveTuple {
+            proto: 17,
+            src: IpAddr::V4(Ipv4Addr::from(n)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            src_port: (n % 65536) as u16,
+            dst_port: 53,
+        })
+    }
+
+    #[test]
+    fn flow_index_insert_remove() {
+        let mut index = FlowIndex::default();
+        // enough entries to grow the table several times
+        for n in 0..1000 {
+            index.insert(key(n), u64::from(n));
+        }
+        assert_eq!(index.len, 1000);
+        for n in 0..1000 {
+            assert_eq!(index.get(&key(n)), Some(u64::from(n)));
+        }
+        // remove every other entry: the others must still be found
+        for n in (0..1000).step_by(2) {
+            assert_eq!(index.remove(&key(n)), Some(u64::from(n)));
+        }
+        assert_eq!(index.len, 500);
+        for n in 0..1000 {
+            let expected = if n % 2 == 0 { None } else { Some(u64::from(n)) };
+            assert_eq!(index.get(&key(n)), expected);
+        }
+        assert_eq!(index.remove(&key(0)), None);
+        index.insert(key(1), 42);
+        assert_eq!(index.get(&key(1)), Some(42));
+        index.clear();
+        assert_eq!(index.len, 0);
+        assert_eq!(index.get(&key(1)), None);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::five_tuple::FiveTuple;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+
+/// Compact, fixed-size key of a flow, built from a `FiveTuple`
+///
+/// Addresses are stored as integers (IPv4 addresses are tagged, so they cannot be confused with
+/// IPv6 addresses), so comparing and hashing a key does not depend on the address family. It is
+/// used by the flow table on the per-packet path, with a fast hasher (see [`FlowHasher`]).
+///
+/// A key is either directional ([`FlowKey::new`]), or symmetric ([`FlowKey::symmetric`]): the
+/// endpoints are ordered, so both directions of a flow have the same key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FlowKey {
+    src: u128,
+    dst: u128,
+    src_port: u16,
+    dst_port: u16,
+    proto: u8,
+    /// IP version (4 or 6)
+    version: u8,
+}
+
+/// Hasher builder for flow keys (aHash, using AES instructions if available)
+pub type FlowHasher = ahash::RandomState;
+
+fn addr_bits(addr: &IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(ip) => (u128::from(u32::from(*ip)), 4),
+        IpAddr::V6(ip) => (u128::from(*ip), 6),
+    }
+}
+
+impl FlowKey {
+    /// Build the key of the direction of `five_t`
+    pub fn new(five_t: &FiveTuple) -> Self {
+        let (src, version) = addr_bits(&five_t.src);
+        let (dst, _) = addr_bits(&five_t.dst);
+        FlowKey {
+            src,
+            dst,
+            src_port: five_t.src_port,
+            dst_port: five_t.dst_port,
+            proto: five_t.proto,
+            version,
+        }
+    }
+
+    /// Build a key which is the same for both directions of `five_t`
+    pub fn symmetric(five_t: &FiveTuple) -> Self {
+        let key = FlowKey::new(five_t);
+        if (key.src, key.src_port) > (key.dst, key.dst_port) {
+            key.reverse()
+        } else {
+            key
+        }
+    }
+
+    /// Return the key of the opposite direction
+    pub fn reverse(&self) -> Self {
+        FlowKey {
+            src: self.dst,
+            dst: self.src,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            ..*self
+        }
+    }
+}
+
+impl Hash for FlowKey {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u128(self.src);
+        state.write_u128(self.dst);
+        state.write_u64(
+            (u64::from(self.src_port) << 32)
+                | (u64::from(self.dst_port) << 16)
+                | (u64::from(self.proto) << 8)
+                | u64::from(self.version),
+        );
+    }
+}
+
+impl From<&FiveTuple> for FlowKey {
+    fn from(five_t: &FiveTuple) -> Self {
+        FlowKey::new(five_t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::BuildHasher;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn flow_key_symmetric() {
+        let five_t = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            src_port: 1234,
+            dst_port: 80,
+        };
+        let reverse = five_t.get_reverse();
+        assert_ne!(FlowKey::new(&five_t), FlowKey::new(&reverse));
+        assert_eq!(FlowKey::new(&five_t).reverse(), FlowKey::new(&reverse));
+        assert_eq!(FlowKey::symmetric(&five_t), FlowKey::symmetric(&reverse));
+        let hasher = FlowHasher::new();
+        let hash = |key: &FlowKey| {
+            let mut h = hasher.build_hasher();
+            key.hash(&mut h);
+            h.finish()
+        };
+        assert_eq!(
+            hash(&FlowKey::symmetric(&five_t)),
+            hash(&FlowKey::symmetric(&reverse))
+        );
+        // an IPv4 address is not the same as the IPv6 address with the same bits
+        let v6 = FiveTuple {
+            src: IpAddr::V6(0x0a00_0002u128.into()),
+            dst: IpAddr::V6(0x0a00_0001u128.into()),
+            ..five_t.clone()
+        };
+        assert_ne!(FlowKey::new(&five_t), FlowKey::new(&v6));
+    }
+}
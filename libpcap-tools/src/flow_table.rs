@@ -0,0 +1,485 @@
+use crate::config::Config;
+use crate::duration::Duration;
+use crate::five_tuple::FiveTuple;
+use crate::flow::{Flow, FlowDirection, FlowID};
+use crate::flow_index::FlowIndex;
+use crate::flow_key::FlowKey;
+use fnv::{FnvHashMap, FnvHashSet};
+use rand::prelude::*;
+use rand_chacha::*;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::{Entry, Iter, Values};
+use std::collections::BTreeMap;
+
+/// TCP FIN flag
+const TCP_FIN: u8 = 0x01;
+/// TCP RST flag
+const TCP_RST: u8 = 0x04;
+
+/// Delay before expiring a closed TCP flow, to keep the last packets (for ex. final ACK) in the
+/// same flow
+const TCP_CLOSE_DELAY: Duration = Duration { secs: 1, nanos: 0 };
+
+/// Flow expiration policies
+///
+/// By default, flows are never expired (they are all destroyed at the end of the analysis).
+///
+/// Policies are read from the `flows` section of the configuration:
+///
+/// - `idle_timeout`: expire flows with no packet for this number of seconds
+/// - `active_timeout`: expire flows older than this number of seconds (later packets create a
+///   new flow)
+/// - `tcp_close`: expire TCP flows after a RST, or a FIN in both directions
+/// - `max_flows`: maximum number of flows. When reached, the least recently used flow is evicted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExpirationPolicy {
+    pub idle_timeout: Option<Duration>,
+    pub active_timeout: Option<Duration>,
+    pub tcp_close: bool,
+    pub max_flows: Option<usize>,
+}
+
+impl ExpirationPolicy {
+    /// Read expiration policies from the `flows` section of `config`
+    pub fn from_config(config: &Config) -> Self {
+        let timeout = |key: &str| {
+            config
+                .get_usize(key)
+                .filter(|secs| *secs > 0)
+                .map(|secs| Duration::new(secs as u32, 0))
+        };
+        ExpirationPolicy {
+            idle_timeout: timeout("flows.idle_timeout"),
+            active_timeout: timeout("flows.active_timeout"),
+            tcp_close: config.get_bool("flows.tcp_close").unwrap_or(false),
+            max_flows: config.get_usize("flows.max_flows").filter(|n| *n > 0),
+        }
+    }
+
+    /// Return true if flows can expire before the end of the analysis (not counting `max_flows`)
+    pub fn has_timeouts(&self) -> bool {
+        self.idle_timeout.is_some() || self.active_timeout.is_some() || self.tcp_close
+    }
+}
+
+/// State of a [`FlowTable`], saved in checkpoints
+///
+/// The expiration policies and the state of the random number generator are not saved.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FlowTableState {
+    /// Flows and their IDs, least recently used first
+    pub flows: Vec<(FlowID, Flow)>,
+    /// Five-tuples of the flows
+    pub flows_id: Vec<(FiveTuple, FlowID)>,
+    /// Directions in which a TCP FIN was seen
+    pub tcp_fin: Vec<(FlowID, u8)>,
+    /// Closed TCP flows
+    pub tcp_closed: Vec<FlowID>,
+}
+
+/// Storage for flows
+///
+/// A `Flow` is identified by a `FlowID`.
+/// Both directions of a flow (the five-tuple and its reverse) have the same `FlowID`: flows are
+/// indexed by their symmetric [`FlowKey`], so a packet of either direction is found with a single
+/// lookup.
+///
+/// The flow table of an analyzer can be accessed using [`PcapAnalyzer::flow_table`](crate::PcapAnalyzer::flow_table),
+/// for ex. to dump flows and their counters after the analysis.
+pub struct FlowTable {
+    trng: ChaChaRng,
+    flows: FnvHashMap<FlowID, Flow>,
+    flows_id: FlowIndex,
+    policy: ExpirationPolicy,
+    /// Flows, ordered by last use (oldest first)
+    lru: BTreeMap<u64, FlowID>,
+    /// Last use of each flow (key in `lru`)
+    last_use: FnvHashMap<FlowID, u64>,
+    use_counter: u64,
+    /// Directions (bit 0: to server, bit 1: to client) in which a TCP FIN was seen
+    tcp_fin: FnvHashMap<FlowID, u8>,
+    /// Closed TCP flows
+    tcp_closed: FnvHashSet<FlowID>,
+}
+
+impl Default for FlowTable {
+    fn default() -> Self {
+        FlowTable {
+            trng: ChaChaRng::from_rng(rand::thread_rng()).unwrap(),
+            flows: FnvHashMap::default(),
+            flows_id: FlowIndex::default(),
+            policy: ExpirationPolicy::default(),
+            lru: BTreeMap::new(),
+            last_use: FnvHashMap::default(),
+            use_counter: 0,
+            tcp_fin: FnvHashMap::default(),
+            tcp_closed: FnvHashSet::default(),
+        }
+    }
+}
+
+impl FlowTable {
+    /// Use provided seed for the random number generator (flow IDs)
+    ///
+    /// This option is intended for use in testing
+    pub fn with_rng_seed(self, seed: u64) -> Self {
+        let trng = <ChaChaRng as SeedableRng>::seed_from_u64(seed);
+        FlowTable { trng, ..self }
+    }
+
+    /// Set the flow expiration policies
+    pub fn with_expiration_policy(self, policy: ExpirationPolicy) -> Self {
+        FlowTable { policy, ..self }
+    }
+
+    /// Return the flow expiration policies
+    pub fn expiration_policy(&self) -> &ExpirationPolicy {
+        &self.policy
+    }
+
+    /// Return the `FlowID` of the flow matching `five_t` (in either direction)
+    #[inline]
+    pub fn lookup_flow(&self, five_t: &FiveTuple) -> Option<FlowID> {
+        self.flows_id.get(&FlowKey::symmetric(five_t))
+    }
+
+    /// Return a reference to the flow matching `five_t` (in either direction)
+    pub fn get_flow_by_five_tuple(&self, five_t: &FiveTuple) -> Option<&Flow> {
+        self.lookup_flow(five_t)
+            .and_then(|flow_id| self.flows.get(&flow_id))
+    }
+
+    /// Return the number of flows
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Returns true if the map contains no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Insert a flow in the hash tables.
+    /// Takes ownership of five_t and flow
+    ///
+    /// If the flow is already known (for ex. in the reverse direction), its `FlowID` is returned
+    /// and `flow` is dropped.
+    pub fn insert_flow(&mut self, five_t: FiveTuple, flow: Flow) -> FlowID {
+        let key = FlowKey::symmetric(&five_t);
+        if let Some(id) = self.flows_id.get(&key) {
+            trace!("Flow already known (id=0x{:x})", id);
+            return id;
+        }
+        // get a new flow index (XXX currently: random number)
+        let id = self.trng.gen();
+        trace!("Inserting new flow (id=0x{:x})", id);
+        trace!("    flow: {:?}", flow);
+        self.flows.insert(id, flow);
+        self.flows_id.insert(key, id);
+        self.touch(id);
+        id
+    }
+
+    /// Update the counters of the flow identified by `flow_id` with a packet, seen at `ts`
+    ///
+    /// `five_t` is the five-tuple of the packet, used to find its direction. `tcp_flags` should
+    /// be 0 if the flow is not TCP.
+    pub fn update_flow(
+        &mut self,
+        flow_id: FlowID,
+        five_t: &FiveTuple,
+        ts: Duration,
+        bytes: u32,
+        tcp_flags: u8,
+    ) {
+        let to_server = match self.flows.get_mut(&flow_id) {
+            Some(flow) => {
+                let to_server = flow.five_tuple == *five_t;
+                flow.flow_id = flow_id;
+                flow.update(ts, bytes, tcp_flags, to_server);
+                to_server
+            }
+            None => return,
+        };
+        self.touch(flow_id);
+        if self.policy.tcp_close && tcp_flags & (TCP_FIN | TCP_RST) != 0 {
+            let fin = self.tcp_fin.entry(flow_id).or_insert(0);
+            if tcp_flags & TCP_FIN != 0 {
+                *fin |= if to_server { 0b01 } else { 0b10 };
+            }
+            if tcp_flags & TCP_RST != 0 || *fin == 0b11 {
+                self.tcp_closed.insert(flow_id);
+            }
+        }
+    }
+
+    /// Reverse the direction of the flow identified by `flow_id`, see [`Flow::reverse`]
+    pub fn reverse_flow(&mut self, flow_id: FlowID, direction: FlowDirection) {
+        if let Some(flow) = self.flows.get_mut(&flow_id) {
+            flow.reverse(direction);
+            if let Some(fin) = self.tcp_fin.get_mut(&flow_id) {
+                *fin = (*fin & 0b01) << 1 | (*fin & 0b10) >> 1;
+            }
+        }
+    }
+
+    /// Mark the flow as recently used
+    fn touch(&mut self, flow_id: FlowID) {
+        if let Some(prev) = self.last_use.insert(flow_id, self.use_counter) {
+            self.lru.remove(&prev);
+        }
+        self.lru.insert(self.use_counter, flow_id);
+        self.use_counter += 1;
+    }
+
+    /// Return true if the maximum number of flows is reached
+    ///
+    /// In this case, a flow should be evicted (see [`FlowTable::evict_lru`]) before inserting
+    /// a new one.
+    pub fn is_full(&self) -> bool {
+        self.policy
+            .max_flows
+            .map_or(false, |max| self.flows.len() >= max)
+    }
+
+    /// Remove and return the least recently used flow
+    pub fn evict_lru(&mut self) -> Option<Flow> {
+        let flow_id = *self.lru.values().next()?;
+        self.remove_flow(flow_id)
+    }
+
+    /// Remove and return the flows expired at `now`, according to the expiration policy
+    pub fn expire_flows(&mut self, now: Duration) -> Vec<Flow> {
+        let policy = &self.policy;
+        let expired: Vec<FlowID> = self
+            .flows
+            .iter()
+            .filter(|(flow_id, flow)| {
+                policy
+                    .idle_timeout
+                    .map_or(false, |timeout| flow.last_seen + timeout < now)
+                    || policy
+                        .active_timeout
+                        .map_or(false, |timeout| flow.first_seen + timeout < now)
+                    || (self.tcp_closed.contains(flow_id) && flow.last_seen + TCP_CLOSE_DELAY < now)
+            })
+            .map(|(flow_id, _)| *flow_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|flow_id| self.remove_flow(flow_id))
+            .collect()
+    }
+
+    /// Remove the flow identified by flow_id (in both directions), and return it
+    pub fn remove_flow(&mut self, flow_id: FlowID) -> Option<Flow> {
+        let flow = self.flows.remove(&flow_id)?;
+        self.flows_id.remove(&FlowKey::symmetric(&flow.five_tuple));
+        if let Some(last_use) = self.last_use.remove(&flow_id) {
+            self.lru.remove(&last_use);
+        }
+        self.tcp_fin.remove(&flow_id);
+        self.tcp_closed.remove(&flow_id);
+        Some(flow)
+    }
+
+    /// Return a reference to the flow identified by flow_id
+    #[inline]
+    pub fn get_flow(&self, flow_id: FlowID) -> Option<&Flow> {
+        self.flows.get(&flow_id)
+    }
+
+    /// Return a mutable reference to the flow identified by flow_id
+    #[inline]
+    pub fn get_flow_mut(&mut self, flow_id: FlowID) -> Option<&mut Flow> {
+        self.flows.get_mut(&flow_id)
+    }
+
+    /// An iterator visiting all flows and their IDs in arbitrary order.
+    #[inline]
+    pub fn iter(&self) -> Iter<FlowID, Flow> {
+        self.flows.iter()
+    }
+
+    /// An iterator visiting all flows in arbitrary order.
+    #[inline]
+    pub fn values(&self) -> Values<FlowID, Flow> {
+        self.flows.values()
+    }
+
+    /// Gets the given key's corresponding entry in the map for in-place manipulation.
+    #[inline]
+    pub fn entry(&mut self, flow_id: FlowID) -> Entry<FlowID, Flow> {
+        self.flows.entry(flow_id)
+    }
+
+    /// Return the state of the table, to save it in a checkpoint
+    pub fn save_state(&self) -> FlowTableState {
+        FlowTableState {
+            flows: self
+                .lru
+                .values()
+                .filter_map(|id| self.flows.get(id).map(|flow| (*id, flow.clone())))
+                .collect(),
+            flows_id: self
+                .flows
+                .iter()
+                .map(|(id, flow)| (flow.five_tuple.clone(), *id))
+                .collect(),
+            tcp_fin: self.tcp_fin.iter().map(|(id, fin)| (*id, *fin)).collect(),
+            tcp_closed: self.tcp_closed.iter().copied().collect(),
+        }
+    }
+
+    /// Replace all flows by the flows of a checkpoint
+    pub fn restore_state(&mut self, state: FlowTableState) {
+        self.clear();
+        self.use_counter = 0;
+        for (id, flow) in state.flows {
+            self.flows.insert(id, flow);
+            self.touch(id);
+        }
+        for (five_t, id) in state.flows_id {
+            self.flows_id.insert(FlowKey::symmetric(&five_t), id);
+        }
+        self.tcp_fin.extend(state.tcp_fin);
+        self.tcp_closed.extend(state.tcp_closed);
+    }
+
+    /// Remove all flows
+    pub fn clear(&mut self) {
+        self.flows.clear();
+        self.flows_id.clear();
+        self.lru.clear();
+        self.last_use.clear();
+        self.tcp_fin.clear();
+        self.tcp_closed.clear();
+    }
+}
+
+impl<'a> IntoIterator for &'a FlowTable {
+    type Item = (&'a FlowID, &'a Flow);
+    type IntoIter = Iter<'a, FlowID, Flow>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn flow_table_lookup() {
+        let five_t = FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: 1234,
+            dst_port: 80,
+        };
+        let mut table = FlowTable::default().with_rng_seed(0);
+        let flow = Flow::new(&five_t, 1, 0);
+        let id = table.insert_flow(five_t.clone(), flow);
+        // reverse flow has the same ID
+        let rev_id = table.insert_flow(five_t.get_reverse(), Flow::new(&five_t, 2, 0));
+        assert_eq!(id, rev_id);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.lookup_flow(&five_t.get_reverse()), Some(id));
+        table
+            .get_flow_mut(id)
+            .unwrap()
+            .update(crate::Duration::new(3, 0), 60, 0x02, false);
+        let flow = table.get_flow_by_five_tuple(&five_t).unwrap();
+        assert_eq!(flow.packets, 1);
+        assert_eq!(flow.bytes, 60);
+        assert_eq!(flow.packets_to_client(), 1);
+        assert_eq!(flow.bytes_to_server, 0);
+        assert_eq!(flow.tcp_flags, 0x02);
+        assert_eq!(flow.first_seen, crate::Duration::new(1, 0));
+        assert_eq!(flow.last_seen, crate::Duration::new(3, 0));
+        assert_eq!(table.iter().count(), 1);
+        // the first packet was sent by the server
+        table.reverse_flow(id, FlowDirection::TcpHandshake);
+        let flow = table.get_flow(id).unwrap();
+        assert_eq!(flow.five_tuple, five_t.get_reverse());
+        assert_eq!(flow.packets_to_server, 1);
+        assert_eq!(flow.bytes_to_client(), 0);
+        assert_eq!(flow.direction, FlowDirection::TcpHandshake);
+        assert_eq!(table.lookup_flow(&five_t), Some(id));
+    }
+
+    #[test]
+    fn flow_table_expiration() {
+        let five_t = |port| FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: port,
+            dst_port: 80,
+        };
+        let policy = ExpirationPolicy {
+            idle_timeout: Some(crate::Duration::new(10, 0)),
+            tcp_close: true,
+            max_flows: Some(2),
+            ..ExpirationPolicy::default()
+        };
+        let mut table = FlowTable::default()
+            .with_rng_seed(0)
+            .with_expiration_policy(policy);
+        let id1 = table.insert_flow(five_t(1), Flow::new(&five_t(1), 1, 0));
+        let id2 = table.insert_flow(five_t(2), Flow::new(&five_t(2), 2, 0));
+        assert!(table.is_full());
+        // flow 1 is used after flow 2, so flow 2 is the least recently used
+        table.update_flow(id1, &five_t(1), crate::Duration::new(3, 0), 60, 0x10);
+        assert_eq!(table.evict_lru().map(|f| f.five_tuple), Some(five_t(2)));
+        assert_eq!(table.lookup_flow(&five_t(2)), None);
+        assert_eq!(table.get_flow(id2), None);
+        // FIN in both directions closes the flow
+        table.update_flow(id1, &five_t(1), crate::Duration::new(4, 0), 60, 0x11);
+        table.update_flow(
+            id1,
+            &five_t(1).get_reverse(),
+            crate::Duration::new(4, 0),
+            60,
+            0x11,
+        );
+        assert!(table.expire_flows(crate::Duration::new(4, 500)).is_empty());
+        assert_eq!(table.expire_flows(crate::Duration::new(6, 0)).len(), 1);
+        assert!(table.is_empty());
+        // idle timeout
+        let id3 = table.insert_flow(five_t(3), Flow::new(&five_t(3), 10, 0));
+        table.update_flow(id3, &five_t(3), crate::Duration::new(10, 0), 60, 0x02);
+        assert!(table.expire_flows(crate::Duration::new(15, 0)).is_empty());
+        assert_eq!(table.expire_flows(crate::Duration::new(21, 0)).len(), 1);
+    }
+
+    #[test]
+    fn flow_table_state() {
+        let five_t = |port| FiveTuple {
+            proto: 6,
+            src: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)),
+            src_port: port,
+            dst_port: 80,
+        };
+        let mut table = FlowTable::default().with_rng_seed(0);
+        let id1 = table.insert_flow(five_t(1), Flow::new(&five_t(1), 1, 0));
+        let id2 = table.insert_flow(five_t(2), Flow::new(&five_t(2), 2, 0));
+        table.insert_flow(five_t(2).get_reverse(), Flow::new(&five_t(2), 2, 0));
+        table.update_flow(id1, &five_t(1), crate::Duration::new(3, 0), 60, 0x10);
+        let state = serde_json::to_string(&table.save_state()).expect("serialize state");
+
+        let mut restored = FlowTable::default();
+        restored.restore_state(serde_json::from_str(&state).expect("deserialize state"));
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.lookup_flow(&five_t(2).get_reverse()), Some(id2));
+        assert_eq!(restored.get_flow(id1), table.get_flow(id1));
+        // LRU order is kept
+        assert_eq!(restored.evict_lru().map(|f| f.five_tuple), Some(five_t(2)));
+    }
+}
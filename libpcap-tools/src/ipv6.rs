@@ -0,0 +1,196 @@
+//! IPv6 extension headers
+//!
+//! [`parse_ipv6_extensions`] walks the chain of extension headers following the fixed IPv6
+//! header (hop-by-hop options, routing, fragment, destination options, authentication, mobility,
+//! HIP and shim6 headers) to find the upper-layer protocol and its data.
+//!
+//! The walk stops at the first header which is not an extension header, at an ESP header (the
+//! rest of the packet is encrypted), and after the fragment header of a non-first fragment (the
+//! rest of the packet is fragment data). The number of extension headers is limited, to bound
+//! the work done for crafted packets.
+
+use thiserror::Error;
+
+/// Default maximum number of extension headers in a packet
+pub const DEFAULT_IPV6_MAX_EXTENSIONS: usize = 8;
+
+const IPV6_HOPOPT: u8 = 0;
+const IPV6_ROUTE: u8 = 43;
+const IPV6_FRAG: u8 = 44;
+const IPV6_AH: u8 = 51;
+const IPV6_OPTS: u8 = 60;
+const IPV6_MOBILITY: u8 = 135;
+const IPV6_HIP: u8 = 139;
+const IPV6_SHIM6: u8 = 140;
+
+/// Errors returned when walking the extension headers
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum Ipv6ExtensionError {
+    #[error("Truncated IPv6 extension header")]
+    Truncated,
+    #[error("Too many IPv6 extension headers")]
+    TooManyExtensions,
+    #[error("Multiple IPv6 fragment headers")]
+    MultipleFragments,
+}
+
+/// Fragment header of an IPv6 packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv6Fragment<'a> {
+    /// Protocol of the fragmented data
+    pub next_header: u8,
+    /// Offset of the fragment, in bytes
+    pub offset: usize,
+    pub more_fragments: bool,
+    pub id: u32,
+    /// Data following the fragment header
+    pub data: &'a [u8],
+}
+
+/// Result of the walk of the extension headers of an IPv6 packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv6Extensions<'a> {
+    /// Protocol of the data following the last extension header
+    pub next_header: u8,
+    /// Number of extension headers
+    pub count: usize,
+    /// Fragment header, if present
+    pub fragment: Option<Ipv6Fragment<'a>>,
+    /// Data following the last extension header
+    pub payload: &'a [u8],
+}
+
+/// Return true if `proto` is an IPv6 extension header which can be skipped
+///
+/// ESP is not included, since the following data is encrypted.
+pub fn is_ipv6_extension(proto: u8) -> bool {
+    matches!(
+        proto,
+        IPV6_HOPOPT
+            | IPV6_ROUTE
+            | IPV6_FRAG
+            | IPV6_AH
+            | IPV6_OPTS
+            | IPV6_MOBILITY
+            | IPV6_HIP
+            | IPV6_SHIM6
+    )
+}
+
+/// Walk the extension headers at the start of `data`, the first one being of type `next_header`
+///
+/// `data` is the payload of the fixed IPv6 header. An error is returned if an extension header
+/// is truncated, if there are more than `max_extensions` headers, or more than one fragment
+/// header.
+pub fn parse_ipv6_extensions(
+    next_header: u8,
+    data: &[u8],
+    max_extensions: usize,
+) -> Result<Ipv6Extensions, Ipv6ExtensionError> {
+    let mut ext = Ipv6Extensions {
+        next_header,
+        count: 0,
+        fragment: None,
+        payload: data,
+    };
+    while is_ipv6_extension(ext.next_header) {
+        if ext.count >= max_extensions {
+            return Err(Ipv6ExtensionError::TooManyExtensions);
+        }
+        let payload = ext.payload;
+        if payload.len() < 8 {
+            return Err(Ipv6ExtensionError::Truncated);
+        }
+        let len = match ext.next_header {
+            IPV6_FRAG => {
+                if ext.fragment.is_some() {
+                    return Err(Ipv6ExtensionError::MultipleFragments);
+                }
+                let offset_flags = u16::from_be_bytes([payload[2], payload[3]]);
+                ext.fragment = Some(Ipv6Fragment {
+                    next_header: payload[0],
+                    offset: usize::from(offset_flags & !7),
+                    more_fragments: offset_flags & 1 != 0,
+                    id: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+                    data: &payload[8..],
+                });
+                8
+            }
+            // RFC 4302: length in 4-octet units, minus 2
+            IPV6_AH => (usize::from(payload[1]) + 2) * 4,
+            // RFC 8200: length in 8-octet units, not including the first 8 octets
+            _ => (usize::from(payload[1]) + 1) * 8,
+        };
+        if len > payload.len() {
+            return Err(Ipv6ExtensionError::Truncated);
+        }
+        ext.count += 1;
+        ext.next_header = payload[0];
+        ext.payload = &payload[len..];
+        if matches!(ext.fragment, Some(f) if f.offset != 0) {
+            // the headers of the upper layer are in the first fragment
+            break;
+        }
+    }
+    Ok(ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_extensions_chain() {
+        // hop-by-hop (8 bytes), destination options (16 bytes), AH (16 bytes), then TCP
+        let mut data = vec![60, 0, 1, 4, 0, 0, 0, 0];
+        data.extend_from_slice(&[51, 1, 1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[6, 2, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0]);
+        data.extend_from_slice(b"tcp");
+        let ext = parse_ipv6_extensions(0, &data, DEFAULT_IPV6_MAX_EXTENSIONS).expect("walk");
+        assert_eq!(ext.next_header, 6);
+        assert_eq!(ext.count, 3);
+        assert_eq!(ext.fragment, None);
+        assert_eq!(ext.payload, b"tcp");
+        // chain longer than the limit, and truncated header
+        assert_eq!(
+            parse_ipv6_extensions(0, &data, 2),
+            Err(Ipv6ExtensionError::TooManyExtensions)
+        );
+        assert_eq!(
+            parse_ipv6_extensions(0, &data[..20], 8),
+            Err(Ipv6ExtensionError::Truncated)
+        );
+        // ESP is not walked
+        let ext = parse_ipv6_extensions(50, &data, 8).expect("walk");
+        assert_eq!((ext.next_header, ext.count), (50, 0));
+    }
+
+    #[test]
+    fn ipv6_extensions_fragment() {
+        // first fragment (more fragments, id 0x1234), then destination options and UDP
+        let mut data = vec![60, 0, 0, 1, 0, 0, 0x12, 0x34];
+        data.extend_from_slice(&[17, 0, 1, 4, 0, 0, 0, 0]);
+        data.extend_from_slice(b"udp");
+        let ext = parse_ipv6_extensions(44, &data, 8).expect("walk");
+        assert_eq!(ext.next_header, 17);
+        assert_eq!(ext.payload, b"udp");
+        let frag = ext.fragment.expect("fragment header");
+        assert_eq!((frag.next_header, frag.offset), (60, 0));
+        assert!(frag.more_fragments);
+        assert_eq!(frag.id, 0x1234);
+        assert_eq!(frag.data, &data[8..]);
+        // non-first fragment (offset 1480): the walk stops after the fragment header
+        let data = [60, 0, 0x05, 0xc8, 0, 0, 0x12, 0x34, 0, 1, 2, 3];
+        let ext = parse_ipv6_extensions(44, &data, 8).expect("walk");
+        assert_eq!(ext.next_header, 60);
+        assert_eq!(ext.count, 1);
+        assert_eq!(ext.fragment.map(|f| f.offset), Some(1480));
+        assert_eq!(ext.payload, &data[8..]);
+        // two fragment headers
+        let data = [44, 0, 0, 0, 0, 0, 0, 1, 6, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!(
+            parse_ipv6_extensions(44, &data, 8),
+            Err(Ipv6ExtensionError::MultipleFragments)
+        );
+    }
+}
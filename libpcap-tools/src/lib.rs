@@ -5,20 +5,39 @@
 extern crate log;
 
 mod analyzer;
+#[cfg(feature = "async")]
+mod async_engine;
 mod block_engine;
+mod checkpoint;
+mod chunk;
+mod compression;
 mod config;
 mod context;
 mod data_engine;
+pub mod defrag;
 mod duration;
 mod engine;
 mod error;
 mod five_tuple;
 mod flow;
+mod flow_index;
+mod flow_key;
+mod flow_table;
+pub mod ipv6;
+#[cfg(feature = "live")]
+mod live_engine;
 mod packet;
+mod parallel_engine;
+mod progress;
 mod three_tuple;
 
 pub use analyzer::*;
+#[cfg(feature = "async")]
+pub use async_engine::*;
 pub use block_engine::*;
+pub use checkpoint::{Checkpoint, CheckpointConfig};
+pub use chunk::{DataChunk, PacketBytes};
+pub use compression::{decompress_reader, Compression};
 pub use config::Config;
 pub use context::*;
 pub use data_engine::*;
@@ -27,7 +46,13 @@ pub use engine::*;
 pub use error::*;
 pub use five_tuple::*;
 pub use flow::*;
+pub use flow_key::{FlowHasher, FlowKey};
+pub use flow_table::{ExpirationPolicy, FlowTable, FlowTableState};
+#[cfg(feature = "live")]
+pub use live_engine::*;
 pub use packet::*;
+pub use parallel_engine::ParallelPcapDataEngine;
+pub use progress::*;
 pub use three_tuple::ThreeTuple;
 
 pub use pcap_parser;
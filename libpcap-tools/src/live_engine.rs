@@ -0,0 +1,183 @@
+use crate::analyzer::PcapAnalyzer;
+use crate::context::*;
+use crate::duration::Duration;
+use crate::error::{Error, ErrorCounters, ErrorPolicy};
+use crate::packet::Packet;
+use pcap_parser::Linktype;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Options of a live capture
+#[derive(Clone, Debug)]
+pub struct LiveOptions {
+    /// Maximum number of octets captured from each packet (default: 65535)
+    pub snaplen: i32,
+    /// Put the interface in promiscuous mode (default: `false`)
+    pub promiscuous: bool,
+    /// Size of the kernel capture buffer, in bytes (default: libpcap default)
+    pub buffer_size: Option<i32>,
+    /// Read timeout, in milliseconds. The stop flag is checked at least once per timeout
+    /// (default: 100)
+    pub timeout_ms: i32,
+    /// Action for packets which cannot be decoded, or for which the analyzer returns an error
+    /// (default: abort). Skipped packets are logged and counted.
+    pub error_policy: ErrorPolicy,
+}
+
+impl Default for LiveOptions {
+    fn default() -> Self {
+        LiveOptions {
+            snaplen: 65535,
+            promiscuous: false,
+            buffer_size: None,
+            timeout_ms: 100,
+            error_policy: ErrorPolicy::default(),
+        }
+    }
+}
+
+/// Live capture engine
+///
+/// `LiveEngine` captures packets from a network interface (using libpcap), and gives them to a
+/// `PcapAnalyzer`, like `PcapDataEngine` does for files. Since there is no file, the
+/// `handle_block` callback of the analyzer is never called.
+///
+/// The capture runs until the stop flag (see [`LiveEngine::stop_handle`]) is set, for ex. from
+/// a signal handler.
+///
+/// This engine requires the `live` feature.
+pub struct LiveEngine<A: PcapAnalyzer> {
+    data_analyzer: A,
+    interface: String,
+    options: LiveOptions,
+    stop: Arc<AtomicBool>,
+    errors: ErrorCounters,
+}
+
+impl<A: PcapAnalyzer> LiveEngine<A> {
+    pub fn new(data_analyzer: A, interface: &str, options: LiveOptions) -> Self {
+        LiveEngine {
+            data_analyzer,
+            interface: interface.to_owned(),
+            options,
+            stop: Arc::new(AtomicBool::new(false)),
+            errors: ErrorCounters::default(),
+        }
+    }
+
+    pub fn data_analyzer(&self) -> &A {
+        &self.data_analyzer
+    }
+
+    pub fn data_analyzer_mut(&mut self) -> &mut A {
+        &mut self.data_analyzer
+    }
+
+    /// Return a flag which stops the capture when set to `true`
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Return the number of packets skipped during the last run (see `LiveOptions::error_policy`)
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.errors
+    }
+
+    /// Main function: capture packets and call analyzer for each Packet, until stopped
+    ///
+    /// `teardown` is called when the capture ends, even if it ends with an error.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut capture = pcap::Capture::from_device(self.interface.as_str())?
+            .snaplen(self.options.snaplen)
+            .promisc(self.options.promiscuous)
+            .timeout(self.options.timeout_ms);
+        if let Some(buffer_size) = self.options.buffer_size {
+            capture = capture.buffer_size(buffer_size);
+        }
+        let mut capture = capture.open()?;
+        let link_type = Linktype(capture.get_datalink().0);
+        debug!(
+            "Live capture on {}, link type: {}",
+            self.interface, link_type
+        );
+
+        self.errors = ErrorCounters::default();
+        self.data_analyzer.init()?;
+        let res = self.capture_packets(&mut capture, link_type);
+        if self.errors.total() > 0 {
+            warn!("Errors skipped: {} packets", self.errors.skipped_packets);
+        }
+        self.data_analyzer.teardown();
+        res
+    }
+
+    fn capture_packets(
+        &mut self,
+        capture: &mut pcap::Capture<pcap::Active>,
+        link_type: Linktype,
+    ) -> Result<(), Error> {
+        let mut ctx = ParseContext::default();
+        ctx.interfaces.push(InterfaceInfo {
+            link_type,
+            if_tsresol: 6,
+            ts_unit: 1_000_000,
+            snaplen: self.options.snaplen as u32,
+            if_name: Some(self.interface.clone()),
+            ..InterfaceInfo::default()
+        });
+        self.data_analyzer
+            .handle_interface_added(0, &ctx.interfaces[0], &ctx)?;
+
+        while !self.stop.load(Ordering::Relaxed) {
+            let packet = match capture.next_packet() {
+                Ok(packet) => packet,
+                Err(pcap::Error::TimeoutExpired) => continue,
+                Err(pcap::Error::NoMorePackets) => break,
+                Err(e) => return Err(e.into()),
+            };
+            ctx.pcap_index += 1;
+            let header = packet.header;
+            let ts = Duration::new(header.ts.tv_sec as u32, header.ts.tv_usec as u32);
+            let data = match pcap_parser::data::get_packetdata(
+                packet.data,
+                link_type,
+                header.caplen as usize,
+            ) {
+                Some(data) => data,
+                None => {
+                    self.check_result(Err(Error::PacketData(link_type)), &ctx)?;
+                    continue;
+                }
+            };
+            let packet = Packet {
+                interface: 0,
+                ts,
+                link_type,
+                data,
+                origlen: header.len,
+                caplen: header.caplen,
+                pcap_index: ctx.pcap_index,
+                chunk: None,
+            };
+            if ctx.first_packet_ts.is_null() {
+                ctx.first_packet_ts = packet.ts;
+            }
+            ctx.rel_ts = packet.ts - ctx.first_packet_ts;
+            let res = self.data_analyzer.handle_packet(&packet, &ctx);
+            self.check_result(res, &ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the error policy to the result of the analysis of a packet
+    fn check_result(&mut self, res: Result<(), Error>, ctx: &ParseContext) -> Result<(), Error> {
+        match res {
+            Err(e) if self.options.error_policy != ErrorPolicy::Abort => {
+                warn!("Skipping packet (pcap_index={}): {}", ctx.pcap_index, e);
+                self.errors.skipped_packets += 1;
+                Ok(())
+            }
+            res => res,
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::chunk::{DataChunk, PacketBytes};
 use crate::duration::Duration;
 use pcap_parser::{data::PacketData, Linktype};
 
@@ -10,4 +11,24 @@ pub struct Packet<'a> {
     pub caplen: u32,
     pub origlen: u32,
     pub pcap_index: usize,
+    /// Chunk of input data containing the packet data, if shared by the engine
+    pub chunk: Option<DataChunk>,
+}
+
+impl<'a> Packet<'a> {
+    /// Return a reference to `data` (for ex. the payload of the packet) which can be kept after
+    /// the packet was handled, without copying it
+    ///
+    /// Returns `None` if `data` is not in the chunk of the packet (or if the packet has no
+    /// chunk, for ex. reassembled or generated data).
+    pub fn bytes(&self, data: &[u8]) -> Option<PacketBytes> {
+        self.chunk.as_ref().and_then(|chunk| chunk.slice_ref(data))
+    }
+
+    /// Return a reference to `data` which can be kept after the packet was handled, copying it
+    /// only if it is not in the chunk of the packet
+    pub fn to_bytes(&self, data: &[u8]) -> PacketBytes {
+        self.bytes(data)
+            .unwrap_or_else(|| PacketBytes::copy_from_slice(data))
+    }
 }
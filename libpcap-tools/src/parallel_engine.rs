@@ -0,0 +1,555 @@
+use crate::analyzer::{PcapAnalyzer, SafePcapAnalyzer};
+use crate::chunk::PacketBytes;
+use crate::config::Config;
+use crate::context::*;
+use crate::data_engine::PcapDataEngine;
+use crate::duration::Duration;
+use crate::engine::{InputMode, PcapEngine};
+use crate::error::{Error, ErrorCounters, ErrorPolicy};
+use crate::packet::Packet;
+use crate::progress::ProgressReporter;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use fnv::FnvHasher;
+use pcap_parser::data::PacketData;
+use pcap_parser::Linktype;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Packet sent to a worker thread
+///
+/// Packet data is shared with the chunk of input data when possible, and copied otherwise.
+struct OwnedPacket {
+    interface: u32,
+    ts: Duration,
+    link_type: Linktype,
+    data: OwnedPacketData,
+    caplen: u32,
+    origlen: u32,
+    pcap_index: usize,
+}
+
+enum OwnedPacketData {
+    L2(PacketBytes),
+    L3(u16, PacketBytes),
+    L4(u8, PacketBytes),
+    Unsupported(PacketBytes),
+}
+
+impl OwnedPacket {
+    fn new(packet: &Packet) -> Self {
+        let data = match packet.data {
+            PacketData::L2(data) => OwnedPacketData::L2(packet.to_bytes(data)),
+            PacketData::L3(ethertype, data) => {
+                OwnedPacketData::L3(ethertype, packet.to_bytes(data))
+            }
+            PacketData::L4(proto, data) => OwnedPacketData::L4(proto, packet.to_bytes(data)),
+            PacketData::Unsupported(data) => OwnedPacketData::Unsupported(packet.to_bytes(data)),
+        };
+        OwnedPacket {
+            interface: packet.interface,
+            ts: packet.ts,
+            link_type: packet.link_type,
+            data,
+            caplen: packet.caplen,
+            origlen: packet.origlen,
+            pcap_index: packet.pcap_index,
+        }
+    }
+
+    fn packet(&self) -> Packet {
+        let (data, bytes) = match &self.data {
+            OwnedPacketData::L2(data) => (PacketData::L2(data), data),
+            OwnedPacketData::L3(ethertype, data) => (PacketData::L3(*ethertype, data), data),
+            OwnedPacketData::L4(proto, data) => (PacketData::L4(*proto, data), data),
+            OwnedPacketData::Unsupported(data) => (PacketData::Unsupported(data), data),
+        };
+        Packet {
+            interface: self.interface,
+            ts: self.ts,
+            link_type: self.link_type,
+            data,
+            caplen: self.caplen,
+            origlen: self.origlen,
+            pcap_index: self.pcap_index,
+            chunk: Some(bytes.chunk().clone()),
+        }
+    }
+}
+
+enum Job {
+    Packet(OwnedPacket, ParseContext),
+    SectionStart(ParseContext),
+    SectionEnd(ParseContext),
+    NameResolution(Vec<(IpAddr, String)>, ParseContext),
+    InterfaceAdded(u32, InterfaceInfo, ParseContext),
+    InterfaceStatistics(InterfaceStatistics, ParseContext),
+}
+
+/// Return a hash of the addresses of a packet, identical for both directions
+///
+/// Ports are not used, so all fragments of a datagram have the same hash. Packets which are
+/// not IPv4 or IPv6 have a hash of 0.
+fn flow_hash(data: &PacketData) -> u64 {
+    let (mut ethertype, mut data) = match *data {
+        PacketData::L2(data) if data.len() >= 14 => {
+            (u16::from_be_bytes([data[12], data[13]]), &data[14..])
+        }
+        PacketData::L3(ethertype, data) => (ethertype, data),
+        _ => return 0,
+    };
+    // skip VLAN tags
+    while (ethertype == 0x8100 || ethertype == 0x88a8) && data.len() >= 4 {
+        ethertype = u16::from_be_bytes([data[2], data[3]]);
+        data = &data[4..];
+    }
+    // the IP version is used if the ethertype is unknown (for ex. RAW link type)
+    let version = data.first().map_or(0, |b| b >> 4);
+    let (src, dst) = match (ethertype, version) {
+        (0x0800, _) | (0, 4) if data.len() >= 20 => (&data[12..16], &data[16..20]),
+        (0x86dd, _) | (0, 6) if data.len() >= 40 => (&data[8..24], &data[24..40]),
+        _ => return 0,
+    };
+    let (a, b) = if src <= dst { (src, dst) } else { (dst, src) };
+    let mut hasher = FnvHasher::default();
+    hasher.write(a);
+    hasher.write(b);
+    hasher.finish()
+}
+
+/// Run the analyzer of a worker thread, sending the errors it returns to the dispatcher
+fn worker<A: PcapAnalyzer>(
+    mut analyzer: A,
+    idx: usize,
+    jobs: Receiver<Job>,
+    errors: Sender<Error>,
+) -> A {
+    debug!("worker thread {} starting", idx);
+    // the loop ends when the dispatcher drops its senders
+    for job in jobs.iter() {
+        let res = match job {
+            Job::Packet(packet, ctx) => analyzer.handle_packet(&packet.packet(), &ctx),
+            Job::SectionStart(ctx) => analyzer.section_start(&ctx),
+            Job::SectionEnd(ctx) => analyzer.section_end(&ctx),
+            Job::NameResolution(names, ctx) => analyzer.handle_name_resolution(&names, &ctx),
+            Job::InterfaceAdded(if_id, info, ctx) => {
+                analyzer.handle_interface_added(if_id, &info, &ctx)
+            }
+            Job::InterfaceStatistics(stats, ctx) => {
+                analyzer.handle_interface_statistics(&stats, &ctx)
+            }
+        };
+        if let Err(e) = res {
+            debug!("worker thread {}: analyzer returned error: {}", idx, e);
+            // the dispatcher only stops after the workers
+            let _ = errors.send(e);
+        }
+    }
+    analyzer.teardown();
+    debug!("worker thread {} done", idx);
+    analyzer
+}
+
+/// Analyzer dispatching packets to worker threads
+struct Dispatcher<A: SafePcapAnalyzer + 'static> {
+    /// Analyzers of the workers, when workers are not running
+    analyzers: Vec<A>,
+    queue_size: usize,
+    jobs: Vec<Sender<Job>>,
+    workers: Vec<JoinHandle<A>>,
+    /// Errors returned by the analyzers of the workers
+    errors: (Sender<Error>, Receiver<Error>),
+    /// Errors received after the last job was sent, while joining the workers
+    late_errors: Vec<Error>,
+}
+
+impl<A: SafePcapAnalyzer + 'static> Dispatcher<A> {
+    fn send(&self, idx: usize, job: Job) -> Result<(), Error> {
+        self.jobs[idx]
+            .send(job)
+            .or(Err(Error::Generic("Error while sending job")))
+    }
+
+    fn broadcast<F: Fn() -> Job>(&self, f: F) -> Result<(), Error> {
+        for idx in 0..self.jobs.len() {
+            self.send(idx, f())?;
+        }
+        Ok(())
+    }
+
+    /// Return the next error returned by the analyzers of the workers, if any
+    ///
+    /// Errors are returned after sending a job, so the error policy of the engine is applied
+    /// to them.
+    fn worker_error(&self) -> Result<(), Error> {
+        match self.errors.1.try_recv() {
+            Ok(e) => Err(e),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Stop the workers, once all jobs are processed, and get back their analyzers
+    fn join_workers(&mut self) {
+        // dropping the senders stops the workers
+        self.jobs.clear();
+        for handle in self.workers.drain(..) {
+            match handle.join() {
+                Ok(analyzer) => self.analyzers.push(analyzer),
+                Err(_) => error!("panic occurred in a worker thread"),
+            }
+        }
+        self.late_errors.extend(self.errors.1.try_iter());
+    }
+}
+
+impl<A: SafePcapAnalyzer + 'static> Drop for Dispatcher<A> {
+    fn drop(&mut self) {
+        self.join_workers();
+    }
+}
+
+impl<A: SafePcapAnalyzer + 'static> PcapAnalyzer for Dispatcher<A> {
+    fn init(&mut self) -> Result<(), Error> {
+        if self.analyzers.len() > 1 {
+            for analyzer in self.analyzers.iter() {
+                analyzer.check_parallel()?;
+            }
+        }
+        for analyzer in self.analyzers.iter_mut() {
+            analyzer.init()?;
+        }
+        // discard the errors of the previous run
+        self.late_errors.clear();
+        while self.errors.1.try_recv().is_ok() {}
+        for (idx, analyzer) in self.analyzers.drain(..).enumerate() {
+            let (sender, receiver) = bounded(self.queue_size);
+            let errors = self.errors.0.clone();
+            let handle = thread::Builder::new()
+                .name(format!("worker {}", idx))
+                .spawn(move || worker(analyzer, idx, receiver, errors))?;
+            self.jobs.push(sender);
+            self.workers.push(handle);
+        }
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: &Packet, ctx: &ParseContext) -> Result<(), Error> {
+        let idx = (flow_hash(&packet.data) % self.jobs.len() as u64) as usize;
+        self.send(idx, Job::Packet(OwnedPacket::new(packet), ctx.clone()))?;
+        self.worker_error()
+    }
+
+    fn section_start(&mut self, ctx: &ParseContext) -> Result<(), Error> {
+        self.broadcast(|| Job::SectionStart(ctx.clone()))?;
+        self.worker_error()
+    }
+
+    fn section_end(&mut self, ctx: &ParseContext) -> Result<(), Error> {
+        self.broadcast(|| Job::SectionEnd(ctx.clone()))?;
+        self.worker_error()
+    }
+
+    fn handle_name_resolution(
+        &mut self,
+        names: &[(IpAddr, String)],
+        ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        self.broadcast(|| Job::NameResolution(names.to_vec(), ctx.clone()))?;
+        self.worker_error()
+    }
+
+    fn handle_interface_added(
+        &mut self,
+        if_id: u32,
+        info: &InterfaceInfo,
+        ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        self.broadcast(|| Job::InterfaceAdded(if_id, info.clone(), ctx.clone()))?;
+        self.worker_error()
+    }
+
+    fn handle_interface_statistics(
+        &mut self,
+        stats: &InterfaceStatistics,
+        ctx: &ParseContext,
+    ) -> Result<(), Error> {
+        self.broadcast(|| Job::InterfaceStatistics(stats.clone(), ctx.clone()))?;
+        self.worker_error()
+    }
+
+    fn teardown(&mut self) {
+        self.join_workers();
+    }
+}
+
+/// Multi-threaded pcap/pcap-ng data analyzer engine
+///
+/// Blocks are parsed by the calling thread, and packets are dispatched to worker threads, each
+/// running its own instance of the analyzer. Packets are dispatched using a hash of their IP
+/// addresses, so all packets of a flow (in both directions) are handled by the same worker, in
+/// order. Other events (sections, name resolution, interface statistics) are sent to all
+/// workers.
+///
+/// Analyzers must implement the [`SafePcapAnalyzer`] trait, and are checked with
+/// `SafePcapAnalyzer::check_parallel` before starting the workers. `init` is called on the
+/// calling thread, other callbacks (including `teardown`) are called on the worker threads.
+/// `handle_block` and `before_refill` are not called.
+///
+/// Errors returned by the analyzers are sent back to the calling thread, and handled following
+/// the error policy of the engine (see [`ErrorPolicy`]). If the run fails, the workers are
+/// stopped before returning the error, so analyzers are always available after a run.
+///
+/// The number of packets waiting for each worker is limited by the `parallel.queue_size`
+/// configuration value (default: 1024).
+pub struct ParallelPcapDataEngine<A: SafePcapAnalyzer + 'static> {
+    engine: PcapDataEngine<Dispatcher<A>>,
+    error_policy: ErrorPolicy,
+    /// Errors returned by the analyzers after the last job, skipped following the error policy
+    skipped_late_errors: u64,
+}
+
+impl<A: SafePcapAnalyzer + 'static> ParallelPcapDataEngine<A> {
+    /// Create an engine, with one worker thread for each analyzer
+    ///
+    /// # Panics
+    ///
+    /// Panics if `analyzers` is empty.
+    pub fn new(analyzers: Vec<A>, config: &Config) -> Self {
+        assert!(!analyzers.is_empty(), "at least one analyzer is required");
+        let dispatcher = Dispatcher {
+            analyzers,
+            queue_size: config.get_usize("parallel.queue_size").unwrap_or(1024),
+            jobs: Vec::new(),
+            workers: Vec::new(),
+            errors: unbounded(),
+            late_errors: Vec::new(),
+        };
+        let engine = PcapDataEngine::new(dispatcher, config);
+        ParallelPcapDataEngine {
+            engine,
+            error_policy: ErrorPolicy::from_config(config),
+            skipped_late_errors: 0,
+        }
+    }
+
+    /// Return the analyzers of the workers
+    ///
+    /// Analyzers are only available when the engine is not running.
+    pub fn analyzers(&self) -> &[A] {
+        &self.engine.data_analyzer().analyzers
+    }
+
+    /// Return the analyzers of the workers (mutable version)
+    pub fn analyzers_mut(&mut self) -> &mut [A] {
+        &mut self.engine.data_analyzer_mut().analyzers
+    }
+
+    /// Return the number of errors skipped during the last run (see `ErrorPolicy`)
+    pub fn error_counters(&self) -> ErrorCounters {
+        let mut counters = self.engine.error_counters();
+        counters.skipped_packets += self.skipped_late_errors;
+        counters
+    }
+
+    /// End a run: stop the workers if the run failed (`teardown` is not called), and apply the
+    /// error policy to the errors returned by the analyzers after the last job
+    fn end_run(&mut self, res: Result<(), Error>) -> Result<(), Error> {
+        self.skipped_late_errors = 0;
+        let dispatcher = self.engine.data_analyzer_mut();
+        dispatcher.join_workers();
+        let late_errors = std::mem::take(&mut dispatcher.late_errors);
+        res?;
+        for e in late_errors {
+            if self.error_policy == ErrorPolicy::Abort {
+                return Err(e);
+            }
+            warn!("Skipping error returned by a worker: {}", e);
+            self.skipped_late_errors += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<A: SafePcapAnalyzer + 'static> PcapEngine for ParallelPcapDataEngine<A> {
+    fn run(&mut self, reader: &mut dyn Read) -> Result<(), Error> {
+        let res = self.engine.run(reader);
+        self.end_run(res)
+    }
+
+    fn run_file(&mut self, file: &File) -> Result<(), Error> {
+        let res = self.engine.run_file(file);
+        self.end_run(res)
+    }
+
+    fn set_progress_reporter(&mut self, reporter: ProgressReporter) {
+        self.engine.set_progress_reporter(reporter)
+    }
+
+    fn set_stop_handle(&mut self, stop: Arc<AtomicBool>) {
+        self.engine.set_stop_handle(stop)
+    }
+
+    fn run_inputs(&mut self, readers: &mut [Box<dyn Read>], mode: InputMode) -> Result<(), Error> {
+        let res = self.engine.run_inputs(readers, mode);
+        self.end_run(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[derive(Default)]
+    struct PacketCounter {
+        /// Index of packets
+        packets: Vec<usize>,
+    }
+
+    impl PcapAnalyzer for PacketCounter {
+        fn handle_packet(&mut self, packet: &Packet, _ctx: &ParseContext) -> Result<(), Error> {
+            self.packets.push(packet.pcap_index);
+            Ok(())
+        }
+    }
+
+    impl SafePcapAnalyzer for PacketCounter {}
+
+    /// Analyzer returning an error for the packet with index `fail_index`
+    struct FailingAnalyzer {
+        fail_index: usize,
+        torn_down: bool,
+    }
+
+    impl PcapAnalyzer for FailingAnalyzer {
+        fn handle_packet(&mut self, packet: &Packet, _ctx: &ParseContext) -> Result<(), Error> {
+            if packet.pcap_index == self.fail_index {
+                return Err(Error::Generic("analyzer error"));
+            }
+            Ok(())
+        }
+
+        fn teardown(&mut self) {
+            self.torn_down = true;
+        }
+    }
+
+    impl SafePcapAnalyzer for FailingAnalyzer {}
+
+    /// Analyzer which cannot run on several worker threads
+    struct SingleAnalyzer;
+
+    impl PcapAnalyzer for SingleAnalyzer {
+        fn handle_packet(&mut self, _packet: &Packet, _ctx: &ParseContext) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl SafePcapAnalyzer for SingleAnalyzer {
+        fn check_parallel(&self) -> Result<(), Error> {
+            Err(Error::Unsupported("single instance"))
+        }
+    }
+
+    fn ipv4(src: u8, dst: u8) -> Vec<u8> {
+        let mut ip = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0];
+        ip.extend_from_slice(&[10, 0, 0, src, 10, 0, 0, dst]);
+        ip
+    }
+
+    /// Build a legacy pcap file (raw link type), with one packet per address pair
+    fn legacy_pcap(addresses: &[(u8, u8)]) -> Vec<u8> {
+        let mut v = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        v.extend_from_slice(&[0; 8]);
+        v.extend_from_slice(&65535u32.to_le_bytes());
+        v.extend_from_slice(&101u32.to_le_bytes());
+        for (src, dst) in addresses {
+            let ip = ipv4(*src, *dst);
+            v.extend_from_slice(&[0; 8]);
+            v.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+            v.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+            v.extend_from_slice(&ip);
+        }
+        v
+    }
+
+    #[test]
+    fn flow_hash_symmetric() {
+        let (a, b) = (ipv4(1, 2), ipv4(2, 1));
+        let hash = flow_hash(&PacketData::L3(0x0800, &a));
+        assert_ne!(hash, 0);
+        assert_eq!(hash, flow_hash(&PacketData::L3(0, &b)));
+        let mut eth = vec![0; 12];
+        eth.extend_from_slice(&[0x81, 0x00, 0x00, 0x01, 0x08, 0x00]);
+        eth.extend_from_slice(&b);
+        assert_eq!(hash, flow_hash(&PacketData::L2(&eth)));
+        assert_ne!(hash, flow_hash(&PacketData::L3(0x0800, &ipv4(1, 3))));
+    }
+
+    #[test]
+    fn parallel_engine_flow_dispatch() {
+        let addresses = [(1, 2), (3, 4), (2, 1), (5, 6), (1, 2), (4, 3), (7, 8)];
+        let data = legacy_pcap(&addresses);
+        let config = Config::default();
+        let analyzers = vec![PacketCounter::default(), PacketCounter::default()];
+        let mut engine = ParallelPcapDataEngine::new(analyzers, &config);
+        engine.run(&mut Cursor::new(data)).expect("run engine");
+        let mut total = 0;
+        for analyzer in engine.analyzers() {
+            total += analyzer.packets.len();
+            // each worker receives packets in order
+            assert!(analyzer.packets.windows(2).all(|w| w[0] < w[1]));
+        }
+        assert_eq!(total, addresses.len());
+        // all packets of a flow, in both directions, are handled by the same worker
+        let worker_of = |index: usize| {
+            engine
+                .analyzers()
+                .iter()
+                .position(|a| a.packets.contains(&index))
+                .expect("packet was handled")
+        };
+        assert_eq!(worker_of(1), worker_of(3));
+        assert_eq!(worker_of(1), worker_of(5));
+        assert_eq!(worker_of(2), worker_of(6));
+    }
+
+    #[test]
+    fn parallel_engine_worker_error() {
+        let data = legacy_pcap(&[(1, 2), (3, 4), (5, 6)]);
+        let analyzers = || {
+            vec![FailingAnalyzer {
+                fail_index: 2,
+                torn_down: false,
+            }]
+        };
+        // the error of the worker is returned, and the workers are stopped
+        let mut engine = ParallelPcapDataEngine::new(analyzers(), &Config::default());
+        assert!(engine.run(&mut Cursor::new(data.clone())).is_err());
+        assert_eq!(engine.analyzers().len(), 1);
+        assert!(engine.analyzers()[0].torn_down);
+        // errors are skipped with another error policy
+        let mut config = Config::default();
+        config.set("error_policy", "skip-packet");
+        let mut engine = ParallelPcapDataEngine::new(analyzers(), &config);
+        engine.run(&mut Cursor::new(data)).expect("run engine");
+        assert_eq!(engine.error_counters().skipped_packets, 1);
+    }
+
+    #[test]
+    fn parallel_engine_check_parallel() {
+        let data = legacy_pcap(&[(1, 2)]);
+        let config = Config::default();
+        let mut engine = ParallelPcapDataEngine::new(vec![SingleAnalyzer], &config);
+        engine
+            .run(&mut Cursor::new(data.clone()))
+            .expect("run engine");
+        let analyzers = vec![SingleAnalyzer, SingleAnalyzer];
+        let mut engine = ParallelPcapDataEngine::new(analyzers, &config);
+        assert!(engine.run(&mut Cursor::new(data)).is_err());
+    }
+}
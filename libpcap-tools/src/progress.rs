@@ -0,0 +1,233 @@
+//! Progress reporting
+//!
+//! Engines call a [`ProgressReporter`] for every block read. The reporter calls its callback
+//! periodically with a [`Progress`] summary (bytes read, total size if known, packet rate), and
+//! a last time at the end of the run.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Number of blocks read between two checks of the clock
+const CHECK_INTERVAL: u64 = 256;
+
+/// Progress of a run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// Number of bytes read
+    pub bytes: u64,
+    /// Total size of the input, if known
+    pub total_bytes: Option<u64>,
+    /// Number of packets read
+    pub packets: u64,
+    /// Time elapsed since the start of the run
+    pub elapsed: Duration,
+    /// True for the last report of the run
+    pub finished: bool,
+}
+
+impl Progress {
+    /// Return the fraction of the input read (between 0 and 1), if the total size is known
+    pub fn ratio(&self) -> Option<f64> {
+        match self.total_bytes {
+            Some(total) if total > 0 => Some((self.bytes as f64 / total as f64).min(1.0)),
+            _ => None,
+        }
+    }
+
+    /// Return the average number of packets per second
+    pub fn packets_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.packets as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Draw the progress on the standard error, as a line updated in place
+    pub fn draw_stderr(&self) {
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\r{}\x1b[K", self);
+        if self.finished {
+            let _ = writeln!(stderr);
+        }
+        let _ = stderr.flush();
+    }
+
+    /// Return the estimated remaining time, if the total size is known
+    pub fn eta(&self) -> Option<Duration> {
+        let ratio = self.ratio().filter(|r| *r > 0.0)?;
+        let elapsed = self.elapsed.as_secs_f64();
+        Some(Duration::from_secs_f64(elapsed / ratio - elapsed))
+    }
+}
+
+fn fmt_hms(f: &mut fmt::Formatter, d: Duration) -> fmt::Result {
+    let secs = d.as_secs();
+    write!(
+        f,
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+/// One-line summary, for ex. `[=====>    ]  52.3% 140.2/268.0 MB  51234 pkt/s  ETA 00:01:12`
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const WIDTH: usize = 30;
+        const MB: f64 = 1024.0 * 1024.0;
+        let mb = self.bytes as f64 / MB;
+        match (self.ratio(), self.total_bytes) {
+            (Some(ratio), Some(total)) => {
+                let done = (ratio * WIDTH as f64) as usize;
+                let bar = if done < WIDTH {
+                    format!("{}>{}", "=".repeat(done), " ".repeat(WIDTH - done - 1))
+                } else {
+                    "=".repeat(WIDTH)
+                };
+                write!(
+                    f,
+                    "[{}] {:5.1}% {:.1}/{:.1} MB",
+                    bar,
+                    ratio * 100.0,
+                    mb,
+                    total as f64 / MB
+                )?;
+            }
+            _ => write!(f, "{:.1} MB", mb)?,
+        }
+        write!(f, "  {:.0} pkt/s  ", self.packets_per_sec())?;
+        match self.eta() {
+            Some(eta) if !self.finished => {
+                f.write_str("ETA ")?;
+                fmt_hms(f, eta)
+            }
+            _ => fmt_hms(f, self.elapsed),
+        }
+    }
+}
+
+/// Callback called with the progress of a run
+pub type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+/// Periodic progress reporter, given to an engine
+///
+/// ## example
+///
+/// ```
+/// use libpcap_tools::ProgressReporter;
+/// use std::time::Duration;
+///
+/// let reporter = ProgressReporter::new(Duration::from_secs(1), |progress| {
+///     println!("{} bytes read", progress.bytes);
+/// });
+/// ```
+pub struct ProgressReporter {
+    callback: ProgressCallback,
+    interval: Duration,
+    progress: Progress,
+    start: Instant,
+    last_report: Instant,
+    /// Number of blocks read since the last check of the clock
+    blocks: u64,
+}
+
+impl ProgressReporter {
+    /// Create a reporter, calling `callback` every `interval`
+    pub fn new<F: FnMut(&Progress) + 'static>(interval: Duration, callback: F) -> Self {
+        let now = Instant::now();
+        ProgressReporter {
+            callback: Box::new(callback),
+            interval,
+            progress: Progress::default(),
+            start: now,
+            last_report: now,
+            blocks: 0,
+        }
+    }
+
+    /// Create a reporter drawing a progress bar on the standard error, twice per second
+    pub fn stderr() -> Self {
+        ProgressReporter::new(Duration::from_millis(500), Progress::draw_stderr)
+    }
+
+    /// Return the total size of the input, if known
+    pub fn total_bytes(&self) -> Option<u64> {
+        self.progress.total_bytes
+    }
+
+    /// Set the total size of the input, used to compute the ratio and remaining time
+    pub fn set_total_bytes(&mut self, total_bytes: Option<u64>) {
+        self.progress.total_bytes = total_bytes;
+    }
+
+    /// Reset counters, at the start of a run
+    pub(crate) fn start(&mut self) {
+        let now = Instant::now();
+        self.progress = Progress {
+            total_bytes: self.progress.total_bytes,
+            ..Progress::default()
+        };
+        self.start = now;
+        self.last_report = now;
+        self.blocks = 0;
+    }
+
+    /// Account for a block of `len` bytes, and call the callback if needed
+    pub(crate) fn update(&mut self, len: usize, is_packet: bool) {
+        self.progress.bytes += len as u64;
+        if is_packet {
+            self.progress.packets += 1;
+        }
+        self.blocks += 1;
+        if self.blocks < CHECK_INTERVAL {
+            return;
+        }
+        self.blocks = 0;
+        let now = Instant::now();
+        if now.duration_since(self.last_report) >= self.interval {
+            self.last_report = now;
+            self.progress.elapsed = now.duration_since(self.start);
+            (self.callback)(&self.progress);
+        }
+    }
+
+    /// Call the callback a last time, at the end of a run
+    pub(crate) fn finish(&mut self) {
+        self.progress.elapsed = self.start.elapsed();
+        self.progress.finished = true;
+        (self.callback)(&self.progress);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn progress_reports() {
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let r = reports.clone();
+        let mut reporter = ProgressReporter::new(Duration::default(), move |progress| {
+            r.borrow_mut().push(progress.clone())
+        });
+        reporter.set_total_bytes(Some(1000));
+        reporter.start();
+        for i in 0..CHECK_INTERVAL {
+            reporter.update(2, i % 2 == 0);
+        }
+        reporter.finish();
+        let reports = reports.borrow();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].bytes, 2 * CHECK_INTERVAL);
+        assert_eq!(reports[0].packets, CHECK_INTERVAL / 2);
+        assert!(!reports[0].finished);
+        assert!(reports[1].finished);
+        assert_eq!(reports[1].ratio(), Some(0.512));
+    }
+}
@@ -4,23 +4,29 @@
 extern crate log;
 
 extern crate clap;
+#[cfg(feature = "live")]
+use clap::ArgMatches;
 use clap::{crate_version, App, Arg};
 
-extern crate flate2;
-extern crate lz4;
-extern crate xz2;
-
+use std::cell::RefCell;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use flate2::read::GzDecoder;
-use xz2::read::XzDecoder;
+use serde_json::json;
 
 use libpcap_analyzer::*;
-use libpcap_tools::{Config, PcapDataEngine, PcapEngine};
+use libpcap_tools::{
+    decompress_reader, Checkpoint, CheckpointConfig, Compression, Config, InputMode,
+    PcapDataEngine, PcapEngine, Progress, ProgressReporter,
+};
+#[cfg(feature = "live")]
+use libpcap_tools::{ErrorPolicy, LiveEngine, LiveOptions, PcapAnalyzer};
 
 fn load_config(config: &mut Config, filename: &str) -> Result<(), io::Error> {
     debug!("Loading configuration {}", filename);
@@ -29,8 +35,145 @@ fn load_config(config: &mut Config, filename: &str) -> Result<(), io::Error> {
     config.load_config(file)
 }
 
+/// Expand glob patterns in input file names
+///
+/// Files matching a pattern are sorted by name, so rotated captures (`trace_0001.pcap`, ...)
+/// are read in order. Names without special characters are kept as is.
+fn expand_input_filenames<'a>(names: impl Iterator<Item = &'a str>) -> io::Result<Vec<String>> {
+    let mut filenames = Vec::new();
+    for name in names {
+        if name == "-" || !name.contains(&['*', '?', '['][..]) || Path::new(name).exists() {
+            filenames.push(name.to_owned());
+            continue;
+        }
+        let paths = glob::glob(name).map_err(|e| Error::new(ErrorKind::Other, e))?;
+        let mut matches = paths
+            .map(|p| p.map(|p| p.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        if matches.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No input file matches '{}'", name),
+            ));
+        }
+        matches.sort();
+        filenames.extend(matches);
+    }
+    Ok(filenames)
+}
+
+/// Open input file (or standard input), decompressing it if needed
+fn open_input(input_filename: &str) -> io::Result<Box<dyn Read>> {
+    let input_reader: Box<dyn Read> = if input_filename == "-" {
+        Box::new(io::stdin())
+    } else {
+        let path = Path::new(&input_filename);
+        let file = File::open(path)?;
+        Box::new(file)
+    };
+    decompress_reader(input_reader)
+}
+
+/// Open the input file if it can be mapped in memory: a single, uncompressed, regular file
+fn open_mappable_input(input_filenames: &[String]) -> io::Result<Option<File>> {
+    let input_filename = match input_filenames {
+        [f] if f != "-" => f,
+        _ => return Ok(None),
+    };
+    let mut file = File::open(input_filename)?;
+    if !file.metadata()?.is_file() {
+        return Ok(None);
+    }
+    let compressed = is_compressed(&mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(if compressed { None } else { Some(file) })
+}
+
+/// Return true if the file starts with the magic bytes of a supported compression format
+fn is_compressed(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0; 6];
+    let len = file.read(&mut magic)?;
+    Ok(Compression::of_magic(&magic[..len]) != Compression::None)
+}
+
+/// Return the total size of the input files, if known (regular and uncompressed files)
+fn inputs_size(input_filenames: &[String]) -> Option<u64> {
+    input_filenames
+        .iter()
+        .map(|f| {
+            if f == "-" {
+                return None;
+            }
+            let mut file = File::open(f).ok()?;
+            let metadata = file.metadata().ok()?;
+            match is_compressed(&mut file) {
+                Ok(false) if metadata.is_file() => Some(metadata.len()),
+                _ => None,
+            }
+        })
+        .sum()
+}
+
+/// Print a summary of the performance of the run on the standard output, as JSON
+///
+/// `elapsed` is the total time of the run, including the teardown of the analyzer.
+fn print_bench_report(progress: &Progress, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let rate = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+    let report = json!({
+        "packets": progress.packets,
+        "bytes": progress.bytes,
+        "elapsed_secs": secs,
+        "packets_per_sec": rate(progress.packets),
+        "bytes_per_sec": rate(progress.bytes),
+    });
+    println!("{}", report);
+}
+
+/// Set `stop` to `true` on the first interruption (Ctrl-C), and exit on the second one
+fn set_interrupt_handler(stop: Arc<AtomicBool>) -> io::Result<()> {
+    ctrlc::set_handler(move || {
+        if stop.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, writing results (press Ctrl-C again to exit now)");
+    })
+    .map_err(|e| Error::new(ErrorKind::Other, e))
+}
+
+/// Capture packets from a network interface, until interrupted (Ctrl-C)
+#[cfg(feature = "live")]
+fn run_live<A: PcapAnalyzer>(analyzer: A, interface: &str, options: LiveOptions) -> io::Result<()> {
+    let mut engine = LiveEngine::new(analyzer, interface, options);
+    set_interrupt_handler(engine.stop_handle())?;
+    engine
+        .run()
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))
+}
+
+#[cfg(feature = "live")]
+fn parse_live_options(matches: &ArgMatches) -> io::Result<LiveOptions> {
+    let mut options = LiveOptions {
+        promiscuous: matches.is_present("promisc"),
+        ..LiveOptions::default()
+    };
+    if let Some(snaplen) = matches.value_of("snaplen") {
+        options.snaplen = snaplen
+            .parse::<i32>()
+            .map_err(|_| Error::new(ErrorKind::Other, "Invalid value for 'snaplen' argument"))?;
+    }
+    if let Some(buffer_size) = matches.value_of("buffer-size") {
+        let buffer_size = buffer_size.parse::<i32>().map_err(|_| {
+            Error::new(ErrorKind::Other, "Invalid value for 'buffer-size' argument")
+        })?;
+        options.buffer_size = Some(buffer_size);
+    }
+    Ok(options)
+}
+
 fn main() -> io::Result<()> {
-    let matches = App::new("Pcap analyzer")
+    let app = App::new("Pcap analyzer")
         .version(crate_version!())
         .author("Pierre Chifflier")
         .about("Tool for Pcap file analyzis")
@@ -60,10 +203,11 @@ fn main() -> io::Result<()> {
         )
         .arg(
             Arg::with_name("plugins")
-                .help("Plugins to load (default: all)")
+                .help("Plugins to load, in order (default: all). Names prefixed by '-' are disabled, for ex. 'tls,flows' or '-basicstats'")
                 .short('p')
                 .long("plugins")
-                .takes_value(true),
+                .takes_value(true)
+                .allow_hyphen_values(true),
         )
         .arg(
             Arg::with_name("config")
@@ -79,10 +223,52 @@ fn main() -> io::Result<()> {
                 .long("outdir")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("report")
+                .help("Save the results of all plugins to a report file (JSON, or TOML if the extension is .toml)")
+                .long("report")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("merge")
+                .help("Merge input files in chronological order (default: read them sequentially)")
+                .long("merge"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .help("Show a progress bar on the standard error")
+                .long("progress"),
+        )
+        .arg(
+            Arg::with_name("bench-report")
+                .help("Print the time, packet and byte rates of the run as JSON on the standard output, to measure performance")
+                .long("bench-report"),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .help("Save the analysis state periodically to this file, to resume it if interrupted")
+                .long("checkpoint")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint-interval")
+                .help("Minimum time between two checkpoints, in seconds (default: 300)")
+                .long("checkpoint-interval")
+                .takes_value(true)
+                .requires("checkpoint"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .help("Resume the analysis from this checkpoint file")
+                .long("resume")
+                .takes_value(true)
+                .conflicts_with("merge"),
+        )
         .arg(
             Arg::with_name("INPUT")
-                .help("Input file name")
-                .required(true)
+                .help("Input file names, or glob patterns (for ex. 'trace_*.pcap')")
+                .required(cfg!(not(feature = "live")))
+                .multiple_values(true)
                 .index(1),
         )
         .arg(
@@ -90,8 +276,38 @@ fn main() -> io::Result<()> {
                 .help("Skip given number of packets")
                 .long("skip")
                 .takes_value(true),
+        );
+    #[cfg(feature = "live")]
+    let app = app
+        .arg(
+            Arg::with_name("interface")
+                .help("Capture packets from this network interface, until interrupted")
+                .short('i')
+                .long("interface")
+                .takes_value(true)
+                .conflicts_with("INPUT"),
+        )
+        .arg(
+            Arg::with_name("promisc")
+                .help("Put the capture interface in promiscuous mode")
+                .long("promisc")
+                .requires("interface"),
+        )
+        .arg(
+            Arg::with_name("snaplen")
+                .help("Maximum number of bytes captured from each packet (default: 65535)")
+                .long("snaplen")
+                .takes_value(true)
+                .requires("interface"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("buffer-size")
+                .help("Size of the kernel capture buffer, in bytes")
+                .long("buffer-size")
+                .takes_value(true)
+                .requires("interface"),
+        );
+    let matches = app.get_matches();
 
     // create plugin factory with all available plugins
     let factory = plugins::PluginsFactory::default();
@@ -118,6 +334,9 @@ fn main() -> io::Result<()> {
     if let Some(dir) = matches.value_of("outdir") {
         config.set("output_dir", dir);
     }
+    if let Some(report) = matches.value_of("report") {
+        config.set("report_file", report);
+    }
 
     let skip = matches.value_of("skip").unwrap_or("0");
     let skip = skip.parse::<u32>().map_err(|_| Error::new(
@@ -148,17 +367,12 @@ fn main() -> io::Result<()> {
     // instantiate all plugins
     let registry = if let Some(plugin_names) = matches.value_of("plugins") {
         debug!("Restricting plugins to: {}", plugin_names);
-        let names: Vec<_> = plugin_names.split(',').collect();
-        factory.build_filter_plugins(
-            |n| {
-                debug!("n: {}", n);
-                names.iter().any(|&x| n.contains(x))
-            },
-            &config,
-        ).expect("Could not build factory")
+        let selection = PluginSelection::parse(plugin_names);
+        factory.build_selected_plugins(&selection, &config)
     } else {
-        factory.build_plugins(&config).expect("Could not build factory")
+        factory.build_plugins(&config)
     };
+    let registry = registry.map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
     // check if asked to list plugins
     if matches.is_present("list-plugins") {
         println!("pcap-analyzer instanciated plugins:");
@@ -190,25 +404,34 @@ fn main() -> io::Result<()> {
             debug!("  {}", p.name());
         },
     );
-    let input_filename = matches.value_of("INPUT").unwrap();
 
-    let mut input_reader = if input_filename == "-" {
-        Box::new(io::stdin())
-    } else {
-        let path = Path::new(&input_filename);
-        let file = File::open(path)?;
-        if input_filename.ends_with(".gz") {
-            Box::new(GzDecoder::new(file))
-        } else if input_filename.ends_with(".xz") {
-            Box::new(XzDecoder::new(file))
-        } else if input_filename.ends_with(".lz4") {
-            Box::new(lz4::Decoder::new(file)?)
+    let num_threads = config.get_usize("num_threads").unwrap_or(1);
+    #[cfg(feature = "live")]
+    if let Some(interface) = matches.value_of("interface") {
+        let options = LiveOptions {
+            error_policy: ErrorPolicy::from_config(&config),
+            ..parse_live_options(&matches)?
+        };
+        info!("Capturing on interface {}", interface);
+        return if num_threads == 1 {
+            let analyzer = Analyzer::new(Arc::new(registry), &config);
+            run_live(analyzer, interface, options)
         } else {
-            Box::new(file) as Box<dyn io::Read>
-        }
-    };
+            let analyzer = ThreadedAnalyzer::new(registry, &config);
+            run_live(analyzer, interface, options)
+        };
+    }
 
-    let num_threads = config.get_usize("num_threads").unwrap_or(1);
+    let input_filenames = matches
+        .values_of("INPUT")
+        .ok_or_else(|| Error::new(ErrorKind::Other, "Missing input file name"))?;
+    let input_filenames = expand_input_filenames(input_filenames)?;
+    if input_filenames.len() > 1 && input_filenames.iter().any(|f| f == "-") {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Standard input cannot be used with multiple input files",
+        ));
+    }
     let mut engine = if num_threads == 1 {
         let analyzer = Analyzer::new(Arc::new(registry), &config);
         Box::new(PcapDataEngine::new(analyzer, &config)) as Box<dyn PcapEngine>
@@ -216,7 +439,72 @@ fn main() -> io::Result<()> {
         let analyzer = ThreadedAnalyzer::new(registry, &config);
         Box::new(PcapDataEngine::new(analyzer, &config)) as Box<dyn PcapEngine>
     };
-    engine.run(&mut input_reader).expect("run analyzer");
+    let show_progress = matches.is_present("progress");
+    let bench_progress = if matches.is_present("bench-report") {
+        Some(Rc::new(RefCell::new(Progress::default())))
+    } else {
+        None
+    };
+    if show_progress || bench_progress.is_some() {
+        let last_progress = bench_progress.clone();
+        let mut reporter = ProgressReporter::new(Duration::from_millis(500), move |progress| {
+            if show_progress {
+                progress.draw_stderr();
+            }
+            if let Some(last_progress) = &last_progress {
+                last_progress.borrow_mut().clone_from(progress);
+            }
+        });
+        reporter.set_total_bytes(inputs_size(&input_filenames));
+        engine.set_progress_reporter(reporter);
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    set_interrupt_handler(stop.clone())?;
+    engine.set_stop_handle(stop);
+    if let Some(path) = matches.value_of("checkpoint") {
+        let interval = match matches.value_of("checkpoint-interval") {
+            Some(secs) => secs.parse::<u64>().map_err(|_| {
+                Error::new(
+                    ErrorKind::Other,
+                    "Invalid value for 'checkpoint-interval' argument",
+                )
+            })?,
+            None => 300,
+        };
+        engine.set_checkpoint_config(CheckpointConfig::new(path, Duration::from_secs(interval)));
+    }
+
+    let start = Instant::now();
+    if let Some(path) = matches.value_of("resume") {
+        let checkpoint =
+            Checkpoint::load(path).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        let file = open_mappable_input(&input_filenames)?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::Other,
+                "Resuming requires a single, uncompressed input file",
+            )
+        })?;
+        engine.resume_file(&file, checkpoint).expect("run analyzer");
+    } else if let Some(file) = open_mappable_input(&input_filenames)? {
+        engine.run_file(&file).expect("run analyzer");
+    } else {
+        let mut input_readers = input_filenames
+            .iter()
+            .map(|f| open_input(f))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mode = if matches.is_present("merge") {
+            InputMode::Merge
+        } else {
+            InputMode::Sequential
+        };
+        info!("Reading {} input files ({:?})", input_readers.len(), mode);
+        engine
+            .run_inputs(&mut input_readers, mode)
+            .expect("run analyzer");
+    }
+    if let Some(progress) = bench_progress {
+        print_bench_report(&progress.borrow(), start.elapsed());
+    }
 
     Ok(())
 }
@@ -147,7 +147,7 @@ impl SectionInfo {
     }
 }
 
-fn open_file(name: &str) -> Result<Box<dyn io::Read>, io::Error> {
+pub(crate) fn open_file(name: &str) -> Result<Box<dyn io::Read>, io::Error> {
     let input_reader: Box<dyn io::Read> = if name == "-" {
         Box::new(io::stdin())
     } else {
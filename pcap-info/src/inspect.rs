@@ -0,0 +1,577 @@
+//! Deep inspection of a capture file
+//!
+//! [`inspect_reader`] reads all the blocks of a pcap or pcap-ng file, and reports the inventory
+//! of blocks, the details of each interface, the time range of packets and the gaps between
+//! them, a histogram of packet sizes, truncation statistics and, if the file does not end with
+//! a complete block, the position of the corrupt or truncated trailing data.
+
+use crate::interface::{pcapng_build_interface, InterfaceInfo};
+use pcap_parser::{create_reader, Block, OptionCode, PcapBlockOwned, PcapError};
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Maximum number of gaps listed (all gaps are counted)
+const MAX_GAPS: usize = 100;
+
+/// Lower bounds of the bins of the packet size histogram (original length)
+const SIZE_BINS: &[u32] = &[0, 64, 128, 256, 512, 1024, 1519, 9217];
+
+pub struct InspectOptions {
+    /// Minimum interval between two consecutive packets to report a gap, in nanoseconds
+    pub gap_threshold: u64,
+}
+
+impl Default for InspectOptions {
+    fn default() -> Self {
+        InspectOptions {
+            gap_threshold: NANOS_PER_SEC,
+        }
+    }
+}
+
+/// Packet timestamp, serialized as `seconds.nanoseconds`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    /// Build a timestamp from a number of `unit` (units per second), and an offset in seconds
+    fn new(ts: u64, unit: u64, offset: u64) -> Self {
+        let unit = unit.max(1);
+        let frac = u128::from(ts % unit) * u128::from(NANOS_PER_SEC) / u128::from(unit);
+        Timestamp {
+            secs: (ts / unit) as i64 + offset as i64,
+            nanos: frac as u32,
+        }
+    }
+
+    fn as_nanos(&self) -> i128 {
+        i128::from(self.secs) * i128::from(NANOS_PER_SEC) + i128::from(self.nanos)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}", self.secs, self.nanos)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+/// Number and total size of the blocks of a type
+#[derive(Debug, Default, Serialize)]
+pub struct BlockStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Option of an interface, with its name and value formatted as strings
+#[derive(Debug, Serialize)]
+pub struct InterfaceOption {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InterfaceDetails {
+    /// Index of the section defining the interface
+    pub section: usize,
+    /// Index of the interface in the section
+    pub if_index: usize,
+    pub linktype: String,
+    pub linktype_value: i32,
+    pub snaplen: u32,
+    pub tsresol: u8,
+    pub tsoffset: u64,
+    pub options: Vec<InterfaceOption>,
+    pub packets: u64,
+    /// Captured bytes
+    pub bytes: u64,
+    /// Packets captured with less bytes than their original length
+    pub truncated_packets: u64,
+    pub stats_blocks: u64,
+    pub first_packet: Option<Timestamp>,
+    pub last_packet: Option<Timestamp>,
+}
+
+impl InterfaceDetails {
+    fn new(section: usize, info: &InterfaceInfo) -> Self {
+        InterfaceDetails {
+            section,
+            if_index: info.if_index,
+            linktype: info.link_type.to_string(),
+            linktype_value: info.link_type.0,
+            snaplen: info.snaplen,
+            tsresol: info.if_tsresol,
+            tsoffset: info.if_tsoffset,
+            options: info
+                .options
+                .iter()
+                .filter(|(code, _)| *code != OptionCode::EndOfOpt)
+                .map(|(code, value)| idb_option(*code, value))
+                .collect(),
+            packets: 0,
+            bytes: 0,
+            truncated_packets: 0,
+            stats_blocks: 0,
+            first_packet: None,
+            last_packet: None,
+        }
+    }
+}
+
+/// Interval between two consecutive packets, longer than the gap threshold
+#[derive(Debug, Clone, Serialize)]
+pub struct Gap {
+    /// Index of the packet preceding the gap (starting at 1)
+    pub after_packet: u64,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TimeRange {
+    pub first_packet: Option<Timestamp>,
+    pub last_packet: Option<Timestamp>,
+    pub duration: f64,
+    /// Packets with a timestamp lower than the previous packet
+    pub out_of_order: u64,
+    /// Number of gaps
+    pub gaps: u64,
+    pub largest_gap: Option<Gap>,
+    /// First gaps (at most 100)
+    pub gap_list: Vec<Gap>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SizeBin {
+    pub range: String,
+    pub packets: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Truncation {
+    /// Packets captured with less bytes than their original length
+    pub packets: u64,
+    /// Total number of bytes not captured
+    pub missing_bytes: u64,
+}
+
+/// Data at the end of the input which could not be read as a block
+#[derive(Debug, Serialize)]
+pub struct TrailingData {
+    /// Offset of the data in the (uncompressed) input
+    pub offset: u64,
+    /// Number of bytes, if known (the rest of the input is not read after a corrupt block)
+    pub bytes: Option<usize>,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub file_type: &'static str,
+    pub version: String,
+    pub file_bytes: u64,
+    pub sections: usize,
+    /// Blocks, by type
+    pub blocks: BTreeMap<String, BlockStats>,
+    pub interfaces: Vec<InterfaceDetails>,
+    pub packets: u64,
+    pub captured_bytes: u64,
+    pub original_bytes: u64,
+    pub time: TimeRange,
+    pub size_histogram: Vec<SizeBin>,
+    pub truncation: Truncation,
+    pub trailing_data: Option<TrailingData>,
+}
+
+struct Inspector<'o> {
+    options: &'o InspectOptions,
+    inspection: Inspection,
+    /// Interfaces of the current section
+    section_interfaces: Vec<InterfaceInfo>,
+    /// Index in `inspection.interfaces` of the first interface of the current section
+    section_base: usize,
+    previous_ts: Option<Timestamp>,
+}
+
+/// Inspect a capture read from `input` (uncompressed)
+pub fn inspect_reader(
+    input: Box<dyn io::Read>,
+    options: &InspectOptions,
+) -> io::Result<Inspection> {
+    let mut reader = create_reader(128 * 1024, input)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Could not create reader"))?;
+    let mut inspector = Inspector {
+        options,
+        inspection: Inspection {
+            file_type: "",
+            version: String::new(),
+            file_bytes: 0,
+            sections: 0,
+            blocks: BTreeMap::new(),
+            interfaces: Vec::new(),
+            packets: 0,
+            captured_bytes: 0,
+            original_bytes: 0,
+            time: TimeRange::default(),
+            size_histogram: SIZE_BINS
+                .iter()
+                .enumerate()
+                .map(|(i, low)| SizeBin {
+                    range: match SIZE_BINS.get(i + 1) {
+                        Some(high) => format!("{}-{}", low, high - 1),
+                        None => format!("{}+", low),
+                    },
+                    packets: 0,
+                })
+                .collect(),
+            truncation: Truncation::default(),
+            trailing_data: None,
+        },
+        section_interfaces: Vec::new(),
+        section_base: 0,
+        previous_ts: None,
+    };
+
+    let mut last_incomplete_offset = None;
+    loop {
+        let offset = inspector.inspection.file_bytes;
+        match reader.next() {
+            Ok((sz, block)) => {
+                if offset == 0
+                    && !matches!(
+                        block,
+                        PcapBlockOwned::LegacyHeader(_)
+                            | PcapBlockOwned::NG(Block::SectionHeader(_))
+                    )
+                {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Neither a pcap nor pcap-ng header was found",
+                    ));
+                }
+                inspector.handle_block(&block, sz);
+                reader.consume(sz);
+            }
+            Err(PcapError::Eof) => break,
+            Err(PcapError::Incomplete) => {
+                if last_incomplete_offset == Some(offset) && reader.reader_exhausted() {
+                    inspector.inspection.trailing_data = Some(TrailingData {
+                        offset,
+                        bytes: Some(reader.data().len()),
+                        error: "Truncated block".to_owned(),
+                    });
+                    break;
+                }
+                last_incomplete_offset = Some(offset);
+                reader
+                    .refill()
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Could not refill reader"))?;
+            }
+            Err(e) => {
+                if offset == 0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Neither a pcap nor pcap-ng header was found",
+                    ));
+                }
+                inspector.inspection.trailing_data = Some(TrailingData {
+                    offset,
+                    bytes: None,
+                    error: format!("{:?}", e),
+                });
+                break;
+            }
+        }
+    }
+    Ok(inspector.inspection)
+}
+
+impl Inspector<'_> {
+    fn handle_block(&mut self, block: &PcapBlockOwned, sz: usize) {
+        let stats = self.inspection.blocks.entry(block_name(block)).or_default();
+        stats.count += 1;
+        stats.bytes += sz as u64;
+        self.inspection.file_bytes += sz as u64;
+        match block {
+            PcapBlockOwned::LegacyHeader(hdr) => {
+                self.inspection.file_type = "pcap";
+                self.inspection.version = format!("{}.{}", hdr.version_major, hdr.version_minor);
+                let (if_tsresol, ts_unit) = if hdr.is_nanosecond_precision() {
+                    (9, NANOS_PER_SEC)
+                } else {
+                    (6, 1_000_000)
+                };
+                self.new_section();
+                self.add_interface(InterfaceInfo {
+                    link_type: hdr.network,
+                    if_tsresol,
+                    ts_unit,
+                    snaplen: hdr.snaplen,
+                    ..InterfaceInfo::default()
+                });
+            }
+            PcapBlockOwned::Legacy(b) => {
+                let unit = self
+                    .section_interfaces
+                    .first()
+                    .map_or(1_000_000, |i| i.ts_unit);
+                let ts = u64::from(b.ts_sec) * unit + u64::from(b.ts_usec);
+                let ts = Timestamp::new(ts, unit, 0);
+                self.add_packet(0, Some(ts), b.caplen, b.origlen);
+            }
+            PcapBlockOwned::NG(Block::SectionHeader(shb)) => {
+                self.inspection.file_type = "pcapng";
+                self.inspection.version = format!("{}.{}", shb.major_version, shb.minor_version);
+                self.new_section();
+            }
+            PcapBlockOwned::NG(Block::InterfaceDescription(idb)) => {
+                let info = pcapng_build_interface(idb, self.section_interfaces.len());
+                self.add_interface(info);
+            }
+            PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => {
+                let if_id = epb.if_id as usize;
+                let ts = self.section_interfaces.get(if_id).map(|i| {
+                    let ts = (u64::from(epb.ts_high) << 32) | u64::from(epb.ts_low);
+                    Timestamp::new(ts, i.ts_unit, i.if_tsoffset)
+                });
+                self.add_packet(if_id, ts, epb.caplen, epb.origlen);
+            }
+            PcapBlockOwned::NG(Block::SimplePacket(spb)) => {
+                let snaplen = self.section_interfaces.first().map_or(0, |i| i.snaplen);
+                let caplen = if snaplen > 0 {
+                    spb.origlen.min(snaplen)
+                } else {
+                    spb.origlen.min(spb.data.len() as u32)
+                };
+                self.add_packet(0, None, caplen, spb.origlen);
+            }
+            PcapBlockOwned::NG(Block::InterfaceStatistics(isb)) => {
+                if let Some(interface) = self.interface_mut(isb.if_id as usize) {
+                    interface.stats_blocks += 1;
+                }
+            }
+            PcapBlockOwned::NG(_) => (),
+        }
+    }
+
+    fn new_section(&mut self) {
+        self.inspection.sections += 1;
+        self.section_interfaces.clear();
+        self.section_base = self.inspection.interfaces.len();
+    }
+
+    fn add_interface(&mut self, info: InterfaceInfo) {
+        let section = self.inspection.sections.saturating_sub(1);
+        self.inspection
+            .interfaces
+            .push(InterfaceDetails::new(section, &info));
+        self.section_interfaces.push(info);
+    }
+
+    fn interface_mut(&mut self, if_id: usize) -> Option<&mut InterfaceDetails> {
+        if if_id < self.section_interfaces.len() {
+            self.inspection
+                .interfaces
+                .get_mut(self.section_base + if_id)
+        } else {
+            None
+        }
+    }
+
+    fn add_packet(&mut self, if_id: usize, ts: Option<Timestamp>, caplen: u32, origlen: u32) {
+        let truncated = caplen < origlen;
+        if let Some(interface) = self.interface_mut(if_id) {
+            interface.packets += 1;
+            interface.bytes += u64::from(caplen);
+            if truncated {
+                interface.truncated_packets += 1;
+            }
+            if let Some(ts) = ts {
+                interface.first_packet = Some(interface.first_packet.map_or(ts, |t| t.min(ts)));
+                interface.last_packet = Some(interface.last_packet.map_or(ts, |t| t.max(ts)));
+            }
+        }
+        let inspection = &mut self.inspection;
+        inspection.packets += 1;
+        inspection.captured_bytes += u64::from(caplen);
+        inspection.original_bytes += u64::from(origlen);
+        let bin = SIZE_BINS
+            .iter()
+            .rposition(|low| origlen >= *low)
+            .unwrap_or(0);
+        inspection.size_histogram[bin].packets += 1;
+        if truncated {
+            inspection.truncation.packets += 1;
+            inspection.truncation.missing_bytes += u64::from(origlen - caplen);
+        }
+        if let Some(ts) = ts {
+            self.add_timestamp(ts);
+        }
+    }
+
+    fn add_timestamp(&mut self, ts: Timestamp) {
+        let time = &mut self.inspection.time;
+        time.first_packet = Some(time.first_packet.map_or(ts, |t| t.min(ts)));
+        time.last_packet = Some(time.last_packet.map_or(ts, |t| t.max(ts)));
+        if let (Some(first), Some(last)) = (time.first_packet, time.last_packet) {
+            time.duration = (last.as_nanos() - first.as_nanos()) as f64 / NANOS_PER_SEC as f64;
+        }
+        if let Some(previous) = self.previous_ts {
+            let interval = ts.as_nanos() - previous.as_nanos();
+            if interval < 0 {
+                time.out_of_order += 1;
+            } else if interval >= i128::from(self.options.gap_threshold) {
+                let gap = Gap {
+                    after_packet: self.inspection.packets - 1,
+                    start: previous,
+                    end: ts,
+                    seconds: interval as f64 / NANOS_PER_SEC as f64,
+                };
+                time.gaps += 1;
+                if time
+                    .largest_gap
+                    .as_ref()
+                    .map_or(true, |g| gap.seconds > g.seconds)
+                {
+                    time.largest_gap = Some(gap.clone());
+                }
+                if time.gap_list.len() < MAX_GAPS {
+                    time.gap_list.push(gap);
+                }
+            }
+        }
+        self.previous_ts = Some(ts);
+    }
+}
+
+fn block_name(block: &PcapBlockOwned) -> String {
+    let name = match block {
+        PcapBlockOwned::LegacyHeader(_) => "pcap header",
+        PcapBlockOwned::Legacy(_) => "pcap record",
+        PcapBlockOwned::NG(b) => match b.magic() {
+            0x0A0D_0D0A => "SHB",
+            0x0000_0001 => "IDB",
+            0x0000_0002 => "PB",
+            0x0000_0003 => "SPB",
+            0x0000_0004 => "NRB",
+            0x0000_0005 => "ISB",
+            0x0000_0006 => "EPB",
+            0x0000_0009 => "SJE",
+            0x0000_000A => "DSB",
+            0x0000_0BAD | 0x4000_0BAD => "CB",
+            0x8000_0001 => "PIB",
+            magic => return format!("0x{:08x}", magic),
+        },
+    };
+    name.to_owned()
+}
+
+/// Format an option of an Interface Description Block
+fn idb_option(code: OptionCode, value: &[u8]) -> InterfaceOption {
+    let name = match code.0 {
+        1 => "comment",
+        2 => "if_name",
+        3 => "if_description",
+        4 => "if_IPv4addr",
+        5 => "if_IPv6addr",
+        6 => "if_MACaddr",
+        7 => "if_EUIaddr",
+        8 => "if_speed",
+        9 => "if_tsresol",
+        10 => "if_tzone",
+        11 => "if_filter",
+        12 => "if_os",
+        13 => "if_fcslen",
+        14 => "if_tsoffset",
+        15 => "if_hardware",
+        16 => "if_txspeed",
+        17 => "if_rxspeed",
+        _ => "",
+    };
+    let name = if name.is_empty() {
+        format!("option {}", code.0)
+    } else {
+        name.to_owned()
+    };
+    let value = match (code.0, value.len()) {
+        (1, _) | (2, _) | (3, _) | (12, _) | (15, _) => String::from_utf8_lossy(value).into_owned(),
+        // the first byte is the type of filter
+        (11, n) if n > 0 => String::from_utf8_lossy(&value[1..]).into_owned(),
+        (4, 8) => format!(
+            "{} / {}",
+            Ipv4Addr::new(value[0], value[1], value[2], value[3]),
+            Ipv4Addr::new(value[4], value[5], value[6], value[7])
+        ),
+        (5, 17) => {
+            let ipv6_bytes: [u8; 16] = value[..16].try_into().expect("IPv6 address");
+            format!("{} / {}", Ipv6Addr::from(ipv6_bytes), value[16])
+        }
+        (6, 6) => value
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":"),
+        (8, 8) | (14, 8) | (16, 8) | (17, 8) => {
+            let int_bytes: [u8; 8] = value.try_into().expect("64-bit integer");
+            u64::from_le_bytes(int_bytes).to_string()
+        }
+        (9, 1) | (13, 1) => value[0].to_string(),
+        _ => value.iter().map(|b| format!("{:02x}", b)).collect(),
+    };
+    InterfaceOption { name, value }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn inspect_pcap() {
+        let data = std::fs::read("../assets/nmap_tcp_22_ipv4.pcap").expect("read capture");
+        let options = InspectOptions::default();
+        let inspection =
+            inspect_reader(Box::new(Cursor::new(data.clone())), &options).expect("inspection");
+        assert_eq!(inspection.file_type, "pcap");
+        assert_eq!(inspection.version, "2.4");
+        assert_eq!(inspection.file_bytes, 1512);
+        assert_eq!(inspection.blocks["pcap record"].count, 18);
+        assert_eq!(inspection.interfaces.len(), 1);
+        assert_eq!(inspection.interfaces[0].snaplen, 262_144);
+        assert_eq!(inspection.packets, 18);
+        assert_eq!(inspection.captured_bytes, 1200);
+        assert_eq!(inspection.truncation.packets, 0);
+        let time = &inspection.time;
+        assert_eq!(
+            time.first_packet.map(|t| t.to_string()),
+            Some("1658321070.679827000".to_owned())
+        );
+        assert_eq!(time.gaps, 1);
+        assert_eq!(time.gap_list[0].after_packet, 8);
+        let packets: u64 = inspection.size_histogram.iter().map(|b| b.packets).sum();
+        assert_eq!(packets, 18);
+        assert!(inspection.trailing_data.is_none());
+
+        // truncated last record
+        let inspection = inspect_reader(Box::new(Cursor::new(data[..1500].to_vec())), &options)
+            .expect("inspection");
+        assert_eq!(inspection.packets, 17);
+        let trailing = inspection.trailing_data.expect("trailing data");
+        assert_eq!(trailing.offset + trailing.bytes.unwrap_or(0) as u64, 1500);
+    }
+}
@@ -1,12 +1,23 @@
 use std::io;
 
 mod info;
+mod inspect;
 mod interface;
 
 pub use info::{FileType, Options, PcapInfo, SectionInfo};
+pub use inspect::{
+    inspect_reader, BlockStats, Gap, InspectOptions, Inspection, InterfaceDetails, InterfaceOption,
+    SizeBin, TimeRange, Timestamp, TrailingData, Truncation,
+};
 pub use interface::InterfaceInfo;
 
 /// Display information about the input file (which must be pcap or pcap-ng)
 pub fn pcap_info(name: &str, options: &info::Options) -> Result<(i32, PcapInfo), io::Error> {
     info::process_file(name, options)
 }
+
+/// Inspect all the blocks of the input file (which must be pcap or pcap-ng, and can be compressed)
+pub fn pcap_inspect(name: &str, options: &InspectOptions) -> Result<Inspection, io::Error> {
+    let input = info::open_file(name)?;
+    inspect::inspect_reader(input, options)
+}
@@ -3,7 +3,7 @@
 use pcap_info::*;
 
 extern crate clap;
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, ArgMatches};
 use pcap_parser::OptionCode;
 use time::UtcOffset;
 
@@ -19,6 +19,7 @@ fn main() -> Result<(), io::Error> {
         .version(crate_version!())
         .author("Pierre Chifflier")
         .about("Display information about pcap files")
+        .subcommand_negates_reqs(true)
         .arg(
             Arg::with_name("no-check")
                 .help("Do not check file")
@@ -31,8 +32,33 @@ fn main() -> Result<(), io::Error> {
                 .required(true)
                 .index(1),
         )
+        .subcommand(
+            App::new("inspect")
+                .about("Inspect all blocks: interfaces, time gaps, packet sizes, truncation")
+                .arg(
+                    Arg::with_name("json")
+                        .help("Display results as JSON")
+                        .long("json"),
+                )
+                .arg(
+                    Arg::with_name("gap")
+                        .help("Minimum interval between packets to report a gap, in seconds (default: 1)")
+                        .long("gap")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Input file name")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("inspect") {
+        return inspect(matches);
+    }
+
     let input_filename = matches.value_of("INPUT").unwrap();
     let options = Options {
         check_file: !matches.is_present("no-check"),
@@ -44,6 +70,120 @@ fn main() -> Result<(), io::Error> {
     process::exit(rc);
 }
 
+fn inspect(matches: &ArgMatches) -> Result<(), io::Error> {
+    let input_filename = matches.value_of("INPUT").unwrap();
+    let mut options = InspectOptions::default();
+    if let Some(gap) = matches.value_of("gap") {
+        match gap.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 => options.gap_threshold = (secs * 1e9) as u64,
+            _ => {
+                eprintln!("Invalid gap value '{}'", gap);
+                process::exit(1);
+            }
+        }
+    }
+
+    let inspection = pcap_inspect(input_filename, &options)?;
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&inspection)?);
+    } else {
+        display_inspection(input_filename, &inspection);
+    }
+
+    process::exit(i32::from(inspection.trailing_data.is_some()));
+}
+
+fn display_inspection(name: &str, inspection: &Inspection) {
+    println!("File name: {}", name);
+    println!("Type: {} {}", inspection.file_type, inspection.version);
+    println!("{:<20}: {} bytes", "File size", inspection.file_bytes);
+    println!("{:<20}: {}", "Number of sections", inspection.sections);
+    println!("Blocks:");
+    for (name, stats) in &inspection.blocks {
+        println!("  {:<18}: {} ({} bytes)", name, stats.count, stats.bytes);
+    }
+
+    for interface in &inspection.interfaces {
+        println!(
+            "Interface #{} (section {}):",
+            interface.if_index, interface.section
+        );
+        println!(
+            "    Encapsulation: {} ({})",
+            interface.linktype, interface.linktype_value
+        );
+        println!("    Capture length: {}", interface.snaplen);
+        println!("    Time resolution: {}", interface.tsresol);
+        println!("    Time offset: {}", interface.tsoffset);
+        for option in &interface.options {
+            println!("    {}: {}", option.name, option.value);
+        }
+        println!("    Number of packets: {}", interface.packets);
+        println!("    Captured bytes: {}", interface.bytes);
+        println!("    Truncated packets: {}", interface.truncated_packets);
+        println!("    Number of stat entries: {}", interface.stats_blocks);
+        if let (Some(first), Some(last)) = (interface.first_packet, interface.last_packet) {
+            println!("    Packet times: {} - {}", first, last);
+        }
+    }
+
+    println!("{:<20}: {}", "Number of packets", inspection.packets);
+    println!(
+        "{:<20}: {} bytes",
+        "Captured size", inspection.captured_bytes
+    );
+    println!(
+        "{:<20}: {} bytes",
+        "Original size", inspection.original_bytes
+    );
+
+    let time = &inspection.time;
+    if let (Some(first), Some(last)) = (time.first_packet, time.last_packet) {
+        println!("{:<20}: {}", "First packet time", first);
+        println!("{:<20}: {}", "Last packet time", last);
+        println!("{:<20}: {:.6} seconds", "Capture duration", time.duration);
+    }
+    println!("{:<20}: {}", "Out of order packets", time.out_of_order);
+    println!("{:<20}: {}", "Number of gaps", time.gaps);
+    for gap in &time.gap_list {
+        println!(
+            "    after packet {}: {} - {} ({:.6} seconds)",
+            gap.after_packet, gap.start, gap.end, gap.seconds
+        );
+    }
+    if time.gaps as usize > time.gap_list.len() {
+        println!("    ...");
+    }
+    if let Some(gap) = &time.largest_gap {
+        println!(
+            "{:<20}: {:.6} seconds (after packet {})",
+            "Largest gap", gap.seconds, gap.after_packet
+        );
+    }
+
+    println!("Packet sizes:");
+    for bin in &inspection.size_histogram {
+        println!("  {:<18}: {}", bin.range, bin.packets);
+    }
+    println!(
+        "{:<20}: {} ({} bytes not captured)",
+        "Truncated packets", inspection.truncation.packets, inspection.truncation.missing_bytes
+    );
+
+    if let Some(trailing) = &inspection.trailing_data {
+        match trailing.bytes {
+            Some(bytes) => println!(
+                "*** {} at offset {} ({} bytes) ***",
+                trailing.error, trailing.offset, bytes
+            ),
+            None => println!(
+                "*** Corrupt block at offset {}: {} ***",
+                trailing.offset, trailing.error
+            ),
+        }
+    }
+}
+
 fn display_pcap_info(name: &str, info: &PcapInfo) {
     println!("File name: {}", name);
 
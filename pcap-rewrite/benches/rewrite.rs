@@ -0,0 +1,136 @@
+//! Dispatch filters, and the full rewriter path, on generated captures
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use libpcap_tools::{Config, PcapDataEngine, PcapEngine};
+use pcap_parser::data::PacketData;
+use pcap_rewrite::filters::dispatch_filter::DispatchFilterBuilder;
+use pcap_rewrite::filters::filter::Filter;
+use pcap_rewrite::filters::filtering_action::FilteringAction;
+use pcap_rewrite::filters::filtering_key::FilteringKey;
+use pcap_rewrite::rewriter::{FileFormat, Rewriter};
+use std::io;
+
+/// Number of flows of generated captures. Keys match the flows with an even index.
+const FLOWS: u32 = 1000;
+/// Size of the UDP payload of generated packets
+const PAYLOAD_LEN: usize = 64;
+
+/// Client address and port of a flow
+fn client(flow: u32) -> ([u8; 4], u16) {
+    ([10, 1, (flow >> 8) as u8, flow as u8], 1024 + flow as u16)
+}
+
+/// Build an Ethernet/IPv4/UDP frame, from the client of `flow` to the server
+fn udp_frame(flow: u32) -> Vec<u8> {
+    let (src, sport) = client(flow);
+    let mut v = Vec::with_capacity(14 + 20 + 8 + PAYLOAD_LEN);
+    // Ethernet
+    v.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    v.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    v.extend_from_slice(&0x0800u16.to_be_bytes());
+    // IPv4 (checksum is not verified by filters)
+    let mut ip = [0u8; 20];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&((20 + 8 + PAYLOAD_LEN) as u16).to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 17;
+    ip[12..16].copy_from_slice(&src);
+    ip[16..20].copy_from_slice(&[10, 2, 0, 1]);
+    v.extend_from_slice(&ip);
+    // UDP (no checksum)
+    v.extend_from_slice(&sport.to_be_bytes());
+    v.extend_from_slice(&53u16.to_be_bytes());
+    v.extend_from_slice(&((8 + PAYLOAD_LEN) as u16).to_be_bytes());
+    v.extend_from_slice(&[0, 0]);
+    v.extend((0..PAYLOAD_LEN).map(|i| i as u8));
+    v
+}
+
+/// Build a legacy pcap file (Ethernet link type), with `packets` packets spread over all flows
+fn generate_pcap(packets: u32) -> Vec<u8> {
+    let mut v = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+    v.extend_from_slice(&[0; 8]);
+    v.extend_from_slice(&65535u32.to_le_bytes());
+    v.extend_from_slice(&1u32.to_le_bytes());
+    for n in 0..packets {
+        let frame = udp_frame(n % FLOWS);
+        // pcap record header: timestamp (one packet per millisecond), caplen, origlen
+        v.extend_from_slice(&(1_600_000_000 + n / 1000).to_le_bytes());
+        v.extend_from_slice(&((n % 1000) * 1000).to_le_bytes());
+        v.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        v.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        v.extend_from_slice(&frame);
+    }
+    v
+}
+
+/// Build a dispatch filter keeping the flows with an even index
+fn dispatch_filter(filtering_key: FilteringKey) -> Box<dyn Filter> {
+    let keys = (0..FLOWS)
+        .step_by(2)
+        .map(|flow| {
+            let (src, sport) = client(flow);
+            let src = format!("{}.{}.{}.{}", src[0], src[1], src[2], src[3]);
+            match filtering_key {
+                FilteringKey::SrcDstIpaddrProtoSrcDstPort => {
+                    format!("{},10.2.0.1,17,{},53\n", src, sport)
+                }
+                _ => format!("{}\n", src),
+            }
+        })
+        .collect::<String>();
+    DispatchFilterBuilder::from_reader(
+        filtering_key,
+        FilteringAction::Keep,
+        keys.as_bytes(),
+        false,
+        None,
+    )
+    .expect("build dispatch filter")
+}
+
+fn bench_dispatch_filter(c: &mut Criterion) {
+    let frames = (0..FLOWS).map(udp_frame).collect::<Vec<_>>();
+    let mut group = c.benchmark_group("dispatch_filter");
+    group.throughput(Throughput::Elements(frames.len() as u64));
+    for (name, filtering_key) in [
+        ("src_ipaddr", FilteringKey::SrcIpaddr),
+        ("five_tuple", FilteringKey::SrcDstIpaddrProtoSrcDstPort),
+    ] {
+        let filter = dispatch_filter(filtering_key);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for frame in &frames {
+                    let _ = black_box(filter.filter(PacketData::L2(frame)));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_rewriter(c: &mut Criterion) {
+    let data = generate_pcap(10_000);
+    let config = Config::default();
+    let mut group = c.benchmark_group("rewriter");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("dispatch_src_ipaddr", |b| {
+        b.iter_batched(
+            || {
+                let filters = vec![dispatch_filter(FilteringKey::SrcIpaddr)];
+                Rewriter::new(Box::new(io::sink()), FileFormat::Pcap, filters)
+            },
+            |rewriter| {
+                let mut engine = PcapDataEngine::new(rewriter, &config);
+                let mut reader = &data[..];
+                engine.run(&mut reader).expect("run rewriter");
+                engine.data_analyzer_mut().finish().expect("finish output");
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_dispatch_filter, bench_rewriter);
+criterion_main!(benches);
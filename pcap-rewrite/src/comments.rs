@@ -0,0 +1,100 @@
+//! Comments added to output packets
+//!
+//! Comments are read from a CSV file without header, with one comment per line: the first field
+//! is a packet index (starting at 1, like frame numbers in Wireshark) or a flow
+//! (`PROTO SRC DST`, see [`parse_flow`]), and the second field is the comment. Lines starting
+//! with `#` are ignored. A flow comment is added to all packets of the flow, in both directions.
+//!
+//! Comments can only be written in the pcapng format (`opt_comment` option of packets).
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use csv::{ReaderBuilder, Trim};
+use libpcap_tools::{FiveTuple, Packet};
+
+use crate::filters::dispatch_filter::parse_flow;
+use crate::flow_output;
+
+#[derive(Clone, Debug, Default)]
+pub struct PacketComments {
+    /// Comments of packets, indexed by the packet index in the input
+    by_index: HashMap<usize, Vec<String>>,
+    /// Comments of flows, indexed by the five-tuple and its reverse
+    by_flow: HashMap<FiveTuple, Vec<String>>,
+}
+
+impl PacketComments {
+    pub fn of_file_path(path: &Path) -> Result<PacketComments, Box<dyn Error>> {
+        let file = File::open(path)?;
+        PacketComments::of_reader(file)
+    }
+
+    pub fn of_reader<R: Read>(reader: R) -> Result<PacketComments, Box<dyn Error>> {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .trim(Trim::All)
+            .from_reader(reader);
+        let mut comments = PacketComments::default();
+        for l in rdr.records() {
+            let record = l?;
+            let key = record
+                .get(0)
+                .ok_or_else(|| "Missing packet index or flow in comments file".to_string())?;
+            let comment = record
+                .get(1)
+                .ok_or_else(|| format!("Missing comment for {} in comments file", key))?;
+            match key.parse::<usize>() {
+                Ok(index) if index > 0 => comments.add_packet_comment(index, comment),
+                Ok(_) => return Err("Invalid packet index 0 in comments file".into()),
+                Err(_) => comments.add_flow_comment(&parse_flow(key)?, comment),
+            }
+        }
+        Ok(comments)
+    }
+
+    /// Add a comment to the packet at `index` in the input (starting at 1)
+    pub fn add_packet_comment(&mut self, index: usize, comment: &str) {
+        self.by_index
+            .entry(index)
+            .or_default()
+            .push(comment.to_owned());
+    }
+
+    /// Add a comment to all packets of a flow, in both directions
+    pub fn add_flow_comment(&mut self, five_tuple: &FiveTuple, comment: &str) {
+        let reverse = five_tuple.get_reverse();
+        if reverse != *five_tuple {
+            self.by_flow
+                .entry(reverse)
+                .or_default()
+                .push(comment.to_owned());
+        }
+        self.by_flow
+            .entry(five_tuple.clone())
+            .or_default()
+            .push(comment.to_owned());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_index.is_empty() && self.by_flow.is_empty()
+    }
+
+    /// Return the comments of a packet: comments of its index first, then of its flow
+    ///
+    /// The flow is read from the outer headers (tunnels are not decapsulated). Fragments other
+    /// than the first one have no L4 header, so they do not match the flow of the datagram.
+    pub fn get<'a>(&'a self, packet: &Packet) -> impl Iterator<Item = &'a String> {
+        let by_index = self.by_index.get(&packet.pcap_index);
+        let by_flow = if self.by_flow.is_empty() {
+            None
+        } else {
+            flow_output::five_tuple(&packet.data).and_then(|t| self.by_flow.get(&t))
+        };
+        by_index.into_iter().chain(by_flow).flatten()
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::{self, BufWriter, Write};
+
+use flate2::write::GzEncoder;
+
+/// Output stream of a writer
+///
+/// Streams are completed explicitly with `finish`, so errors (for ex. when writing the end of
+/// compressed data) are reported instead of being ignored when the stream is dropped.
+pub trait OutputStream: Write {
+    /// Write buffered data, and complete the stream. Nothing must be written after this call.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl<W: Write> OutputStream for BufWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl OutputStream for io::Sink {
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compression of output files
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputCompression {
+    #[default]
+    None,
+    /// gzip, with compression level (0-9)
+    Gzip(u32),
+    /// zstd, with compression level (1-22)
+    Zstd(i32),
+}
+
+impl OutputCompression {
+    /// Parse compression from a string `name[:level]`, where `name` is `gzip` or `zstd`
+    pub fn of_string(s: &str) -> Result<OutputCompression, String> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        match name {
+            "gzip" => {
+                let level = match level {
+                    Some(level) => level
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|level| *level <= 9)
+                        .ok_or_else(|| format!("Invalid gzip compression level: {}", level))?,
+                    None => 6,
+                };
+                Ok(OutputCompression::Gzip(level))
+            }
+            "zstd" => {
+                let level = match level {
+                    Some(level) => level
+                        .parse::<i32>()
+                        .ok()
+                        .filter(|level| (1..=22).contains(level))
+                        .ok_or_else(|| format!("Invalid zstd compression level: {}", level))?,
+                    None => zstd::DEFAULT_COMPRESSION_LEVEL,
+                };
+                Ok(OutputCompression::Zstd(level))
+            }
+            _ => Err(format!("Invalid compression: {} not among gzip|zstd", name)),
+        }
+    }
+
+    /// Wrap `w` in an encoder
+    ///
+    /// Compressed data is completed by [`OutputStream::finish`], which also completes `w`.
+    pub fn compress_writer(self, w: Box<dyn OutputStream>) -> io::Result<Box<dyn OutputStream>> {
+        let w: Box<dyn OutputStream> = match self {
+            OutputCompression::None => w,
+            OutputCompression::Gzip(level) => {
+                Box::new(GzEncoder::new(w, flate2::Compression::new(level)))
+            }
+            OutputCompression::Zstd(level) => Box::new(zstd::Encoder::new(w, level)?),
+        };
+        Ok(w)
+    }
+}
+
+impl OutputStream for GzEncoder<Box<dyn OutputStream>> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().finish()
+    }
+}
+
+impl OutputStream for zstd::Encoder<'static, Box<dyn OutputStream>> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.do_finish()?;
+        self.get_mut().finish()
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashSet;
 use std::error::Error;
 use std::fs::File;
+use std::io::Read;
 use std::iter::FromIterator;
 use std::net::IpAddr;
 use std::path::Path;
@@ -23,8 +24,11 @@ impl FiveTupleC {
 
     pub fn of_file_path(path: &Path) -> Result<FiveTupleC, Box<dyn Error>> {
         let file = File::open(path)?;
+        FiveTupleC::of_reader(file)
+    }
 
-        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(file);
+    pub fn of_reader<R: Read>(reader: R) -> Result<FiveTupleC, Box<dyn Error>> {
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(reader);
         let five_tuple_v = rdr
             .records()
             .map(|l| {
@@ -69,6 +73,11 @@ impl FiveTupleC {
             })
             .collect::<Result<Vec<FiveTuple>, Box<dyn Error>>>()?;
 
+        Ok(FiveTupleC::of_five_tuples(&five_tuple_v))
+    }
+
+    /// Build a container matching both directions of each five-tuple
+    pub fn of_five_tuples(five_tuple_v: &[FiveTuple]) -> FiveTupleC {
         let hs0 = HashSet::from_iter(five_tuple_v.iter().cloned());
 
         let five_tuple_v_reversed = five_tuple_v
@@ -77,7 +86,7 @@ impl FiveTupleC {
             .collect::<Vec<_>>();
         let hs1 = HashSet::from_iter(five_tuple_v_reversed.iter().cloned());
 
-        Ok(FiveTupleC::new(hs0, hs1))
+        FiveTupleC::new(hs0, hs1)
     }
 
     // pub fn is_empty(&self) -> bool {
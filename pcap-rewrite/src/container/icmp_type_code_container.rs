@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::iter::FromIterator;
+use std::path::Path;
+
+use csv::ReaderBuilder;
+
+/// Set of ICMP or ICMPv6 types, with optional codes
+///
+/// Each record of the key file is a type (any code matches), or a type and a code, for ex.
+/// `8` or `3,1`. ICMP and ICMPv6 use different type numbers: the same key file is used for both.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IcmpTypeCodeC {
+    s: HashSet<(u8, Option<u8>)>,
+}
+
+fn parse_u8(s: &str, name: &str) -> Result<u8, String> {
+    s.trim()
+        .parse()
+        .map_err(|e| format!("Error parsing ICMP {} '{}': {}", name, s, e))
+}
+
+impl IcmpTypeCodeC {
+    pub fn new(s: HashSet<(u8, Option<u8>)>) -> IcmpTypeCodeC {
+        IcmpTypeCodeC { s }
+    }
+
+    pub fn of_file_path(path: &Path) -> Result<IcmpTypeCodeC, Box<dyn Error>> {
+        let file = File::open(path)?;
+        IcmpTypeCodeC::of_reader(file)
+    }
+
+    pub fn of_reader<R: Read>(reader: R) -> Result<IcmpTypeCodeC, Box<dyn Error>> {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(reader);
+        let type_code_v = rdr
+            .records()
+            .map(|l| {
+                let record = l?;
+                let icmp_type = record
+                    .get(0)
+                    .ok_or_else(|| "Empty line in dispatch filter key file".to_string())?;
+                let icmp_type = parse_u8(icmp_type, "type")?;
+                let icmp_code = match record.get(1) {
+                    Some(s) => Some(parse_u8(s, "code")?),
+                    None => None,
+                };
+                Ok((icmp_type, icmp_code))
+            })
+            .collect::<Result<Vec<(u8, Option<u8>)>, Box<dyn Error>>>()?;
+
+        Ok(IcmpTypeCodeC::new(HashSet::from_iter(type_code_v)))
+    }
+
+    pub fn contains(&self, icmp_type: u8, icmp_code: u8) -> bool {
+        self.s.contains(&(icmp_type, None)) || self.s.contains(&(icmp_type, Some(icmp_code)))
+    }
+}
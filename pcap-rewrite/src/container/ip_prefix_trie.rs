@@ -0,0 +1,96 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Node {
+    children: [Option<Box<Node>>; 2],
+    /// A prefix ends at this node
+    terminal: bool,
+}
+
+/// Binary trie of IPv4 and IPv6 prefixes, supporting longest-prefix-match lookups
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct IpPrefixTrie {
+    v4: Node,
+    v6: Node,
+}
+
+/// Return the address as a big-endian integer, and its width in bits
+fn addr_bits(ipaddr: &IpAddr) -> (u128, u8) {
+    match ipaddr {
+        IpAddr::V4(a) => (u128::from(u32::from(*a)), 32),
+        IpAddr::V6(a) => (u128::from(*a), 128),
+    }
+}
+
+fn bit_at(bits: u128, width: u8, index: u8) -> usize {
+    ((bits >> (width - 1 - index)) & 1) as usize
+}
+
+/// Parse a prefix (`10.0.0.0/8`, `2001:db8::/32`) or a single address
+pub fn parse_prefix(s: &str) -> Result<(IpAddr, u8), String> {
+    let (addr_s, prefix_len_s) = match s.find('/') {
+        Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+        None => (s, None),
+    };
+    let ipaddr = IpAddr::from_str(addr_s).map_err(|e| e.to_string())?;
+    let max_len = addr_bits(&ipaddr).1;
+    let prefix_len = match prefix_len_s {
+        Some(l) => l
+            .parse::<u8>()
+            .map_err(|e| format!("Invalid prefix length in '{}': {}", s, e))?,
+        None => max_len,
+    };
+    if prefix_len > max_len {
+        return Err(format!("Invalid prefix length in '{}'", s));
+    }
+    Ok((ipaddr, prefix_len))
+}
+
+impl IpPrefixTrie {
+    pub fn new() -> IpPrefixTrie {
+        IpPrefixTrie::default()
+    }
+
+    fn root(&self, ipaddr: &IpAddr) -> &Node {
+        match ipaddr {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        }
+    }
+
+    /// Insert a prefix. `prefix_len` is capped to the address width.
+    pub fn insert(&mut self, ipaddr: &IpAddr, prefix_len: u8) {
+        let (bits, width) = addr_bits(ipaddr);
+        let mut node = match ipaddr {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        };
+        for index in 0..prefix_len.min(width) {
+            let b = bit_at(bits, width, index);
+            node = node.children[b].get_or_insert_with(Box::default);
+        }
+        node.terminal = true;
+    }
+
+    /// Return the length of the longest prefix containing `ipaddr`, if any
+    pub fn longest_prefix_match(&self, ipaddr: &IpAddr) -> Option<u8> {
+        let (bits, width) = addr_bits(ipaddr);
+        let mut node = self.root(ipaddr);
+        let mut best = if node.terminal { Some(0) } else { None };
+        for index in 0..width {
+            match &node.children[bit_at(bits, width, index)] {
+                Some(child) => node = child,
+                None => break,
+            }
+            if node.terminal {
+                best = Some(index + 1);
+            }
+        }
+        best
+    }
+
+    pub fn contains(&self, ipaddr: &IpAddr) -> bool {
+        self.longest_prefix_match(ipaddr).is_some()
+    }
+}
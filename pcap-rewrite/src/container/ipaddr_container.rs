@@ -1,27 +1,47 @@
 use std::collections::HashSet;
 use std::error::Error;
 use std::fs::File;
-use std::iter::FromIterator;
+use std::io::Read;
 use std::net::IpAddr;
 use std::path::Path;
-use std::str::FromStr;
 
 use csv::ReaderBuilder;
 
+use crate::container::ip_prefix_trie::{parse_prefix, IpPrefixTrie};
+
+/// Set of IP addresses and prefixes
+///
+/// Lookups use longest-prefix-match, so a key file can contain single addresses
+/// (`192.168.1.1`, `2001:db8::1`) as well as subnets (`10.0.0.0/8`, `2001:db8::/32`).
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct IpAddrC {
-    s: HashSet<IpAddr>,
+    trie: IpPrefixTrie,
 }
 
 impl IpAddrC {
     pub fn new(s: HashSet<IpAddr>) -> IpAddrC {
-        IpAddrC { s }
+        let mut trie = IpPrefixTrie::new();
+        for ipaddr in &s {
+            trie.insert(ipaddr, 128);
+        }
+        IpAddrC { trie }
+    }
+
+    pub fn of_prefixes(prefixes: &[(IpAddr, u8)]) -> IpAddrC {
+        let mut trie = IpPrefixTrie::new();
+        for (ipaddr, prefix_len) in prefixes {
+            trie.insert(ipaddr, *prefix_len);
+        }
+        IpAddrC { trie }
     }
 
     pub fn of_file_path(ip_file_path: &Path) -> Result<IpAddrC, Box<dyn Error>> {
         let file = File::open(ip_file_path)?;
+        IpAddrC::of_reader(file)
+    }
 
-        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(file);
+    pub fn of_reader<R: Read>(reader: R) -> Result<IpAddrC, Box<dyn Error>> {
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(reader);
         let s_v = rdr
             .records()
             .map(|l| {
@@ -33,24 +53,15 @@ impl IpAddrC {
             })
             .collect::<Result<Vec<String>, Box<dyn Error>>>()?;
 
-        let ip_v = s_v
+        let prefix_v = s_v
             .iter()
-            .map(|s| IpAddr::from_str(s).map_err(|e| e.to_string()))
-            .collect::<Result<Vec<IpAddr>, String>>()?;
-        let ip_hs = HashSet::from_iter(ip_v.iter().cloned());
+            .map(|s| parse_prefix(s))
+            .collect::<Result<Vec<(IpAddr, u8)>, String>>()?;
 
-        Ok(IpAddrC::new(ip_hs))
+        Ok(IpAddrC::of_prefixes(&prefix_v))
     }
 
-    // pub fn is_empty(&self) -> bool {
-    //     self.s.is_empty()
-    // }
-
-    // pub fn len(&self) -> usize {
-    //     self.s.len()
-    // }
-
     pub fn contains(&self, ipaddr: &IpAddr) -> bool {
-        self.s.contains(ipaddr)
+        self.trie.contains(ipaddr)
     }
 }
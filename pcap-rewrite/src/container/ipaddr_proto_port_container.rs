@@ -1,6 +1,7 @@
 use std::collections::HashSet;
 use std::error::Error;
 use std::fs::File;
+use std::io::Read;
 use std::iter::FromIterator;
 use std::net::IpAddr;
 use std::path::Path;
@@ -20,8 +21,11 @@ impl IpAddrProtoPortC {
 
     pub fn of_file_path(ip_file_path: &Path) -> Result<IpAddrProtoPortC, Box<dyn Error>> {
         let file = File::open(ip_file_path)?;
+        IpAddrProtoPortC::of_reader(file)
+    }
 
-        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(file);
+    pub fn of_reader<R: Read>(reader: R) -> Result<IpAddrProtoPortC, Box<dyn Error>> {
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(reader);
         let ipaddr_proto_port_v = rdr
             .records()
             .map(|l| {
@@ -1,4 +1,9 @@
 pub mod five_tuple_container;
+pub mod icmp_type_code_container;
+pub mod ip_prefix_trie;
 pub mod ipaddr_container;
 pub mod ipaddr_proto_port_container;
+pub mod payload_pattern_container;
+pub mod port_range_container;
 pub mod two_tuple_proto_ipid_container;
+pub mod vlan_id_container;
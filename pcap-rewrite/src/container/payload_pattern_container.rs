@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use regex::bytes::RegexSet;
+
+/// Set of patterns matched against packet payloads
+///
+/// Each line of the key file is a pattern: either `hex:` followed by hexadecimal bytes (for
+/// ex. `hex:deadbeef`), or a regular expression (for ex. `Host: example\.com`). Empty lines
+/// are ignored.
+#[derive(Clone, Debug)]
+pub struct PayloadPatternC {
+    s: RegexSet,
+}
+
+/// Convert hexadecimal bytes to a regular expression matching these bytes
+fn hex_to_regex(s: &str) -> Result<String, String> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if s.is_empty() || s.len() % 2 != 0 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hexadecimal pattern '{}'", s));
+    }
+    let mut re = String::from("(?-u)");
+    for i in (0..s.len()).step_by(2) {
+        re.push_str("\\x");
+        re.push_str(&s[i..i + 2]);
+    }
+    Ok(re)
+}
+
+impl PayloadPatternC {
+    pub fn new(s: RegexSet) -> PayloadPatternC {
+        PayloadPatternC { s }
+    }
+
+    pub fn of_file_path(path: &Path) -> Result<PayloadPatternC, Box<dyn Error>> {
+        let file = File::open(path)?;
+        PayloadPatternC::of_reader(file)
+    }
+
+    pub fn of_reader<R: Read>(reader: R) -> Result<PayloadPatternC, Box<dyn Error>> {
+        let patterns = BufReader::new(reader)
+            .lines()
+            .filter(|l| l.as_ref().map_or(true, |l| !l.trim().is_empty()))
+            .map(|l| {
+                let line = l?;
+                match line.strip_prefix("hex:") {
+                    Some(hex) => Ok(hex_to_regex(hex)?),
+                    None => Ok(line),
+                }
+            })
+            .collect::<Result<Vec<String>, Box<dyn Error>>>()?;
+        let set =
+            RegexSet::new(&patterns).map_err(|e| format!("Invalid payload pattern: {}", e))?;
+
+        Ok(PayloadPatternC::new(set))
+    }
+
+    pub fn is_match(&self, payload: &[u8]) -> bool {
+        self.s.is_match(payload)
+    }
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use csv::ReaderBuilder;
+
+/// Set of port ranges
+///
+/// Ranges are kept sorted and merged, so lookups are a binary search.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PortRangeC {
+    ranges: Vec<(u16, u16)>,
+}
+
+fn parse_port_range(s: &str) -> Result<(u16, u16), String> {
+    let s = s.trim();
+    let (low_s, high_s) = match s.find('-') {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None => (s, s),
+    };
+    let low = low_s
+        .trim()
+        .parse::<u16>()
+        .map_err(|e| format!("Error parsing port range '{}': {}", s, e))?;
+    let high = high_s
+        .trim()
+        .parse::<u16>()
+        .map_err(|e| format!("Error parsing port range '{}': {}", s, e))?;
+    if low > high {
+        return Err(format!("Invalid port range '{}': {} > {}", s, low, high));
+    }
+    Ok((low, high))
+}
+
+impl PortRangeC {
+    pub fn new(mut ranges: Vec<(u16, u16)>) -> PortRangeC {
+        ranges.sort_unstable();
+        let mut merged: Vec<(u16, u16)> = Vec::with_capacity(ranges.len());
+        for (low, high) in ranges {
+            match merged.last_mut() {
+                Some(last) if u32::from(low) <= u32::from(last.1) + 1 => {
+                    last.1 = last.1.max(high);
+                }
+                _ => merged.push((low, high)),
+            }
+        }
+        PortRangeC { ranges: merged }
+    }
+
+    /// Read port ranges from a file
+    ///
+    /// Each field is either a port (`443`) or an inclusive range (`1024-2048`), and a line
+    /// can contain multiple fields, for ex. `1024-2048,443,8000-8100`.
+    pub fn of_file_path(path: &Path) -> Result<PortRangeC, Box<dyn Error>> {
+        let file = File::open(path)?;
+        PortRangeC::of_reader(file)
+    }
+
+    pub fn of_reader<R: Read>(reader: R) -> Result<PortRangeC, Box<dyn Error>> {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(reader);
+        let mut ranges = Vec::new();
+        for l in rdr.records() {
+            let record = l?;
+            for field in record.iter().filter(|f| !f.trim().is_empty()) {
+                ranges.push(parse_port_range(field)?);
+            }
+        }
+
+        Ok(PortRangeC::new(ranges))
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        // index of the first range whose upper bound is >= port
+        let idx = self.ranges.partition_point(|&(_, high)| high < port);
+        match self.ranges.get(idx) {
+            Some(&(low, _)) => low <= port,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::iter::FromIterator;
+use std::path::Path;
+
+use csv::ReaderBuilder;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VlanIdC {
+    s: HashSet<u16>,
+}
+
+impl VlanIdC {
+    pub fn new(s: HashSet<u16>) -> VlanIdC {
+        VlanIdC { s }
+    }
+
+    pub fn of_file_path(path: &Path) -> Result<VlanIdC, Box<dyn Error>> {
+        let file = File::open(path)?;
+        VlanIdC::of_reader(file)
+    }
+
+    pub fn of_reader<R: Read>(reader: R) -> Result<VlanIdC, Box<dyn Error>> {
+        let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(reader);
+        let vlan_id_v = rdr
+            .records()
+            .map(|l| {
+                let record = l?;
+                let s: &str = record
+                    .get(0)
+                    .ok_or_else(|| "Empty line in dispatch filter key file".to_string())?;
+                let vlan_id: u16 = s
+                    .trim()
+                    .parse()
+                    .map_err(|e| format!("Error parsing VLAN id '{}': {}", s, e))?;
+                if vlan_id > 4095 {
+                    return Err(format!("Invalid VLAN id {} (must be < 4096)", vlan_id).into());
+                }
+                Ok(vlan_id)
+            })
+            .collect::<Result<Vec<u16>, Box<dyn Error>>>()?;
+
+        Ok(VlanIdC::new(HashSet::from_iter(vlan_id_v)))
+    }
+
+    pub fn contains(&self, vlan_id: u16) -> bool {
+        self.s.contains(&vlan_id)
+    }
+}
@@ -0,0 +1,489 @@
+//! Conversion between the pcap and pcapng formats
+//!
+//! Unlike a rewrite, packets are copied without modification (the link layer is not converted),
+//! with their original timestamps.
+//!
+//! - to pcapng: section and interface blocks are copied with their options (name, description,
+//!   time resolution and offset, etc.), as well as the other blocks (name resolution, statistics,
+//!   etc.). A legacy pcap input gets an interface with its link type, snaplen and time resolution
+//! - to pcap: the file has a single link type. Packets with another link type are an error, or are
+//!   written to other files (see [`ConvertOptions::split_linktypes`]). Timestamps are written in
+//!   microseconds or nanoseconds, and blocks and options which cannot be represented are dropped
+//!   and counted
+
+use crate::compression::OutputStream;
+use crate::rewriter::FileFormat;
+use libpcap_tools::pcapng_build_interface;
+use log::{debug, warn};
+use pcap_parser::pcapng::*;
+use pcap_parser::{
+    create_reader, Block, LegacyPcapBlock, Linktype, PcapBlockOwned, PcapError, PcapHeader,
+    PcapReaderIterator, ToVec,
+};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MICROS_PER_SEC: u64 = 1_000_000;
+/// Snaplen of pcap output, if the input interface has no limit
+const DEFAULT_SNAPLEN: u32 = 262_144;
+
+/// Options of the conversion
+#[derive(Clone, Copy, Debug)]
+pub struct ConvertOptions {
+    pub output_format: FileFormat,
+    /// Write packets with a link type different from the first packet to other files, instead of
+    /// failing (pcap output only)
+    pub split_linktypes: bool,
+    /// Timestamp resolution of pcap output (nanoseconds if `true`). By default, the resolution of
+    /// the interface of the first packet is kept if possible
+    pub nanosecond: Option<bool>,
+}
+
+/// Result of a conversion
+#[derive(Debug, Default)]
+pub struct ConvertReport {
+    /// Number of packets written
+    pub packets: u64,
+    /// Link type and number of packets of each output file, in creation order (pcap only)
+    pub outputs: Vec<(Linktype, u64)>,
+    /// Number of packets with a timestamp rounded to the output resolution (pcap only)
+    pub rounded_timestamps: u64,
+    /// Number of blocks which cannot be represented in pcap (name resolution, statistics, etc.)
+    pub dropped_blocks: u64,
+    /// Number of packets with options (comments, flags, etc.) which were dropped (pcap only)
+    pub dropped_packet_options: u64,
+}
+
+impl ConvertReport {
+    pub fn to_json(&self) -> Value {
+        let outputs: Vec<_> = self
+            .outputs
+            .iter()
+            .map(|(linktype, packets)| json!({"linktype": linktype.0, "packets": packets}))
+            .collect();
+        json!({
+            "packets": self.packets,
+            "outputs": outputs,
+            "rounded_timestamps": self.rounded_timestamps,
+            "dropped_blocks": self.dropped_blocks,
+            "dropped_packet_options": self.dropped_packet_options,
+        })
+    }
+}
+
+impl fmt::Display for ConvertReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Packets written: {}", self.packets)?;
+        for (linktype, packets) in &self.outputs {
+            writeln!(f, "    link type {}: {} packets", linktype.0, packets)?;
+        }
+        writeln!(f, "Rounded timestamps: {}", self.rounded_timestamps)?;
+        writeln!(f, "Dropped blocks: {}", self.dropped_blocks)?;
+        writeln!(f, "Dropped packet options: {}", self.dropped_packet_options)?;
+        Ok(())
+    }
+}
+
+/// Function opening the output file for packets of another link type
+pub type LinktypeOutputFn<'a> = &'a mut dyn FnMut(Linktype) -> io::Result<Box<dyn OutputStream>>;
+
+/// Interface of the current input section
+#[derive(Clone, Copy)]
+struct Interface {
+    link_type: Linktype,
+    snaplen: u32,
+    ts_unit: u64,
+    if_tsoffset: u64,
+}
+
+/// Timestamp of an input packet, in the unit of its interface
+#[derive(Clone, Copy, Default)]
+struct RawTimestamp {
+    value: u64,
+    ts_unit: u64,
+    if_tsoffset: u64,
+}
+
+impl RawTimestamp {
+    /// Return the seconds and the fraction of second in `unit`, and true if precision was lost
+    fn convert(&self, unit: u64) -> (u64, u64, bool) {
+        if self.ts_unit == 0 {
+            return (0, 0, false);
+        }
+        let secs = self.if_tsoffset.saturating_add(self.value / self.ts_unit);
+        let frac = u128::from(self.value % self.ts_unit) * u128::from(unit);
+        let ts_unit = u128::from(self.ts_unit);
+        (secs, (frac / ts_unit) as u64, frac % ts_unit != 0)
+    }
+}
+
+/// Legacy pcap output file
+struct PcapOutput {
+    w: Box<dyn OutputStream>,
+    link_type: Linktype,
+    nanosecond: bool,
+    /// Index in the report outputs
+    index: usize,
+}
+
+struct Converter<'a> {
+    options: ConvertOptions,
+    /// Main output, until it is used
+    w: Option<Box<dyn OutputStream>>,
+    open_output: LinktypeOutputFn<'a>,
+    /// Interfaces of the current input section (or the pcap header)
+    interfaces: Vec<Interface>,
+    pcap_outputs: Vec<PcapOutput>,
+    /// Timestamp of the last packet, used for packets without timestamp (SPB)
+    last_ts: RawTimestamp,
+    report: ConvertReport,
+}
+
+fn reader_error(e: PcapError<&[u8]>) -> io::Error {
+    Error::new(ErrorKind::Other, format!("{:?}", e.to_owned_vec()))
+}
+
+fn serialization_error<E>(_: E) -> io::Error {
+    Error::new(ErrorKind::Other, "Block serialization failed")
+}
+
+impl Converter<'_> {
+    fn interface(&self, if_id: u32) -> Result<Interface, io::Error> {
+        self.interfaces
+            .get(if_id as usize)
+            .copied()
+            .ok_or_else(|| Error::new(ErrorKind::Other, "Packet references an unknown interface"))
+    }
+
+    fn main_output(&mut self) -> &mut dyn OutputStream {
+        self.w.as_deref_mut().expect("main output already used")
+    }
+
+    fn handle_block(&mut self, block: &PcapBlockOwned) -> Result<(), io::Error> {
+        match self.options.output_format {
+            FileFormat::Pcap => self.handle_block_pcap(block),
+            FileFormat::PcapNG => self.handle_block_pcapng(block),
+        }
+    }
+
+    fn handle_block_pcapng(&mut self, block: &PcapBlockOwned) -> Result<(), io::Error> {
+        let v = match block {
+            PcapBlockOwned::LegacyHeader(hdr) => {
+                let (ts_unit, if_tsresol) = if hdr.is_nanosecond_precision() {
+                    (NANOS_PER_SEC, 9)
+                } else {
+                    (MICROS_PER_SEC, 6)
+                };
+                self.interfaces = vec![Interface {
+                    link_type: hdr.network,
+                    snaplen: hdr.snaplen,
+                    ts_unit,
+                    if_tsoffset: 0,
+                }];
+                let shb = SectionHeaderBlock {
+                    block_type: SHB_MAGIC,
+                    block_len1: 28, // no options
+                    bom: BOM_MAGIC,
+                    major_version: 1,
+                    minor_version: 0,
+                    section_len: -1,
+                    options: Vec::new(),
+                    block_len2: 28,
+                };
+                let mut idb = InterfaceDescriptionBlock {
+                    block_type: IDB_MAGIC,
+                    block_len1: 20,
+                    linktype: hdr.network,
+                    reserved: 0,
+                    snaplen: hdr.snaplen,
+                    options: Vec::new(),
+                    block_len2: 20,
+                    if_tsresol,
+                    if_tsoffset: 0,
+                };
+                let mut v = shb.to_vec_raw().map_err(serialization_error)?;
+                // to_vec will add options automatically
+                v.extend(idb.to_vec().map_err(serialization_error)?);
+                v
+            }
+            PcapBlockOwned::Legacy(b) => {
+                let iface = self.interface(0)?;
+                let ts = u64::from(b.ts_sec) * iface.ts_unit + u64::from(b.ts_usec);
+                let caplen = (b.caplen as usize).min(b.data.len());
+                let mut epb = EnhancedPacketBlock {
+                    block_type: EPB_MAGIC,
+                    block_len1: 32,
+                    if_id: 0,
+                    ts_high: (ts >> 32) as u32,
+                    ts_low: (ts & 0xffff_ffff) as u32,
+                    caplen: caplen as u32,
+                    origlen: b.origlen,
+                    data: &b.data[..caplen],
+                    options: Vec::new(),
+                    block_len2: 32,
+                };
+                self.report.packets += 1;
+                // to_vec will adjust length
+                epb.to_vec().map_err(serialization_error)?
+            }
+            // pcapng blocks are copied, with their options
+            PcapBlockOwned::NG(b) => {
+                if matches!(b, Block::EnhancedPacket(_) | Block::SimplePacket(_)) {
+                    self.report.packets += 1;
+                }
+                b.to_vec_raw().map_err(serialization_error)?
+            }
+        };
+        self.main_output().write_all(&v)
+    }
+
+    fn handle_block_pcap(&mut self, block: &PcapBlockOwned) -> Result<(), io::Error> {
+        let (if_id, ts, caplen, origlen, data) = match block {
+            PcapBlockOwned::LegacyHeader(hdr) => {
+                let ts_unit = if hdr.is_nanosecond_precision() {
+                    NANOS_PER_SEC
+                } else {
+                    MICROS_PER_SEC
+                };
+                self.interfaces = vec![Interface {
+                    link_type: hdr.network,
+                    snaplen: hdr.snaplen,
+                    ts_unit,
+                    if_tsoffset: 0,
+                }];
+                return Ok(());
+            }
+            PcapBlockOwned::Legacy(b) => {
+                let iface = self.interface(0)?;
+                let ts = RawTimestamp {
+                    value: u64::from(b.ts_sec) * iface.ts_unit + u64::from(b.ts_usec),
+                    ts_unit: iface.ts_unit,
+                    if_tsoffset: 0,
+                };
+                (0, ts, b.caplen, b.origlen, b.data)
+            }
+            PcapBlockOwned::NG(Block::SectionHeader(_)) => {
+                self.interfaces.clear();
+                return Ok(());
+            }
+            PcapBlockOwned::NG(Block::InterfaceDescription(idb)) => {
+                let if_info = pcapng_build_interface(idb);
+                self.interfaces.push(Interface {
+                    link_type: if_info.link_type,
+                    snaplen: if_info.snaplen,
+                    ts_unit: if_info.ts_unit,
+                    if_tsoffset: if_info.if_tsoffset,
+                });
+                return Ok(());
+            }
+            PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => {
+                let iface = self.interface(epb.if_id)?;
+                if !epb.options.is_empty() {
+                    self.report.dropped_packet_options += 1;
+                }
+                let ts = RawTimestamp {
+                    value: (u64::from(epb.ts_high) << 32) | u64::from(epb.ts_low),
+                    ts_unit: iface.ts_unit,
+                    if_tsoffset: iface.if_tsoffset,
+                };
+                (epb.if_id, ts, epb.caplen, epb.origlen, epb.data)
+            }
+            PcapBlockOwned::NG(Block::SimplePacket(spb)) => {
+                (0, self.last_ts, spb.origlen, spb.origlen, spb.data)
+            }
+            PcapBlockOwned::NG(b) => {
+                debug!(
+                    "convert: dropping pcapng block with magic {:08x}",
+                    b.magic()
+                );
+                self.report.dropped_blocks += 1;
+                return Ok(());
+            }
+        };
+        let iface = self.interface(if_id)?;
+        let data = &data[..(caplen as usize).min(data.len())];
+        self.last_ts = ts;
+        self.write_pcap_packet(&iface, ts, origlen, data)
+    }
+
+    /// Return the index of the pcap output for `iface`, creating it if needed
+    fn pcap_output(&mut self, iface: &Interface) -> Result<usize, io::Error> {
+        if let Some(index) = self
+            .pcap_outputs
+            .iter()
+            .position(|o| o.link_type == iface.link_type)
+        {
+            return Ok(index);
+        }
+        let w = match self.w.take() {
+            Some(w) => w,
+            None if self.options.split_linktypes => (self.open_output)(iface.link_type)?,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "Input has several link types, use pcapng output format or split output by link type",
+                ))
+            }
+        };
+        let nanosecond = self
+            .options
+            .nanosecond
+            .unwrap_or(iface.ts_unit > MICROS_PER_SEC);
+        let mut output = PcapOutput {
+            w,
+            link_type: iface.link_type,
+            nanosecond,
+            index: self.report.outputs.len(),
+        };
+        write_pcap_header(&mut output, iface.snaplen)?;
+        self.report.outputs.push((iface.link_type, 0));
+        self.pcap_outputs.push(output);
+        Ok(self.pcap_outputs.len() - 1)
+    }
+
+    fn write_pcap_packet(
+        &mut self,
+        iface: &Interface,
+        ts: RawTimestamp,
+        origlen: u32,
+        data: &[u8],
+    ) -> Result<(), io::Error> {
+        let index = self.pcap_output(iface)?;
+        let output = &mut self.pcap_outputs[index];
+        let unit = if output.nanosecond {
+            NANOS_PER_SEC
+        } else {
+            MICROS_PER_SEC
+        };
+        let (secs, frac, rounded) = ts.convert(unit);
+        if secs > u64::from(u32::MAX) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Packet timestamp cannot be represented in pcap format",
+            ));
+        }
+        if rounded {
+            self.report.rounded_timestamps += 1;
+        }
+        let record = LegacyPcapBlock {
+            ts_sec: secs as u32,
+            ts_usec: frac as u32,
+            caplen: data.len() as u32,
+            origlen,
+            data,
+        };
+        let v = record.to_vec_raw().map_err(serialization_error)?;
+        output.w.write_all(&v)?;
+        self.report.outputs[output.index].1 += 1;
+        self.report.packets += 1;
+        Ok(())
+    }
+}
+
+fn write_pcap_header(output: &mut PcapOutput, snaplen: u32) -> Result<(), io::Error> {
+    let mut hdr = PcapHeader::new();
+    if output.nanosecond {
+        hdr.magic_number = 0xa1b2_3c4d;
+    }
+    hdr.snaplen = if snaplen == 0 {
+        DEFAULT_SNAPLEN
+    } else {
+        snaplen
+    };
+    hdr.network = output.link_type;
+    let v = hdr.to_vec().map_err(serialization_error)?;
+    output.w.write_all(&v)
+}
+
+/// Convert `input` to `options.output_format`, writing to `w`
+///
+/// If packets with other link types are written to other files (pcap output), these files are
+/// opened with `open_output`.
+pub fn convert(
+    input: Box<dyn Read>,
+    w: Box<dyn OutputStream>,
+    options: &ConvertOptions,
+    open_output: LinktypeOutputFn,
+) -> Result<ConvertReport, io::Error> {
+    let mut reader = create_reader(65536, input).map_err(reader_error)?;
+    let mut converter = Converter {
+        options: *options,
+        w: Some(w),
+        open_output,
+        interfaces: Vec::new(),
+        pcap_outputs: Vec::new(),
+        last_ts: RawTimestamp::default(),
+        report: ConvertReport::default(),
+    };
+    let mut incomplete = false;
+    loop {
+        match reader.next() {
+            Ok((offset, block)) => {
+                incomplete = false;
+                let res = converter.handle_block(&block);
+                reader.consume(offset);
+                res?;
+            }
+            Err(PcapError::Eof) => break,
+            Err(PcapError::Incomplete) => {
+                if incomplete && reader.reader_exhausted() {
+                    warn!("Could not read complete data block, input file may be truncated");
+                    break;
+                }
+                incomplete = true;
+                reader.refill().map_err(reader_error)?;
+            }
+            Err(e) => return Err(reader_error(e)),
+        }
+    }
+    // no packet: write the pcap header anyway
+    if matches!(options.output_format, FileFormat::Pcap) && converter.pcap_outputs.is_empty() {
+        let iface = converter.interfaces.first().copied().unwrap_or(Interface {
+            link_type: Linktype::RAW,
+            snaplen: 0,
+            ts_unit: MICROS_PER_SEC,
+            if_tsoffset: 0,
+        });
+        converter.pcap_output(&iface)?;
+    }
+    if let Some(w) = converter.w.as_mut() {
+        w.finish()?;
+    }
+    for output in &mut converter.pcap_outputs {
+        output.w.finish()?;
+    }
+    Ok(converter.report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawTimestamp;
+
+    #[test]
+    fn convert_timestamp() {
+        // 1.5 s in milliseconds, with an offset of 10 s
+        let ts = RawTimestamp {
+            value: 1500,
+            ts_unit: 1000,
+            if_tsoffset: 10,
+        };
+        assert_eq!(ts.convert(1_000_000), (11, 500_000, false));
+        // nanoseconds to microseconds
+        let ts = RawTimestamp {
+            value: 1_000_000_123,
+            ts_unit: 1_000_000_000,
+            if_tsoffset: 0,
+        };
+        assert_eq!(ts.convert(1_000_000), (1, 0, true));
+        assert_eq!(ts.convert(1_000_000_000), (1, 123, false));
+        // binary resolution (2^-10)
+        let ts = RawTimestamp {
+            value: 1024 + 512,
+            ts_unit: 1024,
+            if_tsoffset: 0,
+        };
+        assert_eq!(ts.convert(1_000_000), (1, 500_000, false));
+    }
+}
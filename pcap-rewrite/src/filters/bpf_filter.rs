@@ -0,0 +1,477 @@
+//! Filter packets using a BPF expression (tcpdump syntax)
+//!
+//! The expression is compiled once into an expression tree, which is then evaluated
+//! on the decoded headers of every packet.
+//!
+//! Supported primitives:
+//!   - `host ADDR`, `src host ADDR`, `dst host ADDR` (`host` can be omitted after `src` or `dst`)
+//!   - `net ADDR/LEN`, `src net ADDR/LEN`, `dst net ADDR/LEN`
+//!   - `port N`, `portrange N-M`, with optional `src`/`dst` and `tcp`/`udp`/`sctp` qualifiers
+//!   - `ip`, `ip6`, `arp`, `tcp`, `udp`, `sctp`, `icmp`, `icmp6`
+//!   - `proto N`, `ip proto N`, `ip6 proto N`
+//!   - `vlan`, `vlan ID`
+//!   - `less N`, `greater N`
+//!
+//! Primitives can be combined using `and` (`&&`), `or` (`||`), `not` (`!`) and parentheses.
+//!
+//! Examples:
+//!   `--bpf 'tcp and dst port 80'`
+//!   `--bpf 'src net 10.0.0.0/8 and not (port 22 or icmp)'`
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use pcap_parser::data::PacketData;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv6::Ipv6Packet;
+
+use crate::filters::filter::{FResult, Filter, Verdict};
+use crate::filters::ipv6_utils;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const ETHERTYPE_QINQ_LEGACY: u16 = 0x9100;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMPV6: u8 = 58;
+const IPPROTO_SCTP: u8 = 132;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dir {
+    Src,
+    Dst,
+    Any,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Host(Dir, IpAddr),
+    Net(Dir, IpAddr, u8),
+    Port(Dir, u16, u16),
+    EtherProto(u16),
+    /// IP protocol, optionally restricted to an ethertype
+    IpProto(Option<u16>, u8),
+    Vlan(Option<u16>),
+    Less(usize),
+    Greater(usize),
+}
+
+/// Header fields extracted from a packet, used to evaluate the expression
+#[derive(Debug, Default)]
+struct PacketFields {
+    len: usize,
+    ethertype: Option<u16>,
+    vlans: Vec<u16>,
+    src: Option<IpAddr>,
+    dst: Option<IpAddr>,
+    proto: Option<u8>,
+    src_port: Option<u16>,
+    dst_port: Option<u16>,
+}
+
+impl PacketFields {
+    fn from_packet_data(packet_data: &PacketData) -> Self {
+        let mut fields = PacketFields::default();
+        match *packet_data {
+            PacketData::L2(data) => {
+                fields.len = data.len();
+                fields.decode_l2(data);
+            }
+            PacketData::L3(ethertype, data) => {
+                fields.len = data.len();
+                fields.decode_l3(ethertype, data);
+            }
+            PacketData::L4(_, data) | PacketData::Unsupported(data) => {
+                fields.len = data.len();
+            }
+        }
+        fields
+    }
+
+    fn decode_l2(&mut self, data: &[u8]) {
+        if data.len() < 14 {
+            return;
+        }
+        let mut ethertype = u16::from_be_bytes([data[12], data[13]]);
+        let mut payload = &data[14..];
+        // 802.1Q and 802.1ad (QinQ) tags
+        while ethertype == ETHERTYPE_VLAN
+            || ethertype == ETHERTYPE_QINQ
+            || ethertype == ETHERTYPE_QINQ_LEGACY
+        {
+            if payload.len() < 4 {
+                return;
+            }
+            self.vlans
+                .push(u16::from_be_bytes([payload[0], payload[1]]) & 0x0fff);
+            ethertype = u16::from_be_bytes([payload[2], payload[3]]);
+            payload = &payload[4..];
+        }
+        self.decode_l3(ethertype, payload);
+    }
+
+    fn decode_l3(&mut self, ethertype: u16, data: &[u8]) {
+        self.ethertype = Some(ethertype);
+        match ethertype {
+            ETHERTYPE_IPV4 => {
+                if let Some(ipv4) = Ipv4Packet::new(data) {
+                    self.src = Some(IpAddr::V4(ipv4.get_source()));
+                    self.dst = Some(IpAddr::V4(ipv4.get_destination()));
+                    let proto = ipv4.get_next_level_protocol().0;
+                    self.proto = Some(proto);
+                    // only the first fragment carries the L4 header
+                    let start = ipv4.get_header_length() as usize * 4;
+                    if ipv4.get_fragment_offset() == 0 && start <= data.len() {
+                        self.decode_ports(proto, &data[start..]);
+                    }
+                }
+            }
+            ETHERTYPE_IPV6 => {
+                if let Some(ipv6) = Ipv6Packet::new(data) {
+                    self.src = Some(IpAddr::V6(ipv6.get_source()));
+                    self.dst = Some(IpAddr::V6(ipv6.get_destination()));
+                    match ipv6_utils::get_fragment_packet_option_l4_protol4_payload(data, &ipv6) {
+                        Ok((fragment_packet_option, l4_proto, payload)) => {
+                            self.proto = Some(l4_proto.0);
+                            let is_first_fragment = fragment_packet_option
+                                .map(|f| f.offset == 0)
+                                .unwrap_or(true);
+                            if is_first_fragment {
+                                self.decode_ports(l4_proto.0, payload);
+                            }
+                        }
+                        Err(_) => self.proto = Some(ipv6.get_next_header().0),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn decode_ports(&mut self, proto: u8, l4_data: &[u8]) {
+        match proto {
+            IPPROTO_TCP | IPPROTO_UDP | IPPROTO_SCTP if l4_data.len() >= 4 => {
+                self.src_port = Some(u16::from_be_bytes([l4_data[0], l4_data[1]]));
+                self.dst_port = Some(u16::from_be_bytes([l4_data[2], l4_data[3]]));
+            }
+            _ => (),
+        }
+    }
+}
+
+fn match_dir<T: Copy, F: Fn(T) -> bool>(dir: Dir, src: Option<T>, dst: Option<T>, f: F) -> bool {
+    let src_match = matches!(src, Some(v) if f(v));
+    let dst_match = matches!(dst, Some(v) if f(v));
+    match dir {
+        Dir::Src => src_match,
+        Dir::Dst => dst_match,
+        Dir::Any => src_match || dst_match,
+    }
+}
+
+fn prefix_match(net: &IpAddr, prefix_len: u8, addr: &IpAddr) -> bool {
+    match (net, addr) {
+        (IpAddr::V4(net), IpAddr::V4(addr)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u32::from(*net) & mask == u32::from(*addr) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(addr)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u128::from(*net) & mask == u128::from(*addr) & mask
+        }
+        _ => false,
+    }
+}
+
+impl Expr {
+    fn eval(&self, fields: &PacketFields) -> bool {
+        match self {
+            Expr::And(lhs, rhs) => lhs.eval(fields) && rhs.eval(fields),
+            Expr::Or(lhs, rhs) => lhs.eval(fields) || rhs.eval(fields),
+            Expr::Not(e) => !e.eval(fields),
+            Expr::Host(dir, ip) => match_dir(*dir, fields.src, fields.dst, |a| a == *ip),
+            Expr::Net(dir, net, prefix_len) => match_dir(*dir, fields.src, fields.dst, |a| {
+                prefix_match(net, *prefix_len, &a)
+            }),
+            Expr::Port(dir, low, high) => match_dir(*dir, fields.src_port, fields.dst_port, |p| {
+                *low <= p && p <= *high
+            }),
+            Expr::EtherProto(ethertype) => fields.ethertype == Some(*ethertype),
+            Expr::IpProto(ethertype, proto) => {
+                fields.proto == Some(*proto)
+                    && ethertype.map_or(true, |e| fields.ethertype == Some(e))
+            }
+            Expr::Vlan(None) => !fields.vlans.is_empty(),
+            Expr::Vlan(Some(id)) => fields.vlans.contains(id),
+            Expr::Less(n) => fields.len <= *n,
+            Expr::Greater(n) => fields.len >= *n,
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<&str>, String> {
+    let bytes = s.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            c if c.is_ascii_whitespace() => i += 1,
+            b'(' | b')' => {
+                tokens.push(&s[i..i + 1]);
+                i += 1;
+            }
+            b'!' => {
+                tokens.push("not");
+                i += 1;
+            }
+            c @ b'&' | c @ b'|' => {
+                if i + 1 < bytes.len() && bytes[i + 1] == c {
+                    tokens.push(&s[i..i + 2]);
+                    i += 2;
+                } else {
+                    return Err(format!(
+                        "Invalid operator '{}' in BPF expression",
+                        c as char
+                    ));
+                }
+            }
+            _ => {
+                let start = i;
+                while i < bytes.len()
+                    && !bytes[i].is_ascii_whitespace()
+                    && !b"()!&|".contains(&bytes[i])
+                {
+                    i += 1;
+                }
+                tokens.push(&s[start..i]);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_value<T: FromStr>(s: &str, what: &str) -> Result<T, String> {
+    s.parse()
+        .map_err(|_| format!("Invalid {} '{}' in BPF expression", what, s))
+}
+
+fn parse_net(s: &str) -> Result<(IpAddr, u8), String> {
+    let (addr_s, prefix_len_s) = match s.find('/') {
+        Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+        None => (s, None),
+    };
+    let addr: IpAddr = parse_value(addr_s, "network address")?;
+    let max_len = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len_s {
+        Some(l) => parse_value(l, "prefix length")?,
+        None => max_len,
+    };
+    if prefix_len > max_len {
+        return Err(format!("Invalid prefix length in network '{}'", s));
+    }
+    Ok((addr, prefix_len))
+}
+
+fn parse_port_range(s: &str) -> Result<(u16, u16), String> {
+    let (low_s, high_s) = match s.find('-') {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None => (s, s),
+    };
+    let low = parse_value(low_s, "port")?;
+    let high = parse_value(high_s, "port")?;
+    if low > high {
+        return Err(format!("Invalid port range '{}'", s));
+    }
+    Ok((low, high))
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next_token(&mut self) -> Result<&'a str, String> {
+        let tok = self
+            .peek()
+            .ok_or_else(|| "Unexpected end of BPF expression".to_string())?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_and()?;
+        while matches!(self.peek(), Some("or") | Some("||")) {
+            self.pos += 1;
+            let rhs = self.parse_and()?;
+            lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_not()?;
+        while matches!(self.peek(), Some("and") | Some("&&")) {
+            self.pos += 1;
+            let rhs = self.parse_not()?;
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        match self.next_token()? {
+            "not" => Ok(Expr::Not(Box::new(self.parse_not()?))),
+            "(" => {
+                let e = self.parse_or()?;
+                match self.next_token()? {
+                    ")" => Ok(e),
+                    t => Err(format!("Expected ')' in BPF expression, found '{}'", t)),
+                }
+            }
+            t => self.parse_primitive(t),
+        }
+    }
+
+    fn parse_primitive(&mut self, tok: &str) -> Result<Expr, String> {
+        match tok {
+            "src" => self.parse_directed(Dir::Src),
+            "dst" => self.parse_directed(Dir::Dst),
+            "host" | "net" | "port" | "portrange" => {
+                self.pos -= 1;
+                self.parse_directed(Dir::Any)
+            }
+            "ip" => self.parse_l3_proto(ETHERTYPE_IPV4),
+            "ip6" => self.parse_l3_proto(ETHERTYPE_IPV6),
+            "arp" => Ok(Expr::EtherProto(ETHERTYPE_ARP)),
+            "tcp" => self.parse_l4_proto(IPPROTO_TCP),
+            "udp" => self.parse_l4_proto(IPPROTO_UDP),
+            "sctp" => self.parse_l4_proto(IPPROTO_SCTP),
+            "icmp" => Ok(Expr::IpProto(Some(ETHERTYPE_IPV4), IPPROTO_ICMP)),
+            "icmp6" => Ok(Expr::IpProto(Some(ETHERTYPE_IPV6), IPPROTO_ICMPV6)),
+            "proto" => Ok(Expr::IpProto(
+                None,
+                parse_value(self.next_token()?, "protocol")?,
+            )),
+            "vlan" => match self.peek().map(str::parse::<u16>) {
+                Some(Ok(id)) => {
+                    self.pos += 1;
+                    Ok(Expr::Vlan(Some(id)))
+                }
+                _ => Ok(Expr::Vlan(None)),
+            },
+            "less" => Ok(Expr::Less(parse_value(self.next_token()?, "length")?)),
+            "greater" => Ok(Expr::Greater(parse_value(self.next_token()?, "length")?)),
+            _ => Err(format!("Unknown primitive '{}' in BPF expression", tok)),
+        }
+    }
+
+    /// Parse `[host|net|port|portrange] VALUE` (host is the default)
+    fn parse_directed(&mut self, dir: Dir) -> Result<Expr, String> {
+        let kind = match self.peek() {
+            Some(k @ "host") | Some(k @ "net") | Some(k @ "port") | Some(k @ "portrange") => {
+                self.pos += 1;
+                k
+            }
+            _ => "host",
+        };
+        let value = self.next_token()?;
+        match kind {
+            "net" => {
+                let (net, prefix_len) = parse_net(value)?;
+                Ok(Expr::Net(dir, net, prefix_len))
+            }
+            "port" => {
+                let port = parse_value(value, "port")?;
+                Ok(Expr::Port(dir, port, port))
+            }
+            "portrange" => {
+                let (low, high) = parse_port_range(value)?;
+                Ok(Expr::Port(dir, low, high))
+            }
+            _ => Ok(Expr::Host(dir, parse_value(value, "host address")?)),
+        }
+    }
+
+    fn parse_l3_proto(&mut self, ethertype: u16) -> Result<Expr, String> {
+        if self.peek() == Some("proto") {
+            self.pos += 1;
+            let proto = parse_value(self.next_token()?, "protocol")?;
+            Ok(Expr::IpProto(Some(ethertype), proto))
+        } else {
+            Ok(Expr::EtherProto(ethertype))
+        }
+    }
+
+    /// Parse a L4 protocol, optionally followed by a port qualifier (e.g `tcp dst port 80`)
+    fn parse_l4_proto(&mut self, proto: u8) -> Result<Expr, String> {
+        let proto_expr = Expr::IpProto(None, proto);
+        let dir = match self.peek() {
+            Some("src") => Dir::Src,
+            Some("dst") => Dir::Dst,
+            Some("port") | Some("portrange") => Dir::Any,
+            _ => return Ok(proto_expr),
+        };
+        if dir != Dir::Any {
+            self.pos += 1;
+        }
+        let port_expr = self.parse_directed(dir)?;
+        Ok(Expr::And(Box::new(proto_expr), Box::new(port_expr)))
+    }
+}
+
+/// Filter packets matching a BPF expression
+///
+/// Examples:
+///   `--bpf 'host 10.9.0.2'` to keep packets from or to this host
+///   `--bpf 'not (tcp port 22)'` to drop SSH traffic
+pub struct BpfFilter {
+    expr: Expr,
+}
+
+impl BpfFilter {
+    /// Compile the expression. Returns an error if the expression is invalid.
+    pub fn new(expression: &str) -> Result<Self, String> {
+        let tokens = tokenize(expression)?;
+        if tokens.is_empty() {
+            return Err("Empty BPF expression".to_string());
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(t) = parser.peek() {
+            return Err(format!("Unexpected token '{}' in BPF expression", t));
+        }
+        Ok(BpfFilter { expr })
+    }
+}
+
+impl Filter for BpfFilter {
+    fn name(&self) -> &'static str {
+        "BPF"
+    }
+
+    fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
+        let fields = PacketFields::from_packet_data(&i);
+        if self.expr.eval(&fields) {
+            Ok(Verdict::Accept(i))
+        } else {
+            Ok(Verdict::Drop)
+        }
+    }
+}
@@ -17,6 +17,10 @@ pub struct IPFilter {
 }
 
 impl Filter for IPFilter {
+    fn name(&self) -> &'static str {
+        "IP"
+    }
+
     fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
         match i {
             PacketData::L2(data) => {
@@ -103,6 +107,10 @@ pub struct SourceFilter {
 }
 
 impl Filter for SourceFilter {
+    fn name(&self) -> &'static str {
+        "Source"
+    }
+
     fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
         match i {
             PacketData::L2(data) => {
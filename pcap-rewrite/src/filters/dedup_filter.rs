@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hasher;
+
+use pcap_parser::data::PacketData;
+
+use crate::filters::filter::{FResult, Filter, Verdict};
+use crate::filters::filter_utils;
+
+/// Hashes of the last packets
+#[derive(Default)]
+struct DedupWindow {
+    hashes: VecDeque<u64>,
+    /// Number of occurrences of each hash in `hashes`
+    counts: HashMap<u64, usize>,
+}
+
+/// Drop duplicate packets
+///
+/// A packet is a duplicate if its L3 data (headers and payload) is identical to one of the
+/// `window` previous packets. L2 headers are ignored, so copies seen on different VLANs or with
+/// different MAC addresses are also duplicates.
+///
+/// If `ignore_ttl` is set, IPv4 TTL, ToS and checksum, and IPv6 hop limit and traffic class
+/// are ignored, to detect copies of a packet seen before and after a router.
+pub struct DedupFilter {
+    window: usize,
+    ignore_ttl: bool,
+    state: RefCell<DedupWindow>,
+}
+
+impl DedupFilter {
+    pub fn new(window: usize, ignore_ttl: bool) -> Self {
+        DedupFilter {
+            window,
+            ignore_ttl,
+            state: RefCell::new(DedupWindow::default()),
+        }
+    }
+
+    fn hash_l3(&self, data: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match data.first().map(|b| b >> 4) {
+            Some(4) if self.ignore_ttl && data.len() >= 20 => {
+                let mut header = [0u8; 20];
+                header.copy_from_slice(&data[..20]);
+                header[1] = 0; // ToS
+                header[8] = 0; // TTL
+                header[10..12].copy_from_slice(&[0, 0]); // checksum
+                hasher.write(&header);
+                hasher.write(&data[20..]);
+            }
+            Some(6) if self.ignore_ttl && data.len() >= 40 => {
+                let mut header = [0u8; 8];
+                header.copy_from_slice(&data[..8]);
+                // traffic class
+                header[0] &= 0xf0;
+                header[1] &= 0x0f;
+                header[7] = 0; // hop limit
+                hasher.write(&header);
+                hasher.write(&data[8..]);
+            }
+            _ => hasher.write(data),
+        }
+        hasher.finish()
+    }
+
+    /// Add hash to the window, and return true if it was already present
+    fn check_and_insert(&self, hash: u64) -> bool {
+        let mut state = self.state.borrow_mut();
+        let count = state.counts.entry(hash).or_insert(0);
+        let duplicate = *count > 0;
+        *count += 1;
+        state.hashes.push_back(hash);
+        if state.hashes.len() > self.window {
+            if let Some(old) = state.hashes.pop_front() {
+                if let Some(count) = state.counts.get_mut(&old) {
+                    *count -= 1;
+                    if *count == 0 {
+                        state.counts.remove(&old);
+                    }
+                }
+            }
+        }
+        duplicate
+    }
+}
+
+impl Filter for DedupFilter {
+    fn name(&self) -> &'static str {
+        "Dedup"
+    }
+
+    fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
+        let hash = match i {
+            PacketData::L2(data) => match filter_utils::parse_ethernet_vlan(data) {
+                Ok(ethernet_l3) => self.hash_l3(ethernet_l3.payload),
+                Err(_) => self.hash_l3(data),
+            },
+            PacketData::L3(_, data) => self.hash_l3(data),
+            PacketData::L4(_, data) | PacketData::Unsupported(data) => self.hash_l3(data),
+        };
+        if self.check_and_insert(hash) {
+            Ok(Verdict::Drop)
+        } else {
+            Ok(Verdict::Accept(i))
+        }
+    }
+}
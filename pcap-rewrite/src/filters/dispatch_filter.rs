@@ -1,22 +1,31 @@
-use std::io;
-use std::net::IpAddr;
-use std::path::Path;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
-use libpcap_tools::FiveTuple;
+use libpcap_tools::{FiveTuple, Packet};
 use pcap_parser::data::PacketData;
 use pnet_packet::ethernet::{EtherType, EtherTypes};
-use pnet_packet::ip::IpNextHeaderProtocol;
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet_packet::PrimitiveValues;
 
 use crate::container::five_tuple_container::FiveTupleC;
+use crate::container::icmp_type_code_container::IcmpTypeCodeC;
 use crate::container::ipaddr_container::IpAddrC;
 use crate::container::ipaddr_proto_port_container::IpAddrProtoPortC;
+use crate::container::payload_pattern_container::PayloadPatternC;
+use crate::container::port_range_container::PortRangeC;
+use crate::container::vlan_id_container::VlanIdC;
 use crate::filters::filter::{FResult, Filter, Verdict};
 use crate::filters::filter_utils;
 use crate::filters::filtering_action::FilteringAction;
 use crate::filters::filtering_key::FilteringKey;
+use crate::filters::fragmentation::fragment_tracker::{FragmentInfo, FragmentTracker};
 use crate::filters::key_parser_ipv4;
 use crate::filters::key_parser_ipv6;
+use crate::filters::payload_filter::PayloadFilter;
+use crate::filters::vlan_filter::VlanFilter;
 
 /// Function to extract key from data
 pub type GetKeyFn<Key> = Box<dyn Fn(&[u8]) -> Result<Key, String>>;
@@ -28,6 +37,17 @@ pub struct DispatchFilter<Container, Key> {
     get_key_from_ipv4_l3_data: GetKeyFn<Key>,
     get_key_from_ipv6_l3_data: GetKeyFn<Key>,
     keep: KeepFn<Container, Key>,
+    /// Extract key from the innermost packet of tunnels
+    decap: bool,
+    /// Action for packets without key. If `None`, these packets are a filter error
+    unsupported: Option<FilteringAction>,
+    /// Return false if the key is not relevant for the packet (for ex. ports of ICMP packets)
+    has_key: fn(&Key) -> bool,
+    /// Verdicts of fragmented datagrams, applied to all their fragments. `None` if fragments
+    /// are not tracked
+    fragments: Option<RefCell<FragmentTracker>>,
+    /// Verdicts of first fragments were stored during the pre-analysis pass
+    fragments_pre_analyzed: bool,
 }
 
 impl<Container, Key> DispatchFilter<Container, Key> {
@@ -36,61 +56,240 @@ impl<Container, Key> DispatchFilter<Container, Key> {
         get_key_from_ipv4_l3_data: GetKeyFn<Key>,
         get_key_from_ipv6_l3_data: GetKeyFn<Key>,
         keep: KeepFn<Container, Key>,
+        decap: bool,
     ) -> Self {
         DispatchFilter {
             key_container,
             get_key_from_ipv4_l3_data,
             get_key_from_ipv6_l3_data,
             keep,
+            decap,
+            unsupported: None,
+            has_key: |_| true,
+            fragments: None,
+            fragments_pre_analyzed: false,
         }
     }
 
-    pub fn keep<'j>(&self, packet_data: PacketData<'j>) -> FResult<PacketData<'j>, String> {
-        let key = match packet_data {
+    /// Set the action for packets without key, instead of returning an error
+    ///
+    /// A packet has no key if it is not IP, if it is malformed, or if `has_key` returns false
+    /// for its key (for ex. keys using ports, for protocols other than TCP and UDP).
+    pub fn with_unsupported(
+        mut self,
+        unsupported: Option<FilteringAction>,
+        has_key: fn(&Key) -> bool,
+    ) -> Self {
+        self.unsupported = unsupported;
+        self.has_key = has_key;
+        self
+    }
+
+    /// Apply the verdict of the first fragment of a datagram to all its fragments
+    ///
+    /// This is required for keys read in the L4 header (ports, ICMP type and code), which is
+    /// only in the first fragment. Verdicts are computed during a pre-analysis pass, so fragments
+    /// can be in any order. If the pre-analysis pass is skipped (for ex. on stdin), verdicts are
+    /// computed while filtering, and fragments captured before their first fragment do not get
+    /// the verdict of their datagram.
+    pub fn with_fragments(mut self) -> Self {
+        self.fragments = Some(RefCell::new(FragmentTracker::default()));
+        self
+    }
+
+    /// Return the L3 data of the packet (of the innermost packet, if decapsulating tunnels)
+    fn get_l3<'a>(&self, packet_data: &PacketData<'a>) -> Result<(EtherType, &'a [u8]), String> {
+        let (ether_type, data) = match *packet_data {
             PacketData::L2(data) => {
                 if data.len() < 14 {
                     return Err("L2 data too small for ethernet".to_owned());
                 }
 
-                filter_utils::extract_callback_ethernet(
-                    &self.get_key_from_ipv4_l3_data,
-                    &self.get_key_from_ipv6_l3_data,
-                    data,
-                )?
+                let ethernet_l3 = filter_utils::parse_ethernet_vlan(data)?;
+                (ethernet_l3.ethertype, ethernet_l3.payload)
             }
             PacketData::L3(l3_layer_value_u8, data) => {
-                let ether_type = EtherType::new(l3_layer_value_u8 as u16);
-                match ether_type {
-                    EtherTypes::Ipv4 => (self.get_key_from_ipv4_l3_data)(data)?,
-                    EtherTypes::Ipv6 => (self.get_key_from_ipv4_l3_data)(data)?,
-                    _ => Err(format!(
-                        "Unimplemented Ethertype in L3 {:?}/{:x}",
-                        ether_type,
-                        ether_type.to_primitive_values().0
-                    ))?,
-                }
+                (EtherType::new(l3_layer_value_u8 as u16), data)
             }
-            PacketData::L4(_, _) => unimplemented!(),
-            PacketData::Unsupported(_) => unimplemented!(),
+            PacketData::L4(_, _) => return Err("L4 data is not supported".to_owned()),
+            PacketData::Unsupported(_) => return Err("Unsupported link type".to_owned()),
+        };
+        if self.decap {
+            filter_utils::decapsulate(ether_type, data)
+        } else {
+            Ok((ether_type, data))
+        }
+    }
+
+    fn get_key(&self, ether_type: EtherType, data: &[u8]) -> Result<Key, String> {
+        match ether_type {
+            EtherTypes::Ipv4 => (self.get_key_from_ipv4_l3_data)(data),
+            EtherTypes::Ipv6 => (self.get_key_from_ipv6_l3_data)(data),
+            _ => Err(format!(
+                "Unimplemented Ethertype {:?}/{:x}",
+                ether_type,
+                ether_type.to_primitive_values().0
+            )),
+        }
+    }
+
+    /// Return the fragmentation fields of a packet, if it is a fragment
+    fn get_fragment(ether_type: EtherType, data: &[u8]) -> Option<FragmentInfo> {
+        match ether_type {
+            EtherTypes::Ipv4 => key_parser_ipv4::parse_fragment(data).unwrap_or(None),
+            EtherTypes::Ipv6 => key_parser_ipv6::parse_fragment(data).unwrap_or(None),
+            _ => None,
+        }
+    }
+
+    /// Return true if a packet must be kept, according to its key
+    fn keep_l3(&self, l3: Result<(EtherType, &[u8]), String>) -> Result<bool, String> {
+        let key = l3.and_then(|(ether_type, data)| self.get_key(ether_type, data));
+        match (key, self.unsupported) {
+            (Ok(key), Some(action)) if !(self.has_key)(&key) => Ok(action == FilteringAction::Keep),
+            (Ok(key), _) => (self.keep)(&self.key_container, &key),
+            (Err(_), Some(action)) => Ok(action == FilteringAction::Keep),
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    /// Return true if a fragment must be kept, according to the verdict of its datagram
+    fn keep_fragment(
+        &self,
+        fragments: &RefCell<FragmentTracker>,
+        fragment: &FragmentInfo,
+        l3: Result<(EtherType, &[u8]), String>,
+    ) -> Result<bool, String> {
+        if fragment.first && !self.fragments_pre_analyzed {
+            // without pre-analysis pass, verdicts are stored while filtering
+            let b = self.keep_l3(l3)?;
+            fragments.borrow_mut().insert(fragment.id.clone(), b);
+            return Ok(b);
+        }
+        let tracked = fragments.borrow().get(fragment);
+        if fragment.last {
+            fragments.borrow_mut().remove(fragment);
+        }
+        match tracked {
+            Some(b) => Ok(b),
+            None => self.keep_l3(l3),
+        }
+    }
+
+    pub fn keep<'j>(&self, packet_data: PacketData<'j>) -> FResult<PacketData<'j>, String> {
+        let l3 = self.get_l3(&packet_data);
+        let fragment = match (&self.fragments, &l3) {
+            (Some(_), Ok((ether_type, data))) => Self::get_fragment(*ether_type, data),
+            _ => None,
+        };
+        // fragments get the verdict of the first fragment of their datagram
+        let b = match (&self.fragments, fragment) {
+            (Some(fragments), Some(fragment)) => self.keep_fragment(fragments, &fragment, l3)?,
+            _ => self.keep_l3(l3)?,
         };
+        if b {
+            Ok(Verdict::Accept(packet_data))
+        } else {
+            Ok(Verdict::Drop)
+        }
+    }
 
-        match (self.keep)(&self.key_container, &key) {
-            Ok(b) => {
-                if b {
-                    Ok(Verdict::Accept(packet_data))
-                } else {
-                    Ok(Verdict::Drop)
+    /// Store the verdict of first fragments
+    fn track_fragment(&mut self, packet: &Packet) {
+        let l3 = self.get_l3(&packet.data);
+        let fragment = match l3 {
+            Ok((ether_type, data)) => Self::get_fragment(ether_type, data),
+            Err(_) => None,
+        };
+        if let Some(fragment) = fragment.filter(|f| f.first) {
+            // errors are reported when filtering the packet
+            if let Ok(b) = self.keep_l3(l3) {
+                if let Some(fragments) = self.fragments.as_mut() {
+                    fragments.get_mut().insert(fragment.id, b);
                 }
             }
-            Err(s) => Err(s),
         }
     }
 }
 
 impl<Container, Key> Filter for DispatchFilter<Container, Key> {
+    fn name(&self) -> &'static str {
+        "Dispatch"
+    }
+
     fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
         self.keep(i)
     }
+
+    fn require_pre_analysis(&self) -> bool {
+        self.fragments.is_some()
+    }
+
+    fn optional_pre_analysis(&self) -> bool {
+        true
+    }
+
+    fn pre_analyze(&mut self, packet: &Packet) -> Result<(), String> {
+        if self.fragments.is_some() {
+            self.track_fragment(packet);
+        }
+        Ok(())
+    }
+
+    fn preanalysis_done(&mut self) -> Result<(), String> {
+        self.fragments_pre_analyzed = self.fragments.is_some();
+        Ok(())
+    }
+}
+
+/// Parse a flow given as `PROTO SRC DST`, for ex. `tcp 10.0.0.1:1234 10.0.0.2:443`
+///
+/// The protocol is `tcp`, `udp`, `icmp`, `icmpv6` or a protocol number. Addresses have a port
+/// only for TCP and UDP. IPv6 addresses with a port are written between brackets, for ex.
+/// `[2001:db8::1]:443`.
+pub fn parse_flow(s: &str) -> Result<FiveTuple, String> {
+    let fields: Vec<_> = s.split_whitespace().collect();
+    if fields.len() != 3 {
+        return Err(format!("Invalid flow '{}': expected PROTO SRC DST", s));
+    }
+    let proto = match fields[0].to_ascii_lowercase().as_str() {
+        "tcp" => 6,
+        "udp" => 17,
+        "icmp" => 1,
+        "icmpv6" => 58,
+        p => p
+            .parse::<u8>()
+            .map_err(|_| format!("Invalid protocol in flow '{}': {}", s, p))?,
+    };
+    let endpoint = |field: &str| -> Result<(IpAddr, u16), String> {
+        if proto == 6 || proto == 17 {
+            let addr = SocketAddr::from_str(field)
+                .map_err(|e| format!("Invalid address in flow '{}': {} ({})", s, field, e))?;
+            Ok((addr.ip(), addr.port()))
+        } else {
+            let addr = IpAddr::from_str(field)
+                .map_err(|e| format!("Invalid address in flow '{}': {} ({})", s, field, e))?;
+            Ok((addr, 0))
+        }
+    };
+    let (src, src_port) = endpoint(fields[1])?;
+    let (dst, dst_port) = endpoint(fields[2])?;
+    Ok(FiveTuple {
+        proto,
+        src,
+        dst,
+        src_port,
+        dst_port,
+    })
+}
+
+/// Return the function testing the port of a packet (source or destination) against a port
+/// range container
+///
+/// Packets without ports are never in the container.
+fn port_range_keep(filtering_action: FilteringAction) -> KeepFn<PortRangeC, Option<u16>> {
+    let keep = filtering_action == FilteringAction::Keep;
+    Box::new(move |c, port| Ok(port.map_or(false, |p| c.contains(p)) == keep))
 }
 
 pub struct DispatchFilterBuilder;
@@ -100,10 +299,53 @@ impl DispatchFilterBuilder {
         filtering_key: FilteringKey,
         filtering_action: FilteringAction,
         key_file_path: &str,
+        decap: bool,
+        unsupported: Option<FilteringAction>,
+    ) -> Result<Box<dyn Filter>, io::Error> {
+        let file = File::open(key_file_path)?;
+        DispatchFilterBuilder::from_reader(
+            filtering_key,
+            filtering_action,
+            file,
+            decap,
+            unsupported,
+        )
+    }
+
+    /// Build a dispatch filter keeping the packets of `flows`, in both directions
+    ///
+    /// This is the same as a `sdipsdp` dispatch filter, without key file. Packets without
+    /// five-tuple (for ex. non-IP packets) are dropped.
+    pub fn from_flows(flows: &[FiveTuple], decap: bool) -> Box<dyn Filter> {
+        let keep: KeepFn<FiveTupleC, FiveTuple> =
+            Box::new(|c, five_tuple| Ok(c.contains(five_tuple)));
+        Box::new(
+            DispatchFilter::new(
+                FiveTupleC::of_five_tuples(flows),
+                Box::new(key_parser_ipv4::parse_five_tuple),
+                Box::new(key_parser_ipv6::parse_five_tuple),
+                keep,
+                decap,
+            )
+            .with_unsupported(Some(FilteringAction::Drop), |_| true)
+            .with_fragments(),
+        )
+    }
+
+    /// Build a dispatch filter, reading filtering keys (csv formatted, without header) from `reader`
+    ///
+    /// If `unsupported` is set, it is the action for packets without key (see
+    /// [`DispatchFilter::with_unsupported`]). VLAN and payload keys are defined for all packets.
+    pub fn from_reader<R: Read>(
+        filtering_key: FilteringKey,
+        filtering_action: FilteringAction,
+        reader: R,
+        decap: bool,
+        unsupported: Option<FilteringAction>,
     ) -> Result<Box<dyn Filter>, io::Error> {
         match filtering_key {
             FilteringKey::SrcIpaddr => {
-                let ipaddr_container = IpAddrC::of_file_path(Path::new(key_file_path))
+                let ipaddr_container = IpAddrC::of_reader(reader)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
 
                 let keep: KeepFn<IpAddrC, IpAddr> = match filtering_action {
@@ -113,15 +355,19 @@ impl DispatchFilterBuilder {
                     }
                 };
 
-                Ok(Box::new(DispatchFilter::new(
-                    ipaddr_container,
-                    Box::new(key_parser_ipv4::parse_src_ipaddr),
-                    Box::new(key_parser_ipv6::parse_src_ipaddr),
-                    Box::new(keep),
-                )))
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        ipaddr_container,
+                        Box::new(key_parser_ipv4::parse_src_ipaddr),
+                        Box::new(key_parser_ipv6::parse_src_ipaddr),
+                        Box::new(keep),
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |_| true),
+                ))
             }
             FilteringKey::DstIpaddr => {
-                let ipaddr_container = IpAddrC::of_file_path(Path::new(key_file_path))
+                let ipaddr_container = IpAddrC::of_reader(reader)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
 
                 let keep: KeepFn<IpAddrC, IpAddr> = match filtering_action {
@@ -131,15 +377,19 @@ impl DispatchFilterBuilder {
                     }
                 };
 
-                Ok(Box::new(DispatchFilter::new(
-                    ipaddr_container,
-                    Box::new(key_parser_ipv4::parse_dst_ipaddr),
-                    Box::new(key_parser_ipv6::parse_dst_ipaddr),
-                    keep,
-                )))
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        ipaddr_container,
+                        Box::new(key_parser_ipv4::parse_dst_ipaddr),
+                        Box::new(key_parser_ipv6::parse_dst_ipaddr),
+                        keep,
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |_| true),
+                ))
             }
             FilteringKey::SrcDstIpaddr => {
-                let ipaddr_container = IpAddrC::of_file_path(Path::new(key_file_path))
+                let ipaddr_container = IpAddrC::of_reader(reader)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
 
                 let keep: KeepFn<IpAddrC, (IpAddr, IpAddr)> = match filtering_action {
@@ -151,17 +401,20 @@ impl DispatchFilterBuilder {
                     }),
                 };
 
-                Ok(Box::new(DispatchFilter::new(
-                    ipaddr_container,
-                    Box::new(key_parser_ipv4::parse_src_dst_ipaddr),
-                    Box::new(key_parser_ipv6::parse_src_dst_ipaddr),
-                    keep,
-                )))
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        ipaddr_container,
+                        Box::new(key_parser_ipv4::parse_src_dst_ipaddr),
+                        Box::new(key_parser_ipv6::parse_src_dst_ipaddr),
+                        keep,
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |_| true),
+                ))
             }
             FilteringKey::SrcIpaddrProtoDstPort => {
-                let ipaddr_proto_port_container =
-                    IpAddrProtoPortC::of_file_path(Path::new(key_file_path))
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                let ipaddr_proto_port_container = IpAddrProtoPortC::of_reader(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
 
                 let keep: KeepFn<IpAddrProtoPortC, (IpAddr, IpNextHeaderProtocol, u16)> =
                     match filtering_action {
@@ -173,15 +426,22 @@ impl DispatchFilterBuilder {
                         }
                     };
 
-                Ok(Box::new(DispatchFilter::new(
-                    ipaddr_proto_port_container,
-                    Box::new(key_parser_ipv4::parse_src_ipaddr_proto_dst_port),
-                    Box::new(key_parser_ipv6::parse_src_ipaddr_proto_dst_port),
-                    keep,
-                )))
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        ipaddr_proto_port_container,
+                        Box::new(key_parser_ipv4::parse_src_ipaddr_proto_dst_port),
+                        Box::new(key_parser_ipv6::parse_src_ipaddr_proto_dst_port),
+                        keep,
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |(_, proto, _)| {
+                        *proto == IpNextHeaderProtocols::Tcp || *proto == IpNextHeaderProtocols::Udp
+                    })
+                    .with_fragments(),
+                ))
             }
             FilteringKey::SrcDstIpaddrProtoSrcDstPort => {
-                let five_tuple_container = FiveTupleC::of_file_path(Path::new(key_file_path))
+                let five_tuple_container = FiveTupleC::of_reader(reader)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
 
                 let keep: KeepFn<FiveTupleC, FiveTuple> = match filtering_action {
@@ -189,11 +449,93 @@ impl DispatchFilterBuilder {
                     FilteringAction::Drop => Box::new(|c, five_tuple| Ok(!c.contains(five_tuple))),
                 };
 
-                Ok(Box::new(DispatchFilter::new(
-                    five_tuple_container,
-                    Box::new(key_parser_ipv4::parse_five_tuple),
-                    Box::new(key_parser_ipv6::parse_five_tuple),
-                    keep,
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        five_tuple_container,
+                        Box::new(key_parser_ipv4::parse_five_tuple),
+                        Box::new(key_parser_ipv6::parse_five_tuple),
+                        keep,
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |t| t.proto == 6 || t.proto == 17)
+                    .with_fragments(),
+                ))
+            }
+            FilteringKey::SrcPortRange => {
+                let port_range_container = PortRangeC::of_reader(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        port_range_container,
+                        Box::new(key_parser_ipv4::parse_src_port),
+                        Box::new(key_parser_ipv6::parse_src_port),
+                        port_range_keep(filtering_action),
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |port| port.is_some())
+                    .with_fragments(),
+                ))
+            }
+            FilteringKey::DstPortRange => {
+                let port_range_container = PortRangeC::of_reader(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        port_range_container,
+                        Box::new(key_parser_ipv4::parse_dst_port),
+                        Box::new(key_parser_ipv6::parse_dst_port),
+                        port_range_keep(filtering_action),
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |port| port.is_some())
+                    .with_fragments(),
+                ))
+            }
+            FilteringKey::VlanId => {
+                let vlan_id_container = VlanIdC::of_reader(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                Ok(Box::new(VlanFilter::new(
+                    vlan_id_container,
+                    filtering_action,
+                )))
+            }
+            FilteringKey::IcmpTypeCode => {
+                let icmp_type_code_container = IcmpTypeCodeC::of_reader(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                // packets other than ICMP are never in the container
+                let keep: KeepFn<IcmpTypeCodeC, Option<(u8, u8)>> = match filtering_action {
+                    FilteringAction::Keep => {
+                        Box::new(|c, k| Ok(k.map_or(false, |(t, code)| c.contains(t, code))))
+                    }
+                    FilteringAction::Drop => {
+                        Box::new(|c, k| Ok(!k.map_or(false, |(t, code)| c.contains(t, code))))
+                    }
+                };
+
+                Ok(Box::new(
+                    DispatchFilter::new(
+                        icmp_type_code_container,
+                        Box::new(key_parser_ipv4::parse_icmp_type_code),
+                        Box::new(key_parser_ipv6::parse_icmp_type_code),
+                        keep,
+                        decap,
+                    )
+                    .with_unsupported(unsupported, |k| k.is_some())
+                    .with_fragments(),
+                ))
+            }
+            FilteringKey::PayloadPattern => {
+                let payload_pattern_container = PayloadPatternC::of_reader(reader)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+                Ok(Box::new(PayloadFilter::new(
+                    payload_pattern_container,
+                    filtering_action,
+                    decap,
                 )))
             }
         }
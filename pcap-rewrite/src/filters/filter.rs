@@ -15,11 +15,22 @@ pub type FResult<O, E> = Result<Verdict<O>, E>;
 pub trait Filter {
     fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String>;
 
+    /// Name of the filter, used in statistics
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
     /// Does this filter plugin require a first pass to pre-analyze data? (default: `false`)
     fn require_pre_analysis(&self) -> bool {
         false
     }
 
+    /// Can this filter run without its pre-analysis pass, if the input cannot be read twice (for
+    /// ex. stdin)? (default: `false`)
+    fn optional_pre_analysis(&self) -> bool {
+        false
+    }
+
     /// Pre-analysis function
     ///
     /// Any error raised in this function is fatal
@@ -34,15 +45,105 @@ pub trait Filter {
     }
 }
 
+/// Apply filters in sequence, stopping at the first filter returning `Drop` or an error
 pub fn apply_filters<'d>(
-    filters: &'d [Box<dyn Filter>],
+    filters: &[Box<dyn Filter>],
     data: PacketData<'d>,
 ) -> FResult<PacketData<'d>, String> {
-    filters.iter().fold(Ok(Verdict::Accept(data)), |d, f| {
-        if let Ok(Verdict::Accept(data)) = d {
-            f.filter(data)
-        } else {
-            d
+    let mut data = data;
+    for f in filters {
+        match f.filter(data)? {
+            Verdict::Accept(d) => data = d,
+            Verdict::Drop => return Ok(Verdict::Drop),
+        }
+    }
+    Ok(Verdict::Accept(data))
+}
+
+/// Sequence of filters, applied in order
+///
+/// A packet is accepted if all filters accept it (AND semantics). Evaluation stops at the
+/// first filter returning `Drop` or an error. A chain is itself a `Filter`, so chains can be
+/// nested.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter>>,
+}
+
+impl FilterChain {
+    pub fn new(filters: Vec<Box<dyn Filter>>) -> Self {
+        FilterChain { filters }
+    }
+
+    /// Add a filter at the end of the chain
+    pub fn push(&mut self, f: Box<dyn Filter>) {
+        self.filters.push(f);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Return an iterator over the filters
+    pub fn iter(&self) -> impl Iterator<Item = &Box<dyn Filter>> {
+        self.filters.iter()
+    }
+
+    /// Return a mutable iterator over the filters
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn Filter>> {
+        self.filters.iter_mut()
+    }
+
+    /// Apply filters in sequence, like [`apply_filters`]
+    ///
+    /// If the packet is dropped, also return the index of the filter which dropped it.
+    pub fn filter_with_index<'i>(
+        &self,
+        data: PacketData<'i>,
+    ) -> Result<(Verdict<PacketData<'i>>, Option<usize>), String> {
+        let mut data = data;
+        for (index, f) in self.filters.iter().enumerate() {
+            match f.filter(data)? {
+                Verdict::Accept(d) => data = d,
+                Verdict::Drop => return Ok((Verdict::Drop, Some(index))),
+            }
         }
-    })
+        Ok((Verdict::Accept(data), None))
+    }
+}
+
+impl Filter for FilterChain {
+    fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
+        apply_filters(&self.filters, i)
+    }
+
+    fn name(&self) -> &'static str {
+        "Chain"
+    }
+
+    fn require_pre_analysis(&self) -> bool {
+        self.filters.iter().any(|f| f.require_pre_analysis())
+    }
+
+    fn optional_pre_analysis(&self) -> bool {
+        self.filters
+            .iter()
+            .all(|f| !f.require_pre_analysis() || f.optional_pre_analysis())
+    }
+
+    fn pre_analyze(&mut self, packet: &Packet) -> Result<(), String> {
+        self.filters
+            .iter_mut()
+            .try_for_each(|f| f.pre_analyze(packet))
+    }
+
+    fn preanalysis_done(&mut self) -> Result<(), String> {
+        self.filters
+            .iter_mut()
+            .try_for_each(|f| f.preanalysis_done())
+    }
 }
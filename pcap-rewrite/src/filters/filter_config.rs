@@ -0,0 +1,210 @@
+//! Declarative filter configuration
+//!
+//! A filter configuration is a TOML file describing an ordered list of filters. Filters are
+//! applied in the order of the file, and a packet is written only if all filters keep it.
+//!
+//! ```toml
+//! # keys read from a file (relative paths are relative to the configuration file)
+//! [[filter]]
+//! key = "si"
+//! action = "k"
+//! path = "subnets"
+//!
+//! # keys given inline, one csv record per value
+//! [[filter]]
+//! key = "dpr"
+//! action = "d"
+//! values = ["1024-2048", 443]
+//!
+//! [[filter]]
+//! type = "fragmentation"
+//! key = "sdipsdp"
+//! action = "k"
+//!
+//! [[filter]]
+//! type = "bpf"
+//! expression = "tcp and dst port 80"
+//! ```
+//!
+//! `type` defaults to `dispatch`. Dispatch filters accept an optional `decap` boolean, which
+//! overrides the default value given to the loader, and an optional `unsupported` action
+//! (`k` or `d`) for packets without key (for ex. non-IP packets, or ICMP packets with a port
+//! key). Without `unsupported`, these packets are an error.
+
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::filters::bpf_filter::BpfFilter;
+use crate::filters::dispatch_filter::DispatchFilterBuilder;
+use crate::filters::filter::Filter;
+use crate::filters::filtering_action::FilteringAction;
+use crate::filters::filtering_key::FilteringKey;
+use crate::filters::fragmentation::fragmentation_filter::FragmentationFilterBuilder;
+
+fn config_error<S: AsRef<str>>(index: usize, msg: S) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("Filter configuration, filter {}: {}", index, msg.as_ref()),
+    )
+}
+
+fn get_str<'a>(entry: &'a toml::Value, index: usize, name: &str) -> Result<&'a str, io::Error> {
+    match entry.get(name) {
+        Some(toml::Value::String(s)) => Ok(s),
+        Some(_) => Err(config_error(index, format!("'{}' must be a string", name))),
+        None => Err(config_error(index, format!("missing '{}'", name))),
+    }
+}
+
+fn get_key_action(
+    entry: &toml::Value,
+    index: usize,
+) -> Result<(FilteringKey, FilteringAction), io::Error> {
+    let filtering_key = FilteringKey::of_string(get_str(entry, index, "key")?)
+        .map_err(|e| config_error(index, e))?;
+    let filtering_action = FilteringAction::of_string(get_str(entry, index, "action")?)
+        .map_err(|e| config_error(index, e))?;
+    Ok((filtering_key, filtering_action))
+}
+
+/// Join inline values as csv records. Integers are accepted for ports and VLAN ids.
+fn inline_values(values: &toml::Value, index: usize) -> Result<String, io::Error> {
+    let values = values
+        .as_array()
+        .ok_or_else(|| config_error(index, "'values' must be an array"))?;
+    let mut s = String::new();
+    for value in values {
+        match value {
+            toml::Value::String(v) => s.push_str(v),
+            toml::Value::Integer(v) => s.push_str(&v.to_string()),
+            _ => {
+                return Err(config_error(
+                    index,
+                    "'values' must contain strings or integers",
+                ))
+            }
+        }
+        s.push('\n');
+    }
+    Ok(s)
+}
+
+fn build_dispatch_filter(
+    entry: &toml::Value,
+    index: usize,
+    base_dir: &Path,
+    decap: bool,
+) -> Result<Box<dyn Filter>, io::Error> {
+    let (filtering_key, filtering_action) = get_key_action(entry, index)?;
+    let decap = match entry.get("decap") {
+        Some(v) => v
+            .as_bool()
+            .ok_or_else(|| config_error(index, "'decap' must be a boolean"))?,
+        None => decap,
+    };
+    let unsupported = match entry.get("unsupported") {
+        Some(_) => Some(
+            FilteringAction::of_string(get_str(entry, index, "unsupported")?)
+                .map_err(|e| config_error(index, e))?,
+        ),
+        None => None,
+    };
+    match (entry.get("path"), entry.get("values")) {
+        (Some(_), Some(_)) => Err(config_error(
+            index,
+            "'path' and 'values' are mutually exclusive",
+        )),
+        (Some(_), None) => {
+            let key_file_path = base_dir.join(get_str(entry, index, "path")?);
+            let key_file_path = key_file_path
+                .to_str()
+                .ok_or_else(|| config_error(index, "invalid path"))?;
+            DispatchFilterBuilder::from_args(
+                filtering_key,
+                filtering_action,
+                key_file_path,
+                decap,
+                unsupported,
+            )
+        }
+        (None, Some(values)) => {
+            let csv = inline_values(values, index)?;
+            DispatchFilterBuilder::from_reader(
+                filtering_key,
+                filtering_action,
+                Cursor::new(csv),
+                decap,
+                unsupported,
+            )
+        }
+        (None, None) => Err(config_error(index, "missing 'path' or 'values'")),
+    }
+}
+
+/// Parse a filter configuration, and build the filters in order
+///
+/// Relative key file paths are resolved from `base_dir`. `decap` is the default value for
+/// dispatch filters.
+pub fn filters_of_str(
+    s: &str,
+    base_dir: &Path,
+    decap: bool,
+) -> Result<Vec<Box<dyn Filter>>, io::Error> {
+    let value = toml::Value::from_str(s).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Invalid filter configuration: {}", e),
+        )
+    })?;
+    let entries = match value.get("filter") {
+        Some(toml::Value::Array(entries)) => entries.as_slice(),
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Invalid filter configuration: 'filter' must be an array of tables",
+            ))
+        }
+        None => &[],
+    };
+
+    let mut filters = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let filter_type = match entry.get("type") {
+            Some(_) => get_str(entry, index, "type")?,
+            None => "dispatch",
+        };
+        let f: Box<dyn Filter> = match filter_type {
+            "dispatch" => build_dispatch_filter(entry, index, base_dir, decap)?,
+            "fragmentation" => {
+                let (filtering_key, filtering_action) = get_key_action(entry, index)?;
+                FragmentationFilterBuilder::from_args(filtering_key, filtering_action)?
+            }
+            "bpf" => {
+                let expression = get_str(entry, index, "expression")?;
+                Box::new(BpfFilter::new(expression).map_err(|e| config_error(index, e))?)
+            }
+            _ => {
+                return Err(config_error(
+                    index,
+                    format!(
+                        "unknown type {} not among dispatch|fragmentation|bpf",
+                        filter_type
+                    ),
+                ))
+            }
+        };
+        filters.push(f);
+    }
+    Ok(filters)
+}
+
+/// Read a filter configuration file, and build the filters in order
+///
+/// See the module documentation for the file format.
+pub fn filters_of_file_path(path: &Path, decap: bool) -> Result<Vec<Box<dyn Filter>>, io::Error> {
+    let s = fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    filters_of_str(&s, base_dir, decap)
+}
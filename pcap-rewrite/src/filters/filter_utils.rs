@@ -1,36 +1,147 @@
-use pnet_packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet_packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
 use pnet_packet::vlan::VlanPacket;
-use pnet_packet::Packet;
 use pnet_packet::PrimitiveValues;
 
+use crate::filters::key_parser_ipv4;
+use crate::filters::key_parser_ipv6;
+
+/// Maximum number of nested tunnels removed by `decapsulate`
+const MAX_TUNNEL_DEPTH: usize = 8;
+
+/// Ethernet frame, after traversal of the VLAN tags, MPLS labels and PPPoE session header
+pub struct EthernetL3<'a> {
+    /// VLAN identifiers, outermost tag first
+    pub vlan_ids: Vec<u16>,
+    /// Ethertype of the payload
+    pub ethertype: EtherType,
+    /// L3 payload
+    pub payload: &'a [u8],
+}
+
+/// Size of an MPLS label stack entry
+const MPLS_LABEL_SIZE: usize = 4;
+/// Size of the PPPoE session header (version/type, code, session ID, length)
+const PPPOE_HEADER_SIZE: usize = 6;
+/// PPP protocol numbers of IPv4 and IPv6
+const PPP_PROTOCOL_IPV4: u16 = 0x0021;
+const PPP_PROTOCOL_IPV6: u16 = 0x0057;
+
+/// Skip an MPLS label stack, and guess the payload type
+///
+/// MPLS does not carry the payload type, so it is guessed from the first nibble after the bottom
+/// of the stack: 4 for IPv4, 6 for IPv6, and 0 for an Ethernet pseudowire with a control word.
+fn parse_mpls(data: &[u8]) -> Result<(EtherType, &[u8]), String> {
+    let mut payload = data;
+    loop {
+        if payload.len() < MPLS_LABEL_SIZE {
+            return Err("Expected MPLS label but not found".to_string());
+        }
+        let bottom_of_stack = payload[2] & 0x01 != 0;
+        payload = &payload[MPLS_LABEL_SIZE..];
+        if bottom_of_stack {
+            break;
+        }
+    }
+    match payload.first().map(|b| b >> 4) {
+        Some(4) => Ok((EtherTypes::Ipv4, payload)),
+        Some(6) => Ok((EtherTypes::Ipv6, payload)),
+        // pseudowire control word, followed by an Ethernet frame
+        Some(0) if payload.len() >= MPLS_LABEL_SIZE => {
+            let inner = &payload[MPLS_LABEL_SIZE..];
+            let ethernet_packet =
+                EthernetPacket::new(inner).ok_or("Expected Ethernet packet but not found")?;
+            Ok((
+                ethernet_packet.get_ethertype(),
+                &inner[EthernetPacket::minimum_packet_size()..],
+            ))
+        }
+        _ => Err("Unsupported MPLS payload".to_string()),
+    }
+}
+
+/// Skip a PPPoE session header and the PPP protocol field
+fn parse_pppoe_session(data: &[u8]) -> Result<(EtherType, &[u8]), String> {
+    if data.len() < PPPOE_HEADER_SIZE + 2 {
+        return Err("Expected PPPoE session header but not found".to_string());
+    }
+    let protocol = u16::from_be_bytes([data[PPPOE_HEADER_SIZE], data[PPPOE_HEADER_SIZE + 1]]);
+    let payload = &data[PPPOE_HEADER_SIZE + 2..];
+    match protocol {
+        PPP_PROTOCOL_IPV4 => Ok((EtherTypes::Ipv4, payload)),
+        PPP_PROTOCOL_IPV6 => Ok((EtherTypes::Ipv6, payload)),
+        _ => Err(format!("Unsupported PPP protocol: {:x}", protocol)),
+    }
+}
+
+/// Parse an Ethernet frame, skipping 802.1Q and 802.1ad (QinQ) tags, single or stacked, MPLS
+/// label stacks and PPPoE session headers
+pub fn parse_ethernet_vlan(packet_data: &[u8]) -> Result<EthernetL3, String> {
+    let ethernet_packet =
+        EthernetPacket::new(packet_data).ok_or("Expected Ethernet packet but not found")?;
+    let mut ethertype = ethernet_packet.get_ethertype();
+    let mut payload = &packet_data[EthernetPacket::minimum_packet_size()..];
+    let mut vlan_ids = Vec::new();
+    loop {
+        match ethertype {
+            EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ => {
+                let vlan_packet =
+                    VlanPacket::new(payload).ok_or("Expected VLAN packet but not found")?;
+                vlan_ids.push(vlan_packet.get_vlan_identifier());
+                ethertype = vlan_packet.get_ethertype();
+                payload = &payload[VlanPacket::minimum_packet_size()..];
+            }
+            EtherTypes::Mpls | EtherTypes::MplsMcast => {
+                let (next_ethertype, next_payload) = parse_mpls(payload)?;
+                ethertype = next_ethertype;
+                payload = next_payload;
+            }
+            EtherTypes::PppoeSession => {
+                let (next_ethertype, next_payload) = parse_pppoe_session(payload)?;
+                ethertype = next_ethertype;
+                payload = next_payload;
+            }
+            _ => break,
+        }
+    }
+    Ok(EthernetL3 {
+        vlan_ids,
+        ethertype,
+        payload,
+    })
+}
+
+/// Remove tunnel headers (GRE, VXLAN, GENEVE), possibly nested, and return the innermost packet
+///
+/// Packets which are not tunnels are returned unchanged.
+pub fn decapsulate(ethertype: EtherType, data: &[u8]) -> Result<(EtherType, &[u8]), String> {
+    let mut inner = (ethertype, data);
+    for _ in 0..MAX_TUNNEL_DEPTH {
+        let next = match inner.0 {
+            EtherTypes::Ipv4 => key_parser_ipv4::parse_tunnel(inner.1)?,
+            EtherTypes::Ipv6 => key_parser_ipv6::parse_tunnel(inner.1)?,
+            _ => None,
+        };
+        match next {
+            Some(next) => inner = next,
+            None => break,
+        }
+    }
+    Ok(inner)
+}
+
 pub fn extract_callback_ethernet<D>(
     get_key_from_ipv4_l3_data: &dyn Fn(&[u8]) -> Result<D, String>,
     get_key_from_ipv6_l3_data: &dyn Fn(&[u8]) -> Result<D, String>,
     packet_data: &[u8],
 ) -> Result<D, String> {
-    let ethernet_packet =
-        EthernetPacket::new(packet_data).ok_or("Expected Ethernet packet but not found")?;
-    match ethernet_packet.get_ethertype() {
-        EtherTypes::Ipv4 => (get_key_from_ipv4_l3_data)(ethernet_packet.payload()),
-        EtherTypes::Ipv6 => (get_key_from_ipv6_l3_data)(ethernet_packet.payload()),
-        EtherTypes::Vlan => {
-            // 802.11q
-            let vlan_packet = VlanPacket::new(ethernet_packet.payload())
-                .ok_or("Expected VLAN packet but not found")?;
-            match vlan_packet.get_ethertype() {
-                EtherTypes::Ipv4 => (get_key_from_ipv4_l3_data)(ethernet_packet.payload()),
-                EtherTypes::Ipv6 => (get_key_from_ipv6_l3_data)(ethernet_packet.payload()),
-                _ => Err(format!(
-                    "Unimplemented Ethertype in 33024/802.11q: {:?}/{:x}",
-                    vlan_packet.get_ethertype(),
-                    vlan_packet.get_ethertype().to_primitive_values().0
-                )),
-            }
-        }
+    let ethernet_l3 = parse_ethernet_vlan(packet_data)?;
+    match ethernet_l3.ethertype {
+        EtherTypes::Ipv4 => (get_key_from_ipv4_l3_data)(ethernet_l3.payload),
+        EtherTypes::Ipv6 => (get_key_from_ipv6_l3_data)(ethernet_l3.payload),
         _ => Err(format!(
             "Unimplemented Ethertype: {:?}/{:x}",
-            ethernet_packet.get_ethertype(),
-            ethernet_packet.get_ethertype().to_primitive_values().0
+            ethernet_l3.ethertype,
+            ethernet_l3.ethertype.to_primitive_values().0
         )),
     }
 }
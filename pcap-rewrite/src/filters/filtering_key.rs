@@ -5,6 +5,11 @@ pub enum FilteringKey {
     SrcDstIpaddr,
     SrcIpaddrProtoDstPort,
     SrcDstIpaddrProtoSrcDstPort,
+    SrcPortRange,
+    DstPortRange,
+    VlanId,
+    PayloadPattern,
+    IcmpTypeCode,
 }
 
 impl FilteringKey {
@@ -15,8 +20,13 @@ impl FilteringKey {
             "sdi" => Ok(FilteringKey::SrcDstIpaddr),
             "sipdp" => Ok(FilteringKey::SrcIpaddrProtoDstPort),
             "sdipsdp" => Ok(FilteringKey::SrcDstIpaddrProtoSrcDstPort),
+            "spr" => Ok(FilteringKey::SrcPortRange),
+            "dpr" => Ok(FilteringKey::DstPortRange),
+            "vid" => Ok(FilteringKey::VlanId),
+            "pp" => Ok(FilteringKey::PayloadPattern),
+            "itc" => Ok(FilteringKey::IcmpTypeCode),
             _ => Err(format!(
-                "Invalid string as input to build filtering criterion: {} not among si|di|sdi|sipdp|sdipsdp",
+                "Invalid string as input to build filtering criterion: {} not among si|di|sdi|sipdp|sdipsdp|spr|dpr|vid|pp|itc",
                 s
             )),
         }
@@ -0,0 +1,50 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+
+use super::two_tuple_proto_ipid::TwoTupleProtoIpid;
+
+/// Fragment of an IP datagram
+#[derive(Clone, Debug)]
+pub struct FragmentInfo {
+    /// Datagram identifier: addresses, protocol and IP id
+    pub id: TwoTupleProtoIpid,
+    /// Fragment offset is 0
+    pub first: bool,
+    /// More fragments flag is not set
+    pub last: bool,
+}
+
+/// Verdicts of fragmented datagrams
+///
+/// Only the first fragment of a datagram has the L4 header (ports, ICMP type), so its verdict is
+/// applied to all fragments. IP ids wrap, so several datagrams can have the same identifier:
+/// their verdicts are queued in the order of their first fragments, and the verdict of a
+/// datagram is removed when its last fragment is filtered.
+#[derive(Default)]
+pub struct FragmentTracker {
+    verdicts: HashMap<TwoTupleProtoIpid, VecDeque<bool>>,
+}
+
+impl FragmentTracker {
+    /// Store the verdict of the first fragment of a datagram
+    pub fn insert(&mut self, id: TwoTupleProtoIpid, keep: bool) {
+        self.verdicts.entry(id).or_default().push_back(keep);
+    }
+
+    /// Return the verdict of the datagram of a fragment, if the first fragment was seen
+    pub fn get(&self, fragment: &FragmentInfo) -> Option<bool> {
+        self.verdicts
+            .get(&fragment.id)
+            .and_then(|verdicts| verdicts.front().copied())
+    }
+
+    /// Remove the verdict of the datagram of a fragment
+    pub fn remove(&mut self, fragment: &FragmentInfo) {
+        if let Entry::Occupied(mut entry) = self.verdicts.entry(fragment.id.clone()) {
+            entry.get_mut().pop_front();
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+}
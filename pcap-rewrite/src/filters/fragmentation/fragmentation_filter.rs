@@ -174,6 +174,10 @@ impl<Container, Key> FragmentationFilter<Container, Key> {
 }
 
 impl<Container, Key> Filter for FragmentationFilter<Container, Key> {
+    fn name(&self) -> &'static str {
+        "Fragmentation"
+    }
+
     fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
         self.keep(i)
     }
@@ -330,6 +334,17 @@ impl FragmentationFilterBuilder {
                     keep,
                 )))
             }
+            FilteringKey::SrcPortRange
+            | FilteringKey::DstPortRange
+            | FilteringKey::VlanId
+            | FilteringKey::PayloadPattern
+            | FilteringKey::IcmpTypeCode => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Filtering key {:?} is not supported by the fragmentation filter",
+                    filtering_key
+                ),
+            )),
         }
     }
 }
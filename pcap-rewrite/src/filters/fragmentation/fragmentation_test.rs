@@ -18,7 +18,7 @@ pub fn is_ipv6_first_fragment(payload: &[u8]) -> Result<bool, String> {
     let (fragment_packet_option, _l4_proto, _payload) =
         ipv6_utils::get_fragment_packet_option_l4_protol4_payload(payload, &ipv6_packet)?;
     match fragment_packet_option {
-        Some(fragment_packet) => Ok(fragment_packet.get_fragment_offset() == 0),
+        Some(fragment_packet) => Ok(fragment_packet.offset == 0),
         None => Ok(false),
     }
 }
@@ -1,4 +1,5 @@
 pub mod convert_fn;
+pub mod fragment_tracker;
 pub mod fragmentation_filter;
 pub mod fragmentation_test;
 pub mod two_tuple_proto_ipid;
@@ -0,0 +1,92 @@
+use std::cell::Cell;
+
+use pcap_parser::data::PacketData;
+
+use crate::filters::filter::{FResult, Filter, Verdict};
+
+/// Range of packet indexes, inclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexRange {
+    pub start: u64,
+    /// Last index of the range, or `None` for all following packets
+    pub end: Option<u64>,
+}
+
+impl IndexRange {
+    fn contains(&self, index: u64) -> bool {
+        index >= self.start && self.end.map_or(true, |end| index <= end)
+    }
+}
+
+/// Parse a list of packet index ranges, for ex. `1000-2000,5000,7000-`
+///
+/// Indexes start at 1. A range without start begins at the first packet, and a range without
+/// end includes all following packets.
+pub fn parse_index_ranges(s: &str) -> Result<Vec<IndexRange>, String> {
+    let parse_index = |v: &str| match v.trim().parse::<u64>() {
+        Ok(index) if index > 0 => Ok(index),
+        _ => Err(format!("Invalid packet index in {}: {}", s, v)),
+    };
+    s.split(',')
+        .map(|range| {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => {
+                    let start = if start.trim().is_empty() {
+                        1
+                    } else {
+                        parse_index(start)?
+                    };
+                    let end = if end.trim().is_empty() {
+                        None
+                    } else {
+                        Some(parse_index(end)?)
+                    };
+                    (start, end)
+                }
+                None => {
+                    let index = parse_index(range)?;
+                    (index, Some(index))
+                }
+            };
+            if end.map_or(false, |end| end < start) {
+                return Err(format!("Invalid packet index range: {}", range));
+            }
+            Ok(IndexRange { start, end })
+        })
+        .collect()
+}
+
+/// Keep packets by their index in the input
+///
+/// Indexes start at 1, like frame numbers in Wireshark. Packets are counted when they are
+/// filtered, so this filter must be the first of the chain to count all input packets.
+pub struct IndexFilter {
+    ranges: Vec<IndexRange>,
+    /// Index of the last packet
+    index: Cell<u64>,
+}
+
+impl IndexFilter {
+    pub fn new(ranges: Vec<IndexRange>) -> Self {
+        IndexFilter {
+            ranges,
+            index: Cell::new(0),
+        }
+    }
+}
+
+impl Filter for IndexFilter {
+    fn name(&self) -> &'static str {
+        "PacketIndex"
+    }
+
+    fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
+        let index = self.index.get() + 1;
+        self.index.set(index);
+        if self.ranges.iter().any(|r| r.contains(index)) {
+            Ok(Verdict::Accept(i))
+        } else {
+            Ok(Verdict::Drop)
+        }
+    }
+}
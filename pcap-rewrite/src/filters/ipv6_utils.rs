@@ -1,85 +1,49 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use log::trace;
 
-use pnet_packet::ipv6::ExtensionPacket;
-use pnet_packet::ipv6::FragmentPacket;
 use pnet_packet::ipv6::Ipv6Packet;
-use pnet_packet::Packet;
-use pnet_packet::PacketSize;
 
 use pnet_packet::ip::IpNextHeaderProtocol;
-use pnet_packet::ip::IpNextHeaderProtocols;
 
-// TODO: factorize with code at URL below
-// From https://github.com/rusticata/pcap-analyzer/blob/3064dabbc51a19c51181dc223670ead34ce25844/libpcap-analyzer/src/analyzer.rs
-pub fn is_ipv6_opt(opt: IpNextHeaderProtocol) -> bool {
-    matches!(
-        opt,
-        IpNextHeaderProtocols::Hopopt
-            | IpNextHeaderProtocols::Ipv6Opts
-            | IpNextHeaderProtocols::Ipv6Route
-            | IpNextHeaderProtocols::Ipv6Frag
-            | IpNextHeaderProtocols::Esp
-            | IpNextHeaderProtocols::Ah
-            | IpNextHeaderProtocols::MobilityHeader
-    )
+use libpcap_tools::ipv6::{parse_ipv6_extensions, Ipv6Fragment, DEFAULT_IPV6_MAX_EXTENSIONS};
+
+/// Maximum number of extension headers walked to find the L4 protocol
+static MAX_EXTENSIONS: AtomicUsize = AtomicUsize::new(DEFAULT_IPV6_MAX_EXTENSIONS);
+
+/// Set the maximum number of IPv6 extension headers in a packet
+///
+/// Packets with longer chains of extension headers cannot be parsed by key parsers.
+pub fn set_max_extensions(max_extensions: usize) {
+    MAX_EXTENSIONS.store(max_extensions, Ordering::Relaxed);
 }
 
+/// Walk the IPv6 extension headers (see [`parse_ipv6_extensions`]), and return the fragment
+/// header, the L4 protocol and the L4 payload
 pub fn get_fragment_packet_option_l4_protol4_payload<'a>(
     data: &'a [u8],
-    ipv6: &'a Ipv6Packet,
-) -> Result<(Option<FragmentPacket<'a>>, IpNextHeaderProtocol, &'a [u8]), String> {
-    // From https://github.com/rusticata/pcap-analyzer/blob/3064dabbc51a19c51181dc223670ead34ce25844/libpcap-analyzer/src/analyzer.rs
-    let mut payload = ipv6.payload();
-    let mut l4_proto = ipv6.get_next_header();
-
-    if payload.is_empty() {
+    ipv6: &Ipv6Packet,
+) -> Result<(Option<Ipv6Fragment<'a>>, IpNextHeaderProtocol, &'a [u8]), String> {
+    // take the payload from data, which outlives ipv6
+    let payload_len = ipv6.get_payload_length() as usize;
+    let payload = if payload_len == 0 {
         // jumbogram ? (rfc2675)
         trace!("IPv6 length is 0. Jumbogram?");
-        if data.len() >= 40 {
-            payload = &data[40..];
-        } else {
-            return Err("IPv6 length is 0, but frame is too short for an IPv6 header".to_string());
-        }
-    }
-
-    let mut extensions = Vec::new();
-    let mut fragment_packet_option = None;
-
-    while is_ipv6_opt(l4_proto) {
-        if l4_proto == IpNextHeaderProtocols::Esp {
-            // ESP, don't try to get next layer protocol
-            break;
-        }
-
-        let ext = ExtensionPacket::new(payload)
-            .expect("Could not build IPv6 Extension packet from payload");
-        let next_header = ext.get_next_header();
-        trace!("option header: {}", l4_proto);
-        if l4_proto == IpNextHeaderProtocols::Ipv6Frag {
-            if fragment_packet_option.is_some() {
-                return Err("multiple IPv6Frag extensions".to_string());
-            }
-            fragment_packet_option = FragmentPacket::new(payload);
-        }
-        // XXX fixup wrong extension size calculation in pnet
-        let offset = if l4_proto != IpNextHeaderProtocols::Ah {
-            ext.packet_size()
-        } else {
-            // https://en.wikipedia.org/wiki/IPsec#Authentication_Header
-            // The length of this Authentication Header in 4-octet units, minus 2. For example, an
-            // AH value of 4 equals 3×(32-bit fixed-length AH fields) + 3×(32-bit ICV fields) − 2
-            // and thus an AH value of 4 means 24 octets. Although the size is measured in 4-octet
-            // units, the length of this header needs to be a multiple of 8 octets if carried in an
-            // IPv6 packet. This restriction does not apply to an Authentication Header carried in
-            // an IPv4 packet.
-            let l1 = (payload[1] - 1) as usize;
-            let val = l1 * 4 + l1 * 4 - 2;
-            (val + 7) & (!7)
-        };
-        extensions.push((l4_proto, ext));
-        l4_proto = next_header;
-        payload = &payload[offset..];
-    }
+        &data[40..]
+    } else {
+        &data[40..data.len().min(40 + payload_len)]
+    };
+
+    let ext = parse_ipv6_extensions(
+        ipv6.get_next_header().0,
+        payload,
+        MAX_EXTENSIONS.load(Ordering::Relaxed),
+    )
+    .map_err(|e| e.to_string())?;
 
-    Ok((fragment_packet_option, l4_proto, payload))
+    Ok((
+        ext.fragment,
+        IpNextHeaderProtocol(ext.next_header),
+        ext.payload,
+    ))
 }
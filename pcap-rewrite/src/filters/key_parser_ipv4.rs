@@ -1,16 +1,19 @@
 use std::net::IpAddr;
 
+use pnet_packet::ethernet::EtherType;
 use pnet_packet::ip::IpNextHeaderProtocol;
 use pnet_packet::ip::IpNextHeaderProtocols;
-use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet_packet::tcp::TcpPacket;
 use pnet_packet::udp::UdpPacket;
 use pnet_packet::Packet;
 
 use libpcap_tools::FiveTuple;
 
+use super::fragmentation::fragment_tracker::FragmentInfo;
 use super::fragmentation::two_tuple_proto_ipid::TwoTupleProtoIpid;
 use super::fragmentation::two_tuple_proto_ipid_five_tuple::TwoTupleProtoIpidFiveTuple;
+use super::tunnel;
 
 pub fn parse_src_ipaddr(payload: &[u8]) -> Result<IpAddr, String> {
     let ipv4 = Ipv4Packet::new(payload).ok_or("Expected Ipv4 packet but not found")?;
@@ -58,6 +61,51 @@ pub fn parse_src_ipaddr_proto_dst_port(
     }
 }
 
+/// Parse L4 ports. Returns `None` for protocols without ports, and for non-first fragments.
+fn parse_src_dst_port(payload: &[u8]) -> Result<Option<(u16, u16)>, String> {
+    let ipv4_packet = Ipv4Packet::new(payload).ok_or("Expected Ipv4 packet but not found")?;
+
+    if ipv4_packet.get_fragment_offset() != 0 {
+        return Ok(None);
+    }
+
+    match ipv4_packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(ipv4_packet.payload()) {
+            Some(ref tcp) => Ok(Some((tcp.get_source(), tcp.get_destination()))),
+            None => Err("Expected TCP packet in Ipv4 but could not parse".to_string()),
+        },
+        IpNextHeaderProtocols::Udp => match UdpPacket::new(ipv4_packet.payload()) {
+            Some(ref udp) => Ok(Some((udp.get_source(), udp.get_destination()))),
+            None => Err("Expected UDP packet in Ipv4 but could not parse".to_string()),
+        },
+        _ => Ok(None),
+    }
+}
+
+pub fn parse_src_port(payload: &[u8]) -> Result<Option<u16>, String> {
+    Ok(parse_src_dst_port(payload)?.map(|(src_port, _)| src_port))
+}
+
+pub fn parse_dst_port(payload: &[u8]) -> Result<Option<u16>, String> {
+    Ok(parse_src_dst_port(payload)?.map(|(_, dst_port)| dst_port))
+}
+
+/// Parse ICMP type and code. Returns `None` for other protocols, and for non-first fragments.
+pub fn parse_icmp_type_code(payload: &[u8]) -> Result<Option<(u8, u8)>, String> {
+    let ipv4_packet = Ipv4Packet::new(payload).ok_or("Expected Ipv4 packet but not found")?;
+
+    if ipv4_packet.get_fragment_offset() != 0
+        || ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp
+    {
+        return Ok(None);
+    }
+
+    match ipv4_packet.payload() {
+        [icmp_type, icmp_code, ..] => Ok(Some((*icmp_type, *icmp_code))),
+        _ => Err("Expected ICMP packet in Ipv4 but could not parse".to_string()),
+    }
+}
+
 pub fn parse_two_tuple_proto_ipid(payload: &[u8]) -> Result<TwoTupleProtoIpid, String> {
     let ipv4_packet = Ipv4Packet::new(payload).ok_or("Expected Ipv4 packet but not found")?;
     let src_ipaddr = IpAddr::V4(ipv4_packet.get_source());
@@ -124,3 +172,43 @@ pub fn parse_two_tuple_proto_ipid_five_tuple(
         TwoTupleProtoIpidFiveTuple::new(Some(two_tuple_proto_ipid), Some(five_tuple));
     Ok(two_tuple_proto_ipid_five_tuple)
 }
+
+/// Parse fragmentation fields. Returns `None` if the packet is not a fragment.
+pub fn parse_fragment(payload: &[u8]) -> Result<Option<FragmentInfo>, String> {
+    let ipv4_packet = Ipv4Packet::new(payload).ok_or("Expected Ipv4 packet but not found")?;
+
+    let first = ipv4_packet.get_fragment_offset() == 0;
+    let last = ipv4_packet.get_flags() & Ipv4Flags::MoreFragments == 0;
+    if first && last {
+        return Ok(None);
+    }
+    Ok(Some(FragmentInfo {
+        id: parse_two_tuple_proto_ipid(payload)?,
+        first,
+        last,
+    }))
+}
+
+/// Parse tunnel encapsulation (GRE, VXLAN, GENEVE), and return the inner packet
+///
+/// Returns `None` if the packet is not a tunnel, or is a fragment.
+pub fn parse_tunnel(payload: &[u8]) -> Result<Option<(EtherType, &[u8])>, String> {
+    let ipv4_packet = Ipv4Packet::new(payload).ok_or("Expected Ipv4 packet but not found")?;
+
+    if ipv4_packet.get_fragment_offset() != 0
+        || ipv4_packet.get_flags() & Ipv4Flags::MoreFragments != 0
+    {
+        return Ok(None);
+    }
+
+    let header_len = usize::from(ipv4_packet.get_header_length()) * 4;
+    let total_len = match usize::from(ipv4_packet.get_total_length()) {
+        // total length can be 0 with TCP segmentation offload
+        len if len >= header_len => len.min(payload.len()),
+        _ => payload.len(),
+    };
+    let l4_payload = payload
+        .get(header_len..total_len)
+        .ok_or("Expected Ipv4 payload but could not parse")?;
+    tunnel::parse_tunnel(ipv4_packet.get_next_level_protocol(), l4_payload)
+}
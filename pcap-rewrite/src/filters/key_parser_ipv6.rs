@@ -1,5 +1,6 @@
 use std::net::IpAddr;
 
+use pnet_packet::ethernet::EtherType;
 use pnet_packet::ip::IpNextHeaderProtocol;
 use pnet_packet::ip::IpNextHeaderProtocols;
 use pnet_packet::ipv6::Ipv6Packet;
@@ -9,8 +10,10 @@ use pnet_packet::udp::UdpPacket;
 use crate::filters::ipv6_utils;
 use libpcap_tools::FiveTuple;
 
+use super::fragmentation::fragment_tracker::FragmentInfo;
 use super::fragmentation::two_tuple_proto_ipid::TwoTupleProtoIpid;
 use super::fragmentation::two_tuple_proto_ipid_five_tuple::TwoTupleProtoIpidFiveTuple;
+use super::tunnel;
 
 pub fn parse_src_ipaddr(payload: &[u8]) -> Result<IpAddr, String> {
     let ipv6 = Ipv6Packet::new(payload).ok_or("Expected Ipv6 packet but not found")?;
@@ -58,9 +61,65 @@ pub fn parse_src_ipaddr_proto_dst_port(
     }
 }
 
+/// Parse L4 ports. Returns `None` for protocols without ports, and for non-first fragments.
+fn parse_src_dst_port(payload: &[u8]) -> Result<Option<(u16, u16)>, String> {
+    let ipv6_packet = Ipv6Packet::new(payload).ok_or("Expected Ipv6 packet but not found")?;
+
+    let (fragment_packet_option, l4_proto, payload) =
+        ipv6_utils::get_fragment_packet_option_l4_protol4_payload(payload, &ipv6_packet)?;
+
+    if let Some(fragment_packet) = fragment_packet_option {
+        if fragment_packet.offset != 0 {
+            return Ok(None);
+        }
+    }
+
+    match l4_proto {
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(payload) {
+            Some(ref tcp) => Ok(Some((tcp.get_source(), tcp.get_destination()))),
+            None => Err("Expected TCP packet in Ipv6 but could not parse".to_string()),
+        },
+        IpNextHeaderProtocols::Udp => match UdpPacket::new(payload) {
+            Some(ref udp) => Ok(Some((udp.get_source(), udp.get_destination()))),
+            None => Err("Expected UDP packet in Ipv6 but could not parse".to_string()),
+        },
+        _ => Ok(None),
+    }
+}
+
+pub fn parse_src_port(payload: &[u8]) -> Result<Option<u16>, String> {
+    Ok(parse_src_dst_port(payload)?.map(|(src_port, _)| src_port))
+}
+
+pub fn parse_dst_port(payload: &[u8]) -> Result<Option<u16>, String> {
+    Ok(parse_src_dst_port(payload)?.map(|(_, dst_port)| dst_port))
+}
+
+/// Parse ICMPv6 type and code. Returns `None` for other protocols, and for non-first fragments.
+pub fn parse_icmp_type_code(payload: &[u8]) -> Result<Option<(u8, u8)>, String> {
+    let ipv6_packet = Ipv6Packet::new(payload).ok_or("Expected Ipv6 packet but not found")?;
+
+    let (fragment_packet_option, l4_proto, payload) =
+        ipv6_utils::get_fragment_packet_option_l4_protol4_payload(payload, &ipv6_packet)?;
+
+    if let Some(fragment_packet) = fragment_packet_option {
+        if fragment_packet.offset != 0 {
+            return Ok(None);
+        }
+    }
+    if l4_proto != IpNextHeaderProtocols::Icmpv6 {
+        return Ok(None);
+    }
+
+    match payload {
+        [icmp_type, icmp_code, ..] => Ok(Some((*icmp_type, *icmp_code))),
+        _ => Err("Expected ICMPv6 packet in Ipv6 but could not parse".to_string()),
+    }
+}
+
 pub fn parse_two_tuple_proto_ipid(payload: &[u8]) -> Result<Option<TwoTupleProtoIpid>, String> {
     let ipv6_packet = Ipv6Packet::new(payload).ok_or("Expected Ipv6 packet but not found")?;
-    let src_ipaddr = IpAddr::V6(ipv6_packet.get_destination());
+    let src_ipaddr = IpAddr::V6(ipv6_packet.get_source());
     let dst_ipaddr = IpAddr::V6(ipv6_packet.get_destination());
 
     let (fragment_packet_option, l4_proto, _payload) =
@@ -70,7 +129,7 @@ pub fn parse_two_tuple_proto_ipid(payload: &[u8]) -> Result<Option<TwoTupleProto
 
     match fragment_packet_option {
         Some(fragment_packet) => {
-            let ip_id = fragment_packet.get_id();
+            let ip_id = fragment_packet.id;
             Ok(Some(TwoTupleProtoIpid::new(
                 src_ipaddr, dst_ipaddr, proto, ip_id,
             )))
@@ -136,3 +195,40 @@ pub fn parse_two_tuple_proto_ipid_five_tuple(
         parse_five_tuple(payload).ok(),
     ))
 }
+
+/// Parse fragmentation fields. Returns `None` if the packet is not a fragment.
+pub fn parse_fragment(payload: &[u8]) -> Result<Option<FragmentInfo>, String> {
+    let ipv6_packet = Ipv6Packet::new(payload).ok_or("Expected Ipv6 packet but not found")?;
+
+    let (fragment_packet_option, _l4_proto, _payload) =
+        ipv6_utils::get_fragment_packet_option_l4_protol4_payload(payload, &ipv6_packet)?;
+
+    let (first, last) = match fragment_packet_option {
+        Some(fragment_packet) => (fragment_packet.offset == 0, !fragment_packet.more_fragments),
+        None => return Ok(None),
+    };
+    // atomic fragment
+    if first && last {
+        return Ok(None);
+    }
+    match parse_two_tuple_proto_ipid(payload)? {
+        Some(id) => Ok(Some(FragmentInfo { id, first, last })),
+        None => Ok(None),
+    }
+}
+
+/// Parse tunnel encapsulation (GRE, VXLAN, GENEVE), and return the inner packet
+///
+/// Returns `None` if the packet is not a tunnel, or is a fragment.
+pub fn parse_tunnel(payload: &[u8]) -> Result<Option<(EtherType, &[u8])>, String> {
+    let ipv6_packet = Ipv6Packet::new(payload).ok_or("Expected Ipv6 packet but not found")?;
+
+    let (fragment_packet_option, l4_proto, l4_payload) =
+        ipv6_utils::get_fragment_packet_option_l4_protol4_payload(payload, &ipv6_packet)?;
+
+    if fragment_packet_option.is_some() {
+        return Ok(None);
+    }
+
+    tunnel::parse_tunnel(l4_proto, l4_payload)
+}
@@ -1,10 +1,18 @@
+pub mod bpf_filter;
 pub mod common_filters;
+pub mod dedup_filter;
 pub mod dispatch_filter;
 pub mod filter;
+pub mod filter_config;
 pub mod filter_utils;
 pub mod filtering_action;
 pub mod filtering_key;
 pub mod fragmentation;
+pub mod index_filter;
 pub mod ipv6_utils;
 pub mod key_parser_ipv4;
 pub mod key_parser_ipv6;
+pub mod payload_filter;
+pub mod sampling_filter;
+pub mod tunnel;
+pub mod vlan_filter;
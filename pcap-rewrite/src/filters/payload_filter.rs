@@ -0,0 +1,77 @@
+use pcap_parser::data::PacketData;
+use pnet_packet::ethernet::{EtherType, EtherTypes};
+
+use crate::container::payload_pattern_container::PayloadPatternC;
+use crate::filters::filter::{FResult, Filter, Verdict};
+use crate::filters::filter_utils;
+use crate::filters::filtering_action::FilteringAction;
+use crate::transforms::payload::locate_payload;
+
+/// Filter packets using patterns matched against their TCP/UDP payload
+///
+/// A packet matches if any pattern of the container is found in its payload. Packets with no
+/// payload (not IP, other L4 protocols, empty payload, or headers which could not be parsed) never
+/// match.
+pub struct PayloadFilter {
+    payload_pattern_container: PayloadPatternC,
+    filtering_action: FilteringAction,
+    /// Match payload of the innermost packet of tunnels
+    decap: bool,
+}
+
+impl PayloadFilter {
+    pub fn new(
+        payload_pattern_container: PayloadPatternC,
+        filtering_action: FilteringAction,
+        decap: bool,
+    ) -> Self {
+        PayloadFilter {
+            payload_pattern_container,
+            filtering_action,
+            decap,
+        }
+    }
+
+    fn is_match(&self, ethertype: EtherType, data: &[u8]) -> bool {
+        let (ethertype, data) = if self.decap {
+            match filter_utils::decapsulate(ethertype, data) {
+                Ok(inner) => inner,
+                Err(_) => return false,
+            }
+        } else {
+            (ethertype, data)
+        };
+        if !matches!(ethertype, EtherTypes::Ipv4 | EtherTypes::Ipv6) {
+            return false;
+        }
+        locate_payload(data).map_or(false, |(_, range)| {
+            self.payload_pattern_container.is_match(&data[range])
+        })
+    }
+}
+
+impl Filter for PayloadFilter {
+    fn name(&self) -> &'static str {
+        "Payload"
+    }
+
+    fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
+        let found = match i {
+            PacketData::L2(data) => filter_utils::parse_ethernet_vlan(data)
+                .map_or(false, |ethernet_l3| {
+                    self.is_match(ethernet_l3.ethertype, ethernet_l3.payload)
+                }),
+            PacketData::L3(ethertype, data) => self.is_match(EtherType::new(ethertype), data),
+            _ => false,
+        };
+        let keep = match self.filtering_action {
+            FilteringAction::Keep => found,
+            FilteringAction::Drop => !found,
+        };
+        if keep {
+            Ok(Verdict::Accept(i))
+        } else {
+            Ok(Verdict::Drop)
+        }
+    }
+}
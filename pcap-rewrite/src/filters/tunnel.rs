@@ -0,0 +1,102 @@
+use pnet_packet::ethernet::EtherType;
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+use crate::filters::filter_utils;
+
+/// UDP port of VXLAN (RFC 7348)
+pub const VXLAN_PORT: u16 = 4789;
+/// UDP port of GENEVE (RFC 8926)
+pub const GENEVE_PORT: u16 = 6081;
+/// Ethertype of Transparent Ethernet Bridging, used to carry ethernet frames in GRE and GENEVE
+const ETHERTYPE_TEB: u16 = 0x6558;
+
+/// Inner packet of a tunnel: ethertype and L3 data
+pub type TunnelInner<'a> = (EtherType, &'a [u8]);
+
+/// Returns `None` if the inner ethernet frame is truncated
+fn inner_of_protocol_type(protocol_type: u16, data: &[u8]) -> Option<TunnelInner> {
+    if protocol_type == ETHERTYPE_TEB {
+        let ethernet_l3 = filter_utils::parse_ethernet_vlan(data).ok()?;
+        Some((ethernet_l3.ethertype, ethernet_l3.payload))
+    } else {
+        Some((EtherType::new(protocol_type), data))
+    }
+}
+
+/// Parse a GRE header (RFC 2784, RFC 2890). Enhanced GRE (version 1, PPTP) is not decapsulated.
+///
+/// Truncated headers are not decapsulated either.
+pub fn parse_gre(data: &[u8]) -> Result<Option<TunnelInner>, String> {
+    if data.len() < 4 {
+        return Ok(None);
+    }
+    let flags = u16::from_be_bytes([data[0], data[1]]);
+    if flags & 0x0007 != 0 {
+        return Ok(None);
+    }
+    let protocol_type = u16::from_be_bytes([data[2], data[3]]);
+    // optional checksum (C), key (K) and sequence number (S)
+    let offset = [0x8000, 0x2000, 0x1000]
+        .iter()
+        .filter(|bit| flags & **bit != 0)
+        .fold(4, |offset, _| offset + 4);
+    Ok(data
+        .get(offset..)
+        .and_then(|payload| inner_of_protocol_type(protocol_type, payload)))
+}
+
+/// Parse a VXLAN header, followed by an ethernet frame
+///
+/// Truncated headers are not decapsulated.
+pub fn parse_vxlan(data: &[u8]) -> Result<Option<TunnelInner>, String> {
+    if data.len() < 8 {
+        return Ok(None);
+    }
+    // VNI flag must be set
+    if data[0] & 0x08 == 0 {
+        return Ok(None);
+    }
+    Ok(inner_of_protocol_type(ETHERTYPE_TEB, &data[8..]))
+}
+
+/// Parse a GENEVE header, including options
+///
+/// Truncated headers are not decapsulated.
+pub fn parse_geneve(data: &[u8]) -> Result<Option<TunnelInner>, String> {
+    if data.len() < 8 {
+        return Ok(None);
+    }
+    if data[0] >> 6 != 0 {
+        return Ok(None);
+    }
+    let options_len = usize::from(data[0] & 0x3f) * 4;
+    let protocol_type = u16::from_be_bytes([data[2], data[3]]);
+    Ok(data
+        .get(8 + options_len..)
+        .and_then(|payload| inner_of_protocol_type(protocol_type, payload)))
+}
+
+/// Parse a tunnel header, given the L4 protocol and payload of the outer packet
+///
+/// Returns `None` if the packet is not a GRE, VXLAN or GENEVE tunnel, or if the tunnel headers
+/// are truncated.
+pub fn parse_tunnel(
+    l4_proto: IpNextHeaderProtocol,
+    l4_payload: &[u8],
+) -> Result<Option<TunnelInner>, String> {
+    match l4_proto {
+        IpNextHeaderProtocols::Gre => parse_gre(l4_payload),
+        IpNextHeaderProtocols::Udp => {
+            if l4_payload.len() < 8 {
+                return Ok(None);
+            }
+            let dst_port = u16::from_be_bytes([l4_payload[2], l4_payload[3]]);
+            match dst_port {
+                VXLAN_PORT => parse_vxlan(&l4_payload[8..]),
+                GENEVE_PORT => parse_geneve(&l4_payload[8..]),
+                _ => Ok(None),
+            }
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,55 @@
+use pcap_parser::data::PacketData;
+
+use crate::container::vlan_id_container::VlanIdC;
+use crate::filters::filter::{FResult, Filter, Verdict};
+use crate::filters::filter_utils;
+use crate::filters::filtering_action::FilteringAction;
+
+/// Filter packets using their VLAN identifiers
+///
+/// A packet matches if any of its tags (including inner tags of QinQ frames) is in the
+/// container. Packets without L2 data, without tags, or with a truncated ethernet header never
+/// match.
+pub struct VlanFilter {
+    vlan_id_container: VlanIdC,
+    filtering_action: FilteringAction,
+}
+
+impl VlanFilter {
+    pub fn new(vlan_id_container: VlanIdC, filtering_action: FilteringAction) -> Self {
+        VlanFilter {
+            vlan_id_container,
+            filtering_action,
+        }
+    }
+}
+
+impl Filter for VlanFilter {
+    fn name(&self) -> &'static str {
+        "VLAN"
+    }
+
+    fn filter<'i>(&self, i: PacketData<'i>) -> FResult<PacketData<'i>, String> {
+        let found = match i {
+            // truncated frames are handled as frames without tags
+            PacketData::L2(data) => filter_utils::parse_ethernet_vlan(data)
+                .map(|ethernet_l3| {
+                    ethernet_l3
+                        .vlan_ids
+                        .iter()
+                        .any(|vlan_id| self.vlan_id_container.contains(*vlan_id))
+                })
+                .unwrap_or(false),
+            _ => false,
+        };
+        let keep = match self.filtering_action {
+            FilteringAction::Keep => found,
+            FilteringAction::Drop => !found,
+        };
+        if keep {
+            Ok(Verdict::Accept(i))
+        } else {
+            Ok(Verdict::Drop)
+        }
+    }
+}
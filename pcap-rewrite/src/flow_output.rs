@@ -0,0 +1,442 @@
+//! Write each bidirectional flow to its own output file
+//!
+//! The number of simultaneously open files is bounded: file handles are kept in a LRU pool,
+//! and files are reopened in append mode when needed. Writers of flows are also bounded: the
+//! least recently used writer is finished, and created again (without writing the file header
+//! again) if its flow has more packets.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use libpcap_tools::{FiveTuple, Packet};
+use log::debug;
+use pcap_parser::data::PacketData;
+use pcap_parser::Linktype;
+use pnet_packet::ethernet::{EtherType, EtherTypes};
+
+use crate::compression::OutputStream;
+use crate::filters::fragmentation::fragment_tracker::FragmentInfo;
+use crate::filters::fragmentation::two_tuple_proto_ipid::TwoTupleProtoIpid;
+use crate::filters::{filter_utils, key_parser_ipv4, key_parser_ipv6};
+use crate::pcap::PcapWriter;
+use crate::pcapng_writer::PcapNGWriter;
+use crate::rewriter::FileFormat;
+use crate::traits::Writer;
+
+/// Maximum number of fragmented datagrams whose first fragment is remembered
+const MAX_FRAGMENTED_DATAGRAMS: usize = 65536;
+
+/// Pool of open files, closing the least recently used file when full
+struct FilePool {
+    max_open_files: usize,
+    /// Open files, with the tick of their last use
+    open: HashMap<usize, (File, u64)>,
+    tick: u64,
+    /// File whose written data is discarded (the header written again when a writer is created
+    /// for a file which already has it)
+    discard: Option<usize>,
+}
+
+impl FilePool {
+    fn get(&mut self, id: usize, path: &Path, create: bool) -> Result<&mut File, io::Error> {
+        self.tick += 1;
+        if !self.open.contains_key(&id) {
+            if self.open.len() >= self.max_open_files {
+                let lru_id = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, (_, last_use))| *last_use)
+                    .map(|(id, _)| *id);
+                if let Some(lru_id) = lru_id {
+                    self.open.remove(&lru_id);
+                }
+            }
+            let file = if create {
+                File::create(path)?
+            } else {
+                OpenOptions::new().append(true).open(path)?
+            };
+            self.open.insert(id, (file, 0));
+        }
+        let entry = self.open.get_mut(&id).expect("file was just opened");
+        entry.1 = self.tick;
+        Ok(&mut entry.0)
+    }
+}
+
+/// Handle to a file of the pool, opened on demand
+struct PooledFile {
+    id: usize,
+    path: PathBuf,
+    created: bool,
+    pool: Rc<RefCell<FilePool>>,
+}
+
+impl Write for PooledFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pool = self.pool.borrow_mut();
+        if pool.discard == Some(self.id) {
+            return Ok(buf.len());
+        }
+        let file = pool.get(self.id, &self.path, !self.created)?;
+        self.created = true;
+        file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.pool.borrow_mut().open.get_mut(&self.id) {
+            Some((file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl OutputStream for PooledFile {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Flow of fragmented datagrams, from their first fragment
+///
+/// When `MAX_FRAGMENTED_DATAGRAMS` datagrams are remembered, the oldest one is forgotten.
+#[derive(Default)]
+struct FragmentFlows {
+    /// Flow of each datagram, with its insertion sequence number
+    flows: HashMap<TwoTupleProtoIpid, (Option<FiveTuple>, u64)>,
+    /// Datagrams, oldest first. Datagrams removed from `flows` are removed lazily
+    order: VecDeque<(TwoTupleProtoIpid, u64)>,
+    seq: u64,
+}
+
+impl FragmentFlows {
+    fn insert(&mut self, id: TwoTupleProtoIpid, key: Option<FiveTuple>) {
+        self.seq += 1;
+        self.flows.insert(id.clone(), (key, self.seq));
+        self.order.push_back((id, self.seq));
+        while self.flows.len() > MAX_FRAGMENTED_DATAGRAMS {
+            match self.order.pop_front() {
+                Some((id, seq)) => self.remove_entry(&id, seq),
+                None => break,
+            }
+        }
+        // drop removed datagrams, so `order` stays bounded
+        if self.order.len() > 2 * MAX_FRAGMENTED_DATAGRAMS {
+            let flows = &self.flows;
+            self.order
+                .retain(|(id, seq)| flows.get(id).map(|(_, s)| s) == Some(seq));
+        }
+    }
+
+    fn get(&self, id: &TwoTupleProtoIpid) -> Option<FiveTuple> {
+        self.flows.get(id).and_then(|(key, _)| key.clone())
+    }
+
+    fn remove(&mut self, id: &TwoTupleProtoIpid) -> Option<FiveTuple> {
+        self.flows.remove(id).and_then(|(key, _)| key)
+    }
+
+    /// Remove the entry of `id` if it was inserted with sequence number `seq`
+    fn remove_entry(&mut self, id: &TwoTupleProtoIpid, seq: u64) {
+        if self.flows.get(id).map(|(_, s)| *s) == Some(seq) {
+            self.flows.remove(id);
+        }
+    }
+}
+
+/// Five-tuple of a L2 or L3 packet, if it is an IP packet
+pub(crate) fn five_tuple(packet_data: &PacketData) -> Option<FiveTuple> {
+    match *packet_data {
+        PacketData::L2(data) => filter_utils::extract_callback_ethernet(
+            &key_parser_ipv4::parse_five_tuple,
+            &key_parser_ipv6::parse_five_tuple,
+            data,
+        )
+        .ok(),
+        PacketData::L3(ethertype, data) => match EtherType::new(ethertype) {
+            EtherTypes::Ipv4 => key_parser_ipv4::parse_five_tuple(data).ok(),
+            EtherTypes::Ipv6 => key_parser_ipv6::parse_five_tuple(data).ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Fragment information of a L2 or L3 packet, if it is an IP fragment
+fn fragment(packet_data: &PacketData) -> Option<FragmentInfo> {
+    match *packet_data {
+        PacketData::L2(data) => filter_utils::extract_callback_ethernet(
+            &key_parser_ipv4::parse_fragment,
+            &key_parser_ipv6::parse_fragment,
+            data,
+        )
+        .unwrap_or(None),
+        PacketData::L3(ethertype, data) => match EtherType::new(ethertype) {
+            EtherTypes::Ipv4 => key_parser_ipv4::parse_fragment(data).unwrap_or(None),
+            EtherTypes::Ipv6 => key_parser_ipv6::parse_fragment(data).unwrap_or(None),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Key of a bidirectional flow: the smallest of the five-tuple and its reverse
+fn flow_key(packet_data: &PacketData) -> Option<FiveTuple> {
+    let five_tuple = five_tuple(packet_data)?;
+    let reverse = five_tuple.get_reverse();
+    Some(five_tuple.min(reverse))
+}
+
+fn addr_to_file_name(addr: &IpAddr) -> String {
+    addr.to_string().replace(':', "_")
+}
+
+fn proto_name(proto: u8) -> String {
+    match proto {
+        1 => "icmp".to_string(),
+        6 => "tcp".to_string(),
+        17 => "udp".to_string(),
+        58 => "icmp6".to_string(),
+        132 => "sctp".to_string(),
+        _ => format!("proto{}", proto),
+    }
+}
+
+/// Output writing one file per flow, in a directory
+///
+/// Files are named from the five-tuple, for ex. `tcp-192.168.1.1.34567-192.168.1.2.80.pcap`
+/// (`:` are replaced by `_` in IPv6 addresses). Packets with no five-tuple (for ex. ARP)
+/// are written to `other.pcap`.
+///
+/// Fragments of an IP datagram are written to the file of its first fragment. Fragments captured
+/// before the first fragment of their datagram are written to `other.pcap`.
+///
+/// Interfaces of the input file are not copied: with the pcapng format, each output file has
+/// a single interface.
+pub struct FlowOutput {
+    output_dir: PathBuf,
+    output_format: FileFormat,
+    snaplen: usize,
+    linktype: Linktype,
+    nanosecond: bool,
+    /// File ID of each flow, once its file is created
+    flows: HashMap<Option<FiveTuple>, usize>,
+    /// Writers of the recently used flows, with the tick of their last use
+    writers: HashMap<Option<FiveTuple>, (Box<dyn Writer>, u64)>,
+    max_writers: usize,
+    tick: u64,
+    fragments: FragmentFlows,
+    pool: Rc<RefCell<FilePool>>,
+}
+
+impl FlowOutput {
+    pub fn new(output_dir: &Path, output_format: FileFormat, max_open_files: usize) -> Self {
+        let pool = FilePool {
+            max_open_files: max_open_files.max(1),
+            open: HashMap::new(),
+            tick: 0,
+            discard: None,
+        };
+        FlowOutput {
+            output_dir: output_dir.to_path_buf(),
+            output_format,
+            snaplen: 65535,
+            linktype: Linktype::RAW,
+            nanosecond: false,
+            flows: HashMap::new(),
+            writers: HashMap::new(),
+            max_writers: max_open_files.max(1),
+            tick: 0,
+            fragments: FragmentFlows::default(),
+            pool: Rc::new(RefCell::new(pool)),
+        }
+    }
+
+    /// Set the parameters of output files. `nanosecond` is the timestamp precision of the input
+    pub fn init(&mut self, snaplen: usize, linktype: Linktype, nanosecond: bool) {
+        self.snaplen = snaplen;
+        self.linktype = linktype;
+        self.nanosecond = nanosecond;
+    }
+
+    fn file_name(&self, key: &Option<FiveTuple>) -> String {
+        let ext = match self.output_format {
+            FileFormat::Pcap => "pcap",
+            FileFormat::PcapNG => "pcapng",
+        };
+        match key {
+            Some(t) => format!(
+                "{}-{}.{}-{}.{}.{}",
+                proto_name(t.proto),
+                addr_to_file_name(&t.src),
+                t.src_port,
+                addr_to_file_name(&t.dst),
+                t.dst_port,
+                ext
+            ),
+            None => format!("other.{}", ext),
+        }
+    }
+
+    /// Key of the flow of a packet. Fragments get the key of the first fragment of their
+    /// datagram
+    fn packet_key(&mut self, packet_data: &PacketData) -> Option<FiveTuple> {
+        let fragment = match fragment(packet_data) {
+            Some(fragment) => fragment,
+            None => return flow_key(packet_data),
+        };
+        if fragment.first {
+            let key = flow_key(packet_data);
+            self.fragments.insert(fragment.id, key.clone());
+            key
+        } else if fragment.last {
+            self.fragments.remove(&fragment.id)
+        } else {
+            self.fragments.get(&fragment.id)
+        }
+    }
+
+    /// Create a writer to `file`, and write the file header. Returns the writer and the number
+    /// of bytes written
+    fn new_writer(&self, file: PooledFile) -> Result<(Box<dyn Writer>, usize), io::Error> {
+        match self.output_format {
+            FileFormat::Pcap => {
+                let mut writer = PcapWriter::new(Box::new(file));
+                writer.set_nanosecond_precision(self.nanosecond);
+                let written = writer.init_file(self.snaplen, self.linktype)?;
+                Ok((Box::new(writer), written))
+            }
+            FileFormat::PcapNG => {
+                let mut writer = PcapNGWriter::new(Box::new(file));
+                writer.set_nanosecond_precision(self.nanosecond);
+                let mut written = writer.init_file(self.snaplen, self.linktype)?;
+                // write the interface now, so the header is complete before the first packet
+                written += writer.default_interface()?.1;
+                Ok((Box::new(writer), written))
+            }
+        }
+    }
+
+    /// Create the writer of a flow. If the file of the flow was already created, it is reopened
+    /// and the header is not written again. Returns the number of bytes written
+    fn open_writer(&mut self, key: &Option<FiveTuple>) -> Result<usize, io::Error> {
+        if self.writers.len() >= self.max_writers {
+            self.close_lru_writer()?;
+        }
+        let path = self.output_dir.join(self.file_name(key));
+        let (id, created) = match self.flows.get(key) {
+            Some(id) => {
+                debug!("Reopening flow output file {}", path.display());
+                (*id, true)
+            }
+            None => {
+                debug!("New flow output file {}", path.display());
+                let id = self.flows.len();
+                self.flows.insert(key.clone(), id);
+                (id, false)
+            }
+        };
+        let file = PooledFile {
+            id,
+            path,
+            created,
+            pool: self.pool.clone(),
+        };
+        if created {
+            self.pool.borrow_mut().discard = Some(id);
+        }
+        let res = self.new_writer(file);
+        self.pool.borrow_mut().discard = None;
+        let (writer, written) = res?;
+        self.writers.insert(key.clone(), (writer, self.tick));
+        Ok(if created { 0 } else { written })
+    }
+
+    /// Finish and drop the least recently used writer
+    fn close_lru_writer(&mut self) -> Result<(), io::Error> {
+        let lru_key = self
+            .writers
+            .iter()
+            .min_by_key(|(_, (_, last_use))| *last_use)
+            .map(|(key, _)| key.clone());
+        if let Some((mut writer, _)) = lru_key.and_then(|key| self.writers.remove(&key)) {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+
+    /// Write packet to the file of its flow. `packet_data` is used to find the flow
+    pub fn write_packet(
+        &mut self,
+        packet: &Packet,
+        packet_data: &PacketData,
+        data: &[u8],
+        origlen: u32,
+    ) -> Result<usize, io::Error> {
+        let key = self.packet_key(packet_data);
+        self.tick += 1;
+        let mut written = 0;
+        if !self.writers.contains_key(&key) {
+            written += self.open_writer(&key)?;
+        }
+        let (writer, last_use) = self.writers.get_mut(&key).expect("writer was just created");
+        *last_use = self.tick;
+        written += writer.write_packet(packet, data, origlen)?;
+        Ok(written)
+    }
+
+    /// Write buffered data of all flows
+    pub fn finish(&mut self) -> Result<(), io::Error> {
+        self.writers
+            .values_mut()
+            .try_for_each(|(writer, _)| writer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn id(ip_id: u32) -> TwoTupleProtoIpid {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        TwoTupleProtoIpid::new(addr, addr, 17, ip_id)
+    }
+
+    fn key(port: u16) -> Option<FiveTuple> {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        Some(FiveTuple {
+            proto: 17,
+            src: addr,
+            dst: addr,
+            src_port: port,
+            dst_port: port,
+        })
+    }
+
+    #[test]
+    fn fragment_flows_evict_oldest() {
+        let mut fragments = FragmentFlows::default();
+        fragments.insert(id(0), key(1));
+        fragments.insert(id(1), key(1));
+        // datagram 0 is completed, then seen again: it is now the newest datagram
+        assert_eq!(fragments.remove(&id(0)), key(1));
+        fragments.insert(id(0), key(2));
+        for i in 2..MAX_FRAGMENTED_DATAGRAMS as u32 {
+            fragments.insert(id(i), key(1));
+        }
+        assert_eq!(fragments.flows.len(), MAX_FRAGMENTED_DATAGRAMS);
+
+        // only the oldest datagram is forgotten
+        fragments.insert(id(MAX_FRAGMENTED_DATAGRAMS as u32), key(1));
+        assert_eq!(fragments.get(&id(1)), None);
+        assert_eq!(fragments.get(&id(0)), key(2));
+        assert_eq!(fragments.get(&id(2)), key(1));
+        assert_eq!(fragments.flows.len(), MAX_FRAGMENTED_DATAGRAMS);
+    }
+}
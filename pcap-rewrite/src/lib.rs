@@ -1,33 +1,91 @@
-use std::fs::File;
-use std::io::{self, Read};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use flate2::read::GzDecoder;
-use libpcap_tools::{Config, PcapDataEngine, PcapEngine};
-use log::{error, info};
-use xz2::read::XzDecoder;
+use libpcap_tools::{
+    decompress_reader, Compression, Config, PcapDataEngine, PcapEngine, ProgressReporter,
+};
+use log::{error, info, warn};
+use pcap_parser::Linktype;
 
+pub mod comments;
+pub mod compression;
 mod container;
+pub mod convert;
 pub mod filters;
+mod flow_output;
+mod link_layer;
+mod merge;
 mod pcap;
-mod pcapng;
+mod pcapng_writer;
+pub mod repair;
+pub mod replay;
 pub mod rewriter;
+pub mod stats;
 mod traits;
+pub mod transforms;
 
-use rewriter::{FileFormat, Rewriter};
+use comments::PacketComments;
+use compression::{OutputCompression, OutputStream};
+use convert::{ConvertOptions, ConvertReport};
+use flow_output::FlowOutput;
+use repair::RepairReport;
+use replay::{ReplayOptions, ReplayWriter};
+use rewriter::{FileFormat, Rewriter, SplitPolicy};
+use stats::RewriteStats;
 
 pub struct RewriteOptions {
     pub output_format: FileFormat,
+    /// Output link type: `RAW` (L3) or `ETHERNET` (L2)
+    pub output_linktype: Linktype,
     pub config: Config,
+    /// Split output into multiple files
+    pub split: SplitPolicy,
+    /// Write each flow to its own file, in the output directory
+    pub split_flows: bool,
+    /// Maximum number of files (and flow writers) open at the same time, when splitting flows
+    pub max_open_files: usize,
+    /// Write packets dropped by filters to this file
+    pub rejected_output: Option<String>,
+    /// Compression of output files (not supported when splitting flows)
+    pub compression: OutputCompression,
+    /// Truncate output packets to this number of bytes (the original length is kept)
+    pub snaplen: Option<usize>,
+    /// Add an Ethernet header to packets without one (for ex. Linux SLL), if the output link
+    /// type is `ETHERNET`
+    pub convert_linktype: bool,
+    /// Add comments to output packets (pcapng output only, not supported when splitting flows)
+    pub comments: Option<PacketComments>,
+    /// Show a progress bar on the standard error
+    pub progress: bool,
+    /// Stop reading input when set to `true`. Output files are still closed properly
+    pub stop: Option<Arc<AtomicBool>>,
 }
 
 /// Rewrite input file applying filters
 ///
 /// - `input_filename` must be a Pcap or Pcap-NG file. If using the special value "-", standard input will be used (see notes below)
-/// - `output_filename` will be created, or truncated if the file exists
+/// - `output_filename` will be created, or truncated if the file exists. If using the special value "-", standard output will be used
 /// - `filters` is an ordered list of [`Filter`](filters::filter::Filter) to apply. It may be empty
+/// - `transforms` is an ordered list of [`Transform`](transforms::transform::Transform) applied to
+///   packets accepted by filters. It may be empty
 /// - `options` are used to specify output format and other options
 ///
+/// If output is split (see [`SplitPolicy`]), a number is inserted before the extension of `output_filename`
+/// for each file, for ex. `out_00001.pcap`, `out_00002.pcap`, etc.
+///
+/// If flows are split, `output_filename` is a directory (created if needed) where one file per flow is written.
+///
+/// If `options.rejected_output` is set, packets dropped by filters are written to this file (never split),
+/// using the same format, link type and transformations as the output.
+///
+/// If `options.comments` is set, comments are added to the packets written to the output. Comments of input
+/// packets are kept with the pcapng format.
+///
+/// Returns the statistics of the run.
+///
 /// # Notes
 ///
 /// `pcap-rewrite` tries to rewrite the file in a single pass. However, some plugins require a pre-analysis pass.
@@ -36,30 +94,320 @@ pub fn pcap_rewrite_file<S1: AsRef<str>, S2: AsRef<str>>(
     input_filename: S1,
     output_filename: S2,
     filters: Vec<Box<dyn filters::filter::Filter>>,
+    transforms: Vec<Box<dyn transforms::transform::Transform>>,
     options: &RewriteOptions,
-) -> Result<(), io::Error> {
+) -> Result<RewriteStats, io::Error> {
     let input_filename = input_filename.as_ref();
     let output_filename = output_filename.as_ref();
-    let mut input_reader = get_reader(input_filename)?;
-    let path = Path::new(output_filename);
-    let outfile = File::create(path)?;
+    let input_reader = get_reader(input_filename)?;
+
+    if output_filename == "-" {
+        if options.split.is_enabled() || options.split_flows {
+            const MSG: &str = "Standard output cannot be used when splitting output";
+            error!("{}", MSG);
+            return Err(io::Error::new(io::ErrorKind::Other, MSG));
+        }
+        if options.rejected_output.as_deref() == Some("-") {
+            const MSG: &str = "Output and rejected output cannot both use standard output";
+            error!("{}", MSG);
+            return Err(io::Error::new(io::ErrorKind::Other, MSG));
+        }
+    }
+
+    if options.comments.is_some() {
+        if let FileFormat::Pcap = options.output_format {
+            const MSG: &str = "Comments can only be added with the pcapng output format";
+            error!("{}", MSG);
+            return Err(io::Error::new(io::ErrorKind::Other, MSG));
+        }
+        if options.split_flows {
+            const MSG: &str = "Comments cannot be added when splitting flows";
+            error!("{}", MSG);
+            return Err(io::Error::new(io::ErrorKind::Other, MSG));
+        }
+    }
+
+    if options.split_flows {
+        if options.split.is_enabled() {
+            const MSG: &str = "Output cannot be split by flow and by size at the same time";
+            error!("{}", MSG);
+            return Err(io::Error::new(io::ErrorKind::Other, MSG));
+        }
+        if options.compression != OutputCompression::None {
+            const MSG: &str = "Output cannot be compressed when splitting flows";
+            error!("{}", MSG);
+            return Err(io::Error::new(io::ErrorKind::Other, MSG));
+        }
+        let output_dir = Path::new(output_filename);
+        fs::create_dir_all(output_dir)?;
+        let mut rewriter = Rewriter::new(Box::new(io::sink()), options.output_format, filters);
+        rewriter.set_output_linktype(options.output_linktype);
+        rewriter.set_convert_linktype(options.convert_linktype);
+        rewriter.set_transforms(transforms);
+        if let Some(snaplen) = options.snaplen {
+            rewriter.set_snaplen(snaplen);
+        }
+        rewriter.set_flow_output(FlowOutput::new(
+            output_dir,
+            options.output_format,
+            options.max_open_files,
+        ));
+        set_rejected_output(&mut rewriter, options)?;
+        return run_rewriter(rewriter, input_filename, input_reader, options);
+    }
+
+    let first_output_filename = if options.split.is_enabled() {
+        split_file_name(output_filename, 1)
+    } else {
+        output_filename.to_owned()
+    };
+    let outfile = options
+        .compression
+        .compress_writer(create_output(&first_output_filename)?)?;
 
     // let block_analyzer = BlockRewriter::new(outfile);
     // let mut engine = BlockEngine::new(block_analyzer, &config);
 
-    let rewriter = Rewriter::new(Box::new(outfile), options.output_format, filters);
+    let mut rewriter = Rewriter::new(outfile, options.output_format, filters);
+    rewriter.set_output_linktype(options.output_linktype);
+    rewriter.set_convert_linktype(options.convert_linktype);
+    rewriter.set_transforms(transforms);
+    if let Some(snaplen) = options.snaplen {
+        rewriter.set_snaplen(snaplen);
+    }
+    if let Some(comments) = &options.comments {
+        rewriter.set_comments(comments.clone());
+    }
+    set_rejected_output(&mut rewriter, options)?;
+    if options.split.is_enabled() {
+        let output_filename = output_filename.to_owned();
+        let compression = options.compression;
+        let mut index = 1;
+        rewriter.set_split(
+            options.split,
+            Box::new(move || {
+                index += 1;
+                let filename = split_file_name(&output_filename, index);
+                info!("Rotating output to {}", filename);
+                compression.compress_writer(create_output(&filename)?)
+            }),
+        );
+    }
+    run_rewriter(rewriter, input_filename, input_reader, options)
+}
+
+/// Replay input file on a network interface, applying filters and transformations
+///
+/// - `input_filename` must be a Pcap or Pcap-NG file. If using the special value "-", standard input will be used
+/// - `filters` and `transforms` are applied as in [`pcap_rewrite_file`]
+/// - `options` must use the `ETHERNET` output link type. Options of output files (split, rejected output,
+///   compression and comments) are not supported
+/// - `replay` gives the network interface and the speed of the replay
+///
+/// See [`replay`] for the timing of packets. Returns the statistics of the run.
+pub fn pcap_replay_file<S: AsRef<str>>(
+    input_filename: S,
+    filters: Vec<Box<dyn filters::filter::Filter>>,
+    transforms: Vec<Box<dyn transforms::transform::Transform>>,
+    options: &RewriteOptions,
+    replay: &ReplayOptions,
+) -> Result<RewriteStats, io::Error> {
+    let input_filename = input_filename.as_ref();
+    if options.output_linktype != Linktype::ETHERNET {
+        const MSG: &str = "Packets can only be replayed with the ethernet output link type";
+        error!("{}", MSG);
+        return Err(io::Error::new(io::ErrorKind::Other, MSG));
+    }
+    if options.split.is_enabled()
+        || options.split_flows
+        || options.rejected_output.is_some()
+        || options.compression != OutputCompression::None
+        || options.comments.is_some()
+    {
+        const MSG: &str = "Options of output files are not supported when replaying packets";
+        error!("{}", MSG);
+        return Err(io::Error::new(io::ErrorKind::Other, MSG));
+    }
+    let input_reader = get_reader(input_filename)?;
+    let writer = ReplayWriter::open(replay, options.stop.clone()).map_err(|e| {
+        error!("Could not open network interface '{}'", replay.interface);
+        e
+    })?;
+
+    let mut rewriter = Rewriter::with_writer(Box::new(writer), filters);
+    rewriter.set_output_linktype(options.output_linktype);
+    rewriter.set_convert_linktype(options.convert_linktype);
+    rewriter.set_transforms(transforms);
+    if let Some(snaplen) = options.snaplen {
+        rewriter.set_snaplen(snaplen);
+    }
+    info!(
+        "Replaying packets on {} (speed: {})",
+        replay.interface, replay.speed
+    );
+    run_rewriter(rewriter, input_filename, input_reader, options)
+}
+
+/// Merge input files, writing packets in chronological order
+///
+/// - `input_filenames` must be Pcap or Pcap-NG files. Packets are copied without modification
+/// - `output_filename` will be created, or truncated if the file exists
+/// - `compression` is applied to the output file
+///
+/// With pcapng output, one interface is created for each input interface, so inputs can have
+/// different link types, and timestamps are written in nanoseconds. With pcap output, all inputs
+/// must have the same link type, and timestamps are written in nanoseconds if one of the inputs
+/// has a better resolution than microseconds.
+pub fn pcap_merge_files<S1: AsRef<str>, S2: AsRef<str>>(
+    input_filenames: &[S1],
+    output_filename: S2,
+    output_format: FileFormat,
+    compression: OutputCompression,
+) -> Result<(), io::Error> {
+    if input_filenames.iter().any(|f| f.as_ref() == "-") {
+        const MSG: &str = "Standard input cannot be used when merging files";
+        error!("{}", MSG);
+        return Err(io::Error::new(io::ErrorKind::Other, MSG));
+    }
+    let inputs = input_filenames
+        .iter()
+        .map(|f| get_reader(f.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let outfile = create_output(output_filename.as_ref())?;
+    info!(
+        "Merging {} files (output format: {:?})",
+        input_filenames.len(),
+        output_format
+    );
+    let outfile = compression.compress_writer(outfile)?;
+    let count = merge::merge(inputs, outfile, output_format)?;
+    info!("Merged {} packets", count);
+    Ok(())
+}
+
+/// Convert input file to another format, copying packets without modification
+///
+/// - `input_filename` must be a Pcap or Pcap-NG file. If using the special value "-", standard input will be used
+/// - `output_filename` will be created, or truncated if the file exists. If using the special value "-", standard output will be used
+/// - `options` are used to specify output format, and how link types and timestamps are converted
+/// - `compression` is applied to output files
+///
+/// If packets with different link types are written to separate files (see
+/// [`ConvertOptions::split_linktypes`]), packets with the link type of the first packet are written to
+/// `output_filename`, and the link type is inserted before the extension of `output_filename` for
+/// the other files, for ex. `out_linktype_113.pcap`.
+///
+/// See [`convert`](convert::convert) for the conversion of metadata. Returns the report of the
+/// conversion.
+pub fn pcap_convert_file<S1: AsRef<str>, S2: AsRef<str>>(
+    input_filename: S1,
+    output_filename: S2,
+    options: &ConvertOptions,
+    compression: OutputCompression,
+) -> Result<ConvertReport, io::Error> {
+    let output_filename = output_filename.as_ref();
+    if output_filename == "-" && options.split_linktypes {
+        const MSG: &str = "Standard output cannot be used when splitting output";
+        error!("{}", MSG);
+        return Err(io::Error::new(io::ErrorKind::Other, MSG));
+    }
+    let input_reader = get_reader(input_filename.as_ref())?;
+    let outfile = compression.compress_writer(create_output(output_filename)?)?;
+    let mut open_output = |linktype: Linktype| {
+        let filename = suffixed_file_name(output_filename, &format!("linktype_{}", linktype.0));
+        info!(
+            "Writing packets with link type {} to {}",
+            linktype.0, filename
+        );
+        compression.compress_writer(create_output(&filename)?)
+    };
+    info!(
+        "Converting file (output format: {:?})",
+        options.output_format
+    );
+    convert::convert(input_reader, outfile, options, &mut open_output)
+}
+
+/// Repair a damaged input file, skipping corrupt blocks and a truncated last block
+///
+/// - `input_filename` must be a Pcap or Pcap-NG file. If using the special value "-", standard input will be used
+/// - `output_filename` will be created, or truncated if the file exists. It has the same format as the input,
+///   and valid blocks are copied without modification
+/// - `compression` is applied to the output file
+///
+/// See [`repair`](repair::repair) for the detection of corrupt blocks. Returns the report of the
+/// repair, with the data which was dropped.
+pub fn pcap_repair_file<S1: AsRef<str>, S2: AsRef<str>>(
+    input_filename: S1,
+    output_filename: S2,
+    compression: OutputCompression,
+) -> Result<RepairReport, io::Error> {
+    let input_reader = get_reader(input_filename.as_ref())?;
+    let outfile = compression.compress_writer(create_output(output_filename.as_ref())?)?;
+    info!("Repairing file");
+    repair::repair(input_reader, outfile)
+}
+
+fn set_rejected_output(rewriter: &mut Rewriter, options: &RewriteOptions) -> Result<(), io::Error> {
+    if let Some(rejected_filename) = &options.rejected_output {
+        let outfile = options
+            .compression
+            .compress_writer(create_output(rejected_filename)?)?;
+        rewriter.set_rejected_output(outfile, options.output_format);
+    }
+    Ok(())
+}
+
+/// Run the engine on the input, converting errors to I/O errors
+fn run_engine(
+    engine: &mut PcapDataEngine<Rewriter>,
+    input_reader: &mut Box<dyn Read>,
+) -> Result<(), io::Error> {
+    engine.run(input_reader).map_err(|e| {
+        error!("Could not rewrite input: {}", e);
+        match e {
+            libpcap_tools::Error::IoError(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+        }
+    })
+}
+
+fn run_rewriter(
+    rewriter: Rewriter,
+    input_filename: &str,
+    mut input_reader: Box<dyn Read>,
+    options: &RewriteOptions,
+) -> Result<RewriteStats, io::Error> {
     let mut engine = PcapDataEngine::new(rewriter, &options.config);
+    if options.progress {
+        let mut reporter = ProgressReporter::stderr();
+        reporter.set_total_bytes(input_size(input_filename));
+        engine.set_progress_reporter(reporter);
+    }
+    if let Some(stop) = &options.stop {
+        engine.set_stop_handle(stop.clone());
+    }
 
-    if engine.data_analyzer().require_pre_analysis() {
-        // check that we are not using stdin
-        if input_filename == "-" {
+    // stdin cannot be read twice
+    if engine.data_analyzer().require_pre_analysis() && input_filename == "-" {
+        if !engine.data_analyzer().optional_pre_analysis() {
             const MSG: &str = "Plugins with pre-analysis pass cannot be run on stdin";
             error!("{}", MSG);
             return Err(io::Error::new(io::ErrorKind::Other, MSG));
         }
+        warn!("Input is stdin, skipping pre-analysis pass");
+    } else if engine.data_analyzer().require_pre_analysis() {
         info!("Running pre-analysis pass");
         engine.data_analyzer_mut().set_run_pre_analysis(true);
-        engine.run(&mut input_reader).expect("run analyzer");
+        run_engine(&mut engine, &mut input_reader)?;
+        if options
+            .stop
+            .as_ref()
+            .map_or(false, |stop| stop.load(Ordering::Relaxed))
+        {
+            warn!("Interrupted during pre-analysis, no packet written");
+            return Ok(engine.data_analyzer_mut().take_stats());
+        }
         // reset reader
         input_reader = get_reader(input_filename)?;
     }
@@ -68,13 +416,69 @@ pub fn pcap_rewrite_file<S1: AsRef<str>, S2: AsRef<str>>(
         "Rewriting file (output format: {:?})",
         options.output_format
     );
-    engine.run(&mut input_reader).expect("run analyzer");
+    run_engine(&mut engine, &mut input_reader)?;
+    engine.data_analyzer_mut().finish().map_err(|e| {
+        error!("Could not write output: {}", e);
+        e
+    })?;
 
-    Ok(())
+    Ok(engine.data_analyzer_mut().take_stats())
+}
+
+/// Build the name of a split output file: `out.pcap` becomes `out_00001.pcap`
+fn split_file_name(output_filename: &str, index: usize) -> String {
+    suffixed_file_name(output_filename, &format!("{:05}", index))
+}
+
+/// Insert `suffix` before the extension of `output_filename`: `out.pcap` becomes `out_suffix.pcap`
+fn suffixed_file_name(output_filename: &str, suffix: &str) -> String {
+    let path = Path::new(output_filename);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path
+            .with_file_name(format!(
+                "{}_{}.{}",
+                stem.to_string_lossy(),
+                suffix,
+                ext.to_string_lossy()
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        _ => format!("{}_{}", output_filename, suffix),
+    }
+}
+
+/// Create output file, or use standard output if `output_filename` is "-"
+///
+/// Output is buffered. Writers never seek, so any output (for ex. a pipe) can be used.
+fn create_output(output_filename: &str) -> io::Result<Box<dyn OutputStream>> {
+    if output_filename == "-" {
+        return Ok(Box::new(BufWriter::new(io::stdout())));
+    }
+    let file = File::create(Path::new(output_filename)).map_err(|e| {
+        error!("Could not create output file '{}'", output_filename);
+        e
+    })?;
+    Ok(Box::new(BufWriter::new(file)))
 }
 
+/// Return the size of the input file, if known (regular and uncompressed file)
+fn input_size(input_filename: &str) -> Option<u64> {
+    if input_filename == "-" {
+        return None;
+    }
+    let mut file = File::open(input_filename).ok()?;
+    let metadata = file.metadata().ok()?;
+    let mut magic = [0; 6];
+    let len = file.read(&mut magic).ok()?;
+    match Compression::of_magic(&magic[..len]) {
+        Compression::None if metadata.is_file() => Some(metadata.len()),
+        _ => None,
+    }
+}
+
+/// Open input file (or standard input), decompressing it if needed
 fn get_reader(input_filename: &str) -> io::Result<Box<dyn Read>> {
-    let input_reader = if input_filename == "-" {
+    let input_reader: Box<dyn Read> = if input_filename == "-" {
         Box::new(io::stdin())
     } else {
         let path = Path::new(&input_filename);
@@ -82,13 +486,7 @@ fn get_reader(input_filename: &str) -> io::Result<Box<dyn Read>> {
             error!("Could not open input file '{}'", input_filename);
             e
         })?;
-        if input_filename.ends_with(".gz") {
-            Box::new(GzDecoder::new(file))
-        } else if input_filename.ends_with(".xz") {
-            Box::new(XzDecoder::new(file))
-        } else {
-            Box::new(file) as Box<dyn io::Read>
-        }
+        Box::new(file)
     };
-    Ok(input_reader)
+    decompress_reader(input_reader)
 }
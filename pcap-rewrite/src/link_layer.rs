@@ -0,0 +1,102 @@
+use pcap_parser::data::PacketData;
+use pcap_parser::Linktype;
+use pnet_packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet_packet::PrimitiveValues;
+
+/// Size of the LINKTYPE_NULL and LINKTYPE_LOOP headers
+const NULL_HEADER_SIZE: usize = 4;
+/// Size of the LINKTYPE_LINUX_SLL header
+const SLL_HEADER_SIZE: usize = 16;
+/// Size of the LINKTYPE_LINUX_SLL2 header
+const SLL2_HEADER_SIZE: usize = 20;
+
+/// BSD address family of IPv4
+const AF_INET: u32 = 2;
+/// BSD address families of IPv6 (the value depends on the OS)
+const AF_INET6: &[u32] = &[10, 24, 28, 30];
+
+/// Decode the link layer of packets which are not Ethernet
+///
+/// Packets captured on Linux "any" interfaces (SLL and SLL2), on BSD loopback interfaces (NULL
+/// and LOOP), or without link layer (RAW, IPV4, IPV6) are returned as L3 data, so they can be
+/// filtered like Ethernet packets. Other packets are returned unchanged.
+pub fn decode_link_layer(link_type: Linktype, packet_data: PacketData) -> PacketData {
+    match packet_data {
+        PacketData::Unsupported(data) => match parse_link_layer(link_type, data) {
+            Some((ethertype, l3_data)) => PacketData::L3(ethertype, l3_data),
+            None => packet_data,
+        },
+        // unknown protocol (for ex. unknown address family): guess it from the IP version
+        PacketData::L3(0, data) => match ethertype_of_ip_version(data) {
+            Some(ethertype) => PacketData::L3(ethertype, data),
+            None => packet_data,
+        },
+        _ => packet_data,
+    }
+}
+
+/// Return the ethertype and the L3 data of a packet with link type `link_type`
+fn parse_link_layer(link_type: Linktype, data: &[u8]) -> Option<(u16, &[u8])> {
+    match link_type {
+        Linktype::NULL | Linktype::LOOP => {
+            if data.len() < NULL_HEADER_SIZE {
+                return None;
+            }
+            let header = [data[0], data[1], data[2], data[3]];
+            // NULL uses the byte order of the capturing host, LOOP uses network byte order
+            let family = if link_type == Linktype::LOOP {
+                u32::from_be_bytes(header)
+            } else {
+                let family = u32::from_le_bytes(header);
+                if family > 0xffff {
+                    family.swap_bytes()
+                } else {
+                    family
+                }
+            };
+            let l3_data = &data[NULL_HEADER_SIZE..];
+            let ethertype = match family {
+                AF_INET => EtherTypes::Ipv4.to_primitive_values().0,
+                f if AF_INET6.contains(&f) => EtherTypes::Ipv6.to_primitive_values().0,
+                _ => ethertype_of_ip_version(l3_data)?,
+            };
+            Some((ethertype, l3_data))
+        }
+        Linktype::LINUX_SLL => {
+            if data.len() < SLL_HEADER_SIZE {
+                return None;
+            }
+            let protocol = u16::from_be_bytes([data[14], data[15]]);
+            Some((protocol, &data[SLL_HEADER_SIZE..]))
+        }
+        Linktype::LINUX_SLL2 => {
+            if data.len() < SLL2_HEADER_SIZE {
+                return None;
+            }
+            let protocol = u16::from_be_bytes([data[0], data[1]]);
+            Some((protocol, &data[SLL2_HEADER_SIZE..]))
+        }
+        Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => {
+            Some((ethertype_of_ip_version(data)?, data))
+        }
+        _ => None,
+    }
+}
+
+fn ethertype_of_ip_version(data: &[u8]) -> Option<u16> {
+    match data.first().map(|b| b >> 4) {
+        Some(4) => Some(EtherTypes::Ipv4.to_primitive_values().0),
+        Some(6) => Some(EtherTypes::Ipv6.to_primitive_values().0),
+        _ => None,
+    }
+}
+
+/// Build an Ethernet frame containing L3 `data`, with null MAC addresses
+pub fn ethernet_of_l3(ethertype: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; EthernetPacket::minimum_packet_size() + data.len()];
+    // the buffer is large enough for the header
+    let mut ethernet_packet = MutableEthernetPacket::new(&mut buf).unwrap();
+    ethernet_packet.set_ethertype(EtherType::new(ethertype));
+    ethernet_packet.set_payload(data);
+    buf
+}
@@ -4,17 +4,65 @@
 use clap::{crate_version, App, Arg};
 use libpcap_tools::Config;
 use log::{debug, error};
+use pcap_parser::Linktype;
 use std::fs::File;
 use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use pcap_rewrite::filters::dispatch_filter::DispatchFilterBuilder;
+use pcap_rewrite::comments::PacketComments;
+use pcap_rewrite::compression::OutputCompression;
+use pcap_rewrite::convert::ConvertOptions;
+use pcap_rewrite::filters::bpf_filter::BpfFilter;
+use pcap_rewrite::filters::dedup_filter::DedupFilter;
+use pcap_rewrite::filters::dispatch_filter::{parse_flow, DispatchFilterBuilder};
+use pcap_rewrite::filters::filter_config::filters_of_file_path;
 use pcap_rewrite::filters::filtering_action::FilteringAction;
 use pcap_rewrite::filters::filtering_key::FilteringKey;
 use pcap_rewrite::filters::fragmentation::fragmentation_filter::FragmentationFilterBuilder;
+use pcap_rewrite::filters::index_filter::{parse_index_ranges, IndexFilter};
+use pcap_rewrite::filters::sampling_filter::{SamplingFilter, SamplingMode};
+use pcap_rewrite::replay::ReplayOptions;
 use pcap_rewrite::rewriter::*;
+use pcap_rewrite::transforms::anonymize::{ip_mapping_of_file_path, CryptoPan, IpAnonymizer};
+use pcap_rewrite::transforms::checksum::ChecksumFixer;
+use pcap_rewrite::transforms::mac::{mac_mapping_of_file_path, MacRewriter};
+use pcap_rewrite::transforms::payload::{PayloadAction, PayloadScrubber};
+use pcap_rewrite::transforms::transform::Transform;
 use pcap_rewrite::{filters, RewriteOptions};
 
+/// Options of the rewrite of a single file (not supported when merging or repairing files)
+const REWRITE_OPTIONS: &[&str] = &[
+    "filters",
+    "filter-config",
+    "bpf",
+    "flow",
+    "packets",
+    "comments",
+    "dedup",
+    "sample",
+    "sample-prob",
+    "anonymize-key",
+    "ip-map",
+    "mac-map",
+    "randomize-mac",
+    "truncate-payload",
+    "zero-payload",
+    "fix-checksums",
+    "snaplen",
+    "output-linktype",
+    "convert-linktype",
+    "split-size",
+    "split-count",
+    "split-flows",
+    "rejected-output",
+    "stats-out",
+    "progress",
+    "replay",
+    "replay-speed",
+];
+
 fn load_config(config: &mut Config, filename: &str) -> Result<(), io::Error> {
     debug!("Loading configuration {}", filename);
     let path = Path::new(&filename);
@@ -25,6 +73,43 @@ fn load_config(config: &mut Config, filename: &str) -> Result<(), io::Error> {
     config.load_config(file)
 }
 
+fn parse_positive_value(value: Option<&str>, name: &str) -> Result<Option<u64>, io::Error> {
+    match value {
+        None => Ok(None),
+        Some(s) => match s.parse::<u64>() {
+            Ok(v) if v > 0 => Ok(Some(v)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Invalid {}: {}", name, s),
+            )),
+        },
+    }
+}
+
+/// Write statistics to `stats_filename` (if set), in JSON format
+fn write_stats(stats_filename: Option<&str>, stats: &serde_json::Value) -> io::Result<()> {
+    if let Some(stats_filename) = stats_filename {
+        let file = File::create(stats_filename)?;
+        serde_json::to_writer_pretty(file, stats)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    }
+    Ok(())
+}
+
+/// Return a flag set to `true` on the first interruption (Ctrl-C). Exit on the second one
+fn interrupt_handle() -> io::Result<Arc<AtomicBool>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let handle = stop.clone();
+    ctrlc::set_handler(move || {
+        if handle.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, closing output files (press Ctrl-C again to exit now)");
+    })
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok(stop)
+}
+
 fn main() -> io::Result<()> {
     let matches = App::new("Pcap rewrite tool")
         .version(crate_version!())
@@ -35,23 +120,40 @@ fn main() -> io::Result<()> {
                 .help(
                     "Filters to load (default: none)
 Arguments can be specified using : after the filter name.
+Several filters can be given. They are applied in order, and a packet is written
+only if all filters keep it.
 Examples:
 -f Source:192.168.1.1
 -f Dispatch:fk%fa%path
+-f Dispatch:fk%fa%path%ua
 -f Dispatch:fk%fa
+-f Dispatch:si%k%subnets -f Dispatch:dpr%d%ports
 
-fk: filtering key=si|di|sdi|sipdp|sdipsdp
+fk: filtering key=si|di|sdi|sipdp|sdipsdp|spr|dpr|vid|pp|itc
 with si: src IP
      di: dst IP
      sdi: srd/dst IP
      sipdp: src IP, proto, dst port
      sdipsdp: src/dst IP, proto, src/dst port
+     spr: src port range
+     dpr: dst port range
+     vid: VLAN id (any tag of the packet)
+     pp: TCP/UDP payload pattern
+     itc: ICMP/ICMPv6 type, or type and code
 
 fa: filtering action=k|d
 with k: keep
      d: drop
 
+ua: action for packets without key (for ex. non-IP packets, or ICMP packets with
+a port key)=k|d. If not set, these packets are an error
+
 path: path to a csv formatted file without header that contains filtering keys
+IP keys can be addresses or prefixes (10.0.0.0/8). Port range keys are ports or
+ranges, for ex. 1024-2048,443,8000-8100
+ICMP keys are types or types and codes, for ex. 8 or 3,1
+Payload pattern keys are regular expressions, or hexadecimal bytes prefixed by hex:
+(one per line), for ex. Host: example\\.com or hex:deadbeef
 ",
                 )
                 .short('f')
@@ -60,6 +162,194 @@ path: path to a csv formatted file without header that contains filtering keys
                 .number_of_values(1)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("filter-config")
+                .help(
+                    "TOML file describing an ordered list of filters, applied before filters given
+with -f. Each [[filter]] table has a type (dispatch, fragmentation or bpf), a key
+and an action, and dispatch keys are given with path or inline values",
+                )
+                .long("filter-config")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rejected-output")
+                .help(
+                    "Write packets dropped by filters to this file, instead of discarding them.
+Uses the same format, link type and transformations as the output",
+                )
+                .long("rejected-output")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .help(
+                    "Compress output files, using gzip (level 0-9, default: 6) or zstd
+(level 1-22, default: 3). Examples: --compress gzip, --compress zstd:19",
+                )
+                .long("compress")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stats-out")
+                .help("Write statistics of the run to this file, in JSON format")
+                .long("stats-out")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .help("Show a progress bar on the standard error")
+                .long("progress"),
+        )
+        .arg(
+            Arg::with_name("decap")
+                .help(
+                    "Dispatch filters use the headers of the inner packet of tunnels
+(GRE, VXLAN, GENEVE) instead of the outer headers",
+                )
+                .long("decap"),
+        )
+        .arg(
+            Arg::with_name("bpf")
+                .help(
+                    "BPF filter expression, using the tcpdump syntax
+Example: --bpf 'tcp and dst port 80'",
+                )
+                .long("bpf")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flow")
+                .help(
+                    "Keep the packets of a flow (both directions), given as PROTO SRC DST
+Several flows can be given. Ports are only used for tcp and udp.
+Example: --flow 'tcp 10.0.0.1:1234 10.0.0.2:443'",
+                )
+                .long("flow")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("anonymize-key")
+                .help(
+                    "Anonymize IP addresses (prefix-preserving, Crypto-PAn)
+Key is 32 bytes, written as 64 hexadecimal characters",
+                )
+                .long("anonymize-key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ip-map")
+                .help(
+                    "Replace IP addresses using a csv file without header, containing
+original,replacement pairs. Takes precedence over --anonymize-key",
+                )
+                .long("ip-map")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mac-map")
+                .help(
+                    "Replace MAC addresses using a csv file without header, containing
+original,replacement pairs. Requires ethernet output link type",
+                )
+                .long("mac-map")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("randomize-mac")
+                .help(
+                    "Replace unicast MAC addresses with random ones, keeping the OUI.
+Requires ethernet output link type",
+                )
+                .long("randomize-mac"),
+        )
+        .arg(
+            Arg::with_name("truncate-payload")
+                .help("Remove application payload (after TCP/UDP headers)")
+                .long("truncate-payload")
+                .conflicts_with("zero-payload"),
+        )
+        .arg(
+            Arg::with_name("zero-payload")
+                .help("Replace application payload (after TCP/UDP headers) with zeroes")
+                .long("zero-payload"),
+        )
+        .arg(
+            Arg::with_name("fix-checksums")
+                .help(
+                    "Recompute IPv4, TCP, UDP, ICMP and ICMPv6 checksums, after other modifications",
+                )
+                .long("fix-checksums"),
+        )
+        .arg(
+            Arg::with_name("snaplen")
+                .help("Truncate output packets to this number of bytes (0: no truncation)")
+                .long("snaplen")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dedup")
+                .help("Drop duplicate packets (same L3 data as one of the previous packets)")
+                .long("dedup"),
+        )
+        .arg(
+            Arg::with_name("dedup-window")
+                .help("Number of previous packets compared when removing duplicates (default: 5)")
+                .long("dedup-window")
+                .takes_value(true)
+                .requires("dedup"),
+        )
+        .arg(
+            Arg::with_name("dedup-ignore-ttl")
+                .help("Ignore TTL/hop limit and ToS/traffic class when removing duplicates")
+                .long("dedup-ignore-ttl")
+                .requires("dedup"),
+        )
+        .arg(
+            Arg::with_name("packets")
+                .help(
+                    "Keep packets by their index in the input, starting at 1 (as frame numbers in
+Wireshark). Example: --packets 1000-2000,5000,7000-",
+                )
+                .long("packets")
+                .takes_value(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("comments")
+                .help(
+                    "Add comments to output packets, from a CSV file of (packet index or flow,
+comment). A flow is written as for --flow. Requires pcapng output",
+                )
+                .long("comments")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .help("Keep one packet out of N (format: 1/N)")
+                .long("sample")
+                .takes_value(true)
+                .conflicts_with("sample-prob"),
+        )
+        .arg(
+            Arg::with_name("sample-prob")
+                .help("Keep each packet with the given probability (between 0 and 1)")
+                .long("sample-prob")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample-flows")
+                .help("Sample flows instead of packets (all packets of a kept flow are kept)")
+                .long("sample-flows"),
+        )
+        .arg(
+            Arg::with_name("sample-seed")
+                .help("Seed used for sampling, to get reproducible samples")
+                .long("sample-seed")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("config")
                 .help("Configuration file")
@@ -74,30 +364,132 @@ path: path to a csv formatted file without header that contains filtering keys
                 .long("output-format")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("output-linktype")
+                .help("Output link type: raw (L3) or ethernet (L2) (default: raw)")
+                .long("output-linktype")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("convert-linktype")
+                .help("Convert input packets without Ethernet header (Linux SLL, NULL, RAW) to Ethernet, with null MAC addresses. Implies ethernet output link type")
+                .long("convert-linktype"),
+        )
+        .arg(
+            Arg::with_name("split-size")
+                .help(
+                    "Split output into files of this size (in bytes). Files are rotated after \
+                     exceeding the size, so they can be larger by one packet",
+                )
+                .long("split-size")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("split-count")
+                .help("Split output into files of at most this number of packets")
+                .long("split-count")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("split-flows")
+                .help("Write each flow to its own file. OUTPUT is a directory")
+                .long("split-flows"),
+        )
+        .arg(
+            Arg::with_name("max-open-files")
+                .help("Maximum number of open files when splitting flows (default: 128)")
+                .long("max-open-files")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("repair")
+                .help(
+                    "Repair a damaged capture: skip corrupt blocks and a truncated last block,
+and report the dropped data. Filters and transformations are not supported",
+                )
+                .long("repair")
+                .conflicts_with("convert"),
+        )
+        .arg(
+            Arg::with_name("convert")
+                .help(
+                    "Convert the input to the output format (-o), copying packets without
+modification. Filters and transformations are not supported",
+                )
+                .long("convert"),
+        )
+        .arg(
+            Arg::with_name("split-linktypes")
+                .help(
+                    "When converting to pcap, write packets with other link types than the first
+packet to other files (default: fail)",
+                )
+                .long("split-linktypes")
+                .requires("convert"),
+        )
+        .arg(
+            Arg::with_name("ts-resolution")
+                .help("Timestamp resolution when converting to pcap: us or ns (default: input)")
+                .long("ts-resolution")
+                .takes_value(true)
+                .requires("convert"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .help(
+                    "Send packets to the network interface OUTPUT instead of writing a file,
+reproducing the gaps between packets. Output link type is ethernet.
+Linux only, requires the CAP_NET_RAW capability",
+                )
+                .long("replay")
+                .conflicts_with_all(&["repair", "convert"]),
+        )
+        .arg(
+            Arg::with_name("replay-speed")
+                .help(
+                    "Speed multiplier of the replay (default: 1). 0 sends packets as fast as
+possible",
+                )
+                .long("replay-speed")
+                .takes_value(true)
+                .requires("replay"),
+        )
         .arg(
             Arg::with_name("INPUT")
-                .help("Input file name")
+                .help(
+                    "Input file name, or - for standard input
+If several input files are given, they are merged in chronological order. Filters
+and transformations are not supported when merging",
+                )
                 .required(true)
+                .multiple_values(true)
                 .index(1),
         )
         .arg(
             Arg::with_name("OUTPUT")
-                .help("Output file name")
+                .help("Output file name, or - for standard output (network interface with --replay)")
                 .required(true)
                 .index(2),
         )
         .get_matches();
 
-    let _ =
-        simplelog::SimpleLogger::init(simplelog::LevelFilter::Debug, simplelog::Config::default());
+    // log to stderr, so stdout can be used as output
+    let _ = simplelog::WriteLogger::init(
+        simplelog::LevelFilter::Debug,
+        simplelog::Config::default(),
+        io::stderr(),
+    );
     debug!("Pcap rewrite tool {}", crate_version!());
 
     let mut config = Config::default();
     if let Some(filename) = matches.value_of("config") {
         load_config(&mut config, filename)?;
     }
+    if let Some(max_extensions) = config.get_usize("ipv6.max_extensions") {
+        filters::ipv6_utils::set_max_extensions(max_extensions);
+    }
 
-    let input_filename = matches.value_of("INPUT").unwrap();
+    let input_filenames: Vec<&str> = matches.values_of("INPUT").unwrap().collect();
     let output_filename = matches.value_of("OUTPUT").unwrap();
     let output_format = match matches.value_of("output-format") {
         Some("pcap") => FileFormat::Pcap,
@@ -108,8 +500,129 @@ path: path to a csv formatted file without header that contains filtering keys
         }
         None => FileFormat::Pcap,
     };
+    let convert_linktype = matches.is_present("convert-linktype");
+    let output_linktype = match matches.value_of("output-linktype") {
+        Some("raw") if convert_linktype => {
+            error!("Option --convert-linktype requires ethernet output link type");
+            ::std::process::exit(1);
+        }
+        None if convert_linktype || matches.is_present("replay") => Linktype::ETHERNET,
+        Some("raw") | None => Linktype::RAW,
+        Some("ethernet") => Linktype::ETHERNET,
+        Some(_) => {
+            error!("Invalid output link type");
+            ::std::process::exit(1);
+        }
+    };
+
+    let compression = match matches.value_of("compress") {
+        Some(s) => {
+            OutputCompression::of_string(s).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        }
+        None => OutputCompression::None,
+    };
 
-    let mut filters: Vec<Box<dyn filters::filter::Filter>> = Vec::new();
+    if matches.is_present("repair") {
+        if input_filenames.len() > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Only one input file can be repaired",
+            ));
+        }
+        let unsupported = REWRITE_OPTIONS
+            .iter()
+            .chain(&["output-format"])
+            .filter(|name| **name != "stats-out")
+            .find(|name| matches.is_present(**name));
+        if let Some(name) = unsupported {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Option --{} is not supported when repairing files", name),
+            ));
+        }
+        let report =
+            pcap_rewrite::pcap_repair_file(input_filenames[0], output_filename, compression)?;
+        eprint!("{}", report);
+        return write_stats(matches.value_of("stats-out"), &report.to_json());
+    }
+
+    if matches.is_present("convert") {
+        if input_filenames.len() > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Only one input file can be converted",
+            ));
+        }
+        let unsupported = REWRITE_OPTIONS
+            .iter()
+            .filter(|name| **name != "stats-out")
+            .find(|name| matches.is_present(**name));
+        if let Some(name) = unsupported {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Option --{} is not supported when converting files", name),
+            ));
+        }
+        let options = ConvertOptions {
+            output_format,
+            split_linktypes: matches.is_present("split-linktypes"),
+            nanosecond: match matches.value_of("ts-resolution") {
+                Some("us") => Some(false),
+                Some("ns") => Some(true),
+                Some(_) => {
+                    error!("Invalid timestamp resolution");
+                    ::std::process::exit(1);
+                }
+                None => None,
+            },
+        };
+        let report = pcap_rewrite::pcap_convert_file(
+            input_filenames[0],
+            output_filename,
+            &options,
+            compression,
+        )?;
+        eprint!("{}", report);
+        return write_stats(matches.value_of("stats-out"), &report.to_json());
+    }
+
+    if input_filenames.len() > 1 {
+        if let Some(name) = REWRITE_OPTIONS
+            .iter()
+            .find(|name| matches.is_present(**name))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Option --{} is not supported when merging files", name),
+            ));
+        }
+        return pcap_rewrite::pcap_merge_files(
+            &input_filenames,
+            output_filename,
+            output_format,
+            compression,
+        );
+    }
+    let input_filename = input_filenames[0];
+
+    let split = SplitPolicy {
+        max_size: parse_positive_value(matches.value_of("split-size"), "split size")?,
+        max_packets: parse_positive_value(matches.value_of("split-count"), "split count")?,
+    };
+    let max_open_files = parse_positive_value(
+        matches.value_of("max-open-files"),
+        "maximum number of open files",
+    )?
+    .unwrap_or(128) as usize;
+
+    let mut filters: Vec<Box<dyn filters::filter::Filter>> = match matches.value_of("filter-config")
+    {
+        Some(path) => {
+            eprintln!("adding filters from configuration: {}", path);
+            filters_of_file_path(Path::new(path), matches.is_present("decap"))?
+        }
+        None => Vec::new(),
+    };
     let filter_names: Vec<&str> = matches.values_of("filters").unwrap_or_default().collect();
     for name in &filter_names {
         eprintln!("adding filter: {}", name);
@@ -129,17 +642,31 @@ path: path to a csv formatted file without header that contains filtering keys
                 eprintln!("adding dispatch filter");
                 let dispatch_data = args[1];
                 let args: Vec<_> = dispatch_data.split('%').collect();
-                assert_eq!(args.len(), 3);
+                if args.len() != 3 && args.len() != 4 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "Dispatch filter expects 3 or 4 arguments (fk%fa%path[%ua])".to_string(),
+                    ));
+                };
                 let filtering_key = FilteringKey::of_string(args[0])
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
                 let filtering_action = FilteringAction::of_string(args[1])
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
                 let key_file_path = args[2];
+                let unsupported = match args.get(3) {
+                    Some(s) => Some(
+                        FilteringAction::of_string(s)
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+                    ),
+                    None => None,
+                };
 
                 let f = DispatchFilterBuilder::from_args(
                     filtering_key,
                     filtering_action,
                     key_file_path,
+                    matches.is_present("decap"),
+                    unsupported,
                 )?;
                 filters.push(f);
             }
@@ -168,10 +695,187 @@ path: path to a csv formatted file without header that contains filtering keys
         }
     }
 
+    if let Some(expression) = matches.value_of("bpf") {
+        eprintln!("adding BPF filter: {}", expression);
+        let f = BpfFilter::new(expression).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        filters.push(Box::new(f));
+    }
+
+    if let Some(values) = matches.values_of("flow") {
+        let flows = values
+            .map(parse_flow)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        for flow in &flows {
+            eprintln!("adding flow filter: {}", flow);
+        }
+        filters.push(DispatchFilterBuilder::from_flows(
+            &flows,
+            matches.is_present("decap"),
+        ));
+    }
+
+    if matches.is_present("dedup") {
+        let window = parse_positive_value(matches.value_of("dedup-window"), "deduplication window")?
+            .unwrap_or(5) as usize;
+        eprintln!("adding deduplication filter (window: {})", window);
+        // duplicates are removed before other filters, so the window contains all packets
+        let f = DedupFilter::new(window, matches.is_present("dedup-ignore-ttl"));
+        filters.insert(0, Box::new(f));
+    }
+
+    if let Some(s) = matches.value_of("packets") {
+        let ranges = parse_index_ranges(s).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        eprintln!("adding packet index filter: {}", s);
+        // indexes refer to input packets, so this filter is applied first
+        filters.insert(0, Box::new(IndexFilter::new(ranges)));
+    }
+
+    let sampling_mode = match (matches.value_of("sample"), matches.value_of("sample-prob")) {
+        (Some(s), _) => Some(SamplingMode::of_ratio(s)),
+        (None, Some(s)) => Some(SamplingMode::of_probability(s)),
+        (None, None) => None,
+    }
+    .transpose()
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    match sampling_mode {
+        Some(mode) => {
+            let seed = match matches.value_of("sample-seed") {
+                Some(s) => Some(s.parse::<u64>().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Invalid sampling seed: {}", s),
+                    )
+                })?),
+                None => None,
+            };
+            let flow_consistent = matches.is_present("sample-flows");
+            eprintln!(
+                "adding sampling filter ({:?}, per {})",
+                mode,
+                if flow_consistent { "flow" } else { "packet" }
+            );
+            // sampling is done last, on packets kept by other filters
+            filters.push(Box::new(SamplingFilter::new(mode, flow_consistent, seed)));
+        }
+        None => {
+            if matches.is_present("sample-flows") || matches.is_present("sample-seed") {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Options --sample-flows and --sample-seed require --sample or --sample-prob",
+                ));
+            }
+        }
+    }
+
+    let mut transforms: Vec<Box<dyn Transform>> = Vec::new();
+    if matches.is_present("anonymize-key") || matches.is_present("ip-map") {
+        let cryptopan = match matches.value_of("anonymize-key") {
+            Some(key) => Some(
+                CryptoPan::of_hex_key(key).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+            ),
+            None => None,
+        };
+        let mapping = match matches.value_of("ip-map") {
+            Some(path) => ip_mapping_of_file_path(Path::new(path))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+            None => Default::default(),
+        };
+        eprintln!("adding IP anonymization transform");
+        transforms.push(Box::new(IpAnonymizer::new(mapping, cryptopan)));
+    }
+    if matches.is_present("mac-map") || matches.is_present("randomize-mac") {
+        if output_linktype != Linktype::ETHERNET {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "MAC address rewriting requires ethernet output link type",
+            ));
+        }
+        let mapping = match matches.value_of("mac-map") {
+            Some(path) => mac_mapping_of_file_path(Path::new(path))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+            None => Default::default(),
+        };
+        eprintln!("adding MAC rewriting transform");
+        transforms.push(Box::new(MacRewriter::new(
+            mapping,
+            matches.is_present("randomize-mac"),
+        )));
+    }
+    if matches.is_present("truncate-payload") {
+        eprintln!("adding payload truncation transform");
+        transforms.push(Box::new(PayloadScrubber::new(PayloadAction::Truncate)));
+    } else if matches.is_present("zero-payload") {
+        eprintln!("adding payload zeroing transform");
+        transforms.push(Box::new(PayloadScrubber::new(PayloadAction::Zero)));
+    }
+    if matches.is_present("fix-checksums") {
+        eprintln!("adding checksum fixing transform");
+        transforms.push(Box::new(ChecksumFixer));
+    }
+
+    let comments = match matches.value_of("comments") {
+        Some(path) => {
+            eprintln!("adding packet comments from: {}", path);
+            let comments = PacketComments::of_file_path(Path::new(path))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            Some(comments)
+        }
+        None => None,
+    };
+
+    // 0 is accepted, and disables truncation
+    let snaplen = match matches.value_of("snaplen") {
+        Some(s) => Some(s.parse::<usize>().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, format!("Invalid snaplen: {}", s))
+        })?),
+        None => None,
+    };
+
     let options = RewriteOptions {
         output_format,
+        output_linktype,
         config,
+        split,
+        split_flows: matches.is_present("split-flows"),
+        max_open_files,
+        rejected_output: matches.value_of("rejected-output").map(String::from),
+        compression,
+        snaplen,
+        convert_linktype,
+        comments,
+        progress: matches.is_present("progress"),
+        stop: Some(interrupt_handle()?),
+    };
+
+    let stats = if matches.is_present("replay") {
+        let speed = match matches.value_of("replay-speed") {
+            Some(s) => match s.parse::<f64>() {
+                Ok(speed) if speed.is_finite() && speed >= 0.0 => speed,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Invalid replay speed: {}", s),
+                    ))
+                }
+            },
+            None => 1.0,
+        };
+        let replay = ReplayOptions {
+            interface: output_filename.to_owned(),
+            speed,
+        };
+        pcap_rewrite::pcap_replay_file(input_filename, filters, transforms, &options, &replay)?
+    } else {
+        pcap_rewrite::pcap_rewrite_file(
+            input_filename,
+            output_filename,
+            filters,
+            transforms,
+            &options,
+        )?
     };
 
-    pcap_rewrite::pcap_rewrite_file(input_filename, output_filename, filters, &options)
+    eprint!("{}", stats);
+    write_stats(matches.value_of("stats-out"), &stats.to_json())
 }
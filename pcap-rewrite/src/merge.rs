@@ -0,0 +1,401 @@
+use crate::compression::OutputStream;
+use crate::rewriter::FileFormat;
+use libpcap_tools::pcapng_build_interface;
+use log::{debug, warn};
+use pcap_parser::pcapng::*;
+use pcap_parser::{
+    create_reader, Block, LegacyPcapBlock, Linktype, OptionCode, PcapBlockOwned, PcapError,
+    PcapReaderIterator, ToVec,
+};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Interface of one of the inputs
+struct InterfaceDesc {
+    link_type: Linktype,
+    snaplen: u32,
+    /// Timestamp unit of the input
+    ts_unit: u64,
+    /// IDB options (name, description, etc.), except time resolution and offset
+    options: Vec<(OptionCode, u16, Vec<u8>)>,
+}
+
+/// Interface of the current section of an input
+#[derive(Clone, Copy)]
+struct SectionInterface {
+    /// Index in the interface registry
+    key: usize,
+    ts_unit: u64,
+    if_tsoffset: u64,
+}
+
+/// Packet read from one of the inputs
+struct MergePacket {
+    /// Timestamp, in nanoseconds
+    ts: u64,
+    /// Index in the interface registry
+    interface: usize,
+    data: Vec<u8>,
+    origlen: u32,
+}
+
+struct MergeInput {
+    reader: Box<dyn PcapReaderIterator>,
+    interfaces: Vec<SectionInterface>,
+    /// Timestamp of the last packet, used for packets without timestamp (SPB)
+    last_ts: u64,
+}
+
+fn reader_error(e: PcapError<&[u8]>) -> io::Error {
+    Error::new(ErrorKind::Other, format!("{:?}", e.to_owned_vec()))
+}
+
+impl MergeInput {
+    fn new(reader: Box<dyn Read>) -> Result<Self, io::Error> {
+        let reader = create_reader(65536, reader).map_err(reader_error)?;
+        Ok(MergeInput {
+            reader,
+            interfaces: Vec::new(),
+            last_ts: 0,
+        })
+    }
+
+    /// Read blocks until the next packet
+    fn next_packet(
+        &mut self,
+        registry: &mut Vec<InterfaceDesc>,
+    ) -> Result<Option<MergePacket>, io::Error> {
+        let mut incomplete = false;
+        loop {
+            match self.reader.next() {
+                Ok((offset, block)) => {
+                    incomplete = false;
+                    let packet =
+                        handle_block(&mut self.interfaces, &mut self.last_ts, &block, registry);
+                    self.reader.consume(offset);
+                    if let Some(packet) = packet? {
+                        return Ok(Some(packet));
+                    }
+                }
+                Err(PcapError::Eof) => return Ok(None),
+                Err(PcapError::Incomplete) => {
+                    if incomplete && self.reader.reader_exhausted() {
+                        warn!("Could not read complete data block, input file may be truncated");
+                        return Ok(None);
+                    }
+                    incomplete = true;
+                    self.reader.refill().map_err(reader_error)?;
+                }
+                Err(e) => return Err(reader_error(e)),
+            }
+        }
+    }
+}
+
+fn section_interface(
+    interfaces: &[SectionInterface],
+    if_id: usize,
+) -> Result<SectionInterface, io::Error> {
+    interfaces
+        .get(if_id)
+        .copied()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "Packet references an unknown interface"))
+}
+
+fn handle_block(
+    interfaces: &mut Vec<SectionInterface>,
+    last_ts: &mut u64,
+    block: &PcapBlockOwned,
+    registry: &mut Vec<InterfaceDesc>,
+) -> Result<Option<MergePacket>, io::Error> {
+    let packet = match block {
+        PcapBlockOwned::NG(Block::SectionHeader(_)) => {
+            interfaces.clear();
+            return Ok(None);
+        }
+        PcapBlockOwned::NG(Block::InterfaceDescription(idb)) => {
+            let if_info = pcapng_build_interface(idb);
+            let options = idb
+                .options
+                .iter()
+                .filter(|o| o.code != OptionCode::IfTsresol && o.code != OptionCode::IfTsoffset)
+                .map(|o| (o.code, o.len, o.value.to_vec()))
+                .collect();
+            registry.push(InterfaceDesc {
+                link_type: if_info.link_type,
+                snaplen: if_info.snaplen,
+                ts_unit: if_info.ts_unit,
+                options,
+            });
+            interfaces.push(SectionInterface {
+                key: registry.len() - 1,
+                ts_unit: if_info.ts_unit,
+                if_tsoffset: if_info.if_tsoffset,
+            });
+            return Ok(None);
+        }
+        PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => {
+            let iface = section_interface(interfaces, epb.if_id as usize)?;
+            let raw_ts = (u64::from(epb.ts_high) << 32) | u64::from(epb.ts_low);
+            let ts = iface.if_tsoffset * NANOS_PER_SEC
+                + (u128::from(raw_ts) * u128::from(NANOS_PER_SEC) / u128::from(iface.ts_unit))
+                    as u64;
+            MergePacket {
+                ts,
+                interface: iface.key,
+                data: epb.data[..(epb.caplen as usize).min(epb.data.len())].to_vec(),
+                origlen: epb.origlen,
+            }
+        }
+        PcapBlockOwned::NG(Block::SimplePacket(spb)) => {
+            let iface = section_interface(interfaces, 0)?;
+            let caplen = (spb.origlen as usize).min(spb.data.len());
+            MergePacket {
+                ts: *last_ts,
+                interface: iface.key,
+                data: spb.data[..caplen].to_vec(),
+                origlen: spb.origlen,
+            }
+        }
+        PcapBlockOwned::LegacyHeader(hdr) => {
+            let ts_unit = if hdr.is_nanosecond_precision() {
+                NANOS_PER_SEC
+            } else {
+                1_000_000
+            };
+            registry.push(InterfaceDesc {
+                link_type: hdr.network,
+                snaplen: hdr.snaplen,
+                ts_unit,
+                options: Vec::new(),
+            });
+            interfaces.push(SectionInterface {
+                key: registry.len() - 1,
+                ts_unit,
+                if_tsoffset: 0,
+            });
+            return Ok(None);
+        }
+        PcapBlockOwned::Legacy(b) => {
+            let iface = section_interface(interfaces, 0)?;
+            let ts = u64::from(b.ts_sec) * NANOS_PER_SEC
+                + u64::from(b.ts_usec) * (NANOS_PER_SEC / iface.ts_unit);
+            MergePacket {
+                ts,
+                interface: iface.key,
+                data: b.data[..(b.caplen as usize).min(b.data.len())].to_vec(),
+                origlen: b.origlen,
+            }
+        }
+        _ => {
+            debug!("merge: skipping block");
+            return Ok(None);
+        }
+    };
+    *last_ts = packet.ts;
+    Ok(Some(packet))
+}
+
+/// Output of the merge
+///
+/// Headers are written when the first packet is written, since link types are not known before.
+struct MergeOutput {
+    w: Box<dyn OutputStream>,
+    format: FileFormat,
+    header_written: bool,
+    /// Link type of the output (pcap only)
+    linktype: Option<Linktype>,
+    /// Write nanosecond timestamps, if one of the inputs has a better resolution than
+    /// microseconds (pcap only)
+    nanosecond: bool,
+    /// Output interface ID of each interface of the registry (pcapng only)
+    output_interfaces: HashMap<usize, u32>,
+}
+
+impl MergeOutput {
+    fn write_header(
+        &mut self,
+        registry: &[InterfaceDesc],
+        interface: Option<usize>,
+    ) -> Result<(), io::Error> {
+        let v = match self.format {
+            FileFormat::Pcap => {
+                let linktype = interface
+                    .map(|i| registry[i].link_type)
+                    .or_else(|| registry.first().map(|i| i.link_type))
+                    .unwrap_or(Linktype::RAW);
+                let mut hdr = pcap_parser::PcapHeader::new();
+                hdr.snaplen = registry.iter().map(|i| i.snaplen).max().unwrap_or(65535);
+                hdr.network = linktype;
+                self.linktype = Some(linktype);
+                self.nanosecond = registry.iter().any(|i| i.ts_unit > 1_000_000);
+                if self.nanosecond {
+                    hdr.magic_number = 0xa1b2_3c4d;
+                }
+                hdr.to_vec()
+            }
+            FileFormat::PcapNG => {
+                let shb = SectionHeaderBlock {
+                    block_type: SHB_MAGIC,
+                    block_len1: 28, // no options
+                    bom: BOM_MAGIC,
+                    major_version: 1,
+                    minor_version: 0,
+                    section_len: -1,
+                    options: Vec::new(),
+                    block_len2: 28,
+                };
+                shb.to_vec_raw()
+            }
+        }
+        .map_err(|_| Error::new(ErrorKind::Other, "Header serialization failed"))?;
+        self.w.write_all(&v)?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    /// Return the output interface ID for an interface of the registry, writing its IDB if needed
+    fn output_interface(
+        &mut self,
+        registry: &[InterfaceDesc],
+        key: usize,
+    ) -> Result<u32, io::Error> {
+        if let Some(if_id) = self.output_interfaces.get(&key) {
+            return Ok(*if_id);
+        }
+        let desc = &registry[key];
+        let options = desc
+            .options
+            .iter()
+            .map(|(code, len, value)| PcapNGOption {
+                code: *code,
+                len: *len,
+                value,
+            })
+            .collect();
+        let mut idb = InterfaceDescriptionBlock {
+            block_type: IDB_MAGIC,
+            block_len1: 20,
+            linktype: desc.link_type,
+            reserved: 0,
+            snaplen: desc.snaplen,
+            options,
+            block_len2: 20,
+            // timestamps are written in nanoseconds
+            if_tsresol: 9,
+            if_tsoffset: 0,
+        };
+        // to_vec will add options automatically
+        let v = idb
+            .to_vec()
+            .map_err(|_| Error::new(ErrorKind::Other, "IDB serialization failed"))?;
+        self.w.write_all(&v)?;
+        let if_id = self.output_interfaces.len() as u32;
+        self.output_interfaces.insert(key, if_id);
+        Ok(if_id)
+    }
+
+    fn write_packet(
+        &mut self,
+        registry: &[InterfaceDesc],
+        packet: &MergePacket,
+    ) -> Result<(), io::Error> {
+        if !self.header_written {
+            self.write_header(registry, Some(packet.interface))?;
+        }
+        let v = match self.format {
+            FileFormat::Pcap => {
+                if self.linktype != Some(registry[packet.interface].link_type) {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "Input files have different link types, use pcapng output format",
+                    ));
+                }
+                let record = LegacyPcapBlock {
+                    ts_sec: (packet.ts / NANOS_PER_SEC) as u32,
+                    ts_usec: if self.nanosecond {
+                        (packet.ts % NANOS_PER_SEC) as u32
+                    } else {
+                        ((packet.ts % NANOS_PER_SEC) / 1000) as u32
+                    },
+                    caplen: packet.data.len() as u32,
+                    origlen: packet.origlen,
+                    data: &packet.data,
+                };
+                record.to_vec_raw()
+            }
+            FileFormat::PcapNG => {
+                let if_id = self.output_interface(registry, packet.interface)?;
+                let mut epb = EnhancedPacketBlock {
+                    block_type: EPB_MAGIC,
+                    block_len1: 32,
+                    if_id,
+                    ts_high: (packet.ts >> 32) as u32,
+                    ts_low: (packet.ts & 0xffff_ffff) as u32,
+                    caplen: packet.data.len() as u32,
+                    origlen: packet.origlen,
+                    data: &packet.data,
+                    options: Vec::new(),
+                    block_len2: 32,
+                };
+                // to_vec will adjust length
+                epb.to_vec()
+            }
+        }
+        .map_err(|_| Error::new(ErrorKind::Other, "Packet serialization failed"))?;
+        self.w.write_all(&v)
+    }
+}
+
+/// Merge inputs to `w`, in chronological order, and return the number of packets written
+///
+/// This is a k-way merge: one packet of each input is kept in a heap. Packets with the same
+/// timestamp are written in the order of the inputs.
+pub fn merge(
+    inputs: Vec<Box<dyn Read>>,
+    w: Box<dyn OutputStream>,
+    format: FileFormat,
+) -> Result<usize, io::Error> {
+    let mut registry = Vec::new();
+    let mut inputs = inputs
+        .into_iter()
+        .map(MergeInput::new)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut pending: Vec<Option<MergePacket>> = Vec::with_capacity(inputs.len());
+    let mut heap = BinaryHeap::new();
+    for (index, input) in inputs.iter_mut().enumerate() {
+        let packet = input.next_packet(&mut registry)?;
+        if let Some(p) = &packet {
+            heap.push(Reverse((p.ts, index)));
+        }
+        pending.push(packet);
+    }
+    let mut output = MergeOutput {
+        w,
+        format,
+        header_written: false,
+        linktype: None,
+        nanosecond: false,
+        output_interfaces: HashMap::new(),
+    };
+    let mut count = 0;
+    while let Some(Reverse((_, index))) = heap.pop() {
+        if let Some(packet) = pending[index].take() {
+            output.write_packet(&registry, &packet)?;
+            count += 1;
+        }
+        let packet = inputs[index].next_packet(&mut registry)?;
+        if let Some(p) = &packet {
+            heap.push(Reverse((p.ts, index)));
+        }
+        pending[index] = packet;
+    }
+    if !output.header_written {
+        output.write_header(&registry, None)?;
+    }
+    output.w.finish()?;
+    Ok(count)
+}
@@ -1,27 +1,43 @@
+use crate::compression::OutputStream;
 use crate::traits::Writer;
 use libpcap_tools::Packet;
 use log::debug;
 use pcap_parser::ToVec;
-use pcap_parser::{LegacyPcapBlock, Linktype, PcapBlockOwned};
+use pcap_parser::{Block, LegacyPcapBlock, Linktype, PcapBlockOwned};
 use std::io::{self, Error, ErrorKind, Write};
 
+/// Magic number of legacy pcap files with nanosecond timestamps
+const PCAP_NANOSECOND_MAGIC: u32 = 0xa1b2_3c4d;
+
 /// Writer for the legacy pcap format
-pub struct PcapWriter<W>
-where
-    W: Write,
-{
-    w: W,
+pub struct PcapWriter {
+    w: Box<dyn OutputStream>,
+    /// File header, written again when output changes
+    header: Vec<u8>,
+    /// Write nanoseconds instead of microseconds in the timestamp fractional part
+    nanosecond: bool,
 }
 
-impl<W: Write> PcapWriter<W> {
-    pub fn new(w: W) -> Self {
-        PcapWriter { w }
+impl PcapWriter {
+    pub fn new(w: Box<dyn OutputStream>) -> Self {
+        PcapWriter {
+            w,
+            header: Vec::new(),
+            nanosecond: false,
+        }
     }
 }
 
-impl<W: Write> Writer for PcapWriter<W> {
+impl Writer for PcapWriter {
+    fn set_nanosecond_precision(&mut self, nanosecond: bool) {
+        self.nanosecond = nanosecond;
+    }
+
     fn init_file(&mut self, snaplen: usize, linktype: Linktype) -> Result<usize, io::Error> {
         let mut hdr = pcap_parser::PcapHeader::new();
+        if self.nanosecond {
+            hdr.magic_number = PCAP_NANOSECOND_MAGIC;
+        }
         hdr.snaplen = snaplen as u32;
         hdr.network = linktype;
         #[allow(clippy::or_fun_call)]
@@ -29,7 +45,8 @@ impl<W: Write> Writer for PcapWriter<W> {
             ErrorKind::Other,
             "Pcap header serialization failed",
         )))?;
-        self.w.write(&s)
+        self.header = s;
+        self.w.write(&self.header)
     }
 
     fn write_block(&mut self, block: &PcapBlockOwned) -> Result<usize, io::Error> {
@@ -46,6 +63,9 @@ impl<W: Write> Writer for PcapWriter<W> {
                 )))?;
                 self.w.write(&v)
             }
+            // data blocks are written in `write_packet`
+            PcapBlockOwned::NG(Block::EnhancedPacket(_))
+            | PcapBlockOwned::NG(Block::SimplePacket(_)) => Ok(0),
             PcapBlockOwned::NG(b) => {
                 debug!(
                     "PcapWriter: skipping pcapng block with magic {:08x}",
@@ -56,12 +76,22 @@ impl<W: Write> Writer for PcapWriter<W> {
         }
     }
 
-    fn write_packet(&mut self, packet: &Packet, data: &[u8]) -> Result<usize, io::Error> {
+    fn write_packet(
+        &mut self,
+        packet: &Packet,
+        data: &[u8],
+        origlen: u32,
+    ) -> Result<usize, io::Error> {
+        let ts_frac = if self.nanosecond {
+            packet.ts.nanos
+        } else {
+            packet.ts.micros()
+        };
         let record = LegacyPcapBlock {
             ts_sec: packet.ts.secs as u32,
-            ts_usec: packet.ts.micros as u32,
-            caplen: data.len() as u32,  // packet.header.caplen,
-            origlen: data.len() as u32, // packet.header.len,
+            ts_usec: ts_frac,
+            caplen: data.len() as u32,
+            origlen,
             data,
         };
         // debug!("rec_hdr: {:?}", rec_hdr);
@@ -73,4 +103,14 @@ impl<W: Write> Writer for PcapWriter<W> {
         )))?;
         self.w.write(&s)
     }
+
+    fn set_output(&mut self, w: Box<dyn OutputStream>) -> Result<usize, io::Error> {
+        self.w.finish()?;
+        self.w = w;
+        self.w.write(&self.header)
+    }
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        self.w.finish()
+    }
 }
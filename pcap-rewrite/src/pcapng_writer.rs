@@ -0,0 +1,294 @@
+use crate::compression::OutputStream;
+use crate::traits::*;
+use libpcap_tools::{pcapng_build_interface, Packet};
+use pcap_parser::pcapng::*;
+use pcap_parser::ToVec;
+use pcap_parser::{Linktype, PcapBlockOwned};
+use std::io::{self, Error, ErrorKind, Write};
+
+/// Time resolution and offset of an output interface
+#[derive(Clone, Copy, Debug)]
+struct OutputInterface {
+    ts_unit: u64,
+    if_tsoffset: u64,
+}
+
+/// Writer for the pcapng format
+///
+/// Input interfaces (IDB) are copied to the output with their options (name, description,
+/// time resolution and offset), and packets are written to the matching output interface
+/// with their original timestamp. Each output interface has the link type of the packets
+/// written to it (see `output_linktype`), so inputs with several link types are supported.
+///
+/// If the input has no interface (legacy pcap), a default interface with microsecond
+/// resolution (or nanosecond, see `set_nanosecond_precision`) is created.
+///
+/// Comments of input packets are kept, other packet options (flags, hashes) are dropped.
+pub struct PcapNGWriter {
+    w: Box<dyn OutputStream>,
+    /// SHB and IDBs, written again when output changes
+    header: Vec<u8>,
+    snaplen: usize,
+    /// Output link type
+    linktype: Linktype,
+    /// Output interfaces, indexed by output interface ID
+    interfaces: Vec<OutputInterface>,
+    /// Output interface ID of each interface of the current input section
+    section_interfaces: Vec<u32>,
+    /// Output interface ID of the default interface, if created
+    default_interface: Option<u32>,
+    /// Interface ID and raw timestamp of the last input EPB
+    last_epb_ts: Option<(u32, u64)>,
+    /// Comments of the next packet: comments of the last input EPB, and added comments
+    packet_comments: Vec<Vec<u8>>,
+    /// Use nanosecond resolution for the default interface
+    nanosecond: bool,
+}
+
+impl PcapNGWriter {
+    pub fn new(w: Box<dyn OutputStream>) -> Self {
+        PcapNGWriter {
+            w,
+            header: Vec::new(),
+            snaplen: 0,
+            linktype: Linktype::RAW,
+            interfaces: Vec::new(),
+            section_interfaces: Vec::new(),
+            default_interface: None,
+            last_epb_ts: None,
+            packet_comments: Vec::new(),
+            nanosecond: false,
+        }
+    }
+
+    /// Write an IDB, and return the output interface ID and the number of bytes written
+    fn write_idb(
+        &mut self,
+        linktype: Linktype,
+        options: Vec<PcapNGOption>,
+        if_tsresol: u8,
+        if_tsoffset: u64,
+        ts_unit: u64,
+    ) -> Result<(u32, usize), io::Error> {
+        let mut idb = InterfaceDescriptionBlock {
+            block_type: IDB_MAGIC,
+            block_len1: 20,
+            linktype,
+            reserved: 0,
+            snaplen: self.snaplen as u32,
+            options,
+            block_len2: 20,
+            if_tsresol,
+            if_tsoffset,
+        };
+        // to_vec will add options automatically
+        #[allow(clippy::or_fun_call)]
+        let v = idb.to_vec().or(Err(Error::new(
+            ErrorKind::Other,
+            "IDB serialization failed",
+        )))?;
+        let sz = self.w.write(&v)?;
+        self.header.extend_from_slice(&v);
+        self.interfaces.push(OutputInterface {
+            ts_unit,
+            if_tsoffset,
+        });
+        Ok(((self.interfaces.len() - 1) as u32, sz))
+    }
+
+    /// Return the link type of the packets of an input interface, once converted
+    ///
+    /// Packets are converted to the output link type. Raw IPv4 and IPv6 link types are kept for
+    /// raw output, since the packet data is not modified.
+    fn output_linktype(&self, input_linktype: Linktype) -> Linktype {
+        match (self.linktype, input_linktype) {
+            (Linktype::RAW, Linktype::IPV4) | (Linktype::RAW, Linktype::IPV6) => input_linktype,
+            _ => self.linktype,
+        }
+    }
+
+    /// Return the ID of the default interface, writing its IDB if needed
+    pub(crate) fn default_interface(&mut self) -> Result<(u32, usize), io::Error> {
+        match self.default_interface {
+            Some(if_id) => Ok((if_id, 0)),
+            None => {
+                let (if_id, sz) = if self.nanosecond {
+                    self.write_idb(self.linktype, Vec::new(), 9, 0, 1_000_000_000)?
+                } else {
+                    self.write_idb(self.linktype, Vec::new(), 6, 0, 1_000_000)?
+                };
+                self.default_interface = Some(if_id);
+                Ok((if_id, sz))
+            }
+        }
+    }
+
+    /// Convert the packet timestamp (nanoseconds, offset included) to the interface unit
+    fn convert_ts(&self, if_id: u32, packet: &Packet) -> u64 {
+        let iface = &self.interfaces[if_id as usize];
+        let secs = u64::from(packet.ts.secs).saturating_sub(iface.if_tsoffset);
+        secs * iface.ts_unit + u64::from(packet.ts.nanos) * iface.ts_unit / 1_000_000_000
+    }
+}
+
+impl Writer for PcapNGWriter {
+    fn set_nanosecond_precision(&mut self, nanosecond: bool) {
+        self.nanosecond = nanosecond;
+    }
+
+    fn init_file(&mut self, snaplen: usize, linktype: Linktype) -> Result<usize, io::Error> {
+        self.snaplen = snaplen;
+        self.linktype = linktype;
+        // write SHB. IDBs are written when input interfaces are declared
+        let shb = SectionHeaderBlock {
+            block_type: SHB_MAGIC,
+            block_len1: 28, // no options
+            bom: BOM_MAGIC,
+            major_version: 1,
+            minor_version: 0,
+            section_len: -1,
+            options: Vec::new(),
+            block_len2: 28,
+        };
+        #[allow(clippy::or_fun_call)]
+        let v = shb.to_vec_raw().or(Err(Error::new(
+            ErrorKind::Other,
+            "SHB serialization failed",
+        )))?;
+        let sz = self.w.write(&v)?;
+        self.header = v;
+        Ok(sz)
+    }
+
+    fn write_block(&mut self, block: &PcapBlockOwned) -> Result<usize, io::Error> {
+        match block {
+            PcapBlockOwned::NG(b) => {
+                match b {
+                    // all input sections are merged in the output section
+                    Block::SectionHeader(_) => {
+                        self.section_interfaces.clear();
+                        Ok(0)
+                    }
+                    Block::InterfaceDescription(idb) => {
+                        let if_info = pcapng_build_interface(idb);
+                        let options = idb
+                            .options
+                            .iter()
+                            .map(|o| PcapNGOption {
+                                code: o.code,
+                                len: o.len,
+                                value: o.value,
+                            })
+                            .collect();
+                        let (if_id, sz) = self.write_idb(
+                            self.output_linktype(idb.linktype),
+                            options,
+                            if_info.if_tsresol,
+                            if_info.if_tsoffset,
+                            if_info.ts_unit,
+                        )?;
+                        self.section_interfaces.push(if_id);
+                        Ok(sz)
+                    }
+                    // data blocks are written in `write_packet`, only keep the raw timestamp
+                    Block::EnhancedPacket(epb) => {
+                        let ts = (u64::from(epb.ts_high) << 32) | u64::from(epb.ts_low);
+                        self.last_epb_ts = Some((epb.if_id, ts));
+                        self.packet_comments = epb
+                            .options
+                            .iter()
+                            .filter(|o| o.code == OptionCode::Comment)
+                            .map(|o| o.value.to_vec())
+                            .collect();
+                        Ok(0)
+                    }
+                    Block::SimplePacket(_) => {
+                        self.last_epb_ts = None;
+                        self.packet_comments.clear();
+                        Ok(0)
+                    }
+                    // other blocks are copied
+                    _ => {
+                        let v = b.to_vec_raw().map_err(|_| {
+                            Error::new(ErrorKind::Other, "Block serialization failed")
+                        })?;
+                        self.w.write(&v)
+                    }
+                }
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write_packet(
+        &mut self,
+        packet: &Packet,
+        data: &[u8],
+        origlen: u32,
+    ) -> Result<usize, io::Error> {
+        let last_epb_ts = self.last_epb_ts.take();
+        let mapped_if_id = self
+            .section_interfaces
+            .get(packet.interface as usize)
+            .copied();
+        let (if_id, ts, sz1) = match mapped_if_id {
+            Some(if_id) => match last_epb_ts {
+                Some((epb_if_id, ts)) if epb_if_id == packet.interface => (if_id, ts, 0),
+                _ => (if_id, self.convert_ts(if_id, packet), 0),
+            },
+            None => {
+                let (if_id, sz) = self.default_interface()?;
+                (if_id, self.convert_ts(if_id, packet), sz)
+            }
+        };
+        let comments = std::mem::take(&mut self.packet_comments);
+        let options = comments
+            .iter()
+            .map(|comment| PcapNGOption {
+                code: OptionCode::Comment,
+                len: comment.len() as u16,
+                value: comment,
+            })
+            .collect();
+        let mut epb = EnhancedPacketBlock {
+            block_type: EPB_MAGIC,
+            block_len1: 32,
+            if_id,
+            ts_high: (ts >> 32) as u32,
+            ts_low: (ts & 0xffff_ffff) as u32,
+            caplen: data.len() as u32,
+            origlen,
+            data,
+            options,
+            block_len2: 32,
+        };
+        // to_vec will adjust length
+        #[allow(clippy::or_fun_call)]
+        let v = epb.to_vec().or(Err(Error::new(
+            ErrorKind::Other,
+            "EPB serialization failed",
+        )))?;
+        let sz2 = self.w.write(&v)?;
+        Ok(sz1 + sz2)
+    }
+
+    fn add_packet_comment(&mut self, comment: &str) {
+        // option length is 16 bits: truncate comment, keeping valid UTF-8
+        let mut len = comment.len().min(usize::from(u16::MAX));
+        while !comment.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.packet_comments
+            .push(comment[..len].as_bytes().to_vec());
+    }
+
+    fn set_output(&mut self, w: Box<dyn OutputStream>) -> Result<usize, io::Error> {
+        self.w.finish()?;
+        self.w = w;
+        self.w.write(&self.header)
+    }
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        self.w.finish()
+    }
+}
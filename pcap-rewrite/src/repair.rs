@@ -0,0 +1,398 @@
+//! Repair of damaged captures
+//!
+//! [`repair`] copies the valid blocks of a pcap or pcap-ng input to the output, without
+//! modification. When an invalid block is found, the input is scanned byte by byte until a valid
+//! block is found (resynchronization), and the skipped data is reported. A truncated last block
+//! (for ex. from an interrupted capture) is dropped.
+//!
+//! A legacy pcap record is valid if its header is consistent (fraction of second, captured and
+//! original lengths). When resynchronizing, it must also be followed by two valid records, or by
+//! the end of the input and have a timestamp close to the last record kept. A pcap-ng block is valid if its type is known, and if its length is
+//! consistent and repeated at the end of the block.
+
+use crate::compression::OutputStream;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+/// Maximum captured length of a legacy pcap record (same as libpcap)
+const MAX_CAPLEN: u32 = 262_144;
+/// Maximum length of a pcap-ng block
+const MAX_BLOCK_LEN: u32 = 16 * 1024 * 1024;
+/// Number of valid records which must follow a legacy pcap record found when resynchronizing
+const RESYNC_RECORDS: usize = 2;
+/// Maximum difference between the timestamps of a record found when resynchronizing at the end
+/// of the input and of the last record kept, in seconds
+const MAX_TIME_GAP: i64 = 86_400;
+/// Size of the chunks read from input
+const READ_SIZE: usize = 65536;
+
+const SHB_MAGIC: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+
+/// Data of the input skipped by the repair
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DroppedRange {
+    /// Offset in the (decompressed) input
+    pub offset: u64,
+    pub bytes: u64,
+    pub reason: &'static str,
+}
+
+/// Result of the repair of a capture
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Format of the input and output: `pcap` or `pcapng`
+    pub format: &'static str,
+    /// Number of blocks written (file header and records, for pcap)
+    pub blocks_kept: u64,
+    /// Number of packets written
+    pub packets_kept: u64,
+    /// Data skipped, in input order
+    pub dropped: Vec<DroppedRange>,
+}
+
+impl RepairReport {
+    /// Total number of bytes skipped
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped.iter().map(|d| d.bytes).sum()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let dropped: Vec<_> = self
+            .dropped
+            .iter()
+            .map(|d| json!({"offset": d.offset, "bytes": d.bytes, "reason": d.reason}))
+            .collect();
+        json!({
+            "format": self.format,
+            "blocks_kept": self.blocks_kept,
+            "packets_kept": self.packets_kept,
+            "dropped_bytes": self.dropped_bytes(),
+            "dropped": dropped,
+        })
+    }
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Format: {}", self.format)?;
+        writeln!(f, "Blocks kept: {}", self.blocks_kept)?;
+        writeln!(f, "Packets kept: {}", self.packets_kept)?;
+        writeln!(f, "Bytes dropped: {}", self.dropped_bytes())?;
+        for d in &self.dropped {
+            writeln!(
+                f,
+                "    offset {}: {} bytes ({})",
+                d.offset, d.bytes, d.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Input buffer, allowing to look ahead of the current position
+struct Input {
+    reader: Box<dyn Read>,
+    buf: Vec<u8>,
+    /// Index of the current position in `buf`
+    start: usize,
+    /// Offset of the current position in the input
+    offset: u64,
+    eof: bool,
+}
+
+impl Input {
+    fn new(reader: Box<dyn Read>) -> Self {
+        Input {
+            reader,
+            buf: Vec::new(),
+            start: 0,
+            offset: 0,
+            eof: false,
+        }
+    }
+
+    /// Return `len` bytes from the current position, or less at the end of the input
+    fn peek(&mut self, len: usize) -> io::Result<&[u8]> {
+        while self.buf.len() - self.start < len && !self.eof {
+            self.buf.drain(..self.start);
+            self.start = 0;
+            let end = self.buf.len();
+            self.buf.resize(end + READ_SIZE, 0);
+            let n = match self.reader.read(&mut self.buf[end..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    0
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => 0,
+                Err(e) => {
+                    self.buf.truncate(end);
+                    return Err(e);
+                }
+            };
+            self.buf.truncate(end + n);
+        }
+        let end = self.buf.len().min(self.start + len);
+        Ok(&self.buf[self.start..end])
+    }
+
+    /// Move the current position (the data must have been read with `peek`)
+    fn advance(&mut self, len: usize) {
+        self.start += len;
+        self.offset += len as u64;
+    }
+
+    fn copy_to(&mut self, len: usize, w: &mut dyn Write) -> io::Result<()> {
+        let data = self.peek(len)?;
+        w.write_all(data)?;
+        self.advance(len);
+        Ok(())
+    }
+}
+
+/// Result of the check of a block at the current position
+enum Check {
+    /// Valid block, with its length
+    Valid(usize),
+    Invalid,
+    /// Incomplete block at the end of the input
+    Truncated,
+}
+
+fn read_u32(b: &[u8], big_endian: bool) -> u32 {
+    let bytes = [b[0], b[1], b[2], b[3]];
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Repair the capture read from `input` (uncompressed), and write it to `w`
+///
+/// Returns an error if the input does not start with a valid pcap header or pcap-ng section
+/// header block.
+pub fn repair(input: Box<dyn Read>, mut w: Box<dyn OutputStream>) -> io::Result<RepairReport> {
+    let mut input = Input::new(input);
+    let header = input.peek(24)?;
+    let complete_header = header.len() == 24;
+    let mut magic = [0; 4];
+    if header.len() >= 4 {
+        magic.copy_from_slice(&header[..4]);
+    }
+    let report = match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] if complete_header => {
+            repair_legacy(&mut input, &mut w, false, magic[0] == 0x4d)?
+        }
+        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] if complete_header => {
+            repair_legacy(&mut input, &mut w, true, magic[3] == 0x4d)?
+        }
+        SHB_MAGIC => repair_pcapng(&mut input, &mut w)?,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Neither a pcap nor pcap-ng header was found",
+            ))
+        }
+    };
+    w.finish()?;
+    Ok(report)
+}
+
+/// Skip data until `check` returns `Valid` (or the end of input), and report the skipped data
+fn resync<F>(input: &mut Input, report: &mut RepairReport, mut check: F) -> io::Result<()>
+where
+    F: FnMut(&mut Input) -> io::Result<Check>,
+{
+    let offset = input.offset;
+    let truncated = loop {
+        input.advance(1);
+        if input.peek(1)?.is_empty() {
+            break true;
+        }
+        if let Check::Valid(_) = check(input)? {
+            break false;
+        }
+    };
+    report.dropped.push(DroppedRange {
+        offset,
+        bytes: input.offset - offset,
+        reason: if truncated {
+            "truncated or invalid data at end of input"
+        } else {
+            "invalid data"
+        },
+    });
+    Ok(())
+}
+
+/// Return the timestamp (seconds) and the length of the legacy pcap record starting with header
+/// `h`, if it is consistent
+fn legacy_record(h: &[u8], big_endian: bool, nanos: bool) -> Option<(u32, usize)> {
+    if h.len() < 16 {
+        return None;
+    }
+    let ts_sec = read_u32(h, big_endian);
+    let frac = read_u32(&h[4..8], big_endian);
+    let caplen = read_u32(&h[8..12], big_endian);
+    let origlen = read_u32(&h[12..16], big_endian);
+    let max_frac = if nanos { 1_000_000_000 } else { 1_000_000 };
+    if frac >= max_frac || caplen > MAX_CAPLEN || caplen > origlen {
+        return None;
+    }
+    Some((ts_sec, 16 + caplen as usize))
+}
+
+fn check_legacy_record(input: &mut Input, big_endian: bool, nanos: bool) -> io::Result<Check> {
+    let h = input.peek(16)?;
+    let len = match legacy_record(h, big_endian, nanos) {
+        Some((_, len)) => len,
+        None if h.len() < 16 => return Ok(Check::Truncated),
+        None => return Ok(Check::Invalid),
+    };
+    if input.peek(len)?.len() < len {
+        return Ok(Check::Truncated);
+    }
+    Ok(Check::Valid(len))
+}
+
+/// Check the legacy pcap record at the current position, when resynchronizing
+///
+/// Payload data can look like a consistent record header, so the record must be followed by
+/// [`RESYNC_RECORDS`] valid records. If the input ends before, the timestamps of the records must
+/// be close to `last_ts`, the timestamp of the last record kept.
+fn check_legacy_resync(
+    input: &mut Input,
+    big_endian: bool,
+    nanos: bool,
+    last_ts: Option<u32>,
+) -> io::Result<Check> {
+    let mut first_len = 0;
+    let mut pos = 0;
+    let mut ts_ok = true;
+    for i in 0..=RESYNC_RECORDS {
+        let h = input.peek(pos + 16)?;
+        if i > 0 && h.len() == pos {
+            // end of input
+            return Ok(if ts_ok {
+                Check::Valid(first_len)
+            } else {
+                Check::Invalid
+            });
+        }
+        let (ts_sec, len) = match legacy_record(&h[pos..], big_endian, nanos) {
+            Some(r) => r,
+            None => return Ok(Check::Invalid),
+        };
+        if input.peek(pos + len)?.len() < pos + len {
+            return Ok(Check::Invalid);
+        }
+        if let Some(last_ts) = last_ts {
+            ts_ok &= (i64::from(ts_sec) - i64::from(last_ts)).abs() <= MAX_TIME_GAP;
+        }
+        if i == 0 {
+            first_len = len;
+        }
+        pos += len;
+    }
+    Ok(Check::Valid(first_len))
+}
+
+fn repair_legacy(
+    input: &mut Input,
+    w: &mut dyn Write,
+    big_endian: bool,
+    nanos: bool,
+) -> io::Result<RepairReport> {
+    let mut report = RepairReport {
+        format: "pcap",
+        ..RepairReport::default()
+    };
+    input.copy_to(24, w)?;
+    report.blocks_kept += 1;
+    let mut last_ts = None;
+    while !input.peek(1)?.is_empty() {
+        match check_legacy_record(input, big_endian, nanos)? {
+            Check::Valid(len) => {
+                last_ts = Some(read_u32(input.peek(4)?, big_endian));
+                input.copy_to(len, w)?;
+                report.blocks_kept += 1;
+                report.packets_kept += 1;
+            }
+            Check::Invalid | Check::Truncated => resync(input, &mut report, |input| {
+                check_legacy_resync(input, big_endian, nanos, last_ts)
+            })?,
+        }
+    }
+    Ok(report)
+}
+
+/// Check the pcap-ng block at the current position
+///
+/// The byte order of section header blocks is read from the block, and `big_endian` is updated.
+fn check_pcapng_block(input: &mut Input, big_endian: &mut bool) -> io::Result<Check> {
+    let h = input.peek(12)?;
+    if h.len() < 12 {
+        return Ok(Check::Truncated);
+    }
+    let be = if h[..4] == SHB_MAGIC {
+        match &h[8..12] {
+            [0x4d, 0x3c, 0x2b, 0x1a] => false,
+            [0x1a, 0x2b, 0x3c, 0x4d] => true,
+            _ => return Ok(Check::Invalid),
+        }
+    } else {
+        *big_endian
+    };
+    let block_type = read_u32(h, be);
+    let len = read_u32(&h[4..], be);
+    let known = matches!(
+        block_type,
+        0x0A0D_0D0A | 1..=6 | 9 | 0xA | 0x0BAD | 0x4000_0BAD | 0x8000_0001
+    );
+    if !known || len < 12 || len % 4 != 0 || len > MAX_BLOCK_LEN {
+        return Ok(Check::Invalid);
+    }
+    let len = len as usize;
+    let data = input.peek(len)?;
+    if data.len() < len {
+        return Ok(Check::Truncated);
+    }
+    if read_u32(&data[len - 4..], be) as usize != len {
+        return Ok(Check::Invalid);
+    }
+    *big_endian = be;
+    Ok(Check::Valid(len))
+}
+
+fn repair_pcapng(input: &mut Input, w: &mut dyn Write) -> io::Result<RepairReport> {
+    let mut report = RepairReport {
+        format: "pcapng",
+        ..RepairReport::default()
+    };
+    let mut big_endian = false;
+    if !matches!(check_pcapng_block(input, &mut big_endian)?, Check::Valid(_)) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid pcap-ng section header block",
+        ));
+    }
+    while !input.peek(1)?.is_empty() {
+        match check_pcapng_block(input, &mut big_endian)? {
+            Check::Valid(len) => {
+                let block_type = read_u32(input.peek(4)?, big_endian);
+                input.copy_to(len, w)?;
+                report.blocks_kept += 1;
+                // EPB, SPB and (obsolete) PB
+                if matches!(block_type, 2 | 3 | 6) {
+                    report.packets_kept += 1;
+                }
+            }
+            Check::Invalid | Check::Truncated => resync(input, &mut report, |input| {
+                check_pcapng_block(input, &mut big_endian)
+            })?,
+        }
+    }
+    Ok(report)
+}
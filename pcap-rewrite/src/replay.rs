@@ -0,0 +1,198 @@
+//! Replay of packets on a network interface
+//!
+//! [`ReplayWriter`] sends packets to a network interface through an `AF_PACKET` socket (Linux
+//! only, the `CAP_NET_RAW` capability is required). Packets are sent as ethernet frames, so the
+//! output link type must be `ETHERNET`.
+//!
+//! The gaps between the timestamps of input packets are reproduced, divided by the speed
+//! multiplier of [`ReplayOptions`]. Packets with a timestamp before the first packet are sent
+//! immediately.
+
+use crate::compression::OutputStream;
+use crate::traits::Writer;
+use libpcap_tools::Packet;
+use log::warn;
+use pcap_parser::{Linktype, PcapBlockOwned};
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Maximum duration of a sleep, so an interruption is handled quickly during long gaps
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+/// Delay before sending a packet again, when the queue of the interface is full
+const RETRY_DELAY: Duration = Duration::from_micros(100);
+
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    /// Name of the network interface
+    pub interface: String,
+    /// Speed multiplier of the original timing (for ex. 2.0 replays packets twice as fast).
+    /// Packets are sent as fast as possible if 0
+    pub speed: f64,
+}
+
+/// Schedule of packets, reproducing the gaps between their timestamps
+struct Pacer {
+    speed: f64,
+    /// Time when the first packet was sent, and its timestamp
+    start: Option<(Instant, Duration)>,
+}
+
+impl Pacer {
+    /// Return the time when a packet with timestamp `ts` must be sent, or `None` to send it now
+    fn deadline(&mut self, ts: Duration) -> Option<Instant> {
+        if self.speed <= 0.0 {
+            return None;
+        }
+        let (start, first_ts) = *self.start.get_or_insert_with(|| (Instant::now(), ts));
+        let offset = ts.checked_sub(first_ts)?;
+        Some(start + offset.div_f64(self.speed))
+    }
+}
+
+/// Writer sending packets to a network interface
+pub struct ReplayWriter {
+    socket: File,
+    pacer: Pacer,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl ReplayWriter {
+    /// Open a socket sending packets to `options.interface`
+    ///
+    /// If `stop` is set to `true`, waiting for the next packet is interrupted.
+    pub fn open(options: &ReplayOptions, stop: Option<Arc<AtomicBool>>) -> io::Result<Self> {
+        let socket = open_packet_socket(&options.interface)?;
+        Ok(ReplayWriter {
+            socket,
+            pacer: Pacer {
+                speed: options.speed,
+                start: None,
+            },
+            stop,
+        })
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop
+            .as_ref()
+            .map_or(false, |stop| stop.load(Ordering::Relaxed))
+    }
+
+    fn wait_until(&self, deadline: Instant) {
+        loop {
+            let now = Instant::now();
+            if now >= deadline || self.stopped() {
+                return;
+            }
+            thread::sleep((deadline - now).min(MAX_SLEEP));
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<usize> {
+        loop {
+            match self.socket.write(data) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => thread::sleep(RETRY_DELAY),
+                r => return r,
+            }
+        }
+    }
+}
+
+impl Writer for ReplayWriter {
+    fn set_nanosecond_precision(&mut self, _nanosecond: bool) {}
+
+    fn init_file(&mut self, _snaplen: usize, linktype: Linktype) -> Result<usize, io::Error> {
+        if linktype != Linktype::ETHERNET {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Packets can only be replayed with the ethernet output link type",
+            ));
+        }
+        Ok(0)
+    }
+
+    fn write_block(&mut self, _block: &PcapBlockOwned) -> Result<usize, io::Error> {
+        Ok(0)
+    }
+
+    fn write_packet(
+        &mut self,
+        packet: &Packet,
+        data: &[u8],
+        _origlen: u32,
+    ) -> Result<usize, io::Error> {
+        let ts = Duration::new(u64::from(packet.ts.secs), packet.ts.nanos);
+        if let Some(deadline) = self.pacer.deadline(ts) {
+            self.wait_until(deadline);
+        }
+        if self.stopped() {
+            return Ok(0);
+        }
+        // a packet can be rejected by the interface (for ex. if larger than the MTU): it is
+        // skipped, and the replay continues
+        self.send(data).or_else(|e| {
+            warn!("Could not send packet {}: {}", packet.pcap_index, e);
+            Ok(0)
+        })
+    }
+
+    fn set_output(&mut self, _w: Box<dyn OutputStream>) -> Result<usize, io::Error> {
+        Err(Error::new(
+            ErrorKind::Other,
+            "Output cannot be changed when replaying packets",
+        ))
+    }
+
+    fn finish(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_packet_socket(interface: &str) -> io::Result<File> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let name = CString::new(interface)?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Unknown network interface '{}'", interface),
+        ));
+    }
+    // protocol 0: the socket only sends packets, and does not receive any
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // the socket is closed when the file is dropped, and writing to the file sends a packet
+    let socket = unsafe { File::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_ifindex = ifindex as i32;
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_packet_socket(_interface: &str) -> io::Result<File> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "Replay is only supported on Linux",
+    ))
+}
@@ -1,13 +1,20 @@
+use crate::comments::PacketComments;
+use crate::compression::OutputStream;
 use crate::filters::filter::*;
+use crate::flow_output::FlowOutput;
+use crate::link_layer;
 use crate::pcap::*;
-use crate::pcapng::*;
+use crate::pcapng_writer::*;
+use crate::stats::RewriteStats;
 use crate::traits::Writer;
+use crate::transforms::transform::{apply_transforms, Transform};
 use libpcap_tools::{Error, Packet, ParseBlockContext, ParseContext, PcapAnalyzer};
 use log::{debug, error, info};
 use pcap_parser::data::*;
 use pcap_parser::Linktype;
-use pcap_parser::{Block, PcapBlockOwned};
-use std::io::Write;
+use pcap_parser::PcapBlockOwned;
+use std::borrow::Cow;
+use std::io;
 
 #[derive(Copy, Clone, Debug)]
 pub enum FileFormat {
@@ -21,45 +28,184 @@ struct Stats {
     num_bytes: u64,
 }
 
+/// Output file rotation policy
+///
+/// A new output file is started when one of the limits is reached. Limits are checked after
+/// writing a packet, so a file is rotated after exceeding `max_size`: it can be larger than the
+/// limit, by at most one packet.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SplitPolicy {
+    /// Size of an output file, in bytes, after which a new file is started
+    pub max_size: Option<u64>,
+    /// Maximum number of packets in an output file
+    pub max_packets: Option<u64>,
+}
+
+impl SplitPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_size.is_some() || self.max_packets.is_some()
+    }
+
+    fn is_reached(&self, stats: &Stats) -> bool {
+        self.max_size.map_or(false, |max| stats.num_bytes >= max)
+            || self
+                .max_packets
+                .map_or(false, |max| u64::from(stats.num_packets) >= max)
+    }
+}
+
+/// Function opening the next output file
+pub type NextOutputFn = Box<dyn FnMut() -> Result<Box<dyn OutputStream>, io::Error>>;
+
 pub struct Rewriter {
     snaplen: usize,
     output_linktype: Linktype,
     output_layer: usize,
+    /// Add an Ethernet header to L3 packets, if the output is L2
+    convert_linktype: bool,
     writer: Box<dyn Writer>,
-    filters: Vec<Box<dyn Filter>>,
+    filters: FilterChain,
+    /// Transformations applied to packets accepted by filters
+    transforms: Vec<Box<dyn Transform>>,
     stats: Stats,
     run_pre_analysis: bool,
+    /// Output files are initialized when the input header is read, to use the same timestamp
+    /// precision
+    output_initialized: bool,
+    split: SplitPolicy,
+    next_output: Option<NextOutputFn>,
+    /// Statistics of the current output file
+    file_stats: Stats,
+    rotate_pending: bool,
+    /// Write each flow to its own file, instead of using `writer`
+    flow_output: Option<FlowOutput>,
+    /// Writer for packets dropped by filters
+    rejected_writer: Option<Box<dyn Writer>>,
+    rejected_stats: Stats,
+    /// Comments added to output packets
+    comments: Option<PacketComments>,
+    /// Statistics of the run, reported to the user
+    report: RewriteStats,
+}
+
+fn new_writer(output: Box<dyn OutputStream>, output_format: FileFormat) -> Box<dyn Writer> {
+    match output_format {
+        FileFormat::Pcap => Box::new(PcapWriter::new(output)),
+        FileFormat::PcapNG => Box::new(PcapNGWriter::new(output)),
+    }
 }
 
 #[allow(dead_code)]
 impl Rewriter {
     pub fn new(
-        output: Box<dyn Write>,
+        output: Box<dyn OutputStream>,
         output_format: FileFormat,
         filters: Vec<Box<dyn Filter>>,
     ) -> Self {
+        Rewriter::with_writer(new_writer(output, output_format), filters)
+    }
+
+    /// Create a rewriter sending packets to `writer`, instead of a file
+    pub(crate) fn with_writer(writer: Box<dyn Writer>, filters: Vec<Box<dyn Filter>>) -> Self {
         let output_linktype = Linktype::RAW;
         let output_layer = get_linktype_layer(output_linktype);
-        let writer: Box<dyn Writer> = match output_format {
-            FileFormat::Pcap => Box::new(PcapWriter::new(output)),
-            FileFormat::PcapNG => Box::new(PcapNGWriter::new(output)),
-        };
         Rewriter {
-            snaplen: 65535, // XXX
+            snaplen: 65535,
             output_linktype,
             output_layer,
+            convert_linktype: false,
             writer,
-            filters,
+            filters: FilterChain::new(filters),
+            transforms: Vec::new(),
             stats: Stats::default(),
             run_pre_analysis: false,
+            output_initialized: false,
+            split: SplitPolicy::default(),
+            next_output: None,
+            file_stats: Stats::default(),
+            rotate_pending: false,
+            flow_output: None,
+            rejected_writer: None,
+            rejected_stats: Stats::default(),
+            comments: None,
+            report: RewriteStats::default(),
+        }
+    }
+
+    /// Truncate packets to `snaplen` bytes on output. The original length of packets is kept.
+    ///
+    /// A value of 0 disables truncation (the snaplen written in output file headers is then
+    /// 65535).
+    pub fn set_snaplen(&mut self, snaplen: usize) {
+        self.snaplen = snaplen;
+    }
+
+    /// Write packets dropped by filters to `output`, instead of discarding them
+    ///
+    /// Rejected packets are converted and transformed like accepted packets, so both outputs
+    /// contain complementary sets of the input.
+    pub fn set_rejected_output(
+        &mut self,
+        output: Box<dyn OutputStream>,
+        output_format: FileFormat,
+    ) {
+        self.rejected_writer = Some(new_writer(output, output_format));
+    }
+
+    /// Write each flow to its own file. The main output is not used.
+    pub fn set_flow_output(&mut self, flow_output: FlowOutput) {
+        self.flow_output = Some(flow_output);
+    }
+
+    /// Add comments to output packets (pcapng output only). Comments are not added to
+    /// rejected packets.
+    pub fn set_comments(&mut self, comments: PacketComments) {
+        self.comments = Some(comments);
+    }
+
+    /// Split output into multiple files, using `next_output` to open each new file
+    pub fn set_split(&mut self, split: SplitPolicy, next_output: NextOutputFn) {
+        self.split = split;
+        self.next_output = Some(next_output);
+    }
+
+    fn rotate_output(&mut self) -> Result<(), Error> {
+        let next_output = self
+            .next_output
+            .as_mut()
+            .ok_or(Error::Generic("Output rotation without output function"))?;
+        let w = next_output()?;
+        let written = self.writer.set_output(w)?;
+        self.file_stats = Stats {
+            num_packets: 0,
+            num_bytes: written as u64,
+        };
+        self.rotate_pending = false;
+        Ok(())
+    }
+
+    /// Write buffered data of all outputs
+    ///
+    /// Must be called at the end of the run, so write errors are reported.
+    pub fn finish(&mut self) -> Result<(), io::Error> {
+        match self.flow_output.as_mut() {
+            Some(flow_output) => flow_output.finish()?,
+            None => self.writer.finish()?,
         }
+        if let Some(writer) = self.rejected_writer.as_mut() {
+            writer.finish()?;
+        }
+        Ok(())
     }
 
     /// Return true if one of the plugins or more require a pre-analysis pass
     pub fn require_pre_analysis(&self) -> bool {
-        self.filters
-            .iter()
-            .fold(false, |acc, filter| acc | filter.require_pre_analysis())
+        self.filters.require_pre_analysis()
+    }
+
+    /// Return true if all the plugins requiring a pre-analysis pass can run without it
+    pub fn optional_pre_analysis(&self) -> bool {
+        self.filters.optional_pre_analysis()
     }
 
     /// Set the rewriter's run pre analysis.
@@ -72,6 +218,23 @@ impl Rewriter {
         self.filters.push(f);
     }
 
+    /// Set the output link type (default: RAW)
+    pub fn set_output_linktype(&mut self, linktype: Linktype) {
+        self.output_linktype = linktype;
+        self.output_layer = get_linktype_layer(linktype);
+    }
+
+    /// Convert L3 input packets (for ex. Linux SLL or NULL link types) to Ethernet, if the output
+    /// link type is `ETHERNET`. MAC addresses are set to zero.
+    pub fn set_convert_linktype(&mut self, convert_linktype: bool) {
+        self.convert_linktype = convert_linktype;
+    }
+
+    /// Set the list of transformations
+    pub fn set_transforms(&mut self, transforms: Vec<Box<dyn Transform>>) {
+        self.transforms = transforms;
+    }
+
     /// Return an iterator over the filters
     pub fn filters(&self) -> impl Iterator<Item = &Box<dyn Filter>> {
         self.filters.iter()
@@ -81,19 +244,121 @@ impl Rewriter {
     pub fn filters_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn Filter>> {
         self.filters.iter_mut()
     }
+
+    /// Return the statistics of the run
+    pub fn stats(&self) -> &RewriteStats {
+        &self.report
+    }
+
+    /// Return the statistics of the run, and reset them
+    pub fn take_stats(&mut self) -> RewriteStats {
+        std::mem::take(&mut self.report)
+    }
+
+    /// Convert packet data to the output layer, truncate it and apply transformations
+    ///
+    /// Returns the output data, and its original length (before truncation, including the
+    /// bytes not captured in the input).
+    fn output_data<'d>(
+        &mut self,
+        packet: &Packet,
+        packet_data: &'d PacketData,
+        ctx: &ParseContext,
+    ) -> Result<(Cow<'d, [u8]>, u32), Error> {
+        // convert data
+        let data = convert_layer(packet_data, self.output_layer, self.convert_linktype)
+            .map_err(Error::Generic)?;
+        let missing = packet.origlen.saturating_sub(packet.caplen) as usize;
+        let origlen = data.len() + missing;
+        // truncate it to new snaplen
+        let data = if self.snaplen > 0 && data.len() > self.snaplen {
+            debug!(
+                "truncating index {} to {} bytes",
+                ctx.pcap_index, self.snaplen
+            );
+            match data {
+                Cow::Borrowed(data) => Cow::Borrowed(&data[..self.snaplen]),
+                Cow::Owned(mut data) => {
+                    data.truncate(self.snaplen);
+                    Cow::Owned(data)
+                }
+            }
+        } else {
+            data
+        };
+        let truncated = origlen - missing - data.len();
+        // apply transformations on a copy of data
+        if self.transforms.is_empty() {
+            return Ok((data, origlen as u32));
+        }
+        let mut buf = data.into_owned();
+        if let Err(e) = apply_transforms(&mut self.transforms, &mut buf, self.output_layer) {
+            error!("Transform returned fatal error {}", e);
+            return Err(Error::Generic("Transform fatal error"));
+        }
+        let origlen = buf.len() + truncated + missing;
+        Ok((Cow::Owned(buf), origlen as u32))
+    }
+
+    /// Write the headers of output files
+    ///
+    /// `nanosecond` is true if the input is a legacy pcap file with nanosecond timestamps.
+    fn init_output(&mut self, nanosecond: bool) -> Result<(), Error> {
+        self.output_initialized = true;
+        let snaplen = if self.snaplen > 0 {
+            self.snaplen
+        } else {
+            65535
+        };
+        if let Some(flow_output) = self.flow_output.as_mut() {
+            flow_output.init(snaplen, self.output_linktype, nanosecond);
+        } else {
+            self.writer.set_nanosecond_precision(nanosecond);
+            let written = self.writer.init_file(snaplen, self.output_linktype)?;
+            self.file_stats.num_bytes = written as u64;
+        }
+        if let Some(writer) = self.rejected_writer.as_mut() {
+            writer.set_nanosecond_precision(nanosecond);
+            let written = writer.init_file(snaplen, self.output_linktype)?;
+            self.rejected_stats.num_bytes = written as u64;
+        }
+        Ok(())
+    }
+
+    fn write_rejected(&mut self, packet: &Packet, ctx: &ParseContext) -> Result<(), Error> {
+        let (data, origlen) = self.output_data(packet, &packet.data, ctx)?;
+        if let Some(writer) = self.rejected_writer.as_mut() {
+            debug!(
+                "Writing rejected packet {} ({} bytes)",
+                ctx.pcap_index,
+                data.len()
+            );
+            let written = writer.write_packet(packet, &data, origlen)?;
+            self.rejected_stats.num_packets += 1;
+            self.rejected_stats.num_bytes += written as u64;
+        }
+        Ok(())
+    }
 }
 
-fn convert_layer<'p>(input: &'p PacketData, output_layer: usize) -> Result<&'p [u8], &'static str> {
+fn convert_layer<'p>(
+    input: &'p PacketData,
+    output_layer: usize,
+    convert_linktype: bool,
+) -> Result<Cow<'p, [u8]>, &'static str> {
     match (input, output_layer) {
-        (PacketData::L2(data), 2) => Ok(data),
+        (PacketData::L2(data), 2) => Ok(Cow::Borrowed(data)),
         (PacketData::L2(data), 3) => {
             if data.len() < 14 {
                 return Err("L2 data too small for ethernet");
             }
-            Ok(&data[14..])
+            Ok(Cow::Borrowed(&data[14..]))
+        }
+        (PacketData::L3(ethertype, data), 2) if convert_linktype => {
+            Ok(Cow::Owned(link_layer::ethernet_of_l3(*ethertype, data)))
         }
         (PacketData::L3(_, _), 2) => Err("Can't convert L3 data to L2"),
-        (PacketData::L3(_, data), 3) => Ok(data),
+        (PacketData::L3(_, data), 3) => Ok(Cow::Borrowed(data)),
         (PacketData::L4(_, _), _) => Err("Input is L4 - don't know what to do"),
         (PacketData::Unsupported(_), _) => Err("Input link type not supported"),
         (_, _) => Err("Invalid layer conversion"),
@@ -102,6 +367,7 @@ fn convert_layer<'p>(input: &'p PacketData, output_layer: usize) -> Result<&'p [
 
 fn get_linktype_layer(l: Linktype) -> usize {
     match l {
+        Linktype::ETHERNET => 2,
         Linktype::RAW => 3,
         _ => panic!("Unsupported output link type"),
     }
@@ -109,9 +375,13 @@ fn get_linktype_layer(l: Linktype) -> usize {
 
 impl PcapAnalyzer for Rewriter {
     fn init(&mut self) -> Result<(), Error> {
-        if !self.run_pre_analysis {
-            self.writer.init_file(self.snaplen, self.output_linktype)?;
+        if self.run_pre_analysis {
+            return Ok(());
         }
+        let filter_names = self.filters.iter().map(|f| f.name().to_owned()).collect();
+        self.report = RewriteStats::new(filter_names);
+        // output files are initialized with the first block (the file header)
+        self.output_initialized = false;
         Ok(())
     }
 
@@ -120,16 +390,27 @@ impl PcapAnalyzer for Rewriter {
         block: &PcapBlockOwned,
         _block_ctx: &ParseBlockContext,
     ) -> Result<(), Error> {
+        // nothing is written during the pre-analysis pass
+        if self.run_pre_analysis {
+            return Ok(());
+        }
+        if !self.output_initialized {
+            let nanosecond = match block {
+                PcapBlockOwned::LegacyHeader(hdr) => hdr.is_nanosecond_precision(),
+                _ => false,
+            };
+            self.init_output(nanosecond)?;
+        }
         // handle specific pcapng blocks
-        if let PcapBlockOwned::NG(b) = block {
-            match b {
-                // skip data blocks, processed in `handle_packet`
-                Block::SimplePacket(_) | Block::EnhancedPacket(_) => (),
-                _ => {
-                    self.writer
-                        .write_block(block)
-                        .expect("Could not write packet");
-                }
+        // Data blocks are also given to the writer (for ex. to get the original timestamp), but
+        // packets are written in `handle_packet`
+        if let PcapBlockOwned::NG(_) = block {
+            // blocks are not copied to flow outputs
+            if self.flow_output.is_none() {
+                self.writer.write_block(block)?;
+            }
+            if let Some(writer) = self.rejected_writer.as_mut() {
+                writer.write_block(block)?;
             }
         }
         // legacy packets are processed in `handle_packet`
@@ -138,6 +419,11 @@ impl PcapAnalyzer for Rewriter {
 
     fn handle_packet(&mut self, packet: &Packet, ctx: &ParseContext) -> Result<(), Error> {
         let link_type = packet.link_type;
+        // filters, statistics and output only handle L2 (Ethernet) and L3 data
+        let packet = &Packet {
+            data: link_layer::decode_link_layer(link_type, packet.data.clone()),
+            ..packet.clone()
+        };
         // let snaplen = if_info.snaplen;
         // debug!("snaplen: {}", snaplen);
 
@@ -152,37 +438,59 @@ impl PcapAnalyzer for Rewriter {
             return Ok(());
         }
 
+        self.report.add_input_packet(packet);
+
         // apply filters
-        let data = match apply_filters(&self.filters, packet.data.clone()) {
-            Ok(Verdict::Accept(d)) => d,
-            Ok(Verdict::Drop) => {
+        let packet_data = match self.filters.filter_with_index(packet.data.clone()) {
+            Ok((Verdict::Accept(d), _)) => d,
+            Ok((Verdict::Drop, index)) => {
+                if let Some(index) = index {
+                    self.report.add_dropped_packet(index);
+                }
+                if self.rejected_writer.is_some() {
+                    self.write_rejected(packet, ctx)?;
+                }
                 return Ok(());
             }
             Err(e) => panic!("Filter fatal error: {}", e),
         };
-        // convert data
-        let data = convert_layer(&data, self.output_layer).map_err(Error::Generic)?;
-        // truncate it to new snaplen
-        let data = {
-            if self.snaplen > 0 && data.len() > self.snaplen {
-                info!(
-                    "truncating index {} to {} bytes",
-                    ctx.pcap_index, self.snaplen
-                );
-                &data[..self.snaplen as usize]
-            } else {
-                data
-            }
-        };
+        let (data, origlen) = self.output_data(packet, &packet_data, ctx)?;
         debug!(
             "Writing packet {} with link_type {} ({} bytes)",
             ctx.pcap_index,
             link_type,
             data.len()
         );
-        let written = self.writer.write_packet(packet, data)?;
+        if let Some(flow_output) = self.flow_output.as_mut() {
+            let written = flow_output.write_packet(packet, &packet_data, &data, origlen)?;
+            self.report.add_output_packet(data.len());
+            self.stats.num_packets += 1;
+            self.stats.num_bytes += written as u64;
+            return Ok(());
+        }
+        // rotate before writing, so the last output file is never empty
+        if self.rotate_pending {
+            self.rotate_output()?;
+        }
+        if let Some(comments) = &self.comments {
+            let mut commented = false;
+            for comment in comments.get(packet) {
+                self.writer.add_packet_comment(comment);
+                commented = true;
+            }
+            if commented {
+                self.report.add_commented_packet();
+            }
+        }
+        let written = self.writer.write_packet(packet, &data, origlen)?;
+        self.report.add_output_packet(data.len());
         self.stats.num_packets += 1;
         self.stats.num_bytes += written as u64;
+        self.file_stats.num_packets += 1;
+        self.file_stats.num_bytes += written as u64;
+        if self.split.is_reached(&self.file_stats) {
+            self.rotate_pending = true;
+        }
 
         Ok(())
     }
@@ -200,7 +508,16 @@ impl PcapAnalyzer for Rewriter {
 
             return;
         }
+        // empty input: write the headers anyway
+        if !self.output_initialized {
+            if let Err(e) = self.init_output(false) {
+                error!("Could not initialize output: {}", e);
+            }
+        }
         info!("Done.");
         info!("Stats: {:?}", self.stats);
+        if self.rejected_writer.is_some() {
+            info!("Rejected stats: {:?}", self.rejected_stats);
+        }
     }
 }
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use libpcap_tools::{Duration, Packet};
+use pcap_parser::data::PacketData;
+use pcap_parser::Linktype;
+use serde_json::{json, Value};
+
+use crate::filters::filter_utils;
+
+/// Statistics of a rewrite run
+#[derive(Debug, Default)]
+pub struct RewriteStats {
+    /// Number of packets read from input
+    pub packets_read: u64,
+    /// Number of bytes of packet data read from input
+    pub bytes_in: u64,
+    /// Number of packets written to output
+    pub packets_written: u64,
+    /// Number of bytes of packet data written to output (after conversion and transformations)
+    pub bytes_out: u64,
+    /// Number of packets written with added comments
+    pub packets_commented: u64,
+    /// Number of packets dropped by each filter, in chain order
+    pub dropped_per_filter: Vec<(String, u64)>,
+    /// Timestamp of the oldest packet read
+    pub first_ts: Option<Duration>,
+    /// Timestamp of the newest packet read
+    pub last_ts: Option<Duration>,
+    /// Number of packets read for each link type
+    pub link_types: BTreeMap<i32, u64>,
+    /// Number of packets read for each ethertype (L2 and L3 packets only)
+    pub ether_types: BTreeMap<u16, u64>,
+}
+
+fn format_ts(ts: &Duration) -> String {
+    format!("{}.{:06}", ts.secs, ts.micros())
+}
+
+impl RewriteStats {
+    /// Create statistics for a chain of filters, using the filter names
+    pub fn new(filter_names: Vec<String>) -> Self {
+        RewriteStats {
+            dropped_per_filter: filter_names.into_iter().map(|name| (name, 0)).collect(),
+            ..RewriteStats::default()
+        }
+    }
+
+    /// Account a packet read from input
+    pub fn add_input_packet(&mut self, packet: &Packet) {
+        self.packets_read += 1;
+        self.bytes_in += u64::from(packet.caplen);
+        self.first_ts = Some(self.first_ts.map_or(packet.ts, |ts| ts.min(packet.ts)));
+        self.last_ts = Some(self.last_ts.map_or(packet.ts, |ts| ts.max(packet.ts)));
+        *self.link_types.entry(packet.link_type.0).or_insert(0) += 1;
+        let ether_type = match packet.data {
+            PacketData::L2(data) => filter_utils::parse_ethernet_vlan(data)
+                .ok()
+                .map(|ethernet_l3| ethernet_l3.ethertype.0),
+            PacketData::L3(ether_type, _) => Some(ether_type),
+            _ => None,
+        };
+        if let Some(ether_type) = ether_type {
+            *self.ether_types.entry(ether_type).or_insert(0) += 1;
+        }
+    }
+
+    /// Account a packet written to output
+    pub fn add_output_packet(&mut self, data_len: usize) {
+        self.packets_written += 1;
+        self.bytes_out += data_len as u64;
+    }
+
+    /// Account a packet written with added comments
+    pub fn add_commented_packet(&mut self) {
+        self.packets_commented += 1;
+    }
+
+    /// Account a packet dropped by the filter at `index` in the chain
+    pub fn add_dropped_packet(&mut self, index: usize) {
+        if let Some((_, count)) = self.dropped_per_filter.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    pub fn packets_dropped(&self) -> u64 {
+        self.dropped_per_filter.iter().map(|(_, count)| count).sum()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let dropped_per_filter: Vec<Value> = self
+            .dropped_per_filter
+            .iter()
+            .map(|(name, count)| json!({ "filter": name, "dropped": count }))
+            .collect();
+        let link_types: serde_json::Map<String, Value> = self
+            .link_types
+            .iter()
+            .map(|(l, count)| (format!("{:?}", Linktype(*l)), json!(count)))
+            .collect();
+        let ether_types: serde_json::Map<String, Value> = self
+            .ether_types
+            .iter()
+            .map(|(e, count)| (format!("0x{:04x}", e), json!(count)))
+            .collect();
+        json!({
+            "packets_read": self.packets_read,
+            "packets_written": self.packets_written,
+            "packets_dropped": self.packets_dropped(),
+            "packets_commented": self.packets_commented,
+            "dropped_per_filter": dropped_per_filter,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "first_timestamp": self.first_ts.as_ref().map(format_ts),
+            "last_timestamp": self.last_ts.as_ref().map(format_ts),
+            "link_types": link_types,
+            "ether_types": ether_types,
+        })
+    }
+}
+
+impl fmt::Display for RewriteStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Packets read: {}", self.packets_read)?;
+        writeln!(f, "Packets written: {}", self.packets_written)?;
+        writeln!(f, "Packets dropped: {}", self.packets_dropped())?;
+        for (index, (name, count)) in self.dropped_per_filter.iter().enumerate() {
+            writeln!(f, "    filter {} ({}): {}", index, name, count)?;
+        }
+        if self.packets_commented > 0 {
+            writeln!(f, "Packets commented: {}", self.packets_commented)?;
+        }
+        writeln!(f, "Bytes in: {}", self.bytes_in)?;
+        writeln!(f, "Bytes out: {}", self.bytes_out)?;
+        if let (Some(first_ts), Some(last_ts)) = (&self.first_ts, &self.last_ts) {
+            writeln!(
+                f,
+                "Time range: {} - {}",
+                format_ts(first_ts),
+                format_ts(last_ts)
+            )?;
+        }
+        writeln!(f, "Link types:")?;
+        for (l, count) in &self.link_types {
+            writeln!(f, "    {:?}: {}", Linktype(*l), count)?;
+        }
+        writeln!(f, "Ethertypes:")?;
+        for (e, count) in &self.ether_types {
+            writeln!(f, "    0x{:04x}: {}", e, count)?;
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,40 @@
+use crate::compression::OutputStream;
 use libpcap_tools::Packet;
 use pcap_parser::{Linktype, PcapBlockOwned};
 use std::io;
 
 pub trait Writer {
+    /// Write timestamps with nanosecond precision, if the input uses nanoseconds (default:
+    /// microseconds). Must be called before `init_file`.
+    fn set_nanosecond_precision(&mut self, nanosecond: bool);
+
     fn init_file(&mut self, snaplen: usize, linktype: Linktype) -> Result<usize, io::Error>;
 
     fn write_block(&mut self, _block: &PcapBlockOwned) -> Result<usize, io::Error>;
 
-    fn write_packet(&mut self, packet: &Packet, data: &[u8]) -> Result<usize, io::Error>;
+    /// Write packet `data`. `origlen` is the original length of the packet, which can be larger
+    /// than `data` if the packet was truncated
+    fn write_packet(
+        &mut self,
+        packet: &Packet,
+        data: &[u8],
+        origlen: u32,
+    ) -> Result<usize, io::Error>;
+
+    /// Add a comment to the next packet written
+    ///
+    /// Comments are only supported by the pcapng format, other formats ignore them.
+    fn add_packet_comment(&mut self, _comment: &str) {}
+
+    /// Continue writing to a new output
+    ///
+    /// The file header (and, for pcapng, the interfaces) is written again, so the new output
+    /// is a valid file. The previous output is completed. Returns the number of bytes written.
+    fn set_output(&mut self, w: Box<dyn OutputStream>) -> Result<usize, io::Error>;
+
+    /// Write buffered data, and complete the output (see [`OutputStream::finish`])
+    ///
+    /// Must be called after the last packet: errors are not reported if the output is only
+    /// dropped.
+    fn finish(&mut self) -> Result<(), io::Error>;
 }
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use csv::ReaderBuilder;
+
+use crate::transforms::checksum::update_checksums_for_addresses;
+use crate::transforms::transform::Transform;
+
+/// Prefix-preserving anonymization of IP addresses (Crypto-PAn)
+///
+/// If two addresses share a prefix of `n` bits, their anonymized versions also share
+/// a prefix of `n` bits. The result only depends on the key, so the same key gives the
+/// same mapping for different captures.
+pub struct CryptoPan {
+    cipher: Aes128,
+    pad: u128,
+}
+
+impl CryptoPan {
+    /// Build anonymizer from a 32 bytes key: the first half is the AES key, the second
+    /// half is used to build the padding
+    pub fn new(key: &[u8; 32]) -> CryptoPan {
+        let cipher = Aes128::new(GenericArray::from_slice(&key[..16]));
+        let mut pad = GenericArray::clone_from_slice(&key[16..]);
+        cipher.encrypt_block(&mut pad);
+        let mut pad_bytes = [0u8; 16];
+        pad_bytes.copy_from_slice(&pad);
+        let pad = u128::from_be_bytes(pad_bytes);
+        CryptoPan { cipher, pad }
+    }
+
+    /// Build anonymizer from a key given as 64 hexadecimal characters
+    pub fn of_hex_key(s: &str) -> Result<CryptoPan, String> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err("Anonymization key must be 64 hexadecimal characters".to_string());
+        }
+        let mut key = [0u8; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)
+                .map_err(|e| format!("Invalid anonymization key: {}", e))?;
+        }
+        Ok(CryptoPan::new(&key))
+    }
+
+    /// Anonymize the `width` most significant bits of `addr`
+    fn anonymize_bits(&self, addr: u128, width: u32) -> u128 {
+        let mut result = 0u128;
+        for pos in 0..width {
+            // first `pos` bits of the address, followed by the padding
+            let mask = if pos == 0 { 0 } else { !0u128 << (128 - pos) };
+            let input = (addr & mask) | (self.pad & !mask);
+            let mut block = GenericArray::clone_from_slice(&input.to_be_bytes());
+            self.cipher.encrypt_block(&mut block);
+            let bit = u128::from(block[0] >> 7);
+            result |= bit << (127 - pos);
+        }
+        addr ^ result
+    }
+
+    pub fn anonymize(&self, addr: &IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(a) => {
+                let bits = u128::from(u32::from(*a)) << 96;
+                let anon = (self.anonymize_bits(bits, 32) >> 96) as u32;
+                IpAddr::V4(Ipv4Addr::from(anon))
+            }
+            IpAddr::V6(a) => {
+                let anon = self.anonymize_bits(u128::from(*a), 128);
+                IpAddr::V6(Ipv6Addr::from(anon))
+            }
+        }
+    }
+}
+
+/// Read a mapping file, with one `original,replacement` pair of addresses per line
+pub fn ip_mapping_of_file_path(path: &Path) -> Result<HashMap<IpAddr, IpAddr>, Box<dyn Error>> {
+    let file = File::open(path)?;
+
+    let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(file);
+    rdr.records()
+        .map(|l| {
+            let record = l?;
+            let parse = |idx: usize| -> Result<IpAddr, Box<dyn Error>> {
+                let s = record
+                    .get(idx)
+                    .ok_or_else(|| "Missing address in IP mapping file".to_string())?;
+                s.trim().parse::<IpAddr>().map_err(|e| {
+                    format!("Invalid address '{}' in IP mapping file: {}", s, e).into()
+                })
+            };
+            let from = parse(0)?;
+            let to = parse(1)?;
+            if from.is_ipv4() != to.is_ipv4() {
+                return Err(
+                    format!("Cannot map {} to {}: address families differ", from, to).into(),
+                );
+            }
+            Ok((from, to))
+        })
+        .collect()
+}
+
+/// Replace IPv4 and IPv6 source and destination addresses
+///
+/// Addresses found in the mapping are replaced by their mapped value. Other addresses are
+/// anonymized if a Crypto-PAn key is set, and kept unchanged otherwise.
+///
+/// IPv4 header checksum, and TCP/UDP/ICMPv6 checksums are updated.
+pub struct IpAnonymizer {
+    mapping: HashMap<IpAddr, IpAddr>,
+    cryptopan: Option<CryptoPan>,
+    /// Addresses already anonymized
+    cache: HashMap<IpAddr, IpAddr>,
+}
+
+impl IpAnonymizer {
+    pub fn new(mapping: HashMap<IpAddr, IpAddr>, cryptopan: Option<CryptoPan>) -> Self {
+        IpAnonymizer {
+            mapping,
+            cryptopan,
+            cache: HashMap::new(),
+        }
+    }
+
+    fn map_addr(&mut self, addr: IpAddr) -> IpAddr {
+        if let Some(mapped) = self.mapping.get(&addr) {
+            return *mapped;
+        }
+        match &self.cryptopan {
+            Some(cryptopan) => *self
+                .cache
+                .entry(addr)
+                .or_insert_with(|| cryptopan.anonymize(&addr)),
+            None => addr,
+        }
+    }
+}
+
+fn ip_octets(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+impl Transform for IpAnonymizer {
+    fn transform_l3(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let (range, addr_len) = match data.first().map(|b| b >> 4) {
+            Some(4) if data.len() >= 20 => (12..20, 4),
+            Some(6) if data.len() >= 40 => (8..40, 16),
+            // not IP, or truncated: nothing to do
+            _ => return Ok(()),
+        };
+        let old = data[range.clone()].to_vec();
+        let (src, dst) = if addr_len == 4 {
+            let src: [u8; 4] = old[..4].try_into().unwrap();
+            let dst: [u8; 4] = old[4..].try_into().unwrap();
+            (IpAddr::from(src), IpAddr::from(dst))
+        } else {
+            let src: [u8; 16] = old[..16].try_into().unwrap();
+            let dst: [u8; 16] = old[16..].try_into().unwrap();
+            (IpAddr::from(src), IpAddr::from(dst))
+        };
+        let mut new = ip_octets(&self.map_addr(src));
+        new.extend(ip_octets(&self.map_addr(dst)));
+        if new == old {
+            return Ok(());
+        }
+        data[range].copy_from_slice(&new);
+        update_checksums_for_addresses(data, &old, &new);
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Checksum helpers for transformations modifying packets, and a transformation recomputing
+//! all checksums
+
+use crate::transforms::transform::Transform;
+use libpcap_tools::ipv6::{parse_ipv6_extensions, DEFAULT_IPV6_MAX_EXTENSIONS};
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+/// Location of the L4 header in a L3 packet
+#[derive(Clone, Copy, Debug)]
+pub struct L4Location {
+    /// L4 protocol
+    pub proto: u8,
+    /// Offset of L4 header
+    pub offset: usize,
+    /// False if packet is a fragment, but not the first one (L4 header is not present)
+    pub has_l4_header: bool,
+    /// True if packet is a fragment (first one included)
+    pub is_fragment: bool,
+}
+
+/// Find the L4 header of an IPv4 or IPv6 packet (IPv6 extension headers are skipped)
+pub fn locate_l4(data: &[u8]) -> Option<L4Location> {
+    match data.first()? >> 4 {
+        4 => {
+            if data.len() < 20 {
+                return None;
+            }
+            let offset = usize::from(data[0] & 0x0f) * 4;
+            let flags_offset = u16::from_be_bytes([data[6], data[7]]);
+            let frag_offset = flags_offset & 0x1fff;
+            let more_fragments = flags_offset & 0x2000 != 0;
+            Some(L4Location {
+                proto: data[9],
+                offset,
+                has_l4_header: frag_offset == 0 && offset <= data.len(),
+                is_fragment: frag_offset != 0 || more_fragments,
+            })
+        }
+        6 => {
+            if data.len() < 40 {
+                return None;
+            }
+            let ext =
+                parse_ipv6_extensions(data[6], &data[40..], DEFAULT_IPV6_MAX_EXTENSIONS).ok()?;
+            Some(L4Location {
+                proto: ext.next_header,
+                offset: data.len() - ext.payload.len(),
+                has_l4_header: ext.fragment.map_or(true, |f| f.offset == 0),
+                is_fragment: ext.fragment.is_some(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Length of IP packet, as declared in the IP header
+///
+/// For IPv6 jumbograms, this is the length of `data`.
+pub fn ip_len(data: &[u8]) -> usize {
+    if data[0] >> 4 == 4 {
+        usize::from(u16::from_be_bytes([data[2], data[3]]))
+    } else {
+        match u16::from_be_bytes([data[4], data[5]]) {
+            // jumbogram
+            0 => data.len(),
+            payload_len => 40 + usize::from(payload_len),
+        }
+    }
+}
+
+/// End of IP packet, excluding trailing bytes like ethernet padding
+pub fn ip_end(data: &[u8]) -> usize {
+    ip_len(data).min(data.len())
+}
+
+/// Compute the internet checksum (RFC 1071) of the concatenation of `parts`
+///
+/// All parts except the last one must have an even length.
+pub fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        let mut chunks = part.chunks_exact(2);
+        for w in &mut chunks {
+            sum += u32::from(u16::from_be_bytes([w[0], w[1]]));
+        }
+        if let [b] = chunks.remainder() {
+            sum += u32::from(*b) << 8;
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+    }
+    !(sum as u16)
+}
+
+/// Recompute the IPv4 header checksum
+pub fn update_ipv4_header_checksum(data: &mut [u8]) {
+    let header_len = usize::from(data[0] & 0x0f) * 4;
+    if header_len < 20 || data.len() < header_len {
+        return;
+    }
+    data[10..12].copy_from_slice(&[0, 0]);
+    let checksum = internet_checksum(&[&data[..header_len]]);
+    data[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Position of the L4 checksum, if the L4 header is present and has a checksum using a
+/// pseudo-header (TCP, UDP, ICMPv6)
+fn l4_checksum_position(data: &[u8], location: &L4Location) -> Option<usize> {
+    if !location.has_l4_header {
+        return None;
+    }
+    let is_ipv4 = data[0] >> 4 == 4;
+    let pos = match location.proto {
+        IPPROTO_TCP => location.offset + 16,
+        IPPROTO_UDP => location.offset + 6,
+        IPPROTO_ICMPV6 if !is_ipv4 => location.offset + 2,
+        _ => return None,
+    };
+    let field = data.get(pos..pos + 2)?;
+    // a null checksum means no checksum for UDP over IPv4
+    if is_ipv4 && location.proto == IPPROTO_UDP && field == [0, 0] {
+        return None;
+    }
+    Some(pos)
+}
+
+fn write_l4_checksum(data: &mut [u8], location: &L4Location, pos: usize, checksum: u16) {
+    // 0 is transmitted as all ones for UDP
+    let checksum = if checksum == 0 && location.proto == IPPROTO_UDP {
+        0xffff
+    } else {
+        checksum
+    };
+    data[pos..pos + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Recompute the L4 checksum (TCP, UDP or ICMPv6) of a complete, non-fragmented packet
+///
+/// The L4 segment is the data from the L4 header to the end of `data`.
+pub fn update_l4_checksum(data: &mut [u8], location: &L4Location) {
+    if location.is_fragment {
+        return;
+    }
+    let pos = match l4_checksum_position(data, location) {
+        Some(pos) => pos,
+        None => return,
+    };
+    let l4_len = data.len() - location.offset;
+    data[pos..pos + 2].copy_from_slice(&[0, 0]);
+    let mut pseudo_header = Vec::with_capacity(40);
+    if data[0] >> 4 == 4 {
+        pseudo_header.extend_from_slice(&data[12..20]);
+        pseudo_header.extend_from_slice(&[0, location.proto]);
+        pseudo_header.extend_from_slice(&(l4_len as u16).to_be_bytes());
+    } else {
+        pseudo_header.extend_from_slice(&data[8..40]);
+        pseudo_header.extend_from_slice(&(l4_len as u32).to_be_bytes());
+        pseudo_header.extend_from_slice(&[0, 0, 0, location.proto]);
+    }
+    let checksum = internet_checksum(&[&pseudo_header, &data[location.offset..]]);
+    write_l4_checksum(data, location, pos, checksum);
+}
+
+/// Update a checksum after replacing `old` bytes by `new` bytes (RFC 1624)
+///
+/// `old` and `new` must have the same, even, length.
+pub fn checksum_adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert_eq!(old.len(), new.len());
+    let mut sum = u32::from(!checksum);
+    for w in old.chunks_exact(2) {
+        sum += u32::from(!u16::from_be_bytes([w[0], w[1]]));
+    }
+    for w in new.chunks_exact(2) {
+        sum += u32::from(u16::from_be_bytes([w[0], w[1]]));
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Update the L4 checksum (TCP, UDP or ICMPv6) after replacing `old` bytes by `new` bytes
+///
+/// Replaced bytes must start at an even offset from the L4 header (or be part of the pseudo-header)
+pub fn adjust_l4_checksum(data: &mut [u8], location: &L4Location, old: &[u8], new: &[u8]) {
+    if let Some(pos) = l4_checksum_position(data, location) {
+        let checksum = u16::from_be_bytes([data[pos], data[pos + 1]]);
+        let checksum = checksum_adjust(checksum, old, new);
+        write_l4_checksum(data, location, pos, checksum);
+    }
+}
+
+/// Update IP and L4 checksums after a change of the IP addresses
+///
+/// `old` and `new` are the concatenation of the source and destination addresses, before
+/// and after the change. L4 checksums using a pseudo-header (TCP, UDP, ICMPv6) are updated.
+pub fn update_checksums_for_addresses(data: &mut [u8], old: &[u8], new: &[u8]) {
+    let location = match locate_l4(data) {
+        Some(l) => l,
+        None => return,
+    };
+    if data[0] >> 4 == 4 {
+        let checksum = u16::from_be_bytes([data[10], data[11]]);
+        data[10..12].copy_from_slice(&checksum_adjust(checksum, old, new).to_be_bytes());
+    }
+    adjust_l4_checksum(data, &location, old, new);
+}
+
+/// Recompute the IPv4 header checksum, and the TCP, UDP, ICMP and ICMPv6 checksums
+///
+/// L4 checksums are not recomputed for fragments, since they cover the data of all fragments, and
+/// for truncated packets. UDP over IPv4 without checksum (null checksum) is left unchanged.
+pub struct ChecksumFixer;
+
+impl Transform for ChecksumFixer {
+    fn transform_l3(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let location = match locate_l4(data) {
+            Some(location) => location,
+            // not IP, or truncated IP header
+            None => return Ok(()),
+        };
+        let is_ipv4 = data[0] >> 4 == 4;
+        if is_ipv4 {
+            update_ipv4_header_checksum(data);
+        }
+        let len = ip_len(data);
+        if location.is_fragment || len > data.len() || len < location.offset {
+            return Ok(());
+        }
+        // exclude trailing bytes from the L4 segment
+        let packet = &mut data[..len];
+        if is_ipv4 && location.proto == IPPROTO_ICMP {
+            // no pseudo-header for ICMP
+            let pos = location.offset + 2;
+            if packet.len() >= pos + 2 {
+                packet[pos..pos + 2].copy_from_slice(&[0, 0]);
+                let checksum = internet_checksum(&[&packet[location.offset..]]);
+                packet[pos..pos + 2].copy_from_slice(&checksum.to_be_bytes());
+            }
+        } else {
+            update_l4_checksum(packet, &location);
+        }
+        Ok(())
+    }
+}
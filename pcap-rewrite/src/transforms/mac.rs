@@ -0,0 +1,118 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::path::Path;
+
+use csv::ReaderBuilder;
+
+use crate::transforms::transform::Transform;
+
+pub type MacAddr = [u8; 6];
+
+/// Parse a MAC address, written as 6 hexadecimal bytes separated by `:` or `-`
+pub fn parse_mac(s: &str) -> Result<MacAddr, String> {
+    let parts: Vec<&str> = s.trim().split(|c| c == ':' || c == '-').collect();
+    if parts.len() != 6 {
+        return Err(format!("Invalid MAC address '{}'", s));
+    }
+    let mut mac = [0u8; 6];
+    for (b, part) in mac.iter_mut().zip(parts) {
+        *b = u8::from_str_radix(part, 16).map_err(|_| format!("Invalid MAC address '{}'", s))?;
+    }
+    Ok(mac)
+}
+
+/// Read a mapping file, with one `original,replacement` pair of MAC addresses per line
+pub fn mac_mapping_of_file_path(path: &Path) -> Result<HashMap<MacAddr, MacAddr>, Box<dyn Error>> {
+    let file = File::open(path)?;
+
+    let mut rdr = ReaderBuilder::new().has_headers(false).from_reader(file);
+    rdr.records()
+        .map(|l| {
+            let record = l?;
+            let from = parse_mac(record.get(0).unwrap_or_default())?;
+            let to = parse_mac(
+                record
+                    .get(1)
+                    .ok_or_else(|| "Missing address in MAC mapping file".to_string())?,
+            )?;
+            Ok((from, to))
+        })
+        .collect()
+}
+
+/// Replace source and destination MAC addresses of Ethernet frames
+///
+/// Addresses found in the mapping are replaced by their mapped value. If randomization is
+/// enabled, other unicast addresses are replaced by a random address with the same OUI (first
+/// 3 bytes), and the same address is always replaced by the same value. Broadcast and multicast
+/// addresses are not modified.
+pub struct MacRewriter {
+    mapping: HashMap<MacAddr, MacAddr>,
+    /// Random state used to generate addresses, if randomization is enabled
+    random_state: Option<RandomState>,
+    /// Addresses already randomized
+    cache: HashMap<MacAddr, MacAddr>,
+    /// Generated addresses, to avoid collisions
+    generated: HashSet<MacAddr>,
+}
+
+impl MacRewriter {
+    pub fn new(mapping: HashMap<MacAddr, MacAddr>, randomize: bool) -> Self {
+        MacRewriter {
+            mapping,
+            random_state: if randomize {
+                Some(RandomState::new())
+            } else {
+                None
+            },
+            cache: HashMap::new(),
+            generated: HashSet::new(),
+        }
+    }
+
+    fn map_addr(&mut self, addr: MacAddr) -> MacAddr {
+        if let Some(mapped) = self.mapping.get(&addr) {
+            return *mapped;
+        }
+        let random_state = match &self.random_state {
+            // multicast bit is also set for broadcast
+            Some(random_state) if addr[0] & 0x01 == 0 => random_state,
+            _ => return addr,
+        };
+        if let Some(randomized) = self.cache.get(&addr) {
+            return *randomized;
+        }
+        let mut randomized = addr;
+        for counter in 0u32.. {
+            let mut hasher = random_state.build_hasher();
+            addr.hash(&mut hasher);
+            counter.hash(&mut hasher);
+            let h = hasher.finish().to_be_bytes();
+            randomized[3..].copy_from_slice(&h[..3]);
+            if !self.generated.contains(&randomized) {
+                break;
+            }
+        }
+        self.generated.insert(randomized);
+        self.cache.insert(addr, randomized);
+        randomized
+    }
+}
+
+impl Transform for MacRewriter {
+    fn transform_l2(&mut self, header: &mut [u8]) -> Result<(), String> {
+        if header.len() < 12 {
+            return Ok(());
+        }
+        for range in &[0..6, 6..12] {
+            let mut addr = [0u8; 6];
+            addr.copy_from_slice(&header[range.clone()]);
+            let new = self.map_addr(addr);
+            header[range.clone()].copy_from_slice(&new);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+pub mod anonymize;
+pub mod checksum;
+pub mod mac;
+pub mod payload;
+pub mod transform;
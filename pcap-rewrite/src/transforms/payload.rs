@@ -0,0 +1,94 @@
+use std::ops::Range;
+
+use crate::transforms::checksum::*;
+use crate::transforms::transform::Transform;
+
+/// Action on application payload
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadAction {
+    /// Remove payload, and update lengths
+    Truncate,
+    /// Replace payload bytes with zeroes
+    Zero,
+}
+
+/// Remove or zero application payload, keeping L3 and L4 headers
+///
+/// Payload is the data after TCP and UDP headers. Other protocols are not modified, except
+/// non-first fragments: all their data is considered as payload.
+///
+/// IP and UDP lengths, and checksums are updated. L4 checksum of fragments is not updated when
+/// truncating, since it covers the data of all fragments.
+pub struct PayloadScrubber {
+    action: PayloadAction,
+}
+
+impl PayloadScrubber {
+    pub fn new(action: PayloadAction) -> Self {
+        PayloadScrubber { action }
+    }
+}
+
+/// Find the application payload of an IPv4 or IPv6 packet
+///
+/// Returns the location of the L4 header, and the range of the payload (after TCP and UDP
+/// headers, or all data of non-first fragments). Returns `None` if the packet is not IP, has
+/// another L4 protocol, is truncated or has no payload.
+pub fn locate_payload(data: &[u8]) -> Option<(L4Location, Range<usize>)> {
+    let location = match locate_l4(data) {
+        Some(l) if l.offset <= data.len() => l,
+        _ => return None,
+    };
+    let payload_offset = if location.has_l4_header {
+        match location.proto {
+            IPPROTO_TCP => location.offset + usize::from(data.get(location.offset + 12)? >> 4) * 4,
+            IPPROTO_UDP => location.offset + 8,
+            _ => return None,
+        }
+    } else {
+        location.offset
+    };
+    let end = ip_end(data);
+    if payload_offset >= end {
+        return None;
+    }
+    Some((location, payload_offset..end))
+}
+
+impl Transform for PayloadScrubber {
+    fn transform_l3(&mut self, data: &mut Vec<u8>) -> Result<(), String> {
+        let (location, payload_offset, end) = match locate_payload(data) {
+            Some((location, range)) => (location, range.start, range.end),
+            // not IP, truncated or no payload: nothing to do
+            None => return Ok(()),
+        };
+        match self.action {
+            PayloadAction::Zero => {
+                let mut old = data[payload_offset..end].to_vec();
+                data[payload_offset..end].iter_mut().for_each(|b| *b = 0);
+                if old.len() % 2 != 0 {
+                    old.push(0);
+                }
+                let new = vec![0; old.len()];
+                adjust_l4_checksum(data, &location, &old, &new);
+            }
+            PayloadAction::Truncate => {
+                data.truncate(payload_offset);
+                let len = data.len();
+                if data[0] >> 4 == 4 {
+                    data[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+                    update_ipv4_header_checksum(data);
+                } else {
+                    data[4..6].copy_from_slice(&((len - 40) as u16).to_be_bytes());
+                }
+                if location.has_l4_header && location.proto == IPPROTO_UDP {
+                    let pos = location.offset + 4;
+                    data[pos..pos + 2]
+                        .copy_from_slice(&((len - location.offset) as u16).to_be_bytes());
+                }
+                update_l4_checksum(data, &location);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use crate::filters::filter_utils::parse_ethernet_vlan;
+use pnet_packet::ethernet::EtherTypes;
+
+/// Transformation of packet data, applied after filters
+///
+/// Data is given as written to the output file. Methods have a default implementation doing
+/// nothing, so a transformation only implements the layers it modifies.
+///
+/// Any error raised in these functions is fatal
+pub trait Transform {
+    /// Modify the L2 header (Ethernet, including VLAN tags) in place.
+    ///
+    /// Only called if the output link type is L2.
+    fn transform_l2(&mut self, _header: &mut [u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Modify the L3 packet (IPv4 or IPv6) in place. Data can be truncated or extended.
+    fn transform_l3(&mut self, _data: &mut Vec<u8>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Apply transformations to `data`, of layer `layer` (2 or 3)
+pub fn apply_transforms(
+    transforms: &mut [Box<dyn Transform>],
+    data: &mut Vec<u8>,
+    layer: usize,
+) -> Result<(), String> {
+    match layer {
+        2 => {
+            let (l3_offset, is_ip) = match parse_ethernet_vlan(data) {
+                Ok(ethernet_l3) => (
+                    data.len() - ethernet_l3.payload.len(),
+                    matches!(ethernet_l3.ethertype, EtherTypes::Ipv4 | EtherTypes::Ipv6),
+                ),
+                // not an ethernet frame: nothing to transform
+                Err(_) => return Ok(()),
+            };
+            let mut l3_data = data.split_off(l3_offset);
+            for t in transforms.iter_mut() {
+                t.transform_l2(data)?;
+                if is_ip {
+                    t.transform_l3(&mut l3_data)?;
+                }
+            }
+            data.append(&mut l3_data);
+            Ok(())
+        }
+        3 => transforms.iter_mut().try_for_each(|t| t.transform_l3(data)),
+        _ => Err(format!("Transforms not supported for layer {}", layer)),
+    }
+}
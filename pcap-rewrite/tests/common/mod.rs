@@ -0,0 +1,108 @@
+//! Helpers shared by the integration tests
+
+// each test file uses only some of these functions
+#![allow(dead_code)]
+
+use pcap_parser::{Capture, PcapCapture};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use assert_cmd::Command;
+
+/// Return the path of a file given relative to the crate directory (for ex. an asset)
+pub fn asset_path<P: AsRef<Path>>(file: P) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(file)
+}
+
+/// Return the path of a file in the temporary directory
+pub fn temp_path<P: AsRef<Path>>(file: P) -> PathBuf {
+    std::env::temp_dir().join(file)
+}
+
+/// Return a command running pcap-rewrite
+pub fn pcap_rewrite() -> Command {
+    Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap()
+}
+
+pub fn count_packet_in_data(data: &[u8]) -> u32 {
+    let cap = PcapCapture::from_file(data).unwrap();
+    let mut count = 0;
+    let mut iter = cap.iter();
+    while iter.next().is_some() {
+        count += 1;
+    }
+    count
+}
+
+/// Count the packets of a pcap file (an empty file has no packets)
+pub fn count_packet_in_trace(trace_file_path: &Path) -> u32 {
+    if !trace_file_path.exists() {
+        panic!("{:#?} does not exists!", trace_file_path)
+    }
+    let data = fs::read(trace_file_path).unwrap();
+    if data.is_empty() {
+        0
+    } else {
+        count_packet_in_data(&data)
+    }
+}
+
+/// Return the data of the packets of a pcap file
+pub fn packets_in_data(data: &[u8]) -> Vec<Vec<u8>> {
+    let cap = PcapCapture::from_file(data).unwrap();
+    cap.blocks.iter().map(|b| b.data.to_vec()).collect()
+}
+
+/// Return the content of a file written by pcap-rewrite, and remove it
+pub fn read_and_remove(path: &Path) -> Vec<u8> {
+    let data = fs::read(path).unwrap();
+    fs::remove_file(path).expect("Could not destroy the output file");
+    data
+}
+
+/// Run pcap-rewrite with `args`, on `trace_input_file_s` (relative to the crate directory),
+/// writing to `trace_output_file_s` (in the temporary directory)
+///
+/// Returns the content of the output file, which is removed, or `None` if pcap-rewrite failed.
+pub fn run_rewrite(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    args: &[&str],
+) -> Option<Vec<u8>> {
+    let trace_output_file_path = temp_path(trace_output_file_s);
+
+    let mut cmd = pcap_rewrite();
+    cmd.args(args)
+        .arg(asset_path(trace_input_file_s))
+        .arg(&trace_output_file_path);
+    let output = cmd.output().unwrap();
+    if !output.status.success() {
+        let _ = fs::remove_file(&trace_output_file_path);
+        return None;
+    }
+
+    Some(read_and_remove(&trace_output_file_path))
+}
+
+/// Run pcap-rewrite with `args`, and check the number of packets written
+pub fn generic_test(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    args: &[&str],
+    expected_packet_number: u32,
+) {
+    let trace_output_file_path = temp_path(trace_output_file_s);
+
+    let mut cmd = pcap_rewrite();
+    cmd.args(args)
+        .arg(asset_path(trace_input_file_s))
+        .arg(&trace_output_file_path);
+
+    let _output = cmd.output().unwrap();
+
+    let output_nb_packet = count_packet_in_trace(&trace_output_file_path);
+
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the filtered file");
+
+    assert_eq!(output_nb_packet, expected_packet_number);
+}
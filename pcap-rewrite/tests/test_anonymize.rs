@@ -0,0 +1,74 @@
+use pnet_packet::ipv4::{self, Ipv4Packet};
+use pnet_packet::tcp::{self, TcpPacket};
+use std::net::Ipv4Addr;
+
+mod common;
+
+/// Run pcap-rewrite with anonymization options, and return the (raw IPv4) output packets
+fn generic_test(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    args: &[&str],
+) -> Vec<Vec<u8>> {
+    let data = common::run_rewrite(trace_input_file_s, trace_output_file_s, args)
+        .expect("Could not anonymize the file");
+    common::packets_in_data(&data)
+}
+
+fn check_checksums(packet: &Ipv4Packet) -> bool {
+    let tcp = TcpPacket::new(packet.payload()).unwrap();
+    ipv4::checksum(packet) == packet.get_checksum()
+        && tcp::ipv4_checksum(&tcp, &packet.get_source(), &packet.get_destination())
+            == tcp.get_checksum()
+}
+
+#[test]
+fn test_anonymize_cryptopan() {
+    // key and results from the reference Crypto-PAn implementation
+    let packets = generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_anonymize_cryptopan",
+        &[
+            "--anonymize-key",
+            "1522178d33a4cf80130a5b1649907d10d8988f837979652762574c2d2a842202",
+        ],
+    );
+    assert_eq!(packets.len(), 18);
+    for data in &packets {
+        let packet = Ipv4Packet::new(data).unwrap();
+        assert_eq!(ipv4::checksum(&packet), packet.get_checksum());
+        for addr in &[packet.get_source(), packet.get_destination()] {
+            assert_eq!(addr.octets()[..3], [252, 103, 248]);
+        }
+    }
+    let first = Ipv4Packet::new(&packets[0]).unwrap();
+    assert_eq!(first.get_source(), Ipv4Addr::new(252, 103, 248, 117));
+    assert_eq!(first.get_destination(), Ipv4Addr::new(252, 103, 248, 126));
+    assert!(check_checksums(&first));
+}
+
+#[test]
+fn test_anonymize_ip_map() {
+    let map_file_path = common::asset_path("../assets/pcap-transform/ip_map");
+    let packets = generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_anonymize_ip_map",
+        &["--ip-map", map_file_path.to_str().unwrap()],
+    );
+    assert_eq!(packets.len(), 18);
+    let first = Ipv4Packet::new(&packets[0]).unwrap();
+    assert_eq!(first.get_source(), Ipv4Addr::new(192, 168, 10, 10));
+    assert_eq!(first.get_destination(), Ipv4Addr::new(10, 0, 0, 1));
+    assert!(check_checksums(&first));
+    let unmapped = Ipv4Packet::new(&packets[4]).unwrap();
+    assert_eq!(unmapped.get_destination(), Ipv4Addr::new(192, 168, 10, 11));
+}
+
+#[test]
+fn test_anonymize_invalid_key() {
+    let mut cmd = common::pcap_rewrite();
+    cmd.args(&["--anonymize-key", "0123"])
+        .arg("../assets/nmap_tcp_22_ipv4.pcap")
+        .arg(common::temp_path("output_anonymize_invalid_key"));
+    cmd.assert().failure();
+}
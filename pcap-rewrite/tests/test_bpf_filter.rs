@@ -0,0 +1,66 @@
+mod common;
+
+fn generic_test(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    expression: &str,
+    expected_packet_number: u32,
+) {
+    common::generic_test(
+        trace_input_file_s,
+        trace_output_file_s,
+        &["--bpf", expression],
+        expected_packet_number,
+    );
+}
+
+#[test]
+fn test_bpf_host() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_bpf_host_ipv4",
+        "host 192.168.10.11",
+        4,
+    )
+}
+
+#[test]
+fn test_bpf_proto_dst_port() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_bpf_proto_dst_port_ipv4",
+        "tcp and dst port 80",
+        11,
+    )
+}
+
+#[test]
+fn test_bpf_not_host() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_bpf_not_host_ipv4",
+        "not host 192.168.10.1",
+        10,
+    )
+}
+
+#[test]
+fn test_bpf_net_length() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_bpf_net_length_ipv4",
+        "src net 192.168.10.0/28 and less 60",
+        5,
+    )
+}
+
+#[test]
+fn test_bpf_invalid_expression() {
+    common::pcap_rewrite()
+        .arg("--bpf")
+        .arg("tcp and (port 80")
+        .arg(common::asset_path("../assets/nmap_tcp_22_ipv4.pcap"))
+        .arg(common::temp_path("output_bpf_invalid"))
+        .assert()
+        .failure();
+}
@@ -0,0 +1,104 @@
+use pcap_parser::{Block, OptionCode, PcapNGCapture};
+use std::fs;
+use std::path::Path;
+
+mod common;
+
+const COMMENTS: &str = "# packet index or flow, comment
+2,second packet
+tcp 192.168.10.1:80 192.168.10.10:45158,\"flow, reversed\"
+";
+
+/// Run pcap-rewrite with pcapng output, and return the comments of each packet written
+fn generic_test(
+    input_path: &Path,
+    trace_output_file_s: &str,
+    args: &[&str],
+) -> Option<Vec<Vec<String>>> {
+    let trace_output_file_path = common::temp_path(trace_output_file_s);
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.args(args).arg(input_path).arg(&trace_output_file_path);
+    let output = cmd.output().unwrap();
+    if !output.status.success() {
+        let _ = fs::remove_file(&trace_output_file_path);
+        return None;
+    }
+
+    let data = fs::read(&trace_output_file_path).unwrap();
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the output file");
+    let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+    let comments = capture.sections[0]
+        .blocks
+        .iter()
+        .filter_map(|b| match b {
+            Block::EnhancedPacket(epb) => Some(
+                epb.options
+                    .iter()
+                    .filter(|o| o.code == OptionCode::Comment)
+                    .map(|o| String::from_utf8_lossy(o.value).into_owned())
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .collect();
+    Some(comments)
+}
+
+#[test]
+fn test_comments() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+    let comments_file_path = common::temp_path("comments.csv");
+    fs::write(&comments_file_path, COMMENTS).unwrap();
+    let comments_arg = comments_file_path.to_string_lossy();
+
+    // comments require pcapng output
+    let comments = generic_test(
+        &trace_input_file_path,
+        "output_comments.pcap",
+        &["--comments", &comments_arg],
+    );
+    assert_eq!(comments, None);
+
+    let comments = generic_test(
+        &trace_input_file_path,
+        "output_comments.pcapng",
+        &["-o", "pcapng", "--comments", &comments_arg],
+    )
+    .unwrap();
+    fs::remove_file(&comments_file_path).expect("Could not destroy the comments file");
+    assert_eq!(comments.len(), 18);
+    assert_eq!(comments[0], ["flow, reversed"]);
+    assert_eq!(comments[1], ["second packet", "flow, reversed"]);
+    assert_eq!(comments[3], ["flow, reversed"]);
+    assert!(comments[4..].iter().all(|c| c.is_empty()));
+}
+
+#[test]
+fn test_comments_preserved() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+    let comments_file_path = common::temp_path("comments_preserved.csv");
+    fs::write(&comments_file_path, "3,third packet\n").unwrap();
+    let comments_arg = comments_file_path.to_string_lossy();
+
+    // write a commented pcapng file
+    let commented_file_path = common::temp_path("input_comments.pcapng");
+    let mut cmd = common::pcap_rewrite();
+    cmd.args(&["-o", "pcapng", "--comments", &comments_arg])
+        .arg(&trace_input_file_path)
+        .arg(&commented_file_path);
+    cmd.assert().success();
+    fs::remove_file(&comments_file_path).expect("Could not destroy the comments file");
+
+    // comments of input packets are kept when rewriting
+    let comments = generic_test(
+        &commented_file_path,
+        "output_comments_preserved.pcapng",
+        &["-o", "pcapng", "--packets", "3-"],
+    )
+    .unwrap();
+    fs::remove_file(&commented_file_path).expect("Could not destroy the commented file");
+    assert_eq!(comments.len(), 16);
+    assert_eq!(comments[0], ["third packet"]);
+    assert!(comments[1..].iter().all(|c| c.is_empty()));
+}
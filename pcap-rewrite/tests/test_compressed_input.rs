@@ -0,0 +1,60 @@
+use std::fs;
+
+mod common;
+
+fn generic_test(trace_input_file_s: &str, trace_output_file_s: &str, use_stdin: bool) {
+    let trace_input_file_path = common::asset_path(trace_input_file_s);
+    let trace_output_file_path = common::temp_path(trace_output_file_s);
+
+    let mut cmd = common::pcap_rewrite();
+    if use_stdin {
+        cmd.arg("-")
+            .write_stdin(fs::read(&trace_input_file_path).unwrap());
+    } else {
+        cmd.arg(&trace_input_file_path);
+    }
+    cmd.arg(&trace_output_file_path);
+    cmd.assert().success();
+
+    let output_nb_packet = common::count_packet_in_trace(&trace_output_file_path);
+
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the output file");
+
+    assert_eq!(output_nb_packet, 18);
+}
+
+#[test]
+fn test_gzip_input() {
+    generic_test(
+        "../assets/compressed/nmap_tcp_22_ipv4.pcap.gz",
+        "output_gzip_input",
+        false,
+    )
+}
+
+#[test]
+fn test_xz_input() {
+    generic_test(
+        "../assets/compressed/nmap_tcp_22_ipv4.pcap.xz",
+        "output_xz_input",
+        false,
+    )
+}
+
+#[test]
+fn test_zstd_input() {
+    generic_test(
+        "../assets/compressed/nmap_tcp_22_ipv4.pcap.zst",
+        "output_zstd_input",
+        false,
+    )
+}
+
+#[test]
+fn test_zstd_stdin() {
+    generic_test(
+        "../assets/compressed/nmap_tcp_22_ipv4.pcap.zst",
+        "output_zstd_stdin",
+        true,
+    )
+}
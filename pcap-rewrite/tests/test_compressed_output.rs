@@ -0,0 +1,41 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+mod common;
+
+fn generic_test(trace_output_file_s: &str, compression_s: &str) -> Vec<u8> {
+    common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        trace_output_file_s,
+        &["--compress", compression_s],
+    )
+    .expect("Could not compress the output file")
+}
+
+#[test]
+fn test_gzip_output() {
+    let data = generic_test("output_gzip_output.pcap.gz", "gzip");
+    let mut decompressed = Vec::new();
+    GzDecoder::new(&data[..])
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    assert_eq!(common::count_packet_in_data(&decompressed), 18);
+}
+
+#[test]
+fn test_zstd_output() {
+    let data = generic_test("output_zstd_output.pcap.zst", "zstd:19");
+    let decompressed = zstd::decode_all(&data[..]).unwrap();
+    assert_eq!(common::count_packet_in_data(&decompressed), 18);
+}
+
+#[test]
+fn test_invalid_compression_level() {
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--compress")
+        .arg("gzip:12")
+        .arg("../assets/nmap_tcp_22_ipv4.pcap")
+        .arg(common::temp_path("output_invalid_compression_level"));
+    cmd.assert().failure();
+}
@@ -0,0 +1,145 @@
+use pcap_parser::{Block, Linktype, OptionCode, PcapCapture, PcapNGCapture};
+use std::fs;
+use std::path::Path;
+
+mod common;
+
+/// Run pcap-rewrite in conversion mode on `input_path`, and return the success status
+fn convert(input_path: &Path, trace_output_file_s: &str, args: &[&str]) -> bool {
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--convert")
+        .args(args)
+        .arg(input_path)
+        .arg(common::temp_path(trace_output_file_s));
+    cmd.output().unwrap().status.success()
+}
+
+#[test]
+fn test_convert_pcap_to_pcapng() {
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_convert.pcapng",
+        &["--convert", "-o", "pcapng"],
+    )
+    .expect("pcap-rewrite failed");
+    let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+    let blocks = &capture.sections[0].blocks;
+    let idb = blocks
+        .iter()
+        .find_map(|b| match b {
+            Block::InterfaceDescription(idb) => Some(idb),
+            _ => None,
+        })
+        .expect("Missing interface");
+    // link type is not converted
+    assert_eq!(idb.linktype, Linktype::ETHERNET);
+    assert_eq!(idb.snaplen, 262_144);
+    let ts: Vec<_> = blocks
+        .iter()
+        .filter_map(|b| match b {
+            Block::EnhancedPacket(epb) => {
+                Some((u64::from(epb.ts_high) << 32) | u64::from(epb.ts_low))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(ts.len(), 18);
+    assert_eq!(ts[0], 1_658_321_070_679_827);
+}
+
+#[test]
+fn test_convert_pcapng_to_pcap() {
+    // the first packet has nanosecond resolution
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4_ns.pcapng",
+        "output_convert_ns.pcap",
+        &["--convert"],
+    )
+    .expect("pcap-rewrite failed");
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert!(cap.header.is_nanosecond_precision());
+    assert_eq!(cap.blocks.len(), 18);
+    assert_eq!(
+        (cap.blocks[0].ts_sec, cap.blocks[0].ts_usec),
+        (1_658_321_070, 679_827_123)
+    );
+    assert_eq!(cap.blocks[1].ts_usec, 679_979_000);
+
+    // sub-microsecond parts are rounded
+    let stats_file_path = common::temp_path("output_convert_us.json");
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4_ns.pcapng",
+        "output_convert_us.pcap",
+        &[
+            "--convert",
+            "--ts-resolution",
+            "us",
+            "--stats-out",
+            &stats_file_path.to_string_lossy(),
+        ],
+    )
+    .expect("pcap-rewrite failed");
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert!(!cap.header.is_nanosecond_precision());
+    assert_eq!(cap.blocks[0].ts_usec, 679_827);
+    let stats: serde_json::Value =
+        serde_json::from_slice(&common::read_and_remove(&stats_file_path)).unwrap();
+    assert_eq!(stats["packets"], 18);
+    assert_eq!(stats["rounded_timestamps"], 9);
+}
+
+#[test]
+fn test_convert_pcapng_interfaces() {
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4_ns.pcapng",
+        "output_convert_copy.pcapng",
+        &["--convert", "-o", "pcapng"],
+    )
+    .expect("pcap-rewrite failed");
+    let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+    let idbs: Vec<_> = capture.sections[0]
+        .blocks
+        .iter()
+        .filter_map(|b| match b {
+            Block::InterfaceDescription(idb) => Some(idb),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(idbs.len(), 2);
+    assert_eq!(idbs[0].if_tsresol, 9);
+    assert!(idbs[1]
+        .options
+        .iter()
+        .any(|o| o.code == OptionCode::IfName && o.value == b"eth1"));
+}
+
+#[test]
+fn test_convert_split_linktypes() {
+    // set the link type of the second interface to RAW
+    let mut data = fs::read(common::asset_path("../assets/nmap_tcp_22_ipv4_ns.pcapng")).unwrap();
+    data[76] = 101;
+    let input_path = common::temp_path("input_convert_linktypes.pcapng");
+    fs::write(&input_path, &data).unwrap();
+
+    let success = convert(&input_path, "output_convert_fail.pcap", &[]);
+    assert!(!success);
+    fs::remove_file(common::temp_path("output_convert_fail.pcap"))
+        .expect("Could not destroy the converted file");
+
+    let success = convert(
+        &input_path,
+        "output_convert_split.pcap",
+        &["--split-linktypes"],
+    );
+    fs::remove_file(&input_path).expect("Could not destroy the input file");
+    assert!(success);
+    let data = common::read_and_remove(&common::temp_path("output_convert_split.pcap"));
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.header.network, Linktype::ETHERNET);
+    assert_eq!(cap.blocks.len(), 9);
+    let data =
+        common::read_and_remove(&common::temp_path("output_convert_split_linktype_101.pcap"));
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.header.network, Linktype::RAW);
+    assert_eq!(cap.blocks.len(), 9);
+}
@@ -0,0 +1,25 @@
+mod common;
+
+use common::generic_test;
+
+// the input is nmap_tcp_22_ipv4.pcap with 3 exact copies, and 1 copy with a different TTL
+
+#[test]
+fn test_dedup() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4_dup.pcap",
+        "output_dedup",
+        &["--dedup"],
+        19,
+    )
+}
+
+#[test]
+fn test_dedup_ignore_ttl() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4_dup.pcap",
+        "output_dedup_ignore_ttl",
+        &["--dedup", "--dedup-ignore-ttl"],
+        18,
+    )
+}
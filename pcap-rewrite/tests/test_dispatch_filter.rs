@@ -1,32 +1,4 @@
-use pcap_parser::{Capture, PcapCapture};
-use std::env;
-use std::fs;
-use std::fs::File;
-use std::path::Path;
-use std::path::PathBuf;
-
-use assert_cmd::Command;
-
-fn count_packet_in_trace(trace_file_path: &Path) -> u32 {
-    if trace_file_path.exists() {
-        let file = File::open(trace_file_path).unwrap();
-        let file_size = file.metadata().unwrap().len();
-        if file_size == 0 {
-            0
-        } else {
-            let data = fs::read(trace_file_path).unwrap();
-            let cap = PcapCapture::from_file(&data).unwrap();
-            let mut count = 0;
-            let mut iter = cap.iter();
-            while iter.next().is_some() {
-                count += 1;
-            }
-            count
-        }
-    } else {
-        panic!("{:#?} does not exists!", trace_file_path)
-    }
-}
+mod common;
 
 fn generic_test(
     trace_input_file_s: &str,
@@ -35,29 +7,14 @@ fn generic_test(
     key_s: &str,
     expected_packet_number: u32,
 ) {
-    let mut trace_input_file_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    trace_input_file_path.push(trace_input_file_s);
-
-    let mut trace_output_file_path = std::env::temp_dir();
-    trace_output_file_path.push(trace_output_file_s);
-
-    let mut key_file_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    key_file_path.push(key_file_s);
-
-    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
-    cmd.arg("-f")
-        .arg(format!("Dispatch:{}%k%{}", key_s, key_file_path.display()))
-        .arg(&trace_input_file_path)
-        .arg(&trace_output_file_path);
-
-    let _output = cmd.output().unwrap();
-    // println!("Output: {:?}", _output);
-
-    let output_nb_packet = count_packet_in_trace(&trace_output_file_path);
-
-    fs::remove_file(&trace_output_file_path).expect("Could not destroy the filtered file");
-
-    assert_eq!(output_nb_packet, expected_packet_number);
+    let key_file_path = common::asset_path(key_file_s);
+    let filter = format!("Dispatch:{}%k%{}", key_s, key_file_path.display());
+    common::generic_test(
+        trace_input_file_s,
+        trace_output_file_s,
+        &["-f", &filter],
+        expected_packet_number,
+    );
 }
 
 // IPV4
@@ -117,6 +74,118 @@ fn test_filter_ipv4_five_tuple() {
     )
 }
 
+// Non-first fragment has no TCP header: it is kept with the first fragment
+#[test]
+fn test_filter_ipv4_five_tuple_fragments() {
+    generic_test(
+        "../assets/frag_tcp_80_ipv4.pcap",
+        "output_five_tuple_fragments_ipv4",
+        "../assets/pcap-filter/ipv4_five_tuple_frag",
+        "sdipsdp",
+        3,
+    )
+}
+
+// The last fragment is captured before the first one: it still gets the verdict of its datagram
+#[test]
+fn test_filter_ipv4_five_tuple_fragments_reordered() {
+    generic_test(
+        "../assets/frag_tcp_80_ipv4_reordered.pcap",
+        "output_five_tuple_fragments_reordered_ipv4",
+        "../assets/pcap-filter/ipv4_five_tuple_frag",
+        "sdipsdp",
+        3,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_src_prefix() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_src_prefix_ipv4",
+        "../assets/pcap-filter/ipv4_prefix",
+        "si",
+        4,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_dst_prefix() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_dst_prefix_ipv4",
+        "../assets/pcap-filter/ipv4_prefix",
+        "di",
+        8,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_src_port_range() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_src_port_range_ipv4",
+        "../assets/pcap-filter/port_range",
+        "spr",
+        3,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_dst_port_range() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_dst_port_range_ipv4",
+        "../assets/pcap-filter/port_range",
+        "dpr",
+        3,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_vlan_dst_ipaddr() {
+    generic_test(
+        "../assets/vlan_qinq_tcp_80_ipv4.pcap",
+        "output_vlan_dst_ip_addr_ipv4",
+        "../assets/pcap-filter/ipv4_ipaddr",
+        "di",
+        1,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_vlan_id() {
+    generic_test(
+        "../assets/vlan_qinq_tcp_80_ipv4.pcap",
+        "output_vlan_id_ipv4",
+        "../assets/pcap-filter/vlan_id",
+        "vid",
+        2,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_mpls_pppoe_dst_ipaddr() {
+    generic_test(
+        "../assets/mpls_pppoe_ipv4.pcap",
+        "output_mpls_pppoe_dst_ip_addr_ipv4",
+        "../assets/pcap-filter/ipv4_ipaddr",
+        "di",
+        2,
+    )
+}
+
+#[test]
+fn test_filter_ipv4_payload_pattern() {
+    generic_test(
+        "../assets/http_host_ipv4.pcap",
+        "output_payload_pattern_ipv4",
+        "../assets/pcap-filter/payload_pattern",
+        "pp",
+        2,
+    )
+}
+
 // IPV6
 
 #[test]
@@ -152,6 +221,17 @@ fn test_filter_ipv6_src_dst_ipaddr() {
     )
 }
 
+#[test]
+fn test_filter_ipv6_src_prefix() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv6.pcap",
+        "output_src_prefix_ipv6",
+        "../assets/pcap-filter/ipv6_prefix",
+        "si",
+        5,
+    )
+}
+
 #[test]
 fn test_filter_ipv6_src_ipaddr_proto_dst_port() {
     generic_test(
@@ -163,7 +243,6 @@ fn test_filter_ipv6_src_ipaddr_proto_dst_port() {
     )
 }
 
-
 #[test]
 fn test_filter_ipv6_five_tuple() {
     generic_test(
@@ -0,0 +1,58 @@
+mod common;
+
+fn generic_test(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    filters: &[(&str, &str, &str)],
+    expected_packet_number: u32,
+) {
+    let filters = filters
+        .iter()
+        .map(|(key_s, action_s, key_file_s)| {
+            let key_file_path = common::asset_path(key_file_s);
+            format!(
+                "Dispatch:{}%{}%{}",
+                key_s,
+                action_s,
+                key_file_path.display()
+            )
+        })
+        .collect::<Vec<_>>();
+    let args = filters
+        .iter()
+        .flat_map(|filter| vec!["-f", filter.as_str()])
+        .collect::<Vec<_>>();
+    common::generic_test(
+        trace_input_file_s,
+        trace_output_file_s,
+        &args,
+        expected_packet_number,
+    );
+}
+
+#[test]
+fn test_chain_keep_prefix_drop_port_range() {
+    // 4 packets from the prefix, 2 of them to ports of the range
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_chain_keep_prefix_drop_port_range",
+        &[
+            ("si", "k", "../assets/pcap-filter/ipv4_prefix"),
+            ("dpr", "d", "../assets/pcap-filter/port_range"),
+        ],
+        2,
+    )
+}
+
+#[test]
+fn test_chain_order_independent() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_chain_order_independent",
+        &[
+            ("dpr", "d", "../assets/pcap-filter/port_range"),
+            ("si", "k", "../assets/pcap-filter/ipv4_prefix"),
+        ],
+        2,
+    )
+}
@@ -0,0 +1,52 @@
+mod common;
+
+fn generic_test(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    filter_config_file_s: &str,
+    expected_packet_number: u32,
+) {
+    let filter_config_file_path = common::asset_path(filter_config_file_s);
+    common::generic_test(
+        trace_input_file_s,
+        trace_output_file_s,
+        &[
+            "--filter-config",
+            &filter_config_file_path.to_string_lossy(),
+        ],
+        expected_packet_number,
+    );
+}
+
+#[test]
+fn test_filter_config_chain() {
+    // same filters as test_chain_keep_prefix_drop_port_range, with a key file and inline values
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_filter_config_chain",
+        "../assets/pcap-filter/filter_config_chain.toml",
+        2,
+    )
+}
+
+#[test]
+fn test_filter_config_inline_five_tuple() {
+    generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_filter_config_inline_five_tuple",
+        "../assets/pcap-filter/filter_config_five_tuple.toml",
+        2,
+    )
+}
+
+#[test]
+fn test_filter_config_invalid_key() {
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--filter-config")
+        .arg(common::asset_path(
+            "../assets/pcap-filter/filter_config_invalid.toml",
+        ))
+        .arg("../assets/nmap_tcp_22_ipv4.pcap")
+        .arg(common::temp_path("output_filter_config_invalid_key"));
+    cmd.assert().failure();
+}
@@ -0,0 +1,34 @@
+use pnet_packet::ethernet::EthernetPacket;
+use pnet_packet::ipv4::{self, Ipv4Packet};
+use pnet_packet::tcp::{self, TcpPacket};
+use pnet_packet::Packet;
+
+mod common;
+
+/// Run pcap-rewrite with `args`, and return the (ethernet) output packets
+fn generic_test(trace_output_file_s: &str, args: &[&str]) -> Vec<Vec<u8>> {
+    let data = common::run_rewrite("../assets/nmap_tcp_22_ipv4.pcap", trace_output_file_s, args)
+        .expect("Could not rewrite the file");
+    common::packets_in_data(&data)
+}
+
+fn check_checksums(frame: &[u8]) -> bool {
+    let ethernet = EthernetPacket::new(frame).unwrap();
+    let packet = Ipv4Packet::new(ethernet.payload()).unwrap();
+    let tcp = TcpPacket::new(packet.payload()).unwrap();
+    ipv4::checksum(&packet) == packet.get_checksum()
+        && tcp::ipv4_checksum(&tcp, &packet.get_source(), &packet.get_destination())
+            == tcp.get_checksum()
+}
+
+#[test]
+fn test_fix_checksums() {
+    // packets sent by the capturing host have invalid TCP checksums (checksum offload)
+    let packets = generic_test("output_no_fix_checksums.pcap", &[]);
+    assert_eq!(packets.len(), 18);
+    assert!(!check_checksums(&packets[1]));
+
+    let packets = generic_test("output_fix_checksums.pcap", &["--fix-checksums"]);
+    assert_eq!(packets.len(), 18);
+    assert!(packets.iter().all(|p| check_checksums(p)));
+}
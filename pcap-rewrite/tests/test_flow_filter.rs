@@ -0,0 +1,66 @@
+use pcap_parser::PcapCapture;
+
+mod common;
+
+/// Run pcap-rewrite with `--flow` arguments, and return the number of packets written
+fn generic_test(trace_output_file_s: &str, flows: &[&str]) -> Option<usize> {
+    let args = flows
+        .iter()
+        .flat_map(|flow| vec!["--flow", *flow])
+        .collect::<Vec<_>>();
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        trace_output_file_s,
+        &args,
+    )?;
+    let cap = PcapCapture::from_file(&data).unwrap();
+    Some(cap.blocks.len())
+}
+
+#[test]
+fn test_flow_filter_both_directions() {
+    let count = generic_test(
+        "output_flow_filter.pcap",
+        &["tcp 192.168.10.10:45158 192.168.10.1:80"],
+    );
+    assert_eq!(count, Some(4));
+    // the direction of the flow is not relevant
+    let count = generic_test(
+        "output_flow_filter_reverse.pcap",
+        &["tcp 192.168.10.1:80 192.168.10.10:45158"],
+    );
+    assert_eq!(count, Some(4));
+}
+
+#[test]
+fn test_flow_filter_several_flows() {
+    let count = generic_test(
+        "output_flow_filter_several.pcap",
+        &[
+            "tcp 192.168.10.10:45158 192.168.10.1:80",
+            "TCP 192.168.10.10:34310 192.168.10.12:80",
+        ],
+    );
+    assert_eq!(count, Some(6));
+    // wrong protocol
+    let count = generic_test(
+        "output_flow_filter_udp.pcap",
+        &["udp 192.168.10.10:45158 192.168.10.1:80"],
+    );
+    assert_eq!(count, Some(0));
+}
+
+#[test]
+fn test_flow_filter_invalid() {
+    assert_eq!(
+        generic_test("output_flow_filter_invalid.pcap", &["tcp 192.168.10.10"]),
+        None
+    );
+    assert_eq!(
+        generic_test(
+            "output_flow_filter_no_port.pcap",
+            &["tcp 192.168.10.10 192.168.10.1"]
+        ),
+        None
+    );
+}
@@ -1,32 +1,4 @@
-use pcap_parser::{Capture, PcapCapture};
-use std::env;
-use std::fs;
-use std::fs::File;
-use std::path::Path;
-use std::path::PathBuf;
-
-use assert_cmd::Command;
-
-fn count_packet_in_trace(trace_file_path: &Path) -> u32 {
-    if trace_file_path.exists() {
-        let file = File::open(trace_file_path).unwrap();
-        let file_size = file.metadata().unwrap().len();
-        if file_size == 0 {
-            0
-        } else {
-            let data = fs::read(trace_file_path).unwrap();
-            let cap = PcapCapture::from_file(&data).unwrap();
-            let mut count = 0;
-            let mut iter = cap.iter();
-            while iter.next().is_some() {
-                count += 1;
-            }
-            count
-        }
-    } else {
-        panic!("{:#?} does not exists!", trace_file_path)
-    }
-}
+mod common;
 
 fn generic_test(
     trace_input_file_s: &str,
@@ -35,29 +7,13 @@ fn generic_test(
     filtering_action_s: &str,
     expected_packet_number: u32,
 ) {
-    let mut trace_input_file_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    trace_input_file_path.push(trace_input_file_s);
-
-    let mut trace_output_file_path = std::env::temp_dir();
-    trace_output_file_path.push(trace_output_file_s);
-
-    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
-    cmd.arg("-f")
-        .arg(format!(
-            "Fragmentation:{}%{}",
-            filtering_key_s, filtering_action_s
-        ))
-        .arg(&trace_input_file_path)
-        .arg(&trace_output_file_path);
-
-    let _output = cmd.output().unwrap();
-    // println!("Output: {:?}", _output);
-
-    let output_nb_packet = count_packet_in_trace(&trace_output_file_path);
-
-    fs::remove_file(&trace_output_file_path).expect("Could not destroy the filtered file");
-
-    assert_eq!(output_nb_packet, expected_packet_number);
+    let filter = format!("Fragmentation:{}%{}", filtering_key_s, filtering_action_s);
+    common::generic_test(
+        trace_input_file_s,
+        trace_output_file_s,
+        &["-f", &filter],
+        expected_packet_number,
+    );
 }
 
 // IPV4 preliminary - two packets/fragments scenario
@@ -0,0 +1,45 @@
+mod common;
+
+fn generic_test(trace_output_file_s: &str, key_file_s: &str, filter_s: &str) -> u32 {
+    let key_file_path = common::asset_path(key_file_s);
+    let filter_s = filter_s.replace("path", &key_file_path.display().to_string());
+    let data = common::run_rewrite(
+        "../assets/icmp_ipv4_ipv6.pcap",
+        trace_output_file_s,
+        &["-f", &format!("Dispatch:{}", filter_s)],
+    )
+    .expect("Could not filter the file");
+    common::count_packet_in_data(&data)
+}
+
+#[test]
+fn test_filter_icmp_type_code() {
+    // ARP and TCP packets have no ICMP type: drop them
+    let count = generic_test(
+        "output_icmp_type_code",
+        "../assets/pcap-filter/icmp_type_code",
+        "itc%k%path%d",
+    );
+    assert_eq!(count, 3);
+}
+
+#[test]
+fn test_filter_icmp_type_code_keep_unsupported() {
+    let count = generic_test(
+        "output_icmp_type_code_keep_unsupported",
+        "../assets/pcap-filter/icmp_type_code",
+        "itc%k%path%k",
+    );
+    assert_eq!(count, 5);
+}
+
+#[test]
+fn test_filter_port_range_keep_unsupported() {
+    // ICMP and ARP packets have no port: keep them, and drop the TCP packet
+    let count = generic_test(
+        "output_port_range_keep_unsupported",
+        "../assets/pcap-filter/port_range",
+        "dpr%d%path%k",
+    );
+    assert_eq!(count, 5);
+}
@@ -0,0 +1,78 @@
+use pcap_parser::{Capture, Linktype, PcapBlock, PcapCapture};
+use std::fs;
+
+mod common;
+
+fn filter_test(trace_input_file_s: &str, trace_output_file_s: &str, expected_packet_count: u32) {
+    let trace_input_file_path = common::asset_path(trace_input_file_s);
+
+    let key_file_path = common::asset_path("../assets/pcap-filter/ipv4_ipaddr");
+
+    let trace_output_file_path = common::temp_path(trace_output_file_s);
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("-f")
+        .arg(format!("Dispatch:di%k%{}", key_file_path.display()))
+        .arg(&trace_input_file_path)
+        .arg(&trace_output_file_path);
+    cmd.assert().success();
+
+    let data = fs::read(&trace_output_file_path).unwrap();
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the output file");
+
+    assert_eq!(common::count_packet_in_data(&data), expected_packet_count);
+}
+
+#[test]
+fn test_filter_linux_sll() {
+    filter_test(
+        "../assets/sll_tcp_80_ipv4.pcap",
+        "output_filter_linux_sll",
+        1,
+    )
+}
+
+#[test]
+fn test_filter_null() {
+    filter_test("../assets/null_tcp_80_ipv4.pcap", "output_filter_null", 1)
+}
+
+#[test]
+fn test_convert_linktype() {
+    let trace_input_file_path = common::asset_path("../assets/sll_tcp_80_ipv4.pcap");
+
+    let trace_output_file_path = common::temp_path("output_convert_linktype");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--convert-linktype")
+        .arg(&trace_input_file_path)
+        .arg(&trace_output_file_path);
+    cmd.assert().success();
+
+    let data = fs::read(&trace_output_file_path).unwrap();
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the output file");
+
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.header.network, Linktype::ETHERNET);
+    let mut ethertypes = Vec::new();
+    for block in cap.iter() {
+        if let PcapBlock::Legacy(b) = block {
+            assert_eq!(&b.data[..12], &[0; 12]);
+            ethertypes.push(u16::from_be_bytes([b.data[12], b.data[13]]));
+        }
+    }
+    assert_eq!(ethertypes, vec![0x0800, 0x0800, 0x86dd, 0x0800]);
+}
+
+#[test]
+fn test_convert_linktype_raw_output() {
+    let trace_output_file_path = common::temp_path("output_convert_linktype_raw_output");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--convert-linktype")
+        .arg("--output-linktype")
+        .arg("raw")
+        .arg("../assets/sll_tcp_80_ipv4.pcap")
+        .arg(&trace_output_file_path);
+    cmd.assert().failure();
+}
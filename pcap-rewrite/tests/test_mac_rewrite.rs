@@ -0,0 +1,57 @@
+use pcap_parser::{Linktype, PcapCapture};
+
+mod common;
+
+/// Run pcap-rewrite with ethernet output and MAC options, and return the output packets
+fn generic_test(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    args: &[&str],
+) -> Vec<Vec<u8>> {
+    let args = [&["--output-linktype", "ethernet"][..], args].concat();
+    let data = common::run_rewrite(trace_input_file_s, trace_output_file_s, &args)
+        .expect("Could not rewrite the file");
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.header.network, Linktype::ETHERNET);
+    cap.blocks.iter().map(|b| b.data.to_vec()).collect()
+}
+
+#[test]
+fn test_mac_map() {
+    let map_file_path = common::asset_path("../assets/pcap-transform/mac_map");
+    let packets = generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_mac_map",
+        &["--mac-map", map_file_path.to_str().unwrap()],
+    );
+    assert_eq!(packets.len(), 18);
+    assert_eq!(packets[0][..6], [0x02, 0, 0, 0, 0, 0x01]);
+    assert_eq!(packets[0][6..12], [0, 0, 0, 0, 0, 0x10]);
+    assert_eq!(packets[1][6..12], [0x02, 0, 0, 0, 0, 0x01]);
+}
+
+#[test]
+fn test_randomize_mac() {
+    let packets = generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_randomize_mac",
+        &["--randomize-mac"],
+    );
+    assert_eq!(packets.len(), 18);
+    let src = &packets[0][6..12];
+    // OUI is kept
+    assert_eq!(src[..3], [0, 0, 0]);
+    assert_ne!(src, [0, 0, 0, 0, 0, 0x10]);
+    // same address gives the same result
+    assert_eq!(&packets[1][..6], src);
+    assert_eq!(&packets[4][6..12], src);
+}
+
+#[test]
+fn test_mac_rewrite_requires_ethernet() {
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--randomize-mac")
+        .arg("../assets/nmap_tcp_22_ipv4.pcap")
+        .arg(common::temp_path("output_mac_rewrite_raw"));
+    cmd.assert().failure();
+}
@@ -0,0 +1,82 @@
+use pcap_parser::{Block, PcapCapture, PcapNGCapture};
+use std::fs;
+
+mod common;
+
+/// Run pcap-rewrite with several inputs, and return the content of the merged file
+fn generic_test(trace_input_files_s: &[&str], trace_output_file_s: &str, format: &str) -> Vec<u8> {
+    let mut cmd = common::pcap_rewrite();
+    cmd.args(&["-o", format]);
+    for trace_input_file_s in trace_input_files_s {
+        let trace_input_file_path = common::asset_path(trace_input_file_s);
+        cmd.arg(&trace_input_file_path);
+    }
+
+    let trace_output_file_path = common::temp_path(trace_output_file_s);
+    cmd.arg(&trace_output_file_path);
+
+    let _output = cmd.output().unwrap();
+
+    let data = fs::read(&trace_output_file_path).unwrap();
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the merged file");
+    data
+}
+
+#[test]
+fn test_merge_pcap() {
+    let data = generic_test(
+        &[
+            "../assets/nmap_tcp_22_ipv4.pcap",
+            "../assets/vlan_qinq_tcp_80_ipv4.pcap",
+        ],
+        "output_merge.pcap",
+        "pcap",
+    );
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.blocks.len(), 24);
+    let ts: Vec<(u32, u32)> = cap.blocks.iter().map(|b| (b.ts_sec, b.ts_usec)).collect();
+    let mut sorted_ts = ts.clone();
+    sorted_ts.sort_unstable();
+    assert_eq!(ts, sorted_ts);
+}
+
+#[test]
+fn test_merge_pcapng() {
+    let data = generic_test(
+        &[
+            "../assets/nmap_tcp_22_ipv4.pcap",
+            "../assets/nmap_tcp_22_ipv4_ns.pcapng",
+        ],
+        "output_merge.pcapng",
+        "pcapng",
+    );
+    let cap = PcapNGCapture::from_file(&data).unwrap();
+    let blocks = &cap.sections[0].blocks;
+    // one interface for the pcap file, two for the pcapng file
+    let num_idb = blocks
+        .iter()
+        .filter(|b| matches!(b, Block::InterfaceDescription(_)))
+        .count();
+    assert_eq!(num_idb, 3);
+    let ts: Vec<u64> = blocks
+        .iter()
+        .filter_map(|b| match b {
+            Block::EnhancedPacket(epb) => {
+                Some((u64::from(epb.ts_high) << 32) | u64::from(epb.ts_low))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(ts.len(), 36);
+    assert!(ts.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[test]
+fn test_merge_unsupported_option() {
+    let mut cmd = common::pcap_rewrite();
+    cmd.args(&["--bpf", "tcp"])
+        .arg("../assets/nmap_tcp_22_ipv4.pcap")
+        .arg("../assets/vlan_qinq_tcp_80_ipv4.pcap")
+        .arg(common::temp_path("output_merge_unsupported.pcap"));
+    cmd.assert().failure();
+}
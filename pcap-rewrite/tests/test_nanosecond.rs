@@ -0,0 +1,48 @@
+use pcap_parser::{Block, Capture, PcapBlock, PcapCapture, PcapNGCapture};
+
+mod common;
+
+#[test]
+fn test_nanosecond_pcap_to_pcap() {
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4_ns.pcap",
+        "output_nanosecond.pcap",
+        &["-o", "pcap"],
+    )
+    .expect("pcap-rewrite failed");
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert!(cap.header.is_nanosecond_precision());
+    let mut count = 0;
+    for block in cap.iter() {
+        if let PcapBlock::Legacy(b) = block {
+            // sub-microsecond part of the timestamp is kept
+            assert_eq!(b.ts_usec % 1000, 123);
+            count += 1;
+        }
+    }
+    assert_eq!(count, 18);
+}
+
+#[test]
+fn test_nanosecond_pcap_to_pcapng() {
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4_ns.pcap",
+        "output_nanosecond.pcapng",
+        &["-o", "pcapng"],
+    )
+    .expect("pcap-rewrite failed");
+    let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+    let mut count = 0;
+    for block in capture.sections.iter().flat_map(|s| s.blocks.iter()) {
+        match block {
+            Block::InterfaceDescription(idb) => assert_eq!(idb.if_tsresol, 9),
+            Block::EnhancedPacket(epb) => {
+                let ts = (u64::from(epb.ts_high) << 32) | u64::from(epb.ts_low);
+                assert_eq!(ts % 1000, 123);
+                count += 1;
+            }
+            _ => (),
+        }
+    }
+    assert_eq!(count, 18);
+}
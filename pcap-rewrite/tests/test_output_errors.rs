@@ -0,0 +1,41 @@
+mod common;
+
+// /dev/full accepts open but fails on write: output is buffered, so the error is only
+// reported when output is flushed, at the end of the run
+#[cfg(target_os = "linux")]
+#[test]
+fn test_output_write_error() {
+    common::pcap_rewrite()
+        .arg(common::asset_path("../assets/nmap_tcp_22_ipv4.pcap"))
+        .arg("/dev/full")
+        .assert()
+        .failure();
+}
+
+// Errors when writing the end of compressed data are also reported
+#[cfg(target_os = "linux")]
+#[test]
+fn test_compressed_output_write_error() {
+    for compression in &["gzip", "zstd"] {
+        common::pcap_rewrite()
+            .args(&["--compress", compression])
+            .arg(common::asset_path("../assets/nmap_tcp_22_ipv4.pcap"))
+            .arg("/dev/full")
+            .assert()
+            .failure();
+    }
+}
+
+// Input which is not a capture file is an error, not a panic
+#[test]
+fn test_invalid_input() {
+    let output_path = common::temp_path("output_invalid_input");
+    let output = common::pcap_rewrite()
+        .arg(common::asset_path("../assets/pcap-filter/ipv4_prefix"))
+        .arg(&output_path)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&output_path);
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("panicked"));
+}
@@ -0,0 +1,37 @@
+use pcap_parser::PcapCapture;
+
+mod common;
+
+/// Run pcap-rewrite with `--packets`, and return the timestamps of the packets written
+fn generic_test(trace_output_file_s: &str, packets: &str) -> Option<Vec<(u32, u32)>> {
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        trace_output_file_s,
+        &["--packets", packets],
+    )?;
+    let cap = PcapCapture::from_file(&data).unwrap();
+    Some(cap.blocks.iter().map(|b| (b.ts_sec, b.ts_usec)).collect())
+}
+
+#[test]
+fn test_packet_index() {
+    let all = generic_test("output_packet_index_all.pcap", "1-").unwrap();
+    assert_eq!(all.len(), 18);
+
+    let selected = generic_test("output_packet_index.pcap", "2-4,9,17-").unwrap();
+    let expected: Vec<_> = [2, 3, 4, 9, 17, 18].iter().map(|&i| all[i - 1]).collect();
+    assert_eq!(selected, expected);
+
+    let first = generic_test("output_packet_index_first.pcap", "-3").unwrap();
+    assert_eq!(first, all[..3]);
+}
+
+#[test]
+fn test_packet_index_invalid() {
+    assert_eq!(generic_test("output_packet_index_zero.pcap", "0-5"), None);
+    assert_eq!(
+        generic_test("output_packet_index_reverse.pcap", "5-2"),
+        None
+    );
+    assert_eq!(generic_test("output_packet_index_text.pcap", "1,a"), None);
+}
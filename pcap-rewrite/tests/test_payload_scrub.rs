@@ -0,0 +1,50 @@
+use pnet_packet::ipv4::{self, Ipv4Packet};
+use pnet_packet::tcp::{self, TcpPacket};
+use pnet_packet::Packet;
+
+mod common;
+
+/// Run pcap-rewrite with payload options, and return the (raw IPv4) output packets
+fn generic_test(trace_input_file_s: &str, trace_output_file_s: &str, arg: &str) -> Vec<Vec<u8>> {
+    let data = common::run_rewrite(trace_input_file_s, trace_output_file_s, &[arg])
+        .expect("Could not rewrite the file");
+    common::packets_in_data(&data)
+}
+
+fn check_checksums(packet: &Ipv4Packet) -> bool {
+    let tcp = TcpPacket::new(packet.payload()).unwrap();
+    ipv4::checksum(packet) == packet.get_checksum()
+        && tcp::ipv4_checksum(&tcp, &packet.get_source(), &packet.get_destination())
+            == tcp.get_checksum()
+}
+
+#[test]
+fn test_truncate_payload() {
+    let packets = generic_test(
+        "../assets/frag_tcp_80_ipv4.pcap",
+        "output_truncate_payload",
+        "--truncate-payload",
+    );
+    let lengths: Vec<usize> = packets.iter().map(|p| p.len()).collect();
+    // last fragment only contains payload
+    assert_eq!(lengths, vec![40, 40, 40, 20, 40]);
+    let first = Ipv4Packet::new(&packets[0]).unwrap();
+    assert_eq!(first.get_total_length(), 40);
+    assert!(check_checksums(&first));
+}
+
+#[test]
+fn test_zero_payload() {
+    let packets = generic_test(
+        "../assets/frag_tcp_80_ipv4.pcap",
+        "output_zero_payload",
+        "--zero-payload",
+    );
+    let lengths: Vec<usize> = packets.iter().map(|p| p.len()).collect();
+    assert_eq!(lengths, vec![1240, 40, 1300, 160, 40]);
+    let first = Ipv4Packet::new(&packets[0]).unwrap();
+    assert!(check_checksums(&first));
+    let tcp = TcpPacket::new(first.payload()).unwrap();
+    assert_eq!(tcp.payload().len(), 1200);
+    assert!(tcp.payload().iter().all(|b| *b == 0));
+}
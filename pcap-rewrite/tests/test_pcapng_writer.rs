@@ -0,0 +1,87 @@
+use pcap_parser::pcapng::{EnhancedPacketBlock, InterfaceDescriptionBlock};
+use pcap_parser::{Block, Linktype, OptionCode, PcapNGCapture};
+
+mod common;
+
+fn rewrite_to_pcapng(trace_input_file_s: &str, trace_output_file_s: &str) -> Vec<u8> {
+    common::run_rewrite(trace_input_file_s, trace_output_file_s, &["-o", "pcapng"])
+        .expect("pcap-rewrite failed")
+}
+
+fn get_idbs_epbs<'a>(
+    capture: &'a PcapNGCapture,
+) -> (
+    Vec<&'a InterfaceDescriptionBlock<'a>>,
+    Vec<&'a EnhancedPacketBlock<'a>>,
+) {
+    let mut idbs = Vec::new();
+    let mut epbs = Vec::new();
+    for block in capture.sections.iter().flat_map(|s| s.blocks.iter()) {
+        match block {
+            Block::InterfaceDescription(idb) => idbs.push(idb),
+            Block::EnhancedPacket(epb) => epbs.push(epb),
+            _ => (),
+        }
+    }
+    (idbs, epbs)
+}
+
+#[test]
+fn test_pcapng_preserve_interfaces() {
+    let data = rewrite_to_pcapng(
+        "../assets/nmap_tcp_22_ipv4_ns.pcapng",
+        "output_pcapng_interfaces.pcapng",
+    );
+    let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+    let (idbs, epbs) = get_idbs_epbs(&capture);
+
+    assert_eq!(idbs.len(), 2);
+    assert_eq!(idbs[0].if_tsresol, 9);
+    assert!(idbs[0]
+        .options
+        .iter()
+        .any(|o| o.code == OptionCode::IfName && o.value == b"eth0"));
+    assert_eq!(idbs[1].if_tsresol, 6);
+
+    assert_eq!(epbs.len(), 18);
+    assert_eq!(epbs[0].if_id, 0);
+    assert_eq!(epbs[1].if_id, 1);
+    // sub-microsecond part of the timestamp is kept
+    let ts = (u64::from(epbs[0].ts_high) << 32) | u64::from(epbs[0].ts_low);
+    assert_eq!(ts % 1000, 123);
+}
+
+#[test]
+fn test_pcapng_from_legacy_pcap() {
+    let data = rewrite_to_pcapng(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_pcapng_from_legacy.pcapng",
+    );
+    let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+    let (idbs, epbs) = get_idbs_epbs(&capture);
+
+    assert_eq!(idbs.len(), 1);
+    assert_eq!(idbs[0].if_tsresol, 6);
+    assert_eq!(epbs.len(), 18);
+    assert!(epbs.iter().all(|epb| epb.if_id == 0));
+}
+
+#[test]
+fn test_pcapng_interface_linktypes() {
+    // interface 0 is Ethernet, interface 1 is raw IPv4
+    let data = rewrite_to_pcapng(
+        "../assets/eth_ipv4_tcp.pcapng",
+        "output_pcapng_linktypes.pcapng",
+    );
+    let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+    let (idbs, epbs) = get_idbs_epbs(&capture);
+
+    assert_eq!(idbs.len(), 2);
+    // Ethernet headers are removed, raw IPv4 packets are not modified
+    assert_eq!(idbs[0].linktype, Linktype::RAW);
+    assert_eq!(idbs[1].linktype, Linktype::IPV4);
+
+    assert_eq!(epbs.len(), 7);
+    assert!(epbs[..4].iter().all(|epb| epb.if_id == 0));
+    assert!(epbs[4..].iter().all(|epb| epb.if_id == 1));
+}
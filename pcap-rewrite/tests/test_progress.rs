@@ -0,0 +1,26 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use assert_cmd::Command;
+
+#[test]
+fn test_progress() {
+    let mut trace_input_file_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    trace_input_file_path.push("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let mut trace_output_file_path = std::env::temp_dir();
+    trace_output_file_path.push("output_progress.pcap");
+
+    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+    cmd.arg("--progress")
+        .arg(&trace_input_file_path)
+        .arg(&trace_output_file_path);
+    let output = cmd.assert().success().get_output().clone();
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the output file");
+
+    // the last report is always written, with the total size of the input
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("100.0%"));
+    assert!(stderr.contains("pkt/s"));
+}
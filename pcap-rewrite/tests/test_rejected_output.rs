@@ -0,0 +1,32 @@
+use std::fs;
+
+mod common;
+
+#[test]
+fn test_rejected_output_complementary() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let key_file_path = common::asset_path("../assets/pcap-filter/ipv4_prefix");
+
+    let trace_output_file_path = common::temp_path("output_rejected_output_kept");
+    let trace_rejected_file_path = common::temp_path("output_rejected_output_rejected");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("-f")
+        .arg(format!("Dispatch:si%k%{}", key_file_path.display()))
+        .arg("--rejected-output")
+        .arg(&trace_rejected_file_path)
+        .arg(&trace_input_file_path)
+        .arg(&trace_output_file_path);
+    cmd.assert().success();
+
+    let input_nb_packet = common::count_packet_in_trace(&trace_input_file_path);
+    let output_nb_packet = common::count_packet_in_trace(&trace_output_file_path);
+    let rejected_nb_packet = common::count_packet_in_trace(&trace_rejected_file_path);
+
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the filtered file");
+    fs::remove_file(&trace_rejected_file_path).expect("Could not destroy the rejected file");
+
+    assert_eq!(output_nb_packet, 4);
+    assert_eq!(output_nb_packet + rejected_nb_packet, input_nb_packet);
+}
@@ -0,0 +1,82 @@
+use pcap_parser::{Block, PcapCapture, PcapNGCapture};
+use std::fs;
+
+mod common;
+
+/// Write a damaged copy of an asset, run pcap-rewrite in repair mode, and return the repaired
+/// file and the statistics
+///
+/// The 4 bytes at `corrupt_offset` are overwritten, and the last `cut` bytes are removed.
+fn generic_test(
+    trace_input_file_s: &str,
+    corrupt_offset: usize,
+    cut: usize,
+    trace_output_file_s: &str,
+) -> (Vec<u8>, serde_json::Value) {
+    let trace_input_file_path = common::asset_path(trace_input_file_s);
+    let mut data = fs::read(&trace_input_file_path).unwrap();
+    data[corrupt_offset..corrupt_offset + 4].copy_from_slice(&[0xff; 4]);
+    data.truncate(data.len() - cut);
+
+    let damaged_file_path = common::temp_path(format!("damaged_{}", trace_output_file_s));
+    fs::write(&damaged_file_path, &data).unwrap();
+
+    let trace_output_file_path = common::temp_path(trace_output_file_s);
+    let stats_file_path = common::temp_path(format!("{}.json", trace_output_file_s));
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--repair")
+        .arg("--stats-out")
+        .arg(&stats_file_path)
+        .arg(&damaged_file_path)
+        .arg(&trace_output_file_path);
+    cmd.assert().success();
+
+    let data = fs::read(&trace_output_file_path).unwrap();
+    let stats = serde_json::from_slice(&fs::read(&stats_file_path).unwrap()).unwrap();
+    fs::remove_file(&damaged_file_path).expect("Could not destroy the damaged file");
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the repaired file");
+    fs::remove_file(&stats_file_path).expect("Could not destroy the stats file");
+    (data, stats)
+}
+
+#[test]
+fn test_repair_pcap() {
+    // corrupt header of the 5th record (90 bytes), and truncate the last record (70 bytes)
+    let (data, stats) = generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        368 + 8,
+        30,
+        "output_repair.pcap",
+    );
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.blocks.len(), 16);
+    assert_eq!(stats["format"], "pcap");
+    assert_eq!(stats["packets_kept"], 16);
+    assert_eq!(stats["dropped_bytes"], 130);
+    assert_eq!(stats["dropped"][0]["offset"], 368);
+    assert_eq!(stats["dropped"][0]["bytes"], 90);
+    assert_eq!(stats["dropped"][1]["offset"], 1442);
+}
+
+#[test]
+fn test_repair_pcapng() {
+    // corrupt type of the 3rd EPB (100 bytes), and truncate the last EPB (88 bytes)
+    let (data, stats) = generic_test(
+        "../assets/nmap_tcp_22_ipv4_ns.pcapng",
+        316,
+        30,
+        "output_repair.pcapng",
+    );
+    let cap = PcapNGCapture::from_file(&data).unwrap();
+    let blocks = &cap.sections[0].blocks;
+    let num_epb = blocks
+        .iter()
+        .filter(|b| matches!(b, Block::EnhancedPacket(_)))
+        .count();
+    assert_eq!(num_epb, 16);
+    assert_eq!(stats["format"], "pcapng");
+    assert_eq!(stats["blocks_kept"], 19);
+    assert_eq!(stats["dropped_bytes"], 158);
+    assert_eq!(stats["dropped"][0]["offset"], 316);
+}
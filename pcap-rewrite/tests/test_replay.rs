@@ -0,0 +1,64 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use assert_cmd::Command;
+
+fn replay_command(args: &[&str], interface: &str) -> Command {
+    let mut trace_input_file_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    trace_input_file_path.push("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+    cmd.arg("--replay")
+        .args(args)
+        .arg(&trace_input_file_path)
+        .arg(interface);
+    cmd
+}
+
+#[test]
+fn test_replay_invalid() {
+    replay_command(&[], "no-such-interface0").assert().failure();
+    replay_command(&["--output-linktype", "raw"], "lo")
+        .assert()
+        .failure();
+    replay_command(&["--replay-speed", "fast"], "lo")
+        .assert()
+        .failure();
+    replay_command(&["--split-count", "10"], "lo")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_replay_loopback() {
+    let mut stats_file_path = std::env::temp_dir();
+    stats_file_path.push("output_replay_stats.json");
+    let stats_arg = stats_file_path.to_string_lossy();
+
+    let output = replay_command(
+        &[
+            "--replay-speed",
+            "0",
+            "--stats-out",
+            &stats_arg,
+            "--packets",
+            "1-4",
+        ],
+        "lo",
+    )
+    .output()
+    .unwrap();
+    // sending packets requires the CAP_NET_RAW capability
+    if String::from_utf8_lossy(&output.stderr).contains("PermissionDenied") {
+        eprintln!("Skipping replay test, not allowed to open a packet socket");
+        return;
+    }
+    assert!(output.status.success());
+
+    let data = fs::read(&stats_file_path).unwrap();
+    fs::remove_file(&stats_file_path).expect("Could not destroy the stats file");
+    let stats: serde_json::Value = serde_json::from_slice(&data).unwrap();
+    assert_eq!(stats["packets_read"], 18);
+    assert_eq!(stats["packets_written"], 4);
+}
@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+mod common;
+
+/// Split trace per flow, and return the names of the flow files
+fn flow_names(trace_file_path: &Path, output_dir: &Path) -> HashSet<String> {
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--split-flows")
+        .arg(trace_file_path)
+        .arg(output_dir);
+    cmd.assert().success();
+
+    // the output directory may not exist if the trace is empty
+    let names = match fs::read_dir(output_dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect(),
+        Err(_) => HashSet::new(),
+    };
+    let _ = fs::remove_dir_all(output_dir);
+    names
+}
+
+#[test]
+fn test_sampling_one_in_n() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let trace_output_file_path = common::temp_path("output_sampling_one_in_n");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--sample")
+        .arg("1/3")
+        .arg(&trace_input_file_path)
+        .arg(&trace_output_file_path);
+    cmd.assert().success();
+
+    let output_nb_packet = common::count_packet_in_trace(&trace_output_file_path);
+
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the output file");
+
+    assert_eq!(output_nb_packet, 6);
+}
+
+#[test]
+fn test_sampling_flow_consistent() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let trace_output_file_path = common::temp_path("output_sampling_flows_kept");
+    let trace_rejected_file_path = common::temp_path("output_sampling_flows_rejected");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--sample-prob")
+        .arg("0.5")
+        .arg("--sample-flows")
+        .arg("--sample-seed")
+        .arg("1")
+        .arg("--rejected-output")
+        .arg(&trace_rejected_file_path)
+        .arg(&trace_input_file_path)
+        .arg(&trace_output_file_path);
+    cmd.assert().success();
+
+    let kept_flows = flow_names(
+        &trace_output_file_path,
+        &common::temp_path("output_sampling_flows_kept_split"),
+    );
+    let rejected_flows = flow_names(
+        &trace_rejected_file_path,
+        &common::temp_path("output_sampling_flows_rejected_split"),
+    );
+
+    fs::remove_file(&trace_output_file_path).expect("Could not destroy the output file");
+    fs::remove_file(&trace_rejected_file_path).expect("Could not destroy the rejected file");
+
+    // a flow is either kept or rejected, never split between both files
+    assert!(kept_flows.is_disjoint(&rejected_flows));
+    assert_eq!(kept_flows.len() + rejected_flows.len(), 7);
+}
+
+#[test]
+fn test_sampling_seed_requires_sample() {
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--sample-seed")
+        .arg("1")
+        .arg("../assets/nmap_tcp_22_ipv4.pcap")
+        .arg(common::temp_path("output_sampling_seed_requires_sample"));
+    cmd.assert().failure();
+}
@@ -0,0 +1,51 @@
+use pcap_parser::{Capture, PcapBlock, PcapCapture};
+
+mod common;
+
+#[test]
+fn test_snaplen() {
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_snaplen",
+        &["--snaplen", "48"],
+    )
+    .expect("pcap-rewrite failed");
+
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.header.snaplen, 48);
+    let mut count = 0;
+    let mut total_origlen = 0;
+    for block in cap.iter() {
+        if let PcapBlock::Legacy(b) = block {
+            assert_eq!(b.caplen, b.origlen.min(48));
+            assert_eq!(b.data.len(), b.caplen as usize);
+            total_origlen += b.origlen;
+            count += 1;
+        }
+    }
+    assert_eq!(count, 18);
+    // input is ethernet, output is raw IP: 14 bytes less per packet
+    assert_eq!(total_origlen, 1200 - 18 * 14);
+}
+
+#[test]
+fn test_snaplen_zero() {
+    let data = common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_snaplen_zero",
+        &["--snaplen", "0"],
+    )
+    .expect("pcap-rewrite failed");
+
+    // 0 disables truncation
+    let cap = PcapCapture::from_file(&data).unwrap();
+    assert_eq!(cap.header.snaplen, 65535);
+    let mut count = 0;
+    for block in cap.iter() {
+        if let PcapBlock::Legacy(b) = block {
+            assert_eq!(b.caplen, b.origlen);
+            count += 1;
+        }
+    }
+    assert_eq!(count, 18);
+}
@@ -0,0 +1,106 @@
+use pcap_parser::{Block, PcapNGCapture};
+use std::fs;
+
+mod common;
+
+#[test]
+fn test_split_flows() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let output_dir = common::temp_path("output_split_flows");
+
+    // use a small number of open files, to test reopening files
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--split-flows")
+        .arg("--max-open-files")
+        .arg("2")
+        .arg(&trace_input_file_path)
+        .arg(&output_dir);
+
+    let _output = cmd.output().unwrap();
+
+    let mut counts = Vec::new();
+    for entry in fs::read_dir(&output_dir).unwrap() {
+        let path = entry.unwrap().path();
+        counts.push(common::count_packet_in_trace(&path));
+    }
+    counts.sort_unstable();
+    let first_flow_count = common::count_packet_in_trace(
+        &output_dir.join("tcp-192.168.10.1.80-192.168.10.10.45158.pcap"),
+    );
+
+    fs::remove_dir_all(&output_dir).expect("Could not destroy the output directory");
+
+    assert_eq!(counts, vec![2, 2, 2, 2, 2, 4, 4]);
+    assert_eq!(first_flow_count, 4);
+}
+
+// Writers are also closed when there are too many flows: a reopened file must not get a second
+// header
+#[test]
+fn test_split_flows_pcapng() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let output_dir = common::temp_path("output_split_flows_pcapng");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.args(&["--split-flows", "--max-open-files", "2", "-o", "pcapng"])
+        .arg(&trace_input_file_path)
+        .arg(&output_dir);
+
+    let _output = cmd.output().unwrap();
+
+    let mut counts = Vec::new();
+    for entry in fs::read_dir(&output_dir).unwrap() {
+        let data = fs::read(entry.unwrap().path()).unwrap();
+        let capture = PcapNGCapture::from_file(&data).expect("Could not parse output file");
+        assert_eq!(capture.sections.len(), 1);
+        let blocks = &capture.sections[0].blocks;
+        let count_blocks = |f: fn(&Block) -> bool| blocks.iter().filter(|b| f(b)).count();
+        assert_eq!(
+            count_blocks(|b| matches!(b, Block::InterfaceDescription(_))),
+            1
+        );
+        counts.push(count_blocks(|b| matches!(b, Block::EnhancedPacket(_))));
+    }
+    counts.sort_unstable();
+
+    fs::remove_dir_all(&output_dir).expect("Could not destroy the output directory");
+
+    assert_eq!(counts, vec![2, 2, 2, 2, 2, 4, 4]);
+}
+
+// Non-first fragments have no TCP header: they are written to the file of their first fragment
+#[test]
+fn test_split_flows_fragments() {
+    let trace_input_file_path = common::asset_path("../assets/frag_tcp_80_ipv4.pcap");
+
+    let output_dir = common::temp_path("output_split_flows_fragments");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--split-flows")
+        .arg(&trace_input_file_path)
+        .arg(&output_dir);
+
+    let _output = cmd.output().unwrap();
+
+    let mut files = fs::read_dir(&output_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    files.sort();
+    let fragmented_flow_count = common::count_packet_in_trace(
+        &output_dir.join("tcp-192.168.10.10.1400-192.168.10.11.80.pcap"),
+    );
+
+    fs::remove_dir_all(&output_dir).expect("Could not destroy the output directory");
+
+    assert_eq!(
+        files,
+        vec![
+            "tcp-192.168.10.10.1200-192.168.10.11.80.pcap",
+            "tcp-192.168.10.10.1400-192.168.10.11.80.pcap"
+        ]
+    );
+    assert_eq!(fragmented_flow_count, 3);
+}
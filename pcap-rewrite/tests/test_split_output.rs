@@ -0,0 +1,54 @@
+use std::fs;
+
+mod common;
+
+/// Run pcap-rewrite with split options, and return the number of packets in each output file
+fn generic_test(
+    trace_input_file_s: &str,
+    trace_output_file_s: &str,
+    split_args: &[&str],
+) -> Vec<u32> {
+    let trace_input_file_path = common::asset_path(trace_input_file_s);
+
+    let trace_output_file_path = common::temp_path(format!("{}.pcap", trace_output_file_s));
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.args(split_args)
+        .arg(&trace_input_file_path)
+        .arg(&trace_output_file_path);
+
+    let _output = cmd.output().unwrap();
+
+    let mut counts = Vec::new();
+    for index in 1.. {
+        let path = common::temp_path(format!("{}_{:05}.pcap", trace_output_file_s, index));
+        if !path.exists() {
+            break;
+        }
+        counts.push(common::count_packet_in_trace(&path));
+        fs::remove_file(&path).expect("Could not destroy the split file");
+    }
+    assert!(!trace_output_file_path.exists());
+    counts
+}
+
+#[test]
+fn test_split_count() {
+    let counts = generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_split_count",
+        &["--split-count", "5"],
+    );
+    assert_eq!(counts, vec![5, 5, 5, 3]);
+}
+
+#[test]
+fn test_split_size() {
+    // every packet is larger than 1 byte, so each file contains exactly one packet
+    let counts = generic_test(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_split_size",
+        &["--split-size", "1"],
+    );
+    assert_eq!(counts, vec![1; 18]);
+}
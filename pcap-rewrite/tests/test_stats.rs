@@ -0,0 +1,32 @@
+mod common;
+
+#[test]
+fn test_stats_out() {
+    let key_file_path = common::asset_path("../assets/pcap-filter/ipv4_prefix");
+    let stats_file_path = common::temp_path("output_stats_out.json");
+
+    common::run_rewrite(
+        "../assets/nmap_tcp_22_ipv4.pcap",
+        "output_stats_out",
+        &[
+            "-f",
+            &format!("Dispatch:si%k%{}", key_file_path.display()),
+            "--stats-out",
+            &stats_file_path.to_string_lossy(),
+        ],
+    )
+    .expect("pcap-rewrite failed");
+
+    let data = common::read_and_remove(&stats_file_path);
+    let stats: serde_json::Value = serde_json::from_slice(&data).unwrap();
+
+    assert_eq!(stats["packets_read"], 18);
+    assert_eq!(stats["packets_written"], 4);
+    assert_eq!(stats["packets_dropped"], 14);
+    assert_eq!(stats["dropped_per_filter"][0]["filter"], "Dispatch");
+    assert_eq!(stats["dropped_per_filter"][0]["dropped"], 14);
+    assert_eq!(stats["bytes_in"], 1200);
+    assert_eq!(stats["first_timestamp"], "1658321070.679827");
+    assert_eq!(stats["last_timestamp"], "1658321073.286467");
+    assert_eq!(stats["ether_types"]["0x0800"], 18);
+}
@@ -0,0 +1,50 @@
+use std::fs;
+
+mod common;
+
+#[test]
+fn test_stdin_stdout() {
+    let trace_input_file_path = common::asset_path("../assets/nmap_tcp_22_ipv4.pcap");
+
+    let key_file_path = common::asset_path("../assets/pcap-filter/ipv4_prefix");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("-f")
+        .arg(format!("Dispatch:si%k%{}", key_file_path.display()))
+        .arg("-")
+        .arg("-")
+        .write_stdin(fs::read(&trace_input_file_path).unwrap());
+    let output = cmd.output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(common::count_packet_in_data(&output.stdout), 4);
+}
+
+// Without pre-analysis pass, fragments in capture order still get the verdict of their datagram
+#[test]
+fn test_stdin_five_tuple_fragments() {
+    let trace_input_file_path = common::asset_path("../assets/frag_tcp_80_ipv4.pcap");
+
+    let key_file_path = common::asset_path("../assets/pcap-filter/ipv4_five_tuple_frag");
+
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("-f")
+        .arg(format!("Dispatch:sdipsdp%k%{}", key_file_path.display()))
+        .arg("-")
+        .arg("-")
+        .write_stdin(fs::read(&trace_input_file_path).unwrap());
+    let output = cmd.output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(common::count_packet_in_data(&output.stdout), 3);
+}
+
+#[test]
+fn test_stdout_split_unsupported() {
+    let mut cmd = common::pcap_rewrite();
+    cmd.arg("--split-count")
+        .arg("10")
+        .arg("../assets/nmap_tcp_22_ipv4.pcap")
+        .arg("-");
+    cmd.assert().failure();
+}
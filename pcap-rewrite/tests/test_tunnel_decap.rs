@@ -0,0 +1,69 @@
+mod common;
+
+fn generic_test(
+    trace_output_file_s: &str,
+    key_file_s: &str,
+    key_s: &str,
+    decap: bool,
+    expected_packet_number: u32,
+) {
+    let key_file_path = common::asset_path(key_file_s);
+    let filter = format!("Dispatch:{}%k%{}", key_s, key_file_path.display());
+    let mut args = vec!["-f", filter.as_str()];
+    if decap {
+        args.insert(0, "--decap");
+    }
+    // GRE (packets 1-2), VXLAN (packets 3-4) and GENEVE over IPv6 (packets 5-6)
+    common::generic_test(
+        "../assets/tunnel_gre_vxlan_geneve_ipv4.pcap",
+        trace_output_file_s,
+        &args,
+        expected_packet_number,
+    );
+}
+
+#[test]
+fn test_filter_outer_dst_prefix() {
+    generic_test(
+        "output_outer_dst_prefix",
+        "../assets/pcap-filter/ipv4_prefix",
+        "di",
+        false,
+        0,
+    )
+}
+
+#[test]
+fn test_filter_inner_dst_prefix() {
+    generic_test(
+        "output_inner_dst_prefix",
+        "../assets/pcap-filter/ipv4_prefix",
+        "di",
+        true,
+        4,
+    )
+}
+
+#[test]
+fn test_filter_inner_five_tuple() {
+    generic_test(
+        "output_inner_five_tuple",
+        "../assets/pcap-filter/ipv4_five_tuple",
+        "sdipsdp",
+        true,
+        1,
+    )
+}
+
+#[test]
+fn test_truncated_vxlan_not_decapsulated() {
+    // the VXLAN header is truncated: the packet is filtered on its outer headers
+    let key_file_path = common::asset_path("../assets/pcap-filter/ipv4_prefix");
+    let filter = format!("Dispatch:di%k%{}", key_file_path.display());
+    common::generic_test(
+        "../assets/vxlan_truncated_ipv4.pcap",
+        "output_truncated_vxlan",
+        &["--decap", "-f", filter.as_str()],
+        1,
+    );
+}
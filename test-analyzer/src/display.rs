@@ -84,15 +84,20 @@ pub fn display_json_flowsinfo(any: Box<dyn Any>) {
 pub fn display_json_rusticata(any: Box<dyn Any>) {
     let results = any.downcast::<Value>().expect("Plugin result is not JSON");
     info!("Rusticata:");
-    if let Some(map) = results.as_object() {
-        for (flow_id, m) in map {
-            info!("  Flow {}:", flow_id);
-            let m = m.as_object().unwrap();
-            for (k, v) in m {
-                info!("    {}: {}", k, v);
+    if let Some(records) = results["flows"].as_array() {
+        for record in records {
+            info!(
+                "  Flow {} [{}, confidence {}%]:",
+                record["flow_id"], record["parser"], record["confidence"]
+            );
+            if let Some(m) = record["metadata"].as_object() {
+                for (k, v) in m {
+                    info!("    {}: {}", k, v);
+                }
             }
         }
     }
+    info!("  Evictions: {}", results["evictions"]);
 }
 
 pub fn display_json_tlsstats(any: Box<dyn Any>) {
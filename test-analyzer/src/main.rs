@@ -67,10 +67,11 @@ fn main() -> Result<(), io::Error> {
         )
         .arg(
             Arg::with_name("plugins")
-                .help("Plugins to load (default: all)")
+                .help("Plugins to load, in order (default: all). Names prefixed by '-' are disabled")
                 .short('p')
                 .long("plugins")
-                .takes_value(true),
+                .takes_value(true)
+                .allow_hyphen_values(true),
         )
         .arg(
             Arg::with_name("INPUT")
@@ -118,14 +119,10 @@ fn main() -> Result<(), io::Error> {
 
     let registry = if let Some(plugin_names) = matches.value_of("plugins") {
         debug!("Restricting plugins to: {}", plugin_names);
-        let names: Vec<_> = plugin_names.split(',').collect();
-        factory.build_filter_plugins(
-            |n| {
-                debug!("n: {}", n);
-                names.iter().any(|&x| n.contains(x))
-            },
-            &config,
-        ).expect("Could not build factory")
+        let selection = PluginSelection::parse(plugin_names);
+        factory
+            .build_selected_plugins(&selection, &config)
+            .expect("Could not build factory")
     } else {
         factory.build_plugins(&config).expect("Could not build factory")
     };